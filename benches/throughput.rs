@@ -0,0 +1,187 @@
+//! Push and pull throughput benchmarks.
+//!
+//! Requests are sent directly to the router of a registry instantiated for testing, i.e. without
+//! any networking involved. Blobs and manifests are kept in memory, so results do not depend on
+//! the filesystem the benchmarks run on.
+//!
+//! Run using `cargo bench --features test-support`.
+
+use axum::{
+    body::Body,
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_RANGE, LOCATION},
+        Request, Response, StatusCode,
+    },
+    routing::RouterIntoService,
+};
+use container_registry::{test_support::TestingContainerRegistry, ContainerRegistry};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use http_body_util::BodyExt;
+use sha2::{Digest, Sha256};
+use tokio::runtime::Runtime;
+use tower::util::ServiceExt;
+
+const RAW_IMAGE: &[u8] =
+    include_bytes!("../fixtures/596a7d877b33569d199046aaf293ecf45026445be36de1818d50b4f1850762ad");
+const RAW_MANIFEST: &[u8] =
+    include_bytes!("../fixtures/9ce67038e4f1297a0b1ce23be1b768ce3649fe9bd496ba8efe9ec1676d153430");
+
+/// Chunk sizes to measure upload throughput with.
+const CHUNK_SIZES: &[usize] = &[64 * 1024, 1024 * 1024, 16 * 1024 * 1024];
+
+/// Formats the digest of `data`.
+fn digest_of(data: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(data)))
+}
+
+/// Sends a request, returning the response with its body fully read.
+async fn send(service: &RouterIntoService<Body>, request: Request<Body>) -> Response<Body> {
+    let response = service
+        .clone()
+        .oneshot(request)
+        .await
+        .expect("service failed");
+
+    let (parts, body) = response.into_parts();
+    let body = body
+        .collect()
+        .await
+        .expect("failed to read response body")
+        .to_bytes();
+
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Starts a new upload, returning its location.
+async fn begin_upload(service: &RouterIntoService<Body>) -> String {
+    let response = send(
+        service,
+        Request::post("/v2/bench/sample/blobs/uploads/")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    response
+        .headers()
+        .get(LOCATION)
+        .expect("missing upload location")
+        .to_str()
+        .unwrap()
+        .to_owned()
+}
+
+/// Uploads a blob in a single chunk.
+async fn push_blob(service: &RouterIntoService<Body>, data: &[u8], digest: &str) {
+    let location = begin_upload(service).await;
+
+    let response = send(
+        service,
+        Request::patch(&location)
+            .header(CONTENT_LENGTH, data.len())
+            .header(CONTENT_RANGE, format!("0-{}", data.len() - 1))
+            .body(Body::from(data.to_vec()))
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    let response = send(
+        service,
+        Request::put(format!("{location}?digest={digest}"))
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+}
+
+/// Creates a registry with the fixture image pushed as `bench/sample:latest`.
+fn registry_with_image(runtime: &Runtime) -> (TestingContainerRegistry, RouterIntoService<Body>) {
+    let ctx = ContainerRegistry::builder()
+        .memory_storage()
+        .build_for_testing();
+    let service = ctx.make_service();
+
+    runtime.block_on(async {
+        push_blob(&service, RAW_IMAGE, &digest_of(RAW_IMAGE)).await;
+
+        let response = send(
+            &service,
+            Request::put("/v2/bench/sample/manifests/latest")
+                .body(Body::from(RAW_MANIFEST))
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+    });
+
+    (ctx, service)
+}
+
+fn chunk_upload(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("failed to create runtime");
+    let (_ctx, service) = registry_with_image(&runtime);
+
+    let mut group = c.benchmark_group("chunk_upload");
+    for &size in CHUNK_SIZES {
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let digest = digest_of(&data);
+
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &data, |b, data| {
+            b.to_async(&runtime)
+                .iter(|| push_blob(&service, data, &digest));
+        });
+    }
+    group.finish();
+}
+
+fn manifests(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("failed to create runtime");
+    let (_ctx, service) = registry_with_image(&runtime);
+
+    let mut group = c.benchmark_group("manifest");
+    group.bench_function("put", |b| {
+        b.to_async(&runtime).iter(|| async {
+            let response = send(
+                &service,
+                Request::put("/v2/bench/sample/manifests/latest")
+                    .body(Body::from(RAW_MANIFEST))
+                    .unwrap(),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+        });
+    });
+    group.bench_function("get", |b| {
+        b.to_async(&runtime).iter(|| async {
+            let response = send(
+                &service,
+                Request::get("/v2/bench/sample/manifests/latest")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::OK);
+        });
+    });
+    group.finish();
+}
+
+fn router(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("failed to create runtime");
+    let (_ctx, service) = registry_with_image(&runtime);
+
+    // The index endpoint does not touch storage, measuring routing, authentication and tracing.
+    c.bench_function("router/index", |b| {
+        b.to_async(&runtime).iter(|| async {
+            let response = send(&service, Request::get("/v2/").body(Body::empty()).unwrap()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        });
+    });
+}
+
+criterion_group!(benches, chunk_upload, manifests, router);
+criterion_main!(benches);
@@ -0,0 +1,7 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|raw: &str| {
+    container_registry::fuzzing::image_digest(raw);
+});
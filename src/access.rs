@@ -0,0 +1,424 @@
+//! Structured access records.
+//!
+//! With [`ContainerRegistryBuilder::access_records`](crate::ContainerRegistryBuilder::access_records)
+//! enabled, every request to a repository's API, i.e. pulling or pushing manifests and blobs and
+//! listing tags or referrers, is reported to
+//! [`RegistryHooks::on_access`](crate::hooks::RegistryHooks::on_access) as an [`AccessRecord`].
+//! Records carry the image, the authenticated user, the bytes transferred and the response status,
+//! so embedders can e.g. bill or charge back usage per repository without parsing HTTP logs.
+//!
+//! A record is emitted once the response body has been sent completely, or the client went away,
+//! so its byte counts and latency cover the whole transfer. Requests to other endpoints, e.g. the
+//! catalog or the administrative API, are not recorded.
+//!
+//! On busy registries, records can be sampled, see [`AccessSampling`]. Every record carries the
+//! number of requests it stands for as its [`weight`](AccessRecord::weight), so totals can still
+//! be estimated by summing weighted values.
+
+use std::{
+    num::NonZeroU32,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use http_body::{Frame, SizeHint};
+
+use crate::{storage::ImageLocation, ContainerRegistry};
+
+/// Which requests to record.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AccessSampling {
+    /// Record every request.
+    All,
+    /// Record one in the given number of requests, counting across all repositories.
+    OneIn(NonZeroU32),
+}
+
+/// What a recorded request did.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AccessAction {
+    /// Downloaded a manifest.
+    PullManifest,
+    /// Checked whether a manifest exists.
+    CheckManifest,
+    /// Uploaded a manifest.
+    PushManifest,
+    /// Downloaded a blob.
+    PullBlob,
+    /// Checked whether a blob exists.
+    CheckBlob,
+    /// Started, continued, finished or checked a blob upload.
+    PushBlob,
+    /// Listed tags.
+    ListTags,
+    /// Listed referrers.
+    ListReferrers,
+}
+
+impl AccessAction {
+    /// Classifies a request by its method and the path below `/v2/<repository>/<image>/`.
+    fn classify(method: &Method, rest: &str) -> Option<Self> {
+        let resource = rest.split('/').next()?;
+        let action = match (resource, method) {
+            ("manifests", &Method::GET) => AccessAction::PullManifest,
+            ("manifests", &Method::HEAD) => AccessAction::CheckManifest,
+            ("manifests", &Method::PUT) => AccessAction::PushManifest,
+            ("blobs", _) if rest.starts_with("blobs/uploads") => AccessAction::PushBlob,
+            ("blobs", &Method::GET) => AccessAction::PullBlob,
+            ("blobs", &Method::HEAD) => AccessAction::CheckBlob,
+            ("uploads", _) => AccessAction::PushBlob,
+            ("tags", &Method::GET) => AccessAction::ListTags,
+            ("referrers", &Method::GET) => AccessAction::ListReferrers,
+            _ => return None,
+        };
+        Some(action)
+    }
+}
+
+/// A request to a repository, see the [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct AccessRecord {
+    /// Image the request concerned.
+    pub location: ImageLocation,
+    /// What the request did.
+    pub action: AccessAction,
+    /// Name of the authenticated user, if known.
+    ///
+    /// `None` for requests that were not authenticated, including requests refused for missing
+    /// credentials and blob downloads through [signed CDN URLs](crate::cdn).
+    pub username: Option<String>,
+    /// Status of the response.
+    pub status: StatusCode,
+    /// Bytes of the request body read by the registry.
+    pub bytes_received: u64,
+    /// Bytes of the response body sent.
+    pub bytes_sent: u64,
+    /// Time from receiving the request until the response body was sent.
+    pub latency: Duration,
+    /// Number of requests this record stands for, `1` unless sampled.
+    pub weight: u32,
+}
+
+/// Sampling state of access records.
+#[derive(Debug)]
+pub(crate) struct AccessLog {
+    /// Which requests to record.
+    sampling: AccessSampling,
+    /// Number of requests to repositories seen so far.
+    seen: AtomicU64,
+}
+
+impl AccessLog {
+    /// Creates a new access log sampling requests as given.
+    pub(crate) fn new(sampling: AccessSampling) -> Self {
+        Self {
+            sampling,
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the weight of the next request's record, `None` if it is not to be recorded.
+    fn sample(&self) -> Option<u32> {
+        match self.sampling {
+            AccessSampling::All => Some(1),
+            AccessSampling::OneIn(n) => {
+                let seen = self.seen.fetch_add(1, Ordering::Relaxed);
+                seen.is_multiple_of(u64::from(n.get())).then_some(n.get())
+            }
+        }
+    }
+}
+
+/// The user a request was authenticated as, filled in by the credentials extractor.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AccessUser(Arc<Mutex<Option<String>>>);
+
+impl AccessUser {
+    /// Records the user the request was authenticated as.
+    pub(crate) fn set(&self, username: Option<&str>) {
+        *self.0.lock().expect("lock poisoned") = username.map(ToOwned::to_owned);
+    }
+
+    /// Returns the recorded user.
+    fn get(&self) -> Option<String> {
+        self.0.lock().expect("lock poisoned").clone()
+    }
+}
+
+/// Middleware recording requests to repositories, if enabled.
+pub(crate) async fn record_access(
+    State(registry): State<Arc<ContainerRegistry>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(ref log) = registry.access_log else {
+        return next.run(request).await;
+    };
+    let Some((location, action)) = parse_request(request.method(), request.uri().path()) else {
+        return next.run(request).await;
+    };
+    let Some(weight) = log.sample() else {
+        return next.run(request).await;
+    };
+
+    let started = Instant::now();
+    let user = AccessUser::default();
+    let received = Arc::new(AtomicU64::new(0));
+    request.extensions_mut().insert(user.clone());
+    let request = request.map(|body| {
+        Body::new(Counted {
+            inner: body,
+            count: received.clone(),
+        })
+    });
+
+    let response = next.run(request).await;
+    let status = response.status();
+    response.map(|body| {
+        Body::new(Recording {
+            inner: body,
+            sent: 0,
+            pending: Some(Pending {
+                registry: registry.clone(),
+                location,
+                action,
+                user,
+                status,
+                received,
+                started,
+                weight,
+            }),
+        })
+    })
+}
+
+/// Extracts the image and action from a request, `None` if it does not concern a repository.
+fn parse_request(method: &Method, path: &str) -> Option<(ImageLocation, AccessAction)> {
+    let mut segments = path.strip_prefix("/v2/")?.splitn(3, '/');
+    let repository = segments.next().filter(|name| !name.starts_with('_'))?;
+    let image = segments.next()?;
+    let action = AccessAction::classify(method, segments.next()?)?;
+
+    Some((
+        ImageLocation::new(repository.to_owned(), image.to_owned()),
+        action,
+    ))
+}
+
+/// A request body counting the bytes read from it.
+struct Counted {
+    /// The wrapped body.
+    inner: Body,
+    /// Bytes read so far.
+    count: Arc<AtomicU64>,
+}
+
+impl http_body::Body for Counted {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let polled = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(ref frame))) = polled {
+            if let Some(data) = frame.data_ref() {
+                self.count.fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Everything needed to emit a record, apart from the bytes sent.
+struct Pending {
+    /// The registry to report to.
+    registry: Arc<ContainerRegistry>,
+    /// Image the request concerned.
+    location: ImageLocation,
+    /// What the request did.
+    action: AccessAction,
+    /// The user the request was authenticated as.
+    user: AccessUser,
+    /// Status of the response.
+    status: StatusCode,
+    /// Bytes of the request body read.
+    received: Arc<AtomicU64>,
+    /// Time the request was received.
+    started: Instant,
+    /// Number of requests the record stands for.
+    weight: u32,
+}
+
+/// A response body emitting the access record once it is done or dropped.
+struct Recording {
+    /// The wrapped body.
+    inner: Body,
+    /// Bytes sent so far.
+    sent: u64,
+    /// The record to emit, `None` once emitted.
+    pending: Option<Pending>,
+}
+
+impl Recording {
+    /// Reports the record to hooks in the background, unless already done.
+    fn emit(&mut self) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        let record = AccessRecord {
+            location: pending.location,
+            action: pending.action,
+            username: pending.user.get(),
+            status: pending.status,
+            bytes_received: pending.received.load(Ordering::Relaxed),
+            bytes_sent: self.sent,
+            latency: pending.started.elapsed(),
+            weight: pending.weight,
+        };
+
+        // Bodies may be dropped outside of a runtime, e.g. when shutting down.
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let registry = pending.registry;
+            runtime.spawn(async move {
+                registry
+                    .run_hook("on_access", registry.hooks.on_access(&record))
+                    .await;
+            });
+        }
+    }
+}
+
+impl http_body::Body for Recording {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let polled = Pin::new(&mut self.inner).poll_frame(cx);
+        match polled {
+            Poll::Ready(Some(Ok(ref frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    self.sent += data.len() as u64;
+                }
+            }
+            Poll::Ready(None) => self.emit(),
+            _ => {}
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        self.emit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use axum::http::Method;
+
+    use super::{parse_request, AccessAction, AccessLog, AccessSampling};
+
+    #[test]
+    fn requests_are_classified() {
+        let cases = [
+            (
+                Method::GET,
+                "/v2/foo/bar/manifests/latest",
+                AccessAction::PullManifest,
+            ),
+            (
+                Method::HEAD,
+                "/v2/foo/bar/manifests/latest",
+                AccessAction::CheckManifest,
+            ),
+            (
+                Method::PUT,
+                "/v2/foo/bar/manifests/latest",
+                AccessAction::PushManifest,
+            ),
+            (
+                Method::GET,
+                "/v2/foo/bar/blobs/sha256:00",
+                AccessAction::PullBlob,
+            ),
+            (
+                Method::HEAD,
+                "/v2/foo/bar/blobs/sha256:00",
+                AccessAction::CheckBlob,
+            ),
+            (
+                Method::POST,
+                "/v2/foo/bar/blobs/uploads/",
+                AccessAction::PushBlob,
+            ),
+            (
+                Method::PATCH,
+                "/v2/foo/bar/uploads/1234",
+                AccessAction::PushBlob,
+            ),
+            (Method::GET, "/v2/foo/bar/tags/list", AccessAction::ListTags),
+            (
+                Method::GET,
+                "/v2/foo/bar/referrers/sha256:00",
+                AccessAction::ListReferrers,
+            ),
+        ];
+        for (method, path, action) in cases {
+            let (location, classified) = parse_request(&method, path).expect(path);
+            assert_eq!(location.to_string(), "foo/bar");
+            assert_eq!(classified, action, "{method} {path}");
+        }
+
+        assert!(parse_request(&Method::GET, "/v2/").is_none());
+        assert!(parse_request(&Method::GET, "/v2/_catalog").is_none());
+        assert!(parse_request(&Method::GET, "/v2/_events").is_none());
+        assert!(parse_request(&Method::GET, "/admin/foo/bar/manifests").is_none());
+        assert!(parse_request(&Method::DELETE, "/v2/foo/bar/tags/list").is_none());
+    }
+
+    #[test]
+    fn records_are_sampled() {
+        let log = AccessLog::new(AccessSampling::OneIn(NonZeroU32::new(3).unwrap()));
+        let weights: Vec<_> = (0..7).map(|_| log.sample()).collect();
+        assert_eq!(weights, [Some(3), None, None, Some(3), None, None, Some(3)]);
+
+        let log = AccessLog::new(AccessSampling::All);
+        assert!((0..3).all(|_| log.sample() == Some(1)));
+    }
+}
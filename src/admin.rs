@@ -0,0 +1,928 @@
+//! Administrative API.
+//!
+//! Exposes operations that are not part of the OCI distribution spec, but useful when operating a
+//! registry, e.g. listing untagged manifests, retagging or promoting images without re-uploading
+//! them, rolling back [tags](crate::tag_history), [fetching](crate::fetch) blobs from remote URLs,
+//! attaching SBOMs, listing the manifests referencing a blob, reviewing
+//! [quarantined](crate::quarantine) manifests, [creating](crate::repositories), fingerprinting
+//! and [purging](crate::purge) repositories, issuing pull tokens, running maintenance tasks as
+//! long-running [`operations`](crate::operations) or redelivering [webhooks](crate::webhooks).
+//! All routes are mounted below `/admin/` and are subject to the same authentication and
+//! authorization as the regular API.
+//!
+//! The administrative API is part of the registry's router by default. It can instead be served
+//! on its own, e.g. on an internal-only listener, through
+//! [`ContainerRegistry::make_admin_router`], which additionally serves
+//!
+//! * `GET /metrics`: The registry's [`metrics`](crate::metrics) in the Prometheus text exposition
+//!   format, and
+//! * `GET /health`: A liveness check answering `200 OK` while the registry is running.
+//!
+//! Both do not require authentication, as they are only meant to be reachable internally.
+
+use std::{
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{
+        header::{CONTENT_TYPE, HOST, LOCATION},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    api_error::ApiError,
+    auth::{MissingPermission, Permissions, ValidCredentials},
+    headers::RegistryHeaders,
+    maintenance::{
+        self, GarbageCollection, IntegrityCheck, Retention, StaleUploadCleanup, StorageUsage,
+    },
+    mk_blob_location, mk_manifest_location,
+    operations::{Operation, OperationStatus},
+    purge::{PurgePlan, PurgeSummary},
+    quarantine::Quarantine,
+    repositories::RepositoryFingerprint,
+    storage::{self, ImageLocation, ManifestReference, Reference},
+    tag_history::TagHistoryEntry,
+    tokens::TokenCreds,
+    types::Manifest,
+    webhooks::WebhookDelivery,
+    ContainerRegistry, ImageDigest, RegistryError,
+};
+
+/// Returns the routes of the administrative API.
+pub(crate) fn routes() -> Router<Arc<ContainerRegistry>> {
+    Router::new()
+        .route("/admin/:repository/:image/tags/:tag", put(tag_put))
+        .route(
+            "/admin/:repository/:image/tags/:tag/history",
+            get(tag_history_get),
+        )
+        .route(
+            "/admin/:repository/:image/tags/:tag/rollback",
+            post(tag_rollback_post),
+        )
+        .route(
+            "/admin/:repository/:image/promotions",
+            post(promotions_post),
+        )
+        .route(
+            "/admin/:repository/:image/blobs/fetch",
+            post(blob_fetch_post),
+        )
+        .route("/admin/:repository/:image/manifests", get(manifests_get))
+        .route(
+            "/admin/:repository/:image/manifests/:digest/sbom",
+            post(sbom_post),
+        )
+        .route("/admin/:repository/:image/quarantine", get(quarantine_get))
+        .route(
+            "/admin/:repository/:image/quarantine/:digest/release",
+            post(release_post),
+        )
+        .route("/admin/repositories/:repository", put(repository_put))
+        .route("/admin/repositories/:repository", delete(repository_delete))
+        .route("/admin/repositories/:repository/purge", post(purge_post))
+        .route(
+            "/admin/repositories/:repository/fingerprint",
+            get(fingerprint_get),
+        )
+        .route("/admin/blobs/:digest/referrers", get(blob_referrers_get))
+        .route("/admin/tokens", post(tokens_post))
+        .route(
+            "/admin/operations",
+            get(operations_get).post(operations_post),
+        )
+        .route("/admin/operations/:id", get(operation_get))
+        .route("/admin/operations/:id", delete(operation_delete))
+        .route("/admin/webhooks/deliveries", get(webhook_deliveries_get))
+        .route(
+            "/admin/webhooks/deliveries/:id/redeliver",
+            post(webhook_redeliver_post),
+        )
+}
+
+/// Returns the routes only served by the administrative router, see the [module
+/// documentation](self).
+pub(crate) fn internal_routes() -> Router<Arc<ContainerRegistry>> {
+    Router::new()
+        .route("/metrics", get(metrics_get))
+        .route("/health", get(health_get))
+}
+
+/// Renders the registry's metrics for Prometheus.
+async fn metrics_get(State(registry): State<Arc<ContainerRegistry>>) -> Response {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        registry.metrics().render_prometheus(),
+    )
+        .into_response()
+}
+
+/// Reports the registry as alive.
+async fn health_get() -> &'static str {
+    "ok"
+}
+
+/// Target of a tag update.
+#[derive(Debug, Deserialize)]
+struct TagTarget {
+    /// Digest of the manifest the tag should point to, may be abbreviated.
+    digest: String,
+}
+
+/// Points a tag at an existing manifest.
+async fn tag_put(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path((repository, image, tag)): Path<(String, String, String)>,
+    creds: ValidCredentials,
+    Json(TagTarget { digest }): Json<TagTarget>,
+) -> Result<Response<Body>, ApiError> {
+    let location = ImageLocation::new(repository, image);
+
+    registry
+        .auth_provider_for(&creds)
+        .image_permissions(&creds, &location)
+        .await
+        .require_write()?;
+
+    let digest = registry.resolve_digest(&digest).await?;
+
+    // Tagging links the manifest's blobs, pulling in blobs of other repositories is up to
+    // administrators once they are scoped.
+    if registry.scope_blobs {
+        let reference = ManifestReference::new(location.clone(), Reference::new_digest(digest));
+        if let Some(raw) = registry.storage.get_manifest(&reference).await? {
+            if let Ok(manifest) = Manifest::from_slice(&raw) {
+                if registry
+                    .unlinked_blob(&location, &manifest)
+                    .await?
+                    .is_some()
+                {
+                    require_admin(&registry, &creds, Permissions::require_write).await?;
+                }
+            }
+        }
+    }
+
+    registry.put_tag(&location, &tag, digest).await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .header(
+            LOCATION,
+            mk_manifest_location(&location, &Reference::new_tag(tag)),
+        )
+        .body(Body::empty())?)
+}
+
+/// Previous manifests of a tag.
+#[derive(Debug, Serialize)]
+struct TagHistoryList {
+    /// Previous manifests, most recent first.
+    history: Vec<TagHistoryEntry>,
+}
+
+/// Lists the previous manifests of a tag, see the [`tag_history`](crate::tag_history) module.
+async fn tag_history_get(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path((repository, image, tag)): Path<(String, String, String)>,
+    creds: ValidCredentials,
+) -> Result<Json<TagHistoryList>, ApiError> {
+    let location = ImageLocation::new(repository, image);
+
+    registry
+        .auth_provider_for(&creds)
+        .image_permissions(&creds, &location)
+        .await
+        .require_read()?;
+
+    let history = registry.tag_history(&location, &tag).await?;
+    Ok(Json(TagHistoryList { history }))
+}
+
+/// Points a tag back at its previous manifest, see [`ContainerRegistry::rollback_tag`].
+async fn tag_rollback_post(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path((repository, image, tag)): Path<(String, String, String)>,
+    creds: ValidCredentials,
+) -> Result<Response<Body>, ApiError> {
+    let location = ImageLocation::new(repository, image);
+
+    registry
+        .auth_provider_for(&creds)
+        .image_permissions(&creds, &location)
+        .await
+        .require_write()?;
+
+    let digest = registry.rollback_tag(&location, &tag).await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .header(
+            LOCATION,
+            mk_manifest_location(&location, &Reference::new_tag(tag)),
+        )
+        .docker_content_digest(digest)
+        .body(Body::empty())?)
+}
+
+/// Request to promote a manifest.
+#[derive(Debug, Deserialize)]
+struct Promotion {
+    /// The manifest to promote, e.g. `staging/app:1.2.3` or `staging/app@sha256:...`. Digests may
+    /// be abbreviated.
+    source: String,
+    /// Tag to promote the manifest as.
+    tag: String,
+}
+
+/// Copies a manifest from another location, see [`ContainerRegistry::promote`].
+///
+/// Requires read access to the source and write access to the destination.
+async fn promotions_post(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(location): Path<ImageLocation>,
+    creds: ValidCredentials,
+    Json(Promotion { source, tag }): Json<Promotion>,
+) -> Result<Response<Body>, ApiError> {
+    // Digests may be abbreviated, they are only resolved once access has been checked.
+    let parsed = match source.split_once('@') {
+        Some((source_location, digest)) => source_location
+            .parse::<ImageLocation>()
+            .ok()
+            .map(|source_location| (source_location, Some(digest))),
+        None => source
+            .parse::<ManifestReference>()
+            .ok()
+            .map(|source| (source.location().clone(), None)),
+    };
+    let Some((source_location, digest)) = parsed else {
+        return Ok((StatusCode::BAD_REQUEST, "invalid promotion source").into_response());
+    };
+
+    registry
+        .auth_provider_for(&creds)
+        .image_permissions(&creds, &source_location)
+        .await
+        .require_read()?;
+    registry
+        .auth_provider_for(&creds)
+        .image_permissions(&creds, &location)
+        .await
+        .require_write()?;
+
+    let source = match digest {
+        Some(digest) => ManifestReference::new(
+            source_location,
+            Reference::new_digest(registry.resolve_digest(digest).await?),
+        ),
+        None => source
+            .parse()
+            .expect("source should have been parsed before"),
+    };
+
+    let digest = registry.promote(&source, &location, &tag).await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .header(
+            LOCATION,
+            mk_manifest_location(&location, &Reference::new_tag(tag)),
+        )
+        .docker_content_digest(digest)
+        .body(Body::empty())?)
+}
+
+/// A blob to fetch from a remote URL.
+#[derive(Debug, Deserialize)]
+struct BlobSource {
+    /// URL to download the blob from.
+    url: String,
+    /// Digest the blob is expected to have.
+    digest: ImageDigest,
+}
+
+/// Downloads a blob, see [`ContainerRegistry::fetch_blob_from_url`].
+async fn blob_fetch_post(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(location): Path<ImageLocation>,
+    creds: ValidCredentials,
+    Json(BlobSource { url, digest }): Json<BlobSource>,
+) -> Result<Response<Body>, ApiError> {
+    // Fetching makes the registry send requests on the caller's behalf.
+    require_admin(&registry, &creds, Permissions::require_write).await?;
+
+    let digest = registry
+        .fetch_blob_from_url(&location, &url, digest.digest)
+        .await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .header(LOCATION, mk_blob_location(&location, digest))
+        .docker_content_digest(digest)
+        .body(Body::empty())?)
+}
+
+/// A manifest stored at a location.
+#[derive(Debug, Serialize)]
+struct ManifestEntry {
+    /// Digest of the manifest.
+    digest: ImageDigest,
+    /// Time the manifest was first stored at the location, in seconds since the Unix epoch.
+    created: u64,
+    /// Tags pointing to the manifest.
+    tags: Vec<String>,
+    /// Total compressed size of the image's layers, in bytes. Absent for indices.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    /// Number of layers of the image. Absent for indices.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    layers: Option<usize>,
+}
+
+/// Listing of all manifests at a location.
+#[derive(Debug, Serialize)]
+struct ManifestList {
+    manifests: Vec<ManifestEntry>,
+}
+
+/// Lists all manifests at a location, including untagged ones.
+async fn manifests_get(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(location): Path<ImageLocation>,
+    creds: ValidCredentials,
+) -> Result<Json<ManifestList>, ApiError> {
+    registry
+        .auth_provider_for(&creds)
+        .image_permissions(&creds, &location)
+        .await
+        .require_read()?;
+
+    let mut manifests: Vec<_> = registry
+        .storage
+        .list_manifests(&location)
+        .await?
+        .into_iter()
+        .map(|manifest| ManifestEntry {
+            digest: ImageDigest::new(manifest.digest),
+            created: manifest
+                .created
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            tags: manifest.tags,
+            size: manifest.summary.map(|summary| summary.size),
+            layers: manifest.summary.map(|summary| summary.layers),
+        })
+        .collect();
+    manifests.sort_by_key(|manifest| manifest.created);
+
+    Ok(Json(ManifestList { manifests }))
+}
+
+/// A quarantined manifest.
+#[derive(Debug, Serialize)]
+struct QuarantineEntry {
+    /// Digest of the manifest.
+    digest: ImageDigest,
+    /// Tags applied once the manifest is released.
+    tags: Vec<String>,
+}
+
+/// Listing of the quarantined manifests at a location.
+#[derive(Debug, Serialize)]
+struct QuarantineList {
+    manifests: Vec<QuarantineEntry>,
+}
+
+/// Returns the quarantine, if the caller is allowed to review quarantined manifests.
+fn require_reviewer<'a>(
+    registry: &'a ContainerRegistry,
+    creds: &ValidCredentials,
+) -> Result<&'a Quarantine, ApiError> {
+    let quarantine = registry
+        .quarantine
+        .as_ref()
+        .ok_or(RegistryError::NotFound)?;
+    if !quarantine.is_reviewer(creds) {
+        return Err(MissingPermission.into());
+    }
+    Ok(quarantine)
+}
+
+/// Lists the quarantined manifests at a location, see the [`quarantine`](crate::quarantine)
+/// module.
+async fn quarantine_get(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(location): Path<ImageLocation>,
+    creds: ValidCredentials,
+) -> Result<Json<QuarantineList>, ApiError> {
+    registry
+        .auth_provider_for(&creds)
+        .image_permissions(&creds, &location)
+        .await
+        .require_read()?;
+    let quarantine = require_reviewer(&registry, &creds)?;
+
+    let manifests = quarantine
+        .list(&location)
+        .await
+        .map_err(|err| RegistryError::Storage(storage::Error::Io(err)))?
+        .into_iter()
+        .map(|(digest, held)| QuarantineEntry {
+            digest: ImageDigest::new(digest),
+            tags: held.tags,
+        })
+        .collect();
+
+    Ok(Json(QuarantineList { manifests }))
+}
+
+/// Releases a quarantined manifest, see [`ContainerRegistry::release`].
+async fn release_post(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path((repository, image, digest)): Path<(String, String, ImageDigest)>,
+    creds: ValidCredentials,
+) -> Result<Response<Body>, ApiError> {
+    let location = ImageLocation::new(repository, image);
+
+    registry
+        .auth_provider_for(&creds)
+        .image_permissions(&creds, &location)
+        .await
+        .require_write()?;
+    require_reviewer(&registry, &creds)?;
+
+    registry.release(&location, digest.digest).await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())?)
+}
+
+/// Checks that the caller may delete from every image of a repository.
+///
+/// Repositories without images have nothing to check permissions on, so only administrators may
+/// remove them.
+async fn require_repository_access(
+    registry: &ContainerRegistry,
+    creds: &ValidCredentials,
+    repository: &str,
+) -> Result<(), ApiError> {
+    let locations = registry.repository_locations(repository).await?;
+    if locations.is_empty() {
+        return require_admin(registry, creds, Permissions::require_delete).await;
+    }
+
+    for location in locations {
+        registry
+            .auth_provider_for(creds)
+            .image_permissions(creds, &location)
+            .await
+            .require_delete()?;
+    }
+    Ok(())
+}
+
+/// Prepares the removal of a repository, see [`ContainerRegistry::prepare_purge`].
+async fn purge_post(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(repository): Path<String>,
+    creds: ValidCredentials,
+) -> Result<Json<PurgePlan>, ApiError> {
+    require_repository_access(&registry, &creds, &repository).await?;
+
+    Ok(Json(registry.prepare_purge(&repository).await?))
+}
+
+/// Computes a fingerprint of a repository, see [`ContainerRegistry::repository_fingerprint`].
+async fn fingerprint_get(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(repository): Path<String>,
+    creds: ValidCredentials,
+) -> Result<Json<RepositoryFingerprint>, ApiError> {
+    for location in registry.repository_locations(&repository).await? {
+        registry
+            .auth_provider_for(&creds)
+            .image_permissions(&creds, &location)
+            .await
+            .require_read()?;
+    }
+
+    Ok(Json(registry.repository_fingerprint(&repository).await?))
+}
+
+/// Confirmation of a repository removal.
+#[derive(Debug, Deserialize)]
+struct PurgeConfirmation {
+    /// Token of the plan returned when preparing the removal.
+    confirm: String,
+}
+
+/// Creates a repository, see [`ContainerRegistry::create_repository`].
+///
+/// Answers `201 Created` if the repository did not exist before, `200 OK` otherwise.
+async fn repository_put(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(repository): Path<String>,
+    creds: ValidCredentials,
+) -> Result<StatusCode, ApiError> {
+    // The repository does not hold any images yet to check permissions on, claiming a name is up
+    // to administrators.
+    require_admin(&registry, &creds, Permissions::require_write).await?;
+
+    if registry.create_repository(&repository).await? {
+        Ok(StatusCode::CREATED)
+    } else {
+        Ok(StatusCode::OK)
+    }
+}
+
+/// Removes a repository, see [`ContainerRegistry::purge_repository`].
+async fn repository_delete(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(repository): Path<String>,
+    Query(PurgeConfirmation { confirm }): Query<PurgeConfirmation>,
+    creds: ValidCredentials,
+) -> Result<Json<PurgeSummary>, ApiError> {
+    require_repository_access(&registry, &creds, &repository).await?;
+
+    Ok(Json(
+        registry
+            .purge_repository_as(&repository, &confirm, creds.username())
+            .await?,
+    ))
+}
+
+/// An image manifest referencing a blob.
+#[derive(Debug, Serialize)]
+struct BlobReferrerEntry {
+    /// Repository the manifest is stored in.
+    repository: String,
+    /// Image the manifest is stored in.
+    image: String,
+    /// Digest of the manifest.
+    manifest: ImageDigest,
+    /// Tags pointing to the manifest.
+    tags: Vec<String>,
+    /// Whether the blob is the image's config, rather than one of its layers.
+    config: bool,
+}
+
+/// Listing of the manifests referencing a blob.
+#[derive(Debug, Serialize)]
+struct BlobReferrerList {
+    referrers: Vec<BlobReferrerEntry>,
+}
+
+/// Lists the image manifests referencing a blob, e.g. to assess the impact of deleting a layer.
+///
+/// Only locations the user may read are included.
+async fn blob_referrers_get(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(digest): Path<ImageDigest>,
+    creds: ValidCredentials,
+) -> Result<Json<BlobReferrerList>, ApiError> {
+    let mut referrers = Vec::new();
+    for referrer in registry.storage.blob_referrers(digest.digest).await? {
+        let readable = registry
+            .auth_provider_for(&creds)
+            .image_permissions(&creds, &referrer.location)
+            .await
+            .require_read()
+            .is_ok();
+        if !readable {
+            continue;
+        }
+
+        referrers.push(BlobReferrerEntry {
+            repository: referrer.location.repository().to_owned(),
+            image: referrer.location.image().to_owned(),
+            manifest: ImageDigest::new(referrer.manifest),
+            tags: referrer.tags,
+            config: referrer.is_config,
+        });
+    }
+
+    Ok(Json(BlobReferrerList { referrers }))
+}
+
+/// Attaches an SBOM to an existing manifest.
+///
+/// The request body is the SBOM document, its media type is taken from the `Content-Type`
+/// header.
+async fn sbom_post(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path((repository, image, digest)): Path<(String, String, String)>,
+    creds: ValidCredentials,
+    headers: HeaderMap,
+    document: Bytes,
+) -> Result<Response<Body>, ApiError> {
+    let location = ImageLocation::new(repository, image);
+
+    registry
+        .auth_provider_for(&creds)
+        .image_permissions(&creds, &location)
+        .await
+        .require_write()?;
+
+    let Some(media_type) = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return Err(ApiError::MissingSbomMediaType);
+    };
+
+    let digest = registry.resolve_digest(&digest).await?;
+    let sbom = registry
+        .attach_sbom(&location, digest, media_type, &document)
+        .await?;
+
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .header(
+            LOCATION,
+            mk_manifest_location(&location, &Reference::new_digest(sbom)),
+        )
+        .docker_content_digest(sbom)
+        .body(Body::empty())?)
+}
+
+/// Request for a pull token.
+#[derive(Debug, Deserialize)]
+struct TokenRequest {
+    /// Locations to grant read access to, as `repository/image`.
+    scope: Vec<String>,
+    /// Requested lifetime in seconds, capped by the issuer.
+    expires_in: Option<u64>,
+}
+
+/// Output options for a pull token.
+#[derive(Debug, Deserialize)]
+struct TokenOutput {
+    /// Name of a Kubernetes secret to render instead of returning the bare token.
+    secret: Option<String>,
+}
+
+/// Issues a pull token to the calling user.
+///
+/// The caller needs read access to every location in the requested scope. Tokens cannot be used
+/// to issue further tokens.
+async fn tokens_post(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Query(output): Query<TokenOutput>,
+    creds: ValidCredentials,
+    headers: HeaderMap,
+    Json(request): Json<TokenRequest>,
+) -> Result<Response<Body>, ApiError> {
+    let Some(username) = creds.username() else {
+        return Ok((
+            StatusCode::FORBIDDEN,
+            "tokens can only be issued to named users",
+        )
+            .into_response());
+    };
+    if creds.try_extract_ref::<TokenCreds>().is_some() {
+        return Ok((StatusCode::FORBIDDEN, "tokens cannot issue further tokens").into_response());
+    }
+
+    let Ok(scope) = request
+        .scope
+        .iter()
+        .map(|location| location.parse())
+        .collect::<Result<Vec<ImageLocation>, _>>()
+    else {
+        return Ok((StatusCode::BAD_REQUEST, "invalid location in token scope").into_response());
+    };
+    if scope.is_empty() {
+        return Ok((StatusCode::BAD_REQUEST, "empty token scope").into_response());
+    }
+
+    for location in &scope {
+        registry
+            .auth_provider_for(&creds)
+            .image_permissions(&creds, location)
+            .await
+            .require_read()?;
+    }
+
+    let Some(token) = registry.issue_pull_token(
+        username,
+        &scope,
+        request.expires_in.map(Duration::from_secs),
+    ) else {
+        return Err(RegistryError::NotSupported("pull tokens").into());
+    };
+
+    let Some(name) = output.secret else {
+        return Ok(Json(token).into_response());
+    };
+
+    // The secret has to refer to the registry the way the cluster reaches it, i.e. as requested.
+    let Some(host) = headers.get(HOST).and_then(|value| value.to_str().ok()) else {
+        return Ok((StatusCode::BAD_REQUEST, "missing host header").into_response());
+    };
+
+    Ok(Json(token.kubernetes_secret(host, &name)).into_response())
+}
+
+/// Checks that the caller has the `required` administrative permission, for actions affecting the
+/// whole registry rather than single images, see
+/// [`AuthProvider::admin_permissions`](crate::auth::AuthProvider::admin_permissions).
+async fn require_admin(
+    registry: &ContainerRegistry,
+    creds: &ValidCredentials,
+    required: fn(Permissions) -> Result<(), MissingPermission>,
+) -> Result<(), ApiError> {
+    required(
+        registry
+            .auth_provider_for(creds)
+            .admin_permissions(creds)
+            .await,
+    )?;
+    Ok(())
+}
+
+/// Returns the [default grace period](maintenance::DEFAULT_GRACE_PERIOD) of garbage collections
+/// in seconds, for use as a serde default.
+fn default_grace_period() -> u64 {
+    maintenance::DEFAULT_GRACE_PERIOD.as_secs()
+}
+
+/// A maintenance task to run as an operation, named like the task.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "task", rename_all = "snake_case")]
+enum OperationRequest {
+    /// See [`GarbageCollection`].
+    GarbageCollection {
+        /// Grace period in seconds, an hour unless given.
+        #[serde(default = "default_grace_period")]
+        grace_period: u64,
+        /// See [`GarbageCollection::dangling_manifests`].
+        #[serde(default)]
+        dangling_manifests: bool,
+    },
+    /// See [`Retention`].
+    Retention {
+        /// Number of tags to keep per image.
+        keep_last: usize,
+        /// See [`Retention::index_children`].
+        #[serde(default)]
+        index_children: bool,
+    },
+    /// See [`IntegrityCheck`].
+    IntegrityCheck,
+    /// See [`StaleUploadCleanup`].
+    StaleUploadCleanup {
+        /// Maximum age in seconds.
+        max_age: u64,
+    },
+    /// See [`StorageUsage`].
+    StorageUsage,
+}
+
+impl OperationRequest {
+    /// Returns whether the task removes content, requiring delete access.
+    fn removes_content(&self) -> bool {
+        match self {
+            OperationRequest::GarbageCollection { .. }
+            | OperationRequest::Retention { .. }
+            | OperationRequest::StaleUploadCleanup { .. } => true,
+            OperationRequest::IntegrityCheck | OperationRequest::StorageUsage => false,
+        }
+    }
+}
+
+/// Listing of all operations.
+#[derive(Debug, Serialize)]
+struct OperationList {
+    operations: Vec<OperationStatus>,
+}
+
+/// Answers with the status of an operation.
+fn operation_response(status: StatusCode, operation: &Operation) -> Response<Body> {
+    let location = format!("/admin/operations/{}", operation.id());
+    (status, [(LOCATION, location)], Json(operation.status())).into_response()
+}
+
+/// Starts a maintenance task in the background.
+async fn operations_post(
+    State(registry): State<Arc<ContainerRegistry>>,
+    creds: ValidCredentials,
+    Json(request): Json<OperationRequest>,
+) -> Result<Response<Body>, ApiError> {
+    let required = if request.removes_content() {
+        Permissions::require_delete
+    } else {
+        Permissions::require_write
+    };
+    require_admin(&registry, &creds, required).await?;
+
+    let operation = match request {
+        OperationRequest::GarbageCollection {
+            grace_period,
+            dangling_manifests,
+        } => registry.start_operation(
+            GarbageCollection::new(Duration::from_secs(grace_period))
+                .dangling_manifests(dangling_manifests),
+        ),
+        OperationRequest::Retention {
+            keep_last,
+            index_children,
+        } => {
+            registry.start_operation(Retention::keep_last(keep_last).index_children(index_children))
+        }
+        OperationRequest::IntegrityCheck => registry.start_operation(IntegrityCheck::new()),
+        OperationRequest::StaleUploadCleanup { max_age } => {
+            registry.start_operation(StaleUploadCleanup::new(Duration::from_secs(max_age)))
+        }
+        OperationRequest::StorageUsage => registry.start_operation(StorageUsage::new()),
+    };
+
+    Ok(operation_response(StatusCode::ACCEPTED, &operation))
+}
+
+/// Lists running and recently finished operations.
+async fn operations_get(
+    State(registry): State<Arc<ContainerRegistry>>,
+    creds: ValidCredentials,
+) -> Result<Json<OperationList>, ApiError> {
+    require_admin(&registry, &creds, Permissions::require_write).await?;
+
+    Ok(Json(OperationList {
+        operations: registry
+            .operations()
+            .iter()
+            .map(Operation::status)
+            .collect(),
+    }))
+}
+
+/// Returns the status of an operation.
+async fn operation_get(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(id): Path<Uuid>,
+    creds: ValidCredentials,
+) -> Result<Json<OperationStatus>, ApiError> {
+    require_admin(&registry, &creds, Permissions::require_write).await?;
+
+    let operation = registry.operation(id).ok_or(RegistryError::NotFound)?;
+    Ok(Json(operation.status()))
+}
+
+/// Cancels an operation.
+///
+/// Cancellation takes effect at the task's next checkpoint, the operation is still running when
+/// the response is sent.
+async fn operation_delete(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(id): Path<Uuid>,
+    creds: ValidCredentials,
+) -> Result<Response<Body>, ApiError> {
+    require_admin(&registry, &creds, Permissions::require_write).await?;
+
+    let operation = registry.operation(id).ok_or(RegistryError::NotFound)?;
+    operation.cancel();
+    Ok(operation_response(StatusCode::ACCEPTED, &operation))
+}
+
+/// Listing of recent webhook deliveries.
+#[derive(Debug, Serialize)]
+struct WebhookDeliveryList {
+    deliveries: Vec<WebhookDelivery>,
+}
+
+/// Lists recent webhook deliveries, most recent first.
+async fn webhook_deliveries_get(
+    State(registry): State<Arc<ContainerRegistry>>,
+    creds: ValidCredentials,
+) -> Result<Json<WebhookDeliveryList>, ApiError> {
+    require_admin(&registry, &creds, Permissions::require_write).await?;
+
+    Ok(Json(WebhookDeliveryList {
+        deliveries: registry.webhook_deliveries(),
+    }))
+}
+
+/// Delivers a webhook again, see [`ContainerRegistry::redeliver_webhook`].
+///
+/// Answers with the delivery's state after the attempt.
+async fn webhook_redeliver_post(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(id): Path<Uuid>,
+    creds: ValidCredentials,
+) -> Result<Json<WebhookDelivery>, ApiError> {
+    require_admin(&registry, &creds, Permissions::require_write).await?;
+
+    Ok(Json(registry.redeliver_webhook(id).await?))
+}
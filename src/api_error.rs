@@ -0,0 +1,370 @@
+//! Mapping of errors to HTTP responses.
+//!
+//! Library APIs fail with [`RegistryError`], describing what went wrong independent of how the
+//! registry is accessed. Request handlers fail with [`ApiError`] instead, which adds the failures
+//! only requests can run into, e.g. malformed headers or misplaced upload chunks, and maps all of
+//! them to the statuses and OCI error codes clients expect.
+
+use std::{error::Error, io};
+
+use axum::{
+    body::Body,
+    http::{
+        header::{CONTENT_LENGTH, RANGE},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::{
+    auth::MissingPermission,
+    headers::{self, RegistryHeaders},
+    storage,
+    types::{self, OciError, OciErrors},
+    upload_range, RegistryError,
+};
+
+/// An error answering a request.
+///
+/// Errors have a "safe" [`IntoResponse`] implementation, thus can be returned straight to the user
+/// without security concerns.
+#[derive(Debug, Error)]
+pub(crate) enum ApiError {
+    /// The registry failed to carry out the request.
+    #[error(transparent)]
+    Registry(#[from] RegistryError),
+    /// Invalid integer supplied for content length.
+    #[error("error parsing content length")]
+    ContentLengthMalformed(#[source] Box<dyn Error + Send + Sync>),
+    /// Reading the request body failed.
+    #[error("failed to read incoming data stream")]
+    IncomingReadFailed(#[source] axum::Error),
+    /// A digest given used an algorithm not supported by this registry.
+    #[error("unsupported digest algorithm {0}")]
+    UnsupportedDigestAlgorithm(String),
+    /// An upload was finished without giving its digest.
+    #[error("missing digest")]
+    MissingDigest,
+    /// An upload does not exist, or belongs to another user or location.
+    #[error("upload unknown")]
+    UploadUnknown,
+    /// The user has too many upload sessions open.
+    #[error("too many concurrent upload sessions")]
+    TooManyUploadSessions,
+    /// The request body uses an unsupported `Content-Encoding`.
+    #[error("unsupported content encoding")]
+    UnsupportedContentEncoding,
+    /// A compressed request body could not be decompressed.
+    #[error("failed to decompress request body")]
+    DecompressionFailed(#[source] io::Error),
+    /// A request body exceeded the size limit.
+    #[error("payload too large")]
+    PayloadTooLarge,
+    /// A `Content-Range` header could not be parsed.
+    #[error("invalid content range")]
+    InvalidRange,
+    /// An upload chunk does not continue where the upload left off.
+    #[error("chunk does not start at offset {stored} of upload {upload}")]
+    UploadOffsetMismatch {
+        /// The upload.
+        upload: Uuid,
+        /// Number of bytes stored for the upload.
+        stored: u64,
+    },
+    /// An upload chunk does not match the digest sent along with it, see [`crate::checksums`].
+    #[error("chunk does not match its digest, upload {upload} has {stored} bytes stored")]
+    ChunkDigestMismatch {
+        /// The upload.
+        upload: Uuid,
+        /// Number of bytes stored for the upload, without the discarded chunk.
+        stored: u64,
+    },
+    /// A manifest's `Content-Type` does not match its `mediaType` field.
+    #[error("manifest media type {declared} does not match content type {embedded}")]
+    ManifestTypeMismatch {
+        /// Media type given in the `Content-Type` header.
+        declared: String,
+        /// Media type given in the manifest.
+        embedded: String,
+    },
+    /// An SBOM was attached without giving its media type in the `Content-Type` header.
+    #[error("missing SBOM content type")]
+    MissingSbomMediaType,
+    /// Error building HTTP response.
+    #[error("axum http error")]
+    // Note: These should never occur.
+    AxumHttp(#[from] axum::http::Error),
+}
+
+impl From<storage::Error> for ApiError {
+    fn from(err: storage::Error) -> Self {
+        Self::Registry(err.into())
+    }
+}
+
+impl From<MissingPermission> for ApiError {
+    fn from(err: MissingPermission) -> Self {
+        Self::Registry(err.into())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self {
+            ApiError::Registry(err) => registry_error_response(err),
+            ApiError::ContentLengthMalformed(err) => (
+                StatusCode::BAD_REQUEST,
+                format!("invalid content length value: {}", err),
+            )
+                .into_response(),
+            ApiError::IncomingReadFailed(_err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not read input stream",
+            )
+                .into_response(),
+            ApiError::UnsupportedDigestAlgorithm(algorithm) => (
+                StatusCode::BAD_REQUEST,
+                OciErrors::single(OciError::new(types::ErrorCode::Unsupported).with_message(
+                    format!(
+                        "digest algorithm {algorithm} is not supported, supported algorithms: {}",
+                        types::DIGEST_ALGORITHMS.join(", ")
+                    ),
+                )),
+            )
+                .into_response(),
+            ApiError::MissingDigest => (
+                StatusCode::BAD_REQUEST,
+                OciErrors::single(
+                    OciError::new(types::ErrorCode::DigestInvalid)
+                        .with_message("the digest query parameter is required"),
+                ),
+            )
+                .into_response(),
+            ApiError::UploadUnknown => (
+                StatusCode::NOT_FOUND,
+                OciErrors::single(OciError::new(types::ErrorCode::BlobUploadUnknown)),
+            )
+                .into_response(),
+            ApiError::TooManyUploadSessions => (
+                StatusCode::TOO_MANY_REQUESTS,
+                OciErrors::single(OciError::new(types::ErrorCode::TooManyRequests)),
+            )
+                .into_response(),
+            ApiError::UnsupportedContentEncoding => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                OciErrors::single(OciError::new(types::ErrorCode::Unsupported)),
+            )
+                .into_response(),
+            ApiError::DecompressionFailed(_err) => {
+                (StatusCode::BAD_REQUEST, "could not decompress request body").into_response()
+            }
+            ApiError::PayloadTooLarge => payload_too_large(),
+            ApiError::InvalidRange => (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                OciErrors::single(OciError::new(types::ErrorCode::BlobUploadInvalid)),
+            )
+                .into_response(),
+            // Tells the client how much was stored, so it can resume from there, like the resumable
+            // uploads of cloud storage do. There is deliberately no `Location`, which would make
+            // HTTP clients follow the redirect, sending the same misplaced chunk again.
+            ApiError::UploadOffsetMismatch { upload, stored } => Response::builder()
+                .status(StatusCode::PERMANENT_REDIRECT)
+                .header(RANGE, upload_range(stored))
+                .header(CONTENT_LENGTH, 0)
+                .docker_upload_uuid(upload)
+                .body(Body::empty())
+                .expect("response should be valid"),
+            ApiError::ChunkDigestMismatch { upload, stored } => (
+                StatusCode::BAD_REQUEST,
+                [
+                    (RANGE, upload_range(stored)),
+                    (headers::DOCKER_UPLOAD_UUID, upload.to_string()),
+                ],
+                OciErrors::single(
+                    OciError::new(types::ErrorCode::DigestInvalid)
+                        .with_message("chunk does not match its digest and was discarded"),
+                ),
+            )
+                .into_response(),
+            ApiError::ManifestTypeMismatch { .. } => (
+                StatusCode::BAD_REQUEST,
+                OciErrors::single(
+                    OciError::new(types::ErrorCode::ManifestInvalid)
+                        .with_message("manifest media type does not match content type"),
+                ),
+            )
+                .into_response(),
+            ApiError::MissingSbomMediaType => (
+                StatusCode::BAD_REQUEST,
+                OciErrors::single(
+                    OciError::new(types::ErrorCode::ManifestInvalid)
+                        .with_message("the SBOM media type is required in the Content-Type header"),
+                ),
+            )
+                .into_response(),
+            ApiError::AxumHttp(_err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                // Fixed message, we don't want to leak anything. This should never happen anyway.
+                "error building axum HTTP response",
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// Answers a request the registry failed to carry out.
+pub(crate) fn registry_error_response(err: RegistryError) -> Response {
+    match err {
+        // TODO: Need better OciError handling here. Not everything is blob unknown.
+        RegistryError::NotFound => (
+            StatusCode::NOT_FOUND,
+            OciErrors::single(OciError::new(types::ErrorCode::BlobUnknown)),
+        )
+            .into_response(),
+        RegistryError::PermissionDenied(_) => (
+            StatusCode::FORBIDDEN,
+            // TODO: Should this be a proper OCI error?
+            "access to request resource was denied",
+        )
+            .into_response(),
+        // Uploads missing from storage are unknown to clients, no matter why.
+        RegistryError::Storage(storage::Error::UploadDoesNotExit) => {
+            ApiError::UploadUnknown.into_response()
+        }
+        RegistryError::Storage(storage::Error::DigestMismatch) => (
+            StatusCode::BAD_REQUEST,
+            OciErrors::single(OciError::new(types::ErrorCode::DigestInvalid)),
+        )
+            .into_response(),
+        RegistryError::Storage(err) => err.into_response(),
+        RegistryError::ParseManifest(err) => (
+            StatusCode::BAD_REQUEST,
+            format!("could not parse manifest: {}", err),
+        )
+            .into_response(),
+        RegistryError::NotSupported(feature) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("feature not supported: {}", feature),
+        )
+            .into_response(),
+        RegistryError::ReadFailed(_err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not read input stream",
+        )
+            .into_response(),
+        RegistryError::LocalWriteFailed(_err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not write image locally",
+        )
+            .into_response(),
+        RegistryError::InvalidDigest(_err) => (
+            StatusCode::BAD_REQUEST,
+            OciErrors::single(OciError::new(types::ErrorCode::DigestInvalid)),
+        )
+            .into_response(),
+        RegistryError::AmbiguousDigest { prefix, matches } => (
+            StatusCode::BAD_REQUEST,
+            OciErrors::single(OciError::new(types::ErrorCode::DigestInvalid).with_message(
+                format!("digest prefix {prefix} is ambiguous, it matches {matches} manifests"),
+            )),
+        )
+            .into_response(),
+        RegistryError::BlobTooLarge { .. } | RegistryError::ManifestTooLarge { .. } => {
+            payload_too_large()
+        }
+        RegistryError::PolicyViolation(reason) => (
+            StatusCode::FORBIDDEN,
+            OciErrors::single(OciError::new(types::ErrorCode::Denied).with_message(reason)),
+        )
+            .into_response(),
+        RegistryError::UnsupportedManifestType(_) => (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            OciErrors::single(
+                OciError::new(types::ErrorCode::ManifestInvalid)
+                    .with_message("unsupported manifest media type"),
+            ),
+        )
+            .into_response(),
+        RegistryError::Cancelled => {
+            (StatusCode::CONFLICT, "operation was cancelled").into_response()
+        }
+        RegistryError::PurgeNotConfirmed => (
+            StatusCode::CONFLICT,
+            "confirmation is invalid, expired or the repository has changed since",
+        )
+            .into_response(),
+        RegistryError::InvalidRepositoryName(_) => (
+            StatusCode::BAD_REQUEST,
+            OciErrors::single(OciError::new(types::ErrorCode::NameInvalid)),
+        )
+            .into_response(),
+        RegistryError::RepositoryUnknown(repository) => {
+            (
+                StatusCode::NOT_FOUND,
+                OciErrors::single(OciError::new(types::ErrorCode::NameUnknown).with_message(
+                    format!("repository {repository} does not exist, it has to be created first"),
+                )),
+            )
+                .into_response()
+        }
+        RegistryError::ManifestCorrupted { .. } => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "stored manifest does not match its digest",
+        )
+            .into_response(),
+        RegistryError::InvalidPlatform(platform) => {
+            (
+                StatusCode::BAD_REQUEST,
+                OciErrors::single(OciError::new(types::ErrorCode::Unsupported).with_message(
+                    format!("invalid platform {platform:?}, expected `os/architecture`"),
+                )),
+            )
+                .into_response()
+        }
+        RegistryError::MediaTypeNotAllowed {
+            ref location,
+            ref media_type,
+        } => (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            OciErrors::single(
+                OciError::new(types::ErrorCode::ManifestInvalid).with_message(format!(
+                    "manifest type {} is not allowed in {location}",
+                    media_type.as_deref().unwrap_or("(unknown)")
+                )),
+            ),
+        )
+            .into_response(),
+        RegistryError::FetchFailed(_err) => (
+            StatusCode::BAD_GATEWAY,
+            OciErrors::single(
+                OciError::new(types::ErrorCode::BlobUnknown).with_message("could not fetch blob"),
+            ),
+        )
+            .into_response(),
+        RegistryError::ManifestBlobUnknown(digest) => (
+            StatusCode::BAD_REQUEST,
+            OciErrors::single(
+                OciError::new(types::ErrorCode::ManifestBlobUnknown)
+                    .with_message(format!("blob {digest} is unknown to this repository")),
+            ),
+        )
+            .into_response(),
+        // The actual digest is not reported, as it could reveal the contents of fetched resources.
+        RegistryError::DigestMismatch { .. } => (
+            StatusCode::BAD_REQUEST,
+            OciErrors::single(OciError::new(types::ErrorCode::DigestInvalid)),
+        )
+            .into_response(),
+    }
+}
+
+/// Answers a request whose body, or the blob it adds to, exceeds a size limit.
+fn payload_too_large() -> Response {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        OciErrors::single(OciError::new(types::ErrorCode::SizeInvalid)),
+    )
+        .into_response()
+}
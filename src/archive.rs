@@ -0,0 +1,267 @@
+//! Archival of rarely pulled images.
+//!
+//! Long-retention registries keep many images that are pulled rarely, if ever again. With an
+//! [`ArchiveStore`] set through
+//! [`ContainerRegistryBuilder::archive_storage`](crate::ContainerRegistryBuilder::archive_storage),
+//! the [`Archival`](crate::maintenance::Archival) maintenance task moves the blobs of tags that
+//! have been neither pushed nor pulled for a while into the archive, e.g. an S3 bucket using the
+//! Glacier storage class, and removes them from regular storage. Manifests and tags are kept, so
+//! archived images are still listed and can be resolved.
+//!
+//! Pulling an archived blob restores it transparently: the registry asks the archive to make the
+//! blob readable and answers with `202 Accepted` and a `Retry-After` header while it is warming
+//! up. Once readable, the blob is moved back into regular storage and served as usual. Clients
+//! that do not retry on their own have to pull again later.
+//!
+//! Pulls of manifests are recorded in the `archive` directory of the storage, along with the
+//! archived blobs. Pull times are recorded at most once an hour per manifest, which is plenty for
+//! telling rarely pulled images apart.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::async_trait;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use crate::{
+    storage::{self, ColdBlobStore, Digest, FilesystemStorageError},
+    ContainerRegistry, RegistryError,
+};
+
+/// Time between two recorded pulls of the same manifest.
+const PULL_RESOLUTION: Duration = Duration::from_secs(60 * 60);
+
+/// An archival backend, see the [module documentation](self).
+///
+/// Blobs are stored and removed through [`ColdBlobStore`], but may only be read once restored.
+#[async_trait]
+pub trait ArchiveStore: ColdBlobStore {
+    /// Requests restoring an archived blob, so it can be read through [`ColdBlobStore::get`].
+    ///
+    /// Returns `None` if the blob can be read now, otherwise the estimated time until it can.
+    /// Called on every pull of the blob until it is readable, so requesting a restore already in
+    /// progress must succeed.
+    async fn restore(&self, digest: Digest) -> Result<Option<Duration>, storage::Error>;
+}
+
+/// Outcome of pulling an archived blob.
+#[derive(Debug)]
+pub(crate) enum Restore {
+    /// The blob is not archived.
+    NotArchived,
+    /// The blob has been moved back into regular storage.
+    Restored,
+    /// The blob is being restored, retry after the given time.
+    Pending(Duration),
+}
+
+/// Archived blobs and recorded pulls, see the [module documentation](self).
+pub(crate) struct Archive {
+    /// The backend holding archived blobs.
+    store: Arc<dyn ArchiveStore>,
+    /// Directory holding a record per archived blob.
+    blobs: PathBuf,
+    /// Directory holding the last recorded pull time per manifest.
+    pulls: PathBuf,
+    /// Pull times recorded since starting, to avoid recording every single pull.
+    recorded: Mutex<HashMap<Digest, SystemTime>>,
+}
+
+impl Archive {
+    /// Opens the records kept in `storage`, creating their directories if necessary.
+    pub(crate) fn open(
+        storage: &Path,
+        store: Arc<dyn ArchiveStore>,
+    ) -> Result<Self, FilesystemStorageError> {
+        let root = storage.join("archive");
+        let blobs = root.join("blobs");
+        let pulls = root.join("pulls");
+        for dir in [&blobs, &pulls] {
+            if !dir.exists() {
+                std::fs::create_dir_all(dir).map_err(|err| {
+                    FilesystemStorageError::FailedToCreateDir {
+                        path: dir.clone(),
+                        err,
+                    }
+                })?;
+            }
+        }
+
+        Ok(Self {
+            store,
+            blobs,
+            pulls,
+            recorded: Default::default(),
+        })
+    }
+
+    /// Records a pull of a manifest at `now`.
+    pub(crate) async fn record_pull(&self, digest: Digest, now: SystemTime) -> io::Result<()> {
+        {
+            let mut recorded = self.recorded.lock().expect("lock poisoned");
+            if recorded
+                .get(&digest)
+                .is_some_and(|last| now.duration_since(*last).unwrap_or_default() < PULL_RESOLUTION)
+            {
+                return Ok(());
+            }
+            recorded.insert(digest, now);
+        }
+
+        tokio::fs::write(
+            self.pulls.join(digest.to_string()),
+            unix_secs(now).to_string(),
+        )
+        .await
+    }
+
+    /// Returns the last recorded pull of a manifest, if any.
+    pub(crate) async fn last_pull(&self, digest: Digest) -> io::Result<Option<SystemTime>> {
+        match tokio::fs::read_to_string(self.pulls.join(digest.to_string())).await {
+            Ok(raw) => {
+                let secs = raw.trim().parse().map_err(io::Error::other)?;
+                Ok(Some(UNIX_EPOCH + Duration::from_secs(secs)))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns whether a blob has been archived.
+    async fn is_archived(&self, digest: Digest) -> io::Result<bool> {
+        tokio::fs::try_exists(self.blobs.join(digest.to_string())).await
+    }
+
+    /// Removes the record of an archived blob, if any.
+    async fn forget(&self, digest: Digest) -> io::Result<()> {
+        match tokio::fs::remove_file(self.blobs.join(digest.to_string())).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ContainerRegistry {
+    /// Moves a blob of `size` bytes from regular storage into the archive.
+    pub(crate) async fn archive_blob(
+        &self,
+        digest: Digest,
+        size: u64,
+    ) -> Result<(), RegistryError> {
+        let archive = self
+            .archive
+            .as_ref()
+            .ok_or(RegistryError::NotSupported("archive storage"))?;
+        let Some(reader) = self.storage.get_blob_reader(digest).await? else {
+            return Ok(());
+        };
+
+        archive.store.put(digest, size, reader).await?;
+        // Recorded before removing the blob, so it is never lost track of.
+        tokio::fs::write(archive.blobs.join(digest.to_string()), size.to_string())
+            .await
+            .map_err(io_error)?;
+        self.storage.delete_blob(digest).await?;
+
+        info!(%digest, size, "blob archived");
+        Ok(())
+    }
+
+    /// Restores an archived blob into regular storage, once the archive allows reading it.
+    pub(crate) async fn restore_blob(&self, digest: Digest) -> Result<Restore, RegistryError> {
+        let Some(ref archive) = self.archive else {
+            return Ok(Restore::NotArchived);
+        };
+        if !archive.is_archived(digest).await.map_err(io_error)? {
+            return Ok(Restore::NotArchived);
+        }
+
+        if let Some(retry_after) = archive.store.restore(digest).await? {
+            return Ok(Restore::Pending(retry_after));
+        }
+        let Some(mut reader) = archive.store.get(digest).await? else {
+            warn!(%digest, "archived blob missing from archive");
+            return Ok(Restore::NotArchived);
+        };
+
+        let upload = self.storage.begin_new_upload().await?;
+        let copied = async {
+            let mut writer = self.storage.get_upload_writer(0, upload).await?;
+            tokio::io::copy(&mut reader, &mut writer)
+                .await
+                .map_err(RegistryError::LocalWriteFailed)?;
+            writer
+                .shutdown()
+                .await
+                .map_err(RegistryError::LocalWriteFailed)?;
+            drop(writer);
+            Ok::<_, RegistryError>(self.storage.finalize_upload(upload, digest).await?)
+        }
+        .await;
+        if let Err(err) = copied {
+            self.storage.cancel_upload(upload).await.ok();
+            return Err(err);
+        }
+
+        archive.forget(digest).await.map_err(io_error)?;
+        archive.store.delete(digest).await?;
+
+        info!(%digest, "blob restored from archive");
+        Ok(Restore::Restored)
+    }
+
+    /// Lists all archived blobs along with their sizes.
+    pub(crate) async fn archived_blobs(&self) -> Result<Vec<(Digest, u64)>, RegistryError> {
+        let Some(ref archive) = self.archive else {
+            return Ok(Vec::new());
+        };
+
+        let mut blobs = Vec::new();
+        let mut entries = tokio::fs::read_dir(&archive.blobs)
+            .await
+            .map_err(io_error)?;
+        while let Some(entry) = entries.next_entry().await.map_err(io_error)? {
+            let Some(digest) = entry.file_name().to_str().and_then(Digest::from_hex_str) else {
+                continue;
+            };
+            let size = tokio::fs::read_to_string(entry.path())
+                .await
+                .map_err(io_error)?
+                .trim()
+                .parse()
+                .unwrap_or_default();
+            blobs.push((digest, size));
+        }
+        Ok(blobs)
+    }
+
+    /// Removes an archived blob for good.
+    pub(crate) async fn discard_archived_blob(&self, digest: Digest) -> Result<(), RegistryError> {
+        let Some(ref archive) = self.archive else {
+            return Ok(());
+        };
+
+        archive.store.delete(digest).await?;
+        archive.forget(digest).await.map_err(io_error)?;
+        info!(%digest, "archived blob removed");
+        Ok(())
+    }
+}
+
+/// Returns `time` in seconds since the Unix epoch.
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Wraps an IO error of the records.
+fn io_error(err: io::Error) -> RegistryError {
+    RegistryError::Storage(storage::Error::Io(err))
+}
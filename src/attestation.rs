@@ -0,0 +1,281 @@
+//! Registry-issued attestations.
+//!
+//! With a [`Signer`] configured through
+//! [`ContainerRegistryBuilder::attestation_signer`](crate::ContainerRegistryBuilder::attestation_signer),
+//! the registry attests every manifest it accepts. Once a pushed manifest has passed all of the
+//! registry's checks, e.g. layer inspection and allowed media types, and has been stored, an
+//! [in-toto statement](https://github.com/in-toto/attestation) naming it as subject is signed and
+//! wrapped in a [DSSE envelope](https://github.com/secure-systems-lab/dsse). The envelope is stored
+//! as an OCI artifact of type [`DSSE_ENVELOPE`] referring to the manifest, so clients can find it
+//! through the referrers API and verify it using the signer's public key, proving the image was
+//! accepted by the registry.
+//!
+//! The statement's predicate, of type [`PREDICATE_TYPE`], records where the manifest was pushed to
+//! and when:
+//!
+//! ```json
+//! {
+//!   "location": "bitnami/nginx",
+//!   "reference": "1.25",
+//!   "attested": 1700000000
+//! }
+//! ```
+//!
+//! Attestations are issued in the background, failures are logged and counted in the registry
+//! metrics. Artifacts referring to other manifests, e.g. signatures or SBOMs, are not attested.
+
+use std::{error::Error, process::Stdio};
+
+use axum::async_trait;
+use base64::Engine;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::storage::{Digest, ImageLocation, Reference};
+
+/// Media type of DSSE envelopes, used as the artifact type of attestations.
+pub const DSSE_ENVELOPE: &str = "application/vnd.dsse.envelope.v1+json";
+
+/// Payload type of in-toto statements within DSSE envelopes.
+pub const IN_TOTO_PAYLOAD: &str = "application/vnd.in-toto+json";
+
+/// Type of in-toto statements.
+const STATEMENT_TYPE: &str = "https://in-toto.io/Statement/v1";
+
+/// Predicate type of registry-issued attestations.
+pub const PREDICATE_TYPE: &str = "https://github.com/mbr/container_registry-rs/attestation/v1";
+
+/// A signer of attestations.
+#[async_trait]
+pub trait Signer: Send + Sync {
+    /// Returns an identifier of the signing key, included with signatures as a hint to verifiers.
+    fn key_id(&self) -> Option<String> {
+        None
+    }
+
+    /// Signs `message`, returning the raw signature.
+    ///
+    /// The message is the DSSE pre-authentication encoding of the envelope's payload.
+    async fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>>;
+}
+
+/// Signs attestations by running an external command, e.g. `openssl`.
+///
+/// The message to sign is passed on standard input, the command's standard output is used as the
+/// signature:
+///
+/// ```
+/// use container_registry::attestation::CommandSigner;
+///
+/// let signer = CommandSigner::new("openssl")
+///     .args(["pkeyutl", "-sign", "-rawin", "-inkey", "/etc/registry/attestation.pem"])
+///     .key_id("registry-2024");
+/// ```
+#[derive(Clone, Debug)]
+pub struct CommandSigner {
+    /// Program to run.
+    program: String,
+    /// Arguments passed to the program.
+    args: Vec<String>,
+    /// Identifier of the signing key.
+    key_id: Option<String>,
+}
+
+impl CommandSigner {
+    /// Creates a new signer running `program`.
+    pub fn new<P: Into<String>>(program: P) -> Self {
+        Self {
+            program: program.into(),
+            args: Vec::new(),
+            key_id: None,
+        }
+    }
+
+    /// Adds arguments.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sets the identifier of the signing key.
+    pub fn key_id<S: Into<String>>(mut self, key_id: S) -> Self {
+        self.key_id = Some(key_id.into());
+        self
+    }
+}
+
+#[async_trait]
+impl Signer for CommandSigner {
+    fn key_id(&self) -> Option<String> {
+        self.key_id.clone()
+    }
+
+    async fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let mut child = tokio::process::Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let mut stdin = child.stdin.take().expect("stdin should be piped");
+        let write = async move {
+            stdin.write_all(message).await?;
+            stdin.shutdown().await
+        };
+        let (written, output) = tokio::join!(write, child.wait_with_output());
+        let output = output?;
+
+        if !output.status.success() {
+            return Err(format!(
+                "{} exited with {}: {}",
+                self.program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        written?;
+
+        Ok(output.stdout)
+    }
+}
+
+/// An in-toto statement about a single manifest.
+#[derive(Debug, Serialize)]
+struct Statement<'a> {
+    /// Type of the statement.
+    #[serde(rename = "_type")]
+    statement_type: &'static str,
+    /// The attested manifest.
+    subject: [Subject; 1],
+    /// Type of the predicate.
+    #[serde(rename = "predicateType")]
+    predicate_type: &'static str,
+    /// What is attested about the subject.
+    predicate: Predicate<'a>,
+}
+
+/// The subject of a statement.
+#[derive(Debug, Serialize)]
+struct Subject {
+    /// Location of the manifest, e.g. `bitnami/nginx`.
+    name: String,
+    /// Digests of the manifest, by algorithm.
+    digest: SubjectDigest,
+}
+
+/// Digests of a subject.
+#[derive(Debug, Serialize)]
+struct SubjectDigest {
+    /// Hex-encoded SHA-256 digest.
+    sha256: String,
+}
+
+/// The predicate of a registry-issued attestation, see the [module documentation](self).
+#[derive(Debug, Serialize)]
+struct Predicate<'a> {
+    /// Location the manifest was pushed to.
+    location: String,
+    /// Tag or digest the manifest was pushed as.
+    reference: &'a Reference,
+    /// Time the attestation was issued, in seconds since the unix epoch.
+    attested: u64,
+}
+
+/// A DSSE envelope.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Envelope {
+    /// Type of the payload.
+    payload_type: &'static str,
+    /// Base64-encoded payload.
+    payload: String,
+    /// Signatures of the payload.
+    signatures: [Signature; 1],
+}
+
+/// A signature within a DSSE envelope.
+#[derive(Debug, Serialize)]
+struct Signature {
+    /// Identifier of the signing key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keyid: Option<String>,
+    /// Base64-encoded signature.
+    sig: String,
+}
+
+/// Computes the DSSE pre-authentication encoding of a payload, which is what gets signed.
+fn pre_authentication_encoding(payload_type: &str, payload: &[u8]) -> Vec<u8> {
+    let mut encoded = format!(
+        "DSSEv1 {} {} {} ",
+        payload_type.len(),
+        payload_type,
+        payload.len()
+    )
+    .into_bytes();
+    encoded.extend_from_slice(payload);
+    encoded
+}
+
+/// Issues a signed attestation for the manifest `digest`, pushed to `location` as `reference`.
+///
+/// Returns the DSSE envelope.
+pub(crate) async fn issue(
+    signer: &dyn Signer,
+    location: &ImageLocation,
+    reference: &Reference,
+    digest: Digest,
+    attested: u64,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let statement = Statement {
+        statement_type: STATEMENT_TYPE,
+        subject: [Subject {
+            name: location.to_string(),
+            digest: SubjectDigest {
+                sha256: digest.to_string(),
+            },
+        }],
+        predicate_type: PREDICATE_TYPE,
+        predicate: Predicate {
+            location: location.to_string(),
+            reference,
+            attested,
+        },
+    };
+    let payload = serde_json::to_vec(&statement).expect("serialization should not fail");
+
+    let signature = signer
+        .sign(&pre_authentication_encoding(IN_TOTO_PAYLOAD, &payload))
+        .await?;
+
+    let base64 = base64::engine::general_purpose::STANDARD;
+    let envelope = Envelope {
+        payload_type: IN_TOTO_PAYLOAD,
+        payload: base64.encode(&payload),
+        signatures: [Signature {
+            keyid: signer.key_id(),
+            sig: base64.encode(signature),
+        }],
+    };
+    Ok(serde_json::to_vec(&envelope).expect("serialization should not fail"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pre_authentication_encoding;
+
+    #[test]
+    fn pre_authentication_encoding_matches_spec() {
+        // Example from the DSSE protocol specification.
+        assert_eq!(
+            pre_authentication_encoding("http://example.com/HelloWorld", b"hello world"),
+            b"DSSEv1 29 http://example.com/HelloWorld 11 hello world"
+        );
+    }
+}
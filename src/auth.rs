@@ -7,32 +7,54 @@
 //! * `Permissions`: The [`Permissions`] type itself is an auth provider, it will allow
 //!                  access with the given permissions to any non-anonymous client.
 //! * `HashMap<String, Secret<String>>`: A mapping of usernames to (unencrypted) passwords.
-//! * `Secret<String>`: Master password, ignores all usernames and just compares the password.
+//! * `Secret<String>`: Master password, ignores all usernames and just compares the password. Also
+//!   accepted as a bearer token.
 //! * `Anonymous`: A decorator that wraps around another [`AuthProvider`], will grant a fixed set
 //!                of permissions to anonymous user, while deferring everything else to the inner
 //!                provider.
 //!
 //! All the above implementations deal with **authentication** only, once authorized, full
-//! write access to everything is granted.
+//! access to everything is granted. This includes administrative access, e.g. to maintenance
+//! operations, which is denied to anonymous users and to providers not implementing
+//! [`AuthProvider::admin_permissions`].
+//!
+//! Deleting is authorized separately from pushing: Only [`Permissions::ReadWriteDelete`] allows
+//! removing content, e.g. purging repositories or running maintenance tasks through the
+//! administrative API, so credentials handed to CI can be limited to [`Permissions::ReadWrite`].
+//! Maintenance tasks and tag limits remove content as the internal [`MAINTENANCE_IDENTITY`], which
+//! removals are attributed to in [events](crate::events::RegistryEvent::TagDeleted) and logs.
+//!
+//! The provider of a running registry can be replaced using
+//! [`ContainerRegistry::set_auth_provider`], e.g. to rotate credentials or switch to another
+//! backend without restarting and dropping in-flight uploads.
 //!
 //! To provide some safety against accidentally leaking passwords via stray `Debug` implementations,
 //! this crate uses the [`sec`]'s crate [`Secret`] type.
 
-use std::{any::Any, collections::HashMap, str, sync::Arc};
+use std::{any::Any, collections::HashMap, fmt, net::IpAddr, str, sync::Arc, time::Duration};
 
 use axum::{
     async_trait,
-    extract::FromRequestParts,
+    extract::{FromRequestParts, Request, State},
     http::{
         header::{self},
         request::Parts,
-        StatusCode,
+        HeaderValue, StatusCode,
     },
+    middleware::Next,
+    response::{IntoResponse, Response},
 };
 use sec::Secret;
 use thiserror::Error;
-
-use crate::{storage::ImageLocation, ImageDigest};
+use tracing::warn;
+
+use crate::{
+    access, lockout,
+    storage::ImageLocation,
+    tokens,
+    types::{ErrorCode, OciError, OciErrors},
+    ImageDigest,
+};
 
 use super::{
     www_authenticate::{self},
@@ -40,7 +62,10 @@ use super::{
 };
 
 /// A set of credentials supplied that has not been verified.
+///
+/// Further authentication schemes may be added, so matches need a wildcard arm.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Unverified {
     /// A set of username and password credentials.
     UsernameAndPassword {
@@ -49,6 +74,11 @@ pub enum Unverified {
         /// The provided password.
         password: Secret<String>,
     },
+    /// A bearer token, e.g. a pull token issued by the registry.
+    BearerToken {
+        /// The provided token.
+        token: Secret<String>,
+    },
     /// No credentials were given.
     NoCredentials,
 }
@@ -67,6 +97,17 @@ impl<S> FromRequestParts<S> for Unverified {
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
         if let Some(auth_header) = parts.headers.get(header::AUTHORIZATION) {
+            // Both schemes are accepted at all times, so clients can be migrated one by one.
+            if let Ok((_unparsed, token)) =
+                www_authenticate::bearer_auth_response(auth_header.as_bytes())
+            {
+                return Ok(Unverified::BearerToken {
+                    token: Secret::new(
+                        String::from_utf8(token).map_err(|_| StatusCode::BAD_REQUEST)?,
+                    ),
+                });
+            }
+
             let (_unparsed, basic) = www_authenticate::basic_auth_response(auth_header.as_bytes())
                 .map_err(|_| StatusCode::BAD_REQUEST)?;
 
@@ -91,39 +132,266 @@ impl<S> FromRequestParts<S> for Unverified {
 /// Every [`AuthProvider`] is free to put [`Any`] type in the credentials and is guaranteed
 /// to be passed back only instances it created itself. Use [`Self::extract_ref`] to retrieve the
 /// passed in actual type.
-#[derive(Debug)]
-pub struct ValidCredentials(pub Box<dyn Any + Send + Sync>);
+///
+/// Additionally, credentials may carry the name of the user they belong to, which the registry
+/// uses to attribute actions (e.g. upload sessions) to users.
+pub struct ValidCredentials(
+    /// The auth provider specific credentials.
+    #[deprecated(
+        since = "0.4.0",
+        note = "use `ValidCredentials::extract_ref` or `ValidCredentials::into_inner` instead"
+    )]
+    pub Box<dyn Any + Send + Sync>,
+    /// The name of the user, if known.
+    Option<String>,
+    /// The provider that authenticated the request, set by the registry.
+    Option<Arc<dyn AuthProvider>>,
+);
+
+#[allow(deprecated)]
+impl fmt::Debug for ValidCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValidCredentials")
+            .field("inner", &self.0)
+            .field("username", &self.1)
+            .finish_non_exhaustive()
+    }
+}
 
+#[allow(deprecated)]
 impl ValidCredentials {
     /// Creates a new set of valid credentials.
     #[inline(always)]
     pub fn new<T: Send + Sync + 'static>(inner: T) -> Self {
-        ValidCredentials(Box::new(inner))
+        ValidCredentials(Box::new(inner), None, None)
+    }
+
+    /// Creates a new set of valid credentials belonging to a specific user.
+    #[inline(always)]
+    pub fn with_username<T: Send + Sync + 'static>(inner: T, username: String) -> Self {
+        ValidCredentials(Box::new(inner), Some(username), None)
     }
 
     /// Extracts a reference to the contained inner type.
     pub fn extract_ref<T: 'static>(&self) -> &T {
         self.0.downcast_ref::<T>().expect("could not downcast `ValidCredentials` into expected type - was auth provider called with the wrong set of credentials?")
     }
+
+    /// Extracts a reference to the contained inner type, if it is of type `T`.
+    ///
+    /// Useful for auth providers decorating others, which need to tell their own credentials
+    /// apart from those of the decorated provider.
+    pub fn try_extract_ref<T: 'static>(&self) -> Option<&T> {
+        self.0.downcast_ref::<T>()
+    }
+
+    /// Returns the contained inner credentials.
+    #[inline(always)]
+    pub fn into_inner(self) -> Box<dyn Any + Send + Sync> {
+        self.0
+    }
+
+    /// Returns the name of the user the credentials belong to, if known.
+    #[inline(always)]
+    pub fn username(&self) -> Option<&str> {
+        self.1.as_deref()
+    }
+
+    /// Returns the provider that authenticated the credentials, if set by the registry.
+    #[inline(always)]
+    fn provider(&self) -> Option<&Arc<dyn AuthProvider>> {
+        self.2.as_ref()
+    }
 }
 
 #[async_trait]
 impl FromRequestParts<Arc<ContainerRegistry>> for ValidCredentials {
-    type Rejection = StatusCode;
+    type Rejection = AuthRejection;
 
-    #[inline(always)]
     async fn from_request_parts(
         parts: &mut Parts,
         state: &Arc<ContainerRegistry>,
     ) -> Result<Self, Self::Rejection> {
-        let unverified = Unverified::from_request_parts(parts, state).await?;
+        let unverified = Unverified::from_request_parts(parts, state)
+            .await
+            .map_err(|_| AuthRejection::Malformed)?;
+        let client = lockout::client_address(parts, &state.trusted_proxies);
+
+        let creds = state.authenticate(&unverified, client).await?;
+        if let Some(user) = parts.extensions.get::<access::AccessUser>() {
+            user.set(creds.username());
+        }
+        Ok(creds)
+    }
+}
+
+/// Reason a request could not be authenticated.
+#[derive(Debug)]
+pub enum AuthRejection {
+    /// The `Authorization` header could not be parsed.
+    Malformed,
+    /// The credentials were refused by the auth provider.
+    Invalid,
+    /// The user or client address is locked out for the given duration, see the
+    /// [`crate::lockout`] module.
+    LockedOut(Duration),
+}
+
+/// Middleware adding the registry's challenges to every `401 Unauthorized` response not carrying
+/// any, so clients learn how to authenticate regardless of the endpoint they tried first.
+pub(crate) async fn challenge_unauthorized(
+    State(registry): State<Arc<ContainerRegistry>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    if response.status() != StatusCode::UNAUTHORIZED
+        || response.headers().contains_key(header::WWW_AUTHENTICATE)
+    {
+        return response;
+    }
 
-        // We got a set of credentials, now verify.
-        match state.auth_provider.check_credentials(&unverified).await {
-            Some(creds) => Ok(creds),
-            None => Err(StatusCode::UNAUTHORIZED),
+    for challenge in registry.challenges() {
+        if let Ok(value) = HeaderValue::from_str(&challenge) {
+            response
+                .headers_mut()
+                .append(header::WWW_AUTHENTICATE, value);
         }
     }
+    response
+}
+
+impl IntoResponse for AuthRejection {
+    fn into_response(self) -> Response {
+        match self {
+            AuthRejection::Malformed => StatusCode::BAD_REQUEST.into_response(),
+            AuthRejection::Invalid => StatusCode::UNAUTHORIZED.into_response(),
+            AuthRejection::LockedOut(remaining) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                // Rounded up, so clients do not retry while still locked out.
+                [(
+                    header::RETRY_AFTER,
+                    (remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0)).to_string(),
+                )],
+                OciErrors::single(
+                    OciError::new(ErrorCode::TooManyRequests)
+                        .with_message("too many failed authentication attempts"),
+                ),
+            )
+                .into_response(),
+        }
+    }
+}
+
+impl ContainerRegistry {
+    /// Replaces the auth provider of the running registry, e.g. to rotate credentials.
+    ///
+    /// Takes effect for all requests received from now on. Requests already authenticated are
+    /// authorized by the provider that authenticated them until they finish. Open
+    /// [event streams](crate::events) are closed instead of delivering further events, clients
+    /// have to reconnect and authenticate with the new provider. Open upload sessions are kept,
+    /// each further request adding to them is checked by the new provider. With a
+    /// [`token_issuer`](crate::ContainerRegistryBuilder::token_issuer) set, pull tokens remain
+    /// valid, as they are verified ahead of the provider.
+    pub fn set_auth_provider(&self, auth_provider: Arc<dyn AuthProvider>) {
+        let auth_provider = match self.token_issuer {
+            Some(ref issuer) => Arc::new(tokens::TokenAuth::new(issuer.clone(), auth_provider)),
+            None => auth_provider,
+        };
+        self.auth_provider.store(Arc::new(auth_provider));
+    }
+
+    /// Returns the current auth provider.
+    pub(crate) fn auth_provider(&self) -> Arc<dyn AuthProvider> {
+        Arc::clone(&self.auth_provider.load())
+    }
+
+    /// Returns the auth provider to authorize `creds` with: the one that authenticated them, so
+    /// they are never passed to another provider after [`Self::set_auth_provider`].
+    ///
+    /// Credentials not authenticated by the registry itself are authorized by the current provider.
+    pub(crate) fn auth_provider_for(&self, creds: &ValidCredentials) -> Arc<dyn AuthProvider> {
+        match creds.provider() {
+            Some(provider) => Arc::clone(provider),
+            None => self.auth_provider(),
+        }
+    }
+
+    /// Returns the challenges sent in `WWW-Authenticate` headers, one per header.
+    ///
+    /// The bearer realm is only advertised if the current provider verifies the tokens issued
+    /// there.
+    pub(crate) fn challenges(&self) -> Vec<String> {
+        let bearer_realm = self
+            .bearer_realm
+            .as_deref()
+            .filter(|_| self.auth_provider().verifies_external_tokens());
+        www_authenticate::challenges(&self.realm, bearer_realm)
+    }
+
+    /// Returns whether `creds` were authenticated by the current provider, or not by the registry
+    /// at all.
+    pub(crate) fn is_current_auth_provider(&self, creds: &ValidCredentials) -> bool {
+        match creds.provider() {
+            Some(provider) => Arc::ptr_eq(provider, &self.auth_provider.load()),
+            None => true,
+        }
+    }
+
+    /// Verifies credentials sent by `client`, tracking failed attempts.
+    ///
+    /// Credentials of locked out users or clients are not checked at all.
+    pub(crate) async fn authenticate(
+        &self,
+        unverified: &Unverified,
+        client: Option<IpAddr>,
+    ) -> Result<ValidCredentials, AuthRejection> {
+        let username = match unverified {
+            Unverified::UsernameAndPassword { username, .. } => Some(username.as_str()),
+            Unverified::BearerToken { .. } | Unverified::NoCredentials => None,
+        };
+        let subjects: Vec<_> = username
+            .map(|username| lockout::Subject::User(username.to_owned()))
+            .into_iter()
+            .chain(client.map(lockout::Subject::Client))
+            .collect();
+
+        // Requests without credentials are how clients discover the auth scheme, never a failure.
+        let has_credentials = !unverified.is_no_credentials();
+        if has_credentials {
+            if let Some(remaining) = self.auth_failures.locked_out(&subjects) {
+                self.metrics.auth_locked_out_requests.inc();
+                return Err(AuthRejection::LockedOut(remaining));
+            }
+        }
+
+        let provider = self.auth_provider();
+        if let Some(mut creds) = provider.check_credentials(unverified).await {
+            creds.2 = Some(provider);
+            if let Some(username) = username {
+                self.auth_failures.record_success(username);
+            }
+            return Ok(creds);
+        }
+
+        if !has_credentials {
+            return Err(AuthRejection::Invalid);
+        }
+
+        self.metrics.auth_failures.inc();
+        warn!(username, client = ?client, "authentication failed");
+        if self.auth_failures.record_failure(&subjects) {
+            self.metrics.auth_lockouts.inc();
+            warn!(username, client = ?client, "locked out after failed authentication attempts");
+        }
+        self.run_hook(
+            "on_authentication_failed",
+            self.hooks.on_authentication_failed(username, client),
+        )
+        .await;
+
+        Err(AuthRejection::Invalid)
+    }
 }
 
 /// A set of permissions granted on a specific image location to a given set of credentials.
@@ -136,8 +404,10 @@ pub enum Permissions {
     WriteOnly = 2,
     /// Read access.
     ReadOnly = 4,
-    /// Read and write access.
+    /// Read and write access, e.g. for CI pushing images.
     ReadWrite = 6,
+    /// Read, write and delete access.
+    ReadWriteDelete = 7,
 }
 
 impl Permissions {
@@ -147,7 +417,7 @@ impl Permissions {
     pub fn has_read_permission(self) -> bool {
         match self {
             Permissions::NoAccess | Permissions::WriteOnly => false,
-            Permissions::ReadOnly | Permissions::ReadWrite => true,
+            Permissions::ReadOnly | Permissions::ReadWrite | Permissions::ReadWriteDelete => true,
         }
     }
 
@@ -157,10 +427,19 @@ impl Permissions {
     pub fn has_write_permission(self) -> bool {
         match self {
             Permissions::NoAccess | Permissions::ReadOnly => false,
-            Permissions::WriteOnly | Permissions::ReadWrite => true,
+            Permissions::WriteOnly | Permissions::ReadWrite | Permissions::ReadWriteDelete => true,
         }
     }
 
+    /// Returns whether or not permissions include delete access.
+    ///
+    /// Deleting is separate from writing, so that e.g. CI pushing images cannot remove them.
+    #[inline(always)]
+    #[must_use = "should not check delete permissions and discard the result"]
+    pub fn has_delete_permission(self) -> bool {
+        matches!(self, Permissions::ReadWriteDelete)
+    }
+
     /// Returns an error if no read permission is included.
     #[inline(always)]
     pub fn require_read(self) -> Result<(), MissingPermission> {
@@ -180,8 +459,23 @@ impl Permissions {
             Ok(())
         }
     }
+
+    /// Returns an error if no delete permission is included.
+    #[inline(always)]
+    pub fn require_delete(self) -> Result<(), MissingPermission> {
+        if !self.has_delete_permission() {
+            Err(MissingPermission)
+        } else {
+            Ok(())
+        }
+    }
 }
 
+/// Identity the registry removes content as on its own, e.g. when applying retention.
+///
+/// Not an account, it cannot be authenticated as. Only used to attribute removals.
+pub const MAINTENANCE_IDENTITY: &str = "registry-maintenance";
+
 /// Error indicating a missing permission.
 #[derive(Debug, Error)]
 #[error("not permitted")]
@@ -220,6 +514,28 @@ pub trait AuthProvider: Send + Sync {
     /// involve the uploader sending a hash beforehand, thus this function cannot be used to
     /// implement a blacklist for specific blobs.
     async fn blob_permissions(&self, creds: &ValidCredentials, blob: &ImageDigest) -> Permissions;
+
+    /// Determine administrative permissions for given credentials.
+    ///
+    /// This is an **authorizing** function for actions affecting the registry as a whole rather
+    /// than a single [`ImageLocation`], e.g. running maintenance operations through the
+    /// administrative API. It is checked once per request, independently of any image
+    /// permissions.
+    ///
+    /// Denies all access unless implemented.
+    async fn admin_permissions(&self, _creds: &ValidCredentials) -> Permissions {
+        Permissions::NoAccess
+    }
+
+    /// Returns whether bearer tokens issued by an external token service are verified.
+    ///
+    /// Only then is the token service set through
+    /// [`ContainerRegistryBuilder::bearer_realm`](crate::ContainerRegistryBuilder::bearer_realm)
+    /// advertised to clients, as they would otherwise obtain tokens that are refused. Pull tokens
+    /// issued by the registry itself do not count. Defaults to `false`.
+    fn verifies_external_tokens(&self) -> bool {
+        false
+    }
 }
 
 /// Anonymous access auth provider.
@@ -268,18 +584,31 @@ where
         creds: &ValidCredentials,
         image: &ImageLocation,
     ) -> Permissions {
-        match creds.extract_ref::<AnonCreds>() {
-            AnonCreds::Anonymous => self.anon_permissions,
+        // Credentials of authenticated users were created by the inner provider.
+        match creds.try_extract_ref::<AnonCreds>() {
+            Some(AnonCreds::Anonymous) => self.anon_permissions,
             _other => self.inner.image_permissions(creds, image).await,
         }
     }
 
     async fn blob_permissions(&self, creds: &ValidCredentials, blob: &ImageDigest) -> Permissions {
-        match creds.extract_ref::<AnonCreds>() {
-            AnonCreds::Anonymous => self.anon_permissions,
+        match creds.try_extract_ref::<AnonCreds>() {
+            Some(AnonCreds::Anonymous) => self.anon_permissions,
             _other => self.inner.blob_permissions(creds, blob).await,
         }
     }
+
+    async fn admin_permissions(&self, creds: &ValidCredentials) -> Permissions {
+        // Anonymous users never administer the registry, regardless of their image permissions.
+        match creds.try_extract_ref::<AnonCreds>() {
+            Some(AnonCreds::Anonymous) => Permissions::NoAccess,
+            _other => self.inner.admin_permissions(creds).await,
+        }
+    }
+
+    fn verifies_external_tokens(&self) -> bool {
+        self.inner.verifies_external_tokens()
+    }
 }
 
 #[async_trait]
@@ -309,6 +638,11 @@ impl AuthProvider for Permissions {
     ) -> Permissions {
         *self
     }
+
+    #[inline(always)]
+    async fn admin_permissions(&self, _creds: &ValidCredentials) -> Permissions {
+        *self
+    }
 }
 
 #[async_trait]
@@ -324,13 +658,16 @@ impl AuthProvider for HashMap<String, Secret<String>> {
                         correct_password.reveal().as_bytes(),
                         unverified_password.reveal().as_bytes(),
                     ) {
-                        return Some(ValidCredentials::new(unverified_username.clone()));
+                        return Some(ValidCredentials::with_username(
+                            unverified_username.clone(),
+                            unverified_username.clone(),
+                        ));
                     }
                 }
 
                 None
             }
-            Unverified::NoCredentials => None,
+            Unverified::BearerToken { .. } | Unverified::NoCredentials => None,
         }
     }
 
@@ -340,7 +677,7 @@ impl AuthProvider for HashMap<String, Secret<String>> {
         _creds: &ValidCredentials,
         _image: &ImageLocation,
     ) -> Permissions {
-        Permissions::ReadWrite
+        Permissions::ReadWriteDelete
     }
 
     #[inline(always)]
@@ -349,14 +686,19 @@ impl AuthProvider for HashMap<String, Secret<String>> {
         _creds: &ValidCredentials,
         _blob: &ImageDigest,
     ) -> Permissions {
-        Permissions::ReadWrite
+        Permissions::ReadWriteDelete
+    }
+
+    #[inline(always)]
+    async fn admin_permissions(&self, _creds: &ValidCredentials) -> Permissions {
+        Permissions::ReadWriteDelete
     }
 }
 
 #[async_trait]
 impl<T> AuthProvider for Box<T>
 where
-    T: AuthProvider,
+    T: AuthProvider + ?Sized,
 {
     #[inline(always)]
     async fn check_credentials(&self, unverified: &Unverified) -> Option<ValidCredentials> {
@@ -366,26 +708,32 @@ where
     #[inline(always)]
     async fn image_permissions(
         &self,
-        _creds: &ValidCredentials,
-        _image: &ImageLocation,
+        creds: &ValidCredentials,
+        image: &ImageLocation,
     ) -> Permissions {
-        Permissions::ReadWrite
+        <T as AuthProvider>::image_permissions(self, creds, image).await
     }
 
     #[inline(always)]
-    async fn blob_permissions(
-        &self,
-        _creds: &ValidCredentials,
-        _blob: &ImageDigest,
-    ) -> Permissions {
-        Permissions::ReadWrite
+    async fn blob_permissions(&self, creds: &ValidCredentials, blob: &ImageDigest) -> Permissions {
+        <T as AuthProvider>::blob_permissions(self, creds, blob).await
+    }
+
+    #[inline(always)]
+    async fn admin_permissions(&self, creds: &ValidCredentials) -> Permissions {
+        <T as AuthProvider>::admin_permissions(self, creds).await
+    }
+
+    #[inline(always)]
+    fn verifies_external_tokens(&self) -> bool {
+        <T as AuthProvider>::verifies_external_tokens(self)
     }
 }
 
 #[async_trait]
 impl<T> AuthProvider for Arc<T>
 where
-    T: AuthProvider,
+    T: AuthProvider + ?Sized,
 {
     #[inline(always)]
     async fn check_credentials(&self, unverified: &Unverified) -> Option<ValidCredentials> {
@@ -395,19 +743,25 @@ where
     #[inline(always)]
     async fn image_permissions(
         &self,
-        _creds: &ValidCredentials,
-        _image: &ImageLocation,
+        creds: &ValidCredentials,
+        image: &ImageLocation,
     ) -> Permissions {
-        Permissions::ReadWrite
+        <T as AuthProvider>::image_permissions(self, creds, image).await
     }
 
     #[inline(always)]
-    async fn blob_permissions(
-        &self,
-        _creds: &ValidCredentials,
-        _blob: &ImageDigest,
-    ) -> Permissions {
-        Permissions::ReadWrite
+    async fn blob_permissions(&self, creds: &ValidCredentials, blob: &ImageDigest) -> Permissions {
+        <T as AuthProvider>::blob_permissions(self, creds, blob).await
+    }
+
+    #[inline(always)]
+    async fn admin_permissions(&self, creds: &ValidCredentials) -> Permissions {
+        <T as AuthProvider>::admin_permissions(self, creds).await
+    }
+
+    #[inline(always)]
+    fn verifies_external_tokens(&self) -> bool {
+        <T as AuthProvider>::verifies_external_tokens(self)
     }
 }
 
@@ -416,10 +770,12 @@ impl AuthProvider for Secret<String> {
     #[inline(always)]
     async fn check_credentials(&self, unverified: &Unverified) -> Option<ValidCredentials> {
         match unverified {
+            // The master password works as a bearer token as well.
             Unverified::UsernameAndPassword {
                 username: _,
                 password,
-            } => {
+            }
+            | Unverified::BearerToken { token: password } => {
                 if constant_time_eq::constant_time_eq(
                     password.reveal().as_bytes(),
                     self.reveal().as_bytes(),
@@ -439,7 +795,7 @@ impl AuthProvider for Secret<String> {
         _creds: &ValidCredentials,
         _image: &ImageLocation,
     ) -> Permissions {
-        Permissions::ReadWrite
+        Permissions::ReadWriteDelete
     }
 
     #[inline(always)]
@@ -448,6 +804,11 @@ impl AuthProvider for Secret<String> {
         _creds: &ValidCredentials,
         _blob: &ImageDigest,
     ) -> Permissions {
-        Permissions::ReadWrite
+        Permissions::ReadWriteDelete
+    }
+
+    #[inline(always)]
+    async fn admin_permissions(&self, _creds: &ValidCredentials) -> Permissions {
+        Permissions::ReadWriteDelete
     }
 }
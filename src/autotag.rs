@@ -0,0 +1,232 @@
+//! Automatic tagging of pushed manifests.
+//!
+//! Policies set through
+//! [`ContainerRegistryBuilder::auto_tag`](crate::ContainerRegistryBuilder::auto_tag) add tags to
+//! every pushed manifest, e.g. moving `latest` along with the highest version or stamping images
+//! with the date they were pushed. Two policies are included:
+//!
+//! * [`LatestSemver`] tags a manifest pushed as a semantic version, e.g. `1.4.2` or `v1.4.2`, as
+//!   `latest` if no other version of the image is higher. Pre-releases are never tagged.
+//! * [`DateStamp`] tags every manifest with the date it was pushed, e.g. `20240131`.
+//!
+//! Additional tags are applied as part of the push: the push is only acknowledged once all of them
+//! are in place, and fails if one cannot be applied. They are subject to the same rules as tags set
+//! through the administrative API, e.g. the tag limit, and are published as
+//! [`TagUpdated`](crate::events::RegistryEvent::TagUpdated) events. Quarantined manifests and
+//! manifests referring to another one, e.g. signatures, are not tagged automatically.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tracing::warn;
+
+use crate::{
+    storage::{is_valid_tag, Digest, ImageLocation, Reference},
+    ContainerRegistry, RegistryError,
+};
+
+/// A manifest that has just been pushed.
+#[derive(Debug)]
+pub struct PushedManifest<'a> {
+    /// Location the manifest was pushed to.
+    pub location: &'a ImageLocation,
+    /// Reference the manifest was pushed by, a tag or its digest.
+    pub reference: &'a Reference,
+    /// Digest of the manifest.
+    pub digest: Digest,
+    /// All tags of the image, including the one pushed.
+    pub tags: &'a [String],
+    /// Time of the push.
+    pub pushed_at: SystemTime,
+}
+
+/// A policy adding tags to pushed manifests, see the [module documentation](self).
+pub trait AutoTagPolicy: Send + Sync {
+    /// Returns the tags to point at the pushed manifest, in addition to the one it was pushed by.
+    ///
+    /// Invalid tags are ignored.
+    fn tags(&self, pushed: &PushedManifest<'_>) -> Vec<String>;
+}
+
+/// Tags the highest semantic version of an image as `latest`.
+#[derive(Clone, Debug)]
+pub struct LatestSemver {
+    /// Tag to apply.
+    tag: String,
+}
+
+impl Default for LatestSemver {
+    fn default() -> Self {
+        Self {
+            tag: "latest".to_owned(),
+        }
+    }
+}
+
+impl LatestSemver {
+    /// Creates a new policy, tagging the highest version as `latest`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the tag to apply instead of `latest`, e.g. `stable`.
+    pub fn tag<S: Into<String>>(mut self, tag: S) -> Self {
+        self.tag = tag.into();
+        self
+    }
+}
+
+impl AutoTagPolicy for LatestSemver {
+    fn tags(&self, pushed: &PushedManifest<'_>) -> Vec<String> {
+        let Some(version) = pushed.reference.as_tag().and_then(parse_semver) else {
+            return Vec::new();
+        };
+        let is_highest = pushed
+            .tags
+            .iter()
+            .filter_map(|tag| parse_semver(tag))
+            .all(|other| other <= version);
+
+        if is_highest {
+            vec![self.tag.clone()]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+/// Parses a release version of the form `1.2.3` or `v1.2.3`.
+fn parse_semver(tag: &str) -> Option<(u64, u64, u64)> {
+    let mut parts = tag.strip_prefix('v').unwrap_or(tag).split('.');
+    let mut next = || -> Option<u64> {
+        let part = parts.next()?;
+        // Leading zeros are not allowed by semver, and would make `1.02.0` equal `1.2.0`.
+        if part.is_empty() || (part.len() > 1 && part.starts_with('0')) {
+            return None;
+        }
+        part.parse().ok()
+    };
+    let version = (next()?, next()?, next()?);
+    parts.next().is_none().then_some(version)
+}
+
+/// Tags manifests with the UTC date they were pushed, e.g. `20240131`.
+#[derive(Clone, Debug, Default)]
+pub struct DateStamp {
+    /// Prefix of the tag.
+    prefix: String,
+}
+
+impl DateStamp {
+    /// Creates a new policy, tagging manifests with their push date.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a prefix for the tag, e.g. `nightly-` for `nightly-20240131`.
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+}
+
+impl AutoTagPolicy for DateStamp {
+    fn tags(&self, pushed: &PushedManifest<'_>) -> Vec<String> {
+        let (year, month, day) = civil_date(pushed.pushed_at);
+        vec![format!("{}{year:04}{month:02}{day:02}", self.prefix)]
+    }
+}
+
+/// Returns the UTC date of `time` as year, month and day.
+fn civil_date(time: SystemTime) -> (i64, u32, u32) {
+    let days = (time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86_400) as i64;
+
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+impl ContainerRegistry {
+    /// Applies the configured auto-tagging policies to a manifest pushed by `reference`.
+    pub(crate) async fn auto_tag(
+        &self,
+        location: &ImageLocation,
+        reference: &Reference,
+        digest: Digest,
+    ) -> Result<(), RegistryError> {
+        if self.auto_tag_policies.is_empty() {
+            return Ok(());
+        }
+
+        let tags: Vec<String> = self
+            .storage
+            .list_tags(location)
+            .await?
+            .into_iter()
+            .map(|existing| existing.tag)
+            .collect();
+        let pushed = PushedManifest {
+            location,
+            reference,
+            digest,
+            tags: &tags,
+            pushed_at: self.clock.now(),
+        };
+
+        let mut additional: Vec<String> = self
+            .auto_tag_policies
+            .iter()
+            .flat_map(|policy| policy.tags(&pushed))
+            .filter(|tag| reference.as_tag() != Some(tag.as_str()))
+            .collect();
+        additional.sort();
+        additional.dedup();
+
+        for tag in additional {
+            if !is_valid_tag(&tag) {
+                warn!(%location, %tag, "ignoring invalid tag returned by auto-tagging policy");
+                continue;
+            }
+            self.put_tag(location, &tag, digest).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{civil_date, parse_semver};
+
+    #[test]
+    fn release_versions_are_parsed() {
+        assert_eq!(parse_semver("1.2.3"), Some((1, 2, 3)));
+        assert_eq!(parse_semver("v10.0.12"), Some((10, 0, 12)));
+        assert_eq!(parse_semver("1.2"), None);
+        assert_eq!(parse_semver("1.2.3.4"), None);
+        assert_eq!(parse_semver("1.2.3-rc.1"), None);
+        assert_eq!(parse_semver("1.02.3"), None);
+        assert_eq!(parse_semver("latest"), None);
+    }
+
+    #[test]
+    fn dates_are_computed_in_utc() {
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(civil_date(at(0)), (1970, 1, 1));
+        assert_eq!(civil_date(at(951_782_400)), (2000, 2, 29));
+        assert_eq!(civil_date(at(1_706_745_599)), (2024, 1, 31));
+        assert_eq!(civil_date(at(1_706_745_600)), (2024, 2, 1));
+    }
+}
@@ -7,6 +7,7 @@ use container_registry::{
     hooks::RegistryHooks,
     storage::ManifestReference,
     storage::ImageLocation,
+    types::Manifest,
     ImageDigest
 };
 use sec::Secret;
@@ -19,12 +20,18 @@ struct Opts {
     /// Which address to bind to.
     #[structopt(short, long, default_value = "127.0.0.1:3000")]
     bind: SocketAddr,
+    /// Address to serve the admin API, metrics and health checks on, instead of `bind`.
+    #[structopt(long)]
+    admin_bind: Option<SocketAddr>,
     /// Directory to use as storage.
     #[structopt(short, long)]
     storage: Option<path::PathBuf>,
     /// Password to require.
     #[structopt(short, long)]
     password: Option<String>,
+    /// Run a self-test against storage and auth in the given repository, then exit.
+    #[structopt(long)]
+    self_test: Option<String>,
 }
 
 struct LoggingHook;
@@ -33,8 +40,13 @@ struct LoggingHook;
 
 impl RegistryHooks for LoggingHook {
     /// Notify about an uploaded manifest.
-    async fn on_manifest_uploaded(&self, manifest_reference: &ManifestReference) {
-        info!(%manifest_reference, "new manifest uploaded");
+    async fn on_manifest_uploaded(
+        &self,
+        manifest_reference: &ManifestReference,
+        manifest: &Manifest,
+        _raw: &[u8],
+    ) {
+        info!(%manifest_reference, media_type = manifest.media_type(), "new manifest uploaded");
     }
 }
 
@@ -51,11 +63,15 @@ impl AuthProvider for NoAuth {
         _creds: &ValidCredentials,
         _image: &ImageLocation,
     ) -> Permissions {
-        Permissions::ReadWrite
+        Permissions::ReadWriteDelete
     }
 
     async fn blob_permissions(&self, _creds: &ValidCredentials, _blob: &ImageDigest) -> Permissions {
-        Permissions::ReadWrite
+        Permissions::ReadWriteDelete
+    }
+
+    async fn admin_permissions(&self, _creds: &ValidCredentials) -> Permissions {
+        Permissions::ReadWriteDelete
     }
 }
 
@@ -85,6 +101,13 @@ async fn run() -> anyhow::Result<()> {
         (Some(tmp_dir), storage)
     };
 
+    let credentials = match opts.password {
+        Some(ref password) => Unverified::UsernameAndPassword {
+            username: "self-test".to_owned(),
+            password: Secret::new(password.clone()),
+        },
+        None => Unverified::NoCredentials,
+    };
     let auth_provider: Arc<dyn AuthProvider> = if let Some(password) = opts.password {
         info!("using password supplied on command line");
         let password = Secret::new(password);
@@ -98,9 +121,30 @@ async fn run() -> anyhow::Result<()> {
         .storage(storage)
         .hooks(Box::new(LoggingHook))
         .auth_provider(auth_provider)
+        .separate_admin_routes(opts.admin_bind.is_some())
         .build()
         .context("failed to instantiate registry")?;
 
+    if let Some(repository) = opts.self_test {
+        let report = registry.self_test(&repository, &credentials).await;
+        print!("{report}");
+        anyhow::ensure!(report.passed(), "self-test failed");
+        return Ok(());
+    }
+
+    if let Some(admin_bind) = opts.admin_bind {
+        let admin_listener = tokio::net::TcpListener::bind(admin_bind)
+            .await
+            .context("failed to bind admin listener")?;
+        let admin_app = registry.clone().make_admin_router().layer(TraceLayer::new_for_http());
+        info!(addr=%admin_bind, "serving admin API");
+        tokio::spawn(async move {
+            if let Err(err) = axum::serve(admin_listener, admin_app).await {
+                error!(%err, "admin listener failed");
+            }
+        });
+    }
+
     let app = Router::new()
         .merge(registry.make_router())
         .layer(DefaultBodyLimit::max(1024 * 1024 * 1024))
@@ -115,7 +159,12 @@ async fn run() -> anyhow::Result<()> {
         .context("failed to get local listener address")?;
     info!(%addr, "bound, starting to serve");
 
-    axum::serve(listener, app).await?;
+    // Client addresses are used to lock out clients failing to authenticate.
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
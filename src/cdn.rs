@@ -0,0 +1,224 @@
+//! Serving blobs through a content delivery network.
+//!
+//! Content addressed by digest never changes, so responses serving blobs or manifests requested
+//! by digest carry `Cache-Control: public, max-age=31536000, immutable`. Manifests requested by
+//! tag carry `Cache-Control: no-cache` instead, as tags can be moved at any time. This alone lets
+//! a CDN or caching proxy in front of the registry cache content safely.
+//!
+//! Offloading blob egress additionally requires clients to download blobs from the CDN, which
+//! cannot check credentials itself. With a [`Cdn`] set through
+//! [`ContainerRegistryBuilder::cdn`](crate::ContainerRegistryBuilder::cdn), authorized blob
+//! downloads are answered with a `307 Temporary Redirect` to a signed URL below the CDN's base
+//! URL, e.g.
+//!
+//! ```text
+//! https://cdn.example.com/v2/foo/bar/blobs/sha256:...?expires=1767225600&signature=...
+//! ```
+//!
+//! The CDN is expected to forward cache misses to the registry, including the query string. The
+//! registry serves requests carrying a valid, unexpired signature for the requested blob without
+//! credentials, which makes a signed URL a bearer capability for a single blob until it expires.
+//!
+//! Expiry times are rounded up to a multiple of the URL lifetime, so all URLs signed for a blob
+//! within the same period are identical and can be cached by the CDN as one. Signed URLs are thus
+//! valid for at least the configured lifetime and less than twice of it.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use sec::Secret;
+use serde::Deserialize;
+
+use crate::{
+    hashing::{hmac_sha256, verify_hmac_sha256},
+    storage::ImageLocation,
+    ImageDigest,
+};
+
+/// `Cache-Control` value of content addressed by digest.
+pub(crate) const CACHE_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// `Cache-Control` value of content addressed by tag.
+pub(crate) const CACHE_REVALIDATE: &str = "no-cache";
+
+/// Default lifetime of signed URLs.
+const DEFAULT_URL_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// A CDN blob downloads are redirected to, see the [module documentation](self).
+#[derive(Debug)]
+pub struct Cdn {
+    /// URL the CDN serves the registry's routes under, without a trailing slash.
+    base_url: String,
+    /// Key URLs are signed with.
+    key: Secret<Vec<u8>>,
+    /// Minimum lifetime of signed URLs.
+    url_ttl: Duration,
+}
+
+impl Cdn {
+    /// Creates a new CDN configuration, redirecting to `base_url` and signing URLs with `key`.
+    ///
+    /// `base_url` is the URL under which the CDN serves the registry's routes, e.g.
+    /// `https://cdn.example.com` if `/v2/` requests to the CDN are forwarded to the registry. The
+    /// key should be at least 32 random bytes. Signed URLs are valid for at least an hour by
+    /// default.
+    pub fn new(base_url: impl Into<String>, key: Secret<Vec<u8>>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_owned(),
+            key,
+            url_ttl: DEFAULT_URL_TTL,
+        }
+    }
+
+    /// Sets the minimum lifetime of signed URLs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ttl` is shorter than a second.
+    pub fn url_ttl(mut self, ttl: Duration) -> Self {
+        assert!(
+            ttl.as_secs() > 0,
+            "signed URLs must be valid for at least a second"
+        );
+        self.url_ttl = ttl;
+        self
+    }
+
+    /// Returns the signed CDN URL of a blob, valid at `now`.
+    pub(crate) fn signed_url(
+        &self,
+        location: &ImageLocation,
+        digest: &ImageDigest,
+        now: SystemTime,
+    ) -> String {
+        let period = self.url_ttl.as_secs();
+        let expires = (unix_secs(now) / period + 2) * period;
+        let signature = URL_SAFE_NO_PAD.encode(self.sign(location, digest, expires));
+
+        format!(
+            "{}/v2/{}/{}/blobs/{}?expires={}&signature={}",
+            self.base_url,
+            location.repository(),
+            location.image(),
+            digest,
+            expires,
+            signature
+        )
+    }
+
+    /// Returns whether `signed` is a valid URL signature for the blob, unexpired at `now`.
+    pub(crate) fn verify(
+        &self,
+        location: &ImageLocation,
+        digest: &ImageDigest,
+        signed: &SignedUrl,
+        now: SystemTime,
+    ) -> bool {
+        let (Some(expires), Some(signature)) = (signed.expires, signed.signature.as_deref()) else {
+            return false;
+        };
+        let Ok(signature) = URL_SAFE_NO_PAD.decode(signature) else {
+            return false;
+        };
+
+        expires > unix_secs(now)
+            && verify_hmac_sha256(
+                self.key.reveal(),
+                &Self::signed_message(location, digest, expires),
+                &signature,
+            )
+    }
+
+    /// Signs a blob URL expiring at `expires`.
+    fn sign(&self, location: &ImageLocation, digest: &ImageDigest, expires: u64) -> [u8; 32] {
+        hmac_sha256(
+            self.key.reveal(),
+            &Self::signed_message(location, digest, expires),
+        )
+    }
+
+    /// Returns the message signed for a blob URL expiring at `expires`.
+    fn signed_message(location: &ImageLocation, digest: &ImageDigest, expires: u64) -> Vec<u8> {
+        format!("{location}@{digest}:{expires}").into_bytes()
+    }
+}
+
+/// Query parameters of a signed blob URL.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct SignedUrl {
+    /// Expiration time, in seconds since the Unix epoch.
+    expires: Option<u64>,
+    /// Signature of the URL.
+    signature: Option<String>,
+}
+
+impl SignedUrl {
+    /// Returns whether the request carries a signature at all.
+    pub(crate) fn is_signed(&self) -> bool {
+        self.signature.is_some()
+    }
+}
+
+/// Returns `time` in seconds since the Unix epoch.
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use sec::Secret;
+
+    use super::{Cdn, SignedUrl};
+    use crate::{storage::ImageLocation, ImageDigest};
+
+    #[test]
+    fn signed_urls_are_stable_and_expire() {
+        let cdn = Cdn::new("https://cdn.example.com/", Secret::new(b"key".to_vec()))
+            .url_ttl(Duration::from_secs(100));
+        let location = ImageLocation::new("foo".to_owned(), "bar".to_owned());
+        let digest: ImageDigest =
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+                .parse()
+                .unwrap();
+
+        let issued = UNIX_EPOCH + Duration::from_secs(1_000);
+        let url = cdn.signed_url(&location, &digest, issued);
+        assert!(url.starts_with(&format!(
+            "https://cdn.example.com/v2/foo/bar/blobs/{digest}?expires=1200&signature="
+        )));
+        assert_eq!(
+            url,
+            cdn.signed_url(&location, &digest, issued + Duration::from_secs(99))
+        );
+
+        let (expires, signature) = url
+            .split_once("?expires=")
+            .and_then(|(_, query)| query.split_once("&signature="))
+            .unwrap();
+        let signed = SignedUrl {
+            expires: Some(expires.parse().unwrap()),
+            signature: Some(signature.to_owned()),
+        };
+        assert!(cdn.verify(&location, &digest, &signed, issued));
+        assert!(cdn.verify(
+            &location,
+            &digest,
+            &signed,
+            issued + Duration::from_secs(199)
+        ));
+        assert!(!cdn.verify(
+            &location,
+            &digest,
+            &signed,
+            issued + Duration::from_secs(200)
+        ));
+
+        let other = ImageLocation::new("foo".to_owned(), "baz".to_owned());
+        assert!(!cdn.verify(&other, &digest, &signed, issued));
+        assert!(!cdn.verify(&location, &digest, &SignedUrl::default(), issued));
+    }
+}
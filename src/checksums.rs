@@ -0,0 +1,187 @@
+//! Checksums of upload chunks.
+//!
+//! Uploads are only verified against the blob's digest once they are finalized, so a chunk
+//! corrupted in transit is only noticed after the entire blob has been pushed. Clients can send
+//! the digest of every chunk along with it, which is verified as soon as the chunk has been
+//! received:
+//!
+//! * in an `OCI-Content-Digest` header, if the digest is known before sending the chunk, or
+//! * in an `OCI-Content-Digest` trailer, if it is computed while streaming. The trailer has to be
+//!   announced through a `Trailer: OCI-Content-Digest` header, chunks without a declared digest
+//!   are not hashed.
+//!
+//! The digest covers the chunk as appended to the blob, i.e. after decompressing a body sent with
+//! a `Content-Encoding`. A chunk not matching its digest, or missing its announced trailer, is
+//! discarded and answered with `400 Bad Request` and a `DIGEST_INVALID` error. The `Range` header
+//! of the response reports what is stored of the upload, so the client can send the chunk again.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header::TRAILER, HeaderMap, HeaderName, HeaderValue},
+};
+use futures::Stream;
+use http_body::Body as _;
+use tokio::io::AsyncWrite;
+
+use crate::{
+    hashing::{self, Hasher},
+    storage::Digest,
+    ImageDigest, ImageDigestParseError, RegistryError,
+};
+
+/// Digest of an upload chunk, sent as a header or trailer.
+pub(crate) const OCI_CONTENT_DIGEST: HeaderName = HeaderName::from_static("oci-content-digest");
+
+/// Where the digest of a chunk is sent.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum DeclaredDigest {
+    /// Sent upfront, as a header.
+    Header(Digest),
+    /// Announced as a trailer, following the body.
+    Trailer,
+}
+
+impl DeclaredDigest {
+    /// Determines the declared digest of a chunk from the request headers, if any.
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, RegistryError> {
+        if let Some(value) = headers.get(OCI_CONTENT_DIGEST) {
+            return parse(value).map(|digest| Some(Self::Header(digest)));
+        }
+
+        let announced = headers
+            .get_all(TRAILER)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|name| {
+                name.trim()
+                    .eq_ignore_ascii_case(OCI_CONTENT_DIGEST.as_str())
+            });
+        Ok(announced.then_some(Self::Trailer))
+    }
+
+    /// Returns the declared digest, once the body and its trailers have been received.
+    ///
+    /// Returns `None` if an announced trailer was never sent.
+    pub(crate) fn resolve(
+        self,
+        trailers: Option<&HeaderMap>,
+    ) -> Result<Option<Digest>, RegistryError> {
+        match self {
+            DeclaredDigest::Header(digest) => Ok(Some(digest)),
+            DeclaredDigest::Trailer => trailers
+                .and_then(|trailers| trailers.get(OCI_CONTENT_DIGEST))
+                .map(parse)
+                .transpose(),
+        }
+    }
+}
+
+/// Parses a digest given as `sha256:<hex>`.
+fn parse(value: &HeaderValue) -> Result<Digest, RegistryError> {
+    value
+        .to_str()
+        .map_err(|_| RegistryError::InvalidDigest(ImageDigestParseError::HexDecodeError))?
+        .trim()
+        .parse::<ImageDigest>()
+        .map(|digest| digest.digest)
+        .map_err(RegistryError::InvalidDigest)
+}
+
+/// A request body, keeping its trailers once all data has been read.
+pub(crate) struct TrailingBody {
+    /// The body.
+    body: Body,
+    /// Trailers received after the data, if any.
+    trailers: Option<HeaderMap>,
+}
+
+impl TrailingBody {
+    /// Wraps a request body.
+    pub(crate) fn new(body: Body) -> Self {
+        Self {
+            body,
+            trailers: None,
+        }
+    }
+
+    /// Returns the trailers, if the body has been read completely and had any.
+    pub(crate) fn trailers(&self) -> Option<&HeaderMap> {
+        self.trailers.as_ref()
+    }
+}
+
+impl Stream for TrailingBody {
+    type Item = Result<Bytes, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let Some(frame) = ready!(Pin::new(&mut self.body).poll_frame(cx)) else {
+                return Poll::Ready(None);
+            };
+
+            match frame?.into_data() {
+                Ok(data) => return Poll::Ready(Some(Ok(data))),
+                Err(frame) => {
+                    if let Ok(trailers) = frame.into_trailers() {
+                        self.trailers = Some(trailers);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A writer hashing everything written through it, if enabled.
+pub(crate) struct HashingWriter<W> {
+    /// The wrapped writer.
+    inner: W,
+    /// Hash of everything written, if enabled.
+    hasher: Option<hashing::Sha256>,
+}
+
+impl<W> HashingWriter<W> {
+    /// Wraps `inner`, hashing written data only if `enabled`.
+    pub(crate) fn new(inner: W, enabled: bool) -> Self {
+        Self {
+            inner,
+            hasher: enabled.then(Default::default),
+        }
+    }
+
+    /// Returns the digest of everything written, if enabled.
+    pub(crate) fn digest(self) -> Option<Digest> {
+        self.hasher.map(|hasher| Digest::new(hasher.finalize()))
+    }
+}
+
+impl<W> AsyncWrite for HashingWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        if let Some(ref mut hasher) = self.hasher {
+            hasher.update(&buf[..written]);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
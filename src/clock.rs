@@ -0,0 +1,92 @@
+//! Time sources.
+//!
+//! Time-based behavior of the registry reads the current time from a [`Clock`], which can be set
+//! through [`ContainerRegistryBuilder::clock`](crate::ContainerRegistryBuilder::clock). This covers
+//! the expiry of upload sessions, pull tokens, purge confirmations and lockouts, the grace periods
+//! of maintenance tasks and the timestamps of attestations, operations and snapshots. By default,
+//! the [`SystemClock`] is used. Tests can use a [`ManualClock`] instead, simulating time passing
+//! deterministically instead of sleeping.
+//!
+//! Some time measurements are deliberately left to the system: schedules of maintenance jobs run
+//! on the `tokio` timer, which can be paused in tests on its own, and latency metrics as well as
+//! bandwidth limits always measure real time. Modification times of stored files, which grace
+//! periods are compared against, are set by the filesystem.
+
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// A source of the current time.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the current wall-clock time, used for timestamps.
+    fn now(&self) -> SystemTime;
+
+    /// Returns the current monotonic time, used for measuring durations.
+    fn instant(&self) -> Instant;
+}
+
+/// The system's clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to.
+///
+/// Starts at the time it was created and stands still until [`ManualClock::advance`] is called.
+#[derive(Debug)]
+pub struct ManualClock {
+    /// Wall-clock time the clock was created at.
+    start: SystemTime,
+    /// Monotonic time the clock was created at.
+    start_instant: Instant,
+    /// Time the clock has been advanced by.
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    /// Creates a new clock standing still at the current time.
+    pub fn new() -> Self {
+        Self {
+            start: SystemTime::now(),
+            start_instant: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().expect("lock poisoned") += duration;
+    }
+
+    /// Returns the time the clock has been advanced by.
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().expect("lock poisoned")
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        self.start + self.elapsed()
+    }
+
+    fn instant(&self) -> Instant {
+        self.start_instant + self.elapsed()
+    }
+}
@@ -0,0 +1,231 @@
+//! Compatibility with the quirks of known clients.
+//!
+//! The OCI distribution spec leaves a few details open, and some clients depend on the behavior of
+//! the reference implementation where it deviates from the spec. Requests are attributed to a
+//! [`Client`] by their `User-Agent`, and responses adjusted to what that client expects:
+//!
+//! * Docker and containerd check for a `Docker-Distribution-API-Version: registry/2.0` header to
+//!   confirm they are talking to a v2 registry. It is not part of the OCI spec and only sent to
+//!   them.
+//! * Upload chunks not continuing where the upload left off are answered with
+//!   `308 Permanent Redirect` and the stored range by default, which lets docker and podman resume
+//!   the upload. ORAS only accepts the status codes given by the spec and receives
+//!   `416 Range Not Satisfiable` instead, along with the same `Range` header.
+//!
+//! Unknown clients receive the default responses. The detected client is available to handlers as
+//! a request extension and extractor. Quirks can be disabled entirely through
+//! [`ContainerRegistryBuilder::strict_spec_compliance`](crate::ContainerRegistryBuilder::strict_spec_compliance).
+//!
+//! Requests deviating from the spec in ways the registry tolerates, e.g. manifests pushed without
+//! their media type as `Content-Type`, are accepted silently. With
+//! [`ContainerRegistryBuilder::report_spec_deviations`](crate::ContainerRegistryBuilder::report_spec_deviations),
+//! every tolerated deviation is logged as a warning instead, naming the route, the client and what
+//! was off. Responses deviating from the spec for the sake of a client's quirks are logged as well.
+//! This is meant for debugging and conformance work, as it can get noisy with popular clients.
+
+use std::{convert::Infallible, fmt, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{header::USER_AGENT, request::Parts, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use tracing::{trace, warn};
+
+use crate::ContainerRegistry;
+
+/// API version header expected by Docker clients.
+const DOCKER_DISTRIBUTION_API_VERSION: HeaderName =
+    HeaderName::from_static("docker-distribution-api-version");
+
+/// A client known to have quirks.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Client {
+    /// The Docker engine and CLI.
+    Docker,
+    /// Podman, buildah, skopeo and other users of `containers/image`.
+    Podman,
+    /// containerd, including `ctr` and `nerdctl`.
+    Containerd,
+    /// The ORAS CLI and library.
+    Oras,
+    /// Bazel's `rules_oci`, which uses crane (`go-containerregistry`) and Bazel's downloader.
+    RulesOci,
+    /// Any other client.
+    Other,
+}
+
+impl Client {
+    /// Detects the client from a `User-Agent` header.
+    ///
+    /// Docker daemons forward the CLI's agent as `UpstreamClient(...)`, which is ignored, as the
+    /// daemon is the one talking to the registry.
+    pub fn from_user_agent(user_agent: &str) -> Self {
+        let product = |name: &str| {
+            user_agent
+                .split_whitespace()
+                .any(|token| token.to_ascii_lowercase().starts_with(name))
+        };
+
+        if product("docker/") {
+            Client::Docker
+        } else if product("containers/") || product("podman/") || product("skopeo/") {
+            Client::Podman
+        } else if product("containerd/") || product("nerdctl/") {
+            Client::Containerd
+        } else if product("oras/") || product("oras-go/") {
+            Client::Oras
+        } else if product("bazel/") || product("crane/") || product("go-containerregistry/") {
+            Client::RulesOci
+        } else {
+            Client::Other
+        }
+    }
+
+    /// Returns the quirks of the client.
+    fn quirks(self) -> Quirks {
+        match self {
+            Client::Docker | Client::Containerd => Quirks {
+                api_version_header: true,
+                ..Quirks::default()
+            },
+            Client::Oras => Quirks {
+                strict_upload_offsets: true,
+                ..Quirks::default()
+            },
+            Client::Podman | Client::RulesOci | Client::Other => Quirks::default(),
+        }
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Client::Docker => "docker",
+            Client::Podman => "podman",
+            Client::Containerd => "containerd",
+            Client::Oras => "oras",
+            Client::RulesOci => "rules_oci",
+            Client::Other => "other",
+        })
+    }
+}
+
+/// Extracts the client detected by the quirks middleware, [`Client::Other`] outside of it.
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Client {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<Client>()
+            .copied()
+            .unwrap_or(Client::Other))
+    }
+}
+
+/// Deviations from the default behavior expected by a client.
+#[derive(Debug, Default)]
+struct Quirks {
+    /// Send the `Docker-Distribution-API-Version` header.
+    api_version_header: bool,
+    /// Answer misplaced upload chunks with `416 Range Not Satisfiable` instead of a redirect.
+    strict_upload_offsets: bool,
+}
+
+/// Middleware detecting the client and adjusting responses to its quirks.
+pub(crate) async fn apply_quirks(
+    State(registry): State<Arc<ContainerRegistry>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let client = request
+        .headers()
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map_or(Client::Other, Client::from_user_agent);
+    request.extensions_mut().insert(client);
+
+    let is_chunk = request.method() == Method::PATCH;
+    let mut response = next.run(request).await;
+    if registry.strict_spec_compliance {
+        return response;
+    }
+
+    let quirks = client.quirks();
+    if quirks.api_version_header {
+        response.headers_mut().insert(
+            DOCKER_DISTRIBUTION_API_VERSION,
+            HeaderValue::from_static("registry/2.0"),
+        );
+    }
+    if is_chunk && response.status() == StatusCode::PERMANENT_REDIRECT {
+        if quirks.strict_upload_offsets {
+            trace!(%client, "answering misplaced chunk as specified");
+            *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+        } else {
+            registry.spec_deviation(
+                "upload_add_chunk",
+                client,
+                "answered misplaced chunk with 308 instead of 416",
+            );
+        }
+    }
+
+    response
+}
+
+impl ContainerRegistry {
+    /// Reports a deviation from the spec tolerated by `route`, if enabled.
+    ///
+    /// See the [module documentation](self).
+    pub(crate) fn spec_deviation(
+        &self,
+        route: &'static str,
+        client: Client,
+        deviation: impl fmt::Display,
+    ) {
+        if self.report_spec_deviations {
+            warn!(route, %client, %deviation, "tolerated spec deviation");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Client;
+
+    #[test]
+    fn clients_are_detected_by_user_agent() {
+        let agents = [
+            (
+                "docker/24.0.7 go/go1.20.10 git-commit/311b9ff kernel/6.5.0 os/linux arch/amd64 \
+                 UpstreamClient(Docker-Client/24.0.7 \\(linux\\))",
+                Client::Docker,
+            ),
+            (
+                "containers/5.29.2 (github.com/containers/image)",
+                Client::Podman,
+            ),
+            ("skopeo/1.14.0", Client::Podman),
+            ("containerd/v1.7.11", Client::Containerd),
+            ("nerdctl/1.7.2", Client::Containerd),
+            ("oras/1.1.0+Homebrew", Client::Oras),
+            ("oras-go/2.3.1", Client::Oras),
+            ("Bazel/release 7.0.0", Client::RulesOci),
+            (
+                "crane/v0.19.0 go-containerregistry/v0.19.0",
+                Client::RulesOci,
+            ),
+            ("curl/8.5.0", Client::Other),
+            ("", Client::Other),
+        ];
+
+        for (user_agent, client) in agents {
+            assert_eq!(Client::from_user_agent(user_agent), client, "{user_agent}");
+        }
+    }
+}
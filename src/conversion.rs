@@ -0,0 +1,271 @@
+//! Conversion between Docker and OCI media types.
+//!
+//! Docker image manifests (schema 2) and manifest lists are structurally the same as OCI image
+//! manifests and indices, only their media types differ. Older clients may only understand one of
+//! the formats, which gets in the way when mixed fleets of clients pull the same tags. With
+//! [`ContainerRegistryBuilder::convert_media_types`](crate::ContainerRegistryBuilder::convert_media_types)
+//! enabled, manifests pulled by tag are converted to the other format if the client's `Accept`
+//! header does not list the stored format, but does list its counterpart.
+//!
+//! The converted manifest has a digest of its own, which is advertised in the
+//! `Docker-Content-Digest` header. It is stored alongside the original, so clients can pull it
+//! by digest afterwards. Indices are converted along with the image manifests they list, which
+//! are stored the same way. Manifests pulled by digest are always served as stored, as their
+//! contents must match the digest.
+//!
+//! Only manifests that can be represented in both formats are converted: images using layer media
+//! types without counterpart, artifacts and manifests referring to others through their `subject`
+//! are always served as stored. Converted manifests are not tagged, so they are removed by garbage
+//! collecting dangling manifests and converted again on the next pull.
+
+use axum::http::{header::ACCEPT, HeaderMap};
+use serde_json::Value;
+use tracing::info;
+
+use crate::{
+    storage::{Digest, ImageLocation, ManifestReference, Reference},
+    types::{DOCKER_MANIFEST, DOCKER_MANIFEST_LIST, OCI_IMAGE_INDEX, OCI_IMAGE_MANIFEST},
+    ContainerRegistry, ImageDigest, RegistryError,
+};
+
+/// Pairs of equivalent OCI and Docker media types.
+const EQUIVALENTS: &[(&str, &str)] = &[
+    (OCI_IMAGE_MANIFEST, DOCKER_MANIFEST),
+    (OCI_IMAGE_INDEX, DOCKER_MANIFEST_LIST),
+    (
+        "application/vnd.oci.image.config.v1+json",
+        "application/vnd.docker.container.image.v1+json",
+    ),
+    (
+        "application/vnd.oci.image.layer.v1.tar",
+        "application/vnd.docker.image.rootfs.diff.tar",
+    ),
+    (
+        "application/vnd.oci.image.layer.v1.tar+gzip",
+        "application/vnd.docker.image.rootfs.diff.tar.gzip",
+    ),
+    (
+        "application/vnd.oci.image.layer.nondistributable.v1.tar",
+        "application/vnd.docker.image.rootfs.foreign.diff.tar",
+    ),
+    (
+        "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip",
+        "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip",
+    ),
+];
+
+/// Returns the equivalent of `media_type` in the other format.
+fn counterpart(media_type: &str) -> Option<&'static str> {
+    EQUIVALENTS.iter().find_map(|&(oci, docker)| {
+        if media_type == oci {
+            Some(docker)
+        } else if media_type == docker {
+            Some(oci)
+        } else {
+            None
+        }
+    })
+}
+
+/// Returns the media type to convert a manifest of type `media_type` to for a client sending
+/// `headers`, `None` if it is to be served as stored.
+pub(crate) fn conversion_target(media_type: &str, headers: &HeaderMap) -> Option<&'static str> {
+    let accepted: Vec<&str> = headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|entry| entry.split(';').next().unwrap_or_default().trim())
+        .filter(|entry| !entry.is_empty())
+        .collect();
+
+    // Clients not stating preferences, or accepting the stored format, receive it.
+    if accepted.is_empty()
+        || accepted
+            .iter()
+            .any(|&entry| entry == media_type || entry == "*/*" || entry == "application/*")
+    {
+        return None;
+    }
+
+    counterpart(media_type).filter(|target| accepted.contains(target))
+}
+
+/// Converts a parsed image manifest or index to `target`, without touching the manifests an index
+/// lists.
+///
+/// Returns `None` if the manifest cannot be represented in the other format.
+fn convert_fields(manifest: &mut Value, target: &'static str) -> Option<()> {
+    let fields = manifest.as_object_mut()?;
+    if fields.contains_key("subject") || fields.contains_key("artifactType") {
+        return None;
+    }
+    fields.insert("mediaType".to_owned(), target.into());
+
+    if target == OCI_IMAGE_MANIFEST || target == DOCKER_MANIFEST {
+        convert_descriptor(fields.get_mut("config")?)?;
+        for layer in fields.get_mut("layers")?.as_array_mut()? {
+            convert_descriptor(layer)?;
+        }
+    }
+    Some(())
+}
+
+/// Converts the media type of a descriptor.
+fn convert_descriptor(descriptor: &mut Value) -> Option<()> {
+    let media_type = descriptor.get_mut("mediaType")?;
+    *media_type = counterpart(media_type.as_str()?)?.into();
+    Some(())
+}
+
+/// Serializes a converted manifest.
+fn serialize(manifest: &Value) -> Vec<u8> {
+    serde_json::to_vec(manifest).expect("serialization should not fail")
+}
+
+impl ContainerRegistry {
+    /// Converts the manifest `raw` stored at `location` to `target`, storing the converted
+    /// manifest, see the [`conversion`](crate::conversion) module.
+    ///
+    /// Returns the converted manifest and its digest, `None` if it cannot be converted.
+    pub(crate) async fn convert_manifest(
+        &self,
+        location: &ImageLocation,
+        raw: &[u8],
+        target: &'static str,
+    ) -> Result<Option<(Vec<u8>, Digest)>, RegistryError> {
+        let Ok(mut manifest) = serde_json::from_slice::<Value>(raw) else {
+            return Ok(None);
+        };
+        if convert_fields(&mut manifest, target).is_none() {
+            return Ok(None);
+        }
+
+        let mut converted_children = Vec::new();
+        if target == OCI_IMAGE_INDEX || target == DOCKER_MANIFEST_LIST {
+            let Some(children) = manifest.get_mut("manifests").and_then(Value::as_array_mut) else {
+                return Ok(None);
+            };
+
+            for descriptor in children {
+                let Some(child_target) = descriptor
+                    .get("mediaType")
+                    .and_then(Value::as_str)
+                    .and_then(counterpart)
+                    .filter(|&child| child == OCI_IMAGE_MANIFEST || child == DOCKER_MANIFEST)
+                else {
+                    return Ok(None);
+                };
+                let Some(child_digest) = descriptor
+                    .get("digest")
+                    .and_then(Value::as_str)
+                    .and_then(|digest| digest.parse::<ImageDigest>().ok())
+                else {
+                    return Ok(None);
+                };
+                let Some(child_raw) = self
+                    .storage
+                    .get_manifest(&ManifestReference::new(
+                        location.clone(),
+                        Reference::new_digest(child_digest.digest),
+                    ))
+                    .await?
+                else {
+                    return Ok(None);
+                };
+                let Ok(mut child) = serde_json::from_slice::<Value>(&child_raw) else {
+                    return Ok(None);
+                };
+                if convert_fields(&mut child, child_target).is_none() {
+                    return Ok(None);
+                }
+
+                let child_raw = serialize(&child);
+                let child_digest = Digest::from_contents(&child_raw);
+                descriptor["mediaType"] = child_target.into();
+                descriptor["digest"] = ImageDigest::new(child_digest).to_string().into();
+                descriptor["size"] = child_raw.len().into();
+                converted_children.push((child_raw, child_digest));
+            }
+        }
+
+        // Children first, so the converted index never refers to missing manifests.
+        for (child_raw, child_digest) in &converted_children {
+            self.store_converted(location, child_raw, *child_digest)
+                .await?;
+        }
+        let converted = serialize(&manifest);
+        let digest = Digest::from_contents(&converted);
+        self.store_converted(location, &converted, digest).await?;
+
+        Ok(Some((converted, digest)))
+    }
+
+    /// Stores a converted manifest by digest, unless already stored.
+    async fn store_converted(
+        &self,
+        location: &ImageLocation,
+        raw: &[u8],
+        digest: Digest,
+    ) -> Result<(), RegistryError> {
+        let reference = ManifestReference::new(location.clone(), Reference::new_digest(digest));
+        if self.storage.get_manifest(&reference).await?.is_none() {
+            self.storage.put_manifest(&reference, raw).await?;
+            info!(%location, %digest, "stored converted manifest");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header::ACCEPT, HeaderMap, HeaderValue};
+
+    use super::conversion_target;
+    use crate::types::{DOCKER_MANIFEST, OCI_IMAGE_INDEX, OCI_IMAGE_MANIFEST};
+
+    #[test]
+    fn targets_follow_accept_headers() {
+        let accepting = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT, HeaderValue::from_static(value));
+            headers
+        };
+
+        assert_eq!(
+            conversion_target(OCI_IMAGE_MANIFEST, &accepting(DOCKER_MANIFEST)),
+            Some(DOCKER_MANIFEST)
+        );
+        assert_eq!(
+            conversion_target(
+                DOCKER_MANIFEST,
+                &accepting("application/vnd.oci.image.manifest.v1+json; q=0.9, text/plain")
+            ),
+            Some(OCI_IMAGE_MANIFEST)
+        );
+
+        // The stored format is preferred whenever it is acceptable.
+        assert_eq!(
+            conversion_target(
+                OCI_IMAGE_MANIFEST,
+                &accepting(
+                    "application/vnd.docker.distribution.manifest.v2+json, \
+                     application/vnd.oci.image.manifest.v1+json"
+                )
+            ),
+            None
+        );
+        assert_eq!(
+            conversion_target(OCI_IMAGE_MANIFEST, &accepting("*/*")),
+            None
+        );
+        assert_eq!(
+            conversion_target(OCI_IMAGE_MANIFEST, &HeaderMap::new()),
+            None
+        );
+        assert_eq!(
+            conversion_target(OCI_IMAGE_INDEX, &accepting(DOCKER_MANIFEST)),
+            None
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! Compressed request bodies.
+//!
+//! Some clients compress request bodies, announcing it through the `Content-Encoding` header.
+//! Bodies are decompressed transparently, with the decompressed size being capped to guard against
+//! decompression bombs.
+
+use async_compression::tokio::bufread::{GzipDecoder, ZlibDecoder};
+use axum::http::{header::CONTENT_ENCODING, HeaderMap};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{api_error::ApiError, RegistryError};
+
+/// Size of the buffer used when copying decompressed data.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Encoding of a request body.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum ContentEncoding {
+    /// Uncompressed.
+    Identity,
+    /// Compressed using gzip.
+    Gzip,
+    /// Compressed using zlib (HTTP's `deflate`).
+    Deflate,
+}
+
+impl ContentEncoding {
+    /// Determines the encoding of a request body from its headers.
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Result<Self, ApiError> {
+        let Some(value) = headers.get(CONTENT_ENCODING) else {
+            return Ok(Self::Identity);
+        };
+
+        let value = value
+            .to_str()
+            .map_err(|_| ApiError::UnsupportedContentEncoding)?
+            .trim();
+
+        if value.eq_ignore_ascii_case("identity") {
+            Ok(Self::Identity)
+        } else if value.eq_ignore_ascii_case("gzip") || value.eq_ignore_ascii_case("x-gzip") {
+            Ok(Self::Gzip)
+        } else if value.eq_ignore_ascii_case("deflate") {
+            Ok(Self::Deflate)
+        } else {
+            Err(ApiError::UnsupportedContentEncoding)
+        }
+    }
+
+    /// Wraps `reader`, decoding its contents.
+    pub(crate) fn decode<'a, R>(self, reader: R) -> Box<dyn AsyncRead + Send + Unpin + 'a>
+    where
+        R: AsyncBufRead + Send + Unpin + 'a,
+    {
+        match self {
+            ContentEncoding::Identity => Box::new(reader),
+            ContentEncoding::Gzip => Box::new(GzipDecoder::new(reader)),
+            ContentEncoding::Deflate => Box::new(ZlibDecoder::new(reader)),
+        }
+    }
+}
+
+/// Copies a decoded body from `reader` to `writer`, returning the number of bytes copied.
+///
+/// Fails with [`ApiError::PayloadTooLarge`] if the body exceeds `limit` bytes.
+pub(crate) async fn copy_limited<R, W>(
+    reader: R,
+    writer: &mut W,
+    limit: u64,
+) -> Result<u64, ApiError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    // Reading one byte past the limit tells us whether it was exceeded.
+    let mut reader = reader.take(limit.saturating_add(1));
+    let mut buf = vec![0; BUFFER_SIZE];
+    let mut copied: u64 = 0;
+
+    loop {
+        let read = reader
+            .read(&mut buf)
+            .await
+            .map_err(ApiError::DecompressionFailed)?;
+        if read == 0 {
+            break;
+        }
+
+        copied += read as u64;
+        if copied > limit {
+            return Err(ApiError::PayloadTooLarge);
+        }
+
+        writer
+            .write_all(&buf[..read])
+            .await
+            .map_err(RegistryError::LocalWriteFailed)?;
+    }
+
+    Ok(copied)
+}
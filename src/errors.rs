@@ -0,0 +1,190 @@
+//! Customizing error responses.
+//!
+//! Error responses carry the error codes of the OCI distribution spec along with fixed English
+//! messages, or a plain text message for errors not covered by the spec. Embedders can decorate or
+//! translate them, e.g. to point users at a support contact, add correlation IDs or localize
+//! messages, by setting an [`ErrorDecorator`] through
+//! [`ContainerRegistryBuilder::error_decorator`](crate::ContainerRegistryBuilder::error_decorator):
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use container_registry::{errors::ErrorResponse, ContainerRegistry};
+//!
+//! let builder = ContainerRegistry::builder().error_decorator(Arc::new(
+//!     |response: &mut ErrorResponse| {
+//!         response.extra.insert(
+//!             "support".to_owned(),
+//!             "https://help.example.com/registry".into(),
+//!         );
+//!     },
+//! ));
+//! ```
+//!
+//! Every response with a `4xx` or `5xx` status passes through the decorator before being sent.
+//! Decorators may change messages, details and headers, and add fields to OCI error bodies, but not
+//! the status or the error codes, which clients rely on to handle errors. Plain text bodies stay
+//! plain text.
+
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header::CONTENT_LENGTH, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::warn;
+
+use crate::ContainerRegistry;
+
+/// Maximum size of an error response body to decorate.
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+/// A decorator of error responses, see the [module documentation](self).
+///
+/// Implemented for closures taking an [`ErrorResponse`].
+pub trait ErrorDecorator: Send + Sync {
+    /// Decorates an error response before it is sent.
+    fn decorate(&self, response: &mut ErrorResponse);
+}
+
+impl<F> ErrorDecorator for F
+where
+    F: Fn(&mut ErrorResponse) + Send + Sync,
+{
+    fn decorate(&self, response: &mut ErrorResponse) {
+        self(response)
+    }
+}
+
+/// An error response about to be sent.
+#[derive(Debug)]
+pub struct ErrorResponse {
+    /// Method of the request answered.
+    method: Method,
+    /// Path of the request answered.
+    path: String,
+    /// Status of the response.
+    status: StatusCode,
+    /// Headers of the response.
+    pub headers: HeaderMap,
+    /// Errors reported in an OCI error body, empty for plain text bodies.
+    pub errors: Vec<ErrorEntry>,
+    /// Message of a plain text body, if any.
+    pub text: Option<String>,
+    /// Additional top-level fields of an OCI error body, ignored for plain text bodies.
+    pub extra: Map<String, Value>,
+}
+
+impl ErrorResponse {
+    /// Returns the method of the request answered.
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    /// Returns the path of the request answered, e.g. `/v2/foo/bar/manifests/latest`.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Returns the status of the response.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+}
+
+/// A single error of an OCI error body.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ErrorEntry {
+    /// The error code, e.g. `BLOB_UNKNOWN`.
+    code: String,
+    /// Message describing the error.
+    pub message: String,
+    /// Unstructured details on the error, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<Value>,
+}
+
+impl ErrorEntry {
+    /// Returns the error code, e.g. `BLOB_UNKNOWN`.
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+}
+
+/// Body of an OCI error response.
+#[derive(Debug, Deserialize, Serialize)]
+struct ErrorBody {
+    /// Errors reported.
+    errors: Vec<ErrorEntry>,
+    /// Any other fields.
+    #[serde(flatten)]
+    extra: Map<String, Value>,
+}
+
+/// Middleware passing error responses through the configured [`ErrorDecorator`].
+pub(crate) async fn decorate_errors(
+    State(registry): State<Arc<ContainerRegistry>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(ref decorator) = registry.error_decorator else {
+        return next.run(request).await;
+    };
+
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+    let response = next.run(request).await;
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let raw = match axum::body::to_bytes(body, MAX_ERROR_BODY).await {
+        Ok(raw) => raw,
+        Err(err) => {
+            warn!(%err, %path, "could not read error response to decorate");
+            parts.headers.remove(CONTENT_LENGTH);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+
+    let (errors, text, extra) = match serde_json::from_slice::<ErrorBody>(&raw) {
+        Ok(body) => (body.errors, None, body.extra),
+        Err(_) if raw.is_empty() => (Vec::new(), None, Map::new()),
+        Err(_) => (
+            Vec::new(),
+            Some(String::from_utf8_lossy(&raw).into_owned()),
+            Map::new(),
+        ),
+    };
+    let is_json = text.is_none() && !raw.is_empty();
+    let mut decorated = ErrorResponse {
+        method,
+        path,
+        status,
+        headers: parts.headers,
+        errors,
+        text,
+        extra,
+    };
+    decorator.decorate(&mut decorated);
+
+    let body = if is_json {
+        serde_json::to_vec(&ErrorBody {
+            errors: decorated.errors,
+            extra: decorated.extra,
+        })
+        .expect("serialization should not fail")
+    } else {
+        decorated.text.map(String::into_bytes).unwrap_or_default()
+    };
+    parts.headers = decorated.headers;
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
@@ -0,0 +1,211 @@
+//! Live registry events.
+//!
+//! Pushes, tag changes and maintenance runs are published as [`RegistryEvent`]s alongside the
+//! corresponding hook invocations. Embedders can receive them through
+//! [`ContainerRegistry::subscribe`](crate::ContainerRegistry::subscribe), dashboards and
+//! controllers can stream them as [Server-Sent Events] from `GET /v2/_events` instead of polling:
+//!
+//! ```text
+//! event: manifest_pushed
+//! data: {"type":"manifest_pushed","repository":"foo","image":"bar","reference":"latest","digest":"sha256:..."}
+//! ```
+//!
+//! Events concerning an image are only streamed to clients allowed to read it. Streams are closed
+//! once the auth provider is replaced, see
+//! [`ContainerRegistry::set_auth_provider`](crate::ContainerRegistry::set_auth_provider). Events
+//! are not persisted, a client that falls too far behind receives a `lagged` event carrying the
+//! number of events it missed, after which it should resynchronize by other means.
+//!
+//! [Server-Sent Events]: https://html.spec.whatwg.org/multipage/server-sent-events.html
+
+use std::{convert::Infallible, sync::Arc};
+
+use axum::{
+    extract::State,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::Stream;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{
+    auth::ValidCredentials,
+    maintenance::{MaintenanceReport, TaskSummary},
+    storage::{ImageLocation, Reference},
+    webhooks::Webhooks,
+    ContainerRegistry, ImageDigest,
+};
+
+/// Number of events buffered per subscriber before it is considered lagging.
+const EVENT_BUFFER: usize = 1024;
+
+/// An event in the registry.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RegistryEvent {
+    /// A manifest has been uploaded.
+    ManifestPushed {
+        /// Location the manifest was uploaded to.
+        #[serde(flatten)]
+        location: ImageLocation,
+        /// Tag or digest the manifest was uploaded as.
+        reference: Reference,
+        /// Digest of the manifest.
+        digest: ImageDigest,
+    },
+    /// A tag has been pointed at an existing manifest.
+    TagUpdated {
+        /// Location of the tag.
+        #[serde(flatten)]
+        location: ImageLocation,
+        /// The tag.
+        tag: String,
+        /// Digest of the manifest the tag now points to.
+        digest: ImageDigest,
+    },
+    /// A tag has been removed.
+    TagDeleted {
+        /// Location of the tag.
+        #[serde(flatten)]
+        location: ImageLocation,
+        /// The removed tag.
+        tag: String,
+        /// Who removed the tag, [`MAINTENANCE_IDENTITY`](crate::auth::MAINTENANCE_IDENTITY) for
+        /// removals by maintenance tasks or tag limits. `None` if unknown, e.g. when removed
+        /// through the library API.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        actor: Option<String>,
+    },
+    /// A maintenance task run has completed, e.g. a garbage collection.
+    MaintenanceCompleted {
+        /// Name of the task that ran.
+        task: String,
+        /// Wall clock time the run took, in milliseconds.
+        duration_ms: u64,
+        /// The task's summary, if it succeeded.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        summary: Option<TaskSummary>,
+        /// Description of the error, if it failed.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+impl RegistryEvent {
+    /// Returns the name of the event's type, as used for the `event` field when streamed.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RegistryEvent::ManifestPushed { .. } => "manifest_pushed",
+            RegistryEvent::TagUpdated { .. } => "tag_updated",
+            RegistryEvent::TagDeleted { .. } => "tag_deleted",
+            RegistryEvent::MaintenanceCompleted { .. } => "maintenance_completed",
+        }
+    }
+
+    /// Returns the location the event concerns, `None` for registry-wide events.
+    pub fn location(&self) -> Option<&ImageLocation> {
+        match self {
+            RegistryEvent::ManifestPushed { location, .. }
+            | RegistryEvent::TagUpdated { location, .. }
+            | RegistryEvent::TagDeleted { location, .. } => Some(location),
+            RegistryEvent::MaintenanceCompleted { .. } => None,
+        }
+    }
+}
+
+impl From<&MaintenanceReport> for RegistryEvent {
+    fn from(report: &MaintenanceReport) -> Self {
+        let (summary, error) = match report.outcome {
+            Ok(ref summary) => (Some(summary.clone()), None),
+            Err(ref err) => (None, Some(err.clone())),
+        };
+
+        RegistryEvent::MaintenanceCompleted {
+            task: report.task.to_owned(),
+            duration_ms: report.duration.as_millis() as u64,
+            summary,
+            error,
+        }
+    }
+}
+
+/// Fans out events to all subscribers.
+#[derive(Debug)]
+pub(crate) struct EventBus {
+    /// Sending half of the channel, subscribers hold the receiving halves.
+    sender: broadcast::Sender<Arc<RegistryEvent>>,
+    /// Webhooks every event is delivered to, if any.
+    webhooks: Option<Arc<Webhooks>>,
+}
+
+impl EventBus {
+    /// Creates a new event bus, delivering events to `webhooks` as well.
+    pub(crate) fn new(webhooks: Option<Arc<Webhooks>>) -> Self {
+        Self {
+            sender: broadcast::channel(EVENT_BUFFER).0,
+            webhooks,
+        }
+    }
+
+    /// Publishes an event to all current subscribers and webhooks.
+    pub(crate) fn publish(&self, event: RegistryEvent) {
+        if let Some(ref webhooks) = self.webhooks {
+            webhooks.dispatch(&event);
+        }
+
+        // Sending only fails if there are no subscribers, in which case nobody is interested.
+        let _ = self.sender.send(Arc::new(event));
+    }
+
+    /// Subscribes to all events published from now on.
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<Arc<RegistryEvent>> {
+        self.sender.subscribe()
+    }
+}
+
+/// Streams registry events as Server-Sent Events.
+pub(crate) async fn events_get(
+    State(registry): State<Arc<ContainerRegistry>>,
+    creds: ValidCredentials,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = registry.events.subscribe();
+
+    let stream = futures::stream::unfold(
+        (receiver, registry, creds),
+        |(mut receiver, registry, creds)| async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        let lagged = Event::default().event("lagged").data(missed.to_string());
+                        return Some((Ok(lagged), (receiver, registry, creds)));
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                };
+
+                // Permissions granted by a replaced provider must not outlive it.
+                if !registry.is_current_auth_provider(&creds) {
+                    return None;
+                }
+
+                if let Some(location) = event.location() {
+                    let permissions = registry
+                        .auth_provider_for(&creds)
+                        .image_permissions(&creds, location)
+                        .await;
+                    if !permissions.has_read_permission() {
+                        continue;
+                    }
+                }
+
+                let sse = Event::default()
+                    .event(event.kind())
+                    .json_data(&*event)
+                    .expect("serialization should not fail");
+                return Some((Ok(sse), (receiver, registry, creds)));
+            }
+        },
+    );
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
@@ -0,0 +1,353 @@
+//! Importing blobs from remote URLs.
+//!
+//! [`ContainerRegistry::fetch_blob_from_url`] streams a blob from a URL straight into storage,
+//! e.g. to import the base layers of an image without routing them through a client machine. The
+//! blob is only stored if its contents match the expected digest. It is also available through the
+//! administrative API as `POST /admin/<repository>/<image>/blobs/fetch`, taking a JSON body of the
+//! form `{"url": "...", "digest": "sha256:..."}` and requiring administrative write access, see
+//! [`AuthProvider::admin_permissions`](crate::auth::AuthProvider::admin_permissions).
+//!
+//! As the registry has no HTTP client of its own, downloads are delegated to a [`BlobFetcher`] set
+//! through
+//! [`ContainerRegistryBuilder::blob_fetcher`](crate::ContainerRegistryBuilder::blob_fetcher), e.g. a
+//! [`CommandBlobFetcher`] running `curl`. Fetching makes the registry issue requests on behalf of
+//! its users, so URLs are checked against a [`UrlPolicy`] first. By default, only `https` URLs are
+//! accepted, and hosts resolving to internal addresses (loopback, private, link-local, ...) are
+//! refused. The check resolves hosts independently of the fetcher, restricting fetches to a list
+//! of trusted hosts is recommended.
+
+use std::{
+    error::Error,
+    io,
+    net::{IpAddr, ToSocketAddrs},
+    pin::Pin,
+    process::Stdio,
+    task::{Context, Poll},
+};
+
+use axum::async_trait;
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    process::{Child, ChildStdout},
+};
+use tracing::info;
+use url::{Host, Url};
+
+use crate::{
+    storage::{Digest, ImageLocation},
+    ContainerRegistry, ImageDigest, RegistryError,
+};
+
+/// A downloader of blobs, see the [module documentation](self).
+#[async_trait]
+pub trait BlobFetcher: Send + Sync {
+    /// Starts downloading `url`, returning a reader of its contents.
+    ///
+    /// Contents are verified by the registry, fetchers only need to report failures they notice.
+    async fn fetch(
+        &self,
+        url: &str,
+    ) -> Result<Box<dyn AsyncRead + Send + Unpin>, Box<dyn Error + Send + Sync>>;
+}
+
+/// Restrictions on the URLs blobs may be fetched from, see the [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct UrlPolicy {
+    /// Accepted URL schemes.
+    schemes: Vec<String>,
+    /// Accepted hosts, any if empty.
+    hosts: Vec<String>,
+    /// Whether hosts may resolve to internal addresses.
+    allow_internal: bool,
+}
+
+impl Default for UrlPolicy {
+    fn default() -> Self {
+        Self {
+            schemes: vec!["https".to_owned()],
+            hosts: Vec::new(),
+            allow_internal: false,
+        }
+    }
+}
+
+impl UrlPolicy {
+    /// Creates the default policy, accepting `https` URLs of hosts not resolving to internal
+    /// addresses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Additionally accepts URLs with the given scheme, only `http` and `https` are supported.
+    pub fn scheme<S: Into<String>>(mut self, scheme: S) -> Self {
+        self.schemes.push(scheme.into());
+        self
+    }
+
+    /// Only accepts URLs of the given host, may be called multiple times.
+    ///
+    /// Without any hosts given, all hosts are accepted.
+    pub fn host<S: Into<String>>(mut self, host: S) -> Self {
+        self.hosts.push(host.into());
+        self
+    }
+
+    /// Accepts hosts resolving to internal addresses.
+    ///
+    /// Lets anyone allowed to fetch blobs probe the registry's internal network, e.g. cloud
+    /// metadata services. Disabled by default.
+    pub fn allow_internal_addresses(mut self, allow: bool) -> Self {
+        self.allow_internal = allow;
+        self
+    }
+
+    /// Checks whether blobs may be fetched from `url`, returning the reason if not.
+    pub(crate) async fn check(&self, url: &str) -> Result<(), String> {
+        let url = parse_url(url).ok_or("not an HTTP URL")?;
+        if !self.schemes.iter().any(|scheme| scheme == url.scheme()) {
+            return Err(format!("scheme {} is not allowed", url.scheme()));
+        }
+        let host = url.host().ok_or("URL has no host")?;
+        let name = match host {
+            Host::Domain(domain) => domain.to_owned(),
+            Host::Ipv4(ip) => ip.to_string(),
+            Host::Ipv6(ip) => format!("[{ip}]"),
+        };
+        if !self.hosts.is_empty()
+            && !self
+                .hosts
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(&name))
+        {
+            return Err(format!("host {name} is not allowed"));
+        }
+        if self.allow_internal {
+            return Ok(());
+        }
+
+        let addresses = match host {
+            Host::Ipv4(ip) => vec![IpAddr::V4(ip)],
+            Host::Ipv6(ip) => vec![IpAddr::V6(ip)],
+            Host::Domain(domain) => {
+                let target = (
+                    domain.to_owned(),
+                    url.port_or_known_default().unwrap_or(443),
+                );
+                tokio::task::spawn_blocking(move || target.to_socket_addrs())
+                    .await
+                    .map_err(|_| "could not resolve host")?
+                    .map_err(|_| "could not resolve host")?
+                    .map(|address| address.ip())
+                    .collect()
+            }
+        };
+        if addresses.into_iter().any(is_internal) {
+            return Err(format!("host {name} resolves to an internal address"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses an `http` or `https` URL.
+fn parse_url(url: &str) -> Option<Url> {
+    Url::parse(url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// Checks whether an address is not reachable from the internet, or reaches the host itself.
+fn is_internal(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || first == 0
+                // Shared address space used for carrier-grade NAT, 100.64.0.0/10.
+                || (first == 100 && second & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local addresses, fc00::/7.
+                    || first & 0xfe00 == 0xfc00
+                    // Link-local addresses, fe80::/10.
+                    || first & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+/// Fetches blobs by running an external command, e.g. `curl`.
+///
+/// The command's standard output is used as the blob's contents. In all arguments, `{url}` is
+/// replaced with the URL to fetch, which must be an `http` or `https` URL. An argument consisting
+/// of `{url}` only is preceded by `--`, so the URL is never mistaken for an option:
+///
+/// ```
+/// use container_registry::fetch::CommandBlobFetcher;
+///
+/// let fetcher = CommandBlobFetcher::new("curl")
+///     .arg("--fail")
+///     .arg("--silent")
+///     .arg("--location")
+///     .arg("--proto")
+///     .arg("=https")
+///     .arg("{url}");
+/// ```
+#[derive(Clone, Debug)]
+pub struct CommandBlobFetcher {
+    /// Program to run.
+    program: String,
+    /// Argument templates.
+    args: Vec<String>,
+}
+
+impl CommandBlobFetcher {
+    /// Creates a new fetcher running `program`.
+    pub fn new<P: Into<String>>(program: P) -> Self {
+        Self {
+            program: program.into(),
+            args: Vec::new(),
+        }
+    }
+
+    /// Adds an argument template.
+    pub fn arg<S: Into<String>>(mut self, arg: S) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+}
+
+#[async_trait]
+impl BlobFetcher for CommandBlobFetcher {
+    async fn fetch(
+        &self,
+        url: &str,
+    ) -> Result<Box<dyn AsyncRead + Send + Unpin>, Box<dyn Error + Send + Sync>> {
+        let url = parse_url(url).ok_or("only HTTP URLs can be fetched")?;
+
+        let mut args = Vec::new();
+        for (idx, arg) in self.args.iter().enumerate() {
+            if arg == "{url}" && (idx == 0 || self.args[idx - 1] != "--") {
+                args.push("--".to_owned());
+            }
+            args.push(arg.replace("{url}", url.as_str()));
+        }
+
+        let mut child = tokio::process::Command::new(&self.program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdout = child.stdout.take().expect("stdout should be piped");
+
+        Ok(Box::new(CommandOutput {
+            program: self.program.clone(),
+            child,
+            stdout,
+        }))
+    }
+}
+
+/// Output of a running fetch command.
+///
+/// Kills the command when dropped. Reports the command failing if it has exited by the time its
+/// output ends, anything else is caught by verifying the digest.
+struct CommandOutput {
+    /// Program run, for error messages.
+    program: String,
+    /// The running command.
+    child: Child,
+    /// Its standard output.
+    stdout: ChildStdout,
+}
+
+impl AsyncRead for CommandOutput {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let this = &mut *self;
+        match Pin::new(&mut this.stdout).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) if buf.filled().len() == filled => match this.child.try_wait()? {
+                Some(status) if !status.success() => Poll::Ready(Err(io::Error::other(format!(
+                    "{} exited with {}",
+                    this.program, status
+                )))),
+                _ => Poll::Ready(Ok(())),
+            },
+            other => other,
+        }
+    }
+}
+
+impl ContainerRegistry {
+    /// Downloads a blob from `url` into `location`, returning its digest.
+    ///
+    /// The blob is only stored if its contents match `expected`, and is not downloaded at all if
+    /// it is already stored. Subject to the configured maximum blob size and
+    /// [URL policy](crate::ContainerRegistryBuilder::blob_fetch_policy). No permissions are
+    /// checked, callers of the library API are trusted. See the [`fetch`](crate::fetch) module for
+    /// details.
+    pub async fn fetch_blob_from_url(
+        &self,
+        location: &ImageLocation,
+        url: &str,
+        expected: Digest,
+    ) -> Result<Digest, RegistryError> {
+        let fetcher = self
+            .blob_fetcher
+            .as_ref()
+            .ok_or(RegistryError::NotSupported("fetching blobs"))?;
+        self.blob_fetch_policy.check(url).await.map_err(|reason| {
+            RegistryError::PolicyViolation(format!("cannot fetch blobs from {url}: {reason}"))
+        })?;
+        self.check_repository_exists(location).await?;
+
+        if self.storage.get_blob_metadata(expected).await?.is_none() {
+            let reader = fetcher
+                .fetch(url)
+                .await
+                .map_err(RegistryError::FetchFailed)?;
+
+            let upload = self.storage.begin_new_upload().await?;
+            let digest = match self.write_upload(upload, reader).await {
+                Ok(digest) if digest == expected => digest,
+                Ok(actual) => {
+                    self.storage.cancel_upload(upload).await?;
+                    return Err(RegistryError::DigestMismatch {
+                        expected: ImageDigest::new(expected),
+                        actual: ImageDigest::new(actual),
+                    });
+                }
+                Err(err) => {
+                    self.storage.cancel_upload(upload).await?;
+                    return Err(match err {
+                        RegistryError::ReadFailed(err) => RegistryError::FetchFailed(Box::new(err)),
+                        err => err,
+                    });
+                }
+            };
+            self.storage.finalize_upload(upload, digest).await?;
+            info!(%location, %digest, %url, "blob fetched");
+        }
+
+        self.storage.link_blob(location, expected).await?;
+        Ok(expected)
+    }
+}
@@ -0,0 +1,32 @@
+//! Entry points for fuzz targets.
+//!
+//! Only available with the `fuzzing` feature, which is enabled by the targets in `fuzz/`. Not part
+//! of the public API.
+
+use crate::{range::ByteRange, types::Manifest, ImageDigest};
+
+/// Parses an image digest, checking it roundtrips if valid.
+pub fn image_digest(raw: &str) {
+    if let Ok(digest) = raw.parse::<ImageDigest>() {
+        assert_eq!(digest.to_string(), raw.to_ascii_lowercase());
+    }
+}
+
+/// Parses a manifest and exercises its accessors.
+pub fn manifest(raw: &[u8]) {
+    if let Ok(manifest) = Manifest::from_slice(raw) {
+        let _ = manifest.media_type();
+        let _ = manifest.artifact_type();
+        if let Some(subject) = manifest.subject() {
+            let _ = subject.parsed_digest();
+        }
+    }
+}
+
+/// Parses a byte range, checking it roundtrips if valid.
+pub fn byte_range(raw: &str) {
+    if let Some(range) = ByteRange::parse(raw) {
+        assert!(range.len() >= 1);
+        assert_eq!(ByteRange::parse(&range.to_string()), Some(range));
+    }
+}
@@ -0,0 +1,183 @@
+//! Pluggable SHA-256 implementations.
+//!
+//! All digests computed by the registry, e.g. when verifying uploads, go through the [`Hasher`]
+//! trait. The implementation is chosen at compile time, allowing deployments with compliance
+//! requirements to use a certified crypto library:
+//!
+//! * `hash-openssl`: [`OpenSsl`], using the system's OpenSSL, e.g. with its FIPS provider enabled.
+//! * `hash-ring`: [`Ring`], using `ring`.
+//! * Otherwise, [`RustCrypto`], using the pure Rust `sha2` crate.
+//!
+//! If multiple features are enabled, the first one in the list above is used. The selected
+//! implementation is available as [`Sha256`].
+//!
+//! Signatures of pull tokens, signed blob URLs and webhook deliveries are HMAC-SHA256, always
+//! computed by the `hmac` and `sha2` crates.
+
+use hmac::{Hmac, Mac};
+
+use crate::storage::SHA256_LEN;
+
+/// HMAC-SHA256 as implemented by the `hmac` and `sha2` crates.
+type HmacSha256 = Hmac<sha2::Sha256>;
+
+/// Computes the HMAC-SHA256 of `message`.
+pub(crate) fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; SHA256_LEN] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// Checks whether `signature` is the HMAC-SHA256 of `message`, in constant time.
+pub(crate) fn verify_hmac_sha256(key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.verify_slice(signature).is_ok()
+}
+
+/// An incremental SHA-256 implementation.
+pub trait Hasher: Default + Send {
+    /// Adds `data` to the hashed contents.
+    fn update(&mut self, data: &[u8]);
+
+    /// Returns the hash of all contents added.
+    fn finalize(self) -> [u8; SHA256_LEN];
+
+    /// Hashes `data` in one go.
+    fn digest(data: &[u8]) -> [u8; SHA256_LEN] {
+        let mut hasher = Self::default();
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+/// The implementation selected through features.
+#[cfg(feature = "hash-openssl")]
+pub type Sha256 = OpenSsl;
+
+/// The implementation selected through features.
+#[cfg(all(feature = "hash-ring", not(feature = "hash-openssl")))]
+pub type Sha256 = Ring;
+
+/// The implementation selected through features.
+#[cfg(not(any(feature = "hash-ring", feature = "hash-openssl")))]
+pub type Sha256 = RustCrypto;
+
+/// SHA-256 implemented by the `sha2` crate.
+#[derive(Default)]
+pub struct RustCrypto(sha2::Sha256);
+
+impl Hasher for RustCrypto {
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(&mut self.0, data);
+    }
+
+    fn finalize(self) -> [u8; SHA256_LEN] {
+        sha2::Digest::finalize(self.0).into()
+    }
+}
+
+/// SHA-256 implemented by `ring`.
+#[cfg(feature = "hash-ring")]
+pub struct Ring(ring::digest::Context);
+
+#[cfg(feature = "hash-ring")]
+impl Default for Ring {
+    fn default() -> Self {
+        Self(ring::digest::Context::new(&ring::digest::SHA256))
+    }
+}
+
+#[cfg(feature = "hash-ring")]
+impl Hasher for Ring {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize(self) -> [u8; SHA256_LEN] {
+        self.0
+            .finish()
+            .as_ref()
+            .try_into()
+            .expect("SHA-256 digest has wrong length")
+    }
+}
+
+/// SHA-256 implemented by OpenSSL.
+#[cfg(feature = "hash-openssl")]
+pub struct OpenSsl(openssl::sha::Sha256);
+
+#[cfg(feature = "hash-openssl")]
+impl Default for OpenSsl {
+    fn default() -> Self {
+        Self(openssl::sha::Sha256::new())
+    }
+}
+
+#[cfg(feature = "hash-openssl")]
+impl Hasher for OpenSsl {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize(self) -> [u8; SHA256_LEN] {
+        self.0.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hmac_sha256, verify_hmac_sha256, Hasher, RustCrypto, Sha256};
+
+    /// Hashes a message in uneven pieces.
+    fn hash_pieces<H: Hasher>() -> String {
+        let mut hasher = H::default();
+        for piece in [
+            &b"The quick brown "[..],
+            b"",
+            b"fox jumps over the lazy dog",
+        ] {
+            hasher.update(piece);
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    #[test]
+    fn implementations_agree() {
+        let expected = "d7a8fbb307d7809469ca9abcb0082e4f8d5651e46d3cdb762d02d0bf37c9e592";
+
+        assert_eq!(hash_pieces::<RustCrypto>(), expected);
+        assert_eq!(hash_pieces::<Sha256>(), expected);
+        #[cfg(feature = "hash-ring")]
+        assert_eq!(hash_pieces::<super::Ring>(), expected);
+        #[cfg(feature = "hash-openssl")]
+        assert_eq!(hash_pieces::<super::OpenSsl>(), expected);
+
+        assert_eq!(
+            hex::encode(Sha256::digest(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        let mac = hmac_sha256(&[0x0b; 20], b"Hi There");
+        assert_eq!(
+            hex::encode(mac),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+        assert!(verify_hmac_sha256(&[0x0b; 20], b"Hi There", &mac));
+        assert!(!verify_hmac_sha256(&[0x0b; 20], b"Hi there", &mac));
+        assert!(!verify_hmac_sha256(&[0x0b; 20], b"Hi There", &mac[..16]));
+
+        // Keys longer than a block are hashed first.
+        let mac = hmac_sha256(
+            &[0xaa; 131],
+            b"Test Using Larger Than Block-Size Key - Hash Key First",
+        );
+        assert_eq!(
+            hex::encode(mac),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+}
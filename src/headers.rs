@@ -0,0 +1,94 @@
+//! Registry-specific response headers.
+//!
+//! The OCI distribution spec and the Docker registry API define a number of non-standard headers.
+//! To keep their names and value formats consistent across handlers, they are only ever set
+//! through [`RegistryHeaders`].
+//!
+//! Header names are case-insensitive. They are stored in lowercase, which is how `hyper` writes
+//! them to the wire and mandatory in HTTP/2, even though the spec spells them e.g.
+//! `Docker-Content-Digest`.
+
+use axum::http::{response::Builder, HeaderName};
+use uuid::Uuid;
+
+use crate::{storage::Digest, ImageDigest};
+
+/// Digest of the blob or manifest in the response.
+pub(crate) const DOCKER_CONTENT_DIGEST: HeaderName =
+    HeaderName::from_static("docker-content-digest");
+
+/// ID of the upload session in the response.
+pub(crate) const DOCKER_UPLOAD_UUID: HeaderName = HeaderName::from_static("docker-upload-uuid");
+
+/// Digest of the subject of an uploaded manifest.
+pub(crate) const OCI_SUBJECT: HeaderName = HeaderName::from_static("oci-subject");
+
+/// Filters applied to a referrers listing.
+pub(crate) const OCI_FILTERS_APPLIED: HeaderName = HeaderName::from_static("oci-filters-applied");
+
+/// Typed setters for registry-specific headers on a response.
+pub(crate) trait RegistryHeaders {
+    /// Sets the `Docker-Content-Digest` header.
+    fn docker_content_digest(self, digest: Digest) -> Self;
+
+    /// Sets the `Docker-Upload-UUID` header.
+    fn docker_upload_uuid(self, upload: Uuid) -> Self;
+
+    /// Sets the `OCI-Subject` header.
+    fn oci_subject(self, subject: Digest) -> Self;
+
+    /// Sets the `OCI-Filters-Applied` header, listing the names of the applied filters.
+    fn oci_filters_applied(self, filters: &[&str]) -> Self;
+}
+
+impl RegistryHeaders for Builder {
+    fn docker_content_digest(self, digest: Digest) -> Self {
+        self.header(DOCKER_CONTENT_DIGEST, ImageDigest::new(digest).to_string())
+    }
+
+    fn docker_upload_uuid(self, upload: Uuid) -> Self {
+        self.header(DOCKER_UPLOAD_UUID, upload.to_string())
+    }
+
+    fn oci_subject(self, subject: Digest) -> Self {
+        self.header(OCI_SUBJECT, ImageDigest::new(subject).to_string())
+    }
+
+    fn oci_filters_applied(self, filters: &[&str]) -> Self {
+        self.header(OCI_FILTERS_APPLIED, filters.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Response};
+    use uuid::Uuid;
+
+    use super::RegistryHeaders;
+    use crate::storage::Digest;
+
+    #[test]
+    fn headers_are_formatted_as_specified() {
+        let digest = Digest::from_contents(b"hello");
+        let upload = Uuid::nil();
+
+        let response = Response::builder()
+            .docker_content_digest(digest)
+            .docker_upload_uuid(upload)
+            .oci_subject(digest)
+            .oci_filters_applied(&["artifactType", "annotation"])
+            .body(Body::empty())
+            .expect("failed to build response");
+        let headers = response.headers();
+
+        let expected_digest =
+            "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert_eq!(headers["Docker-Content-Digest"], expected_digest);
+        assert_eq!(headers["OCI-Subject"], expected_digest);
+        assert_eq!(
+            headers["Docker-Upload-UUID"],
+            "00000000-0000-0000-0000-000000000000"
+        );
+        assert_eq!(headers["OCI-Filters-Applied"], "artifactType,annotation");
+    }
+}
@@ -1,19 +1,119 @@
 //! Notification hooks for registry changes.
 
+use std::net::IpAddr;
+
 use axum::async_trait;
 
-use super::storage::ManifestReference;
+use super::{
+    access::AccessRecord,
+    maintenance::MaintenanceReport,
+    purge::PurgedItem,
+    quarantine::QuarantineDecision,
+    storage::{ImageLocation, ManifestReference},
+    types::Manifest,
+    ImageDigest,
+};
 
 /// A registry hook
 ///
 /// Hooks are used by the registry to notify about changes made by external clients.
 ///
 /// The unit type `()` implements `RegistryHooks`, silently discarding all notifications.
+///
+/// Hook invocations are isolated from request handling: They are aborted once the timeout set
+/// through [`ContainerRegistryBuilder::hook_timeout`](crate::ContainerRegistryBuilder::hook_timeout)
+/// expires and panics are caught. Both cases are logged and counted in the registry metrics.
 #[async_trait]
 pub trait RegistryHooks: Send + Sync {
     /// Notify about an uploaded manifest.
-    async fn on_manifest_uploaded(&self, manifest_reference: &ManifestReference) {
-        let _ = manifest_reference;
+    ///
+    /// Receives the manifest as parsed by the registry along with its raw bytes, exactly as stored,
+    /// so implementations need not fetch it again.
+    async fn on_manifest_uploaded(
+        &self,
+        manifest_reference: &ManifestReference,
+        manifest: &Manifest,
+        raw: &[u8],
+    ) {
+        let _ = (manifest_reference, manifest, raw);
+    }
+
+    /// Review a newly pushed manifest that has been quarantined.
+    ///
+    /// Called in the background once the manifest has been stored, with the reference it was
+    /// pushed as. Returning [`QuarantineDecision::Release`] publishes the manifest, otherwise it
+    /// stays quarantined until released through the administrative API, see the
+    /// [`quarantine`](crate::quarantine) module. Aborted invocations keep the manifest quarantined.
+    async fn on_manifest_quarantined(
+        &self,
+        manifest_reference: &ManifestReference,
+        manifest: &Manifest,
+        raw: &[u8],
+    ) -> QuarantineDecision {
+        let _ = (manifest_reference, manifest, raw);
+        QuarantineDecision::Hold
+    }
+
+    /// Notify about an inspected layer of a manifest being uploaded.
+    ///
+    /// Called before the manifest is stored, which may still fail or be refused due to findings.
+    #[cfg(feature = "inspection")]
+    async fn on_layer_inspected(
+        &self,
+        manifest_reference: &ManifestReference,
+        report: &crate::inspection::LayerReport,
+    ) {
+        let _ = (manifest_reference, report);
+    }
+
+    /// Notify about a request whose credentials were refused by the auth provider.
+    ///
+    /// Receives the username given along with the credentials, if any, and the address of the
+    /// client, if known, see the [`lockout`](crate::lockout) module.
+    async fn on_authentication_failed(&self, username: Option<&str>, client: Option<IpAddr>) {
+        let _ = (username, client);
+    }
+
+    /// Notify about a completed maintenance task run.
+    async fn on_maintenance_completed(&self, report: &MaintenanceReport) {
+        let _ = report;
+    }
+
+    /// Notify about a stored blob found not to match its digest.
+    ///
+    /// The blob has already been moved to quarantine, see
+    /// [`Scrubber`](crate::maintenance::Scrubber).
+    async fn on_blob_corrupted(&self, digest: &ImageDigest) {
+        let _ = digest;
+    }
+
+    /// Notify about a manifest requested by digest whose stored contents hash to another digest.
+    ///
+    /// The request has been answered with `500 Internal Server Error`. The manifest is left in
+    /// place, restoring it e.g. from a backup is up to the operator.
+    async fn on_manifest_corrupted(
+        &self,
+        location: &ImageLocation,
+        expected: &ImageDigest,
+        actual: &ImageDigest,
+    ) {
+        let _ = (location, expected, actual);
+    }
+
+    /// Notify about a request to a repository.
+    ///
+    /// Only called if enabled through
+    /// [`ContainerRegistryBuilder::access_records`](crate::ContainerRegistryBuilder::access_records),
+    /// once the response has been sent, see the [`access`](crate::access) module.
+    async fn on_access(&self, record: &AccessRecord) {
+        let _ = record;
+    }
+
+    /// Notify about a tag, manifest or blob removed while purging a repository.
+    ///
+    /// Called once per item, after it has been removed, see the [`purge`](crate::purge) module.
+    async fn on_item_purged(&self, item: &PurgedItem) {
+        let _ = item;
     }
 }
 
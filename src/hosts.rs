@@ -0,0 +1,144 @@
+//! Host-based virtual registries.
+//!
+//! A single process can serve multiple logical registries, selected by the `Host` header of each
+//! request. Every registry is built as usual, with its own storage root, auth provider and realm,
+//! and registered under the host names it should answer to:
+//!
+//! ```no_run
+//! use container_registry::{hosts::VirtualRegistries, ContainerRegistry};
+//!
+//! let internal = ContainerRegistry::builder()
+//!     .storage("/srv/registries/internal")
+//!     .realm("internal")
+//!     .build()
+//!     .expect("failed to build registry");
+//! let public = ContainerRegistry::builder()
+//!     .storage("/srv/registries/public")
+//!     .realm("public")
+//!     .build()
+//!     .expect("failed to build registry");
+//!
+//! let app = VirtualRegistries::new()
+//!     .host("registry.internal.example.com", internal)
+//!     .host("registry.example.com", public)
+//!     .make_router();
+//! ```
+//!
+//! Host names are matched case-insensitively and regardless of the port. Requests for unknown hosts
+//! are passed to the fallback registry if one is set, otherwise they are refused with
+//! `421 Misdirected Request`.
+
+use std::{collections::HashMap, convert::Infallible, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::{header::HOST, StatusCode},
+    response::{IntoResponse, Response},
+    Router,
+};
+use tower_service::Service;
+
+use crate::ContainerRegistry;
+
+/// Multiple registries, selected by host name.
+#[derive(Default)]
+pub struct VirtualRegistries {
+    /// Registries by lowercase host name.
+    hosts: HashMap<String, Arc<ContainerRegistry>>,
+    /// Registry serving unknown hosts.
+    fallback: Option<Arc<ContainerRegistry>>,
+}
+
+impl VirtualRegistries {
+    /// Creates a new, empty set of virtual registries.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves `registry` for requests to `host`, replacing any registry previously set for it.
+    ///
+    /// `host` is a bare host name, any port given is ignored.
+    pub fn host<S: AsRef<str>>(mut self, host: S, registry: Arc<ContainerRegistry>) -> Self {
+        self.hosts.insert(normalize(host.as_ref()), registry);
+        self
+    }
+
+    /// Serves `registry` for requests to hosts no other registry has been set for.
+    pub fn fallback(mut self, registry: Arc<ContainerRegistry>) -> Self {
+        self.fallback = Some(registry);
+        self
+    }
+
+    /// Builds an [`axum::routing::Router`] dispatching requests to the registries.
+    pub fn make_router(self) -> Router {
+        let routers = Routers {
+            hosts: self
+                .hosts
+                .into_iter()
+                .map(|(host, registry)| (host, registry.make_router()))
+                .collect(),
+            fallback: self.fallback.map(ContainerRegistry::make_router),
+        };
+
+        Router::new()
+            .fallback(dispatch)
+            .with_state(Arc::new(routers))
+    }
+}
+
+/// Routers of all registries.
+struct Routers {
+    /// Routers by lowercase host name.
+    hosts: HashMap<String, Router>,
+    /// Router serving unknown hosts.
+    fallback: Option<Router>,
+}
+
+/// Passes a request to the router of the registry for its host.
+async fn dispatch(State(routers): State<Arc<Routers>>, request: Request) -> Response {
+    // HTTP/2 requests carry the host in the URI instead of a `Host` header.
+    let host = request
+        .headers()
+        .get(HOST)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| request.uri().host())
+        .map(normalize);
+
+    let router = host
+        .and_then(|host| routers.hosts.get(&host))
+        .or(routers.fallback.as_ref());
+
+    match router {
+        Some(router) => {
+            let result: Result<Response, Infallible> = router.clone().call(request).await;
+            result.unwrap_or_else(|never| match never {})
+        }
+        None => (StatusCode::MISDIRECTED_REQUEST, "unknown registry host").into_response(),
+    }
+}
+
+/// Normalizes a host name for lookup, removing the port and converting to lowercase.
+fn normalize(host: &str) -> String {
+    let name = match host.rsplit_once(':') {
+        // Colons inside brackets belong to an IPv6 address.
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    };
+    name.to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize;
+
+    #[test]
+    fn hosts_are_normalized() {
+        assert_eq!(normalize("Registry.Example.com"), "registry.example.com");
+        assert_eq!(
+            normalize("registry.example.com:5000"),
+            "registry.example.com"
+        );
+        assert_eq!(normalize("[::1]:5000"), "[::1]");
+        assert_eq!(normalize("[::1]"), "[::1]");
+    }
+}
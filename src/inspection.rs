@@ -0,0 +1,404 @@
+//! Layer inspection.
+//!
+//! Requires the `inspection` feature to be enabled.
+//!
+//! When a [`LayerInspector`] is configured through
+//! [`ContainerRegistryBuilder::layer_inspector`](crate::ContainerRegistryBuilder::layer_inspector),
+//! every layer of an uploaded image manifest is stream-decompressed and its file listing checked
+//! against a set of [`ContentPolicy`]s before the manifest is stored. Layers are never held in
+//! memory as a whole; only the first [`LayerInspector::max_scan_size`] bytes of each file are
+//! buffered for content checks.
+//!
+//! The resulting [`LayerReport`]s are passed to
+//! [`RegistryHooks::on_layer_inspected`](crate::hooks::RegistryHooks::on_layer_inspected). If the
+//! inspector is set to [`LayerInspector::reject_on_findings`], manifests with findings in any of
+//! their layers are refused instead.
+//!
+//! Uncompressed and gzip compressed tar layers are supported, other layers (e.g. `zstd` compressed
+//! ones) are reported as skipped.
+
+use std::{
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use flate2::read::GzDecoder;
+use tokio::io::AsyncRead;
+use tokio_util::io::SyncIoBridge;
+
+use crate::storage::{self, Digest};
+
+/// Default number of bytes of every file passed to content checks.
+const DEFAULT_MAX_SCAN_SIZE: u64 = 1024 * 1024; // 1 MiB
+
+/// A policy checking layer contents.
+///
+/// Both checks default to finding nothing, implement whichever is needed.
+pub trait ContentPolicy: Send + Sync {
+    /// Name of the policy, reported with every finding.
+    fn name(&self) -> &str;
+
+    /// Checks the path of an entry, returning a description of the problem if one is found.
+    fn check_path(&self, path: &Path) -> Option<String> {
+        let _ = path;
+        None
+    }
+
+    /// Checks the (possibly truncated) contents of a regular file.
+    fn check_contents(&self, path: &Path, contents: &[u8]) -> Option<String> {
+        let _ = (path, contents);
+        None
+    }
+}
+
+/// Flags entries whose path starts with any of a set of prefixes.
+///
+/// Paths are compared without a leading `/` or `./`, i.e. `etc/shadow` matches both `/etc/shadow`
+/// and `./etc/shadow`.
+#[derive(Debug)]
+pub struct ForbiddenPaths {
+    /// Forbidden path prefixes, normalized.
+    prefixes: Vec<PathBuf>,
+}
+
+impl ForbiddenPaths {
+    /// Creates a new policy forbidding the given path prefixes.
+    pub fn new<I, P>(prefixes: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        Self {
+            prefixes: prefixes
+                .into_iter()
+                .map(|prefix| normalize(prefix.as_ref()).to_owned())
+                .collect(),
+        }
+    }
+}
+
+impl ContentPolicy for ForbiddenPaths {
+    fn name(&self) -> &str {
+        "forbidden-paths"
+    }
+
+    fn check_path(&self, path: &Path) -> Option<String> {
+        let path = normalize(path);
+        self.prefixes
+            .iter()
+            .find(|prefix| path.starts_with(prefix))
+            .map(|prefix| format!("path is below forbidden path {}", prefix.display()))
+    }
+}
+
+/// Flags files containing any of a set of byte patterns, e.g. embedded private keys.
+#[derive(Debug)]
+pub struct SecretPatterns {
+    /// Patterns and their descriptions.
+    patterns: Vec<(Vec<u8>, String)>,
+}
+
+impl Default for SecretPatterns {
+    /// Creates a policy detecting PEM-encoded private keys.
+    fn default() -> Self {
+        let mut policy = Self::empty();
+        for kind in ["RSA ", "EC ", "DSA ", "OPENSSH ", "ENCRYPTED ", ""] {
+            policy = policy.pattern(
+                format!("-----BEGIN {kind}PRIVATE KEY-----"),
+                format!("contains {kind}private key"),
+            );
+        }
+        policy
+    }
+}
+
+impl SecretPatterns {
+    /// Creates a policy without any patterns.
+    pub fn empty() -> Self {
+        Self {
+            patterns: Vec::new(),
+        }
+    }
+
+    /// Adds a pattern to look for.
+    pub fn pattern<P: Into<Vec<u8>>, D: Into<String>>(
+        mut self,
+        pattern: P,
+        description: D,
+    ) -> Self {
+        self.patterns.push((pattern.into(), description.into()));
+        self
+    }
+}
+
+impl ContentPolicy for SecretPatterns {
+    fn name(&self) -> &str {
+        "secret-patterns"
+    }
+
+    fn check_contents(&self, _path: &Path, contents: &[u8]) -> Option<String> {
+        self.patterns
+            .iter()
+            .find(|(pattern, _)| {
+                !pattern.is_empty()
+                    && contents
+                        .windows(pattern.len())
+                        .any(|window| window == pattern.as_slice())
+            })
+            .map(|(_, description)| description.clone())
+    }
+}
+
+/// Kind of a layer entry.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EntryKind {
+    /// A regular file.
+    File,
+    /// A directory.
+    Directory,
+    /// A symbolic link.
+    Symlink,
+    /// A hard link.
+    Hardlink,
+    /// Anything else, e.g. device nodes.
+    Other,
+}
+
+/// A single entry of a layer.
+#[derive(Clone, Debug)]
+pub struct FileEntry {
+    /// Path of the entry, as stored in the layer.
+    pub path: PathBuf,
+    /// Size of the entry in bytes.
+    pub size: u64,
+    /// Kind of the entry.
+    pub kind: EntryKind,
+}
+
+/// A problem found by a [`ContentPolicy`].
+#[derive(Clone, Debug)]
+pub struct Finding {
+    /// Path of the offending entry.
+    pub path: PathBuf,
+    /// Name of the policy reporting the finding.
+    pub policy: String,
+    /// Description of the problem.
+    pub description: String,
+}
+
+/// Result of inspecting a single layer.
+#[derive(Clone, Debug)]
+pub struct LayerReport {
+    /// Digest of the inspected layer.
+    pub layer: Digest,
+    /// All entries of the layer, in order. Empty if the layer was skipped.
+    pub files: Vec<FileEntry>,
+    /// Problems found by the configured policies.
+    pub findings: Vec<Finding>,
+    /// Whether the layer was skipped due to an unsupported format.
+    pub skipped: bool,
+}
+
+/// Inspects layers, see the [module documentation](self) for details.
+pub struct LayerInspector {
+    /// Policies to check every entry against.
+    policies: Vec<Box<dyn ContentPolicy>>,
+    /// Number of bytes of every file passed to content checks.
+    max_scan_size: u64,
+    /// Whether manifests with findings are rejected.
+    reject_on_findings: bool,
+}
+
+impl Default for LayerInspector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LayerInspector {
+    /// Creates a new inspector without any policies, which only collects file listings.
+    pub fn new() -> Self {
+        Self {
+            policies: Vec::new(),
+            max_scan_size: DEFAULT_MAX_SCAN_SIZE,
+            reject_on_findings: false,
+        }
+    }
+
+    /// Adds a policy to check layers against.
+    pub fn policy<P: ContentPolicy + 'static>(mut self, policy: P) -> Self {
+        self.policies.push(Box::new(policy));
+        self
+    }
+
+    /// Sets the number of bytes of every file passed to content checks. Defaults to 1 MiB.
+    pub fn max_scan_size(mut self, max_scan_size: u64) -> Self {
+        self.max_scan_size = max_scan_size;
+        self
+    }
+
+    /// Sets whether manifests with findings in any of their layers are rejected.
+    ///
+    /// Defaults to `false`, which only reports findings through hooks.
+    pub fn reject_on_findings(mut self, reject: bool) -> Self {
+        self.reject_on_findings = reject;
+        self
+    }
+
+    /// Returns whether manifests with findings should be rejected.
+    pub(crate) fn rejects_on_findings(&self) -> bool {
+        self.reject_on_findings
+    }
+
+    /// Inspects a layer of the given media type read from `reader`.
+    ///
+    /// This function blocks, use [`tokio::task::spawn_blocking`] when calling it from an async
+    /// context.
+    pub fn inspect_blocking<R: Read>(
+        &self,
+        layer: Digest,
+        media_type: &str,
+        reader: R,
+    ) -> io::Result<LayerReport> {
+        // Some clients push empty layers as zero-length blobs, which are not valid archives of any
+        // format, but clearly contain no files.
+        let mut reader = BufReader::new(reader);
+        if reader.fill_buf()?.is_empty() {
+            return Ok(LayerReport {
+                layer,
+                files: Vec::new(),
+                findings: Vec::new(),
+                skipped: false,
+            });
+        }
+
+        match LayerFormat::from_media_type(media_type) {
+            Some(LayerFormat::Tar) => self.inspect_tar(layer, reader),
+            Some(LayerFormat::TarGzip) => self.inspect_tar(layer, GzDecoder::new(reader)),
+            None => Ok(LayerReport {
+                layer,
+                files: Vec::new(),
+                findings: Vec::new(),
+                skipped: true,
+            }),
+        }
+    }
+
+    /// Returns whether layers of the given media type can be inspected.
+    pub(crate) fn supports(media_type: &str) -> bool {
+        LayerFormat::from_media_type(media_type).is_some()
+    }
+
+    /// Inspects a layer read from `reader` on a blocking thread.
+    pub(crate) async fn inspect(
+        self: &Arc<Self>,
+        layer: Digest,
+        media_type: String,
+        reader: Box<dyn AsyncRead + Send + Unpin>,
+    ) -> Result<LayerReport, storage::Error> {
+        let inspector = self.clone();
+        let reader = SyncIoBridge::new(reader);
+
+        tokio::task::spawn_blocking(move || inspector.inspect_blocking(layer, &media_type, reader))
+            .await
+            .map_err(storage::Error::BackgroundTaskPanicked)?
+            .map_err(storage::Error::Io)
+    }
+
+    /// Inspects an uncompressed tar stream.
+    fn inspect_tar<R: Read>(&self, layer: Digest, reader: R) -> io::Result<LayerReport> {
+        let mut archive = tar::Archive::new(reader);
+        let mut files = Vec::new();
+        let mut findings = Vec::new();
+        let mut buf = Vec::new();
+
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let path = entry.path()?.into_owned();
+            let size = entry.header().size()?;
+            let entry_type = entry.header().entry_type();
+            let kind = if entry_type.is_file() {
+                EntryKind::File
+            } else if entry_type.is_dir() {
+                EntryKind::Directory
+            } else if entry_type.is_symlink() {
+                EntryKind::Symlink
+            } else if entry_type.is_hard_link() {
+                EntryKind::Hardlink
+            } else {
+                EntryKind::Other
+            };
+
+            let mut report = |policy: &dyn ContentPolicy, description| {
+                findings.push(Finding {
+                    path: path.clone(),
+                    policy: policy.name().to_owned(),
+                    description,
+                })
+            };
+
+            for policy in &self.policies {
+                if let Some(description) = policy.check_path(&path) {
+                    report(policy.as_ref(), description);
+                }
+            }
+
+            if kind == EntryKind::File && !self.policies.is_empty() {
+                buf.clear();
+                entry.take(self.max_scan_size).read_to_end(&mut buf)?;
+
+                for policy in &self.policies {
+                    if let Some(description) = policy.check_contents(&path, &buf) {
+                        report(policy.as_ref(), description);
+                    }
+                }
+            }
+
+            files.push(FileEntry { path, size, kind });
+        }
+
+        Ok(LayerReport {
+            layer,
+            files,
+            findings,
+            skipped: false,
+        })
+    }
+}
+
+/// Supported layer formats.
+enum LayerFormat {
+    /// Uncompressed tar.
+    Tar,
+    /// Gzip compressed tar.
+    TarGzip,
+}
+
+impl LayerFormat {
+    /// Determines the format from an OCI or Docker layer media type.
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "application/vnd.oci.image.layer.v1.tar"
+            | "application/vnd.oci.image.layer.nondistributable.v1.tar" => Some(Self::Tar),
+            "application/vnd.oci.image.layer.v1.tar+gzip"
+            | "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip"
+            | "application/vnd.docker.image.rootfs.diff.tar.gzip" => Some(Self::TarGzip),
+            _ => None,
+        }
+    }
+}
+
+/// Strips leading `/` and `./` components from a path.
+fn normalize(path: &Path) -> &Path {
+    let mut path = path;
+    loop {
+        if let Ok(stripped) = path.strip_prefix("/") {
+            path = stripped;
+        } else if let Ok(stripped) = path.strip_prefix(".") {
+            path = stripped;
+        } else {
+            return path;
+        }
+    }
+}
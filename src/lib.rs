@@ -39,61 +39,121 @@
 //!
 //! Afterwards, `app` can be launched via [`axum::serve()`], see its documentation for details.
 
+pub mod access;
+mod admin;
+mod api_error;
+pub mod archive;
+pub mod attestation;
 pub mod auth;
+pub mod autotag;
+pub mod cdn;
+mod checksums;
+pub mod clock;
+pub mod compat;
+pub mod conversion;
+mod encoding;
+pub mod errors;
+pub mod events;
+pub mod fetch;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+pub mod hashing;
+mod headers;
 pub mod hooks;
+pub mod hosts;
+#[cfg(feature = "inspection")]
+pub mod inspection;
+pub mod lockout;
+pub mod maintenance;
+pub mod metrics;
+#[cfg(feature = "notation")]
+pub mod notation;
+pub mod operations;
+pub mod peers;
+pub mod purge;
+pub mod quarantine;
+mod range;
+pub mod repositories;
+pub mod sbom;
+pub mod self_test;
+pub mod service;
 pub mod storage;
+pub mod tag_history;
 #[cfg(any(feature = "test-support", test))]
 pub mod test_support;
 #[cfg(test)]
 mod tests;
-mod types;
+pub mod throttle;
+pub mod tokens;
+pub mod types;
+mod uploads;
+mod urls;
+pub mod webhooks;
 mod www_authenticate;
 
 use std::{
-    fmt::{self, Display},
+    collections::{HashMap, HashSet},
+    future::Future,
     io,
+    net::IpAddr,
+    panic::AssertUnwindSafe,
     path::PathBuf,
-    str::FromStr,
     sync::Arc,
+    time::{Duration, UNIX_EPOCH},
 };
 
 use self::{
+    api_error::ApiError,
     auth::ValidCredentials,
+    headers::RegistryHeaders,
     storage::{FilesystemStorage, ImageLocation, RegistryStorage},
-    types::{ImageManifest, OciError, OciErrors},
+    types::{ContentDescriptor, ImageIndex, Manifest, OciError, OciErrors},
 };
+use arc_swap::ArcSwap;
 use auth::{MissingPermission, Permissions};
 use axum::{
+    async_trait,
     body::Body,
-    extract::{Path, Query, State},
+    extract::{FromRequestParts, Path, Query, State},
     http::{
-        header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION, RANGE},
-        StatusCode,
+        header::{
+            ACCEPT, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LINK, LOCATION,
+            RANGE, RETRY_AFTER, VARY, WWW_AUTHENTICATE,
+        },
+        request::Parts,
+        HeaderMap, Method, StatusCode, Uri,
     },
     response::{IntoResponse, Response},
     routing::{get, head, patch, post, put},
     Router,
 };
-use futures::stream::StreamExt;
-use hex::FromHex;
-use serde::{Deserialize, Deserializer, Serialize};
+use futures::{stream::StreamExt, FutureExt};
+use serde::{Deserialize, Serialize};
 use storage::Reference;
 use thiserror::Error;
-use tokio::io::AsyncWriteExt;
-use tokio_util::io::ReaderStream;
-use tracing::info;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
+pub use types::{ImageDigest, ImageDigestParseError};
+
 pub(crate) use {
-    auth::{AuthProvider, Unverified},
+    auth::AuthProvider,
     hooks::RegistryHooks,
     storage::{FilesystemStorageError, ManifestReference},
 };
 
 /// A container registry error.
 ///
+/// Returned by the library APIs of [`ContainerRegistry`], describing what failed independent of
+/// HTTP, so that callers can match on specific conditions. Failures only requests to the HTTP API
+/// can run into, e.g. malformed headers, are mapped to responses by the API itself.
+///
 /// Errors produced by the registry have a "safe" [`IntoResponse`] implementation, thus can be
-/// returned straight to the user without security concerns.
+/// returned straight to the user without security concerns, e.g. from handlers of an embedding
+/// application.
 #[derive(Debug, Error)]
 pub enum RegistryError {
     /// A requested item (eg. manifest, blob, etc.) was not found.
@@ -108,74 +168,100 @@ pub enum RegistryError {
     Storage(#[from] storage::Error),
     /// Error parsing image manifest.
     #[error("could not parse manifest")]
-    ParseManifest(serde_json::Error),
+    ParseManifest(#[source] serde_json::Error),
     /// A requested/required feature was not supported by this registry.
     #[error("feature not supported: {0}")]
     NotSupported(&'static str),
-    /// Invalid integer supplied for content length.
-    #[error("error parsing content length")]
-    ContentLengthMalformed(#[source] Box<dyn std::error::Error + Send + Sync>),
-    /// Incoming stream read error.
-    #[error("failed to read incoming data stream")]
-    IncomingReadFailed(#[source] axum::Error),
+    /// Reading contents to store failed, e.g. the reader passed to [`ContainerRegistry::put_blob`].
+    #[error("failed to read contents")]
+    ReadFailed(#[source] io::Error),
     /// Failed to write local data to storage.
     #[error("local write failed")]
     LocalWriteFailed(#[source] io::Error),
-    /// Error building HTTP response.
-    #[error("axum http error")]
-    // Note: These should never occur.
-    AxumHttp(#[from] axum::http::Error),
+    /// A digest given or referenced was invalid.
+    #[error("invalid digest")]
+    InvalidDigest(#[source] ImageDigestParseError),
+    /// An abbreviated digest matches more than one manifest.
+    #[error("digest prefix {prefix} matches {matches} manifests")]
+    AmbiguousDigest {
+        /// The abbreviated digest.
+        prefix: String,
+        /// Number of manifests matching it.
+        matches: usize,
+    },
+    /// A blob exceeds the configured maximum size, see [`ContainerRegistryBuilder::max_blob_size`].
+    #[error("blob exceeds the maximum size of {limit} bytes")]
+    BlobTooLarge {
+        /// Maximum size of a blob, in bytes.
+        limit: u64,
+    },
+    /// A manifest exceeds the configured maximum size, see
+    /// [`ContainerRegistryBuilder::max_manifest_size`].
+    #[error("manifest of {size} bytes exceeds the maximum size of {limit} bytes")]
+    ManifestTooLarge {
+        /// Size of the manifest, in bytes.
+        size: u64,
+        /// Maximum size of a manifest, in bytes.
+        limit: u64,
+    },
+    /// Uploaded content was refused by a content policy.
+    #[error("content policy violation: {0}")]
+    PolicyViolation(String),
+    /// A manifest was uploaded with a media type the registry does not accept.
+    #[error("unsupported manifest media type: {0:?}")]
+    UnsupportedManifestType(Option<String>),
+    /// A platform given as `os/architecture` could not be parsed.
+    #[error("invalid platform: {0:?}")]
+    InvalidPlatform(String),
+    /// A manifest's type is not among those allowed for its location.
+    #[error("manifest type {media_type:?} not allowed in {location}")]
+    MediaTypeNotAllowed {
+        /// Location the manifest was pushed to.
+        location: ImageLocation,
+        /// Artifact type of the manifest, or its media type if it has none.
+        media_type: Option<String>,
+    },
+    /// A long-running operation was cancelled, see [`operations`].
+    #[error("operation cancelled")]
+    Cancelled,
+    /// A repository purge was not confirmed by a valid token, see [`purge`].
+    #[error("purge not confirmed")]
+    PurgeNotConfirmed,
+    /// A repository name is not valid, see [`ContainerRegistry::create_repository`].
+    #[error("invalid repository name: {0:?}")]
+    InvalidRepositoryName(String),
+    /// A repository pushed to does not exist, see [`repositories`].
+    #[error("repository {0} does not exist")]
+    RepositoryUnknown(String),
+    /// A manifest retrieved by digest does not match it, i.e. storage is corrupted.
+    #[error("stored manifest {expected} is corrupted, its contents hash to {actual}")]
+    ManifestCorrupted {
+        /// Digest the manifest was requested by.
+        expected: ImageDigest,
+        /// Digest of the stored contents.
+        actual: ImageDigest,
+    },
+    /// A blob could not be fetched from a remote URL, see [`fetch`].
+    #[error("failed to fetch blob")]
+    FetchFailed(#[source] Box<dyn std::error::Error + Send + Sync>),
+    /// Contents received do not match the digest they were expected to have.
+    #[error("contents hash to {actual}, expected {expected}")]
+    DigestMismatch {
+        /// Digest the contents were expected to have.
+        expected: ImageDigest,
+        /// Digest of the contents received.
+        actual: ImageDigest,
+    },
+    /// A manifest references a blob not uploaded to its repository, see
+    /// [`ContainerRegistryBuilder::scope_blobs`].
+    #[error("manifest references unknown blob {0}")]
+    ManifestBlobUnknown(ImageDigest),
 }
 
 impl IntoResponse for RegistryError {
     #[inline(always)]
     fn into_response(self) -> Response {
-        match self {
-            // TODO: Need better OciError handling here. Not everything is blob unknown.
-            RegistryError::NotFound => (
-                StatusCode::NOT_FOUND,
-                OciErrors::single(OciError::new(types::ErrorCode::BlobUnknown)),
-            )
-                .into_response(),
-            RegistryError::PermissionDenied(_) => (
-                StatusCode::FORBIDDEN,
-                // TODO: Should this be a proper OCI error?
-                "access to request resource was denied",
-            )
-                .into_response(),
-            RegistryError::Storage(err) => err.into_response(),
-            RegistryError::ParseManifest(err) => (
-                StatusCode::BAD_REQUEST,
-                format!("could not parse manifest: {}", err),
-            )
-                .into_response(),
-            RegistryError::NotSupported(feature) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("feature not supported: {}", feature),
-            )
-                .into_response(),
-            RegistryError::ContentLengthMalformed(err) => (
-                StatusCode::BAD_REQUEST,
-                format!("invalid content length value: {}", err),
-            )
-                .into_response(),
-            RegistryError::IncomingReadFailed(_err) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "could not read input stream",
-            )
-                .into_response(),
-            RegistryError::LocalWriteFailed(_err) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "could not write image locally",
-            )
-                .into_response(),
-            RegistryError::AxumHttp(_err) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                // Fixed message, we don't want to leak anything. This should never happen anyway.
-                "error building axum HTTP response",
-            )
-                .into_response(),
-        }
+        api_error::registry_error_response(self)
     }
 }
 
@@ -185,12 +271,104 @@ pub struct ContainerRegistry {
     ///
     /// Solely used for HTTP auth.
     realm: String,
-    /// An implementation for authentication.
-    auth_provider: Arc<dyn AuthProvider>,
+    /// URL of the token service advertised in a `Bearer` challenge, if any.
+    bearer_realm: Option<String>,
+    /// An implementation for authentication, replaceable at runtime.
+    auth_provider: ArcSwap<Arc<dyn AuthProvider>>,
     /// A storage backend for the registry.
     storage: Box<dyn RegistryStorage>,
+    /// The local part of `storage`, for taking snapshots.
+    local_storage: FilesystemStorage,
+    /// Listing of all locations and tags, kept up to date by `storage`.
+    catalog: Arc<storage::catalog::Catalog>,
+    /// OCI image layouts kept up to date by `storage`, if enabled.
+    oci_layout: Option<Arc<storage::oci_layout::OciLayout>>,
     /// A hook consumer for the registry.
     hooks: Box<dyn RegistryHooks>,
+    /// Maximum time a single hook invocation may take.
+    hook_timeout: Duration,
+    /// Currently open upload sessions.
+    upload_sessions: uploads::UploadSessions,
+    /// Failed authentication attempts.
+    auth_failures: lockout::AuthFailures,
+    /// Source of the current time.
+    clock: Arc<dyn clock::Clock>,
+    /// Metrics collected by the registry.
+    metrics: metrics::Metrics,
+    /// Inspector for layers of uploaded manifests.
+    #[cfg(feature = "inspection")]
+    layer_inspector: Option<Arc<inspection::LayerInspector>>,
+    /// Generator for SBOMs of pushed images.
+    sbom_generator: Option<Arc<dyn sbom::SbomGenerator>>,
+    /// Downloader of blobs fetched from remote URLs.
+    blob_fetcher: Option<Arc<dyn fetch::BlobFetcher>>,
+    /// URLs blobs may be fetched from.
+    blob_fetch_policy: fetch::UrlPolicy,
+    /// Signer of attestations for accepted manifests.
+    attestation_signer: Option<Arc<dyn attestation::Signer>>,
+    /// Verifier of notation signatures on pull.
+    #[cfg(feature = "notation")]
+    notation_verifier: Option<notation::NotationVerifier>,
+    /// Maximum size of a decompressed upload chunk.
+    decompressed_body_limit: u64,
+    /// Maximum size of a single blob.
+    max_blob_size: Option<u64>,
+    /// Maximum size of a manifest.
+    max_manifest_size: u64,
+    /// Whether to accept manifests of unknown media types.
+    custom_manifest_types: bool,
+    /// Repositories and images manifests may only be pulled from by digest.
+    digest_pull_only: HashSet<String>,
+    /// Quarantined manifests, if enabled.
+    quarantine: Option<quarantine::Quarantine>,
+    /// Pending and running repository purges.
+    purges: purge::Purges,
+    /// Manifests possibly left dangling, to be looked at by garbage collection.
+    dangling: maintenance::DanglingManifests,
+    /// Created repositories.
+    repositories: repositories::Repositories,
+    /// Archive rarely pulled blobs are moved to, if any.
+    archive: Option<archive::Archive>,
+    /// Previous manifests of tags, if recorded.
+    tag_histories: Option<tag_history::TagHistories>,
+    /// Media and artifact types of manifests accepted by repositories and images.
+    allowed_media_types: HashMap<String, HashSet<String>>,
+    /// Maximum number of tags per image and what to do when it is reached.
+    tag_limit: Option<(usize, TagLimitPolicy)>,
+    /// Whether blobs are only served through repositories they belong to.
+    scope_blobs: bool,
+    /// Whether to ignore the quirks of known clients.
+    strict_spec_compliance: bool,
+    /// Whether to log tolerated deviations from the spec.
+    report_spec_deviations: bool,
+    /// Whether to convert manifests between Docker and OCI media types on request.
+    convert_media_types: bool,
+    /// Whether the administrative API is only served by the admin router.
+    separate_admin_routes: bool,
+    /// Egress bandwidth limits for blob downloads.
+    bandwidth_limits: throttle::BandwidthLimits,
+    /// Live events, see [`events`].
+    events: events::EventBus,
+    /// Operations started through [`ContainerRegistry::start_operation`].
+    operations: operations::Operations,
+    /// Issuer of pull tokens, if enabled.
+    token_issuer: Option<Arc<tokens::TokenIssuer>>,
+    /// CDN blob downloads are redirected to, if any.
+    cdn: Option<cdn::Cdn>,
+    /// Directory of peers blob downloads are redirected to, if any.
+    peer_directory: Option<Arc<dyn peers::PeerDirectory>>,
+    /// Webhooks events are delivered to, if any.
+    webhooks: Option<Arc<webhooks::Webhooks>>,
+    /// Decorator of error responses, if any.
+    error_decorator: Option<Arc<dyn errors::ErrorDecorator>>,
+    /// Policies adding tags to pushed manifests.
+    auto_tag_policies: Vec<Arc<dyn autotag::AutoTagPolicy>>,
+    /// Sampling of access records, if enabled.
+    access_log: Option<access::AccessLog>,
+    /// URLs the registry is reachable under.
+    public_urls: urls::PublicUrls,
+    /// Reverse proxies trusted to report client addresses.
+    trusted_proxies: Vec<IpAddr>,
 }
 
 impl ContainerRegistry {
@@ -204,10 +382,13 @@ impl ContainerRegistry {
     /// Builds an [`axum::routing::Router`] for this registry.
     ///
     /// Produces the core entry point for the registry; create and mount the router into an `axum`
-    /// application to use it.
+    /// application to use it. If a base path has been set through
+    /// [`ContainerRegistryBuilder::base_path`], all routes are nested below it.
     pub fn make_router(self: Arc<ContainerRegistry>) -> Router {
-        Router::new()
+        let router = Router::new()
             .route("/v2/", get(index_v2))
+            .route("/v2/_events", get(events::events_get))
+            .route("/v2/_catalog", get(catalog_get))
             .route("/v2/:repository/:image/blobs/:digest", head(blob_check))
             .route("/v2/:repository/:image/blobs/:digest", get(blob_get))
             .route("/v2/:repository/:image/blobs/uploads/", post(upload_new))
@@ -219,6 +400,7 @@ impl ContainerRegistry {
                 "/v2/:repository/:image/uploads/:upload",
                 put(upload_finalize),
             )
+            .route("/v2/:repository/:image/uploads/:upload", get(upload_status))
             .route(
                 "/v2/:repository/:image/manifests/:reference",
                 put(manifest_put),
@@ -227,125 +409,2595 @@ impl ContainerRegistry {
                 "/v2/:repository/:image/manifests/:reference",
                 get(manifest_get),
             )
+            .route(
+                "/v2/:repository/:image/referrers/:digest",
+                get(referrers_get),
+            )
+            .route("/v2/:repository/:image/tags/list", get(tags_list_get));
+        let router = if self.separate_admin_routes {
+            router
+        } else {
+            router.merge(admin::routes())
+        };
+        let router = router
+            .fallback(fallback)
+            .layer(axum::middleware::from_fn_with_state(
+                self.clone(),
+                access::record_access,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                self.clone(),
+                urls::rewrite_locations,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                self.clone(),
+                compat::apply_quirks,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                self.clone(),
+                auth::challenge_unauthorized,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                self.clone(),
+                errors::decorate_errors,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                self.clone(),
+                metrics::count_requests,
+            ))
+            .with_state(self.clone());
+
+        match self.public_urls.base_path {
+            Some(ref base_path) => Router::new().nest(base_path, router),
+            None => router,
+        }
+    }
+
+    /// Builds an [`axum::routing::Router`] for the administrative API, metrics and health checks.
+    ///
+    /// Meant to be served on a listener separate from the public API, e.g. bound to an internal
+    /// interface, along with [`ContainerRegistryBuilder::separate_admin_routes`] to remove the
+    /// administrative API from [`Self::make_router`]. Besides the administrative API below
+    /// `/admin/`, it serves the registry's metrics in the Prometheus format at `/metrics` and a
+    /// liveness check at `/health`, both without authentication. Routes are not nested below the
+    /// base path.
+    pub fn make_admin_router(self: Arc<ContainerRegistry>) -> Router {
+        admin::routes()
+            .merge(admin::internal_routes())
+            .fallback(fallback)
+            .layer(axum::middleware::from_fn_with_state(
+                self.clone(),
+                auth::challenge_unauthorized,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                self.clone(),
+                errors::decorate_errors,
+            ))
             .with_state(self)
     }
-}
 
-/// Builder for a new instance of the container registry.
-///
-/// Requires a storage to be set, either by calling [`Self::storage`] or constructing using
-/// [`Self::build_for_testing()`], which requires the `test-support` feature and will use
-/// a temporary directory.
-///
-/// By default, no hooks are set up and the auth provider requires authentication, but does not
-/// grant access to anything.
-#[derive(Default)]
-pub struct ContainerRegistryBuilder {
-    /// Storage to use.
-    storage: Option<PathBuf>,
-    /// Hooks to use.
-    hooks: Option<Box<dyn RegistryHooks>>,
-    /// Auth provider to use.
-    auth_provider: Option<Arc<dyn AuthProvider>>,
-}
+    /// Creates a [`service::RegistryService`] serving the registry's routes.
+    ///
+    /// An alternative to [`Self::make_router`] for embedding the registry into applications not
+    /// built on axum.
+    pub fn into_service(self: Arc<ContainerRegistry>) -> service::RegistryService {
+        service::RegistryService::new(self.make_router())
+    }
 
-impl ContainerRegistryBuilder {
-    /// Sets the auth provider for the new registry.
-    pub fn auth_provider(mut self, auth_provider: Arc<dyn AuthProvider>) -> Self {
-        self.auth_provider = Some(auth_provider);
-        self
+    /// Returns the metrics collected by the registry.
+    #[inline(always)]
+    pub fn metrics(&self) -> &metrics::Metrics {
+        &self.metrics
     }
 
-    /// Sets hooks for the new registry to call.
-    pub fn hooks(mut self, hooks: Box<dyn RegistryHooks>) -> Self {
-        self.hooks = Some(hooks);
-        self
+    /// Subscribes to live registry events.
+    ///
+    /// The receiver gets all events published after subscribing, see the [`events`] module.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<Arc<events::RegistryEvent>> {
+        self.events.subscribe()
     }
 
-    /// Set the storage path for the new registry.
-    pub fn storage<P>(mut self, storage: P) -> Self
+    /// Runs a hook invocation.
+    ///
+    /// Hooks are isolated from the registry: An invocation exceeding the configured hook timeout is
+    /// aborted and panics are caught, both are logged and counted in the registry's metrics.
+    ///
+    /// Returns the hook's result, unless it was aborted.
+    async fn run_hook<F, T>(&self, name: &'static str, hook: F) -> Option<T>
     where
-        P: Into<PathBuf>,
+        F: Future<Output = T>,
     {
-        self.storage = Some(storage.into());
-        self
+        match tokio::time::timeout(self.hook_timeout, AssertUnwindSafe(hook).catch_unwind()).await {
+            Ok(Ok(result)) => Some(result),
+            Ok(Err(_panic)) => {
+                self.metrics.hook_panics.inc();
+                error!(hook = name, "hook panicked");
+                None
+            }
+            Err(_elapsed) => {
+                self.metrics.hook_timeouts.inc();
+                warn!(hook = name, timeout = ?self.hook_timeout, "hook timed out");
+                None
+            }
+        }
     }
 
-    /// Constructs a new registry.
+    /// Inspects all layers of a manifest about to be stored, if a layer inspector is configured.
     ///
-    /// # Panics
+    /// Reports are passed to hooks, afterwards the manifest is refused if there were findings and
+    /// the inspector is set to reject them.
+    #[cfg(feature = "inspection")]
+    async fn inspect_layers(
+        &self,
+        manifest_reference: &ManifestReference,
+        raw_manifest: &[u8],
+    ) -> Result<(), RegistryError> {
+        let Some(ref inspector) = self.layer_inspector else {
+            return Ok(());
+        };
+
+        // Invalid manifests are rejected by storage later on, nothing to inspect for indices.
+        let Ok(Manifest::Image(manifest)) = Manifest::from_slice(raw_manifest) else {
+            return Ok(());
+        };
+
+        let mut findings = 0;
+        for layer in manifest.layers() {
+            if !inspection::LayerInspector::supports(layer.media_type()) {
+                continue;
+            }
+            let digest = layer
+                .parsed_digest()
+                .map_err(RegistryError::InvalidDigest)?;
+            let Some(reader) = self.storage.get_blob_reader(digest).await? else {
+                continue;
+            };
+
+            let report = inspector
+                .inspect(digest, layer.media_type().to_owned(), reader)
+                .await?;
+            for finding in &report.findings {
+                warn!(%manifest_reference, layer = %digest, path = %finding.path.display(),
+                    policy = finding.policy, description = finding.description,
+                    "layer inspection finding");
+            }
+            findings += report.findings.len();
+
+            self.run_hook(
+                "on_layer_inspected",
+                self.hooks.on_layer_inspected(manifest_reference, &report),
+            )
+            .await;
+        }
+
+        if findings > 0 && inspector.rejects_on_findings() {
+            return Err(RegistryError::PolicyViolation(format!(
+                "{} finding(s) in image layers",
+                findings
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Points a tag at an existing manifest.
     ///
-    /// Will panic if not storage has been set through [`Self::storage`].
-    pub fn build(mut self) -> Result<Arc<ContainerRegistry>, FilesystemStorageError> {
-        let storage_path = self
+    /// Creates or overwrites `tag` at `location`, making it refer to the manifest identified by
+    /// `digest`, which must already be stored in the registry. No blobs or manifests are copied,
+    /// thus this is suitable for promoting images (e.g. from `staging` to `prod`) without pulling
+    /// and pushing them again.
+    pub async fn put_tag(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+        digest: storage::Digest,
+    ) -> Result<(), RegistryError> {
+        self.purges.check_writable(location)?;
+        self.check_repository_exists(location).await?;
+        let reference = ManifestReference::new(location.clone(), Reference::new_digest(digest));
+        if let Some(raw) = self.storage.get_manifest(&reference).await? {
+            if let Ok(manifest) = Manifest::from_slice(&raw) {
+                self.link_manifest_blobs(location, &manifest).await?;
+            }
+        }
+        self.make_room_for_tag(location, tag).await?;
+        let previous = self.tag_target(location, tag).await?;
+        let replaced = self.tagged_index(location, tag).await?;
+        self.storage.put_tag(location, tag, digest).await?;
+        self.record_tag_update(location, tag, previous, digest)
+            .await?;
+        self.record_replaced_index(replaced, digest).await?;
+
+        info!(%location, %tag, %digest, "tag updated");
+        self.events.publish(events::RegistryEvent::TagUpdated {
+            location: location.clone(),
+            tag: tag.to_owned(),
+            digest: ImageDigest::new(digest),
+        });
+
+        Ok(())
+    }
+
+    /// Stores a blob read from `reader` at `location`, returning its digest.
+    ///
+    /// This is the equivalent of a blob upload through the HTTP API, subject to the configured
+    /// maximum blob size. No permissions are checked, callers of the library API are trusted.
+    pub async fn put_blob<R>(
+        &self,
+        location: &ImageLocation,
+        reader: R,
+    ) -> Result<storage::Digest, RegistryError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.check_repository_exists(location).await?;
+        let upload = self.storage.begin_new_upload().await?;
+
+        let written = self.write_upload(upload, reader).await;
+        let digest = match written {
+            Ok(digest) => digest,
+            Err(err) => {
+                self.storage.cancel_upload(upload).await?;
+                return Err(err);
+            }
+        };
+
+        self.storage.finalize_upload(upload, digest).await?;
+        self.storage.link_blob(location, digest).await?;
+
+        info!(%location, %digest, "new blob stored");
+        Ok(digest)
+    }
+
+    /// Writes everything read from `reader` to an upload, returning its digest.
+    async fn write_upload<R>(
+        &self,
+        upload: Uuid,
+        mut reader: R,
+    ) -> Result<storage::Digest, RegistryError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let writer = self.storage.get_upload_writer(0, upload).await?;
+        let mut writer = checksums::HashingWriter::new(writer, true);
+
+        let mut buf = vec![0; 64 * 1024];
+        let mut written: u64 = 0;
+        loop {
+            let read = reader
+                .read(&mut buf)
+                .await
+                .map_err(RegistryError::ReadFailed)?;
+            if read == 0 {
+                break;
+            }
+
+            written += read as u64;
+            self.check_blob_size(written)?;
+            writer
+                .write_all(&buf[..read])
+                .await
+                .map_err(RegistryError::LocalWriteFailed)?;
+        }
+        writer
+            .shutdown()
+            .await
+            .map_err(RegistryError::LocalWriteFailed)?;
+
+        Ok(writer.digest().expect("hashing should be enabled"))
+    }
+
+    /// Retrieves a manifest.
+    ///
+    /// Like a pull through the HTTP API, manifests failing notation trust verification are
+    /// refused. No permissions are checked, callers of the library API are trusted and thus may
+    /// retrieve quarantined manifests or pull by tag where digests are required.
+    pub async fn get_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+    ) -> Result<Vec<u8>, RegistryError> {
+        let raw = self
             .storage
-            .expect("attempted to construct registry with no storage path");
-        let storage = Box::new(FilesystemStorage::new(storage_path)?);
-        let auth_provider = self
-            .auth_provider
-            .take()
-            .unwrap_or_else(|| Arc::new(Permissions::NoAccess));
-        let hooks = self.hooks.take().unwrap_or_else(|| Box::new(()));
-        Ok(Arc::new(ContainerRegistry {
-            realm: "ContainerRegistry".to_string(),
-            auth_provider,
-            storage,
-            hooks,
-        }))
+            .get_manifest(manifest_reference)
+            .await?
+            .ok_or(RegistryError::NotFound)?;
+        #[cfg_attr(not(feature = "notation"), allow(unused_variables))]
+        let digest = self.verify_manifest(manifest_reference, &raw).await?;
+
+        #[cfg(feature = "notation")]
+        {
+            let manifest = Manifest::from_slice(&raw).map_err(RegistryError::ParseManifest)?;
+            self.check_notation_trust(manifest_reference.location(), digest, &manifest)
+                .await?;
+        }
+
+        Ok(raw)
     }
-}
 
-/// Registry index
-///
-/// Returns an empty HTTP OK response if provided credentials are okay, otherwise returns
-/// UNAUTHORIZED.
-async fn index_v2(
-    State(registry): State<Arc<ContainerRegistry>>,
-    unverified: Unverified,
-) -> Response<Body> {
-    let realm = &registry.realm;
+    /// Checks that a manifest retrieved by digest actually has it, returning the digest of `raw`.
+    ///
+    /// A mismatch means storage is corrupted. It is logged, counted and reported through
+    /// [`RegistryHooks::on_manifest_corrupted`], and the manifest is not served, as clients might
+    /// not verify it themselves.
+    async fn verify_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+        raw: &[u8],
+    ) -> Result<storage::Digest, RegistryError> {
+        let actual = storage::Digest::from_contents(raw);
+        let Reference::Digest(expected) = *manifest_reference.reference() else {
+            return Ok(actual);
+        };
+        if expected == actual {
+            return Ok(actual);
+        }
 
-    // Both anonymous and named users should be verified to be able to get index. Restricted access
-    // is handled identically for both via the rules set within the registry constructor.
-    if registry
-        .auth_provider
-        .check_credentials(&unverified)
-        .await
-        .is_some()
+        let (expected, actual) = (ImageDigest::new(expected), ImageDigest::new(actual));
+        error!(location = %manifest_reference.location(), %expected, %actual, "manifest is corrupted");
+        self.metrics.manifests_corrupted.inc();
+        self.run_hook(
+            "on_manifest_corrupted",
+            self.hooks
+                .on_manifest_corrupted(manifest_reference.location(), &expected, &actual),
+        )
+        .await;
+
+        Err(RegistryError::ManifestCorrupted { expected, actual })
+    }
+
+    /// Stores a manifest, returning its digest.
+    ///
+    /// This is the equivalent of a manifest push through the HTTP API: the manifest is validated
+    /// against the registry's policies, e.g. allowed media types, layer inspection or tag limits,
+    /// and may be quarantined. Hooks are run and events published as usual. The media type is
+    /// taken from the manifest's `mediaType` field. No permissions are checked, callers of the
+    /// library API are trusted.
+    pub async fn put_manifest(
+        self: &Arc<Self>,
+        manifest_reference: &ManifestReference,
+        raw_manifest: &[u8],
+    ) -> Result<storage::Digest, RegistryError> {
+        let size = raw_manifest.len() as u64;
+        if size > self.max_manifest_size {
+            return Err(RegistryError::ManifestTooLarge {
+                size,
+                limit: self.max_manifest_size,
+            });
+        }
+
+        let media_type = embedded_media_type(raw_manifest)?;
+        let accepted = self
+            .accept_manifest(manifest_reference, media_type, raw_manifest.to_vec())
+            .await?;
+
+        Ok(accepted.digest)
+    }
+
+    /// Removes a tag, leaving the manifest it points to available by digest.
+    ///
+    /// Returns [`RegistryError::NotFound`] if `tag` does not exist at `location`.
+    pub async fn delete_tag(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+    ) -> Result<(), RegistryError> {
+        let tags = self.storage.list_tags(location).await?;
+        if !tags.iter().any(|existing| existing.tag == tag) {
+            return Err(RegistryError::NotFound);
+        }
+
+        self.storage.delete_tag(location, tag).await?;
+
+        info!(%location, %tag, "tag deleted");
+        self.events.publish(events::RegistryEvent::TagDeleted {
+            location: location.clone(),
+            tag: tag.to_owned(),
+            actor: None,
+        });
+
+        Ok(())
+    }
+
+    /// Validates and stores a pushed manifest of type `media_type`, then runs hooks and starts
+    /// background processing, e.g. SBOM generation.
+    async fn accept_manifest(
+        self: &Arc<Self>,
+        manifest_reference: &ManifestReference,
+        media_type: Option<String>,
+        raw_manifest: Vec<u8>,
+    ) -> Result<AcceptedManifest, RegistryError> {
+        self.purges.check_writable(manifest_reference.location())?;
+        self.check_repository_exists(manifest_reference.location())
+            .await?;
+        if !self.custom_manifest_types
+            && !media_type
+                .as_deref()
+                .is_some_and(types::is_manifest_media_type)
+        {
+            return Err(RegistryError::UnsupportedManifestType(media_type));
+        }
+        self.check_media_type(
+            manifest_reference.location(),
+            media_type.as_deref(),
+            &raw_manifest,
+        )?;
+
+        #[cfg(feature = "inspection")]
+        self.inspect_layers(manifest_reference, &raw_manifest)
+            .await?;
+
+        // With scoping, naming a blob of another repository must not grant access to it.
+        if let Ok(manifest) = Manifest::from_slice(&raw_manifest) {
+            let location = manifest_reference.location();
+            if !self.scope_blobs {
+                self.link_manifest_blobs(location, &manifest).await?;
+            } else if let Some(digest) = self.unlinked_blob(location, &manifest).await? {
+                return Err(RegistryError::ManifestBlobUnknown(ImageDigest::new(digest)));
+            }
+        }
+
+        // Manifests new to a quarantined location are stored by digest only, their tag is applied on
+        // release. Digests are global, so whether a manifest is new is decided by the location index.
+        let location = manifest_reference.location();
+        let digest = storage::Digest::from_contents(&raw_manifest);
+        let digest_reference =
+            ManifestReference::new(location.clone(), Reference::new_digest(digest));
+        let quarantined = match self.quarantine {
+            Some(ref quarantine)
+                if quarantine.applies_to(location)
+                    && Manifest::from_slice(&raw_manifest)
+                        .is_ok_and(|manifest| manifest.subject().is_none()) =>
+            {
+                let io_error = |err| RegistryError::Storage(storage::Error::Io(err));
+                if quarantine
+                    .get(location, digest)
+                    .await
+                    .map_err(io_error)?
+                    .is_some()
+                    || !self
+                        .storage
+                        .list_manifests(location)
+                        .await?
+                        .iter()
+                        .any(|stored| stored.digest == digest)
+                {
+                    quarantine
+                        .hold(location, digest, manifest_reference.reference().as_tag())
+                        .await
+                        .map_err(io_error)?;
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        };
+        let stored_reference = if quarantined {
+            &digest_reference
+        } else {
+            manifest_reference
+        };
+
+        let mut previous = None;
+        let mut replaced = None;
+        if let Some(tag) = stored_reference.reference().as_tag() {
+            self.make_room_for_tag(location, tag).await?;
+            previous = self.tag_target(location, tag).await?;
+            replaced = self.tagged_index(location, tag).await?;
+        }
+
+        let digest = self
+            .storage
+            .put_manifest(stored_reference, &raw_manifest)
+            .await?;
+        if let Some(tag) = stored_reference.reference().as_tag() {
+            self.record_tag_update(location, tag, previous, digest)
+                .await?;
+            self.record_replaced_index(replaced, digest).await?;
+        }
+
+        if quarantined {
+            info!(%manifest_reference, %digest, "new manifest received, quarantined");
+        } else {
+            info!(%manifest_reference, %digest, "new manifest received");
+        }
+
+        // Artifacts referring to another manifest (e.g. signatures) are not tagged automatically.
+        if !quarantined
+            && Manifest::from_slice(&raw_manifest)
+                .is_ok_and(|manifest| manifest.subject().is_none())
+        {
+            self.auto_tag(location, manifest_reference.reference(), digest)
+                .await?;
+        }
+
+        // Storage accepted the manifest, so it is valid.
+        let manifest = Manifest::from_slice(&raw_manifest).map_err(RegistryError::ParseManifest)?;
+
+        // Completed upload, call hook:
+        self.run_hook(
+            "on_manifest_uploaded",
+            self.hooks
+                .on_manifest_uploaded(stored_reference, &manifest, &raw_manifest),
+        )
+        .await;
+        self.events.publish(events::RegistryEvent::ManifestPushed {
+            location: location.clone(),
+            reference: stored_reference.reference().clone(),
+            digest: ImageDigest::new(digest),
+        });
+
+        if quarantined {
+            let registry = self.clone();
+            let manifest_reference = manifest_reference.clone();
+            let raw_manifest = raw_manifest.clone();
+            tokio::spawn(async move {
+                registry
+                    .review_quarantined(manifest_reference, raw_manifest)
+                    .await
+            });
+        }
+
+        // Images get an SBOM generated, unless they are artifacts referring to another manifest
+        // themselves (e.g. signatures or SBOMs).
+        if self.sbom_generator.is_some()
+            && matches!(manifest, Manifest::Image(_))
+            && manifest.subject().is_none()
+        {
+            let registry = self.clone();
+            let location = manifest_reference.location().clone();
+            tokio::spawn(async move { registry.generate_sbom(location, digest).await });
+        }
+
+        // Accepted manifests are attested, again except for artifacts referring to other manifests.
+        if self.attestation_signer.is_some() && manifest.subject().is_none() {
+            let registry = self.clone();
+            let manifest_reference = manifest_reference.clone();
+            tokio::spawn(async move { registry.attest(manifest_reference, digest).await });
+        }
+
+        Ok(AcceptedManifest {
+            reference: stored_reference.clone(),
+            digest,
+            manifest,
+        })
+    }
+
+    /// Copies a manifest to another location and tags it, e.g. to promote an image from `staging`
+    /// to `prod` once it has been tested.
+    ///
+    /// `source` may refer to the manifest by tag or digest. The manifests of all platforms of an
+    /// image index are copied as well, and so are artifacts referring to any of the copied
+    /// manifests, e.g. signatures or SBOMs. Blobs are shared between all locations, thus they are
+    /// never copied. `tag` is only set once everything else is in place, so clients never see a
+    /// partially promoted image.
+    ///
+    /// Returns the digest of the promoted manifest.
+    pub async fn promote(
+        &self,
+        source: &ManifestReference,
+        destination: &ImageLocation,
+        tag: &str,
+    ) -> Result<storage::Digest, RegistryError> {
+        let raw = self
+            .storage
+            .get_manifest(source)
+            .await?
+            .ok_or(RegistryError::NotFound)?;
+        let digest = storage::Digest::from_contents(&raw);
+
+        // Collect everything reachable from the manifest, along the way.
+        let mut seen = HashSet::from([digest]);
+        let mut pending = vec![(digest, raw.clone())];
+        let mut dependencies = Vec::new();
+        while let Some((current, current_raw)) = pending.pop() {
+            let mut linked: Vec<_> = self
+                .storage
+                .get_referrers(source.location(), current)
+                .await?
+                .iter()
+                .filter_map(|descriptor| descriptor.parsed_digest().ok())
+                .collect();
+            if let Ok(Manifest::Index(index)) = Manifest::from_slice(&current_raw) {
+                linked.extend(
+                    index
+                        .manifests()
+                        .iter()
+                        .filter_map(|child| child.parsed_digest().ok()),
+                );
+            }
+
+            for dependency in linked {
+                if !seen.insert(dependency) {
+                    continue;
+                }
+
+                let reference = ManifestReference::new(
+                    source.location().clone(),
+                    Reference::new_digest(dependency),
+                );
+                match self.storage.get_manifest(&reference).await? {
+                    Some(dependency_raw) => {
+                        pending.push((dependency, dependency_raw.clone()));
+                        dependencies.push((dependency, dependency_raw));
+                    }
+                    None => {
+                        warn!(%source, %dependency, "manifest to promote is missing, skipping");
+                    }
+                }
+            }
+        }
+
+        for (dependency, dependency_raw) in dependencies {
+            self.storage
+                .put_manifest(
+                    &ManifestReference::new(destination.clone(), Reference::new_digest(dependency)),
+                    &dependency_raw,
+                )
+                .await?;
+        }
+
+        self.make_room_for_tag(destination, tag).await?;
+        let previous = self.tag_target(destination, tag).await?;
+        let replaced = self.tagged_index(destination, tag).await?;
+        let promoted = ManifestReference::new(destination.clone(), Reference::new_tag(tag));
+        self.storage.put_manifest(&promoted, &raw).await?;
+        self.record_tag_update(destination, tag, previous, digest)
+            .await?;
+        self.record_replaced_index(replaced, digest).await?;
+
+        info!(%source, destination = %promoted, %digest, "manifest promoted");
+        // Stored manifests are valid.
+        let manifest = Manifest::from_slice(&raw).map_err(RegistryError::ParseManifest)?;
+        self.run_hook(
+            "on_manifest_uploaded",
+            self.hooks.on_manifest_uploaded(&promoted, &manifest, &raw),
+        )
+        .await;
+        self.events.publish(events::RegistryEvent::ManifestPushed {
+            location: destination.clone(),
+            reference: promoted.reference().clone(),
+            digest: ImageDigest::new(digest),
+        });
+
+        Ok(digest)
+    }
+
+    /// Returns the index `tag` currently points to, if it points to one.
+    ///
+    /// Called before moving a tag, so the index can be recorded as replaced afterwards, see
+    /// [`maintenance::GarbageCollection::dangling_manifests`].
+    pub(crate) async fn tagged_index(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+    ) -> Result<Option<storage::Digest>, RegistryError> {
+        let reference = ManifestReference::new(location.clone(), Reference::new_tag(tag));
+        let Some(raw) = self.storage.get_manifest(&reference).await? else {
+            return Ok(None);
+        };
+        Ok(matches!(Manifest::from_slice(&raw), Ok(Manifest::Index(_)))
+            .then(|| storage::Digest::from_contents(&raw)))
+    }
+
+    /// Records an index returned by [`Self::tagged_index`] as possibly dangling, unless its tag
+    /// still points to it.
+    pub(crate) async fn record_replaced_index(
+        &self,
+        replaced: Option<storage::Digest>,
+        digest: storage::Digest,
+    ) -> Result<(), RegistryError> {
+        let replaced = replaced.filter(|replaced| *replaced != digest);
+        self.dangling
+            .record(replaced)
+            .await
+            .map_err(|err| RegistryError::Storage(storage::Error::Io(err)))
+    }
+
+    /// Enforces the tag limit before `tag` is created at `location`.
+    ///
+    /// Depending on the configured policy, refuses new tags or removes the oldest ones.
+    async fn make_room_for_tag(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+    ) -> Result<(), RegistryError> {
+        let Some((max, policy)) = self.tag_limit else {
+            return Ok(());
+        };
+
+        let mut tags = self.storage.list_tags(location).await?;
+        if tags.len() < max || tags.iter().any(|existing| existing.tag == tag) {
+            return Ok(());
+        }
+
+        match policy {
+            TagLimitPolicy::Reject => Err(RegistryError::PolicyViolation(format!(
+                "{location} has reached the limit of {max} tags"
+            ))),
+            TagLimitPolicy::EvictOldest => {
+                // Oldest first.
+                tags.sort_by_key(|existing| existing.modified);
+                let excess = tags.len() + 1 - max;
+
+                for evicted in tags.into_iter().take(excess) {
+                    self.storage.delete_tag(location, &evicted.tag).await?;
+                    info!(
+                        %location,
+                        tag = evicted.tag,
+                        actor = auth::MAINTENANCE_IDENTITY,
+                        "removed tag due to tag limit"
+                    );
+                    self.events.publish(events::RegistryEvent::TagDeleted {
+                        location: location.clone(),
+                        tag: evicted.tag,
+                        actor: Some(auth::MAINTENANCE_IDENTITY.to_owned()),
+                    });
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Takes a snapshot of the registry's local storage for backups, returning the number of files
+    /// it contains.
+    ///
+    /// The snapshot is created as a new directory at `destination`, which must not exist yet and
+    /// has to be on the same filesystem as the storage, as files are hard linked rather than
+    /// copied. Pushes and deletions are suspended while the snapshot is taken, so it is
+    /// consistent, e.g. never contains a tag without its manifest. Uploads in progress and blobs
+    /// moved to cold storage are not included.
+    ///
+    /// The snapshot has the layout of a storage directory and can be backed up with any external
+    /// tool, or served by another registry directly. See
+    /// [`maintenance::Snapshots`] for taking snapshots periodically.
+    pub async fn snapshot<P: AsRef<std::path::Path>>(
+        &self,
+        destination: P,
+    ) -> Result<u64, RegistryError> {
+        let destination = destination.as_ref().to_owned();
+        let files = self.local_storage.snapshot(destination.clone()).await?;
+        info!(destination = %destination.display(), files, "snapshot taken");
+        Ok(files)
+    }
+
+    /// Writes the OCI image layouts of all images, returning the number of images written.
+    ///
+    /// Layouts are kept up to date as images change, this is only needed to mirror images stored
+    /// before [`ContainerRegistryBuilder::oci_layout`] was enabled, or to repair layouts after a
+    /// failed update. Returns [`RegistryError::NotSupported`] if layouts are not enabled.
+    pub async fn sync_oci_layouts(&self) -> Result<usize, RegistryError> {
+        let layout = self
+            .oci_layout
+            .as_ref()
+            .ok_or(RegistryError::NotSupported("OCI image layouts"))?;
+        let images = layout.sync_all(&*self.storage).await?;
+        info!(images, "OCI image layouts synchronized");
+        Ok(images)
+    }
+
+    /// Starts running a maintenance task in the background.
+    ///
+    /// The returned handle reports the task's progress and allows cancelling it, see the
+    /// [`operations`] module. Like scheduled runs, the outcome is reported through hooks, logs and
+    /// metrics. Must be called from within a tokio runtime.
+    pub fn start_operation<T>(self: &Arc<Self>, task: T) -> operations::Operation
+    where
+        T: maintenance::MaintenanceTask + 'static,
     {
-        return Response::builder()
-            .status(StatusCode::OK)
-            .header("WWW-Authenticate", format!("Basic realm=\"{realm}\""))
-            .body(Body::empty())
-            .unwrap();
+        let operation = operations::Operation::new(task.name(), self.clock.clone());
+        self.operations.insert(operation.clone());
+        info!(id = %operation.id(), task = task.name(), "operation started");
+
+        let registry = self.clone();
+        let handle = operation.clone();
+        tokio::spawn(async move {
+            let report = maintenance::run_reported(&registry, &task, handle.progress()).await;
+            handle.finish(report.outcome);
+        });
+
+        operation
+    }
+
+    /// Returns an operation started through [`Self::start_operation`], unless it finished long
+    /// ago.
+    pub fn operation(&self, id: Uuid) -> Option<operations::Operation> {
+        self.operations.get(id)
+    }
+
+    /// Returns all operations started through [`Self::start_operation`] that are still running or
+    /// finished recently, oldest first.
+    pub fn operations(&self) -> Vec<operations::Operation> {
+        self.operations.list()
+    }
+
+    /// Resolves a manifest digest, which may be abbreviated like a git commit hash.
+    ///
+    /// Accepts full digests (`sha256:...`) as well as unique prefixes of at least four hex digits,
+    /// with or without the `sha256:` prefix. Full digests are returned as-is, without checking
+    /// whether the manifest exists. Abbreviated digests matching no manifest result in
+    /// [`RegistryError::NotFound`], those matching several in [`RegistryError::AmbiguousDigest`].
+    pub async fn resolve_digest(&self, digest: &str) -> Result<storage::Digest, RegistryError> {
+        if let Ok(digest) = digest.parse::<ImageDigest>() {
+            return Ok(digest.digest());
+        }
+
+        let prefix = digest.strip_prefix("sha256:").unwrap_or(digest);
+        if !(MIN_DIGEST_PREFIX_LEN..storage::SHA256_LEN * 2).contains(&prefix.len()) {
+            return Err(RegistryError::InvalidDigest(
+                ImageDigestParseError::WrongLength,
+            ));
+        }
+        // Digests are always stored lowercase.
+        if !prefix
+            .bytes()
+            .all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+        {
+            return Err(RegistryError::InvalidDigest(
+                ImageDigestParseError::HexDecodeError,
+            ));
+        }
+
+        let mut matches = self.storage.find_manifest_digests(prefix).await?;
+        match matches.len() {
+            0 => Err(RegistryError::NotFound),
+            1 => Ok(matches.pop().expect("should have one match")),
+            matches => Err(RegistryError::AmbiguousDigest {
+                prefix: prefix.to_owned(),
+                matches,
+            }),
+        }
+    }
+
+    /// Checks whether a blob may be served through `location`, see
+    /// [`ContainerRegistryBuilder::scope_blobs`].
+    ///
+    /// Requests authenticated with a [pull token](crate::tokens) are always scoped, as tokens
+    /// grant access to the locations in their scope only.
+    async fn blob_in_scope(
+        &self,
+        creds: &ValidCredentials,
+        location: &ImageLocation,
+        digest: storage::Digest,
+    ) -> Result<bool, RegistryError> {
+        let is_token = creds.try_extract_ref::<tokens::TokenCreds>().is_some();
+        if !self.scope_blobs && !is_token {
+            return Ok(true);
+        }
+
+        self.auth_provider_for(creds)
+            .image_permissions(creds, location)
+            .await
+            .require_read()?;
+
+        Ok(self.storage.is_blob_linked(location, digest).await?)
+    }
+
+    /// Returns the first config or layer of an image manifest not linked to `location`.
+    async fn unlinked_blob(
+        &self,
+        location: &ImageLocation,
+        manifest: &Manifest,
+    ) -> Result<Option<storage::Digest>, RegistryError> {
+        let Manifest::Image(image) = manifest else {
+            return Ok(None);
+        };
+
+        for descriptor in std::iter::once(image.config()).chain(image.layers()) {
+            let Ok(digest) = descriptor.parsed_digest() else {
+                continue;
+            };
+            if !self.storage.is_blob_linked(location, digest).await? {
+                return Ok(Some(digest));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Links the config and layers of an image manifest to `location`.
+    ///
+    /// Only done for manifests pushed while blobs are not scoped, where it grants nothing new but
+    /// lets [pull tokens](crate::tokens) for `location` include them, and when tagging through the
+    /// library API, whose callers are trusted.
+    async fn link_manifest_blobs(
+        &self,
+        location: &ImageLocation,
+        manifest: &Manifest,
+    ) -> Result<(), RegistryError> {
+        let Manifest::Image(image) = manifest else {
+            return Ok(());
+        };
+
+        for descriptor in std::iter::once(image.config()).chain(image.layers()) {
+            if let Ok(digest) = descriptor.parsed_digest() {
+                self.storage.link_blob(location, digest).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Issues a pull token to `username`, granting read access to all locations in `scope`.
+    ///
+    /// Returns `None` if pull tokens are not enabled, see
+    /// [`ContainerRegistryBuilder::token_issuer`]. The lifetime defaults to that configured on the
+    /// issuer if not given.
+    pub fn issue_pull_token(
+        &self,
+        username: &str,
+        scope: &[ImageLocation],
+        ttl: Option<Duration>,
+    ) -> Option<tokens::PullToken> {
+        let issuer = self.token_issuer.as_ref()?;
+        let token = issuer.issue(username, scope, ttl);
+
+        info!(%username, scope = ?scope, "pull token issued");
+
+        Some(token)
+    }
+
+    /// Fails with [`RegistryError::BlobTooLarge`] if a blob of `size` bytes exceeds the
+    /// configured maximum blob size.
+    fn check_blob_size(&self, size: u64) -> Result<(), RegistryError> {
+        match self.max_blob_size {
+            Some(limit) if size > limit => Err(RegistryError::BlobTooLarge { limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks whether manifests at `location` may only be pulled by digest.
+    fn requires_digest_pulls(&self, location: &ImageLocation) -> bool {
+        self.digest_pull_only.contains(location.repository())
+            || self.digest_pull_only.contains(&location.to_string())
+    }
+
+    /// Checks whether a manifest may be stored at `location`, see
+    /// [`ContainerRegistryBuilder::allow_media_types`].
+    fn check_media_type(
+        &self,
+        location: &ImageLocation,
+        media_type: Option<&str>,
+        raw_manifest: &[u8],
+    ) -> Result<(), RegistryError> {
+        let Some(allowed) = self
+            .allowed_media_types
+            .get(&location.to_string())
+            .or_else(|| self.allowed_media_types.get(location.repository()))
+        else {
+            return Ok(());
+        };
+
+        let manifest = Manifest::from_slice(raw_manifest).ok();
+        let artifact_type = manifest.as_ref().and_then(Manifest::artifact_type);
+        if [media_type, artifact_type]
+            .into_iter()
+            .flatten()
+            .any(|ty| allowed.contains(ty))
+        {
+            return Ok(());
+        }
+
+        Err(RegistryError::MediaTypeNotAllowed {
+            location: location.clone(),
+            media_type: artifact_type.or(media_type).map(ToOwned::to_owned),
+        })
     }
 
-    // Return `UNAUTHORIZED`, since we want the client to supply credentials.
-    Response::builder()
-        .status(StatusCode::UNAUTHORIZED)
-        .header("WWW-Authenticate", format!("Basic realm=\"{realm}\""))
-        .body(Body::empty())
-        .unwrap()
+    /// Stores a blob from memory, returning its digest.
+    async fn store_blob(&self, contents: &[u8]) -> Result<storage::Digest, RegistryError> {
+        let digest = storage::Digest::from_contents(contents);
+
+        if self.storage.get_blob_metadata(digest).await?.is_some() {
+            return Ok(digest);
+        }
+
+        let upload = self.storage.begin_new_upload().await?;
+        let mut writer = self.storage.get_upload_writer(0, upload).await?;
+        writer
+            .write_all(contents)
+            .await
+            .map_err(RegistryError::LocalWriteFailed)?;
+        writer
+            .shutdown()
+            .await
+            .map_err(RegistryError::LocalWriteFailed)?;
+        drop(writer);
+        self.storage.finalize_upload(upload, digest).await?;
+
+        Ok(digest)
+    }
+
+    /// Releases a quarantined manifest, applying the tags it was pushed as.
+    ///
+    /// Quarantined manifests listed in a released index are released as well. Returns
+    /// [`RegistryError::NotFound`] if the manifest is not quarantined at `location`. See the
+    /// [`quarantine`] module for details.
+    pub async fn release(
+        &self,
+        location: &ImageLocation,
+        digest: storage::Digest,
+    ) -> Result<(), RegistryError> {
+        let quarantine = self.quarantine.as_ref().ok_or(RegistryError::NotFound)?;
+        let io_error = |err| RegistryError::Storage(storage::Error::Io(err));
+
+        let held = quarantine
+            .get(location, digest)
+            .await
+            .map_err(io_error)?
+            .ok_or(RegistryError::NotFound)?;
+
+        // Children are released first, so a released index never points at quarantined ones.
+        let reference = ManifestReference::new(location.clone(), Reference::new_digest(digest));
+        if let Some(raw) = self.storage.get_manifest(&reference).await? {
+            if let Ok(Manifest::Index(index)) = Manifest::from_slice(&raw) {
+                for child in index.manifests() {
+                    let Ok(child) = child.parsed_digest() else {
+                        continue;
+                    };
+                    if quarantine
+                        .remove(location, child)
+                        .await
+                        .map_err(io_error)?
+                        .is_some()
+                    {
+                        info!(%location, digest = %child, "manifest released");
+                    }
+                }
+            }
+        }
+
+        for tag in &held.tags {
+            self.put_tag(location, tag, digest).await?;
+        }
+        quarantine
+            .remove(location, digest)
+            .await
+            .map_err(io_error)?;
+        info!(%location, %digest, tags = ?held.tags, "manifest released");
+
+        Ok(())
+    }
+
+    /// Checks whether a manifest may be served to the caller, refusing quarantined manifests to
+    /// everyone but reviewers.
+    async fn check_quarantine(
+        &self,
+        creds: &ValidCredentials,
+        location: &ImageLocation,
+        digest: storage::Digest,
+    ) -> Result<(), RegistryError> {
+        let Some(ref quarantine) = self.quarantine else {
+            return Ok(());
+        };
+        if quarantine.is_reviewer(creds) {
+            return Ok(());
+        }
+
+        let held = quarantine
+            .get(location, digest)
+            .await
+            .map_err(|err| RegistryError::Storage(storage::Error::Io(err)))?;
+        if held.is_some() {
+            return Err(RegistryError::PolicyViolation(format!(
+                "manifest {} at {location} is quarantined pending review",
+                ImageDigest::new(digest)
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Lets hooks review a freshly quarantined manifest, releasing it if they decide so.
+    async fn review_quarantined(&self, manifest_reference: ManifestReference, raw: Vec<u8>) {
+        let Ok(manifest) = Manifest::from_slice(&raw) else {
+            return;
+        };
+        let location = manifest_reference.location();
+        let digest = storage::Digest::from_contents(&raw);
+
+        let decision = self
+            .run_hook(
+                "on_manifest_quarantined",
+                self.hooks
+                    .on_manifest_quarantined(&manifest_reference, &manifest, &raw),
+            )
+            .await;
+        if decision == Some(quarantine::QuarantineDecision::Release) {
+            if let Err(err) = self.release(location, digest).await {
+                error!(%location, %digest, %err, "failed to release manifest");
+            }
+        }
+    }
+
+    /// Attaches an SBOM to an image.
+    ///
+    /// `document` is stored as an OCI artifact of type `media_type` (e.g. [`sbom::SPDX_JSON`])
+    /// whose subject is the manifest `subject` at `location`, making it discoverable through the
+    /// referrers API. Returns the digest of the artifact manifest.
+    pub async fn attach_sbom(
+        &self,
+        location: &ImageLocation,
+        subject: storage::Digest,
+        media_type: &str,
+        document: &[u8],
+    ) -> Result<storage::Digest, RegistryError> {
+        let artifact_digest = self
+            .attach_artifact(location, subject, media_type, document)
+            .await?;
+
+        info!(%location, %subject, sbom = %artifact_digest, "SBOM attached");
+
+        Ok(artifact_digest)
+    }
+
+    /// Stores `document` as an OCI artifact of type `media_type` whose subject is the manifest
+    /// `subject` at `location`. Returns the digest of the artifact manifest.
+    async fn attach_artifact(
+        &self,
+        location: &ImageLocation,
+        subject: storage::Digest,
+        media_type: &str,
+        document: &[u8],
+    ) -> Result<storage::Digest, RegistryError> {
+        let subject_raw = self
+            .storage
+            .get_manifest(&ManifestReference::new(
+                location.clone(),
+                Reference::new_digest(subject),
+            ))
+            .await?
+            .ok_or(RegistryError::NotFound)?;
+        let subject_manifest =
+            Manifest::from_slice(&subject_raw).map_err(RegistryError::ParseManifest)?;
+
+        let config = self.store_blob(types::EMPTY_CONFIG).await?;
+        let layer = self.store_blob(document).await?;
+
+        let artifact = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": types::OCI_IMAGE_MANIFEST,
+            "artifactType": media_type,
+            "config": ContentDescriptor::new(
+                types::OCI_EMPTY.to_owned(),
+                config,
+                types::EMPTY_CONFIG.len() as u64,
+            ),
+            "layers": [ContentDescriptor::new(media_type.to_owned(), layer, document.len() as u64)],
+            "subject": ContentDescriptor::new(
+                subject_manifest.media_type().to_owned(),
+                subject,
+                subject_raw.len() as u64,
+            ),
+        });
+        let artifact = serde_json::to_vec(&artifact).expect("serialization should not fail");
+        let artifact_digest = storage::Digest::from_contents(&artifact);
+
+        self.storage
+            .put_manifest(
+                &ManifestReference::new(location.clone(), Reference::new_digest(artifact_digest)),
+                &artifact,
+            )
+            .await?;
+
+        Ok(artifact_digest)
+    }
+
+    /// Generates and attaches an SBOM for a freshly pushed image using the configured generator.
+    async fn generate_sbom(&self, location: ImageLocation, digest: storage::Digest) {
+        let Some(ref generator) = self.sbom_generator else {
+            return;
+        };
+
+        let sbom = match generator.generate(&location, digest).await {
+            Ok(Some(sbom)) => sbom,
+            Ok(None) => return,
+            Err(err) => {
+                self.metrics.sbom_generation_failures.inc();
+                error!(%location, %digest, %err, "failed to generate SBOM");
+                return;
+            }
+        };
+
+        match self
+            .attach_sbom(&location, digest, &sbom.media_type, &sbom.document)
+            .await
+        {
+            Ok(_) => self.metrics.sboms_generated.inc(),
+            Err(err) => {
+                self.metrics.sbom_generation_failures.inc();
+                error!(%location, %digest, %err, "failed to attach generated SBOM");
+            }
+        }
+    }
+
+    /// Issues and attaches an attestation for a freshly pushed manifest using the configured
+    /// signer.
+    async fn attest(&self, manifest_reference: ManifestReference, digest: storage::Digest) {
+        let Some(ref signer) = self.attestation_signer else {
+            return;
+        };
+        let location = manifest_reference.location();
+
+        let attested = self
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let envelope = match attestation::issue(
+            signer.as_ref(),
+            location,
+            manifest_reference.reference(),
+            digest,
+            attested,
+        )
+        .await
+        {
+            Ok(envelope) => envelope,
+            Err(err) => {
+                self.metrics.attestation_failures.inc();
+                error!(%location, %digest, %err, "failed to sign attestation");
+                return;
+            }
+        };
+
+        match self
+            .attach_artifact(location, digest, attestation::DSSE_ENVELOPE, &envelope)
+            .await
+        {
+            Ok(attestation) => {
+                self.metrics.attestations_issued.inc();
+                info!(%location, %digest, %attestation, "attestation attached");
+            }
+            Err(err) => {
+                self.metrics.attestation_failures.inc();
+                error!(%location, %digest, %err, "failed to attach attestation");
+            }
+        }
+    }
+
+    /// Checks whether a manifest about to be served is trusted according to the configured
+    /// notation trust policy.
+    #[cfg(feature = "notation")]
+    async fn check_notation_trust(
+        &self,
+        location: &ImageLocation,
+        digest: storage::Digest,
+        manifest: &Manifest,
+    ) -> Result<(), RegistryError> {
+        let Some(ref verifier) = self.notation_verifier else {
+            return Ok(());
+        };
+        // Signatures and other artifacts are needed to verify the manifests they refer to.
+        if manifest.subject().is_some() || !verifier.applies_to(location) {
+            return Ok(());
+        }
+
+        let mut verdict = self.notation_verdict(verifier, location, digest).await?;
+
+        // Clients pull the image manifests of a multi-platform image by digest after its index.
+        if matches!(verdict, notation::Verdict::Untrusted { .. })
+            && matches!(manifest, Manifest::Image(_))
+        {
+            for candidate in self.storage.list_manifests(location).await? {
+                if candidate.summary.is_some() {
+                    continue;
+                }
+                let reference = ManifestReference::new(
+                    location.clone(),
+                    Reference::new_digest(candidate.digest),
+                );
+                let Some(raw) = self.storage.get_manifest(&reference).await? else {
+                    continue;
+                };
+                let Ok(Manifest::Index(index)) = Manifest::from_slice(&raw) else {
+                    continue;
+                };
+                if !index
+                    .manifests()
+                    .iter()
+                    .any(|child| child.parsed_digest().ok() == Some(digest))
+                {
+                    continue;
+                }
+
+                let index_verdict = self
+                    .notation_verdict(verifier, location, candidate.digest)
+                    .await?;
+                if matches!(index_verdict, notation::Verdict::Trusted { .. }) {
+                    verdict = index_verdict;
+                    break;
+                }
+            }
+        }
+
+        match verdict {
+            notation::Verdict::Unverified => Ok(()),
+            notation::Verdict::Trusted { policy, logged } => {
+                for failure in logged {
+                    warn!(%location, %digest, %policy, %failure, "notation check failed");
+                }
+                Ok(())
+            }
+            notation::Verdict::Untrusted {
+                policy,
+                enforced: false,
+                reason,
+            } => {
+                warn!(%location, %digest, %policy, %reason, "serving untrusted manifest");
+                Ok(())
+            }
+            notation::Verdict::Untrusted { reason, .. } => {
+                Err(RegistryError::PolicyViolation(format!(
+                    "manifest {} at {location} is not trusted: {reason}",
+                    ImageDigest::new(digest)
+                )))
+            }
+        }
+    }
+
+    /// Verifies the notation signatures referring to the manifest `digest` at `location`.
+    #[cfg(feature = "notation")]
+    async fn notation_verdict(
+        &self,
+        verifier: &notation::NotationVerifier,
+        location: &ImageLocation,
+        digest: storage::Digest,
+    ) -> Result<notation::Verdict, RegistryError> {
+        let mut envelopes = Vec::new();
+
+        for descriptor in self.collect_referrers(location, digest).await? {
+            if descriptor.artifact_type() != Some(notation::NOTATION_SIGNATURE) {
+                continue;
+            }
+            let Ok(signature_digest) = descriptor.parsed_digest() else {
+                continue;
+            };
+            let reference =
+                ManifestReference::new(location.clone(), Reference::new_digest(signature_digest));
+            let Some(raw) = self.storage.get_manifest(&reference).await? else {
+                continue;
+            };
+            let Ok(Manifest::Image(signature)) = Manifest::from_slice(&raw) else {
+                continue;
+            };
+
+            for layer in signature.layers() {
+                let Ok(layer_digest) = layer.parsed_digest() else {
+                    continue;
+                };
+                if layer.media_type() != notation::JWS_ENVELOPE {
+                    continue;
+                }
+                let Some(reader) = self.storage.get_blob_reader(layer_digest).await? else {
+                    continue;
+                };
+
+                let mut envelope = Vec::new();
+                reader
+                    .take(notation::MAX_ENVELOPE_SIZE)
+                    .read_to_end(&mut envelope)
+                    .await
+                    .map_err(|err| RegistryError::Storage(storage::Error::Io(err)))?;
+                envelopes.push(envelope);
+            }
+        }
+
+        Ok(verifier.verify(location, digest, &envelopes))
+    }
+
+    /// Resolves a manifest to the image manifest for a specific platform.
+    ///
+    /// If `manifest_reference` refers to an index (e.g. a multi-platform image), the index is
+    /// walked until the image manifest matching `os` and `architecture` is found. Plain image
+    /// manifests are returned as-is if their configuration matches the platform.
+    ///
+    /// Returns [`RegistryError::NotFound`] if no manifest for the platform exists.
+    pub async fn resolve_platform(
+        &self,
+        manifest_reference: &ManifestReference,
+        os: &str,
+        architecture: &str,
+    ) -> Result<PlatformManifest, RegistryError> {
+        let location = manifest_reference.location();
+        let mut reference = manifest_reference.reference().clone();
+
+        for _ in 0..MAX_INDEX_DEPTH {
+            let raw = self
+                .storage
+                .get_manifest(&ManifestReference::new(location.clone(), reference))
+                .await?
+                .ok_or(RegistryError::NotFound)?;
+
+            match Manifest::from_slice(&raw).map_err(RegistryError::ParseManifest)? {
+                Manifest::Index(index) => {
+                    let child = index
+                        .manifests()
+                        .iter()
+                        .find(|descriptor| {
+                            descriptor
+                                .platform()
+                                .is_some_and(|platform| platform.matches(os, architecture))
+                        })
+                        .ok_or(RegistryError::NotFound)?;
+
+                    reference = Reference::new_digest(
+                        child
+                            .parsed_digest()
+                            .map_err(RegistryError::InvalidDigest)?,
+                    );
+                }
+                Manifest::Image(image) => {
+                    let config_digest = image
+                        .config()
+                        .parsed_digest()
+                        .map_err(RegistryError::InvalidDigest)?;
+
+                    let mut config = Vec::new();
+                    self.storage
+                        .get_blob_reader(config_digest)
+                        .await?
+                        .ok_or(RegistryError::NotFound)?
+                        .read_to_end(&mut config)
+                        .await
+                        .map_err(|err| RegistryError::Storage(storage::Error::Io(err)))?;
+
+                    // Without an index, the platform is only recorded in the configuration.
+                    #[derive(Deserialize)]
+                    struct ConfigPlatform {
+                        os: String,
+                        architecture: String,
+                    }
+
+                    let platform: ConfigPlatform =
+                        serde_json::from_slice(&config).map_err(RegistryError::ParseManifest)?;
+                    if platform.os != os || platform.architecture != architecture {
+                        return Err(RegistryError::NotFound);
+                    }
+
+                    return Ok(PlatformManifest {
+                        digest: storage::Digest::from_contents(&raw),
+                        config_digest,
+                        config,
+                    });
+                }
+            }
+        }
+
+        Err(RegistryError::NotFound)
+    }
+
+    /// Collects all manifests at `location` referring to `subject`.
+    ///
+    /// Besides manifests indexed through their `subject` field, manifests stored using the
+    /// referrers tag schema (`sha256-<digest>`) as well as the schema used by `cosign`
+    /// (`sha256-<digest>.sig` and similar) are included, as older clients that do not use the
+    /// referrers API push their artifacts this way.
+    async fn collect_referrers(
+        &self,
+        location: &ImageLocation,
+        subject: storage::Digest,
+    ) -> Result<Vec<ContentDescriptor>, RegistryError> {
+        let mut referrers = self.storage.get_referrers(location, subject).await?;
+
+        let fallback_tag = format!("sha256-{}", subject);
+        let fallback_reference =
+            ManifestReference::new(location.clone(), Reference::new_tag(&fallback_tag));
+        if let Some(raw) = self.storage.get_manifest(&fallback_reference).await? {
+            if let Ok(Manifest::Index(index)) = Manifest::from_slice(&raw) {
+                referrers.extend(index.manifests().iter().cloned());
+            }
+        }
+
+        for suffix in COSIGN_TAG_SUFFIXES {
+            let cosign_reference = ManifestReference::new(
+                location.clone(),
+                Reference::new_tag(format!("{}.{}", fallback_tag, suffix)),
+            );
+            if let Some(raw) = self.storage.get_manifest(&cosign_reference).await? {
+                if let Ok(manifest) = Manifest::from_slice(&raw) {
+                    let digest = storage::Digest::from_contents(&raw);
+                    referrers.push(ContentDescriptor::for_manifest(
+                        &manifest,
+                        digest,
+                        raw.len() as u64,
+                    ));
+                }
+            }
+        }
+
+        // The same manifest may have been found through multiple schemas.
+        let mut seen = HashSet::new();
+        referrers.retain(|descriptor| seen.insert(descriptor.digest().to_owned()));
+
+        Ok(referrers)
+    }
+}
+
+/// Maximum number of nested indices followed when resolving a platform.
+const MAX_INDEX_DEPTH: usize = 4;
+
+/// A manifest resolved for a specific platform.
+///
+/// See [`ContainerRegistry::resolve_platform`] for details.
+#[derive(Debug)]
+pub struct PlatformManifest {
+    /// Digest of the platform-specific image manifest.
+    digest: storage::Digest,
+    /// Digest of the image configuration.
+    config_digest: storage::Digest,
+    /// The raw image configuration.
+    config: Vec<u8>,
+}
+
+impl PlatformManifest {
+    /// Returns the digest of the platform-specific image manifest.
+    #[inline(always)]
+    pub fn digest(&self) -> storage::Digest {
+        self.digest
+    }
+
+    /// Returns the digest of the image configuration.
+    #[inline(always)]
+    pub fn config_digest(&self) -> storage::Digest {
+        self.config_digest
+    }
+
+    /// Returns the raw image configuration, which is a JSON document.
+    #[inline(always)]
+    pub fn config(&self) -> &[u8] {
+        &self.config
+    }
+}
+
+/// Tag suffixes used by `cosign` for signatures, attestations and SBOMs.
+const COSIGN_TAG_SUFFIXES: [&str; 3] = ["sig", "att", "sbom"];
+
+/// Builder for a new instance of the container registry.
+///
+/// Requires a storage to be set, either by calling [`Self::storage`] or constructing using
+/// [`Self::build_for_testing()`], which requires the `test-support` feature and will use
+/// a temporary directory.
+///
+/// By default, no hooks are set up and the auth provider requires authentication, but does not
+/// grant access to anything.
+#[derive(Default)]
+pub struct ContainerRegistryBuilder {
+    /// Storage to use.
+    storage: Option<PathBuf>,
+    /// Hooks to use.
+    hooks: Option<Box<dyn RegistryHooks>>,
+    /// Auth provider to use.
+    auth_provider: Option<Arc<dyn AuthProvider>>,
+    /// Timeout for hook invocations.
+    hook_timeout: Option<Duration>,
+    /// Maximum number of concurrent upload sessions per user.
+    max_upload_sessions_per_user: Option<usize>,
+    /// Time of inactivity after which an upload session is considered abandoned.
+    upload_session_timeout: Option<Duration>,
+    /// Cold blob storage and the local capacity in bytes, if tiering is enabled.
+    cold_storage: Option<(Arc<dyn storage::ColdBlobStore>, u64)>,
+    /// Object store blobs are delegated to.
+    redirect_storage: Option<Arc<dyn storage::RedirectBlobStore>>,
+    /// Archive rarely pulled blobs are moved to.
+    archive_storage: Option<Arc<dyn archive::ArchiveStore>>,
+    /// Number of previous manifests recorded per tag.
+    tag_history: usize,
+    /// Additional volumes to store blobs on.
+    blob_volumes: Vec<PathBuf>,
+    /// Strategy for placing new blobs on volumes.
+    blob_placement: storage::BlobPlacement,
+    /// Whether to store blobs as deduplicated chunks.
+    chunked_blobs: bool,
+    /// Whether to mirror images into OCI image layouts.
+    oci_layout: bool,
+    /// Whether and how to check storage for consistency on startup.
+    consistency_check: Option<storage::ConsistencyCheck>,
+    /// Size of the blocks written to uploads.
+    upload_write_buffer: Option<usize>,
+    /// Inspector for layers of uploaded manifests.
+    #[cfg(feature = "inspection")]
+    layer_inspector: Option<inspection::LayerInspector>,
+    /// Generator for SBOMs of pushed images.
+    sbom_generator: Option<Arc<dyn sbom::SbomGenerator>>,
+    /// Downloader of blobs fetched from remote URLs.
+    blob_fetcher: Option<Arc<dyn fetch::BlobFetcher>>,
+    /// URLs blobs may be fetched from.
+    blob_fetch_policy: fetch::UrlPolicy,
+    /// Signer of attestations for accepted manifests.
+    attestation_signer: Option<Arc<dyn attestation::Signer>>,
+    /// Verifier of notation signatures on pull.
+    #[cfg(feature = "notation")]
+    notation_verifier: Option<notation::NotationVerifier>,
+    /// Maximum size of a decompressed upload chunk.
+    decompressed_body_limit: Option<u64>,
+    /// Maximum size of a single blob.
+    max_blob_size: Option<u64>,
+    /// Maximum size of a manifest.
+    max_manifest_size: Option<u64>,
+    /// Whether to accept manifests of unknown media types.
+    custom_manifest_types: bool,
+    /// Repositories and images manifests may only be pulled from by digest.
+    digest_pull_only: HashSet<String>,
+    /// Repositories and images new manifests are quarantined in.
+    quarantine_scopes: HashSet<String>,
+    /// Users allowed to pull and release quarantined manifests.
+    quarantine_reviewers: HashSet<String>,
+    /// Media and artifact types of manifests accepted by repositories and images.
+    allowed_media_types: HashMap<String, HashSet<String>>,
+    /// Maximum number of tags per image and what to do when it is reached.
+    tag_limit: Option<(usize, TagLimitPolicy)>,
+    /// Whether blobs are only served through repositories they belong to.
+    scope_blobs: bool,
+    /// Whether to ignore the quirks of known clients.
+    strict_spec_compliance: bool,
+    /// Whether to log tolerated deviations from the spec.
+    report_spec_deviations: bool,
+    /// Whether to convert manifests between Docker and OCI media types on request.
+    convert_media_types: bool,
+    /// Whether the administrative API is only served by the admin router.
+    separate_admin_routes: bool,
+    /// Whether to journal metadata updates.
+    write_ahead_log: bool,
+    /// Egress bandwidth limits for blob downloads.
+    bandwidth_limits: throttle::BandwidthLimits,
+    /// Issuer of pull tokens.
+    token_issuer: Option<tokens::TokenIssuer>,
+    /// CDN blob downloads are redirected to.
+    cdn: Option<cdn::Cdn>,
+    /// Directory of peers blob downloads are redirected to.
+    peer_directory: Option<Arc<dyn peers::PeerDirectory>>,
+    /// Webhooks events are delivered to.
+    webhooks: Option<webhooks::Webhooks>,
+    /// Decorator of error responses.
+    error_decorator: Option<Arc<dyn errors::ErrorDecorator>>,
+    /// Policies adding tags to pushed manifests.
+    auto_tag_policies: Vec<Arc<dyn autotag::AutoTagPolicy>>,
+    /// Which requests to report to [`RegistryHooks::on_access`].
+    access_sampling: Option<access::AccessSampling>,
+    /// Policy for locking out users and addresses failing to authenticate.
+    auth_lockout: Option<lockout::LockoutPolicy>,
+    /// Source of the current time.
+    clock: Option<Arc<dyn clock::Clock>>,
+    /// Whether pushing to a repository that does not exist creates it.
+    repository_creation: repositories::RepositoryCreation,
+    /// URLs the registry is reachable under.
+    public_urls: urls::PublicUrls,
+    /// Reverse proxies trusted to report client addresses.
+    trusted_proxies: Vec<IpAddr>,
+    /// Realm name for HTTP auth.
+    realm: Option<String>,
+    /// URL of the token service advertised in a `Bearer` challenge.
+    bearer_realm: Option<String>,
+    /// Key provider for encrypting blobs at rest.
+    #[cfg(feature = "encryption")]
+    blob_encryption: Option<Arc<dyn storage::encryption::KeyProvider>>,
+    /// Faults to inject into storage.
+    #[cfg(any(feature = "test-support", test))]
+    storage_faults: Option<storage::test_util::Faults>,
+    /// Whether to keep blobs, uploads and manifests in memory.
+    #[cfg(any(feature = "test-support", test))]
+    memory_storage: bool,
+}
+
+/// A manifest accepted by [`ContainerRegistry::accept_manifest`].
+struct AcceptedManifest {
+    /// Reference the manifest was stored under, its digest if quarantined.
+    reference: ManifestReference,
+    /// Digest of the manifest.
+    digest: storage::Digest,
+    /// The parsed manifest.
+    manifest: Manifest,
+}
+
+/// What to do when an image has reached the tag limit, see
+/// [`ContainerRegistryBuilder::max_tags_per_image`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TagLimitPolicy {
+    /// Refuse new tags.
+    Reject,
+    /// Remove the least recently updated tags to make room. The manifests they pointed to remain
+    /// available by digest.
+    EvictOldest,
+}
+
+/// Default timeout for a single hook invocation.
+const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default time of inactivity after which an upload session is considered abandoned.
+const DEFAULT_UPLOAD_SESSION_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Default maximum size of a decompressed upload chunk.
+const DEFAULT_DECOMPRESSED_BODY_LIMIT: u64 = 1024 * 1024 * 1024; // 1 GiB
+
+/// Default size of the blocks written to uploads.
+const DEFAULT_UPLOAD_WRITE_BUFFER: usize = 1024 * 1024; // 1 MiB
+
+/// Default maximum size of a manifest.
+const DEFAULT_MAX_MANIFEST_SIZE: u64 = 4 * 1024 * 1024; // 4 MiB
+
+/// Minimum number of hex digits of an abbreviated digest.
+const MIN_DIGEST_PREFIX_LEN: usize = 4;
+
+impl ContainerRegistryBuilder {
+    /// Sets the auth provider for the new registry.
+    ///
+    /// It can be replaced while the registry is running using
+    /// [`ContainerRegistry::set_auth_provider`].
+    pub fn auth_provider(mut self, auth_provider: Arc<dyn AuthProvider>) -> Self {
+        self.auth_provider = Some(auth_provider);
+        self
+    }
+
+    /// Sets hooks for the new registry to call.
+    pub fn hooks(mut self, hooks: Box<dyn RegistryHooks>) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Sets the maximum time a single hook invocation may take.
+    ///
+    /// Hook invocations taking longer are aborted, to prevent slow hooks from stalling requests
+    /// indefinitely. Defaults to 30 seconds.
+    pub fn hook_timeout(mut self, hook_timeout: Duration) -> Self {
+        self.hook_timeout = Some(hook_timeout);
+        self
+    }
+
+    /// Limits the number of concurrent upload sessions per user.
+    ///
+    /// Attempts to start additional uploads are rejected with `429 Too Many Requests`. Anonymous
+    /// users and users whose credentials carry no username share a single limit. By default, the
+    /// number of upload sessions is not limited.
+    pub fn max_upload_sessions_per_user(mut self, max: usize) -> Self {
+        self.max_upload_sessions_per_user = Some(max);
+        self
+    }
+
+    /// Sets the time of inactivity after which an upload session is considered abandoned.
+    ///
+    /// Abandoned sessions no longer count towards the limit set by
+    /// [`Self::max_upload_sessions_per_user`], and the data uploaded through them is discarded the
+    /// next time an upload is started. Defaults to one hour.
+    pub fn upload_session_timeout(mut self, timeout: Duration) -> Self {
+        self.upload_session_timeout = Some(timeout);
+        self
+    }
+
+    /// Enables tiered blob storage.
+    ///
+    /// All blobs are written through to `cold`, while at most `hot_capacity` bytes of recently
+    /// used blobs are kept in the local storage path set through [`Self::storage`]. Blobs missing
+    /// locally are fetched from `cold` on access, manifests and tags are always kept locally.
+    pub fn cold_storage(
+        mut self,
+        cold: Arc<dyn storage::ColdBlobStore>,
+        hot_capacity: u64,
+    ) -> Self {
+        self.cold_storage = Some((cold, hot_capacity));
+        self
+    }
+
+    /// Delegates blob storage to an object store, redirecting blob downloads to it.
+    ///
+    /// Finalized uploads are moved to `remote`, and authorized blob downloads are answered with a
+    /// redirect to the URL it returns, so blob contents no longer pass through the registry when
+    /// pulling. Manifests and tags are kept locally. Cannot be combined with
+    /// [`Self::cold_storage`] or [`Self::chunked_blobs`]. See the [`storage`] module for details.
+    pub fn redirect_storage(mut self, remote: Arc<dyn storage::RedirectBlobStore>) -> Self {
+        self.redirect_storage = Some(remote);
+        self
+    }
+
+    /// Sets an archive to move the blobs of rarely pulled images to.
+    ///
+    /// Blobs are archived by the [`maintenance::Archival`] task and restored when pulled, with
+    /// clients asked to retry while the archive makes them readable. Pulls of manifests are
+    /// recorded to tell rarely pulled images apart. See the [`archive`] module for details.
+    pub fn archive_storage(mut self, archive: Arc<dyn archive::ArchiveStore>) -> Self {
+        self.archive_storage = Some(archive);
+        self
+    }
+
+    /// Sets the number of previous manifests remembered per tag.
+    ///
+    /// Tags can be rolled back to any of them, see the [`tag_history`] module for details. By
+    /// default, or when set to `0`, no history is recorded.
+    pub fn tag_history(mut self, depth: usize) -> Self {
+        self.tag_history = depth;
+        self
+    }
+
+    /// Adds a volume to store blobs on, in addition to the storage path.
+    ///
+    /// Can be called multiple times, e.g. once for every disk. Blobs already stored stay where
+    /// they are, new blobs are placed according to [`Self::blob_placement`]. Manifests and tags
+    /// are always kept in the storage path. See the [`storage`] module for details.
+    ///
+    /// Snapshots through [`ContainerRegistry::snapshot`] are not supported with additional volumes.
+    pub fn blob_volume<P>(mut self, root: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.blob_volumes.push(root.into());
+        self
+    }
+
+    /// Sets the strategy for placing new blobs on volumes added through [`Self::blob_volume`].
+    ///
+    /// Defaults to [`BlobPlacement::RoundRobin`](storage::BlobPlacement::RoundRobin).
+    pub fn blob_placement(mut self, placement: storage::BlobPlacement) -> Self {
+        self.blob_placement = placement;
+        self
+    }
+
+    /// Stores blobs split into content-defined chunks, each stored only once across all blobs.
+    ///
+    /// **Experimental.** Saves space when many blobs share most of their contents, e.g.
+    /// uncompressed layers of frequently rebuilt images, at the cost of slower uploads and reads.
+    /// The on-disk format may change, and blobs stored while enabled are unavailable once it is
+    /// disabled again. Cannot be combined with [`Self::cold_storage`] or blob encryption. See the
+    /// [`storage`] module for details. Disabled by default.
+    pub fn chunked_blobs(mut self, enabled: bool) -> Self {
+        self.chunked_blobs = enabled;
+        self
+    }
+
+    /// Mirrors every image into an OCI image layout inside the storage directory.
+    ///
+    /// The layouts can be consumed by OCI tooling like `skopeo` or `umoci` directly, e.g. to
+    /// recover images without a running registry. Blobs are copied into the layouts, doubling the
+    /// disk space needed. Images stored before enabling this are only mirrored once they change or
+    /// [`ContainerRegistry::sync_oci_layouts`] is called. The layout of `<repository>/<image>` is
+    /// kept in `oci/<repository>/<image>` inside the storage directory. Disabled by default.
+    pub fn oci_layout(mut self, enabled: bool) -> Self {
+        self.oci_layout = enabled;
+        self
+    }
+
+    /// Checks storage for consistency when building the registry.
+    ///
+    /// Finds leftovers of interrupted writes and uploads, as well as tags pointing at missing
+    /// manifests, which would otherwise only surface as server errors once clients run into them.
+    /// Problems are either repaired or make [`Self::build`] fail with
+    /// [`FilesystemStorageError::Inconsistent`], whose report details every problem found. See
+    /// [`storage::Inconsistency`] for what is checked. Disabled by default.
+    pub fn consistency_check(mut self, check: storage::ConsistencyCheck) -> Self {
+        self.consistency_check = Some(check);
+        self
+    }
+
+    /// Sets the size of the buffer batching writes to uploads.
+    ///
+    /// Incoming data is collected and written to disk in blocks of this size, rather than in the
+    /// small pieces it arrives in, reducing syscall overhead and fragmentation for large pushes.
+    /// The buffer is written out at the end of every chunk. Defaults to 1 MiB, zero disables
+    /// batching.
+    pub fn upload_write_buffer(mut self, size: usize) -> Self {
+        self.upload_write_buffer = Some(size);
+        self
+    }
+
+    /// Enables inspection of the layers of every uploaded image manifest.
+    ///
+    /// See the [`inspection`] module for details.
+    #[cfg(feature = "inspection")]
+    pub fn layer_inspector(mut self, inspector: inspection::LayerInspector) -> Self {
+        self.layer_inspector = Some(inspector);
+        self
+    }
+
+    /// Verifies notation signatures of manifests before serving them.
+    ///
+    /// Manifests not trusted according to the verifier's trust policy are refused with
+    /// `403 Forbidden`, see the [`notation`] module for details.
+    #[cfg(feature = "notation")]
+    pub fn notation_verifier(mut self, verifier: notation::NotationVerifier) -> Self {
+        self.notation_verifier = Some(verifier);
+        self
+    }
+
+    /// Sets the maximum size of a compressed upload chunk after decompression.
+    ///
+    /// Chunks sent with a `Content-Encoding` of `gzip` or `deflate` are decompressed before being
+    /// stored; larger ones are refused with `413 Payload Too Large`. Defaults to 1 GiB.
+    /// Manifests are limited by [`Self::max_manifest_size`] instead.
+    pub fn decompressed_body_limit(mut self, limit: u64) -> Self {
+        self.decompressed_body_limit = Some(limit);
+        self
+    }
+
+    /// Limits the size of a single blob.
+    ///
+    /// Upload requests announcing a larger `Content-Length` or `Content-Range` are refused with
+    /// `413 Payload Too Large` before their body is read, as are chunks exceeding the limit while
+    /// being streamed. By default, the size of blobs is not limited.
+    pub fn max_blob_size(mut self, max: u64) -> Self {
+        self.max_blob_size = Some(max);
+        self
+    }
+
+    /// Limits the size of a single manifest.
+    ///
+    /// Larger manifests are refused with `413 Payload Too Large`; compressed manifests are limited
+    /// by their decompressed size. Defaults to 4 MiB, the limit suggested by the OCI distribution
+    /// spec.
+    pub fn max_manifest_size(mut self, max: u64) -> Self {
+        self.max_manifest_size = Some(max);
+        self
+    }
+
+    /// Accepts manifests of any media type.
+    ///
+    /// By default, only OCI and Docker image manifests and indices are accepted, anything else is
+    /// refused with `415 Unsupported Media Type`. Enabling this allows storing artifacts using
+    /// their own manifest media type; they still have to be structured like an image manifest or
+    /// index.
+    pub fn custom_manifest_types(mut self, enabled: bool) -> Self {
+        self.custom_manifest_types = enabled;
+        self
+    }
+
+    /// Only allows pulling manifests by digest from `scope`, either a repository (e.g. `prod`) or a
+    /// single image (e.g. `prod/api`).
+    ///
+    /// Fetching a manifest by tag from it is refused with `403 Forbidden` and an explanatory error,
+    /// forcing clients to pin the exact manifest they deploy. Tags can still be resolved to digests
+    /// using `HEAD` requests, and pushed as usual. May be called multiple times.
+    pub fn require_digest_pulls<S: Into<String>>(mut self, scope: S) -> Self {
+        self.digest_pull_only
+            .insert(scope.into().trim_matches('/').to_owned());
+        self
+    }
+
+    /// Quarantines new manifests pushed to `scope`, either a repository (e.g. `prod`) or a single
+    /// image (e.g. `prod/api`).
+    ///
+    /// Quarantined manifests can only be pulled by reviewers, see [`Self::quarantine_reviewer`],
+    /// until they are released. See the [`quarantine`] module for details. May be called multiple
+    /// times.
+    pub fn quarantine<S: Into<String>>(mut self, scope: S) -> Self {
+        self.quarantine_scopes
+            .insert(scope.into().trim_matches('/').to_owned());
+        self
+    }
+
+    /// Allows the user `username`, e.g. a vulnerability scanner, to pull and release quarantined
+    /// manifests.
+    ///
+    /// May be called multiple times.
+    pub fn quarantine_reviewer<S: Into<String>>(mut self, username: S) -> Self {
+        self.quarantine_reviewers.insert(username.into());
+        self
+    }
+
+    /// Restricts the manifests accepted by `scope`, either a repository (e.g. `charts`) or a single
+    /// image (e.g. `charts/nginx`), to the given media types.
+    ///
+    /// A manifest is accepted if either its own media type or its artifact type is allowed. The
+    /// artifact type of image manifests defaults to their config media type, so e.g. only Helm
+    /// charts are accepted when allowing `application/vnd.cncf.helm.config.v1+json`. Other
+    /// manifests are refused with `415 Unsupported Media Type`. Rules for an image replace those of
+    /// its repository; calling this multiple times for the same scope allows additional types.
+    pub fn allow_media_types<S, I, T>(mut self, scope: S, media_types: I) -> Self
+    where
+        S: Into<String>,
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.allowed_media_types
+            .entry(scope.into().trim_matches('/').to_owned())
+            .or_default()
+            .extend(media_types.into_iter().map(Into::into));
+        self
+    }
+
+    /// Only serves blobs through repositories they belong to, i.e. were uploaded to.
+    ///
+    /// By default, any blob can be fetched through any repository the user can read blobs from,
+    /// leaking the contents of private repositories to anyone knowing a digest. With scoping
+    /// enabled, blob requests also require read access to the repository in their path, and blobs
+    /// not belonging to it are reported as missing. Manifests referencing blobs not belonging to
+    /// their repository are refused.
+    pub fn scope_blobs(mut self, enabled: bool) -> Self {
+        self.scope_blobs = enabled;
+        self
+    }
+
+    /// Ignores the quirks of known clients, treating every client according to the spec.
+    ///
+    /// By default, responses are adjusted to what clients such as docker or ORAS expect where the
+    /// spec leaves room for interpretation, see the [`compat`] module.
+    pub fn strict_spec_compliance(mut self, enabled: bool) -> Self {
+        self.strict_spec_compliance = enabled;
+        self
+    }
+
+    /// Converts manifests pulled by tag between Docker and OCI media types, following the
+    /// client's `Accept` header.
+    ///
+    /// Disabled by default, manifests are always served as pushed. See the [`conversion`] module.
+    pub fn convert_media_types(mut self, enabled: bool) -> Self {
+        self.convert_media_types = enabled;
+        self
+    }
+
+    /// Logs a warning whenever a request deviating from the spec is tolerated.
+    ///
+    /// Meant as a debugging aid when working towards conformance of a client or the registry,
+    /// leniency towards clients stays the same. Disabled by default, see the [`compat`] module.
+    pub fn report_spec_deviations(mut self, enabled: bool) -> Self {
+        self.report_spec_deviations = enabled;
+        self
+    }
+
+    /// Serves the administrative API only through [`ContainerRegistry::make_admin_router`].
+    ///
+    /// By default, the administrative API is also part of [`ContainerRegistry::make_router`].
+    /// Enable this when serving the admin router on a separate, internal-only listener, so the
+    /// administrative API is not reachable through the public one.
+    pub fn separate_admin_routes(mut self, enabled: bool) -> Self {
+        self.separate_admin_routes = enabled;
+        self
+    }
+
+    /// Limits the number of tags per image, e.g. to keep CI pipelines pushing a unique tag per
+    /// build from slowing down tag listings.
+    ///
+    /// Once an image has `max` tags, adding another one is either refused with `403 Forbidden` or
+    /// removes the least recently updated tags, depending on `policy`. Overwriting an existing tag
+    /// is always possible. Concurrent pushes may briefly exceed the limit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero.
+    pub fn max_tags_per_image(mut self, max: usize, policy: TagLimitPolicy) -> Self {
+        assert!(max > 0, "tag limit must not be zero");
+        self.tag_limit = Some((max, policy));
+        self
+    }
+
+    /// Enables a write-ahead log for manifest and tag updates.
+    ///
+    /// Every update is recorded durably before it is carried out, so that updates interrupted by
+    /// a crash are completed or discarded when the registry is started again, never leaving a tag
+    /// pointing at a partially written manifest or a manifest missing from the indices. This costs
+    /// two additional `fsync` calls per update. Disabled by default.
+    pub fn write_ahead_log(mut self, enabled: bool) -> Self {
+        self.write_ahead_log = enabled;
+        self
+    }
+
+    /// Encrypts blobs at rest, using data keys protected by `provider`.
+    ///
+    /// Only blobs stored from now on are encrypted, existing ones remain readable. See the
+    /// [`storage::encryption`] module for details.
+    #[cfg(feature = "encryption")]
+    pub fn blob_encryption(mut self, provider: Arc<dyn storage::encryption::KeyProvider>) -> Self {
+        self.blob_encryption = Some(provider);
+        self
+    }
+
+    /// Adds a bandwidth class, limiting the combined egress bandwidth of its blob downloads.
+    ///
+    /// Can be called multiple times, downloads are throttled by the first class they match. See
+    /// the [`throttle`] module for details.
+    pub fn bandwidth_class(mut self, class: throttle::BandwidthClass) -> Self {
+        self.bandwidth_limits.add(class);
+        self
+    }
+
+    /// Enables read-only pull tokens, issued and verified by `issuer`.
+    ///
+    /// Tokens are accepted as passwords in addition to all credentials accepted by the auth
+    /// provider. See the [`tokens`] module for details.
+    pub fn token_issuer(mut self, issuer: tokens::TokenIssuer) -> Self {
+        self.token_issuer = Some(issuer);
+        self
+    }
+
+    /// Redirects blob downloads to a CDN, using signed URLs.
+    ///
+    /// The CDN has to forward cache misses to the registry, which serves requests with a valid
+    /// signature without credentials. See the [`cdn`] module for details.
+    pub fn cdn(mut self, cdn: cdn::Cdn) -> Self {
+        self.cdn = Some(cdn);
+        self
+    }
+
+    /// Redirects blob downloads to peers advertising the blob, for P2P distribution.
+    ///
+    /// Takes precedence over a [`cdn`](Self::cdn), blobs no peer holds are served as usual. See the
+    /// [`peers`] module for details.
+    pub fn peer_directory(mut self, directory: Arc<dyn peers::PeerDirectory>) -> Self {
+        self.peer_directory = Some(directory);
+        self
+    }
+
+    /// Delivers all events to webhook endpoints, signed with a secret per endpoint.
+    ///
+    /// See the [`webhooks`] module for details.
+    pub fn webhooks(mut self, webhooks: webhooks::Webhooks) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    /// Sets a decorator to customize error responses, e.g. adding a support contact.
+    ///
+    /// Error codes and statuses are preserved, see the [`errors`] module for details.
+    pub fn error_decorator(mut self, decorator: Arc<dyn errors::ErrorDecorator>) -> Self {
+        self.error_decorator = Some(decorator);
+        self
+    }
+
+    /// Adds a policy tagging pushed manifests, e.g. [`autotag::LatestSemver`].
+    ///
+    /// May be called repeatedly, the tags of all policies are applied. See the [`autotag`] module
+    /// for details.
+    pub fn auto_tag(mut self, policy: Arc<dyn autotag::AutoTagPolicy>) -> Self {
+        self.auto_tag_policies.push(policy);
+        self
+    }
+
+    /// Reports requests to repositories to [`RegistryHooks::on_access`], sampled as given.
+    ///
+    /// Records carry the image, user, bytes transferred, latency and status of each request, see
+    /// the [`access`] module for details. Disabled by default.
+    pub fn access_records(mut self, sampling: access::AccessSampling) -> Self {
+        self.access_sampling = Some(sampling);
+        self
+    }
+
+    /// Temporarily locks out users and client addresses after repeated failed authentication
+    /// attempts.
+    ///
+    /// Locked out clients are answered with `429 Too Many Requests`. By default, failures are only
+    /// counted in the metrics and reported to hooks. See the [`lockout`] module for details.
+    pub fn auth_lockout(mut self, policy: lockout::LockoutPolicy) -> Self {
+        self.auth_lockout = Some(policy);
+        self
+    }
+
+    /// Sets the clock driving time-based behavior, e.g. the expiry of upload sessions and tokens.
+    ///
+    /// Meant for tests, which can simulate time passing using a [`clock::ManualClock`]. See the
+    /// [`clock`] module for what is affected. Defaults to the [`clock::SystemClock`].
+    pub fn clock(mut self, clock: Arc<dyn clock::Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Sets whether pushing to a repository that does not exist creates it.
+    ///
+    /// With [`RepositoryCreation::Explicit`](repositories::RepositoryCreation::Explicit),
+    /// repositories have to be created through [`ContainerRegistry::create_repository`] or the
+    /// administrative API before anything can be pushed to them. See the [`repositories`] module
+    /// for details. Defaults to creating repositories implicitly.
+    pub fn repository_creation(mut self, creation: repositories::RepositoryCreation) -> Self {
+        self.repository_creation = creation;
+        self
+    }
+
+    /// Mounts the registry's routes below `path`, e.g. `/registry` to serve `/registry/v2/`.
+    ///
+    /// `Location` headers in responses include the base path. Setting an empty path or `/` mounts
+    /// the registry at the root, which is the default.
+    pub fn base_path<S: Into<String>>(mut self, path: S) -> Self {
+        let path = path.into();
+        let path = path.trim_matches('/');
+        self.public_urls.base_path = (!path.is_empty()).then(|| format!("/{path}"));
+        self
+    }
+
+    /// Sets the URL clients reach the registry's routes under, e.g. `https://registry.example.com`
+    /// or `https://example.com/registry`.
+    ///
+    /// All `Location` headers in responses are made absolute using this URL, which must include
+    /// the base path if it is exposed publicly. Takes precedence over forwarding headers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `url` is not an absolute `http` or `https` URL.
+    pub fn external_url<S: Into<String>>(mut self, url: S) -> Self {
+        let url = url.into();
+        let uri: axum::http::Uri = url.parse().expect("invalid external URL");
+        assert!(
+            matches!(uri.scheme_str(), Some("http" | "https")) && uri.authority().is_some(),
+            "external URL must be an absolute http or https URL"
+        );
+        self.public_urls.external_url = Some(url.trim_end_matches('/').to_owned());
+        self
+    }
+
+    /// Sets the realm name presented to clients for HTTP authentication.
+    ///
+    /// Defaults to `ContainerRegistry`. Registries served from the same process, see the [`hosts`]
+    /// module, should use distinct realms.
+    pub fn realm<S: Into<String>>(mut self, realm: S) -> Self {
+        self.realm = Some(realm.into());
+        self
+    }
+
+    /// Advertises a `Bearer` challenge in addition to `Basic`, pointing clients at the token
+    /// service at `url`.
+    ///
+    /// Bearer tokens, e.g. pull tokens (see [`Self::token_issuer`]), are always accepted alongside
+    /// passwords, this only affects which schemes are advertised to clients. Useful while
+    /// migrating from passwords to tokens issued by an external service. The challenge is only
+    /// sent while the auth provider verifies these tokens, see
+    /// [`AuthProvider::verifies_external_tokens`].
+    pub fn bearer_realm<S: Into<String>>(mut self, url: S) -> Self {
+        self.bearer_realm = Some(url.into());
+        self
+    }
+
+    /// Trusts `Forwarded` and `X-Forwarded-*` headers set by a reverse proxy.
+    ///
+    /// If enabled, `Location` headers in responses point to the origin and path prefix reported by
+    /// the proxy. Only enable this if the registry is reachable exclusively through a proxy that
+    /// sets or strips these headers, as clients could otherwise choose the locations returned to
+    /// them. Disabled by default.
+    pub fn trust_forwarded_headers(mut self, trust: bool) -> Self {
+        self.public_urls.trust_forwarded = trust;
+        self
+    }
+
+    /// Sets the reverse proxies trusted to report client addresses in `X-Forwarded-For`.
+    ///
+    /// Requests from other addresses are attributed to the address they were received from, which
+    /// requires the server to be set up with
+    /// [`into_make_service_with_connect_info`](axum::Router::into_make_service_with_connect_info).
+    /// Client addresses are used by [`Self::auth_lockout`] and [`Self::peer_directory`], see the
+    /// [`lockout`] module for details. Independent of [`Self::trust_forwarded_headers`]. Empty by
+    /// default.
+    pub fn trusted_proxies<I: IntoIterator<Item = IpAddr>>(mut self, proxies: I) -> Self {
+        self.trusted_proxies = proxies.into_iter().collect();
+        self
+    }
+
+    /// Sets a generator to automatically create SBOMs for every pushed image.
+    ///
+    /// Generators run in the background once an image manifest has been stored, see the [`sbom`]
+    /// module for details.
+    pub fn sbom_generator(mut self, generator: Arc<dyn sbom::SbomGenerator>) -> Self {
+        self.sbom_generator = Some(generator);
+        self
+    }
+
+    /// Sets a downloader for blobs imported from remote URLs.
+    ///
+    /// Required by [`ContainerRegistry::fetch_blob_from_url`], see the [`fetch`] module for
+    /// details.
+    pub fn blob_fetcher(mut self, fetcher: Arc<dyn fetch::BlobFetcher>) -> Self {
+        self.blob_fetcher = Some(fetcher);
+        self
+    }
+
+    /// Restricts the URLs blobs may be fetched from.
+    ///
+    /// Defaults to `https` URLs of hosts not resolving to internal addresses, see
+    /// [`fetch::UrlPolicy`].
+    pub fn blob_fetch_policy(mut self, policy: fetch::UrlPolicy) -> Self {
+        self.blob_fetch_policy = policy;
+        self
+    }
+
+    /// Sets a signer to attest every accepted manifest.
+    ///
+    /// Attestations are issued in the background once a manifest has been stored, see the
+    /// [`attestation`] module for details.
+    pub fn attestation_signer(mut self, signer: Arc<dyn attestation::Signer>) -> Self {
+        self.attestation_signer = Some(signer);
+        self
+    }
+
+    /// Set the storage path for the new registry.
+    pub fn storage<P>(mut self, storage: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.storage = Some(storage.into());
+        self
+    }
+
+    /// Constructs a new registry.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if not storage has been set through [`Self::storage`], or if
+    /// [`Self::chunked_blobs`] is combined with cold storage or blob encryption.
+    pub fn build(mut self) -> Result<Arc<ContainerRegistry>, FilesystemStorageError> {
+        let storage_path = self
+            .storage
+            .expect("attempted to construct registry with no storage path");
+        let mut local = FilesystemStorage::new(&storage_path)?.with_upload_block_size(
+            self.upload_write_buffer
+                .unwrap_or(DEFAULT_UPLOAD_WRITE_BUFFER),
+        );
+        if !self.blob_volumes.is_empty() {
+            local = local.with_volumes(&self.blob_volumes, self.blob_placement)?;
+        }
+        if self.write_ahead_log {
+            local = local.with_journal()?;
+        }
+        if let Some(check) = self.consistency_check {
+            let report = local.check_consistency(check)?;
+            if !report.is_consistent() {
+                info!(problems = report.problems().len(), "repaired storage");
+            }
+        }
+        #[cfg(feature = "encryption")]
+        if let Some(provider) = self.blob_encryption.take() {
+            assert!(
+                !self.chunked_blobs,
+                "chunked blobs cannot be combined with blob encryption"
+            );
+            local = local.with_encryption(provider);
+        }
+        let local_storage = local.clone();
+        let metrics = metrics::Metrics::default();
+        let instrumented =
+            |local| storage::InstrumentedStorage::new(local, "filesystem", metrics.storage.clone());
+        #[cfg(any(feature = "test-support", test))]
+        assert!(
+            !self.memory_storage
+                || (self.cold_storage.is_none()
+                    && self.redirect_storage.is_none()
+                    && !self.chunked_blobs),
+            "memory storage cannot be combined with cold, redirect or chunked blob storage"
+        );
+        let storage: Box<dyn RegistryStorage> =
+            match (self.cold_storage.take(), self.redirect_storage.take()) {
+                (Some(_), Some(_)) => {
+                    panic!("redirect storage cannot be combined with cold storage")
+                }
+                (Some(_), None) if self.chunked_blobs => {
+                    panic!("chunked blobs cannot be combined with cold storage")
+                }
+                (None, Some(_)) if self.chunked_blobs => {
+                    panic!("chunked blobs cannot be combined with redirect storage")
+                }
+                (None, None) if self.chunked_blobs => Box::new(storage::ComposedStorage::new(
+                    storage::InstrumentedStorage::new(
+                        storage::ChunkedStorage::new(local.clone())?,
+                        "chunked",
+                        metrics.storage.clone(),
+                    ),
+                    instrumented(local),
+                )),
+                (Some((cold, hot_capacity)), None) => Box::new(storage::ComposedStorage::new(
+                    storage::InstrumentedStorage::new(
+                        storage::TieredStorage::new(local.clone(), cold, hot_capacity)?,
+                        "tiered",
+                        metrics.storage.clone(),
+                    ),
+                    instrumented(local),
+                )),
+                (None, Some(remote)) => Box::new(storage::ComposedStorage::new(
+                    storage::InstrumentedStorage::new(
+                        storage::RedirectStorage::new(local.clone(), remote),
+                        "redirect",
+                        metrics.storage.clone(),
+                    ),
+                    instrumented(local),
+                )),
+                (None, None) => Box::new(instrumented(local)),
+            };
+        #[cfg(any(feature = "test-support", test))]
+        let storage: Box<dyn RegistryStorage> = if self.memory_storage {
+            Box::new(storage::InstrumentedStorage::new(
+                storage::MemoryStorage::default(),
+                "memory",
+                metrics.storage.clone(),
+            ))
+        } else {
+            storage
+        };
+        #[cfg(any(feature = "test-support", test))]
+        let storage: Box<dyn RegistryStorage> = match self.storage_faults.take() {
+            Some(faults) => Box::new(storage::test_util::FlakyStorage::new(storage, faults)),
+            None => storage,
+        };
+        let oci_layout = self
+            .oci_layout
+            .then(|| storage::oci_layout::OciLayout::open(&storage_path).map(Arc::new))
+            .transpose()?;
+        let storage: Box<dyn RegistryStorage> = match oci_layout {
+            Some(ref layout) => Box::new(storage::oci_layout::OciLayoutStorage::new(
+                storage,
+                layout.clone(),
+            )),
+            None => storage,
+        };
+        let quarantine = (!self.quarantine_scopes.is_empty())
+            .then(|| {
+                quarantine::Quarantine::open(
+                    &storage_path,
+                    self.quarantine_scopes,
+                    self.quarantine_reviewers,
+                )
+            })
+            .transpose()?;
+        let repositories =
+            repositories::Repositories::open(&storage_path, self.repository_creation)?;
+        let purges = purge::Purges::open(&storage_path)?;
+        let dangling = maintenance::DanglingManifests::open(&storage_path)?;
+        let archive = self
+            .archive_storage
+            .take()
+            .map(|store| archive::Archive::open(&storage_path, store))
+            .transpose()?;
+        let tag_histories = (self.tag_history > 0)
+            .then(|| tag_history::TagHistories::open(&storage_path, self.tag_history))
+            .transpose()?;
+        let catalog = Arc::new(storage::catalog::Catalog::default());
+        let storage = Box::new(storage::catalog::CatalogStorage::new(
+            storage,
+            catalog.clone(),
+        ));
+        let mut auth_provider = self
+            .auth_provider
+            .take()
+            .unwrap_or_else(|| Arc::new(Permissions::NoAccess));
+        let clock = self
+            .clock
+            .take()
+            .unwrap_or_else(|| Arc::new(clock::SystemClock));
+        let token_issuer = self
+            .token_issuer
+            .take()
+            .map(|issuer| Arc::new(issuer.with_clock(clock.clone())));
+        if let Some(ref issuer) = token_issuer {
+            auth_provider = Arc::new(tokens::TokenAuth::new(issuer.clone(), auth_provider));
+        }
+        let webhooks = self
+            .webhooks
+            .take()
+            .map(|webhooks| Arc::new(webhooks.with_clock(clock.clone())));
+        let hooks = self.hooks.take().unwrap_or_else(|| Box::new(()));
+        Ok(Arc::new(ContainerRegistry {
+            realm: self
+                .realm
+                .take()
+                .unwrap_or_else(|| "ContainerRegistry".to_owned()),
+            bearer_realm: self.bearer_realm,
+            auth_provider: ArcSwap::from_pointee(auth_provider),
+            storage,
+            local_storage,
+            catalog,
+            oci_layout,
+            hooks,
+            hook_timeout: self.hook_timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT),
+            upload_sessions: uploads::UploadSessions::new(
+                self.max_upload_sessions_per_user,
+                self.upload_session_timeout
+                    .unwrap_or(DEFAULT_UPLOAD_SESSION_TIMEOUT),
+                clock.clone(),
+            ),
+            auth_failures: lockout::AuthFailures::new(self.auth_lockout, clock.clone()),
+            clock,
+            metrics,
+            #[cfg(feature = "inspection")]
+            layer_inspector: self.layer_inspector.take().map(Arc::new),
+            sbom_generator: self.sbom_generator.take(),
+            blob_fetcher: self.blob_fetcher.take(),
+            blob_fetch_policy: self.blob_fetch_policy,
+            attestation_signer: self.attestation_signer.take(),
+            #[cfg(feature = "notation")]
+            notation_verifier: self.notation_verifier.take(),
+            decompressed_body_limit: self
+                .decompressed_body_limit
+                .unwrap_or(DEFAULT_DECOMPRESSED_BODY_LIMIT),
+            max_blob_size: self.max_blob_size,
+            max_manifest_size: self.max_manifest_size.unwrap_or(DEFAULT_MAX_MANIFEST_SIZE),
+            custom_manifest_types: self.custom_manifest_types,
+            digest_pull_only: self.digest_pull_only,
+            quarantine,
+            purges,
+            dangling,
+            repositories,
+            archive,
+            tag_histories,
+            allowed_media_types: self.allowed_media_types,
+            tag_limit: self.tag_limit,
+            scope_blobs: self.scope_blobs,
+            strict_spec_compliance: self.strict_spec_compliance,
+            convert_media_types: self.convert_media_types,
+            report_spec_deviations: self.report_spec_deviations,
+            separate_admin_routes: self.separate_admin_routes,
+            bandwidth_limits: self.bandwidth_limits,
+            events: events::EventBus::new(webhooks.clone()),
+            operations: Default::default(),
+            token_issuer,
+            cdn: self.cdn,
+            peer_directory: self.peer_directory.take(),
+            webhooks,
+            error_decorator: self.error_decorator.take(),
+            auto_tag_policies: std::mem::take(&mut self.auto_tag_policies),
+            access_log: self.access_sampling.map(access::AccessLog::new),
+            public_urls: self.public_urls,
+            trusted_proxies: self.trusted_proxies,
+        }))
+    }
+}
+
+/// Responds to requests not matching any route.
+///
+/// Endpoints below `/v2/` the registry does not implement are answered with an OCI error, so
+/// clients can report them properly.
+async fn fallback(uri: Uri) -> Response {
+    if uri.path().starts_with("/v2/") {
+        (
+            StatusCode::NOT_FOUND,
+            OciErrors::single(OciError::new(types::ErrorCode::Unsupported)),
+        )
+            .into_response()
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+/// Registry index
+///
+/// Returns an empty HTTP OK response if provided credentials are okay, otherwise returns
+/// UNAUTHORIZED.
+async fn index_v2(
+    State(registry): State<Arc<ContainerRegistry>>,
+    creds: Result<ValidCredentials, auth::AuthRejection>,
+) -> Response<Body> {
+    // Both anonymous and named users should be verified to be able to get index. Restricted access
+    // is handled identically for both via the rules set within the registry constructor.
+    let status = match creds {
+        Ok(_) => StatusCode::OK,
+        Err(rejection @ (auth::AuthRejection::Malformed | auth::AuthRejection::LockedOut(_))) => {
+            return rejection.into_response()
+        }
+        // Return `UNAUTHORIZED`, since we want the client to supply credentials.
+        Err(auth::AuthRejection::Invalid) => StatusCode::UNAUTHORIZED,
+    };
+
+    let mut response = Response::builder().status(status);
+    for challenge in registry.challenges() {
+        response = response.header(WWW_AUTHENTICATE, challenge);
+    }
+    response.body(Body::empty()).unwrap()
 }
 
 /// Returns metadata of a specific image blob.
 async fn blob_check(
     State(registry): State<Arc<ContainerRegistry>>,
-    Path((_, _, image)): Path<(String, String, ImageDigest)>,
+    Path((repository, name, image)): Path<(String, String, ImageDigest)>,
     creds: ValidCredentials,
-) -> Result<Response, RegistryError> {
+) -> Result<Response, ApiError> {
     registry
-        .auth_provider
+        .auth_provider_for(&creds)
         .blob_permissions(&creds, &image)
         .await
         .require_read()?;
 
-    if let Some(metadata) = registry.storage.get_blob_metadata(image.digest).await? {
+    let location = ImageLocation::new(repository, name);
+    let metadata = if registry
+        .catalog
+        .may_contain_blob(registry.storage.as_ref(), image.digest)
+        .await?
+        && registry
+            .blob_in_scope(&creds, &location, image.digest)
+            .await?
+    {
+        registry.storage.get_blob_metadata(image.digest).await?
+    } else {
+        None
+    };
+
+    if let Some(metadata) = metadata {
         Ok(Response::builder()
             .status(StatusCode::OK)
             .header(CONTENT_LENGTH, metadata.size())
-            .header("Docker-Content-Digest", image.to_string())
+            .docker_content_digest(image.digest)
             .header(CONTENT_TYPE, "application/octet-stream")
+            .header(CACHE_CONTROL, cdn::CACHE_IMMUTABLE)
             .body(Body::empty())
             .unwrap())
     } else {
@@ -357,30 +3009,138 @@ async fn blob_check(
 }
 
 /// Returns a specific image blob.
+///
+/// Requests carrying a valid signature issued for a [`cdn::Cdn`] are served without credentials.
+/// Otherwise, downloads are redirected to a peer holding the blob or the CDN, if set. Blobs
+/// delegated to a [`storage::RedirectBlobStore`] are redirected to it in either case.
 async fn blob_get(
     State(registry): State<Arc<ContainerRegistry>>,
-    Path((_, _, image)): Path<(String, String, ImageDigest)>,
-    creds: ValidCredentials,
-) -> Result<Response, RegistryError> {
-    registry
-        .auth_provider
-        .blob_permissions(&creds, &image)
-        .await
-        .require_read()?;
+    Path((repository, name, image)): Path<(String, String, ImageDigest)>,
+    Query(signed): Query<cdn::SignedUrl>,
+    peers::ClientAddress(client): peers::ClientAddress,
+    creds: Result<ValidCredentials, auth::AuthRejection>,
+) -> Result<Response, ApiError> {
+    let location = ImageLocation::new(repository, name);
+    let presigned = signed.is_signed()
+        && registry
+            .cdn
+            .as_ref()
+            .is_some_and(|cdn| cdn.verify(&location, &image, &signed, registry.clock.now()));
+
+    let creds = if presigned {
+        None
+    } else {
+        let creds = match creds {
+            Ok(creds) => creds,
+            Err(rejection) => return Ok(rejection.into_response()),
+        };
+
+        registry
+            .auth_provider_for(&creds)
+            .blob_permissions(&creds, &image)
+            .await
+            .require_read()?;
+
+        if !registry
+            .blob_in_scope(&creds, &location, image.digest)
+            .await?
+        {
+            return Err(RegistryError::NotFound.into());
+        }
+
+        // Well-known blobs like the empty layer are answered from memory once stored, redirecting
+        // or reading them would only cost a roundtrip.
+        if let Some(contents) = image.digest.known_contents() {
+            if registry
+                .storage
+                .get_blob_metadata(image.digest)
+                .await?
+                .is_some()
+            {
+                return Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header(CONTENT_LENGTH, contents.len())
+                    .docker_content_digest(image.digest)
+                    .header(CACHE_CONTROL, cdn::CACHE_IMMUTABLE)
+                    .body(Body::from(contents))?);
+            }
+        }
+
+        if let Some(ref directory) = registry.peer_directory {
+            match directory.locate(&location, image.digest, client).await {
+                Ok(Some(url)) => {
+                    debug!(%image, %url, "redirecting blob download to peer");
+                    return Ok(Response::builder()
+                        .status(StatusCode::TEMPORARY_REDIRECT)
+                        .header(LOCATION, url)
+                        .header(CACHE_CONTROL, "no-store")
+                        .body(Body::empty())?);
+                }
+                Ok(None) => {}
+                Err(err) => warn!(%image, %err, "failed to locate peers, serving blob"),
+            }
+        }
+
+        if let Some(ref cdn) = registry.cdn {
+            // Redirects depend on the credentials and the time, they must not be cached.
+            return Ok(Response::builder()
+                .status(StatusCode::TEMPORARY_REDIRECT)
+                .header(
+                    LOCATION,
+                    cdn.signed_url(&location, &image, registry.clock.now()),
+                )
+                .header(CACHE_CONTROL, "no-store")
+                .body(Body::empty())?);
+        }
+
+        Some(creds)
+    };
+
+    if let Some(url) = registry.storage.blob_url(image.digest).await? {
+        return Ok(Response::builder()
+            .status(StatusCode::TEMPORARY_REDIRECT)
+            .header(LOCATION, url)
+            .header(CACHE_CONTROL, "no-store")
+            .body(Body::empty())?);
+    }
 
     // TODO: Get size for `Content-length` header.
 
-    let reader = registry
-        .storage
-        .get_blob_reader(image.digest)
-        .await?
-        .ok_or(RegistryError::NotFound)?;
+    let reader = match registry.storage.get_blob_reader(image.digest).await? {
+        Some(reader) => reader,
+        None => match registry.restore_blob(image.digest).await? {
+            archive::Restore::NotArchived => return Err(RegistryError::NotFound.into()),
+            archive::Restore::Restored => registry
+                .storage
+                .get_blob_reader(image.digest)
+                .await?
+                .ok_or(RegistryError::NotFound)?,
+            archive::Restore::Pending(retry_after) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::ACCEPTED)
+                    .header(RETRY_AFTER, retry_after.as_secs().max(1))
+                    .header(CACHE_CONTROL, "no-store")
+                    .body(Body::empty())?);
+            }
+        },
+    };
 
     let stream = ReaderStream::new(reader);
-    let body = Body::from_stream(stream);
+    let body = match registry.bandwidth_limits.classify(
+        creds.as_ref().and_then(ValidCredentials::username),
+        location.repository(),
+    ) {
+        Some(class) => {
+            debug!(class = class.name(), %image, "throttling blob download");
+            Body::from_stream(throttle::throttle(stream, class))
+        }
+        None => Body::from_stream(stream),
+    };
 
     Ok(Response::builder()
         .status(StatusCode::OK)
+        .docker_content_digest(image.digest)
+        .header(CACHE_CONTROL, cdn::CACHE_IMMUTABLE)
         .body(body)
         .expect("Building a streaming response with body works. qed"))
 }
@@ -390,15 +3150,30 @@ async fn upload_new(
     State(registry): State<Arc<ContainerRegistry>>,
     Path(location): Path<ImageLocation>,
     creds: ValidCredentials,
-) -> Result<UploadState, RegistryError> {
+) -> Result<UploadState, ApiError> {
     registry
-        .auth_provider
+        .auth_provider_for(&creds)
         .image_permissions(&creds, &location)
         .await
         .require_write()?;
+    registry.check_repository_exists(&location).await?;
+
+    // Abandoned uploads are discarded right away, instead of taking up disk space until cleaned up.
+    for abandoned in registry.upload_sessions.expire() {
+        match registry.storage.cancel_upload(abandoned).await {
+            Ok(()) | Err(storage::Error::UploadDoesNotExit) => {}
+            Err(err) => warn!(upload = %abandoned, %err, "could not discard abandoned upload"),
+        }
+    }
+
+    let reservation = registry
+        .upload_sessions
+        .reserve(creds.username(), &location)
+        .inspect_err(|_| registry.metrics.upload_sessions_rejected.inc())?;
 
     // Initiate a new upload
     let upload = registry.storage.begin_new_upload().await?;
+    reservation.commit(upload);
 
     Ok(UploadState {
         location,
@@ -414,13 +3189,21 @@ fn mk_upload_location(location: &ImageLocation, uuid: Uuid) -> String {
     format!("/v2/{repository}/{image}/uploads/{uuid}")
 }
 
-/// Returns the URI for a specific part of an upload.
+/// Returns the URI for a specific manifest.
 fn mk_manifest_location(location: &ImageLocation, reference: &Reference) -> String {
     let repository = &location.repository();
     let image = &location.image();
     format!("/v2/{repository}/{image}/manifests/{reference}")
 }
 
+/// Returns the URI for a specific blob.
+fn mk_blob_location(location: &ImageLocation, digest: storage::Digest) -> String {
+    let repository = &location.repository();
+    let image = &location.image();
+    let digest = ImageDigest::new(digest);
+    format!("/v2/{repository}/{image}/blobs/{digest}")
+}
+
 /// Image upload state.
 ///
 /// Represents the state of a partial upload of a specific blob, which may be uploaded in chunks.
@@ -442,119 +3225,91 @@ impl IntoResponse for UploadState {
         let mut builder = Response::builder()
             .header(LOCATION, mk_upload_location(&self.location, self.upload))
             .header(CONTENT_LENGTH, 0)
-            .header("Docker-Upload-UUID", self.upload.to_string());
+            .docker_upload_uuid(self.upload);
 
         if let Some(completed) = self.completed {
-            builder = builder
-                .header(RANGE, format!("0-{}", completed))
-                .status(StatusCode::ACCEPTED)
-        } else {
-            builder = builder
-                .header(CONTENT_LENGTH, 0)
-                .status(StatusCode::ACCEPTED);
-            // The spec says to use `CREATED`, but only `ACCEPTED` works?
+            builder = builder.header(RANGE, upload_range(completed));
         }
 
-        builder.body(Body::empty()).unwrap()
+        // Both opening an upload session and adding a chunk are answered with `202 Accepted`, as
+        // required by the distribution spec. `201 Created` is reserved for finished uploads, i.e.
+        // the final `PUT` or single-request monolithic uploads (which are not supported). Clients
+        // check for the exact status and treat anything else as failure.
+        builder
+            .status(StatusCode::ACCEPTED)
+            .body(Body::empty())
+            .unwrap()
     }
 }
 
-/// An upload ID.
-#[derive(Copy, Clone, Debug, Deserialize)]
-struct UploadId {
-    /// The UUID representing this upload.
-    upload: Uuid,
-}
-
-#[derive(Debug)]
-
-/// An image hash.
+/// Formats the `Range` header of an upload with `stored` bytes, e.g. `0-1023` for 1 KiB.
 ///
-/// Currently only SHA256 hashes are supported.
-pub struct ImageDigest {
-    /// The actual image digest.
-    digest: storage::Digest,
-}
-
-impl Serialize for ImageDigest {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        let full = format!("sha256:{}", self.digest);
-        full.serialize(serializer)
-    }
+/// Like other registries, an empty upload is reported as `0-0`.
+fn upload_range(stored: u64) -> String {
+    format!("0-{}", stored.saturating_sub(1))
 }
 
-impl<'de> Deserialize<'de> for ImageDigest {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        // Note: For some reason, `&str` here causes parsing inside query parameters to fail.
-        let raw = <String>::deserialize(deserializer)?;
-        raw.parse().map_err(serde::de::Error::custom)
-    }
-}
+/// Reports the progress of an upload, allowing clients to resume it.
+async fn upload_status(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(location): Path<ImageLocation>,
+    UploadId { upload }: UploadId,
+    creds: ValidCredentials,
+) -> Result<Response, ApiError> {
+    registry
+        .auth_provider_for(&creds)
+        .image_permissions(&creds, &location)
+        .await
+        .require_write()?;
+    registry
+        .upload_sessions
+        .claim(upload, creds.username(), &location)?;
 
-impl ImageDigest {
-    /// Creats a new image hash from an existing digest.
-    #[inline(always)]
-    pub const fn new(digest: storage::Digest) -> Self {
-        Self { digest }
+    let stored = registry.storage.get_upload_size(upload).await?;
+    let mut response = UploadState {
+        location,
+        completed: Some(stored),
+        upload,
     }
+    .into_response();
+    *response.status_mut() = StatusCode::NO_CONTENT;
 
-    /// Returns the actual digest.
-    pub fn digest(&self) -> storage::Digest {
-        self.digest
-    }
+    Ok(response)
 }
 
-/// Error parsing a specific image digest.
-#[derive(Debug, Error)]
-pub enum ImageDigestParseError {
-    /// The given digest was of the wrong length.
-    #[error("wrong length")]
-    WrongLength,
-    /// The given digest had an invalid or unsupported prefix.
-    #[error("wrong prefix")]
-    WrongPrefix,
-    /// The hex encoding was not valid.
-    #[error("hex decoding error")]
-    HexDecodeError,
+/// An upload ID.
+///
+/// Taken from the `upload` path parameter. Only the hyphenated form handed out to clients is
+/// accepted, anything else cannot belong to an upload session and is answered like an unknown
+/// upload, before any storage is touched.
+#[derive(Copy, Clone, Debug)]
+struct UploadId {
+    /// The UUID representing this upload.
+    upload: Uuid,
 }
 
-impl FromStr for ImageDigest {
-    type Err = ImageDigestParseError;
-
-    fn from_str(raw: &str) -> Result<Self, Self::Err> {
-        const SHA256_LEN: usize = 32;
-        const PREFIX_LEN: usize = 7;
-        const DIGEST_HEX_LEN: usize = SHA256_LEN * 2;
-
-        if raw.len() != PREFIX_LEN + DIGEST_HEX_LEN {
-            return Err(ImageDigestParseError::WrongLength);
+#[async_trait]
+impl<S> FromRequestParts<S> for UploadId
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        #[derive(Deserialize)]
+        struct Params {
+            upload: String,
         }
 
-        if !raw.starts_with("sha256:") {
-            return Err(ImageDigestParseError::WrongPrefix);
+        let Path(Params { upload: raw }) = Path::<Params>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| ApiError::UploadUnknown)?;
+        let upload = Uuid::try_parse(&raw).map_err(|_| ApiError::UploadUnknown)?;
+        if upload.hyphenated().to_string() != raw {
+            return Err(ApiError::UploadUnknown);
         }
 
-        let hex_encoded = &raw[PREFIX_LEN..];
-        debug_assert_eq!(hex_encoded.len(), DIGEST_HEX_LEN);
-
-        let digest = <[u8; SHA256_LEN]>::from_hex(hex_encoded)
-            .map_err(|_| ImageDigestParseError::HexDecodeError)?;
-
-        Ok(Self {
-            digest: storage::Digest::new(digest),
-        })
-    }
-}
-
-impl Display for ImageDigest {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "sha256:{}", self.digest)
+        Ok(UploadId { upload })
     }
 }
 
@@ -562,93 +3317,274 @@ impl Display for ImageDigest {
 async fn upload_add_chunk(
     State(registry): State<Arc<ContainerRegistry>>,
     Path(location): Path<ImageLocation>,
-    Path(UploadId { upload }): Path<UploadId>,
+    UploadId { upload }: UploadId,
     creds: ValidCredentials,
+    client: compat::Client,
     request: axum::extract::Request,
-) -> Result<UploadState, RegistryError> {
+) -> Result<UploadState, ApiError> {
     registry
-        .auth_provider
+        .auth_provider_for(&creds)
         .image_permissions(&creds, &location)
         .await
         .require_write()?;
+    registry
+        .upload_sessions
+        .claim(upload, creds.username(), &location)?;
 
     // Check if we have a range - if so, its an unsupported feature, namely monolith uploads.
     if request.headers().contains_key(RANGE) {
-        return Err(RegistryError::NotSupported(
-            "unsupported feature: chunked uploads",
-        ));
+        return Err(RegistryError::NotSupported("unsupported feature: chunked uploads").into());
     }
 
-    let mut writer = registry.storage.get_upload_writer(0, upload).await?;
+    let stored = registry.storage.get_upload_size(upload).await?;
 
-    // We'll get the entire file in one go, no range header == monolithic uploads.
-    let mut body = request.into_body().into_data_stream();
+    // Refuse oversized chunks before reading any of their body.
+    let content_length = content_length(request.headers())?;
+    if let Some(length) = content_length {
+        registry.check_blob_size(stored.saturating_add(length))?;
+    }
 
-    let mut completed: u64 = 0;
-    while let Some(result) = body.next().await {
-        let chunk = result.map_err(RegistryError::IncomingReadFailed)?;
-        completed += chunk.len() as u64;
-        writer
-            .write_all(chunk.as_ref())
-            .await
-            .map_err(RegistryError::LocalWriteFailed)?;
+    // Chunks are appended, so a range has to continue exactly where the upload left off. Clients
+    // whose connection dropped mid-chunk are told how much arrived, so they can resume.
+    if let Some(value) = request.headers().get(CONTENT_RANGE) {
+        let range = value
+            .to_str()
+            .ok()
+            .and_then(range::ByteRange::parse)
+            .ok_or(ApiError::InvalidRange)?;
+
+        if content_length.is_some_and(|length| length != range.len()) {
+            return Err(ApiError::InvalidRange);
+        }
+
+        // The range tells us the size of the blob after this chunk has been added.
+        registry.check_blob_size(range.end().saturating_add(1))?;
+
+        if range.start() != stored {
+            return Err(ApiError::UploadOffsetMismatch { upload, stored });
+        }
+    } else if stored > 0 {
+        // Only a single chunk may be streamed without a range.
+        registry.spec_deviation(
+            "upload_add_chunk",
+            client,
+            "chunk appended to a non-empty upload without Content-Range",
+        );
     }
 
+    let declared = checksums::DeclaredDigest::from_headers(request.headers())?;
+    let writer = registry.storage.get_upload_writer(0, upload).await?;
+    let mut writer = checksums::HashingWriter::new(writer, declared.is_some());
+
+    // We'll get the entire file in one go, no range header == monolithic uploads.
+    let encoding = encoding::ContentEncoding::from_headers(request.headers())?;
+    let mut body = checksums::TrailingBody::new(request.into_body());
+
+    let written = if encoding == encoding::ContentEncoding::Identity {
+        let mut written: u64 = 0;
+        while let Some(result) = body.next().await {
+            let chunk = result.map_err(ApiError::IncomingReadFailed)?;
+            written += chunk.len() as u64;
+            // Clients may omit or misstate the length, so enforce the limit while streaming too.
+            registry.check_blob_size(stored + written)?;
+            writer
+                .write_all(chunk.as_ref())
+                .await
+                .map_err(RegistryError::LocalWriteFailed)?;
+        }
+        written
+    } else {
+        let compressed =
+            StreamReader::new((&mut body).map(|result| result.map_err(io::Error::other)));
+        let limit = registry.decompressed_body_limit.min(
+            registry
+                .max_blob_size
+                .map_or(u64::MAX, |max| max.saturating_sub(stored)),
+        );
+        encoding::copy_limited(encoding.decode(compressed), &mut writer, limit).await?
+    };
+
     writer
         .flush()
         .await
         .map_err(RegistryError::LocalWriteFailed)?;
 
+    // Chunks failing verification are discarded, so the upload can continue without them.
+    if let Some(declared) = declared {
+        let verified = declared
+            .resolve(body.trailers())
+            .map(|expected| expected.is_some() && expected == writer.digest());
+        if !matches!(verified, Ok(true)) {
+            registry.storage.truncate_upload(upload, stored).await?;
+            verified?;
+            return Err(ApiError::ChunkDigestMismatch { upload, stored });
+        }
+    }
+
     Ok(UploadState {
         location,
-        completed: Some(completed),
+        completed: Some(stored + written),
         upload,
     })
 }
 
+/// Parses the `Content-Length` header of a request, if present.
+fn content_length(headers: &HeaderMap) -> Result<Option<u64>, ApiError> {
+    headers
+        .get(CONTENT_LENGTH)
+        .map(|value| {
+            value
+                .to_str()
+                .map_err(|err| ApiError::ContentLengthMalformed(Box::new(err)))?
+                .parse()
+                .map_err(|err| ApiError::ContentLengthMalformed(Box::new(err)))
+        })
+        .transpose()
+}
+
+/// Determines the media type of an uploaded manifest.
+///
+/// Clients are supposed to send the media type as `Content-Type`, but some send a generic
+/// `application/json` or none at all, in which case the manifest's own `mediaType` is used. If both
+/// are given, they have to match.
+fn manifest_media_type(
+    headers: &HeaderMap,
+    raw_manifest: &[u8],
+) -> Result<Option<String>, ApiError> {
+    let declared = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(str::trim)
+        .filter(|value| !value.is_empty() && *value != "application/json");
+    let embedded = embedded_media_type(raw_manifest)?;
+
+    match (declared, embedded) {
+        (Some(declared), Some(embedded)) if declared != embedded => {
+            Err(ApiError::ManifestTypeMismatch {
+                declared: declared.to_owned(),
+                embedded,
+            })
+        }
+        (Some(declared), _) => Ok(Some(declared.to_owned())),
+        (None, embedded) => Ok(embedded),
+    }
+}
+
+/// Returns the media type given in a manifest's `mediaType` field, if any.
+fn embedded_media_type(raw_manifest: &[u8]) -> Result<Option<String>, RegistryError> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Probe {
+        media_type: Option<String>,
+    }
+
+    Ok(serde_json::from_slice::<Probe>(raw_manifest)
+        .map_err(RegistryError::ParseManifest)?
+        .media_type)
+}
+
 /// An image digest on a query string.
 ///
-/// Newtype to allow [`axum::extract::Query`] to parse it.
+/// Kept unparsed, so unsupported algorithms can be reported as such instead of failing to
+/// extract the query.
 #[derive(Debug, Deserialize)]
 struct DigestQuery {
     /// The image in question.
-    digest: ImageDigest,
+    digest: Option<String>,
+}
+
+impl DigestQuery {
+    /// Parses the digest, telling unsupported algorithms apart from malformed digests.
+    fn parse(&self) -> Result<ImageDigest, ApiError> {
+        let digest = self.digest.as_deref().ok_or(ApiError::MissingDigest)?;
+        match digest.split_once(':') {
+            Some((algorithm, _))
+                if is_digest_algorithm(algorithm)
+                    && !types::DIGEST_ALGORITHMS.contains(&algorithm) =>
+            {
+                Err(ApiError::UnsupportedDigestAlgorithm(algorithm.to_owned()))
+            }
+            _ => Ok(digest.parse().map_err(RegistryError::InvalidDigest)?),
+        }
+    }
+}
+
+/// Returns whether `algorithm` is a well-formed digest algorithm identifier.
+///
+/// See the `algorithm` production of the OCI image specification's digest grammar.
+fn is_digest_algorithm(algorithm: &str) -> bool {
+    algorithm.split(['+', '.', '_', '-']).all(|component| {
+        !component.is_empty()
+            && component
+                .bytes()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+    })
 }
 
 /// Finishes an upload.
 async fn upload_finalize(
     State(registry): State<Arc<ContainerRegistry>>,
-    Path((repository, image, upload)): Path<(String, String, Uuid)>,
-    Query(DigestQuery { digest }): Query<DigestQuery>,
+    Path(location): Path<ImageLocation>,
+    UploadId { upload }: UploadId,
+    Query(query): Query<DigestQuery>,
     creds: ValidCredentials,
+    client: compat::Client,
     request: axum::extract::Request,
-) -> Result<Response<Body>, RegistryError> {
-    let location = ImageLocation::new(repository, image);
-
+) -> Result<Response<Body>, ApiError> {
     registry
-        .auth_provider
+        .auth_provider_for(&creds)
         .image_permissions(&creds, &location)
         .await
         .require_write()?;
+    let digest = query.parse()?;
+
+    let created = || {
+        Response::builder()
+            .status(StatusCode::CREATED)
+            .docker_content_digest(digest.digest)
+            .header(LOCATION, mk_upload_location(&location, upload))
+            .body(Body::empty())
+    };
+
+    // Clients retrying a finalization whose response got lost are told it succeeded again.
+    let finalized = registry
+        .upload_sessions
+        .finalized(upload, creds.username(), &location);
+    if finalized == Some(digest.digest)
+        && registry
+            .storage
+            .get_blob_metadata(digest.digest)
+            .await?
+            .is_some()
+    {
+        return Ok(created()?);
+    }
+
+    registry
+        .upload_sessions
+        .claim(upload, creds.username(), &location)?;
 
     // We do not support the final chunk in the `PUT` call, so ensure that's not the case.
-    match request.headers().get(CONTENT_LENGTH) {
-        Some(value) => {
-            let num_bytes: u64 = value
-                .to_str()
-                .map_err(|err| RegistryError::ContentLengthMalformed(Box::new(err)))?
-                .parse()
-                .map_err(|err| RegistryError::ContentLengthMalformed(Box::new(err)))?;
+    match content_length(request.headers())? {
+        Some(num_bytes) => {
+            registry.check_blob_size(num_bytes)?;
+
             if num_bytes != 0 {
-                return Err(RegistryError::NotSupported(
-                    "missing content length not implemented",
-                ));
+                return Err(
+                    RegistryError::NotSupported("missing content length not implemented").into(),
+                );
             }
 
             // 0 is the only acceptable value here.
         }
         None => {
             // Omitting is fine, indicating no body.
+            registry.spec_deviation(
+                "upload_finalize",
+                client,
+                "upload finished without Content-Length",
+            );
         }
     }
 
@@ -656,13 +3592,11 @@ async fn upload_finalize(
         .storage
         .finalize_upload(upload, digest.digest)
         .await?;
+    registry.storage.link_blob(&location, digest.digest).await?;
+    registry.upload_sessions.finalize(upload, digest.digest);
 
     info!(%upload, %digest, "new image uploaded");
-    Ok(Response::builder()
-        .status(StatusCode::CREATED)
-        .header("Docker-Content-Digest", digest.to_string())
-        .header(LOCATION, mk_upload_location(&location, upload))
-        .body(Body::empty())?)
+    Ok(created()?)
 }
 
 /// Uploads a manifest.
@@ -670,69 +3604,400 @@ async fn manifest_put(
     State(registry): State<Arc<ContainerRegistry>>,
     Path(manifest_reference): Path<ManifestReference>,
     creds: ValidCredentials,
-    image_manifest_json: String,
-) -> Result<Response<Body>, RegistryError> {
+    client: compat::Client,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response<Body>, ApiError> {
     registry
-        .auth_provider
+        .auth_provider_for(&creds)
         .image_permissions(&creds, manifest_reference.location())
         .await
         .require_write()?;
 
-    let digest = registry
-        .storage
-        .put_manifest(&manifest_reference, image_manifest_json.as_bytes())
-        .await?;
+    let limit = registry.max_manifest_size;
+    if content_length(&headers)?.is_some_and(|length| length > limit) {
+        return Err(ApiError::PayloadTooLarge);
+    }
 
-    info!(%manifest_reference, %digest, "new manifest received");
-    // Completed upload, call hook:
-    registry
-        .hooks
-        .on_manifest_uploaded(&manifest_reference)
-        .await;
+    let mut body = body.into_data_stream();
+    let raw_manifest = match encoding::ContentEncoding::from_headers(&headers)? {
+        encoding::ContentEncoding::Identity => {
+            let mut raw_manifest = Vec::new();
+            while let Some(result) = body.next().await {
+                let chunk = result.map_err(ApiError::IncomingReadFailed)?;
+                if raw_manifest.len() as u64 + chunk.len() as u64 > limit {
+                    return Err(ApiError::PayloadTooLarge);
+                }
+                raw_manifest.extend_from_slice(&chunk);
+            }
+            raw_manifest
+        }
+        encoding => {
+            let compressed = StreamReader::new(body.map(|result| result.map_err(io::Error::other)));
+            let mut raw_manifest = Vec::new();
+            encoding::copy_limited(encoding.decode(compressed), &mut raw_manifest, limit).await?;
+            raw_manifest
+        }
+    };
+
+    let media_type = manifest_media_type(&headers, &raw_manifest)?;
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(str::trim);
+    match (content_type, media_type.as_deref()) {
+        (None, Some(media_type)) => registry.spec_deviation(
+            "manifest_put",
+            client,
+            format_args!("manifest of type {media_type} pushed without Content-Type"),
+        ),
+        (Some(content_type), Some(media_type)) if content_type != media_type => registry
+            .spec_deviation(
+                "manifest_put",
+                client,
+                format_args!("manifest of type {media_type} pushed as {content_type}"),
+            ),
+        _ => {}
+    }
+    let AcceptedManifest {
+        reference: stored_reference,
+        digest,
+        manifest,
+    } = registry
+        .accept_manifest(&manifest_reference, media_type, raw_manifest)
+        .await?;
 
-    Ok(Response::builder()
+    let mut response = Response::builder()
         .status(StatusCode::CREATED)
         .header(
             LOCATION,
-            mk_manifest_location(
-                manifest_reference.location(),
-                manifest_reference.reference(),
-            ),
+            mk_manifest_location(stored_reference.location(), stored_reference.reference()),
         )
         .header(CONTENT_LENGTH, 0)
-        .header(
-            "Docker-Content-Digest",
-            ImageDigest::new(digest).to_string(),
-        )
-        .body(Body::empty())
-        .unwrap())
+        .docker_content_digest(digest);
+
+    // Signals OCI 1.1 clients that the subject was indexed and the referrers API can be used.
+    if let Some(Ok(subject)) = manifest.subject().map(ContentDescriptor::parsed_digest) {
+        response = response.oci_subject(subject);
+    }
+
+    Ok(response.body(Body::empty())?)
+}
+
+/// Query parameters of manifest downloads.
+#[derive(Debug, Deserialize)]
+struct ManifestQuery {
+    /// Platform to resolve the manifest for, as `os/architecture`.
+    platform: Option<String>,
 }
 
 /// Retrieves a manifest.
+///
+/// With a `platform` query parameter, e.g. `?platform=linux/arm64`, indices are resolved to the
+/// image manifest for that platform, which is returned instead, see
+/// [`ContainerRegistry::resolve_platform`]. This allows clients unable to handle indices to pull
+/// multi-platform images.
+///
+/// Manifests pulled by tag may be converted between Docker and OCI media types, see the
+/// [`conversion`] module.
 async fn manifest_get(
     State(registry): State<Arc<ContainerRegistry>>,
     Path(manifest_reference): Path<ManifestReference>,
+    Query(ManifestQuery { platform }): Query<ManifestQuery>,
+    method: Method,
+    headers: HeaderMap,
     creds: ValidCredentials,
-) -> Result<Response<Body>, RegistryError> {
+) -> Result<Response<Body>, ApiError> {
     registry
-        .auth_provider
+        .auth_provider_for(&creds)
         .image_permissions(&creds, manifest_reference.location())
         .await
         .require_read()?;
 
+    // `HEAD` requests stay allowed, they are how clients resolve a tag to pin.
+    if method == Method::GET
+        && manifest_reference.reference().as_tag().is_some()
+        && registry.requires_digest_pulls(manifest_reference.location())
+    {
+        return Err(RegistryError::PolicyViolation(format!(
+            "manifests of {} must be pulled by digest, not by tag",
+            manifest_reference.location()
+        ))
+        .into());
+    }
+
+    // Checked before resolving platforms, as the index a tag points to may change.
+    let by_tag = manifest_reference.reference().as_tag().is_some();
+    let cache_control = if by_tag {
+        cdn::CACHE_REVALIDATE
+    } else {
+        cdn::CACHE_IMMUTABLE
+    };
+
+    let manifest_reference = match platform {
+        Some(platform) => {
+            let (os, architecture) = platform
+                .split_once('/')
+                .filter(|(os, architecture)| {
+                    !os.is_empty() && !architecture.is_empty() && !architecture.contains('/')
+                })
+                .ok_or_else(|| RegistryError::InvalidPlatform(platform.clone()))?;
+            let resolved = registry
+                .resolve_platform(&manifest_reference, os, architecture)
+                .await?;
+            ManifestReference::new(
+                manifest_reference.location().clone(),
+                Reference::new_digest(resolved.digest()),
+            )
+        }
+        None => manifest_reference,
+    };
+
     let manifest_json = registry
         .storage
         .get_manifest(&manifest_reference)
         .await?
         .ok_or(RegistryError::NotFound)?;
 
-    let manifest: ImageManifest =
-        serde_json::from_slice(&manifest_json).map_err(RegistryError::ParseManifest)?;
+    // Clients requesting a manifest by tag use the digest to pin it.
+    let digest = registry
+        .verify_manifest(&manifest_reference, &manifest_json)
+        .await?;
+    let manifest = Manifest::from_slice(&manifest_json).map_err(RegistryError::ParseManifest)?;
 
-    Ok(Response::builder()
+    registry
+        .check_quarantine(&creds, manifest_reference.location(), digest)
+        .await?;
+
+    #[cfg(feature = "notation")]
+    if method == Method::GET {
+        registry
+            .check_notation_trust(manifest_reference.location(), digest, &manifest)
+            .await?;
+    }
+
+    if let Some(ref archive) = registry.archive {
+        if method == Method::GET {
+            if let Err(err) = archive.record_pull(digest, registry.clock.now()).await {
+                warn!(%digest, %err, "could not record manifest pull");
+            }
+        }
+    }
+
+    let mut media_type = manifest.media_type().to_owned();
+    let (manifest_json, digest) = match conversion::conversion_target(&media_type, &headers) {
+        Some(target) if registry.convert_media_types && by_tag => match registry
+            .convert_manifest(manifest_reference.location(), &manifest_json, target)
+            .await?
+        {
+            Some(converted) => {
+                media_type = target.to_owned();
+                converted
+            }
+            None => (manifest_json, digest),
+        },
+        _ => (manifest_json, digest),
+    };
+
+    let mut response = Response::builder()
         .status(StatusCode::OK)
         .header(CONTENT_LENGTH, manifest_json.len())
-        .header(CONTENT_TYPE, manifest.media_type())
-        .body(manifest_json.into())
-        .unwrap())
+        .header(CONTENT_TYPE, media_type)
+        .header(CACHE_CONTROL, cache_control)
+        .docker_content_digest(digest);
+    if registry.convert_media_types {
+        response = response.header(VARY, ACCEPT);
+    }
+    Ok(response.body(manifest_json.into()).unwrap())
+}
+
+/// Query parameters of the referrers API.
+#[derive(Debug, Deserialize)]
+struct ReferrersQuery {
+    /// Only return referrers of the given artifact type.
+    #[serde(rename = "artifactType")]
+    artifact_type: Option<String>,
+}
+
+/// Lists all manifests referring to a given manifest.
+async fn referrers_get(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path((repository, image, subject)): Path<(String, String, ImageDigest)>,
+    Query(ReferrersQuery { artifact_type }): Query<ReferrersQuery>,
+    creds: ValidCredentials,
+) -> Result<Response<Body>, ApiError> {
+    let location = ImageLocation::new(repository, image);
+
+    registry
+        .auth_provider_for(&creds)
+        .image_permissions(&creds, &location)
+        .await
+        .require_read()?;
+
+    let mut referrers = registry
+        .collect_referrers(&location, subject.digest())
+        .await?;
+
+    let mut builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, types::OCI_IMAGE_INDEX);
+
+    if let Some(artifact_type) = artifact_type {
+        referrers.retain(|descriptor| descriptor.artifact_type() == Some(artifact_type.as_str()));
+        builder = builder.oci_filters_applied(&["artifactType"]);
+    }
+
+    let index_json =
+        serde_json::to_vec(&ImageIndex::new(referrers)).expect("serialization should not fail");
+
+    Ok(builder.body(index_json.into())?)
+}
+
+/// Query parameters of the listing APIs.
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    /// Maximum number of entries to return.
+    n: Option<usize>,
+    /// Entry to continue the listing after.
+    last: Option<String>,
+}
+
+/// Response of the catalog API.
+#[derive(Debug, Serialize)]
+struct RepositoryList {
+    repositories: Vec<String>,
+}
+
+/// Response of the tag listing API.
+#[derive(Debug, Serialize)]
+struct TagList {
+    name: String,
+    tags: Vec<String>,
+}
+
+/// Builds the `Link` header pointing to the next page of a listing.
+fn mk_next_link(registry: &ContainerRegistry, path: &str, n: usize, last: &str) -> String {
+    let base_path = registry
+        .public_urls
+        .base_path
+        .as_deref()
+        .unwrap_or_default();
+    format!("<{base_path}{path}?n={n}&last={last}>; rel=\"next\"")
+}
+
+/// Builds the response to a listing request, linking the next page if there may be one.
+fn mk_listing_response<T: Serialize>(
+    listing: &T,
+    next: Option<String>,
+) -> Result<Response<Body>, ApiError> {
+    let mut builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json");
+    if let Some(next) = next {
+        builder = builder.header(LINK, next);
+    }
+
+    let json = serde_json::to_vec(listing).expect("serialization should not fail");
+    Ok(builder.body(json.into())?)
+}
+
+/// Lists all repositories the user may read from.
+///
+/// Served from the registry's catalog instead of walking storage, see [`storage::catalog`].
+async fn catalog_get(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Query(ListQuery { n, last }): Query<ListQuery>,
+    creds: ValidCredentials,
+) -> Result<Response<Body>, ApiError> {
+    let mut cursor = match last.map(|last| last.parse::<ImageLocation>()).transpose() {
+        Ok(cursor) => cursor,
+        Err(_) => return Ok((StatusCode::BAD_REQUEST, "invalid last repository").into_response()),
+    };
+    let limit = n.unwrap_or(usize::MAX);
+
+    // Locations the user may not read are skipped, fetch more until the page is full.
+    let mut repositories = Vec::new();
+    while repositories.len() < limit {
+        let wanted = limit - repositories.len();
+        let batch = registry
+            .catalog
+            .locations(registry.storage.as_ref(), cursor.as_ref(), wanted)
+            .await?;
+        let exhausted = batch.len() < wanted;
+        cursor = batch.last().cloned();
+
+        for location in batch {
+            if registry
+                .auth_provider_for(&creds)
+                .image_permissions(&creds, &location)
+                .await
+                .has_read_permission()
+            {
+                repositories.push(location.to_string());
+            }
+        }
+
+        if exhausted {
+            break;
+        }
+    }
+
+    let next = match (n, repositories.last()) {
+        (Some(n), Some(last)) if repositories.len() == n => {
+            Some(mk_next_link(&registry, "/v2/_catalog", n, last))
+        }
+        _ => None,
+    };
+
+    mk_listing_response(&RepositoryList { repositories }, next)
+}
+
+/// Lists the tags of an image.
+async fn tags_list_get(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(location): Path<ImageLocation>,
+    Query(ListQuery { n, last }): Query<ListQuery>,
+    creds: ValidCredentials,
+) -> Result<Response<Body>, ApiError> {
+    registry
+        .auth_provider_for(&creds)
+        .image_permissions(&creds, &location)
+        .await
+        .require_read()?;
+
+    let Some(tags) = registry
+        .catalog
+        .tags(
+            registry.storage.as_ref(),
+            &location,
+            last.as_deref(),
+            n.unwrap_or(usize::MAX),
+        )
+        .await?
+    else {
+        return Ok((
+            StatusCode::NOT_FOUND,
+            OciErrors::single(OciError::new(types::ErrorCode::NameUnknown)),
+        )
+            .into_response());
+    };
+
+    let next = match (n, tags.last()) {
+        (Some(n), Some(last)) if tags.len() == n => Some(mk_next_link(
+            &registry,
+            &format!("/v2/{location}/tags/list"),
+            n,
+            last,
+        )),
+        _ => None,
+    };
+
+    mk_listing_response(
+        &TagList {
+            name: location.to_string(),
+            tags,
+        },
+        next,
+    )
 }
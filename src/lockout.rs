@@ -0,0 +1,411 @@
+//! Tracking of failed authentication attempts.
+//!
+//! Every request carrying credentials the auth provider refuses is counted in the registry
+//! metrics and reported to the
+//! [`RegistryHooks::on_authentication_failed`](crate::hooks::RegistryHooks::on_authentication_failed)
+//! hook. To protect password-based deployments from credential stuffing, a [`LockoutPolicy`] can
+//! be configured through
+//! [`ContainerRegistryBuilder::auth_lockout`](crate::ContainerRegistryBuilder::auth_lockout):
+//! once a client address has failed to authenticate a number of times in a row, all of its
+//! requests are answered with `429 Too Many Requests` and a `Retry-After` header for a while,
+//! without checking the credentials at all. Repeated lockouts of the same address can be made to
+//! last increasingly longer.
+//!
+//! Failures are tracked per client address, which is taken from the connection if the server was
+//! set up with
+//! [`into_make_service_with_connect_info`](axum::Router::into_make_service_with_connect_info).
+//! Connections from one of the
+//! [`ContainerRegistryBuilder::trusted_proxies`](crate::ContainerRegistryBuilder::trusted_proxies)
+//! are attributed to the rightmost address in their `X-Forwarded-For` header that is not a trusted
+//! proxy itself, as everything left of it may have been made up by the client. Requests with an
+//! unknown address are not tracked.
+//!
+//! Failures can also be tracked per username using [`LockoutPolicy::lock_out_users`]. Beware that
+//! this lets anyone lock out any user whose name they know, legitimate clients included, by
+//! sending wrong passwords for it. A successful login resets the failures of its user, but not of
+//! its address.
+//!
+//! Requests without credentials never count as failures, as clients send these to discover the
+//! authentication scheme. Failures are only kept in memory, thus forgotten on restart. At most
+//! [`MAX_RECORDS`] users and addresses are tracked at a time; once full, the records of those that
+//! failed longest ago are dropped first, preferring those not currently locked out.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{request::Parts, HeaderName},
+};
+
+use crate::clock::Clock;
+
+/// Client address as reported by a reverse proxy.
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// Number of tracked users and addresses above which stale records are pruned.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Maximum number of tracked users and addresses.
+pub const MAX_RECORDS: usize = 16 * 1024;
+
+/// When to lock out client addresses and, optionally, users failing to authenticate.
+#[derive(Clone, Copy, Debug)]
+pub struct LockoutPolicy {
+    /// Number of consecutive failures triggering a lockout.
+    max_failures: u32,
+    /// Duration of the first lockout.
+    lockout: Duration,
+    /// Maximum duration of repeated lockouts.
+    max_lockout: Duration,
+    /// Whether users are locked out as well as addresses.
+    lock_out_users: bool,
+}
+
+impl LockoutPolicy {
+    /// Creates a new policy locking out client addresses for `lockout` after `max_failures`
+    /// consecutive failed attempts.
+    pub fn new(max_failures: u32, lockout: Duration) -> Self {
+        Self {
+            max_failures: max_failures.max(1),
+            lockout,
+            max_lockout: lockout,
+            lock_out_users: false,
+        }
+    }
+
+    /// Sets whether users are locked out after failing to authenticate from any address.
+    ///
+    /// This protects against credential stuffing spread across many addresses, but lets anyone
+    /// lock out a user by guessing its password wrong repeatedly. Disabled by default.
+    pub fn lock_out_users(mut self, lock_out_users: bool) -> Self {
+        self.lock_out_users = lock_out_users;
+        self
+    }
+
+    /// Doubles the lockout duration for every repeated lockout, up to `max_lockout`.
+    ///
+    /// Lockouts count as repeated until the user or address has not failed to authenticate for
+    /// `max_lockout`.
+    pub fn backoff(mut self, max_lockout: Duration) -> Self {
+        self.max_lockout = max_lockout.max(self.lockout);
+        self
+    }
+
+    /// Returns whether failures of `subject` are tracked.
+    fn tracks(&self, subject: &Subject) -> bool {
+        match subject {
+            Subject::User(_) => self.lock_out_users,
+            Subject::Client(_) => true,
+        }
+    }
+
+    /// Returns the duration of the `nth` consecutive lockout, starting at one.
+    fn lockout_duration(&self, nth: u32) -> Duration {
+        let factor = 2u32.saturating_pow(nth.saturating_sub(1));
+        self.lockout
+            .checked_mul(factor)
+            .unwrap_or(self.max_lockout)
+            .min(self.max_lockout)
+    }
+}
+
+/// A user or client address failures are tracked for.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum Subject {
+    /// A username given along with credentials.
+    User(String),
+    /// The address of a client.
+    Client(IpAddr),
+}
+
+/// Failures of a single user or address.
+#[derive(Debug)]
+struct Record {
+    /// Number of failures since the last lockout.
+    failures: u32,
+    /// Number of lockouts so far.
+    lockouts: u32,
+    /// Time of the last failure.
+    last_failure: Instant,
+    /// End of the current lockout, if any.
+    locked_until: Option<Instant>,
+}
+
+/// Failed authentication attempts, see the [module documentation](self).
+#[derive(Debug)]
+pub(crate) struct AuthFailures {
+    /// The lockout policy, if enabled.
+    policy: Option<LockoutPolicy>,
+    /// Source of the time failures are recorded at.
+    clock: Arc<dyn Clock>,
+    /// Failures by user and address, only tracked with a lockout policy.
+    records: Mutex<HashMap<Subject, Record>>,
+}
+
+impl AuthFailures {
+    /// Creates a new tracker, locking out according to `policy` if given.
+    pub(crate) fn new(policy: Option<LockoutPolicy>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            policy,
+            clock,
+            records: Default::default(),
+        }
+    }
+
+    /// Returns how much longer any of `subjects` is locked out, if at all.
+    pub(crate) fn locked_out(&self, subjects: &[Subject]) -> Option<Duration> {
+        let policy = self.policy?;
+
+        let now = self.clock.instant();
+        let records = self.records.lock().expect("lock poisoned");
+        subjects
+            .iter()
+            .filter(|subject| policy.tracks(subject))
+            .filter_map(|subject| records.get(subject)?.locked_until)
+            .filter_map(|until| until.checked_duration_since(now))
+            .filter(|remaining| !remaining.is_zero())
+            .max()
+    }
+
+    /// Records a failed attempt by `subjects`, returning whether any of them got locked out.
+    pub(crate) fn record_failure(&self, subjects: &[Subject]) -> bool {
+        let Some(ref policy) = self.policy else {
+            return false;
+        };
+
+        let now = self.clock.instant();
+        let mut records = self.records.lock().expect("lock poisoned");
+        if records.len() > PRUNE_THRESHOLD {
+            records.retain(|_, record| {
+                now.duration_since(record.last_failure) < policy.max_lockout
+                    || record.locked_until.is_some_and(|until| until > now)
+            });
+        }
+
+        let mut locked = false;
+        for subject in subjects.iter().filter(|subject| policy.tracks(subject)) {
+            if records.len() >= MAX_RECORDS && !records.contains_key(subject) {
+                evict(&mut records, now);
+            }
+
+            let record = records.entry(subject.clone()).or_insert(Record {
+                failures: 0,
+                lockouts: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+
+            // Lockouts only count as repeated while failures keep coming in.
+            if now.duration_since(record.last_failure) >= policy.max_lockout {
+                record.failures = 0;
+                record.lockouts = 0;
+            }
+            record.last_failure = now;
+            record.failures += 1;
+
+            if record.failures >= policy.max_failures {
+                record.failures = 0;
+                record.lockouts += 1;
+                record.locked_until = Some(now + policy.lockout_duration(record.lockouts));
+                locked = true;
+            }
+        }
+
+        locked
+    }
+
+    /// Records a successful login of `username`, resetting its failures.
+    pub(crate) fn record_success(&self, username: &str) {
+        if self.policy.is_none() {
+            return;
+        }
+
+        self.records
+            .lock()
+            .expect("lock poisoned")
+            .remove(&Subject::User(username.to_owned()));
+    }
+}
+
+/// Drops the record of the subject that failed longest ago, preferring those not locked out.
+fn evict(records: &mut HashMap<Subject, Record>, now: Instant) {
+    let oldest = records
+        .iter()
+        .min_by_key(|(_, record)| {
+            let locked = record.locked_until.is_some_and(|until| until > now);
+            (locked, record.last_failure)
+        })
+        .map(|(subject, _)| subject.clone());
+    if let Some(oldest) = oldest {
+        records.remove(&oldest);
+    }
+}
+
+/// Determines the address of the client sending a request, if known.
+///
+/// Requests from `trusted_proxies` are attributed to the rightmost address in `X-Forwarded-For`
+/// that is not a trusted proxy, see the [module documentation](self).
+pub(crate) fn client_address(parts: &Parts, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())?;
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+
+    // Proxies append the address they received the request from, so only the entries after the
+    // last one added by an untrusted hop can be relied on.
+    let mut client = peer;
+    for value in parts.headers.get_all(X_FORWARDED_FOR).iter().rev() {
+        for hop in value.to_str().ok()?.rsplit(',') {
+            client = hop.trim().parse().ok()?;
+            if !trusted_proxies.contains(&client) {
+                return Some(client);
+            }
+        }
+    }
+    Some(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use axum::{extract::ConnectInfo, http::Request};
+
+    use super::{client_address, AuthFailures, LockoutPolicy, Subject, MAX_RECORDS};
+    use crate::clock::{ManualClock, SystemClock};
+
+    #[test]
+    fn repeated_lockouts_back_off() {
+        let policy =
+            LockoutPolicy::new(3, Duration::from_secs(10)).backoff(Duration::from_secs(35));
+
+        assert_eq!(policy.lockout_duration(1), Duration::from_secs(10));
+        assert_eq!(policy.lockout_duration(2), Duration::from_secs(20));
+        assert_eq!(policy.lockout_duration(3), Duration::from_secs(35));
+        assert_eq!(policy.lockout_duration(40), Duration::from_secs(35));
+    }
+
+    #[test]
+    fn users_are_locked_out_after_consecutive_failures() {
+        let failures = AuthFailures::new(
+            Some(LockoutPolicy::new(2, Duration::from_secs(60)).lock_out_users(true)),
+            Arc::new(SystemClock),
+        );
+        let alice = [Subject::User("alice".to_owned())];
+
+        assert!(!failures.record_failure(&alice));
+        failures.record_success("alice");
+        assert!(!failures.record_failure(&alice));
+        assert!(failures.locked_out(&alice).is_none());
+
+        assert!(failures.record_failure(&alice));
+        assert!(failures.locked_out(&alice).is_some());
+        assert!(failures
+            .locked_out(&[Subject::User("bob".to_owned())])
+            .is_none());
+    }
+
+    #[test]
+    fn users_are_not_locked_out_by_default() {
+        let failures = AuthFailures::new(
+            Some(LockoutPolicy::new(1, Duration::from_secs(60))),
+            Arc::new(SystemClock),
+        );
+        let alice = Subject::User("alice".to_owned());
+        let client = Subject::Client([192, 0, 2, 1].into());
+
+        assert!(failures.record_failure(&[alice.clone(), client.clone()]));
+        assert!(failures.locked_out(&[alice]).is_none());
+        assert!(failures.locked_out(&[client]).is_some());
+    }
+
+    #[test]
+    fn tracked_clients_are_limited() {
+        let clock = Arc::new(ManualClock::new());
+        let failures = AuthFailures::new(
+            Some(LockoutPolicy::new(2, Duration::from_secs(60))),
+            clock.clone(),
+        );
+        let client = |n: usize| [Subject::Client(IpAddr::V6((n as u128).into()))];
+
+        // A locked out client is kept while newer ones without a lockout are dropped.
+        assert!(!failures.record_failure(&client(0)));
+        assert!(failures.record_failure(&client(0)));
+        for n in 1..=MAX_RECORDS {
+            clock.advance(Duration::from_millis(1));
+            assert!(!failures.record_failure(&client(n)));
+        }
+        assert_eq!(failures.records.lock().unwrap().len(), MAX_RECORDS);
+        assert!(failures.locked_out(&client(0)).is_some());
+        assert!(!failures.records.lock().unwrap().contains_key(&client(1)[0]));
+        assert!(failures.records.lock().unwrap().contains_key(&client(2)[0]));
+    }
+
+    #[test]
+    fn client_addresses_are_only_forwarded_by_trusted_proxies() {
+        let proxies: [IpAddr; 2] = [[10, 0, 0, 1].into(), [10, 0, 0, 2].into()];
+        let parts = |peer: [u8; 4], forwarded: &[&str]| {
+            let mut request =
+                Request::builder().extension(ConnectInfo(SocketAddr::from((peer, 40000))));
+            for value in forwarded {
+                request = request.header("x-forwarded-for", *value);
+            }
+            request.body(()).unwrap().into_parts().0
+        };
+        let address = |parts| client_address(&parts, &proxies);
+
+        assert_eq!(
+            address(parts([192, 0, 2, 1], &["192.0.2.9"])),
+            Some([192, 0, 2, 1].into())
+        );
+        assert_eq!(address(parts([10, 0, 0, 1], &[])), Some(proxies[0]));
+        assert_eq!(
+            address(parts([10, 0, 0, 1], &["192.0.2.9, 192.0.2.1"])),
+            Some([192, 0, 2, 1].into())
+        );
+        assert_eq!(
+            address(parts([10, 0, 0, 1], &["192.0.2.9", "192.0.2.1, 10.0.0.2"])),
+            Some([192, 0, 2, 1].into())
+        );
+        assert_eq!(
+            address(parts([10, 0, 0, 1], &["10.0.0.1, 10.0.0.2"])),
+            Some(proxies[0])
+        );
+        assert_eq!(address(parts([10, 0, 0, 1], &["nonsense"])), None);
+        assert_eq!(
+            client_address(&Request::new(()).into_parts().0, &proxies),
+            None
+        );
+    }
+
+    #[test]
+    fn lockouts_expire() {
+        let clock = Arc::new(ManualClock::new());
+        let failures = AuthFailures::new(
+            Some(LockoutPolicy::new(1, Duration::from_secs(10)).backoff(Duration::from_secs(60))),
+            clock.clone(),
+        );
+        let alice = [Subject::Client([192, 0, 2, 1].into())];
+
+        assert!(failures.record_failure(&alice));
+        assert_eq!(failures.locked_out(&alice), Some(Duration::from_secs(10)));
+        clock.advance(Duration::from_secs(10));
+        assert!(failures.locked_out(&alice).is_none());
+
+        // Failing again soon after is locked out for longer.
+        assert!(failures.record_failure(&alice));
+        assert_eq!(failures.locked_out(&alice), Some(Duration::from_secs(20)));
+    }
+}
@@ -0,0 +1,1498 @@
+//! Periodic registry maintenance.
+//!
+//! A registry accumulates garbage over time: Uploads that were never finished, blobs no longer
+//! referenced by any manifest and old tags. The [`MaintenanceScheduler`] runs
+//! [`MaintenanceTask`]s on fixed intervals to keep this in check, so embedders do not have to wire
+//! up their own timers.
+//!
+//! The following tasks are included:
+//!
+//! * [`StaleUploadCleanup`] removes uploads that have not seen any activity for a while.
+//! * [`GarbageCollection`] removes blobs not referenced by any stored manifest, optionally along
+//!   with replaced indices and the children of removed ones.
+//! * [`StorageUsage`] measures the bytes held by uploads, unreferenced and referenced blobs.
+//! * [`Archival`] moves the blobs of rarely pulled images into an archive.
+//! * [`Retention`] removes all but the newest tags of every image.
+//! * [`IntegrityCheck`] verifies that stored content still matches its digest.
+//! * [`StoragePressure`] collects garbage and prunes tags once storage fills up.
+//! * [`Scrubber`] verifies a sample of blobs while the registry is idle, quarantining corrupt ones.
+//! * [`Snapshots`] takes snapshots of the storage for backups, keeping the most recent ones.
+//!
+//! Every task run is reported through
+//! [`RegistryHooks::on_maintenance_completed`](crate::hooks::RegistryHooks::on_maintenance_completed)
+//! and counted in the registry [`Metrics`](crate::metrics::Metrics). A task is never run
+//! concurrently with itself; if a run is still in progress when the next one is due, the latter
+//! is skipped.
+//!
+//! Tasks can also be run once in the background, following their progress, see the
+//! [`operations`](crate::operations) module.
+
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::async_trait;
+use serde::Serialize;
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+use tracing::{error, info, warn};
+
+use crate::{
+    auth::MAINTENANCE_IDENTITY,
+    events::RegistryEvent,
+    operations::Progress,
+    storage::{self, Digest, FilesystemStorageError},
+    types::Manifest,
+    ContainerRegistry, ImageDigest, RegistryError,
+};
+
+/// A maintenance task.
+///
+/// Tasks are run by the [`MaintenanceScheduler`] or as an [`Operation`](crate::operations::Operation),
+/// but may also be run directly.
+#[async_trait]
+pub trait MaintenanceTask: Send + Sync {
+    /// Name of the task, used in logs and reports.
+    fn name(&self) -> &'static str;
+
+    /// Runs the task once against `registry`.
+    ///
+    /// Tasks should regularly report their counts through [`Progress::checkpoint`], which also
+    /// tells them to stop if the run has been cancelled.
+    async fn run(
+        &self,
+        registry: &ContainerRegistry,
+        progress: &Progress,
+    ) -> Result<TaskSummary, RegistryError>;
+}
+
+/// Summary of a single successful task run.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct TaskSummary {
+    /// Number of items (uploads, blobs, tags, ...) examined.
+    pub examined: u64,
+    /// Number of items removed.
+    pub removed: u64,
+    /// Number of bytes freed by removing items, if known.
+    pub bytes_reclaimed: u64,
+    /// Number of problems found, e.g. corrupted blobs.
+    pub problems: u64,
+}
+
+impl TaskSummary {
+    /// Adds the counts of `other` to this summary.
+    pub(crate) fn add(&mut self, other: &TaskSummary) {
+        self.examined += other.examined;
+        self.removed += other.removed;
+        self.bytes_reclaimed += other.bytes_reclaimed;
+        self.problems += other.problems;
+    }
+}
+
+/// Report of a single task run, passed to hooks.
+#[derive(Clone, Debug)]
+pub struct MaintenanceReport {
+    /// Name of the task that ran.
+    pub task: &'static str,
+    /// Wall clock time the run took.
+    pub duration: Duration,
+    /// The task's summary or, if it failed, a description of the error.
+    pub outcome: Result<TaskSummary, String>,
+}
+
+/// A task scheduled at a fixed interval.
+struct Job {
+    /// Time between two runs of the task.
+    interval: Duration,
+    /// The task to run.
+    task: Arc<dyn MaintenanceTask>,
+    /// Set while the task is running.
+    running: Arc<AtomicBool>,
+}
+
+/// Runs maintenance tasks on fixed intervals.
+///
+/// Tasks are added using [`MaintenanceScheduler::every`], afterwards [`MaintenanceScheduler::start`]
+/// spawns them onto the current tokio runtime. Each task is first run one interval after starting.
+pub struct MaintenanceScheduler {
+    /// The registry to maintain.
+    registry: Arc<ContainerRegistry>,
+    /// Scheduled tasks.
+    jobs: Vec<Job>,
+}
+
+impl MaintenanceScheduler {
+    /// Creates a new scheduler without any tasks.
+    pub fn new(registry: Arc<ContainerRegistry>) -> Self {
+        Self {
+            registry,
+            jobs: Vec::new(),
+        }
+    }
+
+    /// Schedules `task` to run every `interval`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn every<T: MaintenanceTask + 'static>(mut self, interval: Duration, task: T) -> Self {
+        assert!(!interval.is_zero(), "maintenance interval must not be zero");
+
+        self.jobs.push(Job {
+            interval,
+            task: Arc::new(task),
+            running: Arc::new(AtomicBool::new(false)),
+        });
+        self
+    }
+
+    /// Runs every scheduled task once, immediately, and returns their reports.
+    ///
+    /// Tasks that are currently running in the background are skipped.
+    pub async fn run_all_now(&self) -> Vec<MaintenanceReport> {
+        let mut reports = Vec::new();
+        for job in &self.jobs {
+            if let Some(report) =
+                run_exclusive(&self.registry, job.task.as_ref(), &job.running).await
+            {
+                reports.push(report);
+            }
+        }
+        reports
+    }
+
+    /// Starts running all scheduled tasks in the background.
+    ///
+    /// Must be called from within a tokio runtime. Tasks keep running until the returned handle is
+    /// dropped.
+    pub fn start(self) -> MaintenanceHandle {
+        let workers = self
+            .jobs
+            .into_iter()
+            .map(|job| {
+                let registry = self.registry.clone();
+                tokio::spawn(async move {
+                    let start = tokio::time::Instant::now() + job.interval;
+                    let mut ticks = tokio::time::interval_at(start, job.interval);
+                    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+                    loop {
+                        ticks.tick().await;
+
+                        // Runs are spawned, so that a slow run does not delay the ticker, which
+                        // allows us to detect (and skip) overlapping runs.
+                        let registry = registry.clone();
+                        let task = job.task.clone();
+                        let running = job.running.clone();
+                        tokio::spawn(async move {
+                            run_exclusive(&registry, task.as_ref(), &running).await;
+                        });
+                    }
+                })
+            })
+            .collect();
+
+        MaintenanceHandle { workers }
+    }
+}
+
+/// Handle to a started [`MaintenanceScheduler`].
+///
+/// Dropping the handle stops scheduling further runs; runs already in progress are completed.
+#[derive(Debug)]
+pub struct MaintenanceHandle {
+    /// Background tasks driving the individual jobs.
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Drop for MaintenanceHandle {
+    fn drop(&mut self) {
+        for worker in &self.workers {
+            worker.abort();
+        }
+    }
+}
+
+/// Runs a task, unless it is already running.
+///
+/// Returns `None` if the run was skipped, see [`run_reported`].
+async fn run_exclusive(
+    registry: &ContainerRegistry,
+    task: &dyn MaintenanceTask,
+    running: &AtomicBool,
+) -> Option<MaintenanceReport> {
+    if running.swap(true, Ordering::AcqRel) {
+        registry.metrics.maintenance_runs_skipped.inc();
+        warn!(
+            task = task.name(),
+            "previous maintenance run still in progress, skipping"
+        );
+        return None;
+    }
+
+    // Reset the flag even if the task panics.
+    struct Release<'a>(&'a AtomicBool);
+    impl Drop for Release<'_> {
+        fn drop(&mut self) {
+            self.0.store(false, Ordering::Release);
+        }
+    }
+    let _release = Release(running);
+
+    Some(run_reported(registry, task, &Progress::new()).await)
+}
+
+/// Runs a task, reporting the outcome through hooks, logs and metrics.
+pub(crate) async fn run_reported(
+    registry: &ContainerRegistry,
+    task: &dyn MaintenanceTask,
+    progress: &Progress,
+) -> MaintenanceReport {
+    let started = Instant::now();
+    let outcome = task.run(registry, progress).await;
+    let duration = started.elapsed();
+
+    registry.metrics.maintenance_runs.inc();
+    let outcome = match outcome {
+        Ok(summary) => {
+            registry
+                .metrics
+                .maintenance_bytes_reclaimed
+                .add(summary.bytes_reclaimed);
+            info!(
+                task = task.name(),
+                actor = MAINTENANCE_IDENTITY,
+                ?duration,
+                ?summary,
+                "maintenance task completed"
+            );
+            Ok(summary)
+        }
+        Err(err) => {
+            registry.metrics.maintenance_failures.inc();
+            error!(
+                task = task.name(),
+                actor = MAINTENANCE_IDENTITY,
+                ?duration,
+                %err,
+                "maintenance task failed"
+            );
+            Err(err.to_string())
+        }
+    };
+
+    let report = MaintenanceReport {
+        task: task.name(),
+        duration,
+        outcome,
+    };
+
+    registry
+        .run_hook(
+            "on_maintenance_completed",
+            registry.hooks.on_maintenance_completed(&report),
+        )
+        .await;
+    registry.events.publish(RegistryEvent::from(&report));
+
+    report
+}
+
+/// Grace period of garbage collections started without one through the administrative API.
+///
+/// Blobs are uploaded before the manifest referencing them, so collecting recent blobs would
+/// break pushes in progress. [Purges](crate::purge) spare blobs younger than this as well.
+pub(crate) const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Returns whether something last modified at `modified` is older than `age` at `now`.
+///
+/// Timestamps in the future are treated as fresh.
+pub(crate) fn is_older_than(now: SystemTime, modified: SystemTime, age: Duration) -> bool {
+    now.duration_since(modified)
+        .map(|elapsed| elapsed >= age)
+        .unwrap_or(false)
+}
+
+/// Removes uploads that have not been written to for a given time.
+#[derive(Debug)]
+pub struct StaleUploadCleanup {
+    /// Time without activity after which an upload is removed.
+    max_age: Duration,
+}
+
+impl StaleUploadCleanup {
+    /// Creates a new cleanup task, removing uploads inactive for longer than `max_age`.
+    pub fn new(max_age: Duration) -> Self {
+        Self { max_age }
+    }
+}
+
+#[async_trait]
+impl MaintenanceTask for StaleUploadCleanup {
+    fn name(&self) -> &'static str {
+        "stale_upload_cleanup"
+    }
+
+    async fn run(
+        &self,
+        registry: &ContainerRegistry,
+        progress: &Progress,
+    ) -> Result<TaskSummary, RegistryError> {
+        let mut summary = TaskSummary::default();
+
+        let uploads = registry.storage.list_uploads().await?;
+        progress.set_total(uploads.len() as u64);
+
+        for upload in uploads {
+            progress.checkpoint(&summary)?;
+            summary.examined += 1;
+
+            if !is_older_than(registry.clock.now(), upload.modified, self.max_age) {
+                continue;
+            }
+
+            match registry.storage.cancel_upload(upload.upload).await {
+                // Someone else finished or removed it in the meantime.
+                Err(storage::Error::UploadDoesNotExit) => continue,
+                res => res?,
+            }
+            registry.upload_sessions.close(upload.upload);
+
+            summary.removed += 1;
+            summary.bytes_reclaimed += upload.size;
+        }
+
+        Ok(summary)
+    }
+}
+
+/// Manifests that may have been left dangling, see [`GarbageCollection::dangling_manifests`].
+///
+/// Recorded in the `dangling` directory of the storage, one empty file per manifest, so they are
+/// still collected after a restart.
+#[derive(Debug)]
+pub(crate) struct DanglingManifests {
+    /// Directory holding the records.
+    root: PathBuf,
+}
+
+impl DanglingManifests {
+    /// Opens the records kept in `storage`, creating their directory if necessary.
+    pub(crate) fn open(storage: &Path) -> Result<Self, FilesystemStorageError> {
+        let root = storage.join("dangling");
+        if !root.exists() {
+            std::fs::create_dir_all(&root).map_err(|err| {
+                FilesystemStorageError::FailedToCreateDir {
+                    path: root.clone(),
+                    err,
+                }
+            })?;
+        }
+
+        Ok(Self { root })
+    }
+
+    /// Records manifests that may no longer be referenced.
+    pub(crate) async fn record(&self, digests: impl IntoIterator<Item = Digest>) -> io::Result<()> {
+        for digest in digests {
+            tokio::fs::write(self.root.join(digest.to_string()), b"").await?;
+        }
+        Ok(())
+    }
+
+    /// Lists the recorded manifests.
+    async fn list(&self) -> io::Result<Vec<Digest>> {
+        let mut entries = tokio::fs::read_dir(&self.root).await?;
+        let mut digests = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            digests.extend(entry.file_name().to_str().and_then(Digest::from_hex_str));
+        }
+        Ok(digests)
+    }
+
+    /// Removes the record of a manifest.
+    async fn forget(&self, digest: Digest) -> io::Result<()> {
+        match tokio::fs::remove_file(self.root.join(digest.to_string())).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Removes blobs that are not referenced by any stored manifest.
+///
+/// By default, only the blobs themselves are considered, manifests are never removed by this task.
+/// With [`GarbageCollection::dangling_manifests`], indices replaced under their tag are removed
+/// first, along with the manifests only they referred to, so the blobs of multi-platform images
+/// are freed once the index is replaced. To avoid racing with clients that upload blobs before
+/// pushing the manifest referencing them, blobs and manifests younger than a grace period are
+/// kept.
+#[derive(Debug)]
+pub struct GarbageCollection {
+    /// Minimum age of a blob or manifest before it is eligible for removal.
+    grace_period: Duration,
+    /// Whether to remove untagged indices and their dangling children.
+    dangling_manifests: bool,
+}
+
+impl GarbageCollection {
+    /// Creates a new garbage collection task, sparing blobs younger than `grace_period`.
+    pub fn new(grace_period: Duration) -> Self {
+        Self {
+            grace_period,
+            dangling_manifests: false,
+        }
+    }
+
+    /// Also removes replaced indices and the manifests left dangling by removed indices.
+    ///
+    /// An index whose tag has been moved to another manifest, e.g. because a new index has been
+    /// pushed under it, is removed along with its child manifests once it is no longer tagged.
+    /// Likewise, the children of indices removed by [`Retention`] are removed. Manifests still
+    /// tagged, part of another index, quarantined or younger than the grace period are kept, as
+    /// is everything they refer to. Referrers of removed manifests, e.g. signatures, are removed
+    /// as well. Manifests only ever pushed by digest are never removed, as clients may rely on
+    /// them.
+    pub fn dangling_manifests(mut self, enabled: bool) -> Self {
+        self.dangling_manifests = enabled;
+        self
+    }
+
+    /// Returns the recorded dangling manifests eligible for removal, along with the manifests
+    /// only they refer to.
+    ///
+    /// Records of manifests kept for good, e.g. because they have been tagged again, are dropped.
+    async fn find_dangling(
+        &self,
+        registry: &ContainerRegistry,
+        manifests: &HashMap<Digest, Manifest>,
+    ) -> Result<HashSet<Digest>, RegistryError> {
+        let candidates: HashSet<_> = registry
+            .dangling
+            .list()
+            .await
+            .map_err(|err| RegistryError::Storage(storage::Error::Io(err)))?
+            .into_iter()
+            .collect();
+
+        // Tagged, recently stored and quarantined manifests are kept, along with everything they
+        // refer to. So are manifests not recorded as dangling, e.g. indices pushed by digest.
+        let mut recent = HashSet::new();
+        let mut roots: Vec<Digest> = manifests
+            .keys()
+            .filter(|digest| !candidates.contains(digest))
+            .filter(|digest| matches!(manifests.get(digest), Some(Manifest::Index(_))))
+            .copied()
+            .collect();
+        for location in registry.storage.list_locations().await? {
+            for manifest in registry.storage.list_manifests(&location).await? {
+                if !is_older_than(registry.clock.now(), manifest.created, self.grace_period) {
+                    recent.insert(manifest.digest);
+                    roots.push(manifest.digest);
+                } else if !manifest.tags.is_empty() {
+                    roots.push(manifest.digest);
+                }
+            }
+        }
+        if let Some(ref quarantine) = registry.quarantine {
+            roots.extend(
+                quarantine
+                    .held_manifests()
+                    .await
+                    .map_err(|err| RegistryError::Storage(storage::Error::Io(err)))?,
+            );
+        }
+
+        let mut referrers: HashMap<Digest, Vec<Digest>> = HashMap::new();
+        for (digest, manifest) in manifests {
+            if let Some(subject) = manifest.subject().and_then(|s| s.parsed_digest().ok()) {
+                referrers.entry(subject).or_default().push(*digest);
+            }
+        }
+
+        let reachable = reachable_manifests(roots, manifests, &referrers);
+        let mut dangling = reachable_manifests(
+            candidates
+                .iter()
+                .copied()
+                .filter(|digest| !reachable.contains(digest)),
+            manifests,
+            &referrers,
+        );
+        dangling.retain(|digest| !reachable.contains(digest) && manifests.contains_key(digest));
+
+        // Recent candidates are looked at again by the next run.
+        for digest in candidates.difference(&recent) {
+            if !dangling.contains(digest) {
+                registry
+                    .dangling
+                    .forget(*digest)
+                    .await
+                    .map_err(|err| RegistryError::Storage(storage::Error::Io(err)))?;
+            }
+        }
+        Ok(dangling)
+    }
+}
+
+/// Reads and parses every stored manifest.
+///
+/// Returns `None` if a manifest cannot be parsed, counting it as a problem.
+async fn read_manifests(
+    registry: &ContainerRegistry,
+    progress: &Progress,
+    summary: &mut TaskSummary,
+) -> Result<Option<HashMap<Digest, Manifest>>, RegistryError> {
+    let mut manifests = HashMap::new();
+    for manifest_digest in registry.storage.list_manifest_digests().await? {
+        progress.checkpoint(summary)?;
+
+        let Some(raw) = registry
+            .storage
+            .get_manifest(&digest_reference(manifest_digest))
+            .await?
+        else {
+            continue;
+        };
+
+        match Manifest::from_slice(&raw) {
+            Ok(manifest) => {
+                manifests.insert(manifest_digest, manifest);
+            }
+            Err(err) => {
+                error!(manifest = %manifest_digest, %err, "unparsable manifest");
+                summary.problems += 1;
+                return Ok(None);
+            }
+        }
+    }
+    Ok(Some(manifests))
+}
+
+/// Returns the blobs referenced by `manifests`, `None` if a reference is not a valid digest.
+fn referenced_blobs<'a>(
+    manifests: impl IntoIterator<Item = (&'a Digest, &'a Manifest)>,
+    summary: &mut TaskSummary,
+) -> Option<HashSet<Digest>> {
+    let mut referenced = HashSet::new();
+    for (manifest_digest, manifest) in manifests {
+        // Indices reference manifests only, never blobs.
+        let Manifest::Image(image) = manifest else {
+            continue;
+        };
+
+        for descriptor in std::iter::once(image.config()).chain(image.layers()) {
+            match descriptor.parsed_digest() {
+                Ok(digest) => {
+                    referenced.insert(digest);
+                }
+                Err(err) => {
+                    error!(manifest = %manifest_digest, %err, "manifest references invalid digest");
+                    summary.problems += 1;
+                    return None;
+                }
+            }
+        }
+    }
+    Some(referenced)
+}
+
+/// Returns `roots` along with all manifests they refer to, as index or subject, transitively.
+fn reachable_manifests(
+    roots: impl IntoIterator<Item = Digest>,
+    manifests: &HashMap<Digest, Manifest>,
+    referrers: &HashMap<Digest, Vec<Digest>>,
+) -> HashSet<Digest> {
+    let mut reachable = HashSet::new();
+    let mut pending: Vec<Digest> = roots.into_iter().collect();
+    while let Some(digest) = pending.pop() {
+        if !reachable.insert(digest) {
+            continue;
+        }
+
+        if let Some(Manifest::Index(index)) = manifests.get(&digest) {
+            pending.extend(
+                index
+                    .manifests()
+                    .iter()
+                    .filter_map(|child| child.parsed_digest().ok()),
+            );
+        }
+        if let Some(referrers) = referrers.get(&digest) {
+            pending.extend(referrers);
+        }
+    }
+    reachable
+}
+
+#[async_trait]
+impl MaintenanceTask for GarbageCollection {
+    fn name(&self) -> &'static str {
+        "garbage_collection"
+    }
+
+    async fn run(
+        &self,
+        registry: &ContainerRegistry,
+        progress: &Progress,
+    ) -> Result<TaskSummary, RegistryError> {
+        let mut summary = TaskSummary::default();
+
+        // Nothing is safe to remove if a manifest cannot be parsed.
+        let Some(mut manifests) = read_manifests(registry, progress, &mut summary).await? else {
+            error!("unparsable manifest, aborting garbage collection");
+            return Ok(summary);
+        };
+
+        if self.dangling_manifests {
+            for digest in self.find_dangling(registry, &manifests).await? {
+                progress.checkpoint(&summary)?;
+
+                registry.storage.delete_manifest(digest).await?;
+                info!(manifest = %digest, actor = MAINTENANCE_IDENTITY, "removed dangling manifest");
+                registry
+                    .dangling
+                    .forget(digest)
+                    .await
+                    .map_err(|err| RegistryError::Storage(storage::Error::Io(err)))?;
+                manifests.remove(&digest);
+                summary.removed += 1;
+            }
+        }
+
+        // Mark: Collect every blob referenced by a stored manifest. We cannot tell what an
+        // invalid digest refers to, so better not sweep at all then.
+        let Some(referenced) = referenced_blobs(&manifests, &mut summary) else {
+            error!("aborting garbage collection");
+            return Ok(summary);
+        };
+
+        // Sweep: Remove all unreferenced blobs that are old enough.
+        let blobs = registry.storage.list_blobs().await?;
+        progress.set_total(blobs.len() as u64);
+
+        for blob in blobs {
+            progress.checkpoint(&summary)?;
+            summary.examined += 1;
+
+            if referenced.contains(&blob.digest())
+                || !is_older_than(registry.clock.now(), blob.modified(), self.grace_period)
+            {
+                continue;
+            }
+
+            registry.storage.delete_blob(blob.digest()).await?;
+            summary.removed += 1;
+            summary.bytes_reclaimed += blob.size();
+        }
+
+        // Archived blobs are no longer part of regular storage, they are swept separately.
+        for (digest, size) in registry.archived_blobs().await? {
+            progress.checkpoint(&summary)?;
+            summary.examined += 1;
+
+            if referenced.contains(&digest) {
+                continue;
+            }
+
+            registry.discard_archived_blob(digest).await?;
+            summary.removed += 1;
+            summary.bytes_reclaimed += size;
+        }
+
+        Ok(summary)
+    }
+}
+
+/// Measures the bytes held by uploads in progress, unreferenced and referenced blobs.
+///
+/// Nothing is removed, the results are exposed as gauges in the registry
+/// [`Metrics`](crate::metrics::Metrics). Blob sizes are left as last measured if a manifest cannot
+/// be parsed, as it is unknown which blobs it refers to.
+#[derive(Debug, Default)]
+pub struct StorageUsage;
+
+impl StorageUsage {
+    /// Creates a new storage usage task.
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+#[async_trait]
+impl MaintenanceTask for StorageUsage {
+    fn name(&self) -> &'static str {
+        "storage_usage"
+    }
+
+    async fn run(
+        &self,
+        registry: &ContainerRegistry,
+        progress: &Progress,
+    ) -> Result<TaskSummary, RegistryError> {
+        let mut summary = TaskSummary::default();
+
+        let uploads = registry.storage.list_uploads().await?;
+        summary.examined += uploads.len() as u64;
+        registry
+            .metrics
+            .upload_bytes
+            .set(uploads.iter().map(|upload| upload.size).sum());
+
+        let Some(manifests) = read_manifests(registry, progress, &mut summary).await? else {
+            return Ok(summary);
+        };
+        let Some(referenced) = referenced_blobs(&manifests, &mut summary) else {
+            return Ok(summary);
+        };
+
+        let (mut referenced_bytes, mut unreferenced_bytes) = (0, 0);
+        for blob in registry.storage.list_blobs().await? {
+            summary.examined += 1;
+            if referenced.contains(&blob.digest()) {
+                referenced_bytes += blob.size();
+            } else {
+                unreferenced_bytes += blob.size();
+            }
+        }
+        registry.metrics.referenced_blob_bytes.set(referenced_bytes);
+        registry
+            .metrics
+            .unreferenced_blob_bytes
+            .set(unreferenced_bytes);
+
+        Ok(summary)
+    }
+}
+
+/// Moves the blobs of rarely used tags into the archive.
+///
+/// A manifest is idle once it has been neither pushed nor pulled for a given time. Blobs only
+/// referenced by idle manifests, directly or through an index, are moved into the
+/// [`ArchiveStore`](crate::archive::ArchiveStore) set through
+/// [`ContainerRegistryBuilder::archive_storage`](crate::ContainerRegistryBuilder::archive_storage),
+/// see the [`archive`](crate::archive) module. Blobs not referenced at all are left to
+/// [`GarbageCollection`]. Fails if no archive has been set.
+#[derive(Debug)]
+pub struct Archival {
+    /// Time without pushes or pulls after which a manifest is idle.
+    idle: Duration,
+    /// Repositories to archive, all if empty.
+    repositories: HashSet<String>,
+}
+
+impl Archival {
+    /// Creates a new archival task, archiving images idle for longer than `idle`.
+    pub fn new(idle: Duration) -> Self {
+        Self {
+            idle,
+            repositories: HashSet::new(),
+        }
+    }
+
+    /// Only archives images in `repository`.
+    ///
+    /// Can be called multiple times to archive several repositories, blobs shared with images
+    /// elsewhere are never archived.
+    pub fn repository(mut self, repository: impl Into<String>) -> Self {
+        self.repositories.insert(repository.into());
+        self
+    }
+
+    /// Returns the manifests in use, i.e. those not idle and those outside archived repositories.
+    async fn find_active(
+        &self,
+        registry: &ContainerRegistry,
+        manifests: &HashMap<Digest, Manifest>,
+    ) -> Result<HashSet<Digest>, RegistryError> {
+        let archive = registry
+            .archive
+            .as_ref()
+            .ok_or(RegistryError::NotSupported("archive storage"))?;
+        let now = registry.clock.now();
+
+        let mut roots = Vec::new();
+        let mut last_pulls = HashMap::new();
+        for location in registry.storage.list_locations().await? {
+            let archived =
+                self.repositories.is_empty() || self.repositories.contains(location.repository());
+            for manifest in registry.storage.list_manifests(&location).await? {
+                let last_pull = match last_pulls.get(&manifest.digest) {
+                    Some(last_pull) => *last_pull,
+                    None => {
+                        let last_pull = archive
+                            .last_pull(manifest.digest)
+                            .await
+                            .map_err(storage::Error::Io)?;
+                        last_pulls.insert(manifest.digest, last_pull);
+                        last_pull
+                    }
+                };
+
+                if !archived
+                    || !is_older_than(now, manifest.created, self.idle)
+                    || last_pull.is_some_and(|pulled| !is_older_than(now, pulled, self.idle))
+                {
+                    roots.push(manifest.digest);
+                }
+            }
+        }
+
+        let mut referrers: HashMap<Digest, Vec<Digest>> = HashMap::new();
+        for (digest, manifest) in manifests {
+            if let Some(subject) = manifest.subject().and_then(|s| s.parsed_digest().ok()) {
+                referrers.entry(subject).or_default().push(*digest);
+            }
+        }
+
+        Ok(reachable_manifests(roots, manifests, &referrers))
+    }
+}
+
+#[async_trait]
+impl MaintenanceTask for Archival {
+    fn name(&self) -> &'static str {
+        "archival"
+    }
+
+    async fn run(
+        &self,
+        registry: &ContainerRegistry,
+        progress: &Progress,
+    ) -> Result<TaskSummary, RegistryError> {
+        let mut summary = TaskSummary::default();
+
+        let Some(manifests) = read_manifests(registry, progress, &mut summary).await? else {
+            error!("aborting archival");
+            return Ok(summary);
+        };
+        let active = self.find_active(registry, &manifests).await?;
+
+        let (Some(referenced), Some(in_use)) = (
+            referenced_blobs(&manifests, &mut summary),
+            referenced_blobs(
+                manifests
+                    .iter()
+                    .filter(|(digest, _)| active.contains(digest)),
+                &mut summary,
+            ),
+        ) else {
+            error!("aborting archival");
+            return Ok(summary);
+        };
+
+        let blobs = registry.storage.list_blobs().await?;
+        progress.set_total(blobs.len() as u64);
+
+        for blob in blobs {
+            progress.checkpoint(&summary)?;
+            summary.examined += 1;
+
+            if !referenced.contains(&blob.digest())
+                || in_use.contains(&blob.digest())
+                || !is_older_than(registry.clock.now(), blob.modified(), self.idle)
+            {
+                continue;
+            }
+
+            registry.archive_blob(blob.digest(), blob.size()).await?;
+            summary.removed += 1;
+            summary.bytes_reclaimed += blob.size();
+        }
+
+        Ok(summary)
+    }
+}
+
+/// Keeps only the most recently updated tags of every image.
+///
+/// Manifests only reachable through a removed tag are removed as well, unless another tag or a
+/// tagged index still refers to them. The children of removed indices are only removed with
+/// [`Retention::index_children`], otherwise they are left to
+/// [`GarbageCollection::dangling_manifests`]. Blobs are left to [`GarbageCollection`].
+#[derive(Debug)]
+pub struct Retention {
+    /// Number of tags to keep per image.
+    keep_last: usize,
+    /// Whether to remove the children of removed indices.
+    index_children: bool,
+}
+
+impl Retention {
+    /// Creates a new retention task, keeping the `keep_last` most recently updated tags per image.
+    pub fn keep_last(keep_last: usize) -> Self {
+        Self {
+            keep_last,
+            index_children: false,
+        }
+    }
+
+    /// Also removes the child manifests of removed indices, unless still referenced elsewhere.
+    ///
+    /// Children may be pulled by digest even if their index is gone, so this is disabled by
+    /// default.
+    pub fn index_children(mut self, enabled: bool) -> Self {
+        self.index_children = enabled;
+        self
+    }
+}
+
+#[async_trait]
+impl MaintenanceTask for Retention {
+    fn name(&self) -> &'static str {
+        "retention"
+    }
+
+    async fn run(
+        &self,
+        registry: &ContainerRegistry,
+        progress: &Progress,
+    ) -> Result<TaskSummary, RegistryError> {
+        let mut summary = TaskSummary::default();
+        let mut candidates = HashSet::new();
+        let mut live = HashSet::new();
+
+        for location in registry.storage.list_locations().await? {
+            progress.checkpoint(&summary)?;
+
+            let mut tags = registry.storage.list_tags(&location).await?;
+            summary.examined += tags.len() as u64;
+
+            // Newest first.
+            tags.sort_by_key(|tag| Reverse(tag.modified));
+
+            for (idx, tag) in tags.into_iter().enumerate() {
+                if idx < self.keep_last {
+                    live.insert(tag.digest);
+                    continue;
+                }
+
+                registry.storage.delete_tag(&location, &tag.tag).await?;
+                info!(
+                    %location,
+                    tag = tag.tag,
+                    actor = MAINTENANCE_IDENTITY,
+                    "removed tag due to retention policy"
+                );
+                registry.events.publish(RegistryEvent::TagDeleted {
+                    location: location.clone(),
+                    tag: tag.tag,
+                    actor: Some(MAINTENANCE_IDENTITY.to_owned()),
+                });
+                summary.removed += 1;
+                candidates.insert(tag.digest);
+            }
+        }
+
+        // Manifests that are part of a kept index must stay around.
+        for digest in live.clone() {
+            let Some(raw) = registry
+                .storage
+                .get_manifest(&digest_reference(digest))
+                .await?
+            else {
+                continue;
+            };
+
+            if let Ok(Manifest::Index(index)) = Manifest::from_slice(&raw) {
+                live.extend(
+                    index
+                        .manifests()
+                        .iter()
+                        .filter_map(|child| child.parsed_digest().ok()),
+                );
+            }
+        }
+
+        // Children of removed indices would dangle, they go along unless still live. Otherwise,
+        // they are recorded for garbage collection.
+        let mut pending: Vec<Digest> = candidates.difference(&live).copied().collect();
+        let mut children = Vec::new();
+        while let Some(digest) = pending.pop() {
+            let Some(raw) = registry
+                .storage
+                .get_manifest(&digest_reference(digest))
+                .await?
+            else {
+                continue;
+            };
+
+            if let Ok(Manifest::Index(index)) = Manifest::from_slice(&raw) {
+                for child in index
+                    .manifests()
+                    .iter()
+                    .filter_map(|child| child.parsed_digest().ok())
+                {
+                    if !self.index_children {
+                        children.push(child);
+                    } else if !live.contains(&child) && candidates.insert(child) {
+                        pending.push(child);
+                    }
+                }
+            }
+        }
+
+        registry
+            .dangling
+            .record(children)
+            .await
+            .map_err(|err| RegistryError::Storage(storage::Error::Io(err)))?;
+        for digest in candidates.difference(&live) {
+            registry.storage.delete_manifest(*digest).await?;
+        }
+
+        Ok(summary)
+    }
+}
+
+/// Verifies that stored blobs and manifests still match their digests.
+///
+/// Mismatches are logged and counted as problems, but nothing is removed.
+#[derive(Debug, Default)]
+pub struct IntegrityCheck;
+
+impl IntegrityCheck {
+    /// Creates a new integrity check task.
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+#[async_trait]
+impl MaintenanceTask for IntegrityCheck {
+    fn name(&self) -> &'static str {
+        "integrity_check"
+    }
+
+    async fn run(
+        &self,
+        registry: &ContainerRegistry,
+        progress: &Progress,
+    ) -> Result<TaskSummary, RegistryError> {
+        let mut summary = TaskSummary::default();
+
+        let blobs = registry.storage.list_blobs().await?;
+        let manifests = registry.storage.list_manifest_digests().await?;
+        progress.set_total((blobs.len() + manifests.len()) as u64);
+
+        for blob in blobs {
+            progress.checkpoint(&summary)?;
+            summary.examined += 1;
+
+            let Some(reader) = registry.storage.get_blob_reader(blob.digest()).await? else {
+                // Removed in the meantime.
+                continue;
+            };
+
+            let actual = storage::hash_reader(reader).await?;
+            if actual != blob.digest() {
+                error!(expected = %blob.digest(), %actual, "blob is corrupted");
+                summary.problems += 1;
+            }
+        }
+
+        for digest in manifests {
+            progress.checkpoint(&summary)?;
+            summary.examined += 1;
+
+            let Some(raw) = registry
+                .storage
+                .get_manifest(&digest_reference(digest))
+                .await?
+            else {
+                continue;
+            };
+
+            let actual = Digest::from_contents(&raw);
+            if actual != digest {
+                error!(expected = %digest, %actual, "manifest is corrupted");
+                summary.problems += 1;
+            }
+        }
+
+        Ok(summary)
+    }
+}
+
+/// Reclaims space once storage usage exceeds a high watermark.
+///
+/// Usage is determined through the storage backend. Runs below the high watermark do nothing.
+/// Above it, [`GarbageCollection`] is run first. If usage is still above the low watermark
+/// afterwards and retention has been enabled using [`StoragePressure::with_retention`], tags are
+/// pruned in rounds, each halving the number of tags kept per image (newest first) followed by
+/// another garbage collection, until usage drops below the low watermark or the configured minimum
+/// number of tags is reached.
+///
+/// Schedule it at a short interval to react to storage filling up, e.g. every minute. What was
+/// reclaimed is reported like for every other task.
+#[derive(Debug)]
+pub struct StoragePressure {
+    /// Usage in bytes above which space is reclaimed.
+    high_watermark: u64,
+    /// Usage in bytes to reclaim space down to.
+    low_watermark: u64,
+    /// Garbage collection run in every round.
+    gc: GarbageCollection,
+    /// Minimum number of tags to keep per image, if tags may be pruned.
+    min_tags: Option<usize>,
+}
+
+impl StoragePressure {
+    /// Creates a new task, reclaiming space down to `low_watermark` bytes once usage exceeds
+    /// `high_watermark` bytes.
+    ///
+    /// Blobs younger than `grace_period` are never collected, see [`GarbageCollection::new`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `low_watermark` exceeds `high_watermark`.
+    pub fn new(high_watermark: u64, low_watermark: u64, grace_period: Duration) -> Self {
+        assert!(
+            low_watermark <= high_watermark,
+            "low watermark must not exceed high watermark"
+        );
+
+        Self {
+            high_watermark,
+            low_watermark,
+            gc: GarbageCollection::new(grace_period),
+            min_tags: None,
+        }
+    }
+
+    /// Allows pruning tags if garbage collection alone does not free enough space, keeping at
+    /// least the `min_tags` most recently updated tags of every image.
+    pub fn with_retention(mut self, min_tags: usize) -> Self {
+        self.min_tags = Some(min_tags);
+        self
+    }
+
+    /// Returns the largest number of tags of any image.
+    async fn max_tags(registry: &ContainerRegistry) -> Result<usize, RegistryError> {
+        let mut max_tags = 0;
+        for location in registry.storage.list_locations().await? {
+            max_tags = max_tags.max(registry.storage.list_tags(&location).await?.len());
+        }
+        Ok(max_tags)
+    }
+}
+
+#[async_trait]
+impl MaintenanceTask for StoragePressure {
+    fn name(&self) -> &'static str {
+        "storage_pressure"
+    }
+
+    async fn run(
+        &self,
+        registry: &ContainerRegistry,
+        progress: &Progress,
+    ) -> Result<TaskSummary, RegistryError> {
+        let mut summary = TaskSummary::default();
+
+        let mut usage = registry.storage.usage().await?;
+        if usage <= self.high_watermark {
+            return Ok(summary);
+        }
+
+        registry.metrics.storage_pressure_runs.inc();
+        warn!(
+            usage,
+            high_watermark = self.high_watermark,
+            "storage usage above high watermark, reclaiming space"
+        );
+
+        let mut keep_last = match self.min_tags {
+            Some(_) => Some(Self::max_tags(registry).await?),
+            None => None,
+        };
+
+        // Nested runs report their own counts.
+        loop {
+            progress.checkpoint(&summary)?;
+
+            let collected = self.gc.run(registry, progress).await?;
+            summary.add(&collected);
+
+            usage = registry.storage.usage().await?;
+            // Garbage collection aborts on problems, pruning more tags would not help then.
+            if usage <= self.low_watermark || collected.problems > 0 {
+                break;
+            }
+
+            let (Some(min_tags), Some(current)) = (self.min_tags, keep_last) else {
+                break;
+            };
+            if current <= min_tags {
+                break;
+            }
+
+            let next = (current / 2).max(min_tags);
+            keep_last = Some(next);
+            summary.add(&Retention::keep_last(next).run(registry, progress).await?);
+        }
+
+        if usage > self.low_watermark {
+            warn!(
+                usage,
+                low_watermark = self.low_watermark,
+                "could not reclaim enough space"
+            );
+        } else {
+            info!(
+                usage,
+                bytes_reclaimed = summary.bytes_reclaimed,
+                "reclaimed space"
+            );
+        }
+
+        Ok(summary)
+    }
+}
+
+/// Verifies a sample of blobs while the registry is idle.
+///
+/// Unlike [`IntegrityCheck`], which reads every blob in one go, the scrubber spreads verification
+/// over time: Every hour, a configurable fraction of all blobs is verified, in digest order and
+/// continuing where the previous run stopped, so that every blob is eventually covered. Runs while
+/// the registry is busy, i.e. receives more requests per second than a threshold, are skipped;
+/// the blobs they would have verified are caught up on by the next idle run.
+///
+/// Corrupted blobs are moved to quarantine, reported through
+/// [`RegistryHooks::on_blob_corrupted`](crate::hooks::RegistryHooks::on_blob_corrupted) and
+/// counted as both removed and problems. The first run only takes measurements, schedule the task
+/// at a short interval, e.g. every few minutes.
+#[derive(Debug)]
+pub struct Scrubber {
+    /// Fraction of all blobs to verify per hour.
+    fraction_per_hour: f64,
+    /// Request rate per second above which the registry is considered busy.
+    busy_threshold: f64,
+    /// Progress carried over between runs.
+    state: Mutex<ScrubState>,
+}
+
+/// Progress of a [`Scrubber`].
+#[derive(Debug, Default)]
+struct ScrubState {
+    /// Time of the previous run and the request count at that time.
+    previous: Option<(Instant, u64)>,
+    /// Number of blobs due for verification, including fractions.
+    budget: f64,
+    /// Digest of the last blob verified.
+    cursor: Option<Digest>,
+}
+
+impl Scrubber {
+    /// Creates a new scrubber verifying `fraction_per_hour` of all blobs every hour, e.g. `0.01`
+    /// to verify every blob about every four days.
+    ///
+    /// By default, runs are skipped if more than one request per second was received since the
+    /// previous run.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `fraction_per_hour` is not positive.
+    pub fn new(fraction_per_hour: f64) -> Self {
+        assert!(fraction_per_hour > 0.0, "scrub fraction must be positive");
+
+        Self {
+            fraction_per_hour,
+            busy_threshold: 1.0,
+            state: Mutex::new(ScrubState::default()),
+        }
+    }
+
+    /// Sets the request rate per second above which the registry is considered busy.
+    pub fn busy_threshold(mut self, requests_per_second: f64) -> Self {
+        self.busy_threshold = requests_per_second;
+        self
+    }
+}
+
+#[async_trait]
+impl MaintenanceTask for Scrubber {
+    fn name(&self) -> &'static str {
+        "scrubber"
+    }
+
+    async fn run(
+        &self,
+        registry: &ContainerRegistry,
+        progress: &Progress,
+    ) -> Result<TaskSummary, RegistryError> {
+        let mut summary = TaskSummary::default();
+        let mut blobs = registry.storage.list_blobs().await?;
+        blobs.sort_by_key(|blob| blob.digest());
+
+        // Plan the run, without holding the lock while verifying.
+        let (count, cursor) = {
+            let mut state = self.state.lock().expect("lock poisoned");
+            let now = Instant::now();
+            let requests = registry.metrics.requests.get();
+
+            let Some((previous, previous_requests)) = state.previous.replace((now, requests))
+            else {
+                return Ok(summary);
+            };
+
+            let elapsed = now.duration_since(previous).as_secs_f64();
+            let due = self.fraction_per_hour * blobs.len() as f64 * elapsed / 3600.0;
+            // Never verify a blob twice in a single run.
+            state.budget = (state.budget + due).min(blobs.len() as f64);
+
+            let rate = requests.saturating_sub(previous_requests) as f64 / elapsed.max(1e-3);
+            if rate > self.busy_threshold {
+                info!(rate, "registry busy, postponing scrub");
+                return Ok(summary);
+            }
+
+            let count = state.budget.floor();
+            state.budget -= count;
+            (count as usize, state.cursor)
+        };
+
+        // Continue after the last blob verified, wrapping around.
+        let start = cursor
+            .map(|cursor| blobs.partition_point(|blob| blob.digest() <= cursor))
+            .unwrap_or(0);
+        let mut last = cursor;
+
+        progress.set_total(count as u64);
+        for blob in blobs.iter().cycle().skip(start).take(count) {
+            progress.checkpoint(&summary)?;
+
+            let digest = blob.digest();
+            last = Some(digest);
+
+            let Some(reader) = registry.storage.get_blob_reader(digest).await? else {
+                // Removed in the meantime.
+                continue;
+            };
+
+            summary.examined += 1;
+            registry.metrics.blobs_scrubbed.inc();
+
+            let actual = storage::hash_reader(reader).await?;
+            if actual == digest {
+                continue;
+            }
+
+            error!(expected = %digest, %actual, "blob is corrupted, moving to quarantine");
+            registry.storage.quarantine_blob(digest).await?;
+            registry.metrics.blobs_quarantined.inc();
+            summary.problems += 1;
+            summary.removed += 1;
+
+            registry
+                .run_hook(
+                    "on_blob_corrupted",
+                    registry.hooks.on_blob_corrupted(&ImageDigest::new(digest)),
+                )
+                .await;
+        }
+
+        self.state.lock().expect("lock poisoned").cursor = last;
+
+        Ok(summary)
+    }
+}
+
+/// Takes snapshots of the registry's storage, see [`ContainerRegistry::snapshot`].
+///
+/// Every run creates a snapshot named `snapshot-<unix time in milliseconds>` inside a directory,
+/// which has to be on the same filesystem as the storage, then removes all but the most recent
+/// snapshots. Snapshots are counted as examined and removed ones as removed; as they consist of
+/// hard links, removing them frees no space by itself.
+#[derive(Debug)]
+pub struct Snapshots {
+    /// Directory holding the snapshots.
+    directory: PathBuf,
+    /// Number of snapshots to keep.
+    keep: usize,
+}
+
+impl Snapshots {
+    /// Creates a new snapshot task, keeping the `keep` most recent snapshots in `directory`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `keep` is zero.
+    pub fn new<P: Into<PathBuf>>(directory: P, keep: usize) -> Self {
+        assert!(keep > 0, "must keep at least one snapshot");
+
+        Self {
+            directory: directory.into(),
+            keep,
+        }
+    }
+}
+
+#[async_trait]
+impl MaintenanceTask for Snapshots {
+    fn name(&self) -> &'static str {
+        "snapshots"
+    }
+
+    async fn run(
+        &self,
+        registry: &ContainerRegistry,
+        _progress: &Progress,
+    ) -> Result<TaskSummary, RegistryError> {
+        let mut summary = TaskSummary::default();
+
+        let now = registry
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        registry
+            .snapshot(self.directory.join(format!("snapshot-{now}")))
+            .await?;
+
+        let mut snapshots = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.directory)
+            .await
+            .map_err(storage::Error::Io)?;
+        while let Some(entry) = entries.next_entry().await.map_err(storage::Error::Io)? {
+            let taken_at = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix("snapshot-"))
+                .and_then(|millis| millis.parse::<u128>().ok());
+            if let Some(taken_at) = taken_at {
+                snapshots.push((taken_at, entry.path()));
+            }
+        }
+        summary.examined = snapshots.len() as u64;
+
+        snapshots.sort_unstable_by_key(|&(taken_at, _)| Reverse(taken_at));
+        for (_, path) in snapshots.into_iter().skip(self.keep) {
+            tokio::fs::remove_dir_all(&path)
+                .await
+                .map_err(storage::Error::Io)?;
+            info!(path = %path.display(), "removed old snapshot");
+            summary.removed += 1;
+        }
+
+        Ok(summary)
+    }
+}
+
+/// Builds a reference to a manifest by digest.
+///
+/// Storage only uses the location for tags, so an empty one suffices.
+pub(crate) fn digest_reference(digest: Digest) -> storage::ManifestReference {
+    storage::ManifestReference::new(
+        storage::ImageLocation::new(String::new(), String::new()),
+        storage::Reference::new_digest(digest),
+    )
+}
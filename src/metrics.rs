@@ -0,0 +1,419 @@
+//! Registry metrics.
+//!
+//! The registry keeps a set of counters about its own operation, which can be retrieved through
+//! [`ContainerRegistry::metrics`](crate::ContainerRegistry::metrics), either to be inspected
+//! directly or rendered in the Prometheus text exposition format using
+//! [`Metrics::render_prometheus`].
+//!
+//! Bytes on disk are broken down by state, i.e. held by uploads in progress, by blobs no manifest
+//! refers to or by referenced blobs, to tell abandoned uploads and leaks apart from legitimate
+//! growth. Measuring them requires reading every manifest, so they are only updated by the
+//! [`StorageUsage`](crate::maintenance::StorageUsage) maintenance task.
+//!
+//! Additionally, the latency and errors of every storage backend operation are recorded in
+//! [`StorageMetrics`], labeled by backend and operation, to tell slow storage apart from slow
+//! clients or networks.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::ContainerRegistry;
+
+/// A monotonically increasing counter.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    /// Increments the counter by one.
+    #[inline(always)]
+    pub(crate) fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Increments the counter by `n`.
+    #[inline(always)]
+    pub(crate) fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Returns the current value of the counter.
+    #[inline(always)]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that can go up and down.
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    /// Sets the gauge to `value`.
+    #[inline(always)]
+    pub(crate) fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    /// Returns the current value of the gauge.
+    #[inline(always)]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+pub const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0,
+];
+
+/// A histogram of durations, bucketed by [`LATENCY_BUCKETS`].
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// Number of observations per bucket, the last counting those exceeding all bounds.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    /// Sum of all observations, in nanoseconds.
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    /// Records a single observation.
+    pub(crate) fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(
+            duration.as_nanos().try_into().unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Returns the number of observations.
+    pub fn count(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Returns the sum of all observations.
+    pub fn sum(&self) -> Duration {
+        Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed))
+    }
+
+    /// Returns the number of observations per bucket of [`LATENCY_BUCKETS`], cumulatively.
+    ///
+    /// Observations exceeding the largest bound are only included in [`Histogram::count`].
+    pub fn cumulative_counts(&self) -> [u64; LATENCY_BUCKETS.len()] {
+        let mut total = 0;
+        std::array::from_fn(|idx| {
+            total += self.buckets[idx].load(Ordering::Relaxed);
+            total
+        })
+    }
+}
+
+/// Metrics of a single operation of a storage backend.
+#[derive(Debug, Default)]
+pub struct OperationMetrics {
+    /// Time taken by the operation, including failed invocations.
+    pub latency: Histogram,
+    /// Number of invocations that returned an error.
+    pub errors: Counter,
+}
+
+/// Metrics of storage backend operations, labeled by backend and operation.
+#[derive(Debug, Default)]
+pub struct StorageMetrics {
+    /// Metrics by backend and operation name.
+    operations: Mutex<BTreeMap<(&'static str, &'static str), Arc<OperationMetrics>>>,
+}
+
+impl StorageMetrics {
+    /// Returns the metrics of `operation` on `backend`, if it was ever invoked.
+    ///
+    /// Backends are named `filesystem` and `tiered`, operations after the storage method invoked,
+    /// e.g. `get_blob_reader` or `put_manifest`.
+    pub fn get(&self, backend: &str, operation: &str) -> Option<Arc<OperationMetrics>> {
+        self.operations
+            .lock()
+            .expect("lock poisoned")
+            .get(&(backend, operation))
+            .cloned()
+    }
+
+    /// Returns the metrics of `operation` on `backend`, creating them if necessary.
+    pub(crate) fn operation(
+        &self,
+        backend: &'static str,
+        operation: &'static str,
+    ) -> Arc<OperationMetrics> {
+        self.operations
+            .lock()
+            .expect("lock poisoned")
+            .entry((backend, operation))
+            .or_default()
+            .clone()
+    }
+
+    /// Renders all storage metrics in the Prometheus text exposition format.
+    fn render_prometheus(&self, out: &mut String) {
+        let operations = self.operations.lock().expect("lock poisoned").clone();
+
+        let latency = "container_registry_storage_operation_duration_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {latency} Time taken by storage backend operations."
+        );
+        let _ = writeln!(out, "# TYPE {latency} histogram");
+        for ((backend, operation), metrics) in &operations {
+            let labels = format!(r#"backend="{backend}",operation="{operation}""#);
+            let counts = metrics.latency.cumulative_counts();
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(counts) {
+                let _ = writeln!(out, r#"{latency}_bucket{{{labels},le="{bound}"}} {count}"#);
+            }
+            let count = metrics.latency.count();
+            let _ = writeln!(out, r#"{latency}_bucket{{{labels},le="+Inf"}} {count}"#);
+            let sum = metrics.latency.sum().as_secs_f64();
+            let _ = writeln!(out, "{latency}_sum{{{labels}}} {sum}");
+            let _ = writeln!(out, "{latency}_count{{{labels}}} {count}");
+        }
+
+        let errors = "container_registry_storage_operation_errors_total";
+        let _ = writeln!(
+            out,
+            "# HELP {errors} Storage backend operations that failed."
+        );
+        let _ = writeln!(out, "# TYPE {errors} counter");
+        for ((backend, operation), metrics) in &operations {
+            let _ = writeln!(
+                out,
+                r#"{errors}{{backend="{backend}",operation="{operation}"}} {}"#,
+                metrics.errors.get()
+            );
+        }
+    }
+}
+
+/// Metrics collected by a registry.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Number of HTTP requests received.
+    pub requests: Counter,
+    /// Number of hook invocations that did not complete within the configured timeout.
+    pub hook_timeouts: Counter,
+    /// Number of hook invocations that panicked.
+    pub hook_panics: Counter,
+    /// Number of requests with credentials refused by the auth provider.
+    pub auth_failures: Counter,
+    /// Number of times a user or client address was locked out after failing to authenticate.
+    pub auth_lockouts: Counter,
+    /// Number of requests refused because their user or client address was locked out.
+    pub auth_locked_out_requests: Counter,
+    /// Number of upload sessions rejected due to the per-user session limit.
+    pub upload_sessions_rejected: Counter,
+    /// Number of completed maintenance task runs, including failed ones.
+    pub maintenance_runs: Counter,
+    /// Number of maintenance task runs that failed.
+    pub maintenance_failures: Counter,
+    /// Number of maintenance task runs skipped because the previous run was still in progress.
+    pub maintenance_runs_skipped: Counter,
+    /// Number of bytes freed by maintenance tasks.
+    pub maintenance_bytes_reclaimed: Counter,
+    /// Number of maintenance runs that found storage usage above the high watermark.
+    pub storage_pressure_runs: Counter,
+    /// Number of blobs verified by the scrubber.
+    pub blobs_scrubbed: Counter,
+    /// Number of corrupted blobs moved to quarantine.
+    pub blobs_quarantined: Counter,
+    /// Number of manifests found not to match the digest they were requested by.
+    pub manifests_corrupted: Counter,
+    /// Number of SBOMs generated and attached to pushed images.
+    pub sboms_generated: Counter,
+    /// Number of failed attempts to generate or attach an SBOM.
+    pub sbom_generation_failures: Counter,
+    /// Number of attestations issued for pushed manifests.
+    pub attestations_issued: Counter,
+    /// Number of failed attempts to issue or attach an attestation.
+    pub attestation_failures: Counter,
+    /// Bytes held by uploads in progress, as last measured by
+    /// [`StorageUsage`](crate::maintenance::StorageUsage).
+    pub upload_bytes: Gauge,
+    /// Bytes held by blobs not referenced by any manifest, as last measured.
+    pub unreferenced_blob_bytes: Gauge,
+    /// Bytes held by blobs referenced by a manifest, as last measured.
+    pub referenced_blob_bytes: Gauge,
+    /// Latency and errors of storage backend operations.
+    pub storage: Arc<StorageMetrics>,
+}
+
+impl Metrics {
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        write_counter(
+            &mut out,
+            "container_registry_requests_total",
+            "HTTP requests received.",
+            &self.requests,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_hook_timeouts_total",
+            "Hook invocations that timed out.",
+            &self.hook_timeouts,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_hook_panics_total",
+            "Hook invocations that panicked.",
+            &self.hook_panics,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_auth_failures_total",
+            "Requests with credentials refused by the auth provider.",
+            &self.auth_failures,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_auth_lockouts_total",
+            "Users or client addresses locked out after failing to authenticate.",
+            &self.auth_lockouts,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_auth_locked_out_requests_total",
+            "Requests refused due to a locked out user or client address.",
+            &self.auth_locked_out_requests,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_upload_sessions_rejected_total",
+            "Upload sessions rejected due to the per-user session limit.",
+            &self.upload_sessions_rejected,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_maintenance_runs_total",
+            "Completed maintenance task runs, including failed ones.",
+            &self.maintenance_runs,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_maintenance_failures_total",
+            "Maintenance task runs that failed.",
+            &self.maintenance_failures,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_maintenance_runs_skipped_total",
+            "Maintenance task runs skipped due to a run still in progress.",
+            &self.maintenance_runs_skipped,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_maintenance_bytes_reclaimed_total",
+            "Bytes freed by maintenance tasks.",
+            &self.maintenance_bytes_reclaimed,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_storage_pressure_runs_total",
+            "Maintenance runs that found storage usage above the high watermark.",
+            &self.storage_pressure_runs,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_blobs_scrubbed_total",
+            "Blobs verified by the scrubber.",
+            &self.blobs_scrubbed,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_blobs_quarantined_total",
+            "Corrupted blobs moved to quarantine.",
+            &self.blobs_quarantined,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_manifests_corrupted_total",
+            "Manifests found not to match the digest they were requested by.",
+            &self.manifests_corrupted,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_sboms_generated_total",
+            "SBOMs generated and attached to pushed images.",
+            &self.sboms_generated,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_sbom_generation_failures_total",
+            "Failed attempts to generate or attach an SBOM.",
+            &self.sbom_generation_failures,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_attestations_issued_total",
+            "Attestations issued for pushed manifests.",
+            &self.attestations_issued,
+        );
+        write_counter(
+            &mut out,
+            "container_registry_attestation_failures_total",
+            "Failed attempts to issue or attach an attestation.",
+            &self.attestation_failures,
+        );
+        let usage = "container_registry_storage_bytes";
+        let _ = writeln!(
+            &mut out,
+            "# HELP {usage} Bytes on disk by state, as last measured."
+        );
+        let _ = writeln!(&mut out, "# TYPE {usage} gauge");
+        for (state, gauge) in [
+            ("uploading", &self.upload_bytes),
+            ("unreferenced", &self.unreferenced_blob_bytes),
+            ("referenced", &self.referenced_blob_bytes),
+        ] {
+            let _ = writeln!(&mut out, r#"{usage}{{state="{state}"}} {}"#, gauge.get());
+        }
+        self.storage.render_prometheus(&mut out);
+
+        out
+    }
+}
+
+/// Middleware counting incoming requests.
+pub(crate) async fn count_requests(
+    State(registry): State<Arc<ContainerRegistry>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    registry.metrics.requests.inc();
+    next.run(request).await
+}
+
+/// Writes a single counter in Prometheus text format.
+fn write_counter(out: &mut String, name: &str, help: &str, counter: &Counter) {
+    // Writing to a `String` cannot fail.
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} counter");
+    let _ = writeln!(out, "{name} {}", counter.get());
+}
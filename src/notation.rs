@@ -0,0 +1,880 @@
+//! Verification of Notary Project signatures on pull.
+//!
+//! Images signed with [`notation`](https://notaryproject.dev) carry their signatures as OCI
+//! artifacts of type [`NOTATION_SIGNATURE`] referring to the signed manifest. With a
+//! [`NotationVerifier`] configured through
+//! [`ContainerRegistryBuilder::notation_verifier`](crate::ContainerRegistryBuilder::notation_verifier),
+//! these signatures are evaluated against a notation trust policy every time a manifest is
+//! fetched, so untrusted images never reach clients.
+//!
+//! Trust policies use notation's `trustpolicy.json` format. As the registry does not know the
+//! names it is reached under, registry scopes name locations without the registry host: either a
+//! repository (e.g. `prod`), a single image (e.g. `prod/api`) or `*` for everything else. The most
+//! specific scope applies, locations not covered by any policy are not verified:
+//!
+//! ```json
+//! {
+//!   "version": "1.0",
+//!   "trustPolicies": [
+//!     {
+//!       "name": "production",
+//!       "registryScopes": ["prod"],
+//!       "signatureVerification": { "level": "strict" },
+//!       "trustStores": ["ca:acme-rockets"],
+//!       "trustedIdentities": ["x509.subject: C=US, O=acme-rockets.io, CN=releases"]
+//!     },
+//!     {
+//!       "name": "staging",
+//!       "registryScopes": ["staging"],
+//!       "signatureVerification": { "level": "audit" },
+//!       "trustStores": ["ca:acme-rockets"],
+//!       "trustedIdentities": ["*"]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Trust stores referenced by policies are supplied as PEM certificates through
+//! [`NotationVerifier::trust_store`].
+//!
+//! A manifest is trusted if one of its signatures passes all checks enforced by the policy's
+//! verification level:
+//!
+//! | Check          | `strict`  | `permissive` | `audit` |
+//! |----------------|-----------|--------------|---------|
+//! | `integrity`    | enforced  | enforced     | enforced |
+//! | `authenticity` | enforced  | enforced     | logged  |
+//! | `expiry`       | enforced  | logged       | logged  |
+//!
+//! Levels `strict` and `permissive` refuse to serve untrusted manifests with `403 Forbidden`,
+//! `audit` only logs them and `skip` disables verification. Actions of individual checks can be
+//! changed using `override`. The `authenticTimestamp` and `revocation` checks are not implemented
+//! and are always skipped; only JWS signature envelopes are supported.
+//!
+//! Manifests referring to another manifest, such as the signatures themselves, are always served.
+//! Image manifests listed in a trusted index at the same location are trusted as well, since
+//! clients pulling a multi-platform image fetch them by digest after verifying the index.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::Engine;
+use openssl::{
+    bn::BigNum,
+    ecdsa::EcdsaSig,
+    hash::MessageDigest,
+    pkey::{Id, PKey, Public},
+    rsa::Padding,
+    sign::{RsaPssSaltlen, Verifier},
+    stack::Stack,
+    x509::{store::X509StoreBuilder, X509StoreContext, X509},
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::storage::{Digest, ImageLocation};
+
+/// Artifact type of notation signatures.
+pub const NOTATION_SIGNATURE: &str = "application/vnd.cncf.notary.signature";
+
+/// Media type of JWS signature envelopes.
+pub const JWS_ENVELOPE: &str = "application/jose+json";
+
+/// Content type of the signed payload.
+const PAYLOAD_CONTENT_TYPE: &str = "application/vnd.cncf.notary.payload.v1+json";
+
+/// Maximum size of a signature envelope read for verification.
+pub(crate) const MAX_ENVELOPE_SIZE: u64 = 1024 * 1024;
+
+/// Supported version of the trust policy format.
+const POLICY_VERSION: &str = "1.0";
+
+/// Error setting up a [`NotationVerifier`].
+#[derive(Debug, Error)]
+pub enum NotationError {
+    /// The trust policy document could not be parsed.
+    #[error("could not parse trust policy")]
+    ParsePolicy(#[source] serde_json::Error),
+    /// The trust policy document is not valid.
+    #[error("invalid trust policy: {0}")]
+    InvalidPolicy(String),
+    /// Certificates of a trust store could not be loaded.
+    #[error("could not load certificates of trust store {name}")]
+    InvalidCertificates {
+        /// Name of the trust store.
+        name: String,
+        /// Underlying error.
+        #[source]
+        err: openssl::error::ErrorStack,
+    },
+}
+
+/// A trust policy document, as stored in notation's `trustpolicy.json`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PolicyDocument {
+    /// Version of the format.
+    version: String,
+    /// All policies.
+    trust_policies: Vec<PolicyEntry>,
+}
+
+/// A single trust policy within a document.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PolicyEntry {
+    /// Name of the policy.
+    name: String,
+    /// Locations the policy applies to.
+    registry_scopes: Vec<String>,
+    /// Verification level and overrides.
+    signature_verification: SignatureVerification,
+    /// Trust stores to verify certificate chains against, e.g. `ca:acme-rockets`.
+    #[serde(default)]
+    trust_stores: Vec<String>,
+    /// Identities trusted to sign, e.g. `x509.subject: CN=releases` or `*`.
+    #[serde(default)]
+    trusted_identities: Vec<String>,
+}
+
+/// Verification settings of a trust policy.
+#[derive(Debug, Deserialize)]
+struct SignatureVerification {
+    /// Level of verification.
+    level: Level,
+    /// Actions replacing the level's default for individual checks.
+    #[serde(default, rename = "override")]
+    overrides: HashMap<Check, Action>,
+}
+
+/// Verification level of a trust policy.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Level {
+    /// All checks are enforced.
+    Strict,
+    /// Expired signatures are accepted.
+    Permissive,
+    /// Untrusted manifests are logged, but served.
+    Audit,
+    /// Signatures are not verified.
+    Skip,
+}
+
+/// A check performed on signatures, which can be overridden.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(rename_all = "camelCase")]
+enum Check {
+    /// The certificate chain leads to a trusted root and the signer is a trusted identity.
+    Authenticity,
+    /// The signing time is backed by a timestamp. Not implemented.
+    AuthenticTimestamp,
+    /// The signature has not expired.
+    Expiry,
+    /// The signing certificate has not been revoked. Not implemented.
+    Revocation,
+}
+
+/// What to do if a check fails.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    /// Consider the signature invalid.
+    Enforce,
+    /// Log the failure, but accept the signature.
+    Log,
+    /// Do not perform the check.
+    Skip,
+}
+
+/// An identity trusted to sign.
+#[derive(Debug)]
+enum Identity {
+    /// Any signer, as long as its certificate chains to a trust store.
+    Any,
+    /// Signers whose certificate subject contains all given attributes.
+    Subject(Vec<(String, String)>),
+}
+
+impl Identity {
+    /// Parses an identity given as `*` or `x509.subject: <distinguished name>`.
+    fn parse(identity: &str) -> Result<Self, NotationError> {
+        if identity == "*" {
+            return Ok(Identity::Any);
+        }
+
+        let name = identity.strip_prefix("x509.subject:").ok_or_else(|| {
+            NotationError::InvalidPolicy(format!("unsupported identity {identity}"))
+        })?;
+        let attributes = name
+            .split(',')
+            .map(|attribute| {
+                attribute
+                    .split_once('=')
+                    .map(|(key, value)| (key.trim().to_owned(), value.trim().to_owned()))
+                    .filter(|(key, value)| !key.is_empty() && !value.is_empty())
+                    .ok_or_else(|| {
+                        NotationError::InvalidPolicy(format!("invalid identity {identity}"))
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Identity::Subject(attributes))
+    }
+
+    /// Checks whether the signing certificate matches the identity.
+    fn matches(&self, certificate: &X509) -> bool {
+        match self {
+            Identity::Any => true,
+            Identity::Subject(attributes) => {
+                let subject: Vec<_> = certificate
+                    .subject_name()
+                    .entries()
+                    .filter_map(|entry| {
+                        let key = entry.object().nid().short_name().ok()?;
+                        let value = entry.data().to_string().ok()?;
+                        Some((key.to_owned(), value))
+                    })
+                    .collect();
+                attributes
+                    .iter()
+                    .all(|attribute| subject.contains(attribute))
+            }
+        }
+    }
+}
+
+/// A trust policy, validated.
+#[derive(Debug)]
+struct Policy {
+    /// Name of the policy.
+    name: String,
+    /// Verification level.
+    level: Level,
+    /// Action on failed authenticity checks.
+    authenticity: Action,
+    /// Action on expired signatures.
+    expiry: Action,
+    /// Names of the trust stores to verify certificate chains against.
+    trust_stores: Vec<String>,
+    /// Identities trusted to sign.
+    identities: Vec<Identity>,
+}
+
+impl Policy {
+    /// Validates a policy from a trust policy document.
+    fn new(entry: PolicyEntry) -> Result<Self, NotationError> {
+        let SignatureVerification { level, overrides } = entry.signature_verification;
+
+        let (mut authenticity, mut expiry) = match level {
+            Level::Strict => (Action::Enforce, Action::Enforce),
+            Level::Permissive => (Action::Enforce, Action::Log),
+            Level::Audit | Level::Skip => (Action::Log, Action::Log),
+        };
+        for (check, action) in overrides {
+            match check {
+                Check::Authenticity => authenticity = action,
+                Check::Expiry => expiry = action,
+                Check::AuthenticTimestamp | Check::Revocation => {}
+            }
+        }
+
+        if level != Level::Skip
+            && (entry.trust_stores.is_empty() || entry.trusted_identities.is_empty())
+        {
+            return Err(NotationError::InvalidPolicy(format!(
+                "policy {} needs trust stores and trusted identities",
+                entry.name
+            )));
+        }
+
+        Ok(Policy {
+            name: entry.name,
+            level,
+            authenticity,
+            expiry,
+            trust_stores: entry.trust_stores,
+            identities: entry
+                .trusted_identities
+                .iter()
+                .map(|identity| Identity::parse(identity))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// Outcome of verifying the signatures of a manifest.
+#[derive(Debug)]
+pub(crate) enum Verdict {
+    /// No policy applies or verification is skipped.
+    Unverified,
+    /// A signature passed all enforced checks.
+    Trusted {
+        /// Name of the applied policy.
+        policy: String,
+        /// Failures of checks that are only logged.
+        logged: Vec<String>,
+    },
+    /// No signature passed all enforced checks.
+    Untrusted {
+        /// Name of the applied policy.
+        policy: String,
+        /// Whether the manifest must not be served.
+        enforced: bool,
+        /// Why the manifest is not trusted.
+        reason: String,
+    },
+}
+
+/// Verifier of notation signatures, see the [module documentation](self).
+#[derive(Debug)]
+pub struct NotationVerifier {
+    /// Policies by registry scope.
+    scopes: HashMap<String, usize>,
+    /// All policies.
+    policies: Vec<Policy>,
+    /// Certificates of trust stores, by name.
+    trust_stores: HashMap<String, Vec<X509>>,
+}
+
+impl NotationVerifier {
+    /// Creates a verifier from a trust policy document in notation's `trustpolicy.json` format.
+    pub fn new(trust_policy: &[u8]) -> Result<Self, NotationError> {
+        let document: PolicyDocument =
+            serde_json::from_slice(trust_policy).map_err(NotationError::ParsePolicy)?;
+        if document.version != POLICY_VERSION {
+            return Err(NotationError::InvalidPolicy(format!(
+                "unsupported version {}",
+                document.version
+            )));
+        }
+
+        let mut scopes = HashMap::new();
+        let mut policies = Vec::new();
+        for entry in document.trust_policies {
+            if entry.registry_scopes.len() > 1 && entry.registry_scopes.iter().any(|s| s == "*") {
+                return Err(NotationError::InvalidPolicy(format!(
+                    "policy {} combines the wildcard scope with others",
+                    entry.name
+                )));
+            }
+            for scope in &entry.registry_scopes {
+                let scope = scope.trim_matches('/').to_owned();
+                if scopes.insert(scope.clone(), policies.len()).is_some() {
+                    return Err(NotationError::InvalidPolicy(format!(
+                        "scope {scope} is covered by multiple policies"
+                    )));
+                }
+            }
+            policies.push(Policy::new(entry)?);
+        }
+
+        Ok(Self {
+            scopes,
+            policies,
+            trust_stores: HashMap::new(),
+        })
+    }
+
+    /// Adds the PEM-encoded certificates of a trust store, e.g. `ca:acme-rockets`.
+    ///
+    /// May be called multiple times, also for the same store.
+    pub fn trust_store<S: Into<String>>(
+        mut self,
+        name: S,
+        pem: &[u8],
+    ) -> Result<Self, NotationError> {
+        let name = name.into();
+        let certificates =
+            X509::stack_from_pem(pem).map_err(|err| NotationError::InvalidCertificates {
+                name: name.clone(),
+                err,
+            })?;
+        self.trust_stores
+            .entry(name)
+            .or_default()
+            .extend(certificates);
+        Ok(self)
+    }
+
+    /// Returns the policy applying to `location`.
+    fn policy(&self, location: &ImageLocation) -> Option<&Policy> {
+        [location.to_string().as_str(), location.repository(), "*"]
+            .into_iter()
+            .find_map(|scope| self.scopes.get(scope))
+            .map(|&index| &self.policies[index])
+    }
+
+    /// Returns whether manifests at `location` are verified at all.
+    pub(crate) fn applies_to(&self, location: &ImageLocation) -> bool {
+        self.policy(location)
+            .is_some_and(|policy| policy.level != Level::Skip)
+    }
+
+    /// Verifies the manifest `digest` at `location` given the JWS envelopes of its signatures.
+    pub(crate) fn verify(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+        envelopes: &[Vec<u8>],
+    ) -> Verdict {
+        let Some(policy) = self
+            .policy(location)
+            .filter(|policy| policy.level != Level::Skip)
+        else {
+            return Verdict::Unverified;
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+
+        let mut reason = "no signature found".to_owned();
+        for envelope in envelopes {
+            match self.verify_envelope(policy, digest, envelope, now) {
+                Ok(logged) => {
+                    return Verdict::Trusted {
+                        policy: policy.name.clone(),
+                        logged,
+                    }
+                }
+                Err(failure) => reason = failure.to_string(),
+            }
+        }
+
+        Verdict::Untrusted {
+            policy: policy.name.clone(),
+            enforced: policy.level != Level::Audit,
+            reason,
+        }
+    }
+
+    /// Verifies a single signature envelope.
+    ///
+    /// Returns the failures of checks that are only logged, or the first failure of an enforced
+    /// check.
+    fn verify_envelope(
+        &self,
+        policy: &Policy,
+        digest: Digest,
+        envelope: &[u8],
+        now: i64,
+    ) -> Result<Vec<String>, Failure> {
+        let jws = Jws::parse(envelope)?;
+
+        // Integrity is always enforced.
+        jws.verify_signature()?;
+        if jws.payload.target_artifact.digest != format!("sha256:{digest}") {
+            return Err(Failure("signature is for a different manifest".to_owned()));
+        }
+
+        let mut logged = Vec::new();
+        let mut apply = |action, result: Result<(), Failure>| match (action, result) {
+            (Action::Enforce, Err(failure)) => Err(failure),
+            (Action::Log, Err(failure)) => {
+                logged.push(failure.0);
+                Ok(())
+            }
+            _ => Ok(()),
+        };
+
+        if policy.authenticity != Action::Skip {
+            apply(policy.authenticity, self.verify_authenticity(policy, &jws))?;
+        }
+        if policy.expiry != Action::Skip {
+            let expired = jws
+                .protected
+                .expiry
+                .as_deref()
+                .map(|expiry| {
+                    parse_rfc3339(expiry)
+                        .ok_or_else(|| Failure(format!("invalid expiry {expiry}")))
+                        .map(|expiry| expiry < now)
+                })
+                .transpose()?;
+            let result = match expired {
+                Some(true) => Err(Failure("signature has expired".to_owned())),
+                _ => Ok(()),
+            };
+            apply(policy.expiry, result)?;
+        }
+
+        Ok(logged)
+    }
+
+    /// Checks that the signer's certificate chains to one of the policy's trust stores and
+    /// belongs to a trusted identity.
+    fn verify_authenticity(&self, policy: &Policy, jws: &Jws) -> Result<(), Failure> {
+        let mut store = X509StoreBuilder::new()?;
+        for name in &policy.trust_stores {
+            let certificates = self
+                .trust_stores
+                .get(name)
+                .ok_or_else(|| Failure(format!("trust store {name} is not configured")))?;
+            for certificate in certificates {
+                store.add_cert(certificate.clone())?;
+            }
+        }
+        let store = store.build();
+
+        let (leaf, intermediates) = jws
+            .certificates
+            .split_first()
+            .expect("envelopes have at least one certificate");
+        let mut chain = Stack::new()?;
+        for certificate in intermediates {
+            chain.push(certificate.clone())?;
+        }
+
+        let mut context = X509StoreContext::new()?;
+        let error = context.init(&store, leaf, &chain, |context| {
+            Ok((!context.verify_cert()?).then(|| context.error()))
+        })?;
+        if let Some(error) = error {
+            return Err(Failure(format!(
+                "certificate chain is not trusted: {error}"
+            )));
+        }
+
+        if !policy
+            .identities
+            .iter()
+            .any(|identity| identity.matches(leaf))
+        {
+            return Err(Failure("signer is not a trusted identity".to_owned()));
+        }
+
+        Ok(())
+    }
+}
+
+/// A reason for a signature to fail verification.
+#[derive(Debug)]
+struct Failure(String);
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<openssl::error::ErrorStack> for Failure {
+    fn from(err: openssl::error::ErrorStack) -> Self {
+        Failure(format!("signature verification failed: {err}"))
+    }
+}
+
+/// A JWS envelope in JSON serialization.
+#[derive(Debug, Deserialize)]
+struct RawJws {
+    /// Base64url-encoded payload.
+    payload: String,
+    /// Base64url-encoded protected header.
+    protected: String,
+    /// Unprotected header.
+    header: UnprotectedHeader,
+    /// Base64url-encoded signature.
+    signature: String,
+}
+
+/// Unprotected header of a JWS envelope.
+#[derive(Debug, Deserialize)]
+struct UnprotectedHeader {
+    /// Base64-encoded DER certificates, starting with the signer's.
+    x5c: Vec<String>,
+}
+
+/// Protected header of a JWS envelope.
+#[derive(Debug, Deserialize)]
+struct ProtectedHeader {
+    /// Signature algorithm, e.g. `PS256`.
+    alg: String,
+    /// Content type of the payload.
+    cty: String,
+    /// Time the signature expires, if at all.
+    #[serde(rename = "io.cncf.notary.expiry")]
+    expiry: Option<String>,
+}
+
+/// Signed payload of a notation signature.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Payload {
+    /// Descriptor of the signed manifest.
+    target_artifact: TargetArtifact,
+}
+
+/// Descriptor of the signed manifest.
+#[derive(Debug, Deserialize)]
+struct TargetArtifact {
+    /// Digest of the manifest, e.g. `sha256:...`.
+    digest: String,
+}
+
+/// A parsed JWS envelope.
+#[derive(Debug)]
+struct Jws {
+    /// Data covered by the signature.
+    signing_input: String,
+    /// Decoded protected header.
+    protected: ProtectedHeader,
+    /// Decoded payload.
+    payload: Payload,
+    /// Certificate chain, never empty.
+    certificates: Vec<X509>,
+    /// Raw signature.
+    signature: Vec<u8>,
+}
+
+impl Jws {
+    /// Parses a JWS envelope.
+    fn parse(envelope: &[u8]) -> Result<Self, Failure> {
+        let invalid = |what: &str| Failure(format!("invalid signature envelope: {what}"));
+        let url_safe = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+        let raw: RawJws = serde_json::from_slice(envelope).map_err(|_| invalid("not a JWS"))?;
+        let protected: ProtectedHeader = url_safe
+            .decode(&raw.protected)
+            .ok()
+            .and_then(|header| serde_json::from_slice(&header).ok())
+            .ok_or_else(|| invalid("protected header"))?;
+        if protected.cty != PAYLOAD_CONTENT_TYPE {
+            return Err(invalid("unsupported payload"));
+        }
+        let payload: Payload = url_safe
+            .decode(&raw.payload)
+            .ok()
+            .and_then(|payload| serde_json::from_slice(&payload).ok())
+            .ok_or_else(|| invalid("payload"))?;
+        let signature = url_safe
+            .decode(&raw.signature)
+            .map_err(|_| invalid("signature"))?;
+
+        let certificates = raw
+            .header
+            .x5c
+            .iter()
+            .map(|certificate| {
+                base64::engine::general_purpose::STANDARD
+                    .decode(certificate)
+                    .ok()
+                    .and_then(|der| X509::from_der(&der).ok())
+                    .ok_or_else(|| invalid("certificate"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if certificates.is_empty() {
+            return Err(invalid("no certificates"));
+        }
+
+        Ok(Jws {
+            signing_input: format!("{}.{}", raw.protected, raw.payload),
+            protected,
+            payload,
+            certificates,
+            signature,
+        })
+    }
+
+    /// Verifies the signature using the signer's certificate.
+    fn verify_signature(&self) -> Result<(), Failure> {
+        let (digest, is_rsa) = match self.protected.alg.as_str() {
+            "PS256" => (MessageDigest::sha256(), true),
+            "PS384" => (MessageDigest::sha384(), true),
+            "PS512" => (MessageDigest::sha512(), true),
+            "ES256" => (MessageDigest::sha256(), false),
+            "ES384" => (MessageDigest::sha384(), false),
+            "ES512" => (MessageDigest::sha512(), false),
+            alg => return Err(Failure(format!("unsupported signature algorithm {alg}"))),
+        };
+        let key: PKey<Public> = self.certificates[0].public_key()?;
+
+        let signature = if is_rsa {
+            if key.id() != Id::RSA {
+                return Err(Failure("certificate does not match algorithm".to_owned()));
+            }
+            self.signature.clone()
+        } else {
+            if key.id() != Id::EC || !self.signature.len().is_multiple_of(2) {
+                return Err(Failure("certificate does not match algorithm".to_owned()));
+            }
+            // JWS encodes ECDSA signatures as `r || s`, OpenSSL expects DER.
+            let (r, s) = self.signature.split_at(self.signature.len() / 2);
+            EcdsaSig::from_private_components(BigNum::from_slice(r)?, BigNum::from_slice(s)?)?
+                .to_der()?
+        };
+
+        let mut verifier = Verifier::new(digest, &key)?;
+        if is_rsa {
+            verifier.set_rsa_padding(Padding::PKCS1_PSS)?;
+            verifier.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+            verifier.set_rsa_mgf1_md(digest)?;
+        }
+        verifier.update(self.signing_input.as_bytes())?;
+
+        if verifier.verify(&signature).unwrap_or(false) {
+            Ok(())
+        } else {
+            Err(Failure("signature does not match".to_owned()))
+        }
+    }
+}
+
+/// Parses an RFC 3339 timestamp, e.g. `2024-05-01T12:00:00Z`, into seconds since the unix epoch.
+fn parse_rfc3339(timestamp: &str) -> Option<i64> {
+    let number = |s: &str| s.parse::<i64>().ok();
+
+    let (date, time) = timestamp.split_once(['T', 't'])?;
+    let mut date = date.splitn(3, '-');
+    let (year, month, day) = (
+        number(date.next()?)?,
+        number(date.next()?)?,
+        number(date.next()?)?,
+    );
+
+    let (time, offset) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        (time, 0)
+    } else {
+        let (time, zone) = time.split_at(time.rfind(['+', '-'])?);
+        let (hours, minutes) = zone[1..].split_once(':')?;
+        let offset = number(hours)? * 3600 + number(minutes)? * 60;
+        (
+            time,
+            if zone.starts_with('-') {
+                -offset
+            } else {
+                offset
+            },
+        )
+    };
+    let time = time.split('.').next()?;
+    let mut time = time.splitn(3, ':');
+    let (hour, minute, second) = (
+        number(time.next()?)?,
+        number(time.next()?)?,
+        number(time.next()?)?,
+    );
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days since the epoch in the proleptic Gregorian calendar.
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    Some(days * 86_400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::ImageLocation;
+
+    use super::{parse_rfc3339, NotationVerifier};
+
+    #[test]
+    fn rfc3339_timestamps_are_parsed() {
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_rfc3339("2024-02-29T12:30:15Z"), Some(1_709_209_815));
+        assert_eq!(
+            parse_rfc3339("2024-02-29T12:30:15.123Z"),
+            Some(1_709_209_815)
+        );
+        assert_eq!(
+            parse_rfc3339("2024-02-29T14:30:15+02:00"),
+            Some(1_709_209_815)
+        );
+        assert_eq!(
+            parse_rfc3339("2024-02-29T10:00:15-02:30"),
+            Some(1_709_209_815)
+        );
+        assert_eq!(parse_rfc3339("2024-13-01T00:00:00Z"), None);
+        assert_eq!(parse_rfc3339("yesterday"), None);
+    }
+
+    #[test]
+    fn most_specific_scope_applies() {
+        let verifier = NotationVerifier::new(
+            br#"{
+                "version": "1.0",
+                "trustPolicies": [
+                    {
+                        "name": "default",
+                        "registryScopes": ["*"],
+                        "signatureVerification": { "level": "skip" }
+                    },
+                    {
+                        "name": "prod",
+                        "registryScopes": ["prod"],
+                        "signatureVerification": { "level": "strict" },
+                        "trustStores": ["ca:test"],
+                        "trustedIdentities": ["*"]
+                    },
+                    {
+                        "name": "prod-api",
+                        "registryScopes": ["prod/api"],
+                        "signatureVerification": {
+                            "level": "audit",
+                            "override": { "expiry": "enforce" }
+                        },
+                        "trustStores": ["ca:test"],
+                        "trustedIdentities": ["x509.subject: O=Tests, CN=signer"]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let policy = |repository: &str, image: &str| {
+            let location = ImageLocation::new(repository.to_owned(), image.to_owned());
+            verifier.policy(&location).unwrap().name.clone()
+        };
+        assert_eq!(policy("prod", "api"), "prod-api");
+        assert_eq!(policy("prod", "web"), "prod");
+        assert_eq!(policy("dev", "api"), "default");
+        assert!(!verifier.applies_to(&ImageLocation::new("dev".to_owned(), "api".to_owned())));
+    }
+
+    #[test]
+    fn invalid_policies_are_rejected() {
+        let without_stores = br#"{
+            "version": "1.0",
+            "trustPolicies": [
+                {
+                    "name": "prod",
+                    "registryScopes": ["prod"],
+                    "signatureVerification": { "level": "strict" }
+                }
+            ]
+        }"#;
+        assert!(NotationVerifier::new(without_stores).is_err());
+
+        let duplicate_scope = br#"{
+            "version": "1.0",
+            "trustPolicies": [
+                {
+                    "name": "a",
+                    "registryScopes": ["prod"],
+                    "signatureVerification": { "level": "skip" }
+                },
+                {
+                    "name": "b",
+                    "registryScopes": ["prod"],
+                    "signatureVerification": { "level": "skip" }
+                }
+            ]
+        }"#;
+        assert!(NotationVerifier::new(duplicate_scope).is_err());
+    }
+}
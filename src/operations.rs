@@ -0,0 +1,341 @@
+//! Long-running operations.
+//!
+//! Maintenance tasks like garbage collection or integrity checks can take a long time on large
+//! registries. Started through
+//! [`ContainerRegistry::start_operation`](crate::ContainerRegistry::start_operation), a task runs
+//! in the background and is tracked as an [`Operation`], which reports its [`Progress`] while
+//! running and can be cancelled. Operations are also exposed by the administrative API below
+//! `/admin/operations`, requiring administrative write access, and administrative delete access
+//! to start tasks removing content, see
+//! [`AuthProvider::admin_permissions`](crate::auth::AuthProvider::admin_permissions). Either way,
+//! tasks remove content as the internal
+//! [`MAINTENANCE_IDENTITY`](crate::auth::MAINTENANCE_IDENTITY).
+//!
+//! Tasks report progress and notice cancellation through [`Progress::checkpoint`]. Cancellation is
+//! cooperative: a cancelled task stops at its next checkpoint, leaving everything it did until
+//! then in place.
+//!
+//! Finished operations can still be looked up for at least an hour.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use crate::{clock::Clock, maintenance::TaskSummary, RegistryError};
+
+/// Time finished operations are kept around for.
+const FINISHED_OPERATION_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// Progress of a task run.
+///
+/// Shared between the task, which updates it, and everyone following the run.
+#[derive(Debug, Default)]
+pub struct Progress {
+    /// Counts reported by the task so far.
+    summary: Mutex<TaskSummary>,
+    /// Number of items the task expects to examine, if known.
+    total: Mutex<Option<u64>>,
+    /// Set once cancellation has been requested.
+    cancelled: AtomicBool,
+}
+
+impl Progress {
+    /// Creates progress for a run nobody follows, e.g. when running a task directly.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the number of items the task expects to examine, used to estimate completion.
+    pub fn set_total(&self, total: u64) {
+        *self.total.lock().expect("lock poisoned") = Some(total);
+    }
+
+    /// Records the counts of the task so far.
+    ///
+    /// Returns [`RegistryError::Cancelled`] if the run has been cancelled, which the task should
+    /// return as soon as possible.
+    pub fn checkpoint(&self, summary: &TaskSummary) -> Result<(), RegistryError> {
+        *self.summary.lock().expect("lock poisoned") = summary.clone();
+
+        if self.is_cancelled() {
+            Err(RegistryError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the counts last recorded.
+    pub fn summary(&self) -> TaskSummary {
+        self.summary.lock().expect("lock poisoned").clone()
+    }
+
+    /// Returns the number of items the task expects to examine, if known.
+    pub fn total(&self) -> Option<u64> {
+        *self.total.lock().expect("lock poisoned")
+    }
+
+    /// Requests cancellation of the run.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    /// Returns whether cancellation has been requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+}
+
+/// State of an [`Operation`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationState {
+    /// The task is still running.
+    Running,
+    /// Cancellation has been requested, but the task has not stopped yet.
+    Cancelling,
+    /// The task finished successfully.
+    Completed,
+    /// The task failed.
+    Failed,
+    /// The task stopped after being cancelled.
+    Cancelled,
+}
+
+/// Point-in-time status of an [`Operation`].
+#[derive(Clone, Debug, Serialize)]
+pub struct OperationStatus {
+    /// Identifier of the operation.
+    pub id: Uuid,
+    /// Name of the task being run.
+    pub task: &'static str,
+    /// Current state.
+    pub state: OperationState,
+    /// Time the operation was started, in seconds since the unix epoch.
+    pub started: u64,
+    /// Counts reported by the task so far, or its final summary.
+    #[serde(flatten)]
+    pub summary: TaskSummary,
+    /// Number of items the task expects to examine, if known.
+    pub total: Option<u64>,
+    /// Estimated time until the task finishes, in seconds, if it can be estimated.
+    pub eta: Option<u64>,
+    /// Description of the error, if the task failed.
+    pub error: Option<String>,
+}
+
+/// Outcome of a finished operation.
+#[derive(Debug)]
+struct Finished {
+    /// Time the task finished.
+    at: Instant,
+    /// The task's summary or, if it failed, a description of the error.
+    outcome: Result<TaskSummary, String>,
+}
+
+/// Shared state of an [`Operation`].
+#[derive(Debug)]
+struct Inner {
+    /// Identifier of the operation.
+    id: Uuid,
+    /// Name of the task being run.
+    task: &'static str,
+    /// Time the operation was started, for estimates.
+    started: Instant,
+    /// Wall clock time the operation was started, for reporting.
+    started_at: SystemTime,
+    /// Source of the time the operation started and finished at.
+    clock: Arc<dyn Clock>,
+    /// Progress of the task.
+    progress: Progress,
+    /// Set once the task has finished.
+    finished: watch::Sender<Option<Finished>>,
+}
+
+/// Handle to a task running in the background, see the [module documentation](self).
+///
+/// Cloning a handle is cheap, all clones refer to the same operation.
+#[derive(Clone, Debug)]
+pub struct Operation {
+    /// State shared between handles.
+    inner: Arc<Inner>,
+}
+
+impl Operation {
+    /// Creates a new, running operation for `task`, timed by `clock`.
+    pub(crate) fn new(task: &'static str, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                id: Uuid::new_v4(),
+                task,
+                started: clock.instant(),
+                started_at: clock.now(),
+                clock,
+                progress: Progress::default(),
+                finished: watch::Sender::new(None),
+            }),
+        }
+    }
+
+    /// Returns the operation's identifier.
+    pub fn id(&self) -> Uuid {
+        self.inner.id
+    }
+
+    /// Returns the name of the task being run.
+    pub fn task(&self) -> &'static str {
+        self.inner.task
+    }
+
+    /// Returns the task's progress.
+    pub fn progress(&self) -> &Progress {
+        &self.inner.progress
+    }
+
+    /// Requests cancellation, see [`Progress::cancel`].
+    pub fn cancel(&self) {
+        self.inner.progress.cancel();
+    }
+
+    /// Returns the current status of the operation.
+    pub fn status(&self) -> OperationStatus {
+        let progress = &self.inner.progress;
+        let total = progress.total();
+        let finished = self.inner.finished.borrow();
+
+        let (state, summary, eta, error) = match *finished {
+            None => {
+                let summary = progress.summary();
+                let elapsed = self
+                    .inner
+                    .clock
+                    .instant()
+                    .duration_since(self.inner.started);
+                let eta = total.and_then(|total| estimate(elapsed, &summary, total));
+                let state = if progress.is_cancelled() {
+                    OperationState::Cancelling
+                } else {
+                    OperationState::Running
+                };
+                (state, summary, eta, None)
+            }
+            Some(Finished {
+                outcome: Ok(ref summary),
+                ..
+            }) => (OperationState::Completed, summary.clone(), None, None),
+            Some(Finished {
+                outcome: Err(ref err),
+                ..
+            }) => {
+                let state = if progress.is_cancelled() {
+                    OperationState::Cancelled
+                } else {
+                    OperationState::Failed
+                };
+                (state, progress.summary(), None, Some(err.clone()))
+            }
+        };
+
+        OperationStatus {
+            id: self.inner.id,
+            task: self.inner.task,
+            state,
+            started: self
+                .inner
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            summary,
+            total,
+            eta: eta.map(|eta| eta.as_secs()),
+            error,
+        }
+    }
+
+    /// Waits for the task to finish, returning its summary or a description of its error.
+    pub async fn wait(&self) -> Result<TaskSummary, String> {
+        let mut finished = self.inner.finished.subscribe();
+        let finished = finished
+            .wait_for(Option::is_some)
+            .await
+            .expect("sender is kept by the operation");
+
+        finished
+            .as_ref()
+            .expect("operation should be finished")
+            .outcome
+            .clone()
+    }
+
+    /// Records the task's outcome.
+    pub(crate) fn finish(&self, outcome: Result<TaskSummary, String>) {
+        self.inner.finished.send_replace(Some(Finished {
+            at: self.inner.clock.instant(),
+            outcome,
+        }));
+    }
+
+    /// Returns whether the operation finished longer than `age` ago.
+    fn finished_before(&self, age: Duration) -> bool {
+        let now = self.inner.clock.instant();
+        self.inner
+            .finished
+            .borrow()
+            .as_ref()
+            .is_some_and(|finished| now.duration_since(finished.at) > age)
+    }
+}
+
+/// Estimates the time remaining after `elapsed`, assuming items take as long as those examined so
+/// far.
+fn estimate(elapsed: Duration, summary: &TaskSummary, total: u64) -> Option<Duration> {
+    if summary.examined == 0 {
+        return None;
+    }
+
+    let remaining = total.saturating_sub(summary.examined);
+    Some(elapsed.mul_f64(remaining as f64 / summary.examined as f64))
+}
+
+/// Operations started on a registry.
+#[derive(Debug, Default)]
+pub(crate) struct Operations {
+    /// All operations by identifier, including recently finished ones.
+    all: Mutex<HashMap<Uuid, Operation>>,
+}
+
+impl Operations {
+    /// Adds a newly started operation, forgetting those finished long ago.
+    pub(crate) fn insert(&self, operation: Operation) {
+        let mut all = self.all.lock().expect("lock poisoned");
+        all.retain(|_, operation| !operation.finished_before(FINISHED_OPERATION_RETENTION));
+        all.insert(operation.id(), operation);
+    }
+
+    /// Returns an operation by its identifier.
+    pub(crate) fn get(&self, id: Uuid) -> Option<Operation> {
+        self.all.lock().expect("lock poisoned").get(&id).cloned()
+    }
+
+    /// Returns all known operations, oldest first.
+    pub(crate) fn list(&self) -> Vec<Operation> {
+        let mut operations: Vec<_> = self
+            .all
+            .lock()
+            .expect("lock poisoned")
+            .values()
+            .cloned()
+            .collect();
+        operations.sort_by_key(|operation| operation.inner.started);
+        operations
+    }
+}
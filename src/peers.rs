@@ -0,0 +1,192 @@
+//! Peer-to-peer blob distribution.
+//!
+//! In large clusters, every node pulling the same layers from the registry makes it a bottleneck.
+//! P2P distribution systems in the style of Dragonfly or Spegel let nodes serve the blobs they
+//! already hold to each other instead. With a [`PeerDirectory`] set through
+//! [`ContainerRegistryBuilder::peer_directory`](crate::ContainerRegistryBuilder::peer_directory),
+//! authorized blob downloads are answered with a `307 Temporary Redirect` to a peer advertising
+//! the blob, if there is one. Blobs no peer holds are served by the registry as usual, as are all
+//! blobs while the directory fails.
+//!
+//! Credentials and the scope of blobs are checked before asking the directory, so clients only
+//! learn about peers holding blobs they may read. Peers are not told who is downloading, they are
+//! expected to be reachable from within the cluster only. Clients verify blobs against their
+//! digest, so a misbehaving peer cannot substitute contents.
+//!
+//! [`PeerTable`] is a reference implementation, keeping the blobs advertised by peers in memory.
+//! How peers advertise is up to the embedding application, e.g. a node agent periodically calling
+//! [`PeerTable::advertise`] through an endpoint of its own.
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    error::Error,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    lockout,
+    storage::{Digest, ImageLocation},
+    ContainerRegistry, ImageDigest,
+};
+
+/// Default time an advertisement is valid for.
+const DEFAULT_ADVERTISEMENT_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// A directory of peers holding blobs, see the [module documentation](self).
+#[async_trait]
+pub trait PeerDirectory: Send + Sync {
+    /// Returns the URL of a peer to download the blob `digest` at `location` from.
+    ///
+    /// `client` is the address of the client downloading, if known, so that e.g. nearby peers can
+    /// be preferred. Returning `None` serves the blob from the registry.
+    async fn locate(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+        client: Option<IpAddr>,
+    ) -> Result<Option<String>, Box<dyn Error + Send + Sync>>;
+}
+
+/// A peer serving blobs.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Peer {
+    /// Address the peer downloads from, so it is never redirected to itself.
+    pub address: IpAddr,
+    /// URL the peer serves the registry's routes under, e.g. `http://10.0.0.7:5000`.
+    pub base_url: String,
+}
+
+/// An advertisement of a peer.
+#[derive(Debug)]
+struct Advertisement {
+    /// Blobs the peer holds.
+    blobs: HashSet<Digest>,
+    /// Time the advertisement expires.
+    expires: SystemTime,
+}
+
+/// Blobs advertised by peers, kept in memory.
+///
+/// Peers are redirected to in turn, skipping the client itself. Advertisements expire unless
+/// renewed, after five minutes by default.
+#[derive(Debug)]
+pub struct PeerTable {
+    /// Current advertisements.
+    peers: Mutex<HashMap<Peer, Advertisement>>,
+    /// Time an advertisement is valid for.
+    ttl: Duration,
+    /// Source of the current time.
+    clock: Arc<dyn Clock>,
+    /// Number of redirects so far, for rotating between peers.
+    redirects: AtomicUsize,
+}
+
+impl Default for PeerTable {
+    fn default() -> Self {
+        Self {
+            peers: Default::default(),
+            ttl: DEFAULT_ADVERTISEMENT_TTL,
+            clock: Arc::new(SystemClock),
+            redirects: Default::default(),
+        }
+    }
+}
+
+impl PeerTable {
+    /// Creates an empty table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the time advertisements are valid for.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Sets the clock advertisements expire by, see the [`clock`](crate::clock) module.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Records that `peer` holds `blobs`, replacing its previous advertisement.
+    pub fn advertise<I: IntoIterator<Item = Digest>>(&self, peer: Peer, blobs: I) {
+        let advertisement = Advertisement {
+            blobs: blobs.into_iter().collect(),
+            expires: self.clock.now() + self.ttl,
+        };
+        self.peers
+            .lock()
+            .expect("lock poisoned")
+            .insert(peer, advertisement);
+    }
+
+    /// Forgets all blobs advertised by `peer`, e.g. when it shuts down.
+    pub fn withdraw(&self, peer: &Peer) {
+        self.peers.lock().expect("lock poisoned").remove(peer);
+    }
+}
+
+#[async_trait]
+impl PeerDirectory for PeerTable {
+    async fn locate(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+        client: Option<IpAddr>,
+    ) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        let now = self.clock.now();
+        let mut candidates: Vec<String> = {
+            let mut peers = self.peers.lock().expect("lock poisoned");
+            peers.retain(|_, advertisement| advertisement.expires > now);
+            peers
+                .iter()
+                .filter(|(peer, advertisement)| {
+                    Some(peer.address) != client && advertisement.blobs.contains(&digest)
+                })
+                .map(|(peer, _)| peer.base_url.clone())
+                .collect()
+        };
+        if candidates.is_empty() {
+            return Ok(None);
+        }
+
+        // Sorted, so that rotating is not subject to the order of the map.
+        candidates.sort();
+        let chosen = self.redirects.fetch_add(1, Ordering::Relaxed) % candidates.len();
+        Ok(Some(format!(
+            "{}/v2/{}/blobs/{}",
+            candidates[chosen].trim_end_matches('/'),
+            location,
+            ImageDigest::new(digest)
+        )))
+    }
+}
+
+/// Address of the client sending a request, if known.
+pub(crate) struct ClientAddress(pub(crate) Option<IpAddr>);
+
+#[async_trait]
+impl FromRequestParts<Arc<ContainerRegistry>> for ClientAddress {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        registry: &Arc<ContainerRegistry>,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self(lockout::client_address(
+            parts,
+            &registry.trusted_proxies,
+        )))
+    }
+}
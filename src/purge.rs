@@ -0,0 +1,668 @@
+//! Deletion of entire repositories.
+//!
+//! Removing a repository takes two steps, so it is never done by accident:
+//!
+//! 1. [`ContainerRegistry::prepare_purge`] determines what would be removed and returns it as a
+//!    [`PurgePlan`], along with a confirmation token valid for a few minutes.
+//! 2. [`ContainerRegistry::purge_repository`] removes the repository, given the token.
+//!
+//! Through the administrative API, these are `POST /admin/repositories/<repository>/purge` and
+//! `DELETE /admin/repositories/<repository>?confirm=<token>`, both requiring delete access to every
+//! image in the repository. Purging a repository without images, e.g. one only created so far,
+//! requires administrative delete access, see
+//! [`AuthProvider::admin_permissions`](crate::auth::AuthProvider::admin_permissions).
+//!
+//! A purge removes all tags and manifests of the repository's images, along with blobs no longer
+//! referenced by any manifest. Manifests also stored in other repositories are kept, as are blobs
+//! linked to other repositories, e.g. uploaded for a manifest not pushed yet, and blobs younger
+//! than an hour, the default grace period of garbage collections. These are left to a later
+//! garbage collection.
+//!
+//! A token is only accepted once, and only if the repository has not changed since it was issued;
+//! otherwise nothing is removed and the purge has to be prepared again. While a purge is running,
+//! manifests and tags pushed to the repository are refused. Afterwards, the repository has to be
+//! created again if [repositories are created explicitly](crate::repositories).
+//!
+//! Purges are not atomic: items are removed one after another, so a storage error or crash part
+//! way leaves the repository partially removed. Before removing anything, a purge records what it
+//! is about to remove in the `purges` directory of the storage, and only deletes the record once
+//! done. An interrupted purge is resumed by preparing and confirming it again, even after a
+//! restart, which removes everything the interrupted purge did not get to, including items no
+//! longer listed otherwise, e.g. the manifests of images whose tags are already gone. Items
+//! removed before the interruption may be reported to hooks and events again.
+//!
+//! Every removed item is reported through
+//! [`RegistryHooks::on_item_purged`](crate::hooks::RegistryHooks::on_item_purged), removed tags
+//! are also published as [`events`](crate::events).
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    events::RegistryEvent,
+    maintenance::{digest_reference, is_older_than, DEFAULT_GRACE_PERIOD},
+    storage::{self, Digest, ImageLocation},
+    types::Manifest,
+    ContainerRegistry, FilesystemStorageError, ImageDigest, RegistryError,
+};
+
+/// Time a confirmation token stays valid.
+const CONFIRMATION_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// An item removed by a purge.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PurgedItem {
+    /// A tag has been removed.
+    Tag {
+        /// Location of the tag.
+        location: ImageLocation,
+        /// The removed tag.
+        tag: String,
+    },
+    /// A manifest has been removed from a location.
+    ///
+    /// The manifest itself is kept if it is also stored in another repository.
+    Manifest {
+        /// Location the manifest was stored at.
+        location: ImageLocation,
+        /// Digest of the manifest.
+        digest: ImageDigest,
+    },
+    /// A blob no longer referenced by any manifest has been removed.
+    Blob {
+        /// Digest of the blob.
+        digest: ImageDigest,
+    },
+}
+
+/// What a purge would remove, see [`ContainerRegistry::prepare_purge`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct PurgePlan {
+    /// The repository to purge.
+    pub repository: String,
+    /// Token to pass to [`ContainerRegistry::purge_repository`].
+    pub confirmation: String,
+    /// Time the token expires, in seconds since the Unix epoch.
+    pub expires: u64,
+    /// Number of images in the repository.
+    pub images: usize,
+    /// Number of tags to remove.
+    pub tags: usize,
+    /// Number of manifests to remove, counted once per image they are stored at.
+    pub manifests: usize,
+    /// Number of blobs to remove at most.
+    ///
+    /// Blobs linked to other repositories or younger than the grace period by the time the purge
+    /// is confirmed are kept, see the [module documentation](self).
+    pub blobs: usize,
+}
+
+/// What a purge removed, see [`ContainerRegistry::purge_repository`].
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct PurgeSummary {
+    /// Number of tags removed.
+    pub tags: usize,
+    /// Number of manifests removed, counted once per image they were stored at.
+    pub manifests: usize,
+    /// Number of blobs removed.
+    pub blobs: usize,
+    /// Number of bytes freed by removing blobs.
+    pub bytes_reclaimed: u64,
+}
+
+/// A confirmation token not used yet.
+#[derive(Debug)]
+struct Pending {
+    /// The repository the token was issued for.
+    repository: String,
+    /// Fingerprint of the repository's contents at the time.
+    fingerprint: Digest,
+    /// Time the token expires.
+    expires: Instant,
+}
+
+/// Pending and running purges.
+#[derive(Debug)]
+pub(crate) struct Purges {
+    /// Directory holding a record per purge in progress or interrupted.
+    root: PathBuf,
+    /// Confirmation tokens issued, by token.
+    pending: Mutex<HashMap<String, Pending>>,
+    /// Repositories currently being purged.
+    running: Mutex<HashSet<String>>,
+    /// Contents of purges that failed part way, by repository, removed by the next purge.
+    interrupted: Mutex<HashMap<String, Contents>>,
+}
+
+impl Purges {
+    /// Opens the records of purges kept in `storage`, creating their directory if necessary.
+    ///
+    /// Purges interrupted before a restart are picked up, to be resumed by the next purge of their
+    /// repository.
+    pub(crate) fn open(storage: &Path) -> Result<Self, FilesystemStorageError> {
+        let root = storage.join("purges");
+        let read_records = || -> io::Result<HashMap<String, Contents>> {
+            std::fs::create_dir_all(&root)?;
+            let mut interrupted = HashMap::new();
+            for entry in std::fs::read_dir(&root)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "tmp") {
+                    continue;
+                }
+                let record: Record = serde_json::from_slice(&std::fs::read(&path)?)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                warn!(repository = %record.repository, "found interrupted purge");
+                interrupted.insert(record.repository.clone(), record.into());
+            }
+            Ok(interrupted)
+        };
+        let interrupted =
+            read_records().map_err(|err| FilesystemStorageError::FailedToReadPurges {
+                path: root.clone(),
+                err,
+            })?;
+
+        Ok(Self {
+            root,
+            pending: Default::default(),
+            running: Default::default(),
+            interrupted: Mutex::new(interrupted),
+        })
+    }
+
+    /// Returns the path of the record of a purge of `repository`.
+    fn record_path(&self, repository: &str) -> PathBuf {
+        // Repository names are not necessarily valid file names.
+        self.root
+            .join(Digest::from_contents(repository.as_bytes()).to_string())
+            .with_extension("json")
+    }
+
+    /// Durably records that `contents` of `repository` are about to be removed.
+    async fn begin(&self, repository: &str, contents: &Contents) -> io::Result<()> {
+        let path = self.record_path(repository);
+        let tmp = path.with_extension("tmp");
+        let record = Record::new(repository, contents);
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        file.write_all(&serde_json::to_vec(&record).expect("serialization should not fail"))
+            .await?;
+        file.sync_all().await?;
+        tokio::fs::rename(tmp, path).await?;
+        tokio::fs::File::open(&self.root).await?.sync_all().await
+    }
+
+    /// Removes the record of a purge of `repository` once it is complete.
+    async fn finish(&self, repository: &str) -> io::Result<()> {
+        self.interrupted
+            .lock()
+            .expect("lock poisoned")
+            .remove(repository);
+        match tokio::fs::remove_file(self.record_path(repository)).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks whether `location` may be written to, i.e. its repository is not being purged.
+    pub(crate) fn check_writable(&self, location: &ImageLocation) -> Result<(), RegistryError> {
+        if self
+            .running
+            .lock()
+            .expect("lock poisoned")
+            .contains(location.repository())
+        {
+            return Err(RegistryError::PolicyViolation(format!(
+                "repository {} is being deleted",
+                location.repository()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Marks a repository as being purged while alive.
+struct Running<'a> {
+    purges: &'a Purges,
+    repository: String,
+}
+
+impl<'a> Running<'a> {
+    /// Marks `repository` as being purged, returns `None` if it already is.
+    fn start(purges: &'a Purges, repository: &str) -> Option<Self> {
+        purges
+            .running
+            .lock()
+            .expect("lock poisoned")
+            .insert(repository.to_owned())
+            .then(|| Self {
+                purges,
+                repository: repository.to_owned(),
+            })
+    }
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.purges
+            .running
+            .lock()
+            .expect("lock poisoned")
+            .remove(&self.repository);
+    }
+}
+
+/// Contents of a repository to remove.
+#[derive(Clone, Debug, Default)]
+struct Contents {
+    /// Images of the repository.
+    locations: Vec<ImageLocation>,
+    /// Tags of the repository's images.
+    tags: Vec<(ImageLocation, String)>,
+    /// Manifests stored at the repository's images.
+    manifests: Vec<(ImageLocation, Digest)>,
+    /// Manifests not stored anywhere else.
+    orphaned_manifests: BTreeSet<Digest>,
+    /// Blobs referenced only by orphaned manifests.
+    orphaned_blobs: BTreeSet<Digest>,
+}
+
+impl Contents {
+    /// Returns a digest over everything to remove, to detect changes.
+    fn fingerprint(&self) -> Digest {
+        let mut listing = String::new();
+        for (location, tag) in &self.tags {
+            listing.push_str(&format!("tag {location} {tag}\n"));
+        }
+        for (location, digest) in &self.manifests {
+            listing.push_str(&format!("manifest {location} {digest}\n"));
+        }
+        for digest in &self.orphaned_blobs {
+            listing.push_str(&format!("blob {digest}\n"));
+        }
+        Digest::from_contents(listing.as_bytes())
+    }
+}
+
+/// The record of a purge in progress, persisted as JSON.
+#[derive(Debug, Deserialize, Serialize)]
+struct Record {
+    repository: String,
+    locations: Vec<ImageLocation>,
+    tags: Vec<(ImageLocation, String)>,
+    manifests: Vec<(ImageLocation, ImageDigest)>,
+    orphaned_manifests: Vec<ImageDigest>,
+    orphaned_blobs: Vec<ImageDigest>,
+}
+
+impl Record {
+    /// Records the `contents` of `repository`.
+    fn new(repository: &str, contents: &Contents) -> Self {
+        Self {
+            repository: repository.to_owned(),
+            locations: contents.locations.clone(),
+            tags: contents.tags.clone(),
+            manifests: contents
+                .manifests
+                .iter()
+                .map(|(location, digest)| (location.clone(), ImageDigest::new(*digest)))
+                .collect(),
+            orphaned_manifests: contents
+                .orphaned_manifests
+                .iter()
+                .copied()
+                .map(ImageDigest::new)
+                .collect(),
+            orphaned_blobs: contents
+                .orphaned_blobs
+                .iter()
+                .copied()
+                .map(ImageDigest::new)
+                .collect(),
+        }
+    }
+}
+
+impl From<Record> for Contents {
+    fn from(record: Record) -> Self {
+        Self {
+            locations: record.locations,
+            tags: record.tags,
+            manifests: record
+                .manifests
+                .into_iter()
+                .map(|(location, digest)| (location, digest.digest()))
+                .collect(),
+            orphaned_manifests: record
+                .orphaned_manifests
+                .iter()
+                .map(ImageDigest::digest)
+                .collect(),
+            orphaned_blobs: record
+                .orphaned_blobs
+                .iter()
+                .map(ImageDigest::digest)
+                .collect(),
+        }
+    }
+}
+
+/// Returns the blobs referenced by a manifest, `None` if it cannot be read.
+fn referenced_blobs(raw: &[u8]) -> Option<Vec<Digest>> {
+    match Manifest::from_slice(raw).ok()? {
+        Manifest::Image(image) => std::iter::once(image.config())
+            .chain(image.layers())
+            .map(|descriptor| descriptor.parsed_digest().ok())
+            .collect(),
+        // Indices reference manifests only, never blobs.
+        Manifest::Index(_) => Some(Vec::new()),
+    }
+}
+
+impl ContainerRegistry {
+    /// Determines what purging `repository` would remove, see the [`purge`](crate::purge) module.
+    ///
+    /// The returned plan carries the token to confirm the purge with. No permissions are checked,
+    /// callers of the library API are trusted. Returns [`RegistryError::NotFound`] if the
+    /// repository has no images.
+    pub async fn prepare_purge(&self, repository: &str) -> Result<PurgePlan, RegistryError> {
+        let contents = self.repository_contents(repository).await?;
+        if contents.locations.is_empty() {
+            return Err(RegistryError::NotFound);
+        }
+
+        let confirmation = Uuid::new_v4().simple().to_string();
+        let now = self.clock.instant();
+        {
+            let mut pending = self.purges.pending.lock().expect("lock poisoned");
+            pending.retain(|_, pending| pending.expires > now);
+            pending.insert(
+                confirmation.clone(),
+                Pending {
+                    repository: repository.to_owned(),
+                    fingerprint: contents.fingerprint(),
+                    expires: now + CONFIRMATION_TTL,
+                },
+            );
+        }
+
+        Ok(PurgePlan {
+            repository: repository.to_owned(),
+            confirmation,
+            expires: (self.clock.now() + CONFIRMATION_TTL)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            images: contents.locations.len(),
+            tags: contents.tags.len(),
+            manifests: contents.manifests.len(),
+            blobs: contents.orphaned_blobs.len(),
+        })
+    }
+
+    /// Removes `repository`, given the token of a plan returned by [`Self::prepare_purge`].
+    ///
+    /// Returns [`RegistryError::PurgeNotConfirmed`] without removing anything if the token is
+    /// unknown, expired, issued for another repository or the repository has changed since. No
+    /// permissions are checked, callers of the library API are trusted.
+    pub async fn purge_repository(
+        &self,
+        repository: &str,
+        confirmation: &str,
+    ) -> Result<PurgeSummary, RegistryError> {
+        self.purge_repository_as(repository, confirmation, None)
+            .await
+    }
+
+    /// Removes `repository` like [`Self::purge_repository`], attributing removals to `actor`.
+    pub(crate) async fn purge_repository_as(
+        &self,
+        repository: &str,
+        confirmation: &str,
+        actor: Option<&str>,
+    ) -> Result<PurgeSummary, RegistryError> {
+        let pending = self
+            .purges
+            .pending
+            .lock()
+            .expect("lock poisoned")
+            .remove(confirmation)
+            .filter(|pending| pending.repository == repository)
+            .filter(|pending| pending.expires > self.clock.instant())
+            .ok_or(RegistryError::PurgeNotConfirmed)?;
+
+        let Some(_running) = Running::start(&self.purges, repository) else {
+            return Err(RegistryError::PurgeNotConfirmed);
+        };
+
+        // Pushes are refused from here on, so the contents stay as they are checked.
+        let contents = self.repository_contents(repository).await?;
+        if contents.fingerprint() != pending.fingerprint {
+            return Err(RegistryError::PurgeNotConfirmed);
+        }
+
+        // Tags may be gone after an interruption, hiding what is left from the next plan.
+        self.purges
+            .begin(repository, &contents)
+            .await
+            .map_err(|err| RegistryError::Storage(storage::Error::Io(err)))?;
+        let summary = match self.remove_contents(repository, &contents, actor).await {
+            Ok(summary) => summary,
+            Err(err) => {
+                self.purges
+                    .interrupted
+                    .lock()
+                    .expect("lock poisoned")
+                    .insert(repository.to_owned(), contents);
+                return Err(err);
+            }
+        };
+        self.purges
+            .finish(repository)
+            .await
+            .map_err(|err| RegistryError::Storage(storage::Error::Io(err)))?;
+
+        info!(
+            %repository,
+            tags = summary.tags,
+            manifests = summary.manifests,
+            blobs = summary.blobs,
+            actor,
+            "repository purged"
+        );
+        Ok(summary)
+    }
+
+    /// Removes the contents of `repository`, the part of a purge that may fail half way.
+    ///
+    /// Removing items already gone succeeds, so contents can be removed again after a failure.
+    async fn remove_contents(
+        &self,
+        repository: &str,
+        contents: &Contents,
+        actor: Option<&str>,
+    ) -> Result<PurgeSummary, RegistryError> {
+        let mut summary = PurgeSummary::default();
+
+        for (location, tag) in &contents.tags {
+            self.storage.delete_tag(location, tag).await?;
+            summary.tags += 1;
+            self.events.publish(RegistryEvent::TagDeleted {
+                location: location.clone(),
+                tag: tag.clone(),
+                actor: actor.map(ToOwned::to_owned),
+            });
+            self.report_purged(PurgedItem::Tag {
+                location: location.clone(),
+                tag: tag.clone(),
+            })
+            .await;
+        }
+
+        for location in &contents.locations {
+            self.storage.delete_location(location).await?;
+        }
+
+        for (location, digest) in &contents.manifests {
+            if contents.orphaned_manifests.contains(digest) {
+                self.storage.delete_manifest(*digest).await?;
+            }
+            if let Some(ref quarantine) = self.quarantine {
+                quarantine
+                    .remove(location, *digest)
+                    .await
+                    .map_err(|err| RegistryError::Storage(storage::Error::Io(err)))?;
+            }
+            summary.manifests += 1;
+            self.report_purged(PurgedItem::Manifest {
+                location: location.clone(),
+                digest: ImageDigest::new(*digest),
+            })
+            .await;
+        }
+
+        // The repository's own links are gone by now, other locations may have gained some since
+        // the purge was prepared, e.g. by uploads for manifests still to be pushed.
+        for &digest in &contents.orphaned_blobs {
+            let metadata = self.storage.get_blob_metadata(digest).await?;
+            if metadata.as_ref().is_some_and(|metadata| {
+                !is_older_than(self.clock.now(), metadata.modified(), DEFAULT_GRACE_PERIOD)
+            }) || !self.storage.list_blob_links(digest).await?.is_empty()
+            {
+                continue;
+            }
+            let size = metadata.map(|metadata| metadata.size()).unwrap_or_default();
+            self.storage.delete_blob(digest).await?;
+            summary.blobs += 1;
+            summary.bytes_reclaimed += size;
+            self.report_purged(PurgedItem::Blob {
+                digest: ImageDigest::new(digest),
+            })
+            .await;
+        }
+
+        self.repositories
+            .forget(repository)
+            .await
+            .map_err(|err| RegistryError::Storage(storage::Error::Io(err)))?;
+        if let Some(ref histories) = self.tag_histories {
+            histories
+                .forget(repository)
+                .await
+                .map_err(|err| RegistryError::Storage(storage::Error::Io(err)))?;
+        }
+
+        Ok(summary)
+    }
+
+    /// Returns the images of `repository`.
+    pub(crate) async fn repository_locations(
+        &self,
+        repository: &str,
+    ) -> Result<Vec<ImageLocation>, RegistryError> {
+        Ok(self
+            .storage
+            .list_locations()
+            .await?
+            .into_iter()
+            .filter(|location| location.repository() == repository)
+            .collect())
+    }
+
+    /// Collects everything purging `repository` would remove.
+    async fn repository_contents(&self, repository: &str) -> Result<Contents, RegistryError> {
+        let mut contents = Contents::default();
+        let mut kept_manifests = HashSet::new();
+
+        for location in self.storage.list_locations().await? {
+            let manifests = self.storage.list_manifests(&location).await?;
+            if location.repository() != repository {
+                kept_manifests.extend(manifests.into_iter().map(|manifest| manifest.digest));
+                continue;
+            }
+
+            for manifest in manifests {
+                contents
+                    .tags
+                    .extend(manifest.tags.into_iter().map(|tag| (location.clone(), tag)));
+                contents.manifests.push((location.clone(), manifest.digest));
+                contents.orphaned_manifests.insert(manifest.digest);
+            }
+            contents.locations.push(location);
+        }
+
+        // Whatever an interrupted purge did not get to is no longer necessarily listed, e.g. once
+        // the tags of an image are gone.
+        let interrupted = self
+            .purges
+            .interrupted
+            .lock()
+            .expect("lock poisoned")
+            .get(repository)
+            .cloned();
+        if let Some(interrupted) = interrupted {
+            for location in interrupted.locations {
+                if !contents.locations.contains(&location) {
+                    contents.locations.push(location);
+                }
+            }
+            contents.tags.extend(interrupted.tags);
+            contents.manifests.extend(interrupted.manifests);
+            contents
+                .orphaned_manifests
+                .extend(interrupted.orphaned_manifests);
+            contents.orphaned_blobs.extend(interrupted.orphaned_blobs);
+        }
+
+        contents
+            .orphaned_manifests
+            .retain(|digest| !kept_manifests.contains(digest));
+        contents.tags.sort();
+        contents.tags.dedup();
+        contents.manifests.sort();
+        contents.manifests.dedup();
+
+        // Blobs of orphaned manifests are removed unless another manifest references them.
+        let mut kept_blobs = HashSet::new();
+        let mut sweep_blobs = true;
+        for digest in self.storage.list_manifest_digests().await? {
+            let Some(raw) = self.storage.get_manifest(&digest_reference(digest)).await? else {
+                continue;
+            };
+
+            let orphaned = contents.orphaned_manifests.contains(&digest);
+            match referenced_blobs(&raw) {
+                Some(blobs) if orphaned => contents.orphaned_blobs.extend(blobs),
+                Some(blobs) => kept_blobs.extend(blobs),
+                // We cannot tell what a remaining manifest refers to, so better keep all blobs.
+                None if !orphaned => sweep_blobs = false,
+                None => {}
+            }
+        }
+        if sweep_blobs {
+            contents
+                .orphaned_blobs
+                .retain(|digest| !kept_blobs.contains(digest));
+        } else {
+            contents.orphaned_blobs.clear();
+        }
+
+        Ok(contents)
+    }
+
+    /// Reports an item removed by a purge to the hooks.
+    async fn report_purged(&self, item: PurgedItem) {
+        self.run_hook("on_item_purged", self.hooks.on_item_purged(&item))
+            .await;
+    }
+}
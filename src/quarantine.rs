@@ -0,0 +1,216 @@
+//! Quarantine of newly pushed manifests.
+//!
+//! For staged publication, manifests pushed to a quarantined scope (see
+//! [`ContainerRegistryBuilder::quarantine`](crate::ContainerRegistryBuilder::quarantine)) are
+//! stored, but not published right away: only reviewers, e.g. a vulnerability scanner or an
+//! administrator, can pull them, everyone else is refused with `403 Forbidden`. Tags the manifest
+//! was pushed as are only applied once it is released, so clients pulling by tag keep getting the
+//! previous manifest in the meantime.
+//!
+//! A quarantined manifest is released either by the
+//! [`RegistryHooks::on_manifest_quarantined`](crate::hooks::RegistryHooks::on_manifest_quarantined)
+//! hook returning [`QuarantineDecision::Release`], or by a reviewer through the administrative
+//! API:
+//!
+//! * `GET /admin/<repository>/<image>/quarantine` lists the quarantined manifests of an image
+//!   along with their pending tags, and
+//! * `POST /admin/<repository>/<image>/quarantine/<digest>/release` releases a manifest, applying
+//!   its pending tags. Manifests listed in a released index are released along with it.
+//!
+//! Only manifests not yet stored at the location are quarantined. Artifacts referring to another
+//! manifest, e.g. signatures or scan results, are published right away. Quarantined manifests are
+//! recorded in the `held` directory of the storage, thus stay quarantined across restarts.
+
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    auth::ValidCredentials,
+    storage::{Digest, FilesystemStorageError, ImageLocation},
+};
+
+/// Outcome of reviewing a quarantined manifest.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum QuarantineDecision {
+    /// Keep the manifest quarantined until it is released through the administrative API.
+    #[default]
+    Hold,
+    /// Publish the manifest, applying its pending tags.
+    Release,
+}
+
+/// Record of a quarantined manifest.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct Held {
+    /// Tags to apply once the manifest is released.
+    pub(crate) tags: Vec<String>,
+}
+
+/// Quarantined manifests, see the [module documentation](self).
+#[derive(Debug)]
+pub(crate) struct Quarantine {
+    /// Directory holding a record per quarantined manifest.
+    root: PathBuf,
+    /// Repositories or images new manifests are quarantined in.
+    scopes: HashSet<String>,
+    /// Users allowed to pull and release quarantined manifests.
+    reviewers: HashSet<String>,
+    /// Serializes updates of records.
+    updates: Mutex<()>,
+}
+
+impl Quarantine {
+    /// Opens the quarantine kept in `storage`, creating its directory if necessary.
+    pub(crate) fn open(
+        storage: &Path,
+        scopes: HashSet<String>,
+        reviewers: HashSet<String>,
+    ) -> Result<Self, FilesystemStorageError> {
+        let root = storage.join("held");
+        if !root.exists() {
+            std::fs::create_dir_all(&root).map_err(|err| {
+                FilesystemStorageError::FailedToCreateDir {
+                    path: root.clone(),
+                    err,
+                }
+            })?;
+        }
+
+        Ok(Self {
+            root,
+            scopes,
+            reviewers,
+            updates: Mutex::new(()),
+        })
+    }
+
+    /// Checks whether new manifests at `location` are quarantined.
+    pub(crate) fn applies_to(&self, location: &ImageLocation) -> bool {
+        self.scopes.contains(location.repository()) || self.scopes.contains(&location.to_string())
+    }
+
+    /// Checks whether the caller may pull and release quarantined manifests.
+    pub(crate) fn is_reviewer(&self, creds: &ValidCredentials) -> bool {
+        creds
+            .username()
+            .is_some_and(|username| self.reviewers.contains(username))
+    }
+
+    /// Returns the directory holding the records of `location`.
+    fn location_dir(&self, location: &ImageLocation) -> PathBuf {
+        self.root.join(location.repository()).join(location.image())
+    }
+
+    /// Returns the path of the record of a manifest.
+    fn record_path(&self, location: &ImageLocation, digest: Digest) -> PathBuf {
+        self.location_dir(location).join(digest.to_string())
+    }
+
+    /// Returns the record of a quarantined manifest, or `None` if it is not quarantined.
+    pub(crate) async fn get(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> io::Result<Option<Held>> {
+        match tokio::fs::read(self.record_path(location, digest)).await {
+            Ok(raw) => serde_json::from_slice(&raw)
+                .map(Some)
+                .map_err(io::Error::other),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Quarantines a manifest, adding `tag` to the tags applied on release.
+    pub(crate) async fn hold(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+        tag: Option<&str>,
+    ) -> io::Result<()> {
+        let _guard = self.updates.lock().await;
+
+        let mut held = self.get(location, digest).await?.unwrap_or_default();
+        if let Some(tag) = tag {
+            if !held.tags.iter().any(|pending| pending == tag) {
+                held.tags.push(tag.to_owned());
+            }
+        }
+
+        let path = self.record_path(location, digest);
+        tokio::fs::create_dir_all(self.location_dir(location)).await?;
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(
+            &tmp,
+            serde_json::to_vec(&held).expect("serialization should not fail"),
+        )
+        .await?;
+        tokio::fs::rename(tmp, path).await
+    }
+
+    /// Removes a manifest from quarantine, returning its record if it was quarantined.
+    pub(crate) async fn remove(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> io::Result<Option<Held>> {
+        let _guard = self.updates.lock().await;
+
+        let held = self.get(location, digest).await?;
+        if held.is_some() {
+            tokio::fs::remove_file(self.record_path(location, digest)).await?;
+        }
+        Ok(held)
+    }
+
+    /// Lists the quarantined manifests of `location`.
+    pub(crate) async fn list(&self, location: &ImageLocation) -> io::Result<Vec<(Digest, Held)>> {
+        let mut entries = match tokio::fs::read_dir(self.location_dir(location)).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut held = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(digest) = entry.file_name().to_str().and_then(Digest::from_hex_str) else {
+                continue;
+            };
+            if let Some(record) = self.get(location, digest).await? {
+                held.push((digest, record));
+            }
+        }
+        held.sort_by_key(|(digest, _)| *digest);
+
+        Ok(held)
+    }
+
+    /// Returns the digests of all quarantined manifests, at any location.
+    pub(crate) async fn held_manifests(&self) -> io::Result<HashSet<Digest>> {
+        let mut held = HashSet::new();
+        let mut repositories = tokio::fs::read_dir(&self.root).await?;
+        while let Some(repository) = repositories.next_entry().await? {
+            if !repository.file_type().await?.is_dir() {
+                continue;
+            }
+            let mut images = tokio::fs::read_dir(repository.path()).await?;
+            while let Some(image) = images.next_entry().await? {
+                if !image.file_type().await?.is_dir() {
+                    continue;
+                }
+                let mut records = tokio::fs::read_dir(image.path()).await?;
+                while let Some(record) = records.next_entry().await? {
+                    held.extend(record.file_name().to_str().and_then(Digest::from_hex_str));
+                }
+            }
+        }
+
+        Ok(held)
+    }
+}
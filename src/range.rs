@@ -0,0 +1,110 @@
+//! Byte ranges.
+//!
+//! Chunked uploads communicate the position of a chunk through `Content-Range` headers of the form
+//! `<start>-<end>`, with both ends being inclusive.
+
+use std::fmt::{self, Display};
+
+/// An inclusive, non-empty byte range.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) struct ByteRange {
+    /// Offset of the first byte.
+    start: u64,
+    /// Offset of the last byte.
+    end: u64,
+}
+
+impl ByteRange {
+    /// Creates a new range, returns `None` if `end` is before `start` or its length would not
+    /// fit into a `u64`.
+    pub(crate) fn new(start: u64, end: u64) -> Option<Self> {
+        if end < start || (start == 0 && end == u64::MAX) {
+            return None;
+        }
+
+        Some(Self { start, end })
+    }
+
+    /// Parses a range of the form `<start>-<end>`.
+    ///
+    /// A leading `bytes ` or `bytes=` unit, as sent by some clients, is accepted as well.
+    pub(crate) fn parse(raw: &str) -> Option<Self> {
+        let raw = raw.trim();
+        let raw = raw
+            .strip_prefix("bytes=")
+            .or_else(|| raw.strip_prefix("bytes "))
+            .unwrap_or(raw);
+
+        let (start, end) = raw.split_once('-')?;
+
+        // `u64::from_str` accepts a leading `+`, which is not valid here.
+        let parse = |s: &str| {
+            if s.is_empty() || !s.bytes().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            s.parse::<u64>().ok()
+        };
+
+        Self::new(parse(start)?, parse(end)?)
+    }
+
+    /// Returns the offset of the first byte.
+    pub(crate) fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Returns the offset of the last byte.
+    pub(crate) fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Returns the number of bytes in the range.
+    pub(crate) fn len(&self) -> u64 {
+        // Cannot overflow, as guaranteed by the constructor.
+        self.end - self.start + 1
+    }
+}
+
+impl Display for ByteRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::ByteRange;
+
+    #[test]
+    fn parses_known_ranges() {
+        assert_eq!(ByteRange::parse("0-31"), ByteRange::new(0, 31));
+        assert_eq!(ByteRange::parse("bytes=5-5"), ByteRange::new(5, 5));
+        assert_eq!(ByteRange::parse("bytes 1-2"), ByteRange::new(1, 2));
+        assert_eq!(ByteRange::parse("32-31"), None);
+        assert_eq!(ByteRange::parse("+1-2"), None);
+        assert_eq!(ByteRange::parse("-1"), None);
+        assert_eq!(ByteRange::parse(&format!("0-{}", u64::MAX)), None);
+    }
+
+    proptest! {
+        #[test]
+        fn parse_never_panics(raw in "\\PC*") {
+            if let Some(range) = ByteRange::parse(&raw) {
+                prop_assert!(range.start <= range.end);
+                prop_assert!(range.len() >= 1);
+            }
+        }
+
+        #[test]
+        fn parse_roundtrips(a in any::<u64>(), b in any::<u64>()) {
+            let (start, end) = (a.min(b), a.max(b));
+            prop_assume!(start != 0 || end != u64::MAX);
+
+            let range = ByteRange::new(start, end).expect("valid range rejected");
+            prop_assert_eq!(ByteRange::parse(&range.to_string()), Some(range));
+            prop_assert_eq!(range.len() - 1, end - start);
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! Creation and fingerprinting of repositories.
+//!
+//! By default, a repository comes into existence implicitly when the first image is pushed to it.
+//! With [`RepositoryCreation::Explicit`] set through
+//! [`ContainerRegistryBuilder::repository_creation`](crate::ContainerRegistryBuilder::repository_creation),
+//! uploads, manifests and tags pushed to a repository that does not exist yet are refused with
+//! `404 Not Found` (`NAME_UNKNOWN`) instead, so a typo or a squatter cannot claim the name of an
+//! internal repository. Repositories then have to be created up front, using
+//! [`ContainerRegistry::create_repository`] or the administrative API
+//! (`PUT /admin/repositories/<repository>`, requiring administrative write access, see
+//! [`AuthProvider::admin_permissions`](crate::auth::AuthProvider::admin_permissions)).
+//!
+//! Created repositories are recorded in the `repositories` directory of the storage. Repositories
+//! already holding images count as created, so existing registries keep working when switching to
+//! explicit creation. [Purging](crate::purge) a repository removes its record along with it.
+//!
+//! Mirrors and backups can cheaply check whether they are in sync with their source by comparing
+//! [`RepositoryFingerprint`]s, computed by [`ContainerRegistry::repository_fingerprint`] or through
+//! the administrative API (`GET /admin/repositories/<repository>/fingerprint`, requiring read
+//! access to every image in the repository). A fingerprint is the SHA256 digest over a listing of
+//! every manifest stored at and every tag of the repository's images, one line each:
+//!
+//! ```text
+//! manifest <repository>/<image> sha256:<digest>
+//! tag <repository>/<image> <tag> sha256:<digest>
+//! ```
+//!
+//! Lines are sorted bytewise and terminated by a newline. As manifests refer to their blobs by
+//! digest, equal fingerprints imply equal content, without comparing any blobs. Timestamps are not
+//! part of the fingerprint, so it does not change when content is copied.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use tracing::info;
+
+use crate::{
+    storage::{Digest, ImageLocation},
+    ContainerRegistry, FilesystemStorageError, ImageDigest, RegistryError,
+};
+
+/// Whether pushing to a repository that does not exist creates it.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RepositoryCreation {
+    /// Repositories are created by pushing to them.
+    #[default]
+    Implicit,
+    /// Repositories have to be created before pushing to them.
+    Explicit,
+}
+
+/// Fingerprint of a repository's content, see the [module documentation](self).
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct RepositoryFingerprint {
+    /// Digest over the listing of the repository's manifests and tags.
+    pub digest: ImageDigest,
+    /// Number of images in the repository.
+    pub images: usize,
+    /// Number of manifests, counted once per image they are stored at.
+    pub manifests: usize,
+    /// Number of tags.
+    pub tags: usize,
+}
+
+/// Records of created repositories, see the [module documentation](self).
+#[derive(Debug)]
+pub(crate) struct Repositories {
+    /// Directory holding a file per created repository.
+    root: PathBuf,
+    /// Whether pushes create repositories.
+    creation: RepositoryCreation,
+}
+
+impl Repositories {
+    /// Opens the records kept in `storage`, creating their directory if necessary.
+    pub(crate) fn open(
+        storage: &Path,
+        creation: RepositoryCreation,
+    ) -> Result<Self, FilesystemStorageError> {
+        let root = storage.join("repositories");
+        if !root.exists() {
+            std::fs::create_dir_all(&root).map_err(|err| {
+                FilesystemStorageError::FailedToCreateDir {
+                    path: root.clone(),
+                    err,
+                }
+            })?;
+        }
+
+        Ok(Self { root, creation })
+    }
+
+    /// Returns the path of the record of `repository`, if its name is a single path segment.
+    fn record_path(&self, repository: &str) -> Option<PathBuf> {
+        let segment = !repository.is_empty()
+            && !matches!(repository, "." | "..")
+            && !repository.contains(['/', '\\']);
+        segment.then(|| self.root.join(repository))
+    }
+
+    /// Returns whether `repository` has been recorded as created.
+    async fn is_recorded(&self, repository: &str) -> io::Result<bool> {
+        match self.record_path(repository) {
+            Some(path) => tokio::fs::try_exists(path).await,
+            None => Ok(false),
+        }
+    }
+
+    /// Records `repository` as created, returning whether it was not recorded before.
+    async fn record(&self, repository: &str) -> io::Result<bool> {
+        let path = self.record_path(repository).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid repository name")
+        })?;
+        match tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Removes the record of `repository`, if any.
+    pub(crate) async fn forget(&self, repository: &str) -> io::Result<()> {
+        let Some(path) = self.record_path(repository) else {
+            return Ok(());
+        };
+        match tokio::fs::remove_file(path).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Checks that `repository` is usable as a repository name.
+///
+/// Names are single path segments made of lowercase letters, digits and separators (`.`, `_` and
+/// `-`), starting with a letter or digit.
+fn validate_name(repository: &str) -> Result<(), RegistryError> {
+    let mut chars = repository.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && chars
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '.' | '_' | '-'));
+
+    if !valid {
+        return Err(RegistryError::InvalidRepositoryName(repository.to_owned()));
+    }
+    Ok(())
+}
+
+impl ContainerRegistry {
+    /// Creates `repository`, returning whether it did not exist before.
+    ///
+    /// Only needed with [`RepositoryCreation::Explicit`], see the
+    /// [`repositories`](crate::repositories) module. No permissions are checked, callers of the
+    /// library API are trusted.
+    pub async fn create_repository(&self, repository: &str) -> Result<bool, RegistryError> {
+        validate_name(repository)?;
+        if self.repository_exists(repository).await? {
+            return Ok(false);
+        }
+
+        let created = self
+            .repositories
+            .record(repository)
+            .await
+            .map_err(io_error)?;
+        if created {
+            info!(%repository, "repository created");
+        }
+        Ok(created)
+    }
+
+    /// Returns whether `repository` has been created or holds images.
+    pub async fn repository_exists(&self, repository: &str) -> Result<bool, RegistryError> {
+        if self
+            .repositories
+            .is_recorded(repository)
+            .await
+            .map_err(io_error)?
+        {
+            return Ok(true);
+        }
+
+        // Repositories pushed to before they had to be created are recorded once seen, which
+        // saves listing all locations on every push to them.
+        if self.repository_locations(repository).await?.is_empty() {
+            return Ok(false);
+        }
+        self.repositories
+            .record(repository)
+            .await
+            .map_err(io_error)?;
+        Ok(true)
+    }
+
+    /// Computes a fingerprint of everything stored in `repository`.
+    ///
+    /// See the [`repositories`](crate::repositories) module for how it is computed. No permissions
+    /// are checked, callers of the library API are trusted. Returns [`RegistryError::NotFound`] if
+    /// the repository has no images.
+    pub async fn repository_fingerprint(
+        &self,
+        repository: &str,
+    ) -> Result<RepositoryFingerprint, RegistryError> {
+        let locations = self.repository_locations(repository).await?;
+        if locations.is_empty() {
+            return Err(RegistryError::NotFound);
+        }
+
+        let (mut manifests, mut tags) = (0, 0);
+        let mut lines = Vec::new();
+        for location in &locations {
+            for manifest in self.storage.list_manifests(location).await? {
+                let digest = ImageDigest::new(manifest.digest);
+                manifests += 1;
+                lines.push(format!("manifest {location} {digest}\n"));
+                for tag in manifest.tags {
+                    tags += 1;
+                    lines.push(format!("tag {location} {tag} {digest}\n"));
+                }
+            }
+        }
+        lines.sort_unstable();
+
+        Ok(RepositoryFingerprint {
+            digest: ImageDigest::new(Digest::from_contents(lines.concat().as_bytes())),
+            images: locations.len(),
+            manifests,
+            tags,
+        })
+    }
+
+    /// Checks that `location` may be pushed to under the repository creation policy.
+    pub(crate) async fn check_repository_exists(
+        &self,
+        location: &ImageLocation,
+    ) -> Result<(), RegistryError> {
+        if self.repositories.creation == RepositoryCreation::Implicit
+            || self.repository_exists(location.repository()).await?
+        {
+            return Ok(());
+        }
+
+        Err(RegistryError::RepositoryUnknown(
+            location.repository().to_owned(),
+        ))
+    }
+}
+
+/// Wraps an IO error of the records.
+fn io_error(err: io::Error) -> RegistryError {
+    RegistryError::Storage(crate::storage::Error::Io(err))
+}
@@ -0,0 +1,125 @@
+//! Software bill of materials (SBOM) support.
+//!
+//! SBOMs are stored as OCI artifacts referring to the image they describe, thus can be found
+//! through the referrers API by clients such as `oras` or `cosign`. They can be attached manually
+//! using [`ContainerRegistry::attach_sbom`](crate::ContainerRegistry::attach_sbom) or the
+//! administrative API, or generated automatically for every pushed image by configuring an
+//! [`SbomGenerator`] through
+//! [`ContainerRegistryBuilder::sbom_generator`](crate::ContainerRegistryBuilder::sbom_generator).
+
+use std::{error::Error, process::Stdio};
+
+use axum::async_trait;
+
+use crate::storage::{Digest, ImageLocation};
+
+/// Media type of SPDX documents in JSON format.
+pub const SPDX_JSON: &str = "application/spdx+json";
+
+/// Media type of CycloneDX documents in JSON format.
+pub const CYCLONEDX_JSON: &str = "application/vnd.cyclonedx+json";
+
+/// An SBOM document.
+#[derive(Clone, Debug)]
+pub struct Sbom {
+    /// Media type of the document, e.g. [`SPDX_JSON`]. Used as the artifact type as well.
+    pub media_type: String,
+    /// The document itself.
+    pub document: Vec<u8>,
+}
+
+/// A generator of SBOMs for pushed images.
+///
+/// Generators are run in the background after an image manifest has been stored, its result is
+/// attached to the image. Failures are logged and counted in the registry metrics.
+#[async_trait]
+pub trait SbomGenerator: Send + Sync {
+    /// Generates an SBOM for the image manifest `digest` at `location`.
+    ///
+    /// Returns `None` if no SBOM should be attached for this image.
+    async fn generate(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<Option<Sbom>, Box<dyn Error + Send + Sync>>;
+}
+
+/// Generates SBOMs by running an external command, e.g. `syft`.
+///
+/// The command's standard output is used as the SBOM document. In all arguments, `{location}` is
+/// replaced with the image location (e.g. `bitnami/nginx`) and `{digest}` with the manifest digest
+/// (e.g. `sha256:...`). As the registry does not know its own public address, it needs to be part
+/// of the arguments:
+///
+/// ```
+/// use container_registry::sbom::{CommandSbomGenerator, SPDX_JSON};
+///
+/// let generator = CommandSbomGenerator::new("syft", SPDX_JSON)
+///     .arg("registry:localhost:5000/{location}@{digest}")
+///     .arg("--output")
+///     .arg("spdx-json");
+/// ```
+#[derive(Clone, Debug)]
+pub struct CommandSbomGenerator {
+    /// Program to run.
+    program: String,
+    /// Argument templates.
+    args: Vec<String>,
+    /// Media type of the produced documents.
+    media_type: String,
+}
+
+impl CommandSbomGenerator {
+    /// Creates a new generator running `program`, which outputs documents of `media_type`.
+    pub fn new<P: Into<String>, M: Into<String>>(program: P, media_type: M) -> Self {
+        Self {
+            program: program.into(),
+            args: Vec::new(),
+            media_type: media_type.into(),
+        }
+    }
+
+    /// Adds an argument template.
+    pub fn arg<S: Into<String>>(mut self, arg: S) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+}
+
+#[async_trait]
+impl SbomGenerator for CommandSbomGenerator {
+    async fn generate(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<Option<Sbom>, Box<dyn Error + Send + Sync>> {
+        let location = location.to_string();
+        let digest = format!("sha256:{}", digest);
+
+        let output = tokio::process::Command::new(&self.program)
+            .args(self.args.iter().map(|arg| {
+                arg.replace("{location}", &location)
+                    .replace("{digest}", &digest)
+            }))
+            .stdin(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            return Err(format!(
+                "{} exited with {}: {}",
+                self.program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+
+        Ok(Some(Sbom {
+            media_type: self.media_type.clone(),
+            document: output.stdout,
+        }))
+    }
+}
@@ -0,0 +1,250 @@
+//! Startup self-test.
+//!
+//! [`ContainerRegistry::self_test`] runs a full push, pull and delete cycle of a synthetic image
+//! against the configured storage and auth provider, without serving any traffic. It is meant to
+//! be run before accepting requests, e.g. as an init container or a readiness gate, to catch
+//! misconfigured credentials, permissions or storage backends early.
+//!
+//! The synthetic image is stored below a uniquely named image in the given repository and removed
+//! again afterwards, even if a check fails. It is written to storage directly, so hooks, events
+//! and policies such as quarantine or repository creation do not see it. Every check is timed and
+//! reported in a [`SelfTestReport`]; checks depending on a failed one are not run.
+
+use std::{
+    fmt,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    auth::Unverified,
+    storage::{self, Digest, ImageLocation, ManifestReference, Reference},
+    types::OCI_IMAGE_MANIFEST,
+    ContainerRegistry, ImageDigest,
+};
+
+/// Outcome of a single check of a self-test.
+#[derive(Clone, Debug)]
+pub struct SelfTestCheck {
+    /// Name of the check, e.g. `upload_blob`.
+    pub name: &'static str,
+    /// Wall clock time the check took.
+    pub duration: Duration,
+    /// Whether the check passed or, if it failed, a description of the problem.
+    pub outcome: Result<(), String>,
+}
+
+/// Report of a self-test, see the [module documentation](self).
+#[derive(Clone, Debug, Default)]
+pub struct SelfTestReport {
+    /// Checks run, in order.
+    pub checks: Vec<SelfTestCheck>,
+}
+
+impl SelfTestReport {
+    /// Returns whether every check passed.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.outcome.is_ok())
+    }
+
+    /// Runs a check, recording its outcome. Returns its output if it passed.
+    async fn check<T, F>(&mut self, name: &'static str, check: F) -> Option<T>
+    where
+        F: Future<Output = Result<T, String>>,
+    {
+        let started = Instant::now();
+        let outcome = check.await;
+        let duration = started.elapsed();
+
+        let (outcome, output) = match outcome {
+            Ok(output) => (Ok(()), Some(output)),
+            Err(err) => {
+                warn!(check = name, %err, "self-test check failed");
+                (Err(err), None)
+            }
+        };
+        self.checks.push(SelfTestCheck {
+            name,
+            duration,
+            outcome,
+        });
+        output
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            match check.outcome {
+                Ok(()) => writeln!(f, "ok    {} ({:?})", check.name, check.duration)?,
+                Err(ref err) => {
+                    writeln!(f, "FAIL  {} ({:?}): {}", check.name, check.duration, err)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ContainerRegistry {
+    /// Pushes, pulls and deletes a synthetic image in `repository`, using `credentials`.
+    ///
+    /// See the [`self_test`](crate::self_test) module for details.
+    pub async fn self_test(&self, repository: &str, credentials: &Unverified) -> SelfTestReport {
+        let mut report = SelfTestReport::default();
+        let location = ImageLocation::new(
+            repository.to_owned(),
+            format!("self-test-{}", Uuid::new_v4()),
+        );
+
+        // Checked by the same provider throughout, even if it is replaced meanwhile.
+        let provider = self.auth_provider();
+        let Some(creds) = report
+            .check("authenticate", async {
+                provider
+                    .check_credentials(credentials)
+                    .await
+                    .ok_or_else(|| "credentials were rejected".to_owned())
+            })
+            .await
+        else {
+            return report;
+        };
+        let authorized = report
+            .check("authorize", async {
+                let permissions = provider.image_permissions(&creds, &location).await;
+                permissions
+                    .require_read()
+                    .and(permissions.require_write())
+                    .map_err(|_| format!("no read and write access to {location}"))
+            })
+            .await;
+        if authorized.is_none() {
+            return report;
+        }
+
+        // Unique contents, so nothing stored by clients is touched.
+        let config = format!(r#"{{"self-test":"{}"}}"#, location.image()).into_bytes();
+        let config_digest = Digest::from_contents(&config);
+        let manifest = format!(
+            r#"{{"schemaVersion":2,"mediaType":"{OCI_IMAGE_MANIFEST}","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{}","size":{}}},"layers":[]}}"#,
+            ImageDigest::new(config_digest),
+            config.len()
+        )
+        .into_bytes();
+        let manifest_reference = ManifestReference::new(
+            location.clone(),
+            Reference::new_digest(Digest::from_contents(&manifest)),
+        );
+
+        let pushed = report
+            .check("upload_blob", self.self_test_upload(&config, config_digest))
+            .await
+            .is_some()
+            && report
+                .check("read_blob", async {
+                    let mut reader = self
+                        .storage
+                        .get_blob_reader(config_digest)
+                        .await
+                        .map_err(|err| err.to_string())?
+                        .ok_or_else(|| "uploaded blob is missing".to_owned())?;
+                    let mut contents = Vec::new();
+                    reader
+                        .read_to_end(&mut contents)
+                        .await
+                        .map_err(|err| err.to_string())?;
+                    if contents != config {
+                        return Err("blob read back differs from upload".to_owned());
+                    }
+                    Ok(())
+                })
+                .await
+                .is_some()
+            && report
+                .check("push_manifest", async {
+                    self.storage
+                        .put_manifest(&manifest_reference, &manifest)
+                        .await
+                        .map(drop)
+                        .map_err(|err| err.to_string())
+                })
+                .await
+                .is_some();
+        if pushed {
+            report
+                .check("pull_manifest", async {
+                    let pulled = self
+                        .storage
+                        .get_manifest(&manifest_reference)
+                        .await
+                        .map_err(|err| err.to_string())?
+                        .ok_or_else(|| "pushed manifest is missing".to_owned())?;
+                    if pulled != manifest {
+                        return Err("manifest pulled differs from push".to_owned());
+                    }
+                    Ok(())
+                })
+                .await;
+        }
+
+        // Cleaning up is attempted regardless of what failed before.
+        report
+            .check("delete", async {
+                let manifest_digest = Digest::from_contents(&manifest);
+                self.storage
+                    .delete_manifest(manifest_digest)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                self.storage
+                    .delete_location(&location)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                self.storage
+                    .delete_blob(config_digest)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                match self.storage.get_blob_metadata(config_digest).await {
+                    Ok(None) => Ok(()),
+                    Ok(Some(_)) => Err("blob still present after deleting it".to_owned()),
+                    Err(err) => Err(err.to_string()),
+                }
+            })
+            .await;
+
+        if report.passed() {
+            info!(%location, "self-test passed");
+        }
+        report
+    }
+
+    /// Uploads `contents` as a new blob, like a monolithic push.
+    async fn self_test_upload(&self, contents: &[u8], digest: Digest) -> Result<(), String> {
+        let upload = self
+            .storage
+            .begin_new_upload()
+            .await
+            .map_err(|err| err.to_string())?;
+
+        let written = async {
+            let mut writer = self.storage.get_upload_writer(0, upload).await?;
+            writer
+                .write_all(contents)
+                .await
+                .map_err(storage::Error::Io)?;
+            writer.shutdown().await.map_err(storage::Error::Io)?;
+            drop(writer);
+            self.storage.finalize_upload(upload, digest).await
+        }
+        .await;
+        if let Err(err) = written {
+            self.storage.cancel_upload(upload).await.ok();
+            return Err(err.to_string());
+        }
+        Ok(())
+    }
+}
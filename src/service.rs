@@ -0,0 +1,109 @@
+//! The registry as a plain [`tower_service::Service`].
+//!
+//! [`ContainerRegistry::make_router`](crate::ContainerRegistry::make_router) returns an axum
+//! [`Router`], which is the most convenient way of serving the registry from an axum application.
+//! Applications built on other frameworks can use a [`RegistryService`] instead, which accepts any
+//! [`http::Request`] whose body implements [`http_body::Body`] and only exposes `http` and
+//! `http-body` types:
+//!
+//! ```
+//! use container_registry::ContainerRegistry;
+//! use tower_service::Service;
+//!
+//!# #[tokio::main(flavor = "current_thread")]
+//!# async fn main() {
+//! let storage = tempdir::TempDir::new("container_registry_test").unwrap();
+//! let registry = ContainerRegistry::builder()
+//!     .storage(storage.path())
+//!     .build()
+//!     .expect("failed to build registry");
+//!
+//! let mut service = registry.into_service();
+//! let response = service
+//!     .call(http::Request::get("/v2/").body(String::new()).unwrap())
+//!     .await
+//!     .unwrap();
+//! assert_eq!(response.status(), http::StatusCode::UNAUTHORIZED);
+//!# }
+//! ```
+//!
+//! With `hyper`, the service can be adapted using `hyper_util::service::TowerToHyperService`.
+
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::{body::Bytes, Router};
+use http_body::{Frame, SizeHint};
+use tower_service::Service;
+
+/// Error type of request and response bodies.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A container registry serving plain HTTP requests.
+///
+/// Created through [`ContainerRegistry::into_service`](crate::ContainerRegistry::into_service).
+/// Cloning a service is cheap, all clones serve the same registry.
+#[derive(Clone)]
+pub struct RegistryService {
+    /// The registry's routes.
+    router: Router,
+}
+
+impl RegistryService {
+    /// Creates a new service serving the given routes.
+    pub(crate) fn new(router: Router) -> Self {
+        Self { router }
+    }
+}
+
+impl<B> Service<http::Request<B>> for RegistryService
+where
+    B: http_body::Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = http::Response<ResponseBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // The router is always ready.
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let mut router = self.router.clone();
+        Box::pin(async move {
+            let response = router.call(request.map(axum::body::Body::new)).await?;
+            Ok(response.map(ResponseBody))
+        })
+    }
+}
+
+/// Body of a response sent by a [`RegistryService`].
+pub struct ResponseBody(axum::body::Body);
+
+impl http_body::Body for ResponseBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.0)
+            .poll_frame(cx)
+            .map_err(|err| err.into_inner())
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.0.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.0.size_hint()
+    }
+}
@@ -2,24 +2,96 @@
 //!
 //! The `container_registry` crate has somewhat modular storage backends, but currently nothing but
 //! filesystem storage is supported. Contact the author if you'd like to see this change.
+//!
+//! Internally, storage is split into a blob store, an upload session store and a manifest store.
+//! The filesystem backend implements all of them, while chunked, tiered and redirected blob
+//! storage only replace the blob half and are combined with filesystem manifests. These traits
+//! are internal and will stay so until their interface is stable, custom backends are not
+//! supported.
+//!
+//! Blobs can optionally be tiered, keeping only recently used blobs on local disk while all blobs
+//! are stored in a [`ColdBlobStore`], see
+//! [`ContainerRegistryBuilder::cold_storage`](crate::ContainerRegistryBuilder::cold_storage).
+//!
+//! Blobs can also be delegated to an object store entirely, with blob downloads redirected to it,
+//! see [`RedirectBlobStore`] and
+//! [`ContainerRegistryBuilder::redirect_storage`](crate::ContainerRegistryBuilder::redirect_storage).
+//!
+//! Blobs can be spread across multiple volumes, see [`BlobPlacement`] and
+//! [`ContainerRegistryBuilder::blob_volume`](crate::ContainerRegistryBuilder::blob_volume).
+//!
+//! Experimentally, blobs can be split into content-defined chunks that are deduplicated across
+//! blobs, see
+//! [`ContainerRegistryBuilder::chunked_blobs`](crate::ContainerRegistryBuilder::chunked_blobs).
+//!
+//! With the `encryption` feature enabled, blobs on local disk can be encrypted at rest, see the
+//! [`encryption`] module.
+//!
+//! For disaster recovery, images can additionally be mirrored into OCI image layouts, see
+//! [`ContainerRegistryBuilder::oci_layout`](crate::ContainerRegistryBuilder::oci_layout).
+//!
+//! Storage can be checked for leftovers of crashes and tags pointing at missing manifests on
+//! startup, see
+//! [`ContainerRegistryBuilder::consistency_check`](crate::ContainerRegistryBuilder::consistency_check).
 // Note: This module is in worse shape, documentation wise, than the rest. Cleaning this up is the
 //       first step towards supporting custom implementations.
+mod batching;
+pub(crate) mod catalog;
+mod chunked;
+mod consistency;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+mod instrumented;
+mod journal;
+#[cfg(any(feature = "test-support", test))]
+mod memory;
+pub(crate) mod oci_layout;
+mod redirect;
+#[cfg(any(feature = "test-support", test))]
+pub mod test_util;
+mod tiered;
+mod volumes;
+
 use std::{
+    collections::HashMap,
     fmt::{self, Display},
     fs,
     io::{self, Read},
     path::{Path, PathBuf},
-    str::FromStr,
+    sync::Arc,
+    time::SystemTime,
 };
 
 use axum::{async_trait, http::StatusCode, response::IntoResponse};
 use serde::{Deserialize, Serialize};
-use sha2::Digest as Sha2Digest;
 use thiserror::Error;
-use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWrite};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+    sync::RwLock,
+};
+use tracing::info;
 use uuid::Uuid;
 
-use super::{types::ImageManifest, ImageDigest};
+use super::{
+    hashing::{self, Hasher},
+    types::{ContentDescriptor, Manifest},
+    ImageDigest,
+};
+
+pub use crate::types::{ImageLocation, ImageLocationParseError, ManifestReference, Reference};
+
+pub(crate) use self::chunked::ChunkedStorage;
+pub use self::consistency::{ConsistencyCheck, ConsistencyReport, Inconsistency};
+pub(crate) use self::instrumented::InstrumentedStorage;
+use self::journal::{Intent, Journal};
+#[cfg(any(feature = "test-support", test))]
+pub(crate) use self::memory::MemoryStorage;
+pub use self::redirect::RedirectBlobStore;
+pub(crate) use self::redirect::RedirectStorage;
+pub(crate) use self::tiered::TieredStorage;
+pub use self::tiered::{ColdBlobInfo, ColdBlobStore};
+pub use self::volumes::BlobPlacement;
+use self::volumes::{Volume, Volumes};
 
 /// Length of a SHA256 hash in bytes.
 pub const SHA256_LEN: usize = 32;
@@ -33,6 +105,40 @@ const BUFFER_SIZE: usize = 1024 * 1024; // 1 MiB
 pub struct Digest([u8; SHA256_LEN]);
 
 impl Digest {
+    /// Digest of a zero-length blob.
+    ///
+    /// Used by some clients for empty layers and configs, e.g. artifacts without any contents.
+    pub const EMPTY: Self = Self::new([
+        0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f, 0xb9,
+        0x24, 0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b, 0x78, 0x52,
+        0xb8, 0x55,
+    ]);
+
+    /// Digest of the well-known empty layer, a gzip compressed tar archive without any entries.
+    ///
+    /// Pushed by Docker for build steps that do not change the file system, e.g. `ENV`.
+    pub const EMPTY_GZIP_LAYER: Self = Self::new([
+        0xa3, 0xed, 0x95, 0xca, 0xeb, 0x02, 0xff, 0xe6, 0x8c, 0xdd, 0x9f, 0xd8, 0x44, 0x06, 0x68,
+        0x0a, 0xe9, 0x3d, 0x63, 0x3c, 0xb1, 0x64, 0x22, 0xd0, 0x0e, 0x8a, 0x7c, 0x22, 0x95, 0x5b,
+        0x46, 0xd4,
+    ]);
+
+    /// Returns the contents of a well-known blob, [`Self::EMPTY`] or [`Self::EMPTY_GZIP_LAYER`].
+    pub(crate) fn known_contents(&self) -> Option<&'static [u8]> {
+        /// The empty layer as pushed by Docker.
+        const EMPTY_GZIP_LAYER: [u8; 32] = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x09, 0x6e, 0x88, 0x00, 0xff, 0x62, 0x18, 0x05, 0xa3,
+            0x60, 0x14, 0x8c, 0x58, 0x00, 0x08, 0x00, 0x00, 0xff, 0xff, 0x2e, 0xaf, 0xb5, 0xef,
+            0x00, 0x04, 0x00, 0x00,
+        ];
+
+        match *self {
+            Self::EMPTY => Some(&[]),
+            Self::EMPTY_GZIP_LAYER => Some(&EMPTY_GZIP_LAYER),
+            _ => None,
+        }
+    }
+
     /// Creates a digest from an existing hash.
     pub const fn new(bytes: [u8; SHA256_LEN]) -> Self {
         Self(bytes)
@@ -40,10 +146,14 @@ impl Digest {
 
     /// Creates a digest by hashing given contents.
     pub fn from_contents(contents: &[u8]) -> Self {
-        let mut hasher = sha2::Sha256::new();
-        hasher.update(contents);
+        Self::new(hashing::Sha256::digest(contents))
+    }
 
-        Self::new(hasher.finalize().into())
+    /// Parses a bare hex-encoded digest, as used in storage file names.
+    pub(crate) fn from_hex_str(raw: &str) -> Option<Self> {
+        <[u8; SHA256_LEN] as hex::FromHex>::from_hex(raw)
+            .ok()
+            .map(Self::new)
     }
 }
 
@@ -60,154 +170,15 @@ struct LayerManifest {
     blob_sum: String,
 }
 
-/// Location of a given image.
-///
-/// In an open container registry, images are stored in what `container-registry` calls
-/// "repository" and "image" pairs. For example, the container image specified as
-/// `bitnami/nginx:latest` would have a repository of `bitnami`, image of `nginx` and tag (which
-/// is not part of [`ImageLocation`] of `latest`.
-#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
-pub struct ImageLocation {
-    /// The repository part of the image location.
-    repository: String,
-    /// The image part of the image location.
-    image: String,
-}
-
-impl Display for ImageLocation {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}/{}", self.repository, self.image)
-    }
-}
-
-/// Refers to a specific manifest.
-///
-/// Combines an [`ImageLocation`] with a [`Reference`], e.g. `bitnami/nginx:latest`, which has an
-/// [`ImageLocation`] portion of `bitnami/nginx` and a [`Reference::Tag`] `latest`.
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct ManifestReference {
-    #[serde(flatten)]
-    location: ImageLocation,
-    reference: Reference,
-}
-
-impl Display for ManifestReference {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}:{}", self.location, self.reference)
-    }
-}
-
-impl ManifestReference {
-    /// Creates a new manifest reference.
-    pub fn new(location: ImageLocation, reference: Reference) -> Self {
-        Self {
-            location,
-            reference,
-        }
-    }
-
-    /// Returns the location portion of the image location.
-    pub fn location(&self) -> &ImageLocation {
-        &self.location
-    }
-
-    /// Returns the reference portion of the image location.
-    pub fn reference(&self) -> &Reference {
-        &self.reference
-    }
-}
-
-impl ImageLocation {
-    /// Creates a new image location.
-    pub fn new(repository: String, image: String) -> Self {
-        Self { repository, image }
-    }
-
-    /// Returns the repository portion of the given image location.
-    #[inline(always)]
-    pub fn repository(&self) -> &str {
-        self.repository.as_ref()
-    }
-
-    /// Returns the image portion of the given image location.
-    #[inline(always)]
-    pub fn image(&self) -> &str {
-        self.image.as_ref()
-    }
-}
-
-/// Reference to a specific version of an image.
-#[derive(Clone, Debug)]
-pub enum Reference {
-    /// Image reference by given tag (e.g. `latest`).
-    Tag(String),
-    /// Image referenced by given specific hash.
-    Digest(Digest),
-}
-
-impl<'de> Deserialize<'de> for Reference {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let raw = <&str>::deserialize(deserializer)?;
-
-        match ImageDigest::from_str(raw) {
-            Ok(digest) => Ok(Self::Digest(digest.digest)),
-            Err(_) => Ok(Self::Tag(raw.to_owned())),
-        }
-    }
-}
-
-impl Serialize for Reference {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        match self {
-            Reference::Tag(tag) => tag.serialize(serializer),
-            Reference::Digest(digest) => ImageDigest::new(*digest).serialize(serializer),
-        }
-    }
-}
-
-impl Reference {
-    /// Creates a new by-tag reference.
-    #[inline(always)]
-    pub fn new_tag<S: ToString>(s: S) -> Self {
-        Reference::Tag(s.to_string())
-    }
-
-    /// Creats a new by-hash reference.
-    #[inline(always)]
-    pub fn new_digest(d: Digest) -> Self {
-        Reference::Digest(d)
-    }
-
-    /// Returns reference as naked tag, if it is a tag.
-    pub fn as_tag(&self) -> Option<&str> {
-        match self {
-            Reference::Tag(tag) => Some(tag),
-            Reference::Digest(_) => None,
-        }
-    }
-}
-
-impl Display for Reference {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Reference::Tag(tag) => Display::fmt(tag, f),
-            Reference::Digest(digest) => Display::fmt(digest, f),
-        }
-    }
-}
-
 /// A storage error.
 #[derive(Debug, Error)]
 pub enum Error {
     /// Attempted to submit data to an upload that does not exist.
     #[error("given upload does not exist")]
     UploadDoesNotExit,
+    /// Attempted to reference a manifest that does not exist.
+    #[error("given manifest does not exist")]
+    ManifestDoesNotExist,
     /// A content hash mismatched.
     #[error("digest did not match")]
     DigestMismatch,
@@ -221,18 +192,29 @@ pub enum Error {
     /// Invalid image manifest submitted.
     #[error("invalid image manifest")]
     InvalidManifest(#[source] serde_json::Error),
-    /// Attempted to store a manifest under a digest instead of a tag.
-    #[error("cannot store manifest under hash")]
-    NotATag,
+    /// The given tag is not a valid tag name.
+    #[error("invalid tag name")]
+    InvalidTag,
+    /// The subject of a manifest is not a valid digest.
+    #[error("invalid manifest subject")]
+    InvalidSubject(#[source] crate::ImageDigestParseError),
+    /// An error in an external storage backend, e.g. a [`ColdBlobStore`].
+    #[error("storage backend error")]
+    Backend(#[source] Box<dyn std::error::Error + Send + Sync>),
 }
 
 impl IntoResponse for Error {
     #[inline]
     fn into_response(self) -> axum::response::Response {
         match self {
-            Error::UploadDoesNotExit => StatusCode::NOT_FOUND.into_response(),
-            Error::InvalidManifest(_) | Error::NotATag => StatusCode::BAD_REQUEST.into_response(),
-            Error::DigestMismatch | Error::Io(_) | Error::BackgroundTaskPanicked(_) => {
+            Error::UploadDoesNotExit | Error::ManifestDoesNotExist => {
+                StatusCode::NOT_FOUND.into_response()
+            }
+            Error::InvalidManifest(_)
+            | Error::InvalidTag
+            | Error::DigestMismatch
+            | Error::InvalidSubject(_) => StatusCode::BAD_REQUEST.into_response(),
+            Error::Io(_) | Error::BackgroundTaskPanicked(_) | Error::Backend(_) => {
                 StatusCode::INTERNAL_SERVER_ERROR.into_response()
             }
         }
@@ -241,13 +223,12 @@ impl IntoResponse for Error {
 
 #[derive(Debug)]
 pub(crate) struct BlobMetadata {
-    #[allow(dead_code)] // TODO
     digest: Digest,
     size: u64,
+    modified: SystemTime,
 }
 
 impl BlobMetadata {
-    #[allow(dead_code)] // TODO
     pub(crate) fn digest(&self) -> Digest {
         self.digest
     }
@@ -255,12 +236,66 @@ impl BlobMetadata {
     pub(crate) fn size(&self) -> u64 {
         self.size
     }
+
+    /// Time the blob was last written, used to protect fresh blobs from garbage collection.
+    pub(crate) fn modified(&self) -> SystemTime {
+        self.modified
+    }
 }
 
-#[async_trait]
-pub(crate) trait RegistryStorage: Send + Sync {
-    async fn begin_new_upload(&self) -> Result<Uuid, Error>;
+/// An in-progress upload, as seen by storage.
+#[derive(Debug)]
+pub(crate) struct UploadMetadata {
+    pub(crate) upload: Uuid,
+    pub(crate) size: u64,
+    pub(crate) modified: SystemTime,
+}
+
+/// A tag stored at a specific location.
+#[derive(Debug)]
+pub(crate) struct TagMetadata {
+    pub(crate) tag: String,
+    pub(crate) digest: Digest,
+    pub(crate) modified: SystemTime,
+}
+
+/// A manifest stored at a specific location.
+#[derive(Debug)]
+pub(crate) struct ManifestMetadata {
+    pub(crate) digest: Digest,
+    /// Time the manifest was first stored at the location.
+    pub(crate) created: SystemTime,
+    /// Tags at the location pointing to the manifest.
+    pub(crate) tags: Vec<String>,
+    /// Summary of the image, `None` for indices.
+    pub(crate) summary: Option<ManifestSummary>,
+}
+
+/// Summary of an image manifest, computed when it is stored.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct ManifestSummary {
+    /// Total compressed size of all layers, in bytes.
+    pub(crate) size: u64,
+    /// Number of layers.
+    pub(crate) layers: usize,
+}
+
+impl ManifestSummary {
+    /// Summarizes a manifest, returns `None` for indices.
+    pub(crate) fn of(manifest: &Manifest) -> Option<Self> {
+        match manifest {
+            Manifest::Image(image) => Some(Self {
+                size: image.layers().iter().map(ContentDescriptor::size).sum(),
+                layers: image.layers().len(),
+            }),
+            Manifest::Index(_) => None,
+        }
+    }
+}
 
+/// Storage of finished, content-addressed blobs.
+#[async_trait]
+pub(crate) trait BlobStore: Send + Sync {
     async fn get_blob_reader(
         &self,
         digest: Digest,
@@ -268,6 +303,48 @@ pub(crate) trait RegistryStorage: Send + Sync {
 
     async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error>;
 
+    /// Lists all stored blobs.
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error>;
+
+    /// Removes a blob. Removing a blob that does not exist is not an error.
+    async fn delete_blob(&self, digest: Digest) -> Result<(), Error>;
+
+    /// Moves a blob whose contents do not match its digest out of the way.
+    ///
+    /// Afterwards, the blob is treated as missing. The default implementation removes it.
+    async fn quarantine_blob(&self, digest: Digest) -> Result<(), Error> {
+        self.delete_blob(digest).await
+    }
+
+    /// Returns the number of bytes used by stored blobs.
+    ///
+    /// The default implementation adds up the sizes of all listed blobs; backends able to report
+    /// usage more cheaply should override it.
+    async fn usage(&self) -> Result<u64, Error> {
+        Ok(self
+            .list_blobs()
+            .await?
+            .iter()
+            .map(BlobMetadata::size)
+            .sum())
+    }
+
+    /// Returns a URL clients can download a blob from directly, instead of through the registry.
+    ///
+    /// The default implementation returns `None`, blobs are served by the registry.
+    async fn blob_url(&self, _digest: Digest) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+}
+
+/// Storage of in-progress uploads.
+///
+/// Finalizing an upload turns it into a blob, so implementations are usually tied to a
+/// [`BlobStore`] of the same backend.
+#[async_trait]
+pub(crate) trait UploadSessionStore: Send + Sync {
+    async fn begin_new_upload(&self) -> Result<Uuid, Error>;
+
     async fn get_upload_writer(
         &self,
         start_at: u64,
@@ -276,6 +353,22 @@ pub(crate) trait RegistryStorage: Send + Sync {
 
     async fn finalize_upload(&self, upload: Uuid, hash: Digest) -> Result<(), Error>;
 
+    /// Returns the number of bytes written to an upload so far.
+    async fn get_upload_size(&self, upload: Uuid) -> Result<u64, Error>;
+
+    /// Lists all uploads that have not been finalized yet.
+    async fn list_uploads(&self) -> Result<Vec<UploadMetadata>, Error>;
+
+    /// Discards an unfinished upload.
+    async fn cancel_upload(&self, upload: Uuid) -> Result<(), Error>;
+
+    /// Discards everything written to an upload past its first `size` bytes.
+    async fn truncate_upload(&self, upload: Uuid, size: u64) -> Result<(), Error>;
+}
+
+/// Storage of manifests, tags and the referrers index.
+#[async_trait]
+pub(crate) trait ManifestStore: Send + Sync {
     async fn get_manifest(
         &self,
         manifest_reference: &ManifestReference,
@@ -286,136 +379,218 @@ pub(crate) trait RegistryStorage: Send + Sync {
         manifest_reference: &ManifestReference,
         manifest: &[u8],
     ) -> Result<Digest, Error>;
-}
 
-/// A filesystem backend error.
-#[derive(Debug, Error)]
-pub enum FilesystemStorageError {
-    /// The storage path given could not be canonicalized.
-    #[error("could not canonicalize root {}", path.display())]
-    CouldNotCanonicalizeRoot {
-        path: PathBuf,
-        #[source]
-        err: io::Error,
-    },
-    /// Failed to create directory in storage path.
-    #[error("could not create directory {}", path.display())]
-    FailedToCreateDir {
-        path: PathBuf,
-        #[source]
-        err: io::Error,
-    },
-}
+    /// Points a tag at an already stored manifest.
+    async fn put_tag(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+        digest: Digest,
+    ) -> Result<(), Error>;
 
-#[derive(Debug)]
-pub(crate) struct FilesystemStorage {
-    uploads: PathBuf,
-    blobs: PathBuf,
-    manifests: PathBuf,
-    tags: PathBuf,
-    rel_manifest_to_blobs: PathBuf,
-}
+    /// Returns descriptors of all manifests at `location` whose subject is `subject`.
+    async fn get_referrers(
+        &self,
+        location: &ImageLocation,
+        subject: Digest,
+    ) -> Result<Vec<ContentDescriptor>, Error>;
+
+    /// Lists the digests of all stored manifests, regardless of location.
+    async fn list_manifest_digests(&self) -> Result<Vec<Digest>, Error>;
+
+    /// Lists the digests of all stored manifests whose hex encoding starts with `prefix`.
+    ///
+    /// The default implementation filters all listed digests; backends able to look up prefixes
+    /// more cheaply should override it.
+    async fn find_manifest_digests(&self, prefix: &str) -> Result<Vec<Digest>, Error> {
+        Ok(self
+            .list_manifest_digests()
+            .await?
+            .into_iter()
+            .filter(|digest| digest.to_string().starts_with(prefix))
+            .collect())
+    }
 
-impl FilesystemStorage {
-    pub(crate) fn new<P: AsRef<Path>>(root: P) -> Result<Self, FilesystemStorageError> {
-        let raw_root = root.as_ref();
-        let root = raw_root.canonicalize().map_err(|err| {
-            FilesystemStorageError::CouldNotCanonicalizeRoot {
-                path: raw_root.to_owned(),
-                err,
-            }
-        })?;
+    /// Removes a manifest. Tags pointing at it are not touched.
+    async fn delete_manifest(&self, digest: Digest) -> Result<(), Error>;
 
-        let uploads = root.join("uploads");
-        let blobs = root.join("blobs");
-        let manifests = root.join("manifests");
-        let tags = root.join("tags");
-        let rel_manifest_to_blobs = PathBuf::from("../../../manifests");
+    /// Lists all locations that have at least one tag.
+    async fn list_locations(&self) -> Result<Vec<ImageLocation>, Error>;
 
-        for dir in [&uploads, &blobs, &manifests, &tags] {
-            if !dir.exists() {
-                fs::create_dir(dir).map_err(|err| FilesystemStorageError::FailedToCreateDir {
-                    path: dir.to_owned(),
-                    err,
-                })?;
-            }
-        }
+    /// Lists all tags at a location.
+    async fn list_tags(&self, location: &ImageLocation) -> Result<Vec<TagMetadata>, Error>;
 
-        Ok(FilesystemStorage {
-            uploads,
-            blobs,
-            manifests,
-            tags,
-            rel_manifest_to_blobs,
-        })
-    }
-    fn blob_path(&self, digest: Digest) -> PathBuf {
-        self.blobs.join(format!("{}", digest))
-    }
-    fn upload_path(&self, upload: Uuid) -> PathBuf {
-        self.uploads.join(format!("{}.partial", upload))
-    }
+    /// Removes a tag. The manifest it points to is not touched.
+    async fn delete_tag(&self, location: &ImageLocation, tag: &str) -> Result<(), Error>;
 
-    fn manifest_path(&self, digest: Digest) -> PathBuf {
-        self.manifests.join(format!("{}", digest))
-    }
+    /// Removes everything recorded about a location: its tags, manifest index, referrers and blob
+    /// links. Manifests and blobs themselves are not touched.
+    async fn delete_location(&self, location: &ImageLocation) -> Result<(), Error>;
 
-    fn blob_rel_path(&self, digest: Digest) -> PathBuf {
-        self.rel_manifest_to_blobs.join(format!("{}", digest))
-    }
+    /// Lists all manifests stored at a location, tagged or not, along with their tags.
+    async fn list_manifests(
+        &self,
+        location: &ImageLocation,
+    ) -> Result<Vec<ManifestMetadata>, Error>;
 
-    fn tag_path(&self, location: &ImageLocation, tag: &str) -> PathBuf {
-        self.tags
-            .join(location.repository())
-            .join(location.image())
-            .join(tag)
-    }
+    /// Records that a blob belongs to a location.
+    ///
+    /// Storing a manifest does not link its blobs, this is up to the registry.
+    async fn link_blob(&self, location: &ImageLocation, digest: Digest) -> Result<(), Error>;
 
-    fn temp_tag_path(&self) -> PathBuf {
-        self.tags.join(Uuid::new_v4().to_string())
-    }
+    /// Checks whether a blob has been linked to a location.
+    async fn is_blob_linked(&self, location: &ImageLocation, digest: Digest)
+        -> Result<bool, Error>;
+
+    /// Lists the locations a blob has been linked to.
+    async fn list_blob_links(&self, digest: Digest) -> Result<Vec<ImageLocation>, Error>;
+}
+
+/// An image manifest referencing a blob, see [`RegistryStorage::blob_referrers`].
+#[derive(Debug)]
+pub(crate) struct BlobReferrer {
+    /// Location the manifest is stored at.
+    pub(crate) location: ImageLocation,
+    /// Digest of the manifest.
+    pub(crate) manifest: Digest,
+    /// Tags at the location pointing to the manifest.
+    pub(crate) tags: Vec<String>,
+    /// Whether the blob is the image's config, rather than one of its layers.
+    pub(crate) is_config: bool,
 }
 
+/// Complete storage of a registry.
+///
+/// Implemented for everything implementing all of [`BlobStore`], [`UploadSessionStore`] and
+/// [`ManifestStore`]. Use [`ComposedStorage`] to combine separate implementations.
 #[async_trait]
-impl RegistryStorage for FilesystemStorage {
-    async fn begin_new_upload(&self) -> Result<Uuid, Error> {
-        let upload = Uuid::new_v4();
-        let out_path = self.upload_path(upload);
+pub(crate) trait RegistryStorage: BlobStore + UploadSessionStore + ManifestStore {
+    /// Returns the image manifests referencing a blob as config or layer, once for every location
+    /// they are stored at.
+    ///
+    /// Reads every image manifest, which takes a while on large registries.
+    async fn blob_referrers(&self, digest: Digest) -> Result<Vec<BlobReferrer>, Error> {
+        // Manifests are often stored at several locations, but only need to be read once.
+        let mut roles: HashMap<Digest, Option<bool>> = HashMap::new();
+        let mut referrers = Vec::new();
+
+        for location in self.list_locations().await? {
+            for manifest in self.list_manifests(&location).await? {
+                // Indices reference manifests only, never blobs.
+                if manifest.summary.is_none() {
+                    continue;
+                }
 
-        // Write zero-sized file.
-        let _file = tokio::fs::File::create(out_path).await.map_err(Error::Io)?;
+                let role = match roles.get(&manifest.digest) {
+                    Some(&role) => role,
+                    None => {
+                        let reference = ManifestReference::new(
+                            location.clone(),
+                            Reference::new_digest(manifest.digest),
+                        );
+                        let role = match self.get_manifest(&reference).await? {
+                            Some(raw) => match Manifest::from_slice(&raw) {
+                                Ok(Manifest::Image(image)) => {
+                                    let references = |descriptor: &ContentDescriptor| {
+                                        descriptor.parsed_digest().ok() == Some(digest)
+                                    };
+                                    if references(image.config()) {
+                                        Some(true)
+                                    } else {
+                                        image.layers().iter().any(references).then_some(false)
+                                    }
+                                }
+                                _ => None,
+                            },
+                            None => None,
+                        };
+                        roles.insert(manifest.digest, role);
+                        role
+                    }
+                };
+
+                if let Some(is_config) = role {
+                    referrers.push(BlobReferrer {
+                        location: location.clone(),
+                        manifest: manifest.digest,
+                        tags: manifest.tags,
+                        is_config,
+                    });
+                }
+            }
+        }
 
-        Ok(upload)
+        Ok(referrers)
     }
+}
 
-    async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
-        let blob_path = self.blob_path(digest);
-
-        if !blob_path.exists() {
-            return Ok(None);
-        }
+impl<T> RegistryStorage for T where T: BlobStore + UploadSessionStore + ManifestStore {}
 
-        let metadata = tokio::fs::metadata(blob_path).await.map_err(Error::Io)?;
+/// Storage combining blobs and uploads from one backend with manifests from another.
+pub(crate) struct ComposedStorage<B, M> {
+    /// Backend for blobs and uploads.
+    blobs: B,
+    /// Backend for manifests.
+    manifests: M,
+}
 
-        Ok(Some(BlobMetadata {
-            digest,
-            size: metadata.len(),
-        }))
+impl<B, M> ComposedStorage<B, M>
+where
+    B: BlobStore + UploadSessionStore,
+    M: ManifestStore,
+{
+    /// Creates a new composed storage.
+    pub(crate) fn new(blobs: B, manifests: M) -> Self {
+        Self { blobs, manifests }
     }
+}
 
+#[async_trait]
+impl<B, M> BlobStore for ComposedStorage<B, M>
+where
+    B: BlobStore,
+    M: Send + Sync,
+{
     async fn get_blob_reader(
         &self,
         digest: Digest,
     ) -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>, Error> {
-        let blob_path = self.blob_path(digest);
+        self.blobs.get_blob_reader(digest).await
+    }
 
-        if !blob_path.exists() {
-            return Ok(None);
-        }
+    async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+        self.blobs.get_blob_metadata(digest).await
+    }
 
-        let reader = tokio::fs::File::open(blob_path).await.map_err(Error::Io)?;
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error> {
+        self.blobs.list_blobs().await
+    }
 
-        Ok(Some(Box::new(reader)))
+    async fn delete_blob(&self, digest: Digest) -> Result<(), Error> {
+        self.blobs.delete_blob(digest).await
+    }
+
+    async fn quarantine_blob(&self, digest: Digest) -> Result<(), Error> {
+        self.blobs.quarantine_blob(digest).await
+    }
+
+    async fn usage(&self) -> Result<u64, Error> {
+        self.blobs.usage().await
+    }
+
+    async fn blob_url(&self, digest: Digest) -> Result<Option<String>, Error> {
+        self.blobs.blob_url(digest).await
+    }
+}
+
+#[async_trait]
+impl<B, M> UploadSessionStore for ComposedStorage<B, M>
+where
+    B: UploadSessionStore,
+    M: Send + Sync,
+{
+    async fn begin_new_upload(&self) -> Result<Uuid, Error> {
+        self.blobs.begin_new_upload().await
     }
 
     async fn get_upload_writer(
@@ -423,34 +598,1207 @@ impl RegistryStorage for FilesystemStorage {
         start_at: u64,
         upload: Uuid,
     ) -> Result<Box<dyn AsyncWrite + Send + Unpin>, Error> {
-        let location = self.upload_path(upload);
+        self.blobs.get_upload_writer(start_at, upload).await
+    }
 
-        if !location.exists() {
-            return Err(Error::UploadDoesNotExit);
-        }
+    async fn finalize_upload(&self, upload: Uuid, hash: Digest) -> Result<(), Error> {
+        self.blobs.finalize_upload(upload, hash).await
+    }
 
-        let mut file = tokio::fs::OpenOptions::new()
-            .append(true)
-            .truncate(false)
-            .open(location)
-            .await
-            .map_err(Error::Io)?;
+    async fn list_uploads(&self) -> Result<Vec<UploadMetadata>, Error> {
+        self.blobs.list_uploads().await
+    }
 
-        file.seek(io::SeekFrom::Start(start_at))
-            .await
-            .map_err(Error::Io)?;
+    async fn cancel_upload(&self, upload: Uuid) -> Result<(), Error> {
+        self.blobs.cancel_upload(upload).await
+    }
 
-        Ok(Box::new(file))
+    async fn truncate_upload(&self, upload: Uuid, size: u64) -> Result<(), Error> {
+        self.blobs.truncate_upload(upload, size).await
     }
 
-    async fn finalize_upload(&self, upload: Uuid, digest: Digest) -> Result<(), Error> {
+    async fn get_upload_size(&self, upload: Uuid) -> Result<u64, Error> {
+        self.blobs.get_upload_size(upload).await
+    }
+}
+
+#[async_trait]
+impl<B, M> ManifestStore for ComposedStorage<B, M>
+where
+    B: Send + Sync,
+    M: ManifestStore,
+{
+    async fn get_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        self.manifests.get_manifest(manifest_reference).await
+    }
+
+    async fn put_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+        manifest: &[u8],
+    ) -> Result<Digest, Error> {
+        self.manifests
+            .put_manifest(manifest_reference, manifest)
+            .await
+    }
+
+    async fn put_tag(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+        digest: Digest,
+    ) -> Result<(), Error> {
+        self.manifests.put_tag(location, tag, digest).await
+    }
+
+    async fn get_referrers(
+        &self,
+        location: &ImageLocation,
+        subject: Digest,
+    ) -> Result<Vec<ContentDescriptor>, Error> {
+        self.manifests.get_referrers(location, subject).await
+    }
+
+    async fn list_manifest_digests(&self) -> Result<Vec<Digest>, Error> {
+        self.manifests.list_manifest_digests().await
+    }
+
+    async fn find_manifest_digests(&self, prefix: &str) -> Result<Vec<Digest>, Error> {
+        self.manifests.find_manifest_digests(prefix).await
+    }
+
+    async fn delete_manifest(&self, digest: Digest) -> Result<(), Error> {
+        self.manifests.delete_manifest(digest).await
+    }
+
+    async fn list_locations(&self) -> Result<Vec<ImageLocation>, Error> {
+        self.manifests.list_locations().await
+    }
+
+    async fn list_tags(&self, location: &ImageLocation) -> Result<Vec<TagMetadata>, Error> {
+        self.manifests.list_tags(location).await
+    }
+
+    async fn delete_tag(&self, location: &ImageLocation, tag: &str) -> Result<(), Error> {
+        self.manifests.delete_tag(location, tag).await
+    }
+
+    async fn delete_location(&self, location: &ImageLocation) -> Result<(), Error> {
+        self.manifests.delete_location(location).await
+    }
+
+    async fn list_manifests(
+        &self,
+        location: &ImageLocation,
+    ) -> Result<Vec<ManifestMetadata>, Error> {
+        self.manifests.list_manifests(location).await
+    }
+
+    async fn link_blob(&self, location: &ImageLocation, digest: Digest) -> Result<(), Error> {
+        self.manifests.link_blob(location, digest).await
+    }
+
+    async fn is_blob_linked(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<bool, Error> {
+        self.manifests.is_blob_linked(location, digest).await
+    }
+
+    async fn list_blob_links(&self, digest: Digest) -> Result<Vec<ImageLocation>, Error> {
+        self.manifests.list_blob_links(digest).await
+    }
+}
+
+/// Hashes everything read from `reader`.
+pub(crate) async fn hash_reader<R: AsyncRead + Unpin>(mut reader: R) -> Result<Digest, Error> {
+    let mut buf = vec![0; BUFFER_SIZE];
+    let mut hasher = hashing::Sha256::default();
+
+    loop {
+        let read = reader.read(buf.as_mut()).await.map_err(Error::Io)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+
+    Ok(Digest::new(hasher.finalize()))
+}
+
+/// Recreates the directory tree at `src` as `dest`, hard linking files and copying symlinks.
+///
+/// Returns the number of files and symlinks created.
+fn link_tree(src: &Path, dest: &Path) -> io::Result<u64> {
+    fs::create_dir(dest)?;
+
+    let mut created = 0;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dest.join(entry.file_name());
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            created += link_tree(&entry.path(), &target)?;
+            continue;
+        }
+
+        if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, target)?;
+        } else {
+            fs::hard_link(entry.path(), target)?;
+        }
+        created += 1;
+    }
+
+    Ok(created)
+}
+
+/// Returns the path of the entry for `digest` at `location` in a per-location tree like the index
+/// or the blob links.
+fn location_entry(root: &Path, location: &ImageLocation, digest: Digest) -> PathBuf {
+    root.join(location.repository())
+        .join(location.image())
+        .join(format!("{}", digest))
+}
+
+/// Creates an empty blob link, keeping it if it already exists.
+fn create_link(link: &Path) -> io::Result<()> {
+    fs::create_dir_all(link.parent().expect("should have parent"))?;
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(link)?;
+    Ok(())
+}
+
+/// Returns the locations holding an entry for `digest` in a per-location tree like the index or
+/// the blob links.
+fn locations_with(root: &Path, digest: Digest) -> io::Result<Vec<ImageLocation>> {
+    let mut locations = Vec::new();
+    let repositories = match fs::read_dir(root) {
+        Ok(repositories) => repositories,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(locations),
+        Err(err) => return Err(err),
+    };
+    for repository in repositories {
+        let repository = repository?;
+        if !repository.file_type()?.is_dir() {
+            continue;
+        }
+        for image in fs::read_dir(repository.path())? {
+            let image = image?;
+            if !image.path().join(format!("{}", digest)).exists() {
+                continue;
+            }
+            if let (Ok(repository), Ok(image)) = (
+                repository.file_name().into_string(),
+                image.file_name().into_string(),
+            ) {
+                locations.push(ImageLocation::new(repository, image));
+            }
+        }
+    }
+
+    Ok(locations)
+}
+
+/// Lists a directory, returning an empty list if it does not exist.
+async fn read_dir_or_empty(dir: &Path) -> Result<Vec<tokio::fs::DirEntry>, Error> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::Io(e)),
+    };
+
+    let mut rv = Vec::new();
+    while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+        rv.push(entry);
+    }
+
+    Ok(rv)
+}
+
+/// Flushes a directory to disk, making entries created, renamed or removed in it durable.
+async fn sync_dir(dir: &Path) -> Result<(), Error> {
+    let dir = tokio::fs::File::open(dir).await.map_err(Error::Io)?;
+    dir.sync_all().await.map_err(Error::Io)
+}
+
+/// Removes a file, ignoring it if it is already gone.
+async fn remove_file_if_exists(path: &Path) -> Result<bool, Error> {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+/// Checks whether a given tag is valid.
+///
+/// Follows the OCI distribution spec, which allows at most 128 characters matching
+/// `[a-zA-Z0-9_][a-zA-Z0-9._-]*`.
+pub(crate) fn is_valid_tag(tag: &str) -> bool {
+    let mut chars = tag.chars();
+
+    match chars.next() {
+        Some(first) if first.is_ascii_alphanumeric() || first == '_' => {}
+        _ => return false,
+    }
+
+    tag.len() <= 128 && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+/// A filesystem backend error.
+#[derive(Debug, Error)]
+pub enum FilesystemStorageError {
+    /// The storage path given could not be canonicalized.
+    #[error("could not canonicalize root {}", path.display())]
+    CouldNotCanonicalizeRoot {
+        path: PathBuf,
+        #[source]
+        err: io::Error,
+    },
+    /// Failed to create directory in storage path.
+    #[error("could not create directory {}", path.display())]
+    FailedToCreateDir {
+        path: PathBuf,
+        #[source]
+        err: io::Error,
+    },
+    /// Failed to scan locally stored blobs.
+    #[error("could not scan blobs in {}", path.display())]
+    FailedToScanBlobs {
+        path: PathBuf,
+        #[source]
+        err: io::Error,
+    },
+    /// Failed to build the manifest index from existing tags.
+    #[error("could not build manifest index from tags in {}", path.display())]
+    FailedToBuildIndex {
+        path: PathBuf,
+        #[source]
+        err: io::Error,
+    },
+    /// Failed to link the blobs of indexed manifests to their locations.
+    #[error("could not link blobs of indexed manifests in {}", path.display())]
+    FailedToBuildLinks {
+        path: PathBuf,
+        #[source]
+        err: io::Error,
+    },
+    /// Failed to replay updates left incomplete in the write-ahead journal.
+    #[error("could not recover from journal in {}", path.display())]
+    FailedToRecoverJournal {
+        path: PathBuf,
+        #[source]
+        err: io::Error,
+    },
+    /// Failed to read the records of interrupted repository purges.
+    #[error("could not read interrupted purges in {}", path.display())]
+    FailedToReadPurges {
+        path: PathBuf,
+        #[source]
+        err: io::Error,
+    },
+    /// Failed to check or repair the consistency of storage.
+    #[error("could not check consistency of {}", path.display())]
+    FailedToCheckConsistency {
+        path: PathBuf,
+        #[source]
+        err: io::Error,
+    },
+    /// The startup consistency check found problems and was configured to refuse starting.
+    #[error("storage is inconsistent, refusing to start: {0}")]
+    Inconsistent(ConsistencyReport),
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct FilesystemStorage {
+    /// Volumes holding uploads and blobs, see the [`volumes`] module.
+    volumes: Volumes,
+    manifests: PathBuf,
+    tags: PathBuf,
+    referrers: PathBuf,
+    /// Index of manifests by location, see [`FilesystemStorage::index_manifest`].
+    index: PathBuf,
+    /// Blobs known to each location, see [`FilesystemStorage::link_blobs`].
+    links: PathBuf,
+    rel_manifest_to_blobs: PathBuf,
+    /// Write-ahead journal for metadata updates, if enabled.
+    journal: Option<Journal>,
+    /// Held shared by every update and exclusively while taking a snapshot, see
+    /// [`FilesystemStorage::snapshot`].
+    writes: Arc<RwLock<()>>,
+    /// Size of the blocks written to uploads, see the [`batching`] module. Zero disables batching.
+    upload_block_size: usize,
+    /// Cipher encrypting blobs at rest, if enabled.
+    #[cfg(feature = "encryption")]
+    cipher: Option<encryption::BlobCipher>,
+}
+
+impl FilesystemStorage {
+    pub(crate) fn new<P: AsRef<Path>>(root: P) -> Result<Self, FilesystemStorageError> {
+        let raw_root = root.as_ref();
+        let root = raw_root.canonicalize().map_err(|err| {
+            FilesystemStorageError::CouldNotCanonicalizeRoot {
+                path: raw_root.to_owned(),
+                err,
+            }
+        })?;
+
+        let manifests = root.join("manifests");
+        let tags = root.join("tags");
+        let referrers = root.join("referrers");
+        let index = root.join("index");
+        let links = root.join("links");
+        let rel_manifest_to_blobs = PathBuf::from("../../../manifests");
+
+        // Storage created before the index existed needs it built from the tags.
+        let build_index = !index.exists();
+        // Likewise, storage created before blob links existed needs them built from the index.
+        let build_links = !links.exists();
+
+        let volume = Volume::open(&root)?;
+        for dir in [&manifests, &tags, &referrers] {
+            if !dir.exists() {
+                fs::create_dir(dir).map_err(|err| FilesystemStorageError::FailedToCreateDir {
+                    path: dir.to_owned(),
+                    err,
+                })?;
+            }
+        }
+
+        let storage = FilesystemStorage {
+            volumes: Volumes::new(volume),
+            manifests,
+            tags,
+            referrers,
+            index,
+            links,
+            rel_manifest_to_blobs,
+            journal: None,
+            writes: Default::default(),
+            upload_block_size: 0,
+            #[cfg(feature = "encryption")]
+            cipher: None,
+        };
+
+        if build_index {
+            storage
+                .build_aside(&storage.index, Self::build_index)
+                .map_err(|err| FilesystemStorageError::FailedToBuildIndex {
+                    path: storage.tags.clone(),
+                    err,
+                })?;
+        }
+
+        if build_links {
+            storage
+                .build_aside(&storage.links, Self::build_links)
+                .map_err(|err| FilesystemStorageError::FailedToBuildLinks {
+                    path: storage.index.clone(),
+                    err,
+                })?;
+        }
+
+        // A journal left behind is always replayed, even if it is no longer enabled.
+        let journal_dir = root.join("journal");
+        if journal_dir.exists() {
+            storage
+                .recover(&Journal::new(journal_dir.clone()))
+                .map_err(|err| FilesystemStorageError::FailedToRecoverJournal {
+                    path: journal_dir,
+                    err,
+                })?;
+        }
+
+        Ok(storage)
+    }
+
+    /// Directories making up a snapshot, see [`FilesystemStorage::snapshot`].
+    ///
+    /// Includes the directories of [`ChunkedStorage`], the markers of tiered storage, the
+    /// quarantine and the records of created repositories, purges and dangling manifests if they
+    /// have been used on this storage.
+    fn snapshot_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = vec![
+            self.volumes.primary().blobs.clone(),
+            self.manifests.clone(),
+            self.tags.clone(),
+            self.referrers.clone(),
+            self.index.clone(),
+            self.links.clone(),
+        ];
+        dirs.extend(
+            [
+                "chunks",
+                "recipes",
+                "cold-pending",
+                "held",
+                "repositories",
+                "purges",
+                "dangling",
+                "archive",
+                "tag-history",
+            ]
+            .into_iter()
+            .map(|name| self.manifests.with_file_name(name))
+            .filter(|dir| dir.exists()),
+        );
+        dirs
+    }
+
+    /// Creates a read-only snapshot of the storage at `destination`, returning the number of files
+    /// it contains.
+    ///
+    /// Updates are suspended while the snapshot is taken, which is fast as files are hard linked
+    /// instead of copied. This is safe because stored files are never modified in place, only
+    /// replaced, but requires `destination` to be on the same filesystem. Uploads in progress are
+    /// not included. The snapshot has the same layout as the storage itself.
+    pub(crate) async fn snapshot(&self, destination: PathBuf) -> Result<u64, Error> {
+        // Hard links cannot span filesystems, which additional volumes usually are.
+        if !self.volumes.is_single() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "snapshots of storage with multiple blob volumes are not supported",
+            )));
+        }
+
+        let _quiesced = self.writes.write().await;
+
+        self.blocking(move |storage| {
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::create_dir(&destination)?;
+
+            let mut files = 0;
+            for dir in storage.snapshot_dirs() {
+                let name = dir
+                    .file_name()
+                    .expect("storage directories should be named");
+                files += link_tree(&dir, &destination.join(name))?;
+            }
+            Ok(files)
+        })
+        .await
+    }
+
+    /// Adds volumes to store blobs on, placing new blobs according to `placement`.
+    ///
+    /// See the [`volumes`] module for details.
+    pub(crate) fn with_volumes(
+        mut self,
+        roots: &[PathBuf],
+        placement: BlobPlacement,
+    ) -> Result<Self, FilesystemStorageError> {
+        for root in roots {
+            self.volumes.add(Volume::open(root)?);
+        }
+        self.volumes.set_placement(placement);
+        Ok(self)
+    }
+
+    /// Enables the write-ahead journal for metadata updates.
+    ///
+    /// See the [`journal`] module for details.
+    pub(crate) fn with_journal(mut self) -> Result<Self, FilesystemStorageError> {
+        let dir = self.manifests.with_file_name("journal");
+        if !dir.exists() {
+            fs::create_dir(&dir).map_err(|err| FilesystemStorageError::FailedToCreateDir {
+                path: dir.clone(),
+                err,
+            })?;
+        }
+
+        self.journal = Some(Journal::new(dir));
+        Ok(self)
+    }
+
+    /// Batches writes to uploads into blocks of `block_size` bytes, zero disables batching.
+    ///
+    /// See the [`batching`] module for details.
+    pub(crate) fn with_upload_block_size(mut self, block_size: usize) -> Self {
+        self.upload_block_size = block_size;
+        self
+    }
+
+    /// Enables encryption of blobs written from now on.
+    ///
+    /// See the [`encryption`] module for details.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn with_encryption(
+        mut self,
+        provider: std::sync::Arc<dyn encryption::KeyProvider>,
+    ) -> Self {
+        self.cipher = Some(encryption::BlobCipher::new(provider));
+        self
+    }
+
+    /// Returns the size of a blob's contents, given the size of its file.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    async fn blob_size(&self, path: &Path, file_size: u64) -> Result<u64, Error> {
+        #[cfg(feature = "encryption")]
+        if let Some(ref cipher) = self.cipher {
+            return cipher.plaintext_size(path).await;
+        }
+
+        Ok(file_size)
+    }
+
+    /// Finalizes an upload by sealing it into an encrypted blob.
+    #[cfg(feature = "encryption")]
+    async fn finalize_sealed(
+        &self,
+        cipher: &encryption::BlobCipher,
+        volume: &Volume,
+        upload_path: PathBuf,
+        digest: Digest,
+    ) -> Result<(), Error> {
+        let sealer = cipher.sealer().await?;
+        let sealed_path = upload_path.with_extension("sealed");
+
+        let actual = {
+            let (upload_path, sealed_path) = (upload_path.clone(), sealed_path.clone());
+            tokio::task::spawn_blocking(move || sealer.seal(&upload_path, &sealed_path))
+        }
+        .await
+        .map_err(Error::BackgroundTaskPanicked)?;
+
+        match actual {
+            Ok(actual) if actual == digest => {}
+            Ok(_) => {
+                remove_file_if_exists(&sealed_path).await?;
+                return Err(Error::DigestMismatch);
+            }
+            Err(err) => {
+                remove_file_if_exists(&sealed_path).await?;
+                return Err(err);
+            }
+        }
+
+        self.store_blob(volume, sealed_path, digest).await?;
+        remove_file_if_exists(&upload_path).await?;
+
+        Ok(())
+    }
+
+    /// Moves a verified blob from `path` into `volume`.
+    ///
+    /// If the blob is already stored on another volume, `path` is removed instead, as blobs are
+    /// content-addressed.
+    async fn store_blob(
+        &self,
+        volume: &Volume,
+        path: PathBuf,
+        digest: Digest,
+    ) -> Result<(), Error> {
+        let _update = self.writes.read().await;
+        match self.volumes.find_blob(digest) {
+            Some(existing) if !existing.starts_with(&volume.blobs) => {
+                remove_file_if_exists(&path).await?;
+                Ok(())
+            }
+            _ => tokio::fs::rename(path, volume.blob_path(digest))
+                .await
+                .map_err(Error::Io),
+        }
+    }
+
+    /// Replays updates left incomplete in `journal`, e.g. by a crash.
+    ///
+    /// Updates whose manifest has been written are completed, all others are discarded.
+    fn recover(&self, journal: &Journal) -> io::Result<()> {
+        // Manifests still being written when the process died are incomplete.
+        for entry in fs::read_dir(&self.manifests)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "tmp") {
+                fs::remove_file(path)?;
+            }
+        }
+
+        for (entry, intent) in journal.pending()? {
+            match intent {
+                Some(Intent::PutManifest {
+                    location,
+                    digest,
+                    tag,
+                }) => {
+                    let digest = digest.digest();
+                    match fs::read(self.manifest_path(digest)) {
+                        Ok(raw) => {
+                            info!(%location, %digest, "completing interrupted manifest upload");
+                            let manifest = Manifest::from_slice(&raw)
+                                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                            self.link_manifest(
+                                &location,
+                                &manifest,
+                                digest,
+                                raw.len() as u64,
+                                tag.as_deref(),
+                            )?;
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                            info!(%location, %digest, "discarding interrupted manifest upload");
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                Some(Intent::PutTag {
+                    location,
+                    tag,
+                    digest,
+                }) => {
+                    let digest = digest.digest();
+                    if self.manifest_path(digest).exists() {
+                        info!(%location, %tag, %digest, "completing interrupted tag update");
+                        self.adopt_manifest(&location, digest)?;
+                        self.link_tag(&location, &tag, digest)?;
+                    }
+                }
+                Some(Intent::DeleteLocation { location }) => {
+                    info!(%location, "completing interrupted location removal");
+                    self.unlink_location(&location)?;
+                }
+                // Torn entries were never acted upon.
+                None => {}
+            }
+
+            fs::remove_file(entry)?;
+        }
+
+        Ok(())
+    }
+
+    /// Durably records an intended update, if the journal is enabled.
+    async fn begin(&self, intent: Intent) -> Result<Option<PathBuf>, Error> {
+        match self.journal {
+            Some(ref journal) => journal.begin(&intent).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Marks an update recorded through [`Self::begin`] as carried out.
+    ///
+    /// Updates failing halfway are not committed, they are completed on the next start, just like
+    /// those interrupted by a crash.
+    async fn commit(&self, entry: Option<PathBuf>) -> Result<(), Error> {
+        match (&self.journal, entry) {
+            (Some(journal), Some(entry)) => journal.commit(entry).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs blocking filesystem operations on a background thread.
+    async fn blocking<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Self) -> io::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let storage = self.clone();
+        tokio::task::spawn_blocking(move || f(&storage))
+            .await
+            .map_err(Error::BackgroundTaskPanicked)?
+            .map_err(Error::Io)
+    }
+
+    /// Writes a manifest atomically, so it is never visible partially written.
+    async fn write_manifest(&self, digest: Digest, manifest: &[u8]) -> Result<(), Error> {
+        let tmp = self
+            .manifests
+            .join(format!("{}.{}.tmp", digest, Uuid::new_v4()));
+
+        let mut file = tokio::fs::File::create(&tmp).await.map_err(Error::Io)?;
+        file.write_all(manifest).await.map_err(Error::Io)?;
+        if self.journal.is_some() {
+            // Recovery relies on the manifest's contents having made it to disk.
+            file.sync_all().await.map_err(Error::Io)?;
+        } else {
+            file.flush().await.map_err(Error::Io)?;
+        }
+        drop(file);
+
+        tokio::fs::rename(tmp, self.manifest_path(digest))
+            .await
+            .map_err(Error::Io)
+    }
+
+    /// Adds a stored manifest to the location and referrers indices, tagging it if requested.
+    fn link_manifest(
+        &self,
+        location: &ImageLocation,
+        manifest: &Manifest,
+        digest: Digest,
+        size: u64,
+        tag: Option<&str>,
+    ) -> io::Result<()> {
+        self.index_manifest(location, digest, ManifestSummary::of(manifest))?;
+
+        // Manifests with a subject are indexed, so they can be found through the referrers API.
+        if let Some(Ok(subject)) = manifest.subject().map(|subject| subject.parsed_digest()) {
+            let referrers_dir = self.referrers_dir(location, subject);
+            fs::create_dir_all(&referrers_dir)?;
+
+            let descriptor = ContentDescriptor::for_manifest(manifest, digest, size);
+            let descriptor_json =
+                serde_json::to_vec(&descriptor).expect("serialization should not fail");
+            fs::write(referrers_dir.join(format!("{}", digest)), descriptor_json)?;
+        }
+
+        if let Some(tag) = tag {
+            self.link_tag(location, tag, digest)?;
+        }
+
+        Ok(())
+    }
+
+    /// Atomically points a tag at a manifest.
+    fn link_tag(&self, location: &ImageLocation, tag: &str, digest: Digest) -> io::Result<()> {
+        let tag = self.tag_path(location, tag);
+        fs::create_dir_all(tag.parent().expect("should have parent"))?;
+
+        let tmp_tag = self.temp_tag_path();
+        std::os::unix::fs::symlink(self.blob_rel_path(digest), &tmp_tag)?;
+        fs::rename(tmp_tag, tag)
+    }
+
+    /// Builds `dir` from scratch next to it, moving it into place once complete.
+    ///
+    /// A build interrupted by a crash thus leaves no partially built directory behind that would
+    /// be taken for a complete one on the next start. Leftovers of such builds are discarded.
+    fn build_aside(
+        &self,
+        dir: &Path,
+        build: impl FnOnce(&Self, &Path) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let building = dir.with_extension("building");
+        match fs::remove_dir_all(&building) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        fs::create_dir(&building)?;
+        build(self, &building)?;
+        fs::rename(&building, dir)?;
+        fs::File::open(dir.parent().expect("should have parent"))?.sync_all()
+    }
+
+    /// Adds every tagged manifest to the index being built in `index`, dating entries to the time
+    /// they were tagged.
+    fn build_index(&self, index: &Path) -> io::Result<()> {
+        let dirs = |path: &Path| -> io::Result<Vec<fs::DirEntry>> {
+            fs::read_dir(path)?
+                .filter(|entry| {
+                    entry
+                        .as_ref()
+                        .map_or(true, |entry| entry.file_type().is_ok_and(|ty| ty.is_dir()))
+                })
+                .collect()
+        };
+
+        for repository in dirs(&self.tags)? {
+            for image in dirs(&repository.path())? {
+                let (Ok(repository), Ok(image)) = (
+                    repository.file_name().into_string(),
+                    image.file_name().into_string(),
+                ) else {
+                    continue;
+                };
+                let location = ImageLocation::new(repository, image);
+                let location_dir = self.tags.join(location.repository()).join(location.image());
+
+                for tag in fs::read_dir(location_dir)? {
+                    let tag = tag?;
+                    let Some(digest) = fs::read_link(tag.path())?
+                        .file_name()
+                        .and_then(|name| name.to_str())
+                        .and_then(Digest::from_hex_str)
+                    else {
+                        continue;
+                    };
+
+                    let entry = location_entry(index, &location, digest);
+                    fs::create_dir_all(entry.parent().expect("should have parent"))?;
+                    let mut file = fs::File::create(entry)?;
+                    // Manifests that cannot be read are summarized when listed instead.
+                    if let Ok(summary) = self.stored_summary(digest) {
+                        serde_json::to_writer(&mut file, &summary)?;
+                    }
+                    file.set_modified(fs::symlink_metadata(tag.path())?.modified()?)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Links the blobs of every indexed image manifest to the locations it is stored at, in the
+    /// links being built in `links`.
+    fn build_links(&self, links: &Path) -> io::Result<()> {
+        for repository in fs::read_dir(&self.index)? {
+            let repository = repository?;
+            if !repository.file_type()?.is_dir() {
+                continue;
+            }
+            for image in fs::read_dir(repository.path())? {
+                let image = image?;
+                let (Ok(repository), Ok(image_name)) = (
+                    repository.file_name().into_string(),
+                    image.file_name().into_string(),
+                ) else {
+                    continue;
+                };
+                let location = ImageLocation::new(repository, image_name);
+
+                for entry in fs::read_dir(image.path())? {
+                    let Some(digest) = entry?.file_name().to_str().and_then(Digest::from_hex_str)
+                    else {
+                        continue;
+                    };
+                    // Manifests removed since are skipped, as are unreadable ones.
+                    if let Ok(manifest) = self.read_manifest(digest) {
+                        self.link_blobs(links, &location, &manifest)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records that a manifest is stored at a location.
+    ///
+    /// The index holds a file per manifest and location, whose modification time is the time the
+    /// manifest was first stored there. Manifests themselves are stored independent of their
+    /// location, so this is the only way of listing untagged manifests of a location. Entries
+    /// contain the manifest's [`ManifestSummary`] as JSON, `null` for indices.
+    fn index_manifest(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+        summary: Option<ManifestSummary>,
+    ) -> io::Result<()> {
+        let entry = self.index_path(location, digest);
+        fs::create_dir_all(entry.parent().expect("should have parent"))?;
+
+        // Re-indexing must not reset the creation time.
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(entry)
+        {
+            Ok(file) => Ok(serde_json::to_writer(file, &summary)?),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Adds a manifest stored at another location to the indices of `location`.
+    fn adopt_manifest(&self, location: &ImageLocation, digest: Digest) -> io::Result<()> {
+        let manifest = self.read_manifest(digest)?;
+        self.index_manifest(location, digest, ManifestSummary::of(&manifest))
+    }
+
+    /// Links the config and layers of an image manifest to a location.
+    ///
+    /// The links record which blobs are part of a location, allowing blob access to be scoped to
+    /// repositories, see
+    /// [`ContainerRegistryBuilder::scope_blobs`](crate::ContainerRegistryBuilder::scope_blobs).
+    /// Like index entries, links are empty files. Only used to link the blobs of manifests stored
+    /// before links existed into the `links` being built, see [`FilesystemStorage::build_links`].
+    fn link_blobs(
+        &self,
+        links: &Path,
+        location: &ImageLocation,
+        manifest: &Manifest,
+    ) -> io::Result<()> {
+        let Manifest::Image(image) = manifest else {
+            return Ok(());
+        };
+
+        for descriptor in std::iter::once(image.config()).chain(image.layers()) {
+            if let Ok(digest) = descriptor.parsed_digest() {
+                create_link(&location_entry(links, location, digest))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Links a single blob to a location.
+    fn link_blob_sync(&self, location: &ImageLocation, digest: Digest) -> io::Result<()> {
+        create_link(&self.link_path(location, digest))
+    }
+
+    /// Returns the locations a blob is linked to.
+    fn blob_links(&self, digest: Digest) -> io::Result<Vec<ImageLocation>> {
+        locations_with(&self.links, digest)
+    }
+
+    /// Removes the index entries of a deleted manifest at all locations.
+    fn unindex_manifest(&self, digest: Digest) -> io::Result<()> {
+        for location in locations_with(&self.index, digest)? {
+            match fs::remove_file(self.index_path(&location, digest)) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the tags, indices and blob links of a location.
+    ///
+    /// Tags go first, so an interrupted removal never leaves the location listed without its
+    /// manifests. Repository directories left empty are removed as well.
+    fn unlink_location(&self, location: &ImageLocation) -> io::Result<()> {
+        for root in [&self.tags, &self.index, &self.referrers, &self.links] {
+            let repository_dir = root.join(location.repository());
+            match fs::remove_dir_all(repository_dir.join(location.image())) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+
+            // Fails if other images remain, which is fine.
+            let _ = fs::remove_dir(repository_dir);
+        }
+
+        Ok(())
+    }
+
+    /// Reads and parses a stored manifest.
+    fn read_manifest(&self, digest: Digest) -> io::Result<Manifest> {
+        let raw = fs::read(self.manifest_path(digest))?;
+        Manifest::from_slice(&raw).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Summarizes a stored manifest.
+    fn stored_summary(&self, digest: Digest) -> io::Result<Option<ManifestSummary>> {
+        Ok(ManifestSummary::of(&self.read_manifest(digest)?))
+    }
+
+    /// Returns the path of a blob on the volume storing it, or in the storage path if missing.
+    fn blob_path(&self, digest: Digest) -> PathBuf {
+        self.volumes
+            .find_blob(digest)
+            .unwrap_or_else(|| self.volumes.primary().blob_path(digest))
+    }
+
+    /// Returns the path of an upload on the volume holding it, or in the storage path if missing.
+    fn upload_path(&self, upload: Uuid) -> PathBuf {
+        self.volumes.find_upload(upload).map_or_else(
+            || self.volumes.primary().upload_path(upload),
+            |(_, path)| path,
+        )
+    }
+
+    fn manifest_path(&self, digest: Digest) -> PathBuf {
+        self.manifests.join(format!("{}", digest))
+    }
+
+    fn blob_rel_path(&self, digest: Digest) -> PathBuf {
+        self.rel_manifest_to_blobs.join(format!("{}", digest))
+    }
+
+    fn tag_path(&self, location: &ImageLocation, tag: &str) -> PathBuf {
+        self.tags
+            .join(location.repository())
+            .join(location.image())
+            .join(tag)
+    }
+
+    fn referrers_dir(&self, location: &ImageLocation, subject: Digest) -> PathBuf {
+        self.referrers
+            .join(location.repository())
+            .join(location.image())
+            .join(format!("{}", subject))
+    }
+
+    fn link_path(&self, location: &ImageLocation, digest: Digest) -> PathBuf {
+        location_entry(&self.links, location, digest)
+    }
+
+    fn index_path(&self, location: &ImageLocation, digest: Digest) -> PathBuf {
+        location_entry(&self.index, location, digest)
+    }
+
+    fn temp_tag_path(&self) -> PathBuf {
+        self.tags.join(Uuid::new_v4().to_string())
+    }
+}
+
+#[async_trait]
+impl BlobStore for FilesystemStorage {
+    async fn get_blob_reader(
+        &self,
+        digest: Digest,
+    ) -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>, Error> {
+        let blob_path = self.blob_path(digest);
+
+        if !blob_path.exists() {
+            return Ok(None);
+        }
+
+        // Nothing to read or decrypt for the empty blob, it is only stored to record its presence.
+        if digest == Digest::EMPTY {
+            return Ok(Some(Box::new(tokio::io::empty())));
+        }
+
+        let reader = tokio::fs::File::open(blob_path).await.map_err(Error::Io)?;
+
+        #[cfg(feature = "encryption")]
+        if let Some(ref cipher) = self.cipher {
+            return cipher.open(reader).await.map(Some);
+        }
+
+        Ok(Some(Box::new(reader)))
+    }
+
+    async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+        let blob_path = self.blob_path(digest);
+
+        if !blob_path.exists() {
+            return Ok(None);
+        }
+
+        let metadata = tokio::fs::metadata(&blob_path).await.map_err(Error::Io)?;
+
+        Ok(Some(BlobMetadata {
+            digest,
+            size: self.blob_size(&blob_path, metadata.len()).await?,
+            modified: metadata.modified().map_err(Error::Io)?,
+        }))
+    }
+
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error> {
+        let mut blobs = Vec::new();
+        for volume in self.volumes.iter() {
+            for entry in read_dir_or_empty(&volume.blobs).await? {
+                let Some(digest) = entry.file_name().to_str().and_then(Digest::from_hex_str) else {
+                    continue;
+                };
+
+                let metadata = entry.metadata().await.map_err(Error::Io)?;
+                blobs.push(BlobMetadata {
+                    digest,
+                    size: self.blob_size(&entry.path(), metadata.len()).await?,
+                    modified: metadata.modified().map_err(Error::Io)?,
+                });
+            }
+        }
+
+        Ok(blobs)
+    }
+
+    async fn delete_blob(&self, digest: Digest) -> Result<(), Error> {
+        let _update = self.writes.read().await;
+        for volume in self.volumes.iter() {
+            remove_file_if_exists(&volume.blob_path(digest)).await?;
+        }
+        Ok(())
+    }
+
+    /// Moves the blob to the `quarantine` directory of its volume, where it is kept for inspection.
+    async fn quarantine_blob(&self, digest: Digest) -> Result<(), Error> {
+        let _update = self.writes.read().await;
+        for volume in self.volumes.iter() {
+            let quarantine = volume.quarantine();
+            tokio::fs::create_dir_all(&quarantine)
+                .await
+                .map_err(Error::Io)?;
+
+            match tokio::fs::rename(
+                volume.blob_path(digest),
+                quarantine.join(digest.to_string()),
+            )
+            .await
+            {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(Error::Io(e)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UploadSessionStore for FilesystemStorage {
+    async fn begin_new_upload(&self) -> Result<Uuid, Error> {
+        let upload = Uuid::new_v4();
+        let out_path = self.volumes.place().upload_path(upload);
+
+        // Write zero-sized file.
+        let _file = tokio::fs::File::create(out_path).await.map_err(Error::Io)?;
+
+        Ok(upload)
+    }
+
+    async fn get_upload_writer(
+        &self,
+        start_at: u64,
+        upload: Uuid,
+    ) -> Result<Box<dyn AsyncWrite + Send + Unpin>, Error> {
+        let location = self.upload_path(upload);
+
+        if !location.exists() {
+            return Err(Error::UploadDoesNotExit);
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .truncate(false)
+            .open(location)
+            .await
+            .map_err(Error::Io)?;
+
+        file.seek(io::SeekFrom::Start(start_at))
+            .await
+            .map_err(Error::Io)?;
+
+        if self.upload_block_size == 0 {
+            return Ok(Box::new(file));
+        }
+        Ok(Box::new(batching::BatchingWriter::new(
+            file,
+            self.upload_block_size,
+        )))
+    }
+
+    async fn finalize_upload(&self, upload: Uuid, digest: Digest) -> Result<(), Error> {
         // We are to validate the uploaded partial, then move it into the proper store.
         // TODO: Lock in place so that the hash cannot be corrupted/attacked.
 
-        let upload_path = self.upload_path(upload);
-
-        if !upload_path.exists() {
+        let Some((volume, upload_path)) = self.volumes.find_upload(upload) else {
             return Err(Error::UploadDoesNotExit);
+        };
+
+        #[cfg(feature = "encryption")]
+        if let Some(ref cipher) = self.cipher {
+            return self
+                .finalize_sealed(cipher, volume, upload_path, digest)
+                .await;
+        }
+
+        // Empty uploads need no hashing, they can only ever be the empty blob.
+        let size = tokio::fs::metadata(&upload_path)
+            .await
+            .map_err(Error::Io)?
+            .len();
+        if size == 0 {
+            if digest != Digest::EMPTY {
+                return Err(Error::DigestMismatch);
+            }
+            return self.store_blob(volume, upload_path, digest).await;
         }
 
         // We offload hashing to a blocking thread.
@@ -461,7 +1809,7 @@ impl RegistryStorage for FilesystemStorage {
 
                 // Uses `vec!` instead of `Box`, as initializing the latter blows the stack:
                 let mut buf = vec![0; BUFFER_SIZE];
-                let mut hasher = sha2::Sha256::new();
+                let mut hasher = hashing::Sha256::default();
 
                 loop {
                     let read = src.read(buf.as_mut()).map_err(Error::Io)?;
@@ -471,8 +1819,7 @@ impl RegistryStorage for FilesystemStorage {
                     hasher.update(&buf[..read]);
                 }
 
-                let actual = hasher.finalize();
-                Ok(Digest::new(actual.into()))
+                Ok(Digest::new(hasher.finalize()))
             })
         }
         .await
@@ -483,15 +1830,71 @@ impl RegistryStorage for FilesystemStorage {
         }
 
         // The uploaded file matches, we can rename it now.
-        let dest = self.blob_path(digest);
-        tokio::fs::rename(upload_path, dest)
-            .await
-            .map_err(Error::Io)?;
+        self.store_blob(volume, upload_path, digest).await?;
 
         // All good.
         Ok(())
     }
 
+    async fn list_uploads(&self) -> Result<Vec<UploadMetadata>, Error> {
+        let mut uploads = Vec::new();
+        for volume in self.volumes.iter() {
+            for entry in read_dir_or_empty(&volume.uploads).await? {
+                let Some(upload) = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(".partial"))
+                    .and_then(|name| Uuid::parse_str(name).ok())
+                else {
+                    continue;
+                };
+
+                let metadata = entry.metadata().await.map_err(Error::Io)?;
+                uploads.push(UploadMetadata {
+                    upload,
+                    size: metadata.len(),
+                    modified: metadata.modified().map_err(Error::Io)?,
+                });
+            }
+        }
+
+        Ok(uploads)
+    }
+
+    async fn cancel_upload(&self, upload: Uuid) -> Result<(), Error> {
+        if remove_file_if_exists(&self.upload_path(upload)).await? {
+            Ok(())
+        } else {
+            Err(Error::UploadDoesNotExit)
+        }
+    }
+
+    async fn truncate_upload(&self, upload: Uuid, size: u64) -> Result<(), Error> {
+        let file = match tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(self.upload_path(upload))
+            .await
+        {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::UploadDoesNotExit)
+            }
+            Err(err) => return Err(Error::Io(err)),
+        };
+        file.set_len(size).await.map_err(Error::Io)
+    }
+
+    async fn get_upload_size(&self, upload: Uuid) -> Result<u64, Error> {
+        match tokio::fs::metadata(self.upload_path(upload)).await {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(Error::UploadDoesNotExit),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+}
+
+#[async_trait]
+impl ManifestStore for FilesystemStorage {
     async fn get_manifest(
         &self,
         manifest_reference: &ManifestReference,
@@ -514,36 +1917,267 @@ impl RegistryStorage for FilesystemStorage {
         manifest: &[u8],
     ) -> Result<Digest, Error> {
         // TODO: Validate all blobs are completely uploaded.
-        let _manifest: ImageManifest =
-            serde_json::from_slice(manifest).map_err(Error::InvalidManifest)?;
+        let parsed = Manifest::from_slice(manifest).map_err(Error::InvalidManifest)?;
 
         let digest = Digest::from_contents(manifest);
-        let dest = self.manifest_path(digest);
-        tokio::fs::write(dest, &manifest).await.map_err(Error::Io)?;
-
-        let tag = self.tag_path(
-            manifest_reference.location(),
-            manifest_reference
-                .reference()
-                .as_tag()
-                .ok_or(Error::NotATag)?,
-        );
 
-        let tag_parent = tag.parent().expect("should have parent");
+        match manifest_reference.reference() {
+            Reference::Tag(tag) if !is_valid_tag(tag) => return Err(Error::InvalidTag),
+            Reference::Digest(expected) if *expected != digest => {
+                return Err(Error::DigestMismatch)
+            }
+            _ => {}
+        }
+
+        // Referrers are only indexed once the manifest has been written, validate upfront.
+        parsed
+            .subject()
+            .map(|subject| subject.parsed_digest())
+            .transpose()
+            .map_err(Error::InvalidSubject)?;
+
+        let location = manifest_reference.location().clone();
+        let tag = manifest_reference
+            .reference()
+            .as_tag()
+            .map(ToOwned::to_owned);
+        let _update = self.writes.read().await;
+        let entry = self
+            .begin(Intent::PutManifest {
+                location: location.clone(),
+                digest: ImageDigest::new(digest),
+                tag: tag.clone(),
+            })
+            .await?;
+
+        self.write_manifest(digest, manifest).await?;
+
+        let size = manifest.len() as u64;
+        self.blocking(move |storage| {
+            storage.link_manifest(&location, &parsed, digest, size, tag.as_deref())
+        })
+        .await?;
+
+        self.commit(entry).await?;
+
+        Ok(digest)
+    }
+
+    async fn put_tag(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+        digest: Digest,
+    ) -> Result<(), Error> {
+        if !is_valid_tag(tag) {
+            return Err(Error::InvalidTag);
+        }
+
+        if !self.manifest_path(digest).exists() {
+            return Err(Error::ManifestDoesNotExist);
+        }
+
+        let _update = self.writes.read().await;
+        let entry = self
+            .begin(Intent::PutTag {
+                location: location.clone(),
+                tag: tag.to_owned(),
+                digest: ImageDigest::new(digest),
+            })
+            .await?;
+
+        let (location, tag) = (location.clone(), tag.to_owned());
+        self.blocking(move |storage| {
+            // Tags may point at manifests originally pushed to another location.
+            storage.adopt_manifest(&location, digest)?;
+            storage.link_tag(&location, &tag, digest)
+        })
+        .await?;
+
+        self.commit(entry).await
+    }
+
+    async fn get_referrers(
+        &self,
+        location: &ImageLocation,
+        subject: Digest,
+    ) -> Result<Vec<ContentDescriptor>, Error> {
+        let mut referrers = Vec::new();
+        for entry in read_dir_or_empty(&self.referrers_dir(location, subject)).await? {
+            // Skip referrers whose manifest has since been removed.
+            let still_exists = entry
+                .file_name()
+                .to_str()
+                .and_then(Digest::from_hex_str)
+                .is_some_and(|digest| self.manifest_path(digest).exists());
+            if !still_exists {
+                continue;
+            }
+
+            let raw = tokio::fs::read(entry.path()).await.map_err(Error::Io)?;
+            referrers.push(serde_json::from_slice(&raw).map_err(Error::InvalidManifest)?);
+        }
+
+        Ok(referrers)
+    }
+
+    async fn list_manifest_digests(&self) -> Result<Vec<Digest>, Error> {
+        Ok(read_dir_or_empty(&self.manifests)
+            .await?
+            .into_iter()
+            .filter_map(|entry| entry.file_name().to_str().and_then(Digest::from_hex_str))
+            .collect())
+    }
+
+    async fn delete_manifest(&self, digest: Digest) -> Result<(), Error> {
+        let _update = self.writes.read().await;
+        remove_file_if_exists(&self.manifest_path(digest)).await?;
+        // Entries left behind if this fails are skipped when listing manifests.
+        self.blocking(move |storage| storage.unindex_manifest(digest))
+            .await
+    }
+
+    async fn list_locations(&self) -> Result<Vec<ImageLocation>, Error> {
+        let mut locations = Vec::new();
+        for repository in read_dir_or_empty(&self.tags).await? {
+            // Temporary tags live directly inside the tags directory and are skipped here.
+            if !repository.file_type().await.map_err(Error::Io)?.is_dir() {
+                continue;
+            }
+            let Ok(repository_name) = repository.file_name().into_string() else {
+                continue;
+            };
+
+            for image in read_dir_or_empty(&repository.path()).await? {
+                if let Ok(image_name) = image.file_name().into_string() {
+                    locations.push(ImageLocation::new(repository_name.clone(), image_name));
+                }
+            }
+        }
+
+        Ok(locations)
+    }
+
+    async fn list_tags(&self, location: &ImageLocation) -> Result<Vec<TagMetadata>, Error> {
+        let location_dir = self.tags.join(location.repository()).join(location.image());
+
+        let mut tags = Vec::new();
+        for entry in read_dir_or_empty(&location_dir).await? {
+            let Ok(tag) = entry.file_name().into_string() else {
+                continue;
+            };
 
-        if !tag_parent.exists() {
-            tokio::fs::create_dir_all(tag_parent)
+            let target = tokio::fs::read_link(entry.path())
                 .await
                 .map_err(Error::Io)?;
+            let Some(digest) = target
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(Digest::from_hex_str)
+            else {
+                continue;
+            };
+
+            let metadata = tokio::fs::symlink_metadata(entry.path())
+                .await
+                .map_err(Error::Io)?;
+            tags.push(TagMetadata {
+                tag,
+                digest,
+                modified: metadata.modified().map_err(Error::Io)?,
+            });
         }
 
-        let tmp_tag = self.temp_tag_path();
+        Ok(tags)
+    }
+
+    async fn delete_tag(&self, location: &ImageLocation, tag: &str) -> Result<(), Error> {
+        let _update = self.writes.read().await;
+        remove_file_if_exists(&self.tag_path(location, tag)).await?;
+        Ok(())
+    }
+
+    async fn delete_location(&self, location: &ImageLocation) -> Result<(), Error> {
+        let _update = self.writes.read().await;
+        let entry = self
+            .begin(Intent::DeleteLocation {
+                location: location.clone(),
+            })
+            .await?;
+
+        let location = location.clone();
+        self.blocking(move |storage| storage.unlink_location(&location))
+            .await?;
+
+        self.commit(entry).await
+    }
+
+    async fn list_manifests(
+        &self,
+        location: &ImageLocation,
+    ) -> Result<Vec<ManifestMetadata>, Error> {
+        let mut tags: HashMap<Digest, Vec<String>> = HashMap::new();
+        for tag in self.list_tags(location).await? {
+            tags.entry(tag.digest).or_default().push(tag.tag);
+        }
+
+        let location_dir = self
+            .index
+            .join(location.repository())
+            .join(location.image());
+
+        let mut manifests = Vec::new();
+        for entry in read_dir_or_empty(&location_dir).await? {
+            let Some(digest) = entry.file_name().to_str().and_then(Digest::from_hex_str) else {
+                continue;
+            };
+
+            // Entries of deleted manifests may be left behind by a crash, they are not removed on
+            // this read path though, as the manifest may be stored again concurrently.
+            if !self.manifest_path(digest).exists() {
+                continue;
+            }
+
+            let metadata = entry.metadata().await.map_err(Error::Io)?;
+            let contents = tokio::fs::read(entry.path()).await.map_err(Error::Io)?;
+            let summary = match serde_json::from_slice(&contents) {
+                Ok(summary) => summary,
+                // Entries written by older versions are empty, summarize their manifest instead.
+                Err(_) => {
+                    self.blocking(move |storage| storage.stored_summary(digest))
+                        .await?
+                }
+            };
+            manifests.push(ManifestMetadata {
+                digest,
+                created: metadata.modified().map_err(Error::Io)?,
+                tags: tags.remove(&digest).unwrap_or_default(),
+                summary,
+            });
+        }
+
+        Ok(manifests)
+    }
 
-        tokio::fs::symlink(self.blob_rel_path(digest), &tmp_tag)
+    async fn link_blob(&self, location: &ImageLocation, digest: Digest) -> Result<(), Error> {
+        let location = location.clone();
+        let _update = self.writes.read().await;
+        self.blocking(move |storage| storage.link_blob_sync(&location, digest))
             .await
-            .map_err(Error::Io)?;
-        tokio::fs::rename(tmp_tag, tag).await.map_err(Error::Io)?;
+    }
 
-        Ok(digest)
+    async fn is_blob_linked(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<bool, Error> {
+        tokio::fs::try_exists(self.link_path(location, digest))
+            .await
+            .map_err(Error::Io)
+    }
+
+    async fn list_blob_links(&self, digest: Digest) -> Result<Vec<ImageLocation>, Error> {
+        self.blocking(move |storage| storage.blob_links(digest))
+            .await
     }
 }
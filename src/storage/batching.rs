@@ -0,0 +1,145 @@
+//! Batching of upload writes.
+//!
+//! Some clients push blobs in many small chunks, e.g. 64 KiB each, and request bodies arrive in
+//! even smaller frames. Writing each of them to the upload file right away costs a syscall per
+//! frame and fragments large files. Upload writers thus collect incoming data in a buffer and
+//! only write it out in blocks of the buffer's size, see
+//! [`ContainerRegistryBuilder::upload_write_buffer`](crate::ContainerRegistryBuilder::upload_write_buffer).
+//! Whatever is left in the buffer is written when the writer is flushed, which happens at the end
+//! of every chunk, so the stored size of an upload always reflects all received chunks.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::AsyncWrite;
+
+/// A writer batching writes into blocks of a fixed size.
+pub(super) struct BatchingWriter<W> {
+    /// The wrapped writer.
+    inner: W,
+    /// Data not yet written to `inner`.
+    buf: Vec<u8>,
+    /// Size of the blocks written to `inner`.
+    block_size: usize,
+    /// Number of bytes at the start of `buf` already written to `inner`.
+    written: usize,
+}
+
+impl<W> BatchingWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    /// Wraps `inner`, writing to it in blocks of `block_size` bytes.
+    pub(super) fn new(inner: W, block_size: usize) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(block_size),
+            block_size,
+            written: 0,
+        }
+    }
+
+    /// Writes all buffered data to the wrapped writer.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.buf.len() {
+            let written =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.buf[self.written..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += written;
+        }
+
+        self.buf.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W> AsyncWrite for BatchingWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // Full blocks are only written once more data arrives, or on flush.
+        if self.buf.len() == self.block_size {
+            ready!(self.poll_drain(cx))?;
+        }
+
+        let accepted = data.len().min(self.block_size - self.buf.len());
+        self.buf.extend_from_slice(&data[..accepted]);
+        Poll::Ready(Ok(accepted))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+    use super::BatchingWriter;
+
+    /// Records the size of every write.
+    #[derive(Default)]
+    struct Recorder {
+        writes: Vec<usize>,
+        data: Vec<u8>,
+    }
+
+    impl AsyncWrite for Recorder {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.writes.push(buf.len());
+            self.data.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn small_writes_are_batched_into_blocks() {
+        let mut writer = BatchingWriter::new(Recorder::default(), 16);
+        let data: Vec<u8> = (0..40).collect();
+
+        for chunk in data.chunks(3) {
+            writer.write_all(chunk).await.unwrap();
+        }
+        assert_eq!(writer.inner.writes, [16, 16]);
+
+        writer.flush().await.unwrap();
+        assert_eq!(writer.inner.writes, [16, 16, 8]);
+        assert_eq!(writer.inner.data, data);
+    }
+}
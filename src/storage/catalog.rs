@@ -0,0 +1,369 @@
+//! In-memory index of repositories, tags and blobs.
+//!
+//! Listing every location and its tags requires a walk of the whole manifest store, which becomes
+//! expensive with tens of thousands of repositories. The [`Catalog`] loads this listing once, on
+//! first use, and is kept up to date by [`CatalogStorage`], which wraps the registry's storage and
+//! records every tag written or removed through it.
+//!
+//! Likewise, the catalog keeps the digests of all stored blobs. The set may claim a blob exists
+//! that does not, e.g. if it was removed concurrently, so blobs found in it are still looked up in
+//! storage. Blobs missing from it are looked up as well, as they may have been stored without
+//! going through the registry, e.g. by another registry sharing the storage, and are added to the
+//! set once found.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    ops::Bound,
+    sync::Arc,
+};
+
+use axum::async_trait;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::Mutex,
+};
+use uuid::Uuid;
+
+use super::{
+    BlobMetadata, BlobStore, Digest, Error, ImageLocation, ManifestMetadata, ManifestReference,
+    ManifestStore, RegistryStorage, TagMetadata, UploadMetadata, UploadSessionStore,
+};
+use crate::types::ContentDescriptor;
+
+/// Tag names by location.
+type Index = BTreeMap<ImageLocation, BTreeSet<String>>;
+
+/// Cached listing of all locations and their tags.
+#[derive(Debug, Default)]
+pub(crate) struct Catalog {
+    /// The index, `None` until loaded from storage.
+    ///
+    /// Held while loading, so changes made concurrently are applied after the load has finished.
+    index: Mutex<Option<Index>>,
+    /// Digests of all stored blobs, `None` until loaded from storage.
+    blobs: Mutex<Option<HashSet<Digest>>>,
+}
+
+impl Catalog {
+    /// Returns up to `limit` locations, in order, following `after`.
+    pub(crate) async fn locations(
+        &self,
+        storage: &dyn RegistryStorage,
+        after: Option<&ImageLocation>,
+        limit: usize,
+    ) -> Result<Vec<ImageLocation>, Error> {
+        let mut guard = self.index.lock().await;
+        let index = Self::loaded(&mut guard, storage).await?;
+
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        Ok(index
+            .range::<ImageLocation, _>((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(location, _)| location.clone())
+            .collect())
+    }
+
+    /// Returns up to `limit` tags of `location`, in order, following `after`.
+    ///
+    /// Returns `None` if the location is unknown.
+    pub(crate) async fn tags(
+        &self,
+        storage: &dyn RegistryStorage,
+        location: &ImageLocation,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Option<Vec<String>>, Error> {
+        let mut guard = self.index.lock().await;
+        let index = Self::loaded(&mut guard, storage).await?;
+
+        let Some(tags) = index.get(location) else {
+            return Ok(None);
+        };
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        Ok(Some(
+            tags.range::<str, _>((start, Bound::Unbounded))
+                .take(limit)
+                .cloned()
+                .collect(),
+        ))
+    }
+
+    /// Returns whether a blob may be stored, `false` if it is certainly not.
+    ///
+    /// Blobs missing from the set are looked up in storage, so a blob stored is never missed.
+    pub(crate) async fn may_contain_blob(
+        &self,
+        storage: &dyn RegistryStorage,
+        digest: Digest,
+    ) -> Result<bool, Error> {
+        {
+            let mut guard = self.blobs.lock().await;
+            if guard.is_none() {
+                let blobs = storage.list_blobs().await?;
+                *guard = Some(blobs.iter().map(BlobMetadata::digest).collect());
+            }
+            if guard
+                .as_ref()
+                .expect("blobs should be loaded")
+                .contains(&digest)
+            {
+                return Ok(true);
+            }
+        }
+
+        // Not holding the lock, lookups of other blobs are not held up by storage.
+        if storage.get_blob_metadata(digest).await?.is_none() {
+            return Ok(false);
+        }
+        self.blob_added(digest).await;
+        Ok(true)
+    }
+
+    /// Records a blob written to storage.
+    async fn blob_added(&self, digest: Digest) {
+        if let Some(ref mut blobs) = *self.blobs.lock().await {
+            blobs.insert(digest);
+        }
+    }
+
+    /// Records a blob removed from storage.
+    async fn blob_removed(&self, digest: Digest) {
+        if let Some(ref mut blobs) = *self.blobs.lock().await {
+            blobs.remove(&digest);
+        }
+    }
+
+    /// Records a tag written to storage.
+    async fn tag_added(&self, location: &ImageLocation, tag: &str) {
+        if let Some(ref mut index) = *self.index.lock().await {
+            index
+                .entry(location.clone())
+                .or_default()
+                .insert(tag.to_owned());
+        }
+    }
+
+    /// Records a tag removed from storage.
+    ///
+    /// The location itself stays listed, like it does in storage.
+    async fn tag_removed(&self, location: &ImageLocation, tag: &str) {
+        if let Some(ref mut index) = *self.index.lock().await {
+            if let Some(tags) = index.get_mut(location) {
+                tags.remove(tag);
+            }
+        }
+    }
+
+    /// Records a location removed from storage along with all of its tags.
+    async fn location_removed(&self, location: &ImageLocation) {
+        if let Some(ref mut index) = *self.index.lock().await {
+            index.remove(location);
+        }
+    }
+
+    /// Returns the index, loading it from storage if necessary.
+    async fn loaded<'a>(
+        index: &'a mut Option<Index>,
+        storage: &dyn RegistryStorage,
+    ) -> Result<&'a Index, Error> {
+        if index.is_none() {
+            let mut loaded = Index::new();
+            for location in storage.list_locations().await? {
+                let tags = storage.list_tags(&location).await?;
+                loaded.insert(location, tags.into_iter().map(|tag| tag.tag).collect());
+            }
+            *index = Some(loaded);
+        }
+
+        Ok(index.as_ref().expect("index should be loaded"))
+    }
+}
+
+/// A storage wrapper keeping a [`Catalog`] up to date, see the [module documentation](self).
+pub(crate) struct CatalogStorage {
+    /// The wrapped storage.
+    inner: Box<dyn RegistryStorage>,
+    /// The catalog to update.
+    catalog: Arc<Catalog>,
+}
+
+impl CatalogStorage {
+    /// Wraps a storage, recording tag changes in `catalog`.
+    pub(crate) fn new(inner: Box<dyn RegistryStorage>, catalog: Arc<Catalog>) -> Self {
+        Self { inner, catalog }
+    }
+}
+
+#[async_trait]
+impl BlobStore for CatalogStorage {
+    async fn get_blob_reader(
+        &self,
+        digest: Digest,
+    ) -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>, Error> {
+        self.inner.get_blob_reader(digest).await
+    }
+
+    async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+        self.inner.get_blob_metadata(digest).await
+    }
+
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error> {
+        self.inner.list_blobs().await
+    }
+
+    async fn delete_blob(&self, digest: Digest) -> Result<(), Error> {
+        self.inner.delete_blob(digest).await?;
+        self.catalog.blob_removed(digest).await;
+        Ok(())
+    }
+
+    async fn quarantine_blob(&self, digest: Digest) -> Result<(), Error> {
+        self.inner.quarantine_blob(digest).await
+    }
+
+    async fn usage(&self) -> Result<u64, Error> {
+        self.inner.usage().await
+    }
+
+    async fn blob_url(&self, digest: Digest) -> Result<Option<String>, Error> {
+        self.inner.blob_url(digest).await
+    }
+}
+
+#[async_trait]
+impl UploadSessionStore for CatalogStorage {
+    async fn begin_new_upload(&self) -> Result<Uuid, Error> {
+        self.inner.begin_new_upload().await
+    }
+
+    async fn get_upload_writer(
+        &self,
+        start_at: u64,
+        upload: Uuid,
+    ) -> Result<Box<dyn AsyncWrite + Send + Unpin>, Error> {
+        self.inner.get_upload_writer(start_at, upload).await
+    }
+
+    async fn finalize_upload(&self, upload: Uuid, hash: Digest) -> Result<(), Error> {
+        self.inner.finalize_upload(upload, hash).await?;
+        self.catalog.blob_added(hash).await;
+        Ok(())
+    }
+
+    async fn list_uploads(&self) -> Result<Vec<UploadMetadata>, Error> {
+        self.inner.list_uploads().await
+    }
+
+    async fn cancel_upload(&self, upload: Uuid) -> Result<(), Error> {
+        self.inner.cancel_upload(upload).await
+    }
+
+    async fn truncate_upload(&self, upload: Uuid, size: u64) -> Result<(), Error> {
+        self.inner.truncate_upload(upload, size).await
+    }
+
+    async fn get_upload_size(&self, upload: Uuid) -> Result<u64, Error> {
+        self.inner.get_upload_size(upload).await
+    }
+}
+
+#[async_trait]
+impl ManifestStore for CatalogStorage {
+    async fn get_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        self.inner.get_manifest(manifest_reference).await
+    }
+
+    async fn put_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+        manifest: &[u8],
+    ) -> Result<Digest, Error> {
+        let digest = self
+            .inner
+            .put_manifest(manifest_reference, manifest)
+            .await?;
+        if let Some(tag) = manifest_reference.reference().as_tag() {
+            self.catalog
+                .tag_added(manifest_reference.location(), tag)
+                .await;
+        }
+        Ok(digest)
+    }
+
+    async fn put_tag(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+        digest: Digest,
+    ) -> Result<(), Error> {
+        self.inner.put_tag(location, tag, digest).await?;
+        self.catalog.tag_added(location, tag).await;
+        Ok(())
+    }
+
+    async fn get_referrers(
+        &self,
+        location: &ImageLocation,
+        subject: Digest,
+    ) -> Result<Vec<ContentDescriptor>, Error> {
+        self.inner.get_referrers(location, subject).await
+    }
+
+    async fn list_manifest_digests(&self) -> Result<Vec<Digest>, Error> {
+        self.inner.list_manifest_digests().await
+    }
+
+    async fn find_manifest_digests(&self, prefix: &str) -> Result<Vec<Digest>, Error> {
+        self.inner.find_manifest_digests(prefix).await
+    }
+
+    async fn delete_manifest(&self, digest: Digest) -> Result<(), Error> {
+        self.inner.delete_manifest(digest).await
+    }
+
+    async fn list_locations(&self) -> Result<Vec<ImageLocation>, Error> {
+        self.inner.list_locations().await
+    }
+
+    async fn list_tags(&self, location: &ImageLocation) -> Result<Vec<TagMetadata>, Error> {
+        self.inner.list_tags(location).await
+    }
+
+    async fn delete_tag(&self, location: &ImageLocation, tag: &str) -> Result<(), Error> {
+        self.inner.delete_tag(location, tag).await?;
+        self.catalog.tag_removed(location, tag).await;
+        Ok(())
+    }
+
+    async fn delete_location(&self, location: &ImageLocation) -> Result<(), Error> {
+        self.inner.delete_location(location).await?;
+        self.catalog.location_removed(location).await;
+        Ok(())
+    }
+
+    async fn list_manifests(
+        &self,
+        location: &ImageLocation,
+    ) -> Result<Vec<ManifestMetadata>, Error> {
+        self.inner.list_manifests(location).await
+    }
+
+    async fn link_blob(&self, location: &ImageLocation, digest: Digest) -> Result<(), Error> {
+        self.inner.link_blob(location, digest).await
+    }
+
+    async fn is_blob_linked(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<bool, Error> {
+        self.inner.is_blob_linked(location, digest).await
+    }
+
+    async fn list_blob_links(&self, digest: Digest) -> Result<Vec<ImageLocation>, Error> {
+        self.inner.list_blob_links(digest).await
+    }
+}
@@ -0,0 +1,538 @@
+//! Deduplicating blob storage using content-defined chunking.
+//!
+//! Rebuilt images often differ from their predecessors in a few files only, yet every layer is a
+//! new blob, stored in full. [`ChunkedStorage`] splits blobs into chunks at boundaries determined
+//! by their contents, using a gear-based rolling hash, so that an insertion or deletion only
+//! changes the chunks around it. Chunks are stored once, addressed by their digest, and shared by
+//! all blobs containing them; a blob is stored as a recipe listing its chunks, which are
+//! reassembled on read.
+//!
+//! Chunks are between 16 KiB and 256 KiB, averaging about 80 KiB. Compressed layers only share
+//! chunks if they were compressed identically up to the point of change, uncompressed layers
+//! deduplicate best.
+//!
+//! Chunks and recipes are kept in the `chunks` and `recipes` directories of the storage path, next
+//! to the regular storage. Every chunk's number of references is counted in memory, loaded from
+//! the recipes on start; chunks no longer referenced are removed along with the last blob using
+//! them. Uploads are handled by the filesystem storage, chunking happens when finalizing them.
+//!
+//! This backend is experimental, see
+//! [`ContainerRegistryBuilder::chunked_blobs`](crate::ContainerRegistryBuilder::chunked_blobs).
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::io::{ReaderStream, StreamReader};
+use uuid::Uuid;
+
+use super::{
+    BlobMetadata, BlobStore, Digest, Error, FilesystemStorage, FilesystemStorageError,
+    UploadMetadata, UploadSessionStore, BUFFER_SIZE,
+};
+use crate::hashing::{self, Hasher};
+
+/// Minimum size of a chunk, except for the last one of a blob.
+const MIN_CHUNK_SIZE: u64 = 16 * 1024;
+
+/// Maximum size of a chunk.
+const MAX_CHUNK_SIZE: u64 = 256 * 1024;
+
+/// Mask selecting the hash bits that must be zero at a chunk boundary.
+///
+/// Sixteen bits result in a boundary about every 64 KiB past the minimum size.
+const BOUNDARY_MASK: u64 = (1 << 16) - 1;
+
+/// Random values mixed into the rolling hash, one for every byte value.
+///
+/// Generated with splitmix64 from a fixed seed, as boundaries must never change for stored blobs.
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut idx = 0;
+    while idx < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[idx] = z ^ (z >> 31);
+        idx += 1;
+    }
+    table
+};
+
+/// A chunk of a blob.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct ChunkRef {
+    /// Hex-encoded digest of the chunk's contents.
+    digest: String,
+    /// Size of the chunk in bytes.
+    size: u64,
+}
+
+/// The chunks making up a blob, in order.
+#[derive(Debug, Deserialize, Serialize)]
+struct Recipe {
+    /// Size of the blob in bytes.
+    size: u64,
+    /// The blob's chunks.
+    chunks: Vec<ChunkRef>,
+}
+
+/// Splits a stream of bytes into content-defined chunks.
+struct Chunker {
+    /// Rolling hash of the bytes of the current chunk.
+    gear: u64,
+    /// Size of the current chunk so far.
+    size: u64,
+    /// Hash of the current chunk so far.
+    hasher: hashing::Sha256,
+    /// Chunks completed so far.
+    chunks: Vec<ChunkRef>,
+}
+
+impl Chunker {
+    /// Creates a new chunker.
+    fn new() -> Self {
+        Self {
+            gear: 0,
+            size: 0,
+            hasher: Default::default(),
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Adds the next bytes of the stream.
+    fn update(&mut self, mut data: &[u8]) {
+        while let Some(boundary) = self.find_boundary(data) {
+            self.hasher.update(&data[..boundary]);
+            self.cut();
+            data = &data[boundary..];
+        }
+        self.hasher.update(data);
+    }
+
+    /// Returns the chunks of the stream, once it has ended.
+    fn finish(mut self) -> Vec<ChunkRef> {
+        if self.size > 0 {
+            self.cut();
+        }
+        self.chunks
+    }
+
+    /// Advances the rolling hash over `data`, returning the offset after the next boundary.
+    fn find_boundary(&mut self, data: &[u8]) -> Option<usize> {
+        for (idx, &byte) in data.iter().enumerate() {
+            self.gear = (self.gear << 1).wrapping_add(GEAR[byte as usize]);
+            self.size += 1;
+
+            if self.size >= MAX_CHUNK_SIZE
+                || (self.size >= MIN_CHUNK_SIZE && self.gear & BOUNDARY_MASK == 0)
+            {
+                return Some(idx + 1);
+            }
+        }
+        None
+    }
+
+    /// Completes the current chunk.
+    fn cut(&mut self) {
+        let hasher = std::mem::take(&mut self.hasher);
+        self.chunks.push(ChunkRef {
+            digest: Digest::new(hasher.finalize()).to_string(),
+            size: self.size,
+        });
+        self.gear = 0;
+        self.size = 0;
+    }
+}
+
+/// Number of references and size of every stored chunk, by hex-encoded digest.
+type ChunkIndex = HashMap<String, (u64, u64)>;
+
+/// A blob store deduplicating chunks of blobs, see the [module documentation](self).
+#[derive(Clone, Debug)]
+pub(crate) struct ChunkedStorage {
+    /// Storage handling uploads.
+    uploads: FilesystemStorage,
+    /// Directory holding chunks.
+    chunks: PathBuf,
+    /// Directory holding recipes.
+    recipes: PathBuf,
+    /// References to stored chunks, held while adding or removing blobs.
+    index: Arc<Mutex<ChunkIndex>>,
+    /// Total size of all stored chunks.
+    stored: Arc<AtomicU64>,
+}
+
+impl ChunkedStorage {
+    /// Creates a new chunked store next to the given filesystem storage, using it for uploads.
+    pub(crate) fn new(uploads: FilesystemStorage) -> Result<Self, FilesystemStorageError> {
+        let chunks = uploads.manifests.with_file_name("chunks");
+        let recipes = uploads.manifests.with_file_name("recipes");
+        for dir in [&chunks, &recipes] {
+            if !dir.exists() {
+                fs::create_dir(dir).map_err(|err| FilesystemStorageError::FailedToCreateDir {
+                    path: dir.to_owned(),
+                    err,
+                })?;
+            }
+        }
+
+        let index = Self::load_index(&chunks, &recipes).map_err(|err| {
+            FilesystemStorageError::FailedToScanBlobs {
+                path: recipes.clone(),
+                err,
+            }
+        })?;
+        let stored = index.values().map(|&(_, size)| size).sum();
+
+        Ok(Self {
+            uploads,
+            chunks,
+            recipes,
+            index: Arc::new(Mutex::new(index)),
+            stored: Arc::new(AtomicU64::new(stored)),
+        })
+    }
+
+    /// Counts the references to chunks of all stored recipes.
+    ///
+    /// Files left behind by an interrupted upload, i.e. partially written files and chunks not
+    /// referenced by any recipe, are removed.
+    fn load_index(chunks: &Path, recipes: &Path) -> io::Result<ChunkIndex> {
+        let is_partial = |path: &Path| path.extension().is_some_and(|ext| ext == "tmp");
+
+        let mut index = ChunkIndex::new();
+        for entry in fs::read_dir(recipes)? {
+            let path = entry?.path();
+            if is_partial(&path) {
+                fs::remove_file(path)?;
+                continue;
+            }
+
+            for chunk in read_recipe(&path)?.chunks {
+                let (references, size) = index.entry(chunk.digest).or_default();
+                *size = chunk.size;
+                *references += 1;
+            }
+        }
+
+        for entry in fs::read_dir(chunks)? {
+            let entry = entry?;
+            let referenced = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| index.contains_key(name));
+            if !referenced {
+                fs::remove_file(entry.path())?;
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// Returns the path of a chunk, by hex-encoded digest.
+    fn chunk_path(&self, digest: &str) -> PathBuf {
+        self.chunks.join(digest)
+    }
+
+    /// Returns the path of a blob's recipe.
+    fn recipe_path(&self, digest: Digest) -> PathBuf {
+        self.recipes.join(digest.to_string())
+    }
+
+    /// Stores the upload at `path` as a blob, if it matches `digest`.
+    fn store(&self, path: &Path, digest: Digest) -> Result<(), Error> {
+        // First pass: Verify the upload while determining its chunks.
+        let mut src = fs::File::open(path).map_err(Error::Io)?;
+        let mut buf = vec![0; BUFFER_SIZE];
+        let mut hasher = hashing::Sha256::default();
+        let mut chunker = Chunker::new();
+        loop {
+            let read = src.read(&mut buf).map_err(Error::Io)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+            chunker.update(&buf[..read]);
+        }
+        if Digest::new(hasher.finalize()) != digest {
+            return Err(Error::DigestMismatch);
+        }
+        let chunks = chunker.finish();
+
+        let mut index = self.index.lock().expect("lock poisoned");
+        let recipe_path = self.recipe_path(digest);
+        if recipe_path.exists() {
+            return Ok(());
+        }
+
+        // Second pass: Write chunks not stored yet.
+        let mut src = io::BufReader::new(fs::File::open(path).map_err(Error::Io)?);
+        let mut written = HashSet::new();
+        for chunk in &chunks {
+            let mut contents = vec![0; chunk.size as usize];
+            src.read_exact(&mut contents).map_err(Error::Io)?;
+
+            if index.contains_key(&chunk.digest) || !written.insert(chunk.digest.as_str()) {
+                continue;
+            }
+            write_atomically(&self.chunk_path(&chunk.digest), &contents).map_err(Error::Io)?;
+        }
+
+        let size = chunks.iter().map(|chunk| chunk.size).sum();
+        let recipe = serde_json::to_vec(&Recipe {
+            size,
+            chunks: chunks.clone(),
+        })
+        .expect("recipes should always serialize");
+        write_atomically(&recipe_path, &recipe).map_err(Error::Io)?;
+
+        for chunk in chunks {
+            let (references, stored_size) = index.entry(chunk.digest).or_default();
+            if *references == 0 {
+                *stored_size = chunk.size;
+                self.stored.fetch_add(chunk.size, Ordering::Relaxed);
+            }
+            *references += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Removes a blob, along with chunks no longer referenced.
+    fn remove(&self, digest: Digest) -> Result<(), Error> {
+        let mut index = self.index.lock().expect("lock poisoned");
+        let recipe_path = self.recipe_path(digest);
+        let recipe = match read_recipe(&recipe_path) {
+            Ok(recipe) => recipe,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(Error::Io(err)),
+        };
+        fs::remove_file(&recipe_path).map_err(Error::Io)?;
+
+        for chunk in recipe.chunks {
+            let Some((references, size)) = index.get_mut(&chunk.digest) else {
+                continue;
+            };
+            *references -= 1;
+            if *references > 0 {
+                continue;
+            }
+
+            self.stored.fetch_sub(*size, Ordering::Relaxed);
+            index.remove(&chunk.digest);
+            match fs::remove_file(self.chunk_path(&chunk.digest)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(Error::Io(err)),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs a blocking operation on a background thread.
+    async fn blocking<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Self) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let storage = self.clone();
+        tokio::task::spawn_blocking(move || f(&storage))
+            .await
+            .map_err(Error::BackgroundTaskPanicked)?
+    }
+}
+
+/// Reads and parses a recipe.
+fn read_recipe(path: &Path) -> io::Result<Recipe> {
+    let raw = fs::read(path)?;
+    serde_json::from_slice(&raw).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Durably writes a file, so it is never visible partially written.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(tmp, path)?;
+    fs::File::open(path.parent().expect("should have parent"))?.sync_all()
+}
+
+#[async_trait]
+impl BlobStore for ChunkedStorage {
+    async fn get_blob_reader(
+        &self,
+        digest: Digest,
+    ) -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>, Error> {
+        let recipe = match read_recipe(&self.recipe_path(digest)) {
+            Ok(recipe) => recipe,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(Error::Io(err)),
+        };
+
+        // Chunks are opened one after another while reading.
+        let paths: Vec<_> = recipe
+            .chunks
+            .iter()
+            .map(|chunk| self.chunk_path(&chunk.digest))
+            .collect();
+        let contents = futures::stream::iter(paths)
+            .then(tokio::fs::File::open)
+            .map_ok(ReaderStream::new)
+            .try_flatten();
+
+        Ok(Some(Box::new(StreamReader::new(Box::pin(contents)))))
+    }
+
+    async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+        let path = self.recipe_path(digest);
+        let modified = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata.modified().map_err(Error::Io)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(Error::Io(err)),
+        };
+
+        let recipe = read_recipe(&path).map_err(Error::Io)?;
+        Ok(Some(BlobMetadata {
+            digest,
+            size: recipe.size,
+            modified,
+        }))
+    }
+
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error> {
+        let mut blobs = Vec::new();
+        for entry in super::read_dir_or_empty(&self.recipes).await? {
+            let Some(digest) = entry.file_name().to_str().and_then(Digest::from_hex_str) else {
+                continue;
+            };
+            if let Some(metadata) = self.get_blob_metadata(digest).await? {
+                blobs.push(metadata);
+            }
+        }
+        Ok(blobs)
+    }
+
+    async fn delete_blob(&self, digest: Digest) -> Result<(), Error> {
+        self.blocking(move |storage| storage.remove(digest)).await
+    }
+
+    /// Returns the size of all stored chunks, i.e. the space used after deduplication.
+    async fn usage(&self) -> Result<u64, Error> {
+        Ok(self.stored.load(Ordering::Relaxed))
+    }
+}
+
+#[async_trait]
+impl UploadSessionStore for ChunkedStorage {
+    async fn begin_new_upload(&self) -> Result<Uuid, Error> {
+        self.uploads.begin_new_upload().await
+    }
+
+    async fn get_upload_writer(
+        &self,
+        start_at: u64,
+        upload: Uuid,
+    ) -> Result<Box<dyn AsyncWrite + Send + Unpin>, Error> {
+        self.uploads.get_upload_writer(start_at, upload).await
+    }
+
+    async fn finalize_upload(&self, upload: Uuid, digest: Digest) -> Result<(), Error> {
+        let path = self.uploads.upload_path(upload);
+        if !path.exists() {
+            return Err(Error::UploadDoesNotExit);
+        }
+
+        self.blocking(move |storage| {
+            storage.store(&path, digest)?;
+            fs::remove_file(&path).map_err(Error::Io)
+        })
+        .await
+    }
+
+    async fn list_uploads(&self) -> Result<Vec<UploadMetadata>, Error> {
+        self.uploads.list_uploads().await
+    }
+
+    async fn cancel_upload(&self, upload: Uuid) -> Result<(), Error> {
+        self.uploads.cancel_upload(upload).await
+    }
+
+    async fn truncate_upload(&self, upload: Uuid, size: u64) -> Result<(), Error> {
+        self.uploads.truncate_upload(upload, size).await
+    }
+
+    async fn get_upload_size(&self, upload: Uuid) -> Result<u64, Error> {
+        self.uploads.get_upload_size(upload).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chunker, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+
+    /// Returns `len` bytes of deterministic noise.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chunk(data: &[u8], piece: usize) -> Vec<super::ChunkRef> {
+        let mut chunker = Chunker::new();
+        for piece in data.chunks(piece) {
+            chunker.update(piece);
+        }
+        chunker.finish()
+    }
+
+    #[test]
+    fn chunks_survive_insertions() {
+        let original = noise(4 * 1024 * 1024, 1);
+        let chunks = chunk(&original, 4096);
+
+        assert_eq!(
+            chunks.iter().map(|chunk| chunk.size).sum::<u64>(),
+            original.len() as u64
+        );
+        let (last, rest) = chunks.split_last().unwrap();
+        assert!(last.size <= MAX_CHUNK_SIZE);
+        assert!(rest
+            .iter()
+            .all(|chunk| (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk.size)));
+
+        // Boundaries do not depend on how the data is fed.
+        assert_eq!(chunk(&original, 1_000_003), chunks);
+
+        // Only the chunks around an insertion change.
+        let mut modified = original.clone();
+        modified.splice(2_000_000..2_000_000, noise(100, 2));
+        let modified_chunks = chunk(&modified, 4096);
+        let shared = modified_chunks
+            .iter()
+            .filter(|chunk| chunks.contains(chunk))
+            .count();
+        assert!(shared >= chunks.len() - 2, "{shared} of {}", chunks.len());
+    }
+}
@@ -0,0 +1,236 @@
+//! Consistency check of filesystem storage on startup.
+//!
+//! Crashes, full disks or manual changes to the storage directory can leave behind files that
+//! are never cleaned up, or tags pointing at manifests that no longer exist. The latter would only
+//! show up once a client pulls the tag, as a server error that is hard to trace back to its
+//! cause. With [`ContainerRegistryBuilder::consistency_check`](crate::ContainerRegistryBuilder::consistency_check)
+//! enabled, storage is scanned while the registry is built, and problems found are either
+//! repaired or reported in a [`FilesystemStorageError::Inconsistent`] error, refusing to start.
+//!
+//! The check is quick, as it only looks at file names and tag links, not at contents. It runs
+//! after updates left in the write-ahead journal have been replayed, so interrupted updates the
+//! journal can complete are not reported.
+
+use std::{
+    fmt::{self, Display},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use tracing::warn;
+
+use super::{Digest, FilesystemStorage, FilesystemStorageError, ImageLocation};
+use crate::ImageDigest;
+
+/// What to do about problems found by the startup consistency check.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConsistencyCheck {
+    /// Repair all problems found, logging every repair.
+    Repair,
+    /// Refuse to start if any problems are found, leaving storage untouched.
+    Refuse,
+}
+
+/// A problem found by the startup consistency check.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Inconsistency {
+    /// A manifest that was still being written when the registry stopped.
+    TemporaryManifest {
+        /// Path of the partially written file.
+        path: PathBuf,
+    },
+    /// A tag that was still being updated when the registry stopped.
+    TemporaryTag {
+        /// Path of the temporary link.
+        path: PathBuf,
+    },
+    /// An upload left behind by a previous run, which cannot be continued anymore.
+    StaleUpload {
+        /// Path of the partial upload.
+        path: PathBuf,
+    },
+    /// A tag pointing at a manifest that does not exist.
+    DanglingTag {
+        /// Location of the tag.
+        location: ImageLocation,
+        /// Name of the tag.
+        tag: String,
+        /// Digest of the missing manifest.
+        digest: ImageDigest,
+    },
+}
+
+impl Inconsistency {
+    /// Returns the file to remove to repair this problem.
+    fn path(&self, storage: &FilesystemStorage) -> PathBuf {
+        match self {
+            Inconsistency::TemporaryManifest { path }
+            | Inconsistency::TemporaryTag { path }
+            | Inconsistency::StaleUpload { path } => path.clone(),
+            Inconsistency::DanglingTag { location, tag, .. } => storage.tag_path(location, tag),
+        }
+    }
+}
+
+impl Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::TemporaryManifest { path } => write!(
+                f,
+                "partially written manifest {}, can be removed",
+                path.display()
+            ),
+            Inconsistency::TemporaryTag { path } => {
+                write!(f, "temporary tag link {}, can be removed", path.display())
+            }
+            Inconsistency::StaleUpload { path } => write!(
+                f,
+                "upload {} from a previous run, can be removed as it cannot be continued",
+                path.display()
+            ),
+            Inconsistency::DanglingTag {
+                location,
+                tag,
+                digest,
+            } => write!(
+                f,
+                "tag {}:{} points at missing manifest {}, push the image again or delete the tag",
+                location, tag, digest
+            ),
+        }
+    }
+}
+
+/// The problems found by the startup consistency check.
+#[derive(Clone, Debug, Default)]
+pub struct ConsistencyReport {
+    /// All problems, in the order they were found.
+    problems: Vec<Inconsistency>,
+}
+
+impl ConsistencyReport {
+    /// Returns all problems found.
+    pub fn problems(&self) -> &[Inconsistency] {
+        &self.problems
+    }
+
+    /// Returns whether no problems were found.
+    pub fn is_consistent(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Display for ConsistencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} problem(s) found", self.problems.len())?;
+        for problem in &self.problems {
+            write!(f, "\n  - {}", problem)?;
+        }
+        if !self.problems.is_empty() {
+            f.write_str("\nfix these by hand, or enable repairs to have them fixed on startup")?;
+        }
+        Ok(())
+    }
+}
+
+impl FilesystemStorage {
+    /// Scans storage for problems, handling them according to `check`.
+    ///
+    /// See the [module documentation](self) for details.
+    pub(crate) fn check_consistency(
+        &self,
+        check: ConsistencyCheck,
+    ) -> Result<ConsistencyReport, FilesystemStorageError> {
+        let report =
+            self.scan()
+                .map_err(|err| FilesystemStorageError::FailedToCheckConsistency {
+                    path: self.tags.clone(),
+                    err,
+                })?;
+
+        if report.is_consistent() {
+            return Ok(report);
+        }
+        if check == ConsistencyCheck::Refuse {
+            return Err(FilesystemStorageError::Inconsistent(report));
+        }
+
+        for problem in &report.problems {
+            warn!(%problem, "repairing storage");
+            let path = problem.path(self);
+            if let Err(err) = fs::remove_file(&path) {
+                if err.kind() != io::ErrorKind::NotFound {
+                    return Err(FilesystemStorageError::FailedToCheckConsistency { path, err });
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Collects all problems, without repairing any.
+    fn scan(&self) -> io::Result<ConsistencyReport> {
+        let mut problems = Vec::new();
+
+        for entry in entries(&self.manifests)? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "tmp") {
+                problems.push(Inconsistency::TemporaryManifest { path });
+            }
+        }
+
+        for volume in self.volumes.iter() {
+            for entry in entries(&volume.uploads)? {
+                problems.push(Inconsistency::StaleUpload { path: entry.path() });
+            }
+        }
+
+        // Tags are kept below their repository and image, links directly in `tags` are temporary.
+        for repository in entries(&self.tags)? {
+            if !repository.file_type()?.is_dir() {
+                problems.push(Inconsistency::TemporaryTag {
+                    path: repository.path(),
+                });
+                continue;
+            }
+
+            for image in entries(&repository.path())? {
+                let (Ok(repository), Ok(name)) = (
+                    repository.file_name().into_string(),
+                    image.file_name().into_string(),
+                ) else {
+                    continue;
+                };
+                let location = ImageLocation::new(repository, name);
+
+                for tag in entries(&image.path())? {
+                    let Ok(name) = tag.file_name().into_string() else {
+                        continue;
+                    };
+                    let Some(digest) = fs::read_link(tag.path())?
+                        .file_name()
+                        .and_then(|name| name.to_str())
+                        .and_then(Digest::from_hex_str)
+                    else {
+                        continue;
+                    };
+
+                    if !self.manifest_path(digest).exists() {
+                        problems.push(Inconsistency::DanglingTag {
+                            location: location.clone(),
+                            tag: name,
+                            digest: ImageDigest::new(digest),
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(ConsistencyReport { problems })
+    }
+}
+
+/// Returns the entries of the directory at `path`.
+fn entries(path: &Path) -> io::Result<Vec<fs::DirEntry>> {
+    fs::read_dir(path)?.collect()
+}
@@ -0,0 +1,366 @@
+//! Encryption at rest for blobs.
+//!
+//! Requires the `encryption` feature to be enabled.
+//!
+//! With a [`KeyProvider`] set through
+//! [`ContainerRegistryBuilder::blob_encryption`](crate::ContainerRegistryBuilder::blob_encryption),
+//! blobs are encrypted using AES-256-GCM before being stored on local disk and decrypted when read.
+//! Every blob is encrypted with its own randomly generated data key, which is stored alongside it
+//! after being wrapped (encrypted) by the key provider. Providers can thus keep their master key
+//! outside the registry entirely, e.g. in a KMS, while [`StaticKey`] wraps data keys using a fixed
+//! key:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use container_registry::{storage::encryption::StaticKey, ContainerRegistry};
+//! use sec::Secret;
+//!
+//! let builder = ContainerRegistry::builder()
+//!     .blob_encryption(Arc::new(StaticKey::new(Secret::new([0x42; 32]))));
+//! ```
+//!
+//! Blobs are still addressed and verified by the digest of their plaintext, so clients cannot tell
+//! the difference. Blobs are sealed in segments, each authenticated separately, which allows
+//! streaming them without holding them in memory and detects any tampering, including truncation.
+//!
+//! Uploads in progress are kept in plaintext until they are finalized. Blobs written through to a
+//! [`ColdBlobStore`](super::ColdBlobStore) are passed on decrypted, the cold store is expected to
+//! encrypt them itself. Blobs stored before encryption was enabled remain readable.
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use axum::{async_trait, body::Bytes};
+use sec::Secret;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::StreamReader;
+
+use super::{Digest, Error};
+use crate::hashing::{self, Hasher};
+
+/// Length of a data key in bytes.
+pub const KEY_LEN: usize = 32;
+
+/// Marks an encrypted blob file, plaintext blobs are stored as-is.
+const MAGIC: &[u8; 8] = b"\0crblob\x01";
+
+/// Number of plaintext bytes per sealed segment.
+const SEGMENT_SIZE: usize = 64 * 1024;
+
+/// Length of the authentication tag appended to every segment.
+const TAG_LEN: usize = 16;
+
+/// Length of the nonce used for wrapping keys with a [`StaticKey`].
+const NONCE_LEN: usize = 12;
+
+/// Provider of the keys protecting blob data keys.
+///
+/// Implement this trait to integrate an external key management service. Data keys are generated
+/// by the registry, the provider only wraps them for storage and unwraps them again when a blob is
+/// read. Wrapped keys may embed e.g. the id of the master key used, to support key rotation.
+#[async_trait]
+pub trait KeyProvider: Send + Sync {
+    /// Wraps a data key, returning an opaque value to store alongside the blob it encrypts.
+    async fn wrap_key(&self, key: &Secret<[u8; KEY_LEN]>) -> Result<Vec<u8>, Error>;
+
+    /// Unwraps a data key previously wrapped using [`Self::wrap_key`].
+    async fn unwrap_key(&self, wrapped: &[u8]) -> Result<Secret<[u8; KEY_LEN]>, Error>;
+}
+
+/// A key provider wrapping data keys using a fixed key.
+#[derive(Debug)]
+pub struct StaticKey {
+    /// Key data keys are wrapped with.
+    key: Secret<[u8; KEY_LEN]>,
+}
+
+impl StaticKey {
+    /// Creates a new key provider, the key should be random.
+    pub fn new(key: Secret<[u8; KEY_LEN]>) -> Self {
+        Self { key }
+    }
+
+    /// Returns a cipher for the key.
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(self.key.reveal()))
+    }
+}
+
+#[async_trait]
+impl KeyProvider for StaticKey {
+    async fn wrap_key(&self, key: &Secret<[u8; KEY_LEN]>) -> Result<Vec<u8>, Error> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend(
+            self.cipher()
+                .encrypt(&nonce, key.reveal().as_slice())
+                .map_err(|_| invalid_data("failed to wrap data key"))
+                .map_err(|err| Error::Backend(Box::new(err)))?,
+        );
+        Ok(wrapped)
+    }
+
+    async fn unwrap_key(&self, wrapped: &[u8]) -> Result<Secret<[u8; KEY_LEN]>, Error> {
+        let unwrap = || {
+            if wrapped.len() < NONCE_LEN {
+                return None;
+            }
+            let (nonce, ciphertext) = wrapped.split_at(NONCE_LEN);
+            let key = self
+                .cipher()
+                .decrypt(Nonce::from_slice(nonce), ciphertext)
+                .ok()?;
+            key.try_into().ok()
+        };
+
+        unwrap()
+            .map(Secret::new)
+            .ok_or_else(|| Error::Backend(Box::new(invalid_data("failed to unwrap data key"))))
+    }
+}
+
+/// Creates an error signaling corrupted or tampered data.
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Seals and opens blob files using data keys protected by a [`KeyProvider`].
+#[derive(Clone)]
+pub(super) struct BlobCipher {
+    /// Provider wrapping the data keys.
+    provider: Arc<dyn KeyProvider>,
+}
+
+impl std::fmt::Debug for BlobCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlobCipher").finish_non_exhaustive()
+    }
+}
+
+impl BlobCipher {
+    /// Creates a new blob cipher.
+    pub(super) fn new(provider: Arc<dyn KeyProvider>) -> Self {
+        Self { provider }
+    }
+
+    /// Generates a fresh data key for sealing a single blob.
+    pub(super) async fn sealer(&self) -> Result<Sealer, Error> {
+        let key = Secret::new(Aes256Gcm::generate_key(OsRng).into());
+        let wrapped = self.provider.wrap_key(&key).await?;
+        let wrapped_len = u16::try_from(wrapped.len())
+            .map_err(|_| Error::Backend(Box::new(invalid_data("wrapped data key too long"))))?;
+
+        let mut header = MAGIC.to_vec();
+        header.extend(wrapped_len.to_be_bytes());
+        header.extend(wrapped);
+
+        Ok(Sealer {
+            aead: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.reveal())),
+            header,
+        })
+    }
+
+    /// Opens a blob file for reading its plaintext.
+    pub(super) async fn open(
+        &self,
+        mut file: tokio::fs::File,
+    ) -> Result<Box<dyn AsyncRead + Send + Unpin>, Error> {
+        let file_len = file.metadata().await.map_err(Error::Io)?.len();
+        let Some(header_len) = read_header_len(&mut file).await.map_err(Error::Io)? else {
+            return Ok(Box::new(file));
+        };
+
+        let mut wrapped = vec![0; header_len as usize - MAGIC.len() - 2];
+        file.read_exact(&mut wrapped).await.map_err(Error::Io)?;
+        let key = self.provider.unwrap_key(&wrapped).await?;
+        let aead = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.reveal()));
+
+        let sealed_len = file_len
+            .checked_sub(header_len)
+            .ok_or_else(|| Error::Io(invalid_data("blob truncated")))?;
+        let segments = segment_count(sealed_len);
+        let stream = futures::stream::try_unfold(
+            (file, aead, 0),
+            move |(mut file, aead, index)| async move {
+                if index == segments {
+                    return Ok::<_, io::Error>(None);
+                }
+
+                let last = index + 1 == segments;
+                let len = if last {
+                    (sealed_len - index * (SEGMENT_SIZE + TAG_LEN) as u64) as usize
+                } else {
+                    SEGMENT_SIZE + TAG_LEN
+                };
+                let mut sealed = vec![0; len];
+                file.read_exact(&mut sealed).await?;
+
+                let plaintext = aead
+                    .decrypt(
+                        &segment_nonce(index),
+                        Payload {
+                            msg: &sealed,
+                            aad: &[last as u8],
+                        },
+                    )
+                    .map_err(|_| invalid_data("blob failed authentication"))?;
+
+                Ok(Some((Bytes::from(plaintext), (file, aead, index + 1))))
+            },
+        );
+
+        Ok(Box::new(StreamReader::new(Box::pin(stream))))
+    }
+
+    /// Returns the plaintext size of a blob file.
+    pub(super) async fn plaintext_size(&self, path: &Path) -> Result<u64, Error> {
+        let mut file = tokio::fs::File::open(path).await.map_err(Error::Io)?;
+        let file_len = file.metadata().await.map_err(Error::Io)?.len();
+
+        Ok(match read_header_len(&mut file).await.map_err(Error::Io)? {
+            Some(header_len) => {
+                let sealed_len = file_len.saturating_sub(header_len);
+                sealed_len.saturating_sub(segment_count(sealed_len) * TAG_LEN as u64)
+            }
+            None => file_len,
+        })
+    }
+}
+
+/// Seals a single blob.
+pub(super) struct Sealer {
+    /// Cipher using the blob's data key.
+    aead: Aes256Gcm,
+    /// File header, carrying the wrapped data key.
+    header: Vec<u8>,
+}
+
+impl Sealer {
+    /// Seals the file at `src` into `dest`, returning the digest of the plaintext.
+    ///
+    /// Performs blocking IO.
+    pub(super) fn seal(&self, src: &Path, dest: &Path) -> Result<Digest, Error> {
+        let mut src = fs::File::open(src).map_err(Error::Io)?;
+        let segments = src.metadata().map_err(Error::Io)?.len() / SEGMENT_SIZE as u64 + 1;
+        let mut dest = io::BufWriter::new(fs::File::create(dest).map_err(Error::Io)?);
+        dest.write_all(&self.header).map_err(Error::Io)?;
+
+        let mut hasher = hashing::Sha256::default();
+        let mut buf = vec![0; SEGMENT_SIZE];
+        for index in 0..segments {
+            let len = fill(&mut src, &mut buf).map_err(Error::Io)?;
+            hasher.update(&buf[..len]);
+
+            let sealed = self
+                .aead
+                .encrypt(
+                    &segment_nonce(index),
+                    Payload {
+                        msg: &buf[..len],
+                        aad: &[(index + 1 == segments) as u8],
+                    },
+                )
+                .map_err(|_| Error::Io(invalid_data("segment too large")))?;
+            dest.write_all(&sealed).map_err(Error::Io)?;
+        }
+
+        dest.into_inner()
+            .map_err(|err| Error::Io(err.into_error()))?
+            .sync_all()
+            .map_err(Error::Io)?;
+
+        Ok(Digest::new(hasher.finalize()))
+    }
+}
+
+/// Reads the header of a blob file, returning its length or `None` for plaintext blobs.
+///
+/// Leaves the file positioned after the wrapped key's length, rewinds it for plaintext blobs.
+async fn read_header_len(file: &mut tokio::fs::File) -> io::Result<Option<u64>> {
+    let mut prefix = [0; MAGIC.len() + 2];
+    let mut read = 0;
+    while read < prefix.len() {
+        match file.read(&mut prefix[read..]).await? {
+            0 => break,
+            n => read += n,
+        }
+    }
+
+    if read < prefix.len() || &prefix[..MAGIC.len()] != MAGIC {
+        tokio::io::AsyncSeekExt::rewind(file).await?;
+        return Ok(None);
+    }
+
+    let wrapped_len = u16::from_be_bytes([prefix[MAGIC.len()], prefix[MAGIC.len() + 1]]);
+    Ok(Some((prefix.len() + wrapped_len as usize) as u64))
+}
+
+/// Returns the number of segments of a sealed blob, from the combined length of its segments.
+fn segment_count(sealed_len: u64) -> u64 {
+    sealed_len.div_ceil((SEGMENT_SIZE + TAG_LEN) as u64).max(1)
+}
+
+/// Returns the nonce of a segment.
+///
+/// Data keys are never reused, so the segment index is a unique nonce.
+fn segment_nonce(index: u64) -> Nonce<<Aes256Gcm as AeadCore>::NonceSize> {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[NONCE_LEN - 8..].copy_from_slice(&index.to_be_bytes());
+    nonce.into()
+}
+
+/// Reads from `src` until `buf` is full or the end is reached, returning the number of bytes read.
+fn fill(src: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match src.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use sec::Secret;
+
+    use super::{segment_count, KeyProvider, StaticKey, SEGMENT_SIZE, TAG_LEN};
+
+    #[tokio::test]
+    async fn static_keys_wrap_and_unwrap() {
+        let provider = StaticKey::new(Secret::new([1; 32]));
+        let key = Secret::new([7; 32]);
+
+        let wrapped = provider.wrap_key(&key).await.unwrap();
+        assert_ne!(&wrapped[12..44], key.reveal());
+        assert_eq!(
+            provider.unwrap_key(&wrapped).await.unwrap().reveal(),
+            key.reveal()
+        );
+
+        let other = StaticKey::new(Secret::new([2; 32]));
+        assert!(other.unwrap_key(&wrapped).await.is_err());
+        assert!(provider.unwrap_key(&wrapped[..8]).await.is_err());
+    }
+
+    #[test]
+    fn segments_are_counted() {
+        let sealed = (SEGMENT_SIZE + TAG_LEN) as u64;
+
+        // Empty blobs still consist of a single, empty segment.
+        assert_eq!(segment_count(TAG_LEN as u64), 1);
+        assert_eq!(segment_count(sealed), 1);
+        assert_eq!(segment_count(sealed + TAG_LEN as u64), 2);
+        assert_eq!(segment_count(2 * sealed + 100), 3);
+    }
+}
@@ -0,0 +1,257 @@
+//! Storage latency instrumentation.
+//!
+//! [`InstrumentedStorage`] wraps a storage backend, timing every operation and counting its
+//! failures in the registry's [`StorageMetrics`]. Operations returning readers or writers are only
+//! timed until the reader or writer has been opened.
+
+use std::{future::Future, sync::Arc, time::Instant};
+
+use axum::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+use uuid::Uuid;
+
+use super::{
+    BlobMetadata, BlobStore, Digest, Error, ImageLocation, ManifestMetadata, ManifestReference,
+    ManifestStore, TagMetadata, UploadMetadata, UploadSessionStore,
+};
+use crate::{metrics::StorageMetrics, types::ContentDescriptor};
+
+/// A storage backend recording the latency of its operations.
+pub(crate) struct InstrumentedStorage<S> {
+    /// The wrapped backend.
+    inner: S,
+    /// Name of the backend, used to label its metrics.
+    backend: &'static str,
+    /// The metrics to record into.
+    metrics: Arc<StorageMetrics>,
+}
+
+impl<S> InstrumentedStorage<S> {
+    /// Wraps a backend, recording its metrics labeled as `backend`.
+    pub(crate) fn new(inner: S, backend: &'static str, metrics: Arc<StorageMetrics>) -> Self {
+        Self {
+            inner,
+            backend,
+            metrics,
+        }
+    }
+
+    /// Runs a single operation, recording its latency and outcome.
+    async fn record<T>(
+        &self,
+        operation: &'static str,
+        fut: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        let started = Instant::now();
+        let result = fut.await;
+
+        let metrics = self.metrics.operation(self.backend, operation);
+        metrics.latency.observe(started.elapsed());
+        if result.is_err() {
+            metrics.errors.inc();
+        }
+
+        result
+    }
+}
+
+#[async_trait]
+impl<S> BlobStore for InstrumentedStorage<S>
+where
+    S: BlobStore,
+{
+    async fn get_blob_reader(
+        &self,
+        digest: Digest,
+    ) -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>, Error> {
+        self.record("get_blob_reader", self.inner.get_blob_reader(digest))
+            .await
+    }
+
+    async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+        self.record("get_blob_metadata", self.inner.get_blob_metadata(digest))
+            .await
+    }
+
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error> {
+        self.record("list_blobs", self.inner.list_blobs()).await
+    }
+
+    async fn delete_blob(&self, digest: Digest) -> Result<(), Error> {
+        self.record("delete_blob", self.inner.delete_blob(digest))
+            .await
+    }
+
+    async fn quarantine_blob(&self, digest: Digest) -> Result<(), Error> {
+        self.record("quarantine_blob", self.inner.quarantine_blob(digest))
+            .await
+    }
+
+    async fn usage(&self) -> Result<u64, Error> {
+        self.record("usage", self.inner.usage()).await
+    }
+
+    async fn blob_url(&self, digest: Digest) -> Result<Option<String>, Error> {
+        self.record("blob_url", self.inner.blob_url(digest)).await
+    }
+}
+
+#[async_trait]
+impl<S> UploadSessionStore for InstrumentedStorage<S>
+where
+    S: UploadSessionStore,
+{
+    async fn begin_new_upload(&self) -> Result<Uuid, Error> {
+        self.record("begin_new_upload", self.inner.begin_new_upload())
+            .await
+    }
+
+    async fn get_upload_writer(
+        &self,
+        start_at: u64,
+        upload: Uuid,
+    ) -> Result<Box<dyn AsyncWrite + Send + Unpin>, Error> {
+        self.record(
+            "get_upload_writer",
+            self.inner.get_upload_writer(start_at, upload),
+        )
+        .await
+    }
+
+    async fn finalize_upload(&self, upload: Uuid, hash: Digest) -> Result<(), Error> {
+        self.record("finalize_upload", self.inner.finalize_upload(upload, hash))
+            .await
+    }
+
+    async fn list_uploads(&self) -> Result<Vec<UploadMetadata>, Error> {
+        self.record("list_uploads", self.inner.list_uploads()).await
+    }
+
+    async fn cancel_upload(&self, upload: Uuid) -> Result<(), Error> {
+        self.record("cancel_upload", self.inner.cancel_upload(upload))
+            .await
+    }
+
+    async fn truncate_upload(&self, upload: Uuid, size: u64) -> Result<(), Error> {
+        self.record("truncate_upload", self.inner.truncate_upload(upload, size))
+            .await
+    }
+
+    async fn get_upload_size(&self, upload: Uuid) -> Result<u64, Error> {
+        self.record("get_upload_size", self.inner.get_upload_size(upload))
+            .await
+    }
+}
+
+#[async_trait]
+impl<S> ManifestStore for InstrumentedStorage<S>
+where
+    S: ManifestStore,
+{
+    async fn get_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        self.record("get_manifest", self.inner.get_manifest(manifest_reference))
+            .await
+    }
+
+    async fn put_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+        manifest: &[u8],
+    ) -> Result<Digest, Error> {
+        self.record(
+            "put_manifest",
+            self.inner.put_manifest(manifest_reference, manifest),
+        )
+        .await
+    }
+
+    async fn put_tag(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+        digest: Digest,
+    ) -> Result<(), Error> {
+        self.record("put_tag", self.inner.put_tag(location, tag, digest))
+            .await
+    }
+
+    async fn get_referrers(
+        &self,
+        location: &ImageLocation,
+        subject: Digest,
+    ) -> Result<Vec<ContentDescriptor>, Error> {
+        self.record("get_referrers", self.inner.get_referrers(location, subject))
+            .await
+    }
+
+    async fn list_manifest_digests(&self) -> Result<Vec<Digest>, Error> {
+        self.record("list_manifest_digests", self.inner.list_manifest_digests())
+            .await
+    }
+
+    async fn find_manifest_digests(&self, prefix: &str) -> Result<Vec<Digest>, Error> {
+        self.record(
+            "find_manifest_digests",
+            self.inner.find_manifest_digests(prefix),
+        )
+        .await
+    }
+
+    async fn delete_manifest(&self, digest: Digest) -> Result<(), Error> {
+        self.record("delete_manifest", self.inner.delete_manifest(digest))
+            .await
+    }
+
+    async fn list_locations(&self) -> Result<Vec<ImageLocation>, Error> {
+        self.record("list_locations", self.inner.list_locations())
+            .await
+    }
+
+    async fn list_tags(&self, location: &ImageLocation) -> Result<Vec<TagMetadata>, Error> {
+        self.record("list_tags", self.inner.list_tags(location))
+            .await
+    }
+
+    async fn delete_tag(&self, location: &ImageLocation, tag: &str) -> Result<(), Error> {
+        self.record("delete_tag", self.inner.delete_tag(location, tag))
+            .await
+    }
+
+    async fn delete_location(&self, location: &ImageLocation) -> Result<(), Error> {
+        self.record("delete_location", self.inner.delete_location(location))
+            .await
+    }
+
+    async fn list_manifests(
+        &self,
+        location: &ImageLocation,
+    ) -> Result<Vec<ManifestMetadata>, Error> {
+        self.record("list_manifests", self.inner.list_manifests(location))
+            .await
+    }
+
+    async fn link_blob(&self, location: &ImageLocation, digest: Digest) -> Result<(), Error> {
+        self.record("link_blob", self.inner.link_blob(location, digest))
+            .await
+    }
+
+    async fn is_blob_linked(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<bool, Error> {
+        self.record(
+            "is_blob_linked",
+            self.inner.is_blob_linked(location, digest),
+        )
+        .await
+    }
+
+    async fn list_blob_links(&self, digest: Digest) -> Result<Vec<ImageLocation>, Error> {
+        self.record("list_blob_links", self.inner.list_blob_links(digest))
+            .await
+    }
+}
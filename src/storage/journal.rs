@@ -0,0 +1,86 @@
+//! Write-ahead journal for metadata updates.
+//!
+//! Storing a manifest touches several files: the manifest itself, the location index, the
+//! referrers index and possibly a tag. Before any of them is written, the intended update is
+//! recorded durably in the journal and only removed once all files have been written. After a
+//! crash, [`FilesystemStorage`](super::FilesystemStorage) replays leftover entries on startup,
+//! completing updates whose manifest made it to disk. Manifests are written atomically, so a tag
+//! can never point at a partially written one.
+
+use std::{fs, io, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use super::{remove_file_if_exists, sync_dir, Error, ImageLocation};
+use crate::ImageDigest;
+
+/// A metadata update recorded in the journal.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(super) enum Intent {
+    /// Storing a manifest at a location, optionally tagging it.
+    PutManifest {
+        location: ImageLocation,
+        digest: ImageDigest,
+        tag: Option<String>,
+    },
+    /// Pointing a tag at an existing manifest.
+    PutTag {
+        location: ImageLocation,
+        tag: String,
+        digest: ImageDigest,
+    },
+    /// Removing a location's tags and indices.
+    DeleteLocation { location: ImageLocation },
+}
+
+/// The journal, one file per in-flight update.
+#[derive(Clone, Debug)]
+pub(super) struct Journal {
+    /// Directory holding the entries.
+    dir: PathBuf,
+}
+
+impl Journal {
+    /// Opens the journal in `dir`, which must exist.
+    pub(super) fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Durably records an intent, returning the entry to pass to [`Self::commit`] once the update
+    /// has been carried out.
+    pub(super) async fn begin(&self, intent: &Intent) -> Result<PathBuf, Error> {
+        let entry = self.dir.join(format!("{}.json", Uuid::new_v4()));
+        let raw = serde_json::to_vec(intent).expect("serialization should not fail");
+
+        let mut file = tokio::fs::File::create(&entry).await.map_err(Error::Io)?;
+        file.write_all(&raw).await.map_err(Error::Io)?;
+        file.sync_all().await.map_err(Error::Io)?;
+        // Without, the entry itself may be lost in a crash despite its contents being on disk.
+        sync_dir(&self.dir).await?;
+
+        Ok(entry)
+    }
+
+    /// Removes an entry after its update has been carried out.
+    pub(super) async fn commit(&self, entry: PathBuf) -> Result<(), Error> {
+        remove_file_if_exists(&entry).await?;
+        Ok(())
+    }
+
+    /// Returns all entries left behind by updates that did not complete.
+    ///
+    /// Entries that cannot be parsed were torn while being written, i.e. before their update
+    /// started, and are returned as `None`.
+    pub(super) fn pending(&self) -> io::Result<Vec<(PathBuf, Option<Intent>)>> {
+        let mut pending = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let intent = serde_json::from_slice(&fs::read(&path)?).ok();
+            pending.push((path, intent));
+        }
+        Ok(pending)
+    }
+}
@@ -0,0 +1,474 @@
+//! Storage keeping everything in memory.
+//!
+//! Requires the `test-support` feature to be enabled, see
+//! [`ContainerRegistryBuilder::memory_storage`](crate::ContainerRegistryBuilder::memory_storage).
+//!
+//! Blobs, uploads, manifests and tags are lost once the registry is dropped. Meant for
+//! benchmarks and tests whose results should not depend on the filesystem they run on.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use axum::{async_trait, body::Bytes};
+use tokio::io::{AsyncRead, AsyncWrite};
+use uuid::Uuid;
+
+use super::{
+    is_valid_tag, BlobMetadata, BlobStore, Digest, Error, ImageLocation, ManifestMetadata,
+    ManifestReference, ManifestStore, ManifestSummary, Reference, TagMetadata, UploadMetadata,
+    UploadSessionStore,
+};
+use crate::types::{ContentDescriptor, Manifest};
+
+/// Contents stored along with the time they were last written.
+#[derive(Debug)]
+struct Stored<T> {
+    data: T,
+    modified: SystemTime,
+}
+
+impl<T> Stored<T> {
+    /// Stores `data`, written just now.
+    fn now(data: T) -> Self {
+        Self {
+            data,
+            modified: SystemTime::now(),
+        }
+    }
+}
+
+/// Everything stored, guarded by a single lock.
+#[derive(Debug, Default)]
+struct State {
+    blobs: HashMap<Digest, Stored<Bytes>>,
+    uploads: HashMap<Uuid, Stored<Vec<u8>>>,
+    manifests: HashMap<Digest, Bytes>,
+    /// Tags by location, pointing at manifests.
+    tags: HashMap<ImageLocation, HashMap<String, Stored<Digest>>>,
+    /// Manifests stored at each location, with the summary of image manifests.
+    index: HashMap<ImageLocation, HashMap<Digest, Stored<Option<ManifestSummary>>>>,
+    /// Descriptors of manifests with a subject, by location and subject.
+    referrers: HashMap<(ImageLocation, Digest), HashMap<Digest, ContentDescriptor>>,
+    /// Blobs linked to each location.
+    links: HashMap<ImageLocation, HashSet<Digest>>,
+}
+
+impl State {
+    /// Adds a stored manifest to the index of `location`, keeping its creation time if present.
+    fn index_manifest(
+        &mut self,
+        location: &ImageLocation,
+        digest: Digest,
+        summary: Option<ManifestSummary>,
+    ) {
+        self.index
+            .entry(location.clone())
+            .or_default()
+            .entry(digest)
+            .or_insert_with(|| Stored::now(summary));
+    }
+}
+
+/// Storage keeping everything in memory, see the [module documentation](self).
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MemoryStorage {
+    state: Arc<Mutex<State>>,
+}
+
+impl MemoryStorage {
+    /// Locks the stored state.
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("lock poisoned")
+    }
+}
+
+/// Writer appending to an in-memory upload.
+struct UploadWriter {
+    state: Arc<Mutex<State>>,
+    upload: Uuid,
+}
+
+impl AsyncWrite for UploadWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.state.lock().expect("lock poisoned");
+        let Some(upload) = state.uploads.get_mut(&self.upload) else {
+            return Poll::Ready(Err(io::ErrorKind::NotFound.into()));
+        };
+
+        upload.data.extend_from_slice(buf);
+        upload.modified = SystemTime::now();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait]
+impl BlobStore for MemoryStorage {
+    async fn get_blob_reader(
+        &self,
+        digest: Digest,
+    ) -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>, Error> {
+        Ok(self.state().blobs.get(&digest).map(|blob| {
+            Box::new(io::Cursor::new(blob.data.clone())) as Box<dyn AsyncRead + Send + Unpin>
+        }))
+    }
+
+    async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+        Ok(self.state().blobs.get(&digest).map(|blob| BlobMetadata {
+            digest,
+            size: blob.data.len() as u64,
+            modified: blob.modified,
+        }))
+    }
+
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error> {
+        Ok(self
+            .state()
+            .blobs
+            .iter()
+            .map(|(&digest, blob)| BlobMetadata {
+                digest,
+                size: blob.data.len() as u64,
+                modified: blob.modified,
+            })
+            .collect())
+    }
+
+    async fn delete_blob(&self, digest: Digest) -> Result<(), Error> {
+        self.state().blobs.remove(&digest);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UploadSessionStore for MemoryStorage {
+    async fn begin_new_upload(&self) -> Result<Uuid, Error> {
+        let upload = Uuid::new_v4();
+        self.state().uploads.insert(upload, Stored::now(Vec::new()));
+        Ok(upload)
+    }
+
+    /// Like files opened for appending, writes always go to the end of the upload.
+    async fn get_upload_writer(
+        &self,
+        _start_at: u64,
+        upload: Uuid,
+    ) -> Result<Box<dyn AsyncWrite + Send + Unpin>, Error> {
+        if !self.state().uploads.contains_key(&upload) {
+            return Err(Error::UploadDoesNotExit);
+        }
+
+        Ok(Box::new(UploadWriter {
+            state: self.state.clone(),
+            upload,
+        }))
+    }
+
+    async fn finalize_upload(&self, upload: Uuid, hash: Digest) -> Result<(), Error> {
+        let mut state = self.state();
+        let Some(stored) = state.uploads.get(&upload) else {
+            return Err(Error::UploadDoesNotExit);
+        };
+
+        // Like on disk, a mismatching upload is kept, so it can be inspected or cancelled.
+        if Digest::from_contents(&stored.data) != hash {
+            return Err(Error::DigestMismatch);
+        }
+
+        let stored = state.uploads.remove(&upload).expect("upload vanished");
+        state
+            .blobs
+            .insert(hash, Stored::now(Bytes::from(stored.data)));
+        Ok(())
+    }
+
+    async fn get_upload_size(&self, upload: Uuid) -> Result<u64, Error> {
+        self.state()
+            .uploads
+            .get(&upload)
+            .map(|stored| stored.data.len() as u64)
+            .ok_or(Error::UploadDoesNotExit)
+    }
+
+    async fn list_uploads(&self) -> Result<Vec<UploadMetadata>, Error> {
+        Ok(self
+            .state()
+            .uploads
+            .iter()
+            .map(|(&upload, stored)| UploadMetadata {
+                upload,
+                size: stored.data.len() as u64,
+                modified: stored.modified,
+            })
+            .collect())
+    }
+
+    async fn cancel_upload(&self, upload: Uuid) -> Result<(), Error> {
+        self.state()
+            .uploads
+            .remove(&upload)
+            .map(|_| ())
+            .ok_or(Error::UploadDoesNotExit)
+    }
+
+    async fn truncate_upload(&self, upload: Uuid, size: u64) -> Result<(), Error> {
+        let mut state = self.state();
+        let stored = state
+            .uploads
+            .get_mut(&upload)
+            .ok_or(Error::UploadDoesNotExit)?;
+        stored.data.resize(size as usize, 0);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ManifestStore for MemoryStorage {
+    async fn get_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let state = self.state();
+        let digest = match manifest_reference.reference() {
+            Reference::Tag(ref tag) => {
+                match state
+                    .tags
+                    .get(manifest_reference.location())
+                    .and_then(|tags| tags.get(tag))
+                {
+                    Some(stored) => stored.data,
+                    None => return Ok(None),
+                }
+            }
+            Reference::Digest(digest) => *digest,
+        };
+
+        Ok(state.manifests.get(&digest).map(|raw| raw.to_vec()))
+    }
+
+    async fn put_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+        manifest: &[u8],
+    ) -> Result<Digest, Error> {
+        let parsed = Manifest::from_slice(manifest).map_err(Error::InvalidManifest)?;
+
+        let digest = Digest::from_contents(manifest);
+
+        match manifest_reference.reference() {
+            Reference::Tag(tag) if !is_valid_tag(tag) => return Err(Error::InvalidTag),
+            Reference::Digest(expected) if *expected != digest => {
+                return Err(Error::DigestMismatch)
+            }
+            _ => {}
+        }
+
+        let subject = parsed
+            .subject()
+            .map(|subject| subject.parsed_digest())
+            .transpose()
+            .map_err(Error::InvalidSubject)?;
+
+        let location = manifest_reference.location();
+        let mut state = self.state();
+        state
+            .manifests
+            .insert(digest, Bytes::copy_from_slice(manifest));
+        state.index_manifest(location, digest, ManifestSummary::of(&parsed));
+
+        if let Some(subject) = subject {
+            let descriptor =
+                ContentDescriptor::for_manifest(&parsed, digest, manifest.len() as u64);
+            state
+                .referrers
+                .entry((location.clone(), subject))
+                .or_default()
+                .insert(digest, descriptor);
+        }
+
+        if let Some(tag) = manifest_reference.reference().as_tag() {
+            state
+                .tags
+                .entry(location.clone())
+                .or_default()
+                .insert(tag.to_owned(), Stored::now(digest));
+        }
+
+        Ok(digest)
+    }
+
+    async fn put_tag(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+        digest: Digest,
+    ) -> Result<(), Error> {
+        if !is_valid_tag(tag) {
+            return Err(Error::InvalidTag);
+        }
+
+        let mut state = self.state();
+        let Some(raw) = state.manifests.get(&digest) else {
+            return Err(Error::ManifestDoesNotExist);
+        };
+
+        // Tags may point at manifests originally pushed to another location.
+        let parsed = Manifest::from_slice(raw).map_err(Error::InvalidManifest)?;
+        state.index_manifest(location, digest, ManifestSummary::of(&parsed));
+        state
+            .tags
+            .entry(location.clone())
+            .or_default()
+            .insert(tag.to_owned(), Stored::now(digest));
+
+        Ok(())
+    }
+
+    async fn get_referrers(
+        &self,
+        location: &ImageLocation,
+        subject: Digest,
+    ) -> Result<Vec<ContentDescriptor>, Error> {
+        let state = self.state();
+        let Some(referrers) = state.referrers.get(&(location.clone(), subject)) else {
+            return Ok(Vec::new());
+        };
+
+        // Skip referrers whose manifest has since been removed.
+        Ok(referrers
+            .iter()
+            .filter(|(digest, _)| state.manifests.contains_key(digest))
+            .map(|(_, descriptor)| descriptor.clone())
+            .collect())
+    }
+
+    async fn list_manifest_digests(&self) -> Result<Vec<Digest>, Error> {
+        Ok(self.state().manifests.keys().copied().collect())
+    }
+
+    async fn delete_manifest(&self, digest: Digest) -> Result<(), Error> {
+        let mut state = self.state();
+        state.manifests.remove(&digest);
+        for manifests in state.index.values_mut() {
+            manifests.remove(&digest);
+        }
+        Ok(())
+    }
+
+    async fn list_locations(&self) -> Result<Vec<ImageLocation>, Error> {
+        Ok(self
+            .state()
+            .tags
+            .iter()
+            .filter(|(_, tags)| !tags.is_empty())
+            .map(|(location, _)| location.clone())
+            .collect())
+    }
+
+    async fn list_tags(&self, location: &ImageLocation) -> Result<Vec<TagMetadata>, Error> {
+        Ok(self
+            .state()
+            .tags
+            .get(location)
+            .into_iter()
+            .flatten()
+            .map(|(tag, stored)| TagMetadata {
+                tag: tag.clone(),
+                digest: stored.data,
+                modified: stored.modified,
+            })
+            .collect())
+    }
+
+    async fn delete_tag(&self, location: &ImageLocation, tag: &str) -> Result<(), Error> {
+        if let Some(tags) = self.state().tags.get_mut(location) {
+            tags.remove(tag);
+        }
+        Ok(())
+    }
+
+    async fn delete_location(&self, location: &ImageLocation) -> Result<(), Error> {
+        let mut state = self.state();
+        state.tags.remove(location);
+        state.index.remove(location);
+        state
+            .referrers
+            .retain(|(referrer_location, _), _| referrer_location != location);
+        state.links.remove(location);
+        Ok(())
+    }
+
+    async fn list_manifests(
+        &self,
+        location: &ImageLocation,
+    ) -> Result<Vec<ManifestMetadata>, Error> {
+        let state = self.state();
+
+        let mut tags: HashMap<Digest, Vec<String>> = HashMap::new();
+        for (tag, stored) in state.tags.get(location).into_iter().flatten() {
+            tags.entry(stored.data).or_default().push(tag.clone());
+        }
+
+        Ok(state
+            .index
+            .get(location)
+            .into_iter()
+            .flatten()
+            .filter(|(digest, _)| state.manifests.contains_key(digest))
+            .map(|(&digest, stored)| ManifestMetadata {
+                digest,
+                created: stored.modified,
+                tags: tags.remove(&digest).unwrap_or_default(),
+                summary: stored.data,
+            })
+            .collect())
+    }
+
+    async fn link_blob(&self, location: &ImageLocation, digest: Digest) -> Result<(), Error> {
+        self.state()
+            .links
+            .entry(location.clone())
+            .or_default()
+            .insert(digest);
+        Ok(())
+    }
+
+    async fn is_blob_linked(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<bool, Error> {
+        Ok(self
+            .state()
+            .links
+            .get(location)
+            .is_some_and(|links| links.contains(&digest)))
+    }
+
+    async fn list_blob_links(&self, digest: Digest) -> Result<Vec<ImageLocation>, Error> {
+        Ok(self
+            .state()
+            .links
+            .iter()
+            .filter(|(_, links)| links.contains(&digest))
+            .map(|(location, _)| location.clone())
+            .collect())
+    }
+}
@@ -0,0 +1,512 @@
+//! Mirroring of stored images into OCI image layouts.
+//!
+//! For disaster recovery, the registry can keep an [OCI image layout] per image alongside its own
+//! storage, see
+//! [`ContainerRegistryBuilder::oci_layout`](crate::ContainerRegistryBuilder::oci_layout). The
+//! layout of `<repository>/<image>` lives in `oci/<repository>/<image>` inside the storage
+//! directory and consists of
+//!
+//! * the `oci-layout` marker file,
+//! * an `index.json` listing every manifest stored at the location, once per tag with the tag as
+//!   `org.opencontainers.image.ref.name` annotation, untagged manifests without one, and
+//! * a `blobs/sha256` directory holding these manifests, the manifests of indices and all configs
+//!   and layers they refer to.
+//!
+//! Layouts can thus be read directly by tools like `skopeo` or `umoci`, e.g.
+//! `skopeo copy oci:/var/lib/registry/oci/library/nginx:latest ...`. Blobs are copied rather than
+//! linked, so layouts stay usable regardless of how the registry itself stores blobs, e.g.
+//! encrypted, chunked or in cold storage, at the cost of the disk space.
+//!
+//! [`OciLayoutStorage`] updates the layout of a location whenever its manifests or tags change.
+//! Failing to do so is logged, but does not fail the change itself; the layout is brought up to
+//! date with the next change or by
+//! [`ContainerRegistry::sync_oci_layouts`](crate::ContainerRegistry::sync_oci_layouts), which
+//! also writes layouts for content stored before mirroring was enabled.
+//!
+//! [OCI image layout]: https://github.com/opencontainers/image-spec/blob/main/image-layout.md
+
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::async_trait;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::Mutex,
+};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use super::{
+    read_dir_or_empty, BlobMetadata, BlobStore, Digest, Error, FilesystemStorageError,
+    ImageLocation, ManifestMetadata, ManifestReference, ManifestStore, Reference, RegistryStorage,
+    TagMetadata, UploadMetadata, UploadSessionStore,
+};
+use crate::{
+    types::{ContentDescriptor, Manifest},
+    ImageDigest,
+};
+
+/// Contents of the `oci-layout` marker file.
+const LAYOUT_MARKER: &[u8] = br#"{"imageLayoutVersion":"1.0.0"}"#;
+
+/// Annotation carrying the tag of a manifest listed in `index.json`.
+const REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+/// OCI image layouts kept in a directory, see the [module documentation](self).
+#[derive(Debug)]
+pub(crate) struct OciLayout {
+    /// Directory holding a layout per location.
+    root: PathBuf,
+    /// Serializes updates of layouts.
+    updates: Mutex<()>,
+}
+
+impl OciLayout {
+    /// Opens the layouts kept in `storage`, creating their directory if necessary.
+    pub(crate) fn open(storage: &Path) -> Result<Self, FilesystemStorageError> {
+        let root = storage.join("oci");
+        if !root.exists() {
+            std::fs::create_dir_all(&root).map_err(|err| {
+                FilesystemStorageError::FailedToCreateDir {
+                    path: root.clone(),
+                    err,
+                }
+            })?;
+        }
+
+        Ok(Self {
+            root,
+            updates: Mutex::new(()),
+        })
+    }
+
+    /// Returns the directory holding the layout of `location`.
+    fn location_dir(&self, location: &ImageLocation) -> PathBuf {
+        self.root.join(location.repository()).join(location.image())
+    }
+
+    /// Writes all layouts, returning the number of locations written.
+    ///
+    /// Layouts of locations no longer stored are removed.
+    pub(crate) async fn sync_all(&self, storage: &dyn RegistryStorage) -> Result<usize, Error> {
+        let mut locations: HashSet<_> = storage.list_locations().await?.into_iter().collect();
+        locations.extend(self.mirrored_locations().await?);
+
+        for location in &locations {
+            self.refresh(storage, location).await?;
+        }
+
+        Ok(locations.len())
+    }
+
+    /// Lists the locations a layout has been written for.
+    async fn mirrored_locations(&self) -> Result<Vec<ImageLocation>, Error> {
+        let mut locations = Vec::new();
+        for repository in read_dir_or_empty(&self.root).await? {
+            let Ok(repository_name) = repository.file_name().into_string() else {
+                continue;
+            };
+            for image in read_dir_or_empty(&repository.path()).await? {
+                if let Ok(image_name) = image.file_name().into_string() {
+                    locations.push(ImageLocation::new(repository_name.clone(), image_name));
+                }
+            }
+        }
+        Ok(locations)
+    }
+
+    /// Rewrites the layouts containing a manifest, e.g. after it has been removed.
+    pub(crate) async fn refresh_containing(
+        &self,
+        storage: &dyn RegistryStorage,
+        digest: Digest,
+    ) -> Result<(), Error> {
+        for location in self.mirrored_locations().await? {
+            let blob = self
+                .location_dir(&location)
+                .join("blobs/sha256")
+                .join(digest.to_string());
+            if tokio::fs::try_exists(blob).await.map_err(Error::Io)? {
+                self.refresh(storage, &location).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites the layout of `location` from `storage`, removing it if the location is empty.
+    pub(crate) async fn refresh(
+        &self,
+        storage: &dyn RegistryStorage,
+        location: &ImageLocation,
+    ) -> Result<(), Error> {
+        let _guard = self.updates.lock().await;
+
+        let dir = self.location_dir(location);
+        let mut manifests = storage.list_manifests(location).await?;
+        if manifests.is_empty() {
+            match tokio::fs::remove_dir_all(&dir).await {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(Error::Io(err)),
+            }
+            // Fails if other images remain, which is fine.
+            let _ = tokio::fs::remove_dir(self.root.join(location.repository())).await;
+            return Ok(());
+        }
+        manifests.sort_by_key(|manifest| manifest.digest);
+
+        let blobs_dir = dir.join("blobs/sha256");
+        tokio::fs::create_dir_all(&blobs_dir)
+            .await
+            .map_err(Error::Io)?;
+        tokio::fs::write(dir.join("oci-layout"), LAYOUT_MARKER)
+            .await
+            .map_err(Error::Io)?;
+
+        let mut mirror = Mirror {
+            storage,
+            blobs_dir: &blobs_dir,
+            written: HashSet::new(),
+        };
+        let mut descriptors = Vec::new();
+        for manifest in manifests {
+            let Some((media_type, size)) = mirror.manifest(location, manifest.digest).await? else {
+                continue;
+            };
+
+            let descriptor = |tag: Option<&str>| {
+                let mut descriptor = serde_json::json!({
+                    "mediaType": media_type,
+                    "digest": ImageDigest::new(manifest.digest).to_string(),
+                    "size": size,
+                });
+                if let Some(tag) = tag {
+                    descriptor["annotations"] = serde_json::json!({ REF_NAME: tag });
+                }
+                descriptor
+            };
+            if manifest.tags.is_empty() {
+                descriptors.push(descriptor(None));
+            }
+            let mut tags = manifest.tags.clone();
+            tags.sort();
+            descriptors.extend(tags.iter().map(|tag| descriptor(Some(tag))));
+        }
+
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": crate::types::OCI_IMAGE_INDEX,
+            "manifests": descriptors,
+        });
+        let tmp = dir.join(format!("index.json.{}.tmp", Uuid::new_v4()));
+        tokio::fs::write(
+            &tmp,
+            serde_json::to_vec_pretty(&index).expect("serialization should not fail"),
+        )
+        .await
+        .map_err(Error::Io)?;
+        tokio::fs::rename(tmp, dir.join("index.json"))
+            .await
+            .map_err(Error::Io)?;
+
+        // Drop whatever is no longer referenced, including leftovers of interrupted copies.
+        for entry in read_dir_or_empty(&blobs_dir).await? {
+            let referenced = entry
+                .file_name()
+                .to_str()
+                .and_then(Digest::from_hex_str)
+                .is_some_and(|digest| mirror.written.contains(&digest));
+            if !referenced {
+                tokio::fs::remove_file(entry.path())
+                    .await
+                    .map_err(Error::Io)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Copies manifests and blobs into the blobs directory of a layout.
+struct Mirror<'a> {
+    /// Storage to copy from.
+    storage: &'a dyn RegistryStorage,
+    /// The layout's blobs directory.
+    blobs_dir: &'a Path,
+    /// Digests of everything in the layout.
+    written: HashSet<Digest>,
+}
+
+impl Mirror<'_> {
+    /// Copies a manifest and everything it refers to, returning its media type and size.
+    ///
+    /// Returns `None` if the manifest has been removed in the meantime.
+    async fn manifest(
+        &mut self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<Option<(String, u64)>, Error> {
+        let reference = ManifestReference::new(location.clone(), Reference::new_digest(digest));
+        let Some(raw) = self.storage.get_manifest(&reference).await? else {
+            return Ok(None);
+        };
+        let manifest = Manifest::from_slice(&raw).map_err(Error::InvalidManifest)?;
+
+        if self.written.insert(digest) {
+            let path = self.blobs_dir.join(digest.to_string());
+            if !tokio::fs::try_exists(&path).await.map_err(Error::Io)? {
+                write_atomically(&path, &raw).await?;
+            }
+
+            match manifest {
+                Manifest::Image(ref image) => {
+                    for descriptor in std::iter::once(image.config()).chain(image.layers()) {
+                        self.blob(descriptor).await?;
+                    }
+                }
+                Manifest::Index(ref index) => {
+                    for child in index.manifests() {
+                        let Ok(child) = child.parsed_digest() else {
+                            continue;
+                        };
+                        if Box::pin(self.manifest(location, child)).await?.is_none() {
+                            warn!(%location, manifest = %child, "index child missing from storage, OCI layout incomplete");
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(Some((manifest.media_type().to_owned(), raw.len() as u64)))
+    }
+
+    /// Copies a blob, unless already present.
+    async fn blob(&mut self, descriptor: &ContentDescriptor) -> Result<(), Error> {
+        let Ok(digest) = descriptor.parsed_digest() else {
+            return Ok(());
+        };
+        if !self.written.insert(digest) {
+            return Ok(());
+        }
+
+        let path = self.blobs_dir.join(digest.to_string());
+        if tokio::fs::try_exists(&path).await.map_err(Error::Io)? {
+            return Ok(());
+        }
+
+        let Some(mut reader) = self.storage.get_blob_reader(digest).await? else {
+            warn!(blob = %digest, "blob missing from storage, OCI layout incomplete");
+            return Ok(());
+        };
+        let tmp = path.with_extension("tmp");
+        let mut file = tokio::fs::File::create(&tmp).await.map_err(Error::Io)?;
+        tokio::io::copy(&mut reader, &mut file)
+            .await
+            .map_err(Error::Io)?;
+        file.sync_all().await.map_err(Error::Io)?;
+        tokio::fs::rename(tmp, path).await.map_err(Error::Io)
+    }
+}
+
+/// Writes a file, so it is never visible partially written.
+async fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, contents).await.map_err(Error::Io)?;
+    tokio::fs::rename(tmp, path).await.map_err(Error::Io)
+}
+
+/// A storage wrapper keeping [`OciLayout`]s up to date, see the [module documentation](self).
+pub(crate) struct OciLayoutStorage {
+    /// The wrapped storage.
+    inner: Box<dyn RegistryStorage>,
+    /// The layouts to update.
+    layout: Arc<OciLayout>,
+}
+
+impl OciLayoutStorage {
+    /// Wraps a storage, mirroring changes into `layout`.
+    pub(crate) fn new(inner: Box<dyn RegistryStorage>, layout: Arc<OciLayout>) -> Self {
+        Self { inner, layout }
+    }
+
+    /// Rewrites the layout of a changed location, logging failures.
+    async fn refresh(&self, location: &ImageLocation) {
+        if let Err(err) = self.layout.refresh(&*self.inner, location).await {
+            error!(%location, %err, "failed to update OCI image layout");
+        }
+    }
+}
+
+#[async_trait]
+impl BlobStore for OciLayoutStorage {
+    async fn get_blob_reader(
+        &self,
+        digest: Digest,
+    ) -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>, Error> {
+        self.inner.get_blob_reader(digest).await
+    }
+
+    async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+        self.inner.get_blob_metadata(digest).await
+    }
+
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error> {
+        self.inner.list_blobs().await
+    }
+
+    async fn delete_blob(&self, digest: Digest) -> Result<(), Error> {
+        self.inner.delete_blob(digest).await
+    }
+
+    async fn quarantine_blob(&self, digest: Digest) -> Result<(), Error> {
+        self.inner.quarantine_blob(digest).await
+    }
+
+    async fn usage(&self) -> Result<u64, Error> {
+        self.inner.usage().await
+    }
+
+    async fn blob_url(&self, digest: Digest) -> Result<Option<String>, Error> {
+        self.inner.blob_url(digest).await
+    }
+}
+
+#[async_trait]
+impl UploadSessionStore for OciLayoutStorage {
+    async fn begin_new_upload(&self) -> Result<Uuid, Error> {
+        self.inner.begin_new_upload().await
+    }
+
+    async fn get_upload_writer(
+        &self,
+        start_at: u64,
+        upload: Uuid,
+    ) -> Result<Box<dyn AsyncWrite + Send + Unpin>, Error> {
+        self.inner.get_upload_writer(start_at, upload).await
+    }
+
+    async fn finalize_upload(&self, upload: Uuid, hash: Digest) -> Result<(), Error> {
+        self.inner.finalize_upload(upload, hash).await
+    }
+
+    async fn list_uploads(&self) -> Result<Vec<UploadMetadata>, Error> {
+        self.inner.list_uploads().await
+    }
+
+    async fn cancel_upload(&self, upload: Uuid) -> Result<(), Error> {
+        self.inner.cancel_upload(upload).await
+    }
+
+    async fn truncate_upload(&self, upload: Uuid, size: u64) -> Result<(), Error> {
+        self.inner.truncate_upload(upload, size).await
+    }
+
+    async fn get_upload_size(&self, upload: Uuid) -> Result<u64, Error> {
+        self.inner.get_upload_size(upload).await
+    }
+}
+
+#[async_trait]
+impl ManifestStore for OciLayoutStorage {
+    async fn get_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        self.inner.get_manifest(manifest_reference).await
+    }
+
+    async fn put_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+        manifest: &[u8],
+    ) -> Result<Digest, Error> {
+        let digest = self
+            .inner
+            .put_manifest(manifest_reference, manifest)
+            .await?;
+        self.refresh(manifest_reference.location()).await;
+        Ok(digest)
+    }
+
+    async fn put_tag(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+        digest: Digest,
+    ) -> Result<(), Error> {
+        self.inner.put_tag(location, tag, digest).await?;
+        self.refresh(location).await;
+        Ok(())
+    }
+
+    async fn get_referrers(
+        &self,
+        location: &ImageLocation,
+        subject: Digest,
+    ) -> Result<Vec<ContentDescriptor>, Error> {
+        self.inner.get_referrers(location, subject).await
+    }
+
+    async fn list_manifest_digests(&self) -> Result<Vec<Digest>, Error> {
+        self.inner.list_manifest_digests().await
+    }
+
+    async fn find_manifest_digests(&self, prefix: &str) -> Result<Vec<Digest>, Error> {
+        self.inner.find_manifest_digests(prefix).await
+    }
+
+    async fn delete_manifest(&self, digest: Digest) -> Result<(), Error> {
+        self.inner.delete_manifest(digest).await?;
+        if let Err(err) = self.layout.refresh_containing(&*self.inner, digest).await {
+            error!(manifest = %digest, %err, "failed to update OCI image layouts");
+        }
+        Ok(())
+    }
+
+    async fn list_locations(&self) -> Result<Vec<ImageLocation>, Error> {
+        self.inner.list_locations().await
+    }
+
+    async fn list_tags(&self, location: &ImageLocation) -> Result<Vec<TagMetadata>, Error> {
+        self.inner.list_tags(location).await
+    }
+
+    async fn delete_tag(&self, location: &ImageLocation, tag: &str) -> Result<(), Error> {
+        self.inner.delete_tag(location, tag).await?;
+        self.refresh(location).await;
+        Ok(())
+    }
+
+    async fn delete_location(&self, location: &ImageLocation) -> Result<(), Error> {
+        self.inner.delete_location(location).await?;
+        self.refresh(location).await;
+        Ok(())
+    }
+
+    async fn list_manifests(
+        &self,
+        location: &ImageLocation,
+    ) -> Result<Vec<ManifestMetadata>, Error> {
+        self.inner.list_manifests(location).await
+    }
+
+    async fn link_blob(&self, location: &ImageLocation, digest: Digest) -> Result<(), Error> {
+        self.inner.link_blob(location, digest).await
+    }
+
+    async fn is_blob_linked(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<bool, Error> {
+        self.inner.is_blob_linked(location, digest).await
+    }
+
+    async fn list_blob_links(&self, digest: Digest) -> Result<Vec<ImageLocation>, Error> {
+        self.inner.list_blob_links(digest).await
+    }
+}
@@ -0,0 +1,159 @@
+//! Blob storage delegated to an object store clients download from directly.
+//!
+//! With a [`RedirectBlobStore`] set through
+//! [`ContainerRegistryBuilder::redirect_storage`](crate::ContainerRegistryBuilder::redirect_storage),
+//! blobs are stored in any HTTP-accessible object store, e.g. S3, GCS or a static file server,
+//! instead of on local disk. Blob downloads are answered with a `307 Temporary Redirect` to the
+//! URL returned by [`RedirectBlobStore::blob_url`], so blob contents never pass through the
+//! registry when pulling. Implementations decide whether these URLs are public or signed, e.g.
+//! using S3 presigned URLs.
+//!
+//! Uploads are still received on local disk and moved to the object store once finalized.
+//! Failing to do so fails the upload, as a blob only stored locally could not be redirected to.
+//! Manifests and tags always stay local. The registry itself still reads blobs from the object
+//! store when it needs their contents, e.g. when validating manifests or running maintenance
+//! tasks.
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+use uuid::Uuid;
+
+use super::{
+    BlobMetadata, BlobStore, ColdBlobStore, Digest, Error, FilesystemStorage, UploadMetadata,
+    UploadSessionStore,
+};
+
+/// An object store blob downloads are redirected to.
+///
+/// Implement this trait, along with [`ColdBlobStore`] for storing and retrieving blobs, to
+/// delegate blob storage. Blob downloads are redirected to [`blob_url`](Self::blob_url), while
+/// uploads are received locally and moved to the object store once finalized. Manifests and tags
+/// always stay local.
+#[async_trait]
+pub trait RedirectBlobStore: ColdBlobStore {
+    /// Returns the URL clients download a blob from.
+    ///
+    /// Called only for blobs known to exist and after the client has been authorized. Returning
+    /// `None` serves the blob through the registry instead.
+    async fn blob_url(&self, digest: Digest) -> Result<Option<String>, Error>;
+}
+
+/// Blob storage delegating to a [`RedirectBlobStore`], see the [module documentation](self).
+pub(crate) struct RedirectStorage {
+    /// Local storage, holding uploads and blobs stored before delegating.
+    local: FilesystemStorage,
+    /// The object store holding all blobs.
+    remote: Arc<dyn RedirectBlobStore>,
+}
+
+impl RedirectStorage {
+    /// Creates a new storage, receiving uploads in `local` and storing blobs in `remote`.
+    pub(crate) fn new(local: FilesystemStorage, remote: Arc<dyn RedirectBlobStore>) -> Self {
+        Self { local, remote }
+    }
+}
+
+#[async_trait]
+impl BlobStore for RedirectStorage {
+    async fn get_blob_reader(
+        &self,
+        digest: Digest,
+    ) -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>, Error> {
+        if let Some(reader) = self.local.get_blob_reader(digest).await? {
+            return Ok(Some(reader));
+        }
+        self.remote.get(digest).await
+    }
+
+    async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+        if let Some(metadata) = self.local.get_blob_metadata(digest).await? {
+            return Ok(Some(metadata));
+        }
+        Ok(self.remote.info(digest).await?.map(BlobMetadata::from))
+    }
+
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error> {
+        let mut blobs: HashMap<_, _> = self
+            .remote
+            .list()
+            .await?
+            .into_iter()
+            .map(|info| (info.digest, BlobMetadata::from(info)))
+            .collect();
+        for blob in self.local.list_blobs().await? {
+            blobs.entry(blob.digest()).or_insert(blob);
+        }
+
+        Ok(blobs.into_values().collect())
+    }
+
+    async fn delete_blob(&self, digest: Digest) -> Result<(), Error> {
+        self.local.delete_blob(digest).await?;
+        self.remote.delete(digest).await
+    }
+
+    /// Blobs stored locally, e.g. before delegating, are served directly.
+    async fn blob_url(&self, digest: Digest) -> Result<Option<String>, Error> {
+        if self.local.get_blob_metadata(digest).await?.is_some()
+            || self.remote.info(digest).await?.is_none()
+        {
+            return Ok(None);
+        }
+        self.remote.blob_url(digest).await
+    }
+}
+
+#[async_trait]
+impl UploadSessionStore for RedirectStorage {
+    async fn begin_new_upload(&self) -> Result<Uuid, Error> {
+        self.local.begin_new_upload().await
+    }
+
+    async fn get_upload_writer(
+        &self,
+        start_at: u64,
+        upload: Uuid,
+    ) -> Result<Box<dyn AsyncWrite + Send + Unpin>, Error> {
+        self.local.get_upload_writer(start_at, upload).await
+    }
+
+    async fn finalize_upload(&self, upload: Uuid, digest: Digest) -> Result<(), Error> {
+        let existed = self.local.get_blob_metadata(digest).await?.is_some();
+        self.local.finalize_upload(upload, digest).await?;
+
+        let Some(metadata) = self.local.get_blob_metadata(digest).await? else {
+            return Ok(());
+        };
+        let reader = self
+            .local
+            .get_blob_reader(digest)
+            .await?
+            .ok_or(Error::UploadDoesNotExit)?;
+
+        // Unlike with cold storage, a blob that could not be stored remotely is not kept locally,
+        // unless it was stored before delegating.
+        let stored = self.remote.put(digest, metadata.size(), reader).await;
+        if stored.is_ok() || !existed {
+            self.local.delete_blob(digest).await?;
+        }
+        stored
+    }
+
+    async fn list_uploads(&self) -> Result<Vec<UploadMetadata>, Error> {
+        self.local.list_uploads().await
+    }
+
+    async fn cancel_upload(&self, upload: Uuid) -> Result<(), Error> {
+        self.local.cancel_upload(upload).await
+    }
+
+    async fn truncate_upload(&self, upload: Uuid, size: u64) -> Result<(), Error> {
+        self.local.truncate_upload(upload, size).await
+    }
+
+    async fn get_upload_size(&self, upload: Uuid) -> Result<u64, Error> {
+        self.local.get_upload_size(upload).await
+    }
+}
@@ -0,0 +1,358 @@
+//! Storage error injection.
+//!
+//! Requires the `test-support` feature to be enabled.
+//!
+//! [`FlakyStorage`] wraps the storage of a registry, failing or delaying operations on demand. It
+//! is configured through a [`Faults`] handle, which can be changed while the registry is running,
+//! allowing tests to verify client retry behavior and error mapping during storage outages:
+//!
+//! ```
+//! use container_registry::{storage::test_util::{Faults, Operation}, ContainerRegistry};
+//!
+//! let faults = Faults::default();
+//! let ctx = ContainerRegistry::builder()
+//!     .storage_faults(faults.clone())
+//!     .build_for_testing();
+//!
+//! // The next two manifest uploads will fail with an internal server error.
+//! faults.fail_times(Operation::PutManifest, 2);
+//! ```
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+use uuid::Uuid;
+
+use super::{
+    BlobMetadata, BlobStore, Digest, Error, ImageLocation, ManifestMetadata, ManifestReference,
+    ManifestStore, RegistryStorage, TagMetadata, UploadMetadata, UploadSessionStore,
+};
+use crate::types::ContentDescriptor;
+
+/// A storage operation faults can be injected into.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Operation {
+    /// Starting a new upload.
+    BeginUpload,
+    /// Opening a blob for reading.
+    GetBlob,
+    /// Retrieving blob metadata.
+    GetBlobMetadata,
+    /// Opening an upload for writing.
+    WriteUpload,
+    /// Retrieving the number of bytes written to an upload.
+    GetUpload,
+    /// Finalizing an upload.
+    FinalizeUpload,
+    /// Retrieving a manifest.
+    GetManifest,
+    /// Storing a manifest.
+    PutManifest,
+    /// Updating a tag.
+    PutTag,
+    /// Listing referrers.
+    GetReferrers,
+    /// Any listing or deletion, as done by maintenance tasks.
+    Maintenance,
+    /// Removing a manifest, in addition to [`Operation::Maintenance`].
+    DeleteManifest,
+}
+
+/// A fault to inject.
+#[derive(Clone, Debug)]
+enum Fault {
+    /// Fail the next `n` invocations, or all if `None`.
+    Fail(Option<usize>),
+    /// Delay every invocation.
+    Delay(Duration),
+}
+
+/// Faults to inject, shared between tests and a [`FlakyStorage`].
+///
+/// Cloning a `Faults` handle yields another handle to the same configuration.
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    /// Configured faults by operation.
+    faults: Arc<Mutex<HashMap<Operation, Fault>>>,
+}
+
+impl Faults {
+    /// Makes every invocation of `operation` fail.
+    pub fn fail(&self, operation: Operation) {
+        self.set(operation, Fault::Fail(None));
+    }
+
+    /// Makes the next `times` invocations of `operation` fail.
+    pub fn fail_times(&self, operation: Operation, times: usize) {
+        if times == 0 {
+            self.clear(operation);
+        } else {
+            self.set(operation, Fault::Fail(Some(times)));
+        }
+    }
+
+    /// Delays every invocation of `operation` by `delay`.
+    pub fn delay(&self, operation: Operation, delay: Duration) {
+        self.set(operation, Fault::Delay(delay));
+    }
+
+    /// Removes any fault configured for `operation`.
+    pub fn clear(&self, operation: Operation) {
+        self.faults
+            .lock()
+            .expect("lock poisoned")
+            .remove(&operation);
+    }
+
+    /// Removes all configured faults.
+    pub fn clear_all(&self) {
+        self.faults.lock().expect("lock poisoned").clear();
+    }
+
+    /// Sets the fault for an operation.
+    fn set(&self, operation: Operation, fault: Fault) {
+        self.faults
+            .lock()
+            .expect("lock poisoned")
+            .insert(operation, fault);
+    }
+
+    /// Applies the fault configured for `operation`, if any.
+    async fn apply(&self, operation: Operation) -> Result<(), Error> {
+        let delay = {
+            let mut faults = self.faults.lock().expect("lock poisoned");
+            match faults.get_mut(&operation) {
+                None => None,
+                Some(Fault::Delay(delay)) => Some(*delay),
+                Some(Fault::Fail(None)) => return Err(injected(operation)),
+                Some(Fault::Fail(Some(remaining))) => {
+                    *remaining -= 1;
+                    if *remaining == 0 {
+                        faults.remove(&operation);
+                    }
+                    return Err(injected(operation));
+                }
+            }
+        };
+
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates the error returned by failing operations.
+fn injected(operation: Operation) -> Error {
+    Error::Io(io::Error::other(format!(
+        "injected fault in {:?}",
+        operation
+    )))
+}
+
+/// A storage wrapper injecting faults, see the [module documentation](self).
+pub struct FlakyStorage {
+    /// The wrapped storage.
+    inner: Box<dyn RegistryStorage>,
+    /// Faults to inject.
+    faults: Faults,
+}
+
+impl FlakyStorage {
+    /// Wraps a storage, injecting `faults` into it.
+    pub(crate) fn new(inner: Box<dyn RegistryStorage>, faults: Faults) -> Self {
+        Self { inner, faults }
+    }
+}
+
+#[async_trait]
+impl BlobStore for FlakyStorage {
+    async fn get_blob_reader(
+        &self,
+        digest: Digest,
+    ) -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>, Error> {
+        self.faults.apply(Operation::GetBlob).await?;
+        self.inner.get_blob_reader(digest).await
+    }
+
+    async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+        self.faults.apply(Operation::GetBlobMetadata).await?;
+        self.inner.get_blob_metadata(digest).await
+    }
+
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error> {
+        self.faults.apply(Operation::Maintenance).await?;
+        self.inner.list_blobs().await
+    }
+
+    async fn delete_blob(&self, digest: Digest) -> Result<(), Error> {
+        self.faults.apply(Operation::Maintenance).await?;
+        self.inner.delete_blob(digest).await
+    }
+
+    async fn quarantine_blob(&self, digest: Digest) -> Result<(), Error> {
+        self.faults.apply(Operation::Maintenance).await?;
+        self.inner.quarantine_blob(digest).await
+    }
+
+    async fn usage(&self) -> Result<u64, Error> {
+        self.faults.apply(Operation::Maintenance).await?;
+        self.inner.usage().await
+    }
+
+    async fn blob_url(&self, digest: Digest) -> Result<Option<String>, Error> {
+        self.faults.apply(Operation::GetBlob).await?;
+        self.inner.blob_url(digest).await
+    }
+}
+
+#[async_trait]
+impl UploadSessionStore for FlakyStorage {
+    async fn begin_new_upload(&self) -> Result<Uuid, Error> {
+        self.faults.apply(Operation::BeginUpload).await?;
+        self.inner.begin_new_upload().await
+    }
+
+    async fn get_upload_writer(
+        &self,
+        start_at: u64,
+        upload: Uuid,
+    ) -> Result<Box<dyn AsyncWrite + Send + Unpin>, Error> {
+        self.faults.apply(Operation::WriteUpload).await?;
+        self.inner.get_upload_writer(start_at, upload).await
+    }
+
+    async fn finalize_upload(&self, upload: Uuid, hash: Digest) -> Result<(), Error> {
+        self.faults.apply(Operation::FinalizeUpload).await?;
+        self.inner.finalize_upload(upload, hash).await
+    }
+
+    async fn list_uploads(&self) -> Result<Vec<UploadMetadata>, Error> {
+        self.faults.apply(Operation::Maintenance).await?;
+        self.inner.list_uploads().await
+    }
+
+    async fn cancel_upload(&self, upload: Uuid) -> Result<(), Error> {
+        self.faults.apply(Operation::Maintenance).await?;
+        self.inner.cancel_upload(upload).await
+    }
+
+    async fn truncate_upload(&self, upload: Uuid, size: u64) -> Result<(), Error> {
+        self.faults.apply(Operation::WriteUpload).await?;
+        self.inner.truncate_upload(upload, size).await
+    }
+
+    async fn get_upload_size(&self, upload: Uuid) -> Result<u64, Error> {
+        self.faults.apply(Operation::GetUpload).await?;
+        self.inner.get_upload_size(upload).await
+    }
+}
+
+#[async_trait]
+impl ManifestStore for FlakyStorage {
+    async fn get_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        self.faults.apply(Operation::GetManifest).await?;
+        self.inner.get_manifest(manifest_reference).await
+    }
+
+    async fn put_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+        manifest: &[u8],
+    ) -> Result<Digest, Error> {
+        self.faults.apply(Operation::PutManifest).await?;
+        self.inner.put_manifest(manifest_reference, manifest).await
+    }
+
+    async fn put_tag(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+        digest: Digest,
+    ) -> Result<(), Error> {
+        self.faults.apply(Operation::PutTag).await?;
+        self.inner.put_tag(location, tag, digest).await
+    }
+
+    async fn get_referrers(
+        &self,
+        location: &ImageLocation,
+        subject: Digest,
+    ) -> Result<Vec<ContentDescriptor>, Error> {
+        self.faults.apply(Operation::GetReferrers).await?;
+        self.inner.get_referrers(location, subject).await
+    }
+
+    async fn list_manifest_digests(&self) -> Result<Vec<Digest>, Error> {
+        self.faults.apply(Operation::Maintenance).await?;
+        self.inner.list_manifest_digests().await
+    }
+
+    async fn find_manifest_digests(&self, prefix: &str) -> Result<Vec<Digest>, Error> {
+        self.faults.apply(Operation::Maintenance).await?;
+        self.inner.find_manifest_digests(prefix).await
+    }
+
+    async fn delete_manifest(&self, digest: Digest) -> Result<(), Error> {
+        self.faults.apply(Operation::Maintenance).await?;
+        self.faults.apply(Operation::DeleteManifest).await?;
+        self.inner.delete_manifest(digest).await
+    }
+
+    async fn list_locations(&self) -> Result<Vec<ImageLocation>, Error> {
+        self.faults.apply(Operation::Maintenance).await?;
+        self.inner.list_locations().await
+    }
+
+    async fn list_tags(&self, location: &ImageLocation) -> Result<Vec<TagMetadata>, Error> {
+        self.faults.apply(Operation::Maintenance).await?;
+        self.inner.list_tags(location).await
+    }
+
+    async fn delete_tag(&self, location: &ImageLocation, tag: &str) -> Result<(), Error> {
+        self.faults.apply(Operation::Maintenance).await?;
+        self.inner.delete_tag(location, tag).await
+    }
+
+    async fn delete_location(&self, location: &ImageLocation) -> Result<(), Error> {
+        self.faults.apply(Operation::Maintenance).await?;
+        self.inner.delete_location(location).await
+    }
+
+    async fn list_manifests(
+        &self,
+        location: &ImageLocation,
+    ) -> Result<Vec<ManifestMetadata>, Error> {
+        self.faults.apply(Operation::Maintenance).await?;
+        self.inner.list_manifests(location).await
+    }
+
+    async fn link_blob(&self, location: &ImageLocation, digest: Digest) -> Result<(), Error> {
+        self.faults.apply(Operation::FinalizeUpload).await?;
+        self.inner.link_blob(location, digest).await
+    }
+
+    async fn is_blob_linked(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<bool, Error> {
+        self.faults.apply(Operation::GetBlobMetadata).await?;
+        self.inner.is_blob_linked(location, digest).await
+    }
+
+    async fn list_blob_links(&self, digest: Digest) -> Result<Vec<ImageLocation>, Error> {
+        self.faults.apply(Operation::GetBlobMetadata).await?;
+        self.inner.list_blob_links(digest).await
+    }
+}
@@ -0,0 +1,648 @@
+//! Two-tier blob storage.
+//!
+//! Keeps a bounded set of recently used blobs on local disk, while all blobs are stored in a
+//! (typically remote and cheaper) cold store, e.g. S3 or GCS. Blobs are written through to the
+//! cold store once an upload is finalized, read from the cold store and promoted to local disk on
+//! access and evicted locally in least-recently-used order once the configured capacity is
+//! exceeded.
+//!
+//! Concurrent reads of a blob missing locally are coalesced into a single fetch from the cold
+//! store. Every reader streams the blob from the local file it is being fetched into, as soon as
+//! the bytes arrive, so hundreds of nodes pulling the same layer cause only one backend request.
+//! Blobs exceeding the local capacity are streamed straight from the cold store instead.
+//!
+//! Blobs are only evicted once they are known to be in the cold store. Every finalized upload is
+//! marked as pending in the `cold-pending` directory of the storage path until it has been written
+//! through, blobs failing to be written stay on local disk. On start, blobs found locally are only
+//! made evictable after checking the cold store, writing those missing or still marked as pending
+//! to it first. Blobs streamed from the cold store have their digest verified as well.
+//!
+//! Uploads are always kept on local disk. [`TieredStorage`] only stores blobs and uploads, it is
+//! composed with a local manifest store to form the registry's storage.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs, io,
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::SystemTime,
+};
+
+use axum::{async_trait, body::Bytes};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::{watch, OnceCell},
+};
+use tokio_util::io::StreamReader;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use super::{
+    BlobMetadata, BlobStore, Digest, Error, FilesystemStorage, FilesystemStorageError,
+    UploadMetadata, UploadSessionStore, BUFFER_SIZE,
+};
+use crate::hashing::{self, Hasher};
+
+/// A cold blob store.
+///
+/// Implement this trait to back a registry with remote object storage, see
+/// [`ContainerRegistryBuilder::cold_storage`](crate::ContainerRegistryBuilder::cold_storage).
+/// Blobs are immutable and addressed by their digest, implementations do not need to verify
+/// contents, as the registry does so on retrieval.
+#[async_trait]
+pub trait ColdBlobStore: Send + Sync {
+    /// Stores a blob of `size` bytes read from `contents`.
+    ///
+    /// Storing a blob that already exists must succeed.
+    async fn put(
+        &self,
+        digest: Digest,
+        size: u64,
+        contents: Box<dyn AsyncRead + Send + Unpin>,
+    ) -> Result<(), Error>;
+
+    /// Retrieves a blob, returns `None` if it does not exist.
+    async fn get(&self, digest: Digest)
+        -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>, Error>;
+
+    /// Returns information about a blob, `None` if it does not exist.
+    async fn info(&self, digest: Digest) -> Result<Option<ColdBlobInfo>, Error>;
+
+    /// Lists all stored blobs.
+    async fn list(&self) -> Result<Vec<ColdBlobInfo>, Error>;
+
+    /// Removes a blob. Removing a blob that does not exist must succeed.
+    async fn delete(&self, digest: Digest) -> Result<(), Error>;
+}
+
+/// Information about a blob in a [`ColdBlobStore`].
+#[derive(Clone, Debug)]
+pub struct ColdBlobInfo {
+    /// The blob's digest.
+    pub digest: Digest,
+    /// Size of the blob in bytes.
+    pub size: u64,
+    /// Time the blob was stored.
+    pub modified: SystemTime,
+}
+
+/// Local blobs, tracked in least-recently-used order.
+#[derive(Debug, Default)]
+struct Lru {
+    /// Size and last use of every evictable local blob.
+    entries: HashMap<Digest, (u64, u64)>,
+    /// Evictable local blobs by last use.
+    order: BTreeMap<u64, Digest>,
+    /// Combined size of all evictable local blobs.
+    total: u64,
+    /// Use counter, incremented on every access.
+    clock: u64,
+}
+
+impl Lru {
+    /// Records a use of `digest`, adding it if not yet tracked.
+    fn touch(&mut self, digest: Digest, size: u64) {
+        self.clock += 1;
+        match self.entries.insert(digest, (size, self.clock)) {
+            Some((_, last_used)) => {
+                self.order.remove(&last_used);
+            }
+            None => self.total += size,
+        }
+        self.order.insert(self.clock, digest);
+    }
+
+    /// Records a use of `digest`, if it is tracked.
+    fn touch_existing(&mut self, digest: Digest) {
+        if let Some(&(size, _)) = self.entries.get(&digest) {
+            self.touch(digest, size);
+        }
+    }
+
+    /// Stops tracking `digest`.
+    fn remove(&mut self, digest: Digest) {
+        if let Some((size, last_used)) = self.entries.remove(&digest) {
+            self.order.remove(&last_used);
+            self.total -= size;
+        }
+    }
+
+    /// Removes and returns least recently used blobs until at most `capacity` bytes remain.
+    fn evict(&mut self, capacity: u64) -> Vec<Digest> {
+        let mut evicted = Vec::new();
+        while self.total > capacity {
+            let Some((_, digest)) = self.order.pop_first() else {
+                break;
+            };
+            let (size, _) = self.entries.remove(&digest).expect("LRU out of sync");
+            self.total -= size;
+            evicted.push(digest);
+        }
+        evicted
+    }
+}
+
+/// Progress of a blob being fetched from cold storage.
+#[derive(Clone, Copy, Debug)]
+enum FetchProgress {
+    /// The given number of bytes has been written locally.
+    Running(u64),
+    /// The blob has been fetched and verified, it is this many bytes long.
+    Done(u64),
+    /// Fetching failed, the local copy is incomplete.
+    Failed,
+}
+
+/// A fetch from cold storage, shared by all concurrent readers of a blob.
+#[derive(Clone, Debug)]
+struct Fetch {
+    /// Local file the blob is being fetched into.
+    path: PathBuf,
+    /// Progress, updated whenever a chunk has been written.
+    progress: watch::Receiver<FetchProgress>,
+}
+
+/// Blob storage keeping hot blobs on local disk and all blobs in a [`ColdBlobStore`].
+///
+/// Cloning is cheap, clones share all state.
+#[derive(Clone)]
+pub(crate) struct TieredStorage {
+    /// Local storage.
+    hot: FilesystemStorage,
+    /// Remote storage.
+    cold: Arc<dyn ColdBlobStore>,
+    /// Maximum combined size of evictable local blobs.
+    capacity: u64,
+    /// Directory holding a marker for every local blob not yet written to cold storage.
+    pending: PathBuf,
+    /// Local blobs known to be in cold storage, thus evictable.
+    ///
+    /// Blobs failing to be written to cold storage are not tracked and stay on local disk.
+    lru: Arc<Mutex<Lru>>,
+    /// Blobs found locally on start, oldest first, not yet checked against cold storage.
+    found: Arc<Mutex<Vec<(Digest, u64)>>>,
+    /// Set once the blobs found on start have been checked, see [`TieredStorage::reconcile`].
+    reconciled: Arc<OnceCell<()>>,
+    /// Fetches from cold storage currently in progress.
+    fetches: Arc<Mutex<HashMap<Digest, Fetch>>>,
+}
+
+impl TieredStorage {
+    /// Creates a new tiered storage.
+    ///
+    /// Blobs already present on local disk only become evictable once checked against the cold
+    /// store, which happens before the first eviction.
+    pub(crate) fn new(
+        hot: FilesystemStorage,
+        cold: Arc<dyn ColdBlobStore>,
+        capacity: u64,
+    ) -> Result<Self, FilesystemStorageError> {
+        let pending = hot.manifests.with_file_name("cold-pending");
+        if !pending.exists() {
+            fs::create_dir(&pending).map_err(|err| FilesystemStorageError::FailedToCreateDir {
+                path: pending.clone(),
+                err,
+            })?;
+        }
+
+        let mut local = Vec::new();
+        for volume in hot.volumes.iter() {
+            let scan_err = |err| FilesystemStorageError::FailedToScanBlobs {
+                path: volume.blobs.clone(),
+                err,
+            };
+
+            for entry in fs::read_dir(&volume.blobs).map_err(scan_err)? {
+                let entry = entry.map_err(scan_err)?;
+                let Some(digest) = entry.file_name().to_str().and_then(Digest::from_hex_str) else {
+                    continue;
+                };
+                let metadata = entry.metadata().map_err(scan_err)?;
+                local.push((
+                    metadata.modified().map_err(scan_err)?,
+                    digest,
+                    metadata.len(),
+                ));
+            }
+        }
+
+        // Without access times, modification time is the best guess for last use.
+        local.sort();
+        let found = local
+            .into_iter()
+            .map(|(_, digest, size)| (digest, size))
+            .collect();
+
+        Ok(Self {
+            hot,
+            cold,
+            capacity,
+            pending,
+            lru: Default::default(),
+            found: Arc::new(Mutex::new(found)),
+            reconciled: Default::default(),
+            fetches: Default::default(),
+        })
+    }
+
+    /// Path of the marker of a blob not yet written to cold storage.
+    fn pending_path(&self, digest: Digest) -> PathBuf {
+        self.pending.join(digest.to_string())
+    }
+
+    /// Durably marks a blob as not yet written to cold storage.
+    async fn mark_pending(&self, digest: Digest) -> Result<(), Error> {
+        let marker = tokio::fs::File::create(self.pending_path(digest))
+            .await
+            .map_err(Error::Io)?;
+        marker.sync_all().await.map_err(Error::Io)?;
+        super::sync_dir(&self.pending).await
+    }
+
+    /// Removes the marker of a blob written to cold storage.
+    async fn clear_pending(&self, digest: Digest) -> Result<(), Error> {
+        super::remove_file_if_exists(&self.pending_path(digest)).await?;
+        Ok(())
+    }
+
+    /// Writes a local blob to cold storage, returning whether it succeeded.
+    ///
+    /// Failures are logged, the blob stays marked as pending and thus on local disk.
+    async fn write_through(&self, digest: Digest, size: u64) -> Result<bool, Error> {
+        let Some(reader) = self.hot.get_blob_reader(digest).await? else {
+            return Ok(false);
+        };
+
+        match self.cold.put(digest, size, reader).await {
+            Ok(()) => {
+                self.clear_pending(digest).await?;
+                Ok(true)
+            }
+            Err(err) => {
+                warn!(%digest, %err, "failed to write blob to cold storage, keeping it local");
+                Ok(false)
+            }
+        }
+    }
+
+    /// Makes the blobs found locally on start evictable, once they are known to be in cold
+    /// storage.
+    ///
+    /// Blobs missing from cold storage or still marked as pending are written to it first, those
+    /// failing to be written stay on local disk. Only done once, but retried if listing the cold
+    /// store fails.
+    async fn reconcile(&self) -> Result<(), Error> {
+        self.reconciled
+            .get_or_try_init(|| async {
+                let stored: HashSet<_> = self
+                    .cold
+                    .list()
+                    .await?
+                    .into_iter()
+                    .map(|info| info.digest)
+                    .collect();
+                let found = std::mem::take(&mut *self.found.lock().expect("lock poisoned"));
+
+                for (digest, size) in found {
+                    let pending = tokio::fs::try_exists(self.pending_path(digest))
+                        .await
+                        .map_err(Error::Io)?;
+                    if pending || !stored.contains(&digest) {
+                        // Marked before writing, so a failure cannot lose track of it.
+                        self.mark_pending(digest).await?;
+                        if !self.write_through(digest, size).await? {
+                            continue;
+                        }
+                    }
+                    self.lru.lock().expect("lock poisoned").touch(digest, size);
+                }
+
+                Ok::<_, Error>(())
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Tracks a local blob known to be in cold storage as evictable, evicting others if needed.
+    async fn track(&self, digest: Digest, size: u64) -> Result<(), Error> {
+        // Blobs not checked yet are never evicted, so failing here does not stop eviction.
+        if let Err(err) = self.reconcile().await {
+            warn!(%err, "failed to check local blobs against cold storage");
+        }
+
+        self.lru.lock().expect("lock poisoned").touch(digest, size);
+        self.evict().await
+    }
+
+    /// Evicts local blobs until capacity is no longer exceeded.
+    async fn evict(&self) -> Result<(), Error> {
+        let evicted = self.lru.lock().expect("lock poisoned").evict(self.capacity);
+
+        for digest in evicted {
+            debug!(%digest, "evicting blob from local storage");
+            self.hot.delete_blob(digest).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the fetch of a blob from cold storage, starting it unless already in progress.
+    fn fetch(&self, digest: Digest) -> Result<Fetch, Error> {
+        // Held across starting the fetch, so concurrent readers cannot start a second one.
+        let mut fetches = self.fetches.lock().expect("lock poisoned");
+        if let Some(fetch) = fetches.get(&digest) {
+            debug!(%digest, "joining fetch from cold storage");
+            return Ok(fetch.clone());
+        }
+
+        // Creating the upload file is quick, so it is done while holding the lock.
+        let upload = Uuid::new_v4();
+        let path = self.hot.upload_path(upload);
+        fs::File::create(&path).map_err(Error::Io)?;
+
+        let (progress, receiver) = watch::channel(FetchProgress::Running(0));
+        let fetch = Fetch {
+            path,
+            progress: receiver,
+        };
+        fetches.insert(digest, fetch.clone());
+        drop(fetches);
+
+        // Fetching continues even if the reader that started it goes away.
+        tokio::spawn(self.clone().run_fetch(digest, upload, progress));
+
+        Ok(fetch)
+    }
+
+    /// Copies a blob from cold storage to local disk, verifying its digest.
+    async fn run_fetch(self, digest: Digest, upload: Uuid, progress: watch::Sender<FetchProgress>) {
+        debug!(%digest, "fetching blob from cold storage");
+
+        let copied = async {
+            let mut reader = self
+                .cold
+                .get(digest)
+                .await?
+                .ok_or_else(|| Error::Backend("blob vanished from cold storage".into()))?;
+            let mut writer = self.hot.get_upload_writer(0, upload).await?;
+
+            let mut buf = vec![0; BUFFER_SIZE];
+            let mut written = 0;
+            loop {
+                let read = reader.read(&mut buf).await.map_err(Error::Io)?;
+                if read == 0 {
+                    break;
+                }
+                writer.write_all(&buf[..read]).await.map_err(Error::Io)?;
+                // Readers may only see bytes that actually made it to the file.
+                writer.flush().await.map_err(Error::Io)?;
+                written += read as u64;
+                progress.send_replace(FetchProgress::Running(written));
+            }
+            writer.shutdown().await.map_err(Error::Io)?;
+            drop(writer);
+
+            self.hot.finalize_upload(upload, digest).await?;
+            Ok::<_, Error>(written)
+        }
+        .await;
+
+        self.fetches.lock().expect("lock poisoned").remove(&digest);
+
+        match copied {
+            Ok(size) => {
+                if let Err(err) = self.track(digest, size).await {
+                    warn!(%err, "failed to evict blobs from local storage");
+                }
+                progress.send_replace(FetchProgress::Done(size));
+            }
+            Err(err) => {
+                warn!(%digest, %err, "failed to fetch blob from cold storage");
+                // Do not leave the partial download behind, readers keep their open files.
+                let _ = self.hot.cancel_upload(upload).await;
+                progress.send_replace(FetchProgress::Failed);
+            }
+        }
+    }
+}
+
+/// Reads a blob while it is being fetched, waiting for more bytes whenever caught up.
+fn follow(file: tokio::fs::File, fetch: Fetch) -> impl AsyncRead + Send + Unpin {
+    let stream = futures::stream::try_unfold(
+        (file, fetch.progress, 0u64),
+        |(mut file, mut progress, mut read)| async move {
+            loop {
+                let state = *progress.borrow_and_update();
+                let available = match state {
+                    FetchProgress::Running(written) | FetchProgress::Done(written) => written,
+                    FetchProgress::Failed => {
+                        return Err(io::Error::other("failed to fetch blob from cold storage"))
+                    }
+                };
+
+                if read < available {
+                    let len = (available - read).min(BUFFER_SIZE as u64) as usize;
+                    let mut buf = vec![0; len];
+                    file.read_exact(&mut buf).await?;
+                    read += len as u64;
+                    return Ok(Some((Bytes::from(buf), (file, progress, read))));
+                }
+
+                if matches!(state, FetchProgress::Done(_)) {
+                    return Ok(None);
+                }
+
+                if progress.changed().await.is_err() {
+                    return Err(io::Error::other("fetch from cold storage aborted"));
+                }
+            }
+        },
+    );
+
+    StreamReader::new(Box::pin(stream))
+}
+
+/// Reads a blob streamed straight from cold storage, failing at its end if the contents do not
+/// match its digest.
+struct Verified<R> {
+    /// The blob's contents.
+    inner: R,
+    /// Hash of the contents read so far, `None` once verified.
+    hasher: Option<hashing::Sha256>,
+    /// Digest the contents must match.
+    digest: Digest,
+}
+
+impl<R> AsyncRead for Verified<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let this = &mut *self;
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+
+        let read = &buf.filled()[filled..];
+        if !read.is_empty() {
+            if let Some(hasher) = this.hasher.as_mut() {
+                hasher.update(read);
+            }
+        } else if let Some(hasher) = this.hasher.take() {
+            if Digest::new(hasher.finalize()) != this.digest {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "blob from cold storage does not match its digest",
+                )));
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait]
+impl BlobStore for TieredStorage {
+    async fn get_blob_reader(
+        &self,
+        digest: Digest,
+    ) -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>, Error> {
+        if let Some(reader) = self.hot.get_blob_reader(digest).await? {
+            self.lru
+                .lock()
+                .expect("lock poisoned")
+                .touch_existing(digest);
+            return Ok(Some(reader));
+        }
+
+        // Blobs that would not fit locally anyway are streamed straight from cold storage.
+        match self.cold.info(digest).await? {
+            None => return Ok(None),
+            Some(info) if info.size > self.capacity => {
+                return Ok(self.cold.get(digest).await?.map(|inner| {
+                    Box::new(Verified {
+                        inner,
+                        hasher: Some(Default::default()),
+                        digest,
+                    }) as Box<_>
+                }));
+            }
+            Some(_) => {}
+        }
+
+        let fetch = self.fetch(digest)?;
+        match tokio::fs::File::open(&fetch.path).await {
+            Ok(file) => Ok(Some(Box::new(follow(file, fetch)))),
+            // The fetch completed (or failed) in the meantime.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.hot.get_blob_reader(digest).await,
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+
+    async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+        if let Some(metadata) = self.hot.get_blob_metadata(digest).await? {
+            return Ok(Some(metadata));
+        }
+
+        Ok(self.cold.info(digest).await?.map(BlobMetadata::from))
+    }
+
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error> {
+        let mut blobs: HashMap<_, _> = self
+            .cold
+            .list()
+            .await?
+            .into_iter()
+            .map(|info| (info.digest, BlobMetadata::from(info)))
+            .collect();
+
+        // Blobs pinned locally may be missing from cold storage.
+        for blob in self.hot.list_blobs().await? {
+            blobs.entry(blob.digest()).or_insert(blob);
+        }
+
+        Ok(blobs.into_values().collect())
+    }
+
+    async fn delete_blob(&self, digest: Digest) -> Result<(), Error> {
+        self.lru.lock().expect("lock poisoned").remove(digest);
+        self.hot.delete_blob(digest).await?;
+        self.clear_pending(digest).await?;
+        self.cold.delete(digest).await
+    }
+
+    /// Quarantines the local copy only, the blob is fetched from cold storage again on access.
+    async fn quarantine_blob(&self, digest: Digest) -> Result<(), Error> {
+        self.lru.lock().expect("lock poisoned").remove(digest);
+        self.hot.quarantine_blob(digest).await
+    }
+}
+
+#[async_trait]
+impl UploadSessionStore for TieredStorage {
+    async fn begin_new_upload(&self) -> Result<Uuid, Error> {
+        self.hot.begin_new_upload().await
+    }
+
+    async fn get_upload_writer(
+        &self,
+        start_at: u64,
+        upload: Uuid,
+    ) -> Result<Box<dyn AsyncWrite + Send + Unpin>, Error> {
+        self.hot.get_upload_writer(start_at, upload).await
+    }
+
+    async fn finalize_upload(&self, upload: Uuid, digest: Digest) -> Result<(), Error> {
+        // Marked before storing, so a crash can never leave a local blob that is assumed to be in
+        // cold storage while it is not.
+        self.mark_pending(digest).await?;
+        if let Err(err) = self.hot.finalize_upload(upload, digest).await {
+            if self.hot.get_blob_metadata(digest).await?.is_none() {
+                self.clear_pending(digest).await?;
+            }
+            return Err(err);
+        }
+
+        let Some(metadata) = self.hot.get_blob_metadata(digest).await? else {
+            return Ok(());
+        };
+
+        // The upload itself succeeded, blobs failing to be written through stay pinned locally.
+        if self.write_through(digest, metadata.size()).await? {
+            self.track(digest, metadata.size()).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn list_uploads(&self) -> Result<Vec<UploadMetadata>, Error> {
+        self.hot.list_uploads().await
+    }
+
+    async fn cancel_upload(&self, upload: Uuid) -> Result<(), Error> {
+        self.hot.cancel_upload(upload).await
+    }
+
+    async fn truncate_upload(&self, upload: Uuid, size: u64) -> Result<(), Error> {
+        self.hot.truncate_upload(upload, size).await
+    }
+
+    async fn get_upload_size(&self, upload: Uuid) -> Result<u64, Error> {
+        self.hot.get_upload_size(upload).await
+    }
+}
+
+impl From<ColdBlobInfo> for BlobMetadata {
+    fn from(info: ColdBlobInfo) -> Self {
+        BlobMetadata {
+            digest: info.digest,
+            size: info.size,
+            modified: info.modified,
+        }
+    }
+}
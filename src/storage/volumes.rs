@@ -0,0 +1,180 @@
+//! Blob storage spread across multiple volumes.
+//!
+//! Besides its storage path, [`FilesystemStorage`](super::FilesystemStorage) can keep blobs on
+//! additional volumes, e.g. further disks, see
+//! [`ContainerRegistryBuilder::blob_volume`](crate::ContainerRegistryBuilder::blob_volume). Every
+//! volume holds an `uploads` and a `blobs` directory. New uploads are placed on a volume according
+//! to the [`BlobPlacement`] and stay there once finalized, as moving a file between directories of
+//! the same filesystem is cheap. Lookups check every volume.
+//!
+//! Manifests, tags and all other metadata are always kept in the storage path.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tracing::warn;
+use uuid::Uuid;
+
+use super::{Digest, FilesystemStorageError};
+
+/// Strategy choosing the volume a new blob is stored on.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum BlobPlacement {
+    /// Use every volume in turn.
+    #[default]
+    RoundRobin,
+    /// Use the volume with the most available space.
+    MostFreeSpace,
+}
+
+/// A directory holding uploads and blobs.
+#[derive(Clone, Debug)]
+pub(crate) struct Volume {
+    /// Uploads in progress.
+    pub(crate) uploads: PathBuf,
+    /// Finalized blobs.
+    pub(crate) blobs: PathBuf,
+}
+
+impl Volume {
+    /// Opens the volume at `root`, creating its directories if necessary.
+    pub(crate) fn open(root: &Path) -> Result<Self, FilesystemStorageError> {
+        let root = root.canonicalize().map_err(|err| {
+            FilesystemStorageError::CouldNotCanonicalizeRoot {
+                path: root.to_owned(),
+                err,
+            }
+        })?;
+
+        let volume = Volume {
+            uploads: root.join("uploads"),
+            blobs: root.join("blobs"),
+        };
+        for dir in [&volume.uploads, &volume.blobs] {
+            if !dir.exists() {
+                std::fs::create_dir(dir).map_err(|err| {
+                    FilesystemStorageError::FailedToCreateDir {
+                        path: dir.to_owned(),
+                        err,
+                    }
+                })?;
+            }
+        }
+
+        Ok(volume)
+    }
+
+    /// Returns the path of a blob on this volume.
+    pub(crate) fn blob_path(&self, digest: Digest) -> PathBuf {
+        self.blobs.join(format!("{}", digest))
+    }
+
+    /// Returns the path of an upload on this volume.
+    pub(crate) fn upload_path(&self, upload: Uuid) -> PathBuf {
+        self.uploads.join(format!("{}.partial", upload))
+    }
+
+    /// Returns the directory blobs failing verification are moved to.
+    pub(crate) fn quarantine(&self) -> PathBuf {
+        self.blobs.with_file_name("quarantine")
+    }
+
+    /// Returns the space available on the volume's filesystem.
+    fn available_space(&self) -> io::Result<u64> {
+        fs2::available_space(&self.blobs)
+    }
+}
+
+/// The volumes of a storage, the first being its storage path.
+#[derive(Clone, Debug)]
+pub(crate) struct Volumes {
+    /// All volumes, never empty.
+    all: Vec<Volume>,
+    /// Strategy for placing new uploads.
+    placement: BlobPlacement,
+    /// Volume to use next with [`BlobPlacement::RoundRobin`], shared between clones.
+    next: Arc<AtomicUsize>,
+}
+
+impl Volumes {
+    /// Creates a set of volumes containing only `primary`.
+    pub(crate) fn new(primary: Volume) -> Self {
+        Self {
+            all: vec![primary],
+            placement: BlobPlacement::default(),
+            next: Default::default(),
+        }
+    }
+
+    /// Adds a volume.
+    pub(crate) fn add(&mut self, volume: Volume) {
+        self.all.push(volume);
+    }
+
+    /// Sets the strategy for placing new uploads.
+    pub(crate) fn set_placement(&mut self, placement: BlobPlacement) {
+        self.placement = placement;
+    }
+
+    /// Returns the volume in the storage path.
+    pub(crate) fn primary(&self) -> &Volume {
+        &self.all[0]
+    }
+
+    /// Returns whether blobs are kept in the storage path only.
+    pub(crate) fn is_single(&self) -> bool {
+        self.all.len() == 1
+    }
+
+    /// Iterates over all volumes.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Volume> {
+        self.all.iter()
+    }
+
+    /// Chooses the volume to place a new upload on.
+    pub(crate) fn place(&self) -> &Volume {
+        match self.placement {
+            BlobPlacement::RoundRobin => {
+                &self.all[self.next.fetch_add(1, Ordering::Relaxed) % self.all.len()]
+            }
+            BlobPlacement::MostFreeSpace => {
+                let available = |volume: &&Volume| {
+                    volume.available_space().unwrap_or_else(|err| {
+                        let blobs = volume.blobs.display();
+                        warn!(%blobs, %err, "could not determine free space");
+                        0
+                    })
+                };
+
+                self.all
+                    .iter()
+                    // `max_by_key` returns the last maximum, ties should go to earlier volumes.
+                    .rev()
+                    .max_by_key(available)
+                    .expect("there should always be a volume")
+            }
+        }
+    }
+
+    /// Returns the path of a stored blob, if present on any volume.
+    pub(crate) fn find_blob(&self, digest: Digest) -> Option<PathBuf> {
+        self.all
+            .iter()
+            .map(|volume| volume.blob_path(digest))
+            .find(|path| path.exists())
+    }
+
+    /// Returns the volume holding an upload along with the upload's path.
+    pub(crate) fn find_upload(&self, upload: Uuid) -> Option<(&Volume, PathBuf)> {
+        self.all
+            .iter()
+            .map(|volume| (volume, volume.upload_path(upload)))
+            .find(|(_, path)| path.exists())
+    }
+}
@@ -0,0 +1,250 @@
+//! History of tags.
+//!
+//! With [`ContainerRegistryBuilder::tag_history`](crate::ContainerRegistryBuilder::tag_history)
+//! set, the registry remembers the manifests a tag pointed to before it was moved, whether by a
+//! push, retagging, promotion or release from quarantine. After a bad image has been pushed over
+//! e.g. `latest`, the tag can be rolled back to its previous manifest using
+//! [`ContainerRegistry::rollback_tag`], or through the administrative API:
+//!
+//! * `GET /admin/<repository>/<image>/tags/<tag>/history` lists the previous manifests of a tag,
+//!   most recent first, requiring read access, and
+//! * `POST /admin/<repository>/<image>/tags/<tag>/rollback` points the tag at its previous
+//!   manifest, requiring write access.
+//!
+//! Rolling back removes the manifest rolled back to from the history, so rolling back repeatedly
+//! goes further back in time. Only the configured number of previous manifests is kept per tag.
+//! Manifests only referenced by the history are not protected from
+//! [garbage collection](crate::maintenance::GarbageCollection) or retention; rolling back to a
+//! removed manifest fails.
+//!
+//! Histories are recorded in the `tag-history` directory of the storage. [Purging](crate::purge) a
+//! repository removes the histories of its tags along with it.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::{
+    events::RegistryEvent,
+    storage::{self, Digest, FilesystemStorageError, ImageLocation},
+    ContainerRegistry, ImageDigest, RegistryError,
+};
+
+/// A manifest a tag pointed to before it was moved.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TagHistoryEntry {
+    /// Digest of the manifest.
+    pub digest: ImageDigest,
+    /// Time the tag was moved away from the manifest, in seconds since the Unix epoch.
+    pub replaced: u64,
+}
+
+impl TagHistoryEntry {
+    /// Returns the time the tag was moved away from the manifest.
+    pub fn replaced_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.replaced)
+    }
+}
+
+/// Recorded tag histories, see the [module documentation](self).
+#[derive(Debug)]
+pub(crate) struct TagHistories {
+    /// Directory holding a record per tag.
+    root: PathBuf,
+    /// Number of previous manifests kept per tag.
+    depth: usize,
+    /// Serializes updates of records.
+    updates: Mutex<()>,
+}
+
+impl TagHistories {
+    /// Opens the histories kept in `storage`, creating their directory if necessary.
+    pub(crate) fn open(storage: &Path, depth: usize) -> Result<Self, FilesystemStorageError> {
+        let root = storage.join("tag-history");
+        if !root.exists() {
+            std::fs::create_dir_all(&root).map_err(|err| {
+                FilesystemStorageError::FailedToCreateDir {
+                    path: root.clone(),
+                    err,
+                }
+            })?;
+        }
+
+        Ok(Self {
+            root,
+            depth,
+            updates: Mutex::new(()),
+        })
+    }
+
+    /// Returns the path of the record of a tag.
+    fn record_path(&self, location: &ImageLocation, tag: &str) -> PathBuf {
+        self.root
+            .join(location.repository())
+            .join(location.image())
+            .join(tag)
+    }
+
+    /// Returns the history of a tag, most recent first.
+    async fn get(&self, location: &ImageLocation, tag: &str) -> io::Result<Vec<TagHistoryEntry>> {
+        match tokio::fs::read(self.record_path(location, tag)).await {
+            Ok(raw) => serde_json::from_slice(&raw).map_err(io::Error::other),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Replaces the history of a tag.
+    async fn put(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+        entries: &[TagHistoryEntry],
+    ) -> io::Result<()> {
+        let path = self.record_path(location, tag);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(
+            path,
+            serde_json::to_vec(entries).expect("serialization should not fail"),
+        )
+        .await
+    }
+
+    /// Removes the histories of all tags in `repository`.
+    pub(crate) async fn forget(&self, repository: &str) -> io::Result<()> {
+        let _guard = self.updates.lock().await;
+        match tokio::fs::remove_dir_all(self.root.join(repository)).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ContainerRegistry {
+    /// Returns the manifests `tag` at `location` pointed to before, most recent first.
+    ///
+    /// Empty if tag histories are not enabled, see the [`tag_history`](crate::tag_history)
+    /// module.
+    pub async fn tag_history(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+    ) -> Result<Vec<TagHistoryEntry>, RegistryError> {
+        let Some(ref histories) = self.tag_histories else {
+            return Ok(Vec::new());
+        };
+        histories.get(location, tag).await.map_err(io_error)
+    }
+
+    /// Points `tag` at `location` back at the manifest it pointed to before, returning its
+    /// digest.
+    ///
+    /// Returns [`RegistryError::NotFound`] if the tag has no history. No permissions are checked,
+    /// callers of the library API are trusted. See the [`tag_history`](crate::tag_history)
+    /// module for details.
+    pub async fn rollback_tag(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+    ) -> Result<storage::Digest, RegistryError> {
+        let histories = self.tag_histories.as_ref().ok_or(RegistryError::NotFound)?;
+        self.purges.check_writable(location)?;
+
+        let _guard = histories.updates.lock().await;
+        let mut entries = histories.get(location, tag).await.map_err(io_error)?;
+        if entries.is_empty() {
+            return Err(RegistryError::NotFound);
+        }
+        let previous = entries.remove(0);
+
+        let replaced = self.tagged_index(location, tag).await?;
+        self.storage
+            .put_tag(location, tag, previous.digest.digest)
+            .await?;
+        self.record_replaced_index(replaced, previous.digest.digest)
+            .await?;
+        histories
+            .put(location, tag, &entries)
+            .await
+            .map_err(io_error)?;
+
+        info!(%location, %tag, digest = %previous.digest, "tag rolled back");
+        self.events.publish(RegistryEvent::TagUpdated {
+            location: location.clone(),
+            tag: tag.to_owned(),
+            digest: previous.digest,
+        });
+
+        Ok(previous.digest.digest)
+    }
+
+    /// Returns the manifest `tag` currently points to, if its history is recorded.
+    ///
+    /// Called before moving a tag, see [`Self::record_tag_update`].
+    pub(crate) async fn tag_target(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+    ) -> Result<Option<Digest>, RegistryError> {
+        if self.tag_histories.is_none() {
+            return Ok(None);
+        }
+
+        Ok(self
+            .storage
+            .list_tags(location)
+            .await?
+            .into_iter()
+            .find(|existing| existing.tag == tag)
+            .map(|existing| existing.digest))
+    }
+
+    /// Records that `tag` has been moved from `previous` to `digest`.
+    pub(crate) async fn record_tag_update(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+        previous: Option<Digest>,
+        digest: Digest,
+    ) -> Result<(), RegistryError> {
+        let (Some(histories), Some(previous)) = (self.tag_histories.as_ref(), previous) else {
+            return Ok(());
+        };
+        if previous == digest {
+            return Ok(());
+        }
+
+        let _guard = histories.updates.lock().await;
+        let mut entries = histories.get(location, tag).await.map_err(io_error)?;
+        entries.insert(
+            0,
+            TagHistoryEntry {
+                digest: ImageDigest::new(previous),
+                replaced: self
+                    .clock
+                    .now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            },
+        );
+        entries.truncate(histories.depth);
+        histories
+            .put(location, tag, &entries)
+            .await
+            .map_err(io_error)
+    }
+}
+
+/// Wraps an IO error of the records.
+fn io_error(err: io::Error) -> RegistryError {
+    RegistryError::Storage(storage::Error::Io(err))
+}
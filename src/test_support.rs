@@ -24,17 +24,49 @@
 //! // To launch the app and potentially use `app.call`:
 //! // let app = service.ready().await.expect("could not launch service");
 //! ```
-use std::{net::SocketAddr, sync::Arc, thread};
+//!
+//! ## Crafting images
+//!
+//! Instead of committing binary fixtures, tests can generate minimal, valid OCI images using an
+//! [`ImageBuilder`], optionally combined into an index using an [`IndexBuilder`]. Building is
+//! deterministic, so the same builder always yields the same digests:
+//!
+//! ```
+//! use container_registry::{storage::ImageLocation, test_support::ImageBuilder, ContainerRegistry};
+//!
+//! # tokio::runtime::Runtime::new().unwrap().block_on(async {
+//! let ctx = ContainerRegistry::builder().build_for_testing();
+//! let image = ImageBuilder::new()
+//!     .tar_layer([("etc/motd", b"hello".as_slice())])
+//!     .annotation("org.opencontainers.image.title", "example")
+//!     .build();
+//!
+//! let location = ImageLocation::new("tests".to_owned(), "example".to_owned());
+//! let digest = ctx.push_image(&location, "latest", &image).await;
+//! assert_eq!(digest, image.digest());
+//! # });
+//! ```
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, thread};
 
 use axum::{body::Body, routing::RouterIntoService};
+use serde_json::{json, Value};
 use tokio::runtime::Runtime;
 use tower_http::trace::TraceLayer;
 
 use super::{
     auth::{self, Permissions},
-    ContainerRegistry, ContainerRegistryBuilder,
+    storage::{self, Digest, ImageLocation, ManifestReference, Reference},
+    types::{OCI_IMAGE_INDEX, OCI_IMAGE_MANIFEST},
+    ContainerRegistry, ContainerRegistryBuilder, ImageDigest,
 };
 
+/// Media type of image configurations.
+const OCI_IMAGE_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+/// Media type of uncompressed layers.
+const OCI_TAR_LAYER: &str = "application/vnd.oci.image.layer.v1.tar";
+/// Size of a tar header or data block.
+const TAR_BLOCK: usize = 512;
+
 /// A context of a container registry instantiated for testing.
 pub struct TestingContainerRegistry {
     /// Reference to the registry instance.
@@ -144,14 +176,435 @@ impl TestingContainerRegistry {
     pub fn registry(&self) -> &ContainerRegistry {
         &self.registry
     }
+
+    /// Stores `image` at `location`, tagged as `tag`, returning the digest of its manifest.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the registry refuses the image, e.g. because the repository does not exist.
+    pub async fn push_image(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+        image: &TestImage,
+    ) -> Digest {
+        self.push_image_as(location, Reference::new_tag(tag), image)
+            .await
+    }
+
+    /// Stores `index` along with all of its images at `location`, tagged as `tag`, returning the
+    /// digest of the index.
+    ///
+    /// Images are stored by digest only.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the registry refuses any of the manifests.
+    pub async fn push_index(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+        index: &TestIndex,
+    ) -> Digest {
+        for image in &index.images {
+            self.push_image_as(location, Reference::new_digest(image.digest()), image)
+                .await;
+        }
+        self.put_test_manifest(location, Reference::new_tag(tag), &index.manifest)
+            .await
+    }
+
+    /// Stores the blobs and manifest of `image` as `reference`.
+    async fn push_image_as(
+        &self,
+        location: &ImageLocation,
+        reference: Reference,
+        image: &TestImage,
+    ) -> Digest {
+        for blob in image.blobs() {
+            self.registry
+                .put_blob(location, blob.data())
+                .await
+                .expect("could not store blob of test image");
+        }
+        self.put_test_manifest(location, reference, &image.manifest)
+            .await
+    }
+
+    /// Stores a manifest as `reference`.
+    async fn put_test_manifest(
+        &self,
+        location: &ImageLocation,
+        reference: Reference,
+        manifest: &TestBlob,
+    ) -> Digest {
+        self.registry
+            .put_manifest(
+                &ManifestReference::new(location.clone(), reference),
+                manifest.data(),
+            )
+            .await
+            .expect("could not store manifest of test image")
+    }
+}
+
+/// Content of a crafted image, e.g. a layer or a manifest.
+#[derive(Clone, Debug)]
+pub struct TestBlob {
+    /// Media type of the content.
+    media_type: String,
+    /// The content itself.
+    data: Vec<u8>,
+    /// Digest of the content.
+    digest: Digest,
+}
+
+impl TestBlob {
+    /// Creates a new blob, computing its digest.
+    fn new(media_type: &str, data: Vec<u8>) -> Self {
+        Self {
+            media_type: media_type.to_owned(),
+            digest: Digest::from_contents(&data),
+            data,
+        }
+    }
+
+    /// Creates a new blob holding `value` serialized as JSON.
+    fn json(media_type: &str, value: &Value) -> Self {
+        Self::new(
+            media_type,
+            serde_json::to_vec(value).expect("serialization should not fail"),
+        )
+    }
+
+    /// Returns the media type of the content.
+    pub fn media_type(&self) -> &str {
+        &self.media_type
+    }
+
+    /// Returns the content.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the digest of the content.
+    pub fn digest(&self) -> Digest {
+        self.digest
+    }
+
+    /// Returns the size of the content in bytes.
+    pub fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// Returns a descriptor of the content, as referenced from a manifest.
+    fn descriptor(&self) -> Value {
+        json!({
+            "mediaType": self.media_type,
+            "digest": ImageDigest::new(self.digest).to_string(),
+            "size": self.size(),
+        })
+    }
+}
+
+/// Builder of minimal OCI images for tests, see the [module documentation](self).
+///
+/// Images are built for `linux/amd64` by default.
+#[derive(Clone, Debug)]
+pub struct ImageBuilder {
+    /// Operating system the image is built for.
+    os: String,
+    /// Architecture the image is built for.
+    architecture: String,
+    /// Layers, bottom first.
+    layers: Vec<TestBlob>,
+    /// Annotations of the manifest.
+    annotations: BTreeMap<String, String>,
+}
+
+impl Default for ImageBuilder {
+    fn default() -> Self {
+        Self {
+            os: "linux".to_owned(),
+            architecture: "amd64".to_owned(),
+            layers: Vec::new(),
+            annotations: BTreeMap::new(),
+        }
+    }
+}
+
+impl ImageBuilder {
+    /// Creates a new builder for an image without any layers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the platform the image is built for, e.g. `linux` and `arm64`.
+    pub fn platform<O: Into<String>, A: Into<String>>(mut self, os: O, architecture: A) -> Self {
+        self.os = os.into();
+        self.architecture = architecture.into();
+        self
+    }
+
+    /// Adds an uncompressed layer with the given contents.
+    ///
+    /// The contents are used as-is, use [`Self::tar_layer`] for a layer that is an actual tar
+    /// archive.
+    pub fn layer<B: Into<Vec<u8>>>(mut self, contents: B) -> Self {
+        self.layers
+            .push(TestBlob::new(OCI_TAR_LAYER, contents.into()));
+        self
+    }
+
+    /// Adds an uncompressed layer holding the given regular files, e.g. `("etc/motd", b"hello")`.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if a path is longer than 100 bytes.
+    pub fn tar_layer<'a, I>(self, files: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a str, &'a [u8])>,
+    {
+        self.layer(tar_archive(files))
+    }
+
+    /// Adds an annotation to the manifest.
+    pub fn annotation<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.annotations.insert(key.into(), value.into());
+        self
+    }
+
+    /// Builds the image.
+    pub fn build(&self) -> TestImage {
+        // Layers are uncompressed, so their digests are their diff IDs as well.
+        let diff_ids: Vec<String> = self
+            .layers
+            .iter()
+            .map(|layer| ImageDigest::new(layer.digest).to_string())
+            .collect();
+        let config = TestBlob::json(
+            OCI_IMAGE_CONFIG,
+            &json!({
+                "architecture": self.architecture,
+                "os": self.os,
+                "config": {},
+                "rootfs": { "type": "layers", "diff_ids": diff_ids },
+            }),
+        );
+
+        let mut manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_IMAGE_MANIFEST,
+            "config": config.descriptor(),
+            "layers": self.layers.iter().map(TestBlob::descriptor).collect::<Vec<_>>(),
+        });
+        if !self.annotations.is_empty() {
+            manifest["annotations"] = json!(self.annotations);
+        }
+
+        TestImage {
+            os: self.os.clone(),
+            architecture: self.architecture.clone(),
+            manifest: TestBlob::json(OCI_IMAGE_MANIFEST, &manifest),
+            config,
+            layers: self.layers.clone(),
+        }
+    }
+}
+
+/// An image built by an [`ImageBuilder`].
+#[derive(Clone, Debug)]
+pub struct TestImage {
+    /// Operating system the image is built for.
+    os: String,
+    /// Architecture the image is built for.
+    architecture: String,
+    /// The image manifest.
+    manifest: TestBlob,
+    /// The image configuration.
+    config: TestBlob,
+    /// Layers, bottom first.
+    layers: Vec<TestBlob>,
+}
+
+impl TestImage {
+    /// Returns the digest of the image's manifest.
+    pub fn digest(&self) -> Digest {
+        self.manifest.digest
+    }
+
+    /// Returns the image's manifest.
+    pub fn manifest(&self) -> &TestBlob {
+        &self.manifest
+    }
+
+    /// Returns the image's configuration.
+    pub fn config(&self) -> &TestBlob {
+        &self.config
+    }
+
+    /// Returns the image's layers, bottom first.
+    pub fn layers(&self) -> &[TestBlob] {
+        &self.layers
+    }
+
+    /// Returns all blobs referenced by the manifest, i.e. the configuration and the layers.
+    pub fn blobs(&self) -> impl Iterator<Item = &TestBlob> {
+        std::iter::once(&self.config).chain(&self.layers)
+    }
+}
+
+/// Builder of OCI image indices for tests, see the [module documentation](self).
+#[derive(Clone, Debug, Default)]
+pub struct IndexBuilder {
+    /// Images of the index.
+    images: Vec<TestImage>,
+    /// Annotations of the index.
+    annotations: BTreeMap<String, String>,
+}
+
+impl IndexBuilder {
+    /// Creates a new builder for an empty index.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an image, listed along with its platform.
+    pub fn image(mut self, image: TestImage) -> Self {
+        self.images.push(image);
+        self
+    }
+
+    /// Adds an annotation to the index.
+    pub fn annotation<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.annotations.insert(key.into(), value.into());
+        self
+    }
+
+    /// Builds the index.
+    pub fn build(&self) -> TestIndex {
+        let manifests: Vec<Value> = self
+            .images
+            .iter()
+            .map(|image| {
+                let mut descriptor = image.manifest.descriptor();
+                descriptor["platform"] = json!({
+                    "architecture": image.architecture,
+                    "os": image.os,
+                });
+                descriptor
+            })
+            .collect();
+
+        let mut index = json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_IMAGE_INDEX,
+            "manifests": manifests,
+        });
+        if !self.annotations.is_empty() {
+            index["annotations"] = json!(self.annotations);
+        }
+
+        TestIndex {
+            manifest: TestBlob::json(OCI_IMAGE_INDEX, &index),
+            images: self.images.clone(),
+        }
+    }
+}
+
+/// An index built by an [`IndexBuilder`].
+#[derive(Clone, Debug)]
+pub struct TestIndex {
+    /// The index manifest.
+    manifest: TestBlob,
+    /// Images of the index.
+    images: Vec<TestImage>,
+}
+
+impl TestIndex {
+    /// Returns the digest of the index.
+    pub fn digest(&self) -> Digest {
+        self.manifest.digest
+    }
+
+    /// Returns the index manifest.
+    pub fn manifest(&self) -> &TestBlob {
+        &self.manifest
+    }
+
+    /// Returns the images of the index.
+    pub fn images(&self) -> &[TestImage] {
+        &self.images
+    }
+}
+
+/// Creates an uncompressed tar archive holding regular files.
+///
+/// All metadata besides paths and sizes is fixed, so archives are reproducible.
+fn tar_archive<'a, I>(files: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = (&'a str, &'a [u8])>,
+{
+    let mut archive = Vec::new();
+
+    for (path, contents) in files {
+        assert!(path.len() <= 100, "path too long for a tar header: {path}");
+
+        let mut header = [0u8; TAR_BLOCK];
+        header[..path.len()].copy_from_slice(path.as_bytes());
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[108..116].copy_from_slice(b"0000000\0");
+        header[116..124].copy_from_slice(b"0000000\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", contents.len()).as_bytes());
+        header[136..148].copy_from_slice(b"00000000000\0");
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+
+        // The checksum is computed with its own field set to spaces.
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+        header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(contents);
+        archive.resize(archive.len().next_multiple_of(TAR_BLOCK), 0);
+    }
+
+    // An archive ends with two empty blocks.
+    archive.resize(archive.len() + 2 * TAR_BLOCK, 0);
+    archive
 }
 
 impl ContainerRegistryBuilder {
+    /// Injects faults into the storage of the new registry.
+    ///
+    /// See [`storage::test_util`] for details.
+    pub fn storage_faults(mut self, faults: storage::test_util::Faults) -> Self {
+        self.storage_faults = Some(faults);
+        self
+    }
+
+    /// Keeps blobs, uploads, manifests and tags of the new registry in memory.
+    ///
+    /// Useful for benchmarks, whose results should not depend on the filesystem they run on.
+    /// Options only affecting filesystem storage, e.g. [`Self::blob_volume`] or
+    /// [`Self::write_ahead_log`], have no effect. Other state, e.g. quarantined manifests, is
+    /// still kept in the storage directory.
+    ///
+    /// # Panics
+    ///
+    /// Building the registry will panic if combined with cold, redirect or chunked blob storage.
+    pub fn memory_storage(mut self) -> Self {
+        self.memory_storage = true;
+        self
+    }
+
     /// Constructs a new registry for testing purposes.
     ///
     /// Similar to [`Self::build`], except
     ///
-    /// * If no auth provider has been set, a default one granting **full write access** to any
+    /// * If no auth provider has been set, a default one granting **full access** to any
     ///   user, including anonymous ones.
     /// * If no storage path has been set, creates a temporary directory for the registry, which
     ///   will be cleaned up if `TestingContainerRegistry` is dropped.
@@ -172,8 +625,8 @@ impl ContainerRegistryBuilder {
 
         if self.auth_provider.is_none() {
             self = self.auth_provider(Arc::new(auth::Anonymous::new(
-                Permissions::ReadWrite,
-                Permissions::ReadWrite,
+                Permissions::ReadWriteDelete,
+                Permissions::ReadWriteDelete,
             )));
         }
 
@@ -0,0 +1,218 @@
+//! Egress bandwidth throttling.
+//!
+//! Blob downloads can be assigned to [`BandwidthClass`]es by the user downloading or the
+//! repository downloaded from. All downloads of a class share a single token bucket, limiting
+//! their combined bandwidth, so that e.g. bulk mirror jobs can be deprioritized below production
+//! nodes pulling images:
+//!
+//! ```
+//! use container_registry::{throttle::BandwidthClass, ContainerRegistry};
+//!
+//! let builder = ContainerRegistry::builder().bandwidth_class(
+//!     BandwidthClass::new("mirrors", 10 * 1024 * 1024)
+//!         .user("mirror-bot")
+//!         .repository("archive"),
+//! );
+//! ```
+//!
+//! Every download is throttled by the first class it matches, downloads matching none are not
+//! throttled at all. Manifests are small and never throttled.
+
+use std::{
+    collections::HashSet,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::body::Bytes;
+use futures::{Stream, StreamExt};
+use tokio::time::Instant;
+
+/// A token bucket, refilling at a fixed rate up to a maximum burst size.
+///
+/// Reservations may exceed the available tokens, in which case the bucket goes into debt and the
+/// caller is asked to wait until it has been paid off. This keeps the long-term rate exact even if
+/// chunks are larger than the burst size.
+#[derive(Debug)]
+struct TokenBucket {
+    /// Tokens (bytes) added per second.
+    rate: f64,
+    /// Maximum number of tokens.
+    burst: f64,
+    /// Available tokens, negative if in debt, and the time they were last updated.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// Creates a new, full token bucket.
+    fn new(rate: u64, burst: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst as f64,
+            state: Mutex::new((burst as f64, Instant::now())),
+        }
+    }
+
+    /// Takes `amount` tokens, returning how long to wait before using them.
+    fn reserve(&self, amount: u64) -> Duration {
+        let mut state = self.state.lock().expect("lock poisoned");
+        let (ref mut tokens, ref mut updated) = *state;
+
+        let now = Instant::now();
+        *tokens =
+            (*tokens + now.duration_since(*updated).as_secs_f64() * self.rate).min(self.burst);
+        *updated = now;
+        *tokens -= amount as f64;
+
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+}
+
+/// A class of downloads sharing a bandwidth limit.
+#[derive(Debug)]
+pub struct BandwidthClass {
+    /// Name of the class, used in logs.
+    name: String,
+    /// Users whose downloads belong to the class.
+    users: HashSet<String>,
+    /// Repositories whose downloads belong to the class.
+    repositories: HashSet<String>,
+    /// Bucket shared by all downloads of the class.
+    bucket: TokenBucket,
+}
+
+impl BandwidthClass {
+    /// Creates a new class limiting the combined bandwidth of its downloads to `bytes_per_second`.
+    ///
+    /// Up to one second worth of data can be sent in a burst, see [`Self::burst`]. The class has
+    /// no members until users or repositories are added.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_second` is zero.
+    pub fn new<S: Into<String>>(name: S, bytes_per_second: u64) -> Self {
+        assert!(bytes_per_second > 0, "bandwidth limit must not be zero");
+
+        Self {
+            name: name.into(),
+            users: HashSet::new(),
+            repositories: HashSet::new(),
+            bucket: TokenBucket::new(bytes_per_second, bytes_per_second),
+        }
+    }
+
+    /// Sets the number of bytes that can be sent at once after the class has been idle.
+    pub fn burst(mut self, bytes: u64) -> Self {
+        self.bucket = TokenBucket::new(self.bucket.rate as u64, bytes);
+        self
+    }
+
+    /// Adds all downloads by the given user to the class.
+    pub fn user<S: Into<String>>(mut self, username: S) -> Self {
+        self.users.insert(username.into());
+        self
+    }
+
+    /// Adds all downloads from the given repository to the class.
+    pub fn repository<S: Into<String>>(mut self, repository: S) -> Self {
+        self.repositories.insert(repository.into());
+        self
+    }
+
+    /// Returns the name of the class.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Checks whether a download belongs to the class.
+    fn matches(&self, username: Option<&str>, repository: &str) -> bool {
+        username.is_some_and(|username| self.users.contains(username))
+            || self.repositories.contains(repository)
+    }
+}
+
+/// The bandwidth classes of a registry.
+#[derive(Debug, Default)]
+pub(crate) struct BandwidthLimits {
+    /// Classes in the order they were added.
+    classes: Vec<Arc<BandwidthClass>>,
+}
+
+impl BandwidthLimits {
+    /// Adds a class, matched after all previously added ones.
+    pub(crate) fn add(&mut self, class: BandwidthClass) {
+        self.classes.push(Arc::new(class));
+    }
+
+    /// Returns the class a download belongs to, if any.
+    pub(crate) fn classify(
+        &self,
+        username: Option<&str>,
+        repository: &str,
+    ) -> Option<Arc<BandwidthClass>> {
+        self.classes
+            .iter()
+            .find(|class| class.matches(username, repository))
+            .cloned()
+    }
+}
+
+/// Throttles a stream of chunks to the bandwidth of `class`.
+pub(crate) fn throttle<S>(
+    stream: S,
+    class: Arc<BandwidthClass>,
+) -> impl Stream<Item = io::Result<Bytes>> + Send
+where
+    S: Stream<Item = io::Result<Bytes>> + Send,
+{
+    stream.then(move |chunk| {
+        let delay = match chunk {
+            Ok(ref bytes) => class.bucket.reserve(bytes.len() as u64),
+            Err(_) => Duration::ZERO,
+        };
+
+        async move {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            chunk
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{BandwidthClass, TokenBucket};
+
+    #[test]
+    fn buckets_go_into_debt() {
+        let bucket = TokenBucket::new(1000, 500);
+
+        // The initial burst is free, everything beyond it has to be waited for.
+        assert_eq!(bucket.reserve(500), Duration::ZERO);
+        let delay = bucket.reserve(1000);
+        assert!(delay > Duration::from_millis(900) && delay <= Duration::from_secs(1));
+        let delay = bucket.reserve(500);
+        assert!(delay > Duration::from_millis(1400) && delay <= Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn classes_match_users_and_repositories() {
+        let class = BandwidthClass::new("mirrors", 1024)
+            .user("mirror-bot")
+            .repository("archive");
+
+        assert!(class.matches(Some("mirror-bot"), "production"));
+        assert!(class.matches(None, "archive"));
+        assert!(class.matches(Some("alice"), "archive"));
+        assert!(!class.matches(Some("alice"), "production"));
+        assert!(!class.matches(None, "production"));
+    }
+}
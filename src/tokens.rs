@@ -0,0 +1,368 @@
+//! Pull tokens for cluster integration.
+//!
+//! A [`TokenIssuer`] set through
+//! [`ContainerRegistryBuilder::token_issuer`](crate::ContainerRegistryBuilder::token_issuer)
+//! issues short-lived, read-only [`PullToken`]s scoped to a set of image locations. Tokens are
+//! used as the password of regular basic authentication, so they can be placed in a docker
+//! `config.json` or a Kubernetes `imagePullSecret` as-is, see [`PullToken::docker_config`] and
+//! [`PullToken::kubernetes_secret`].
+//!
+//! Tokens are accepted as bearer tokens (`Authorization: Bearer <token>`) as well, the username
+//! is then taken from the token itself.
+//!
+//! Tokens are stateless: They are signed using HMAC-SHA256 with the issuer's key and cannot be
+//! revoked individually, changing the key invalidates all of them. They are issued either through
+//! [`ContainerRegistry::issue_pull_token`](crate::ContainerRegistry::issue_pull_token) or the
+//! administrative API:
+//!
+//! ```text
+//! POST /admin/tokens
+//! {"scope": ["foo/bar"], "expires_in": 3600}
+//! ```
+//!
+//! which responds with the token, or with a rendered Kubernetes secret if the `secret` query
+//! parameter is set to the name of the secret. Callers need read access to every location in the
+//! requested scope.
+//!
+//! Blobs are only served to token holders through locations in scope they belong to, as if
+//! [`ContainerRegistryBuilder::scope_blobs`](crate::ContainerRegistryBuilder::scope_blobs) was
+//! enabled, so a token cannot be used to fetch blobs of other repositories by digest.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::async_trait;
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use sec::Secret;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    auth::{AuthProvider, Permissions, Unverified, ValidCredentials},
+    clock::{Clock, SystemClock},
+    hashing::{hmac_sha256, verify_hmac_sha256},
+    storage::ImageLocation,
+    ImageDigest,
+};
+
+/// Prefix of every token, identifying the format.
+const TOKEN_PREFIX: &str = "crpt1";
+
+/// Default lifetime of an issued token.
+const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Default maximum lifetime of an issued token.
+///
+/// Tokens cannot be revoked and outlive changes to the permissions of the user they were issued
+/// to, so they are kept short-lived.
+const DEFAULT_MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Signed contents of a token.
+#[derive(Debug, Deserialize, Serialize)]
+struct Claims {
+    /// Username the token was issued to.
+    sub: String,
+    /// Expiration time, in seconds since the Unix epoch.
+    exp: u64,
+    /// Locations readable with the token, as `repository/image`.
+    scope: Vec<String>,
+}
+
+/// Issues and verifies pull tokens.
+#[derive(Debug)]
+pub struct TokenIssuer {
+    /// Key tokens are signed with.
+    key: Secret<Vec<u8>>,
+    /// Lifetime of tokens not requesting a specific one.
+    default_ttl: Duration,
+    /// Maximum lifetime of tokens.
+    max_ttl: Duration,
+    /// Source of the time tokens are issued and verified at.
+    clock: Arc<dyn Clock>,
+}
+
+impl TokenIssuer {
+    /// Creates a new issuer signing tokens with `key`.
+    ///
+    /// The key should be at least 32 random bytes. Tokens are valid for an hour by default and at
+    /// most a day.
+    pub fn new(key: Secret<Vec<u8>>) -> Self {
+        Self {
+            key,
+            default_ttl: DEFAULT_TTL,
+            max_ttl: DEFAULT_MAX_TTL,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Sets the clock tokens are issued and verified by, see the [`clock`](crate::clock) module.
+    pub(crate) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Sets the lifetime of tokens not requesting a specific one.
+    pub fn default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = ttl;
+        self
+    }
+
+    /// Sets the maximum lifetime of tokens, longer requested lifetimes are capped.
+    pub fn max_ttl(mut self, ttl: Duration) -> Self {
+        self.max_ttl = ttl;
+        self
+    }
+
+    /// Issues a token to `username`, granting read access to all locations in `scope`.
+    pub fn issue(
+        &self,
+        username: &str,
+        scope: &[ImageLocation],
+        ttl: Option<Duration>,
+    ) -> PullToken {
+        let ttl = ttl.unwrap_or(self.default_ttl).min(self.max_ttl);
+        let expires_at = self.clock.now() + ttl;
+
+        let claims = Claims {
+            sub: username.to_owned(),
+            exp: expires_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            scope: scope.iter().map(ToString::to_string).collect(),
+        };
+        let payload = URL_SAFE_NO_PAD
+            .encode(serde_json::to_vec(&claims).expect("serialization should not fail"));
+        let signature = URL_SAFE_NO_PAD.encode(self.sign(&payload));
+
+        PullToken {
+            username: claims.sub,
+            token: Secret::new(format!("{TOKEN_PREFIX}.{payload}.{signature}")),
+            expires_at,
+        }
+    }
+
+    /// Returns the scope of `token`, if it is a valid and unexpired token issued to `username`.
+    fn verify(&self, username: &str, token: &str) -> Option<Vec<ImageLocation>> {
+        let (subject, scope) = self.verify_bearer(token)?;
+        (subject == username).then_some(scope)
+    }
+
+    /// Returns the user `token` was issued to and its scope, if it is valid and unexpired.
+    fn verify_bearer(&self, token: &str) -> Option<(String, Vec<ImageLocation>)> {
+        let (payload, signature) = token
+            .strip_prefix(TOKEN_PREFIX)?
+            .strip_prefix('.')?
+            .split_once('.')?;
+
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        if !verify_hmac_sha256(self.key.reveal(), payload.as_bytes(), &signature) {
+            return None;
+        }
+
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+        let now = self
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if claims.exp <= now {
+            return None;
+        }
+
+        let scope = claims
+            .scope
+            .iter()
+            .map(|location| location.parse().ok())
+            .collect::<Option<_>>()?;
+        Some((claims.sub, scope))
+    }
+
+    /// Signs an encoded payload.
+    fn sign(&self, payload: &str) -> [u8; 32] {
+        hmac_sha256(self.key.reveal(), payload.as_bytes())
+    }
+}
+
+/// An issued pull token.
+#[derive(Debug, Serialize)]
+pub struct PullToken {
+    /// Username to present the token with.
+    pub username: String,
+    /// The token, to be used as password.
+    #[serde(serialize_with = "reveal")]
+    pub token: Secret<String>,
+    /// Time the token expires.
+    #[serde(serialize_with = "unix_seconds")]
+    pub expires_at: SystemTime,
+}
+
+/// Serializes a secret, revealing it.
+fn reveal<S: serde::Serializer>(secret: &Secret<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    secret.reveal().serialize(serializer)
+}
+
+/// Serializes a time as seconds since the Unix epoch.
+fn unix_seconds<S: serde::Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        .serialize(serializer)
+}
+
+impl PullToken {
+    /// Renders a docker `config.json` authenticating against `registry` (e.g. `host:port`).
+    pub fn docker_config(&self, registry: &str) -> serde_json::Value {
+        let auth = STANDARD.encode(format!("{}:{}", self.username, self.token.reveal()));
+
+        json!({
+            "auths": {
+                registry: {
+                    "username": self.username,
+                    "password": self.token.reveal(),
+                    "auth": auth,
+                }
+            }
+        })
+    }
+
+    /// Renders a Kubernetes secret of type `kubernetes.io/dockerconfigjson` named `name`.
+    ///
+    /// The result can be applied using `kubectl apply -f` and referenced in `imagePullSecrets`.
+    pub fn kubernetes_secret(&self, registry: &str, name: &str) -> serde_json::Value {
+        let config = self.docker_config(registry).to_string();
+
+        json!({
+            "apiVersion": "v1",
+            "kind": "Secret",
+            "metadata": {
+                "name": name,
+            },
+            "type": "kubernetes.io/dockerconfigjson",
+            "data": {
+                ".dockerconfigjson": STANDARD.encode(config),
+            }
+        })
+    }
+}
+
+/// Credentials of a client authenticated using a pull token.
+#[derive(Debug)]
+pub(crate) struct TokenCreds {
+    /// Locations readable with the token.
+    scope: Vec<ImageLocation>,
+}
+
+/// Auth provider accepting pull tokens, deferring everything else to `inner`.
+pub(crate) struct TokenAuth<A> {
+    /// Issuer verifying tokens.
+    issuer: Arc<TokenIssuer>,
+    /// Provider for all other credentials.
+    inner: A,
+}
+
+impl<A> TokenAuth<A> {
+    /// Creates a new token auth provider decorating `inner`.
+    pub(crate) fn new(issuer: Arc<TokenIssuer>, inner: A) -> Self {
+        Self { issuer, inner }
+    }
+}
+
+#[async_trait]
+impl<A> AuthProvider for TokenAuth<A>
+where
+    A: AuthProvider,
+{
+    async fn check_credentials(&self, unverified: &Unverified) -> Option<ValidCredentials> {
+        match unverified {
+            Unverified::UsernameAndPassword { username, password } => {
+                if let Some(scope) = self.issuer.verify(username, password.reveal()) {
+                    return Some(ValidCredentials::with_username(
+                        TokenCreds { scope },
+                        username.clone(),
+                    ));
+                }
+            }
+            Unverified::BearerToken { token } => {
+                if let Some((username, scope)) = self.issuer.verify_bearer(token.reveal()) {
+                    return Some(ValidCredentials::with_username(
+                        TokenCreds { scope },
+                        username,
+                    ));
+                }
+            }
+            Unverified::NoCredentials => {}
+        }
+
+        self.inner.check_credentials(unverified).await
+    }
+
+    async fn image_permissions(
+        &self,
+        creds: &ValidCredentials,
+        image: &ImageLocation,
+    ) -> Permissions {
+        match creds.try_extract_ref::<TokenCreds>() {
+            Some(token) if token.scope.contains(image) => Permissions::ReadOnly,
+            Some(_) => Permissions::NoAccess,
+            None => self.inner.image_permissions(creds, image).await,
+        }
+    }
+
+    async fn blob_permissions(&self, creds: &ValidCredentials, blob: &ImageDigest) -> Permissions {
+        match creds.try_extract_ref::<TokenCreds>() {
+            // Blobs are always scoped for tokens, the registry only serves them through locations
+            // in scope they are linked to.
+            Some(_) => Permissions::ReadOnly,
+            None => self.inner.blob_permissions(creds, blob).await,
+        }
+    }
+
+    async fn admin_permissions(&self, creds: &ValidCredentials) -> Permissions {
+        match creds.try_extract_ref::<TokenCreds>() {
+            Some(_) => Permissions::NoAccess,
+            None => self.inner.admin_permissions(creds).await,
+        }
+    }
+
+    fn verifies_external_tokens(&self) -> bool {
+        self.inner.verifies_external_tokens()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sec::Secret;
+
+    use super::TokenIssuer;
+    use crate::storage::ImageLocation;
+
+    #[test]
+    fn tokens_are_verified() {
+        let issuer = TokenIssuer::new(Secret::new(b"0123456789abcdef".to_vec()));
+        let scope = vec![ImageLocation::new("foo".to_owned(), "bar".to_owned())];
+
+        let token = issuer.issue("alice", &scope, None);
+        let raw = token.token.reveal();
+        assert_eq!(issuer.verify("alice", raw).as_ref(), Some(&scope));
+
+        // Tokens are bound to their user and key and cannot be altered.
+        assert_eq!(issuer.verify("bob", raw), None);
+        let other = TokenIssuer::new(Secret::new(b"fedcba9876543210".to_vec()));
+        assert_eq!(other.verify("alice", raw), None);
+        let mut tampered = raw.clone();
+        tampered.insert(10, 'x');
+        assert_eq!(issuer.verify("alice", &tampered), None);
+
+        let expired = issuer.issue("alice", &scope, Some(Duration::ZERO));
+        assert_eq!(issuer.verify("alice", expired.token.reveal()), None);
+    }
+}
@@ -1,27 +1,554 @@
-use std::{collections::HashMap, fmt::Display};
+//! Identifiers of images and manifests.
+//!
+//! These types name the things stored in a registry and are used throughout its public API, e.g.
+//! in [hooks](crate::hooks) and [events](crate::events):
+//!
+//! * [`ImageDigest`]: A content digest, e.g. `sha256:2cf2...`.
+//! * [`ImageLocation`]: A repository and image pair, e.g. `bitnami/nginx`.
+//! * [`Reference`]: A tag or digest, e.g. `latest`.
+//! * [`ManifestReference`]: A location and reference, e.g. `bitnami/nginx:latest` or
+//!   `bitnami/nginx@sha256:2cf2...`.
+//!
+//! All of them are cheap to clone, compare by value, can be used as map keys and serialize to
+//! their string representation (except for [`ImageLocation`] and [`ManifestReference`], which
+//! serialize as objects with `repository`, `image` and `reference` fields). Their [`Display`] and
+//! [`FromStr`] implementations round-trip:
+//!
+//! ```
+//! use container_registry::types::{ManifestReference, Reference};
+//!
+//! let reference: ManifestReference = "bitnami/nginx:latest".parse().unwrap();
+//! assert_eq!(reference.location().repository(), "bitnami");
+//! assert_eq!(reference.reference(), &Reference::new_tag("latest"));
+//! assert_eq!(reference.to_string(), "bitnami/nginx:latest");
+//! ```
+//!
+//! Parsed manifests are passed to [hooks](crate::hooks) as a [`Manifest`], which is either an
+//! [`ImageManifest`] or an [`ImageIndex`]; both give read-only access to the manifest's contents.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    str::FromStr,
+};
 
 use axum::{
     body::Body,
     http::header::CONTENT_TYPE,
     response::{IntoResponse, Response},
 };
-use serde::{Deserialize, Serialize};
+use hex::FromHex;
+use serde::{Deserialize, Deserializer, Serialize};
+use thiserror::Error;
 
-#[derive(Debug, Deserialize, Serialize)]
+use crate::storage::{self, is_valid_tag, Digest};
+
+/// An image digest, e.g. `sha256:2cf2...`.
+///
+/// Currently only SHA256 digests are supported.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ImageDigest {
+    /// The actual image digest.
+    pub(crate) digest: storage::Digest,
+}
+
+impl Serialize for ImageDigest {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let full = format!("sha256:{}", self.digest);
+        full.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ImageDigest {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // Note: For some reason, `&str` here causes parsing inside query parameters to fail.
+        let raw = <String>::deserialize(deserializer)?;
+        raw.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl ImageDigest {
+    /// Creates a new image digest from an existing digest.
+    #[inline(always)]
+    pub const fn new(digest: storage::Digest) -> Self {
+        Self { digest }
+    }
+
+    /// Returns the actual digest.
+    pub fn digest(&self) -> storage::Digest {
+        self.digest
+    }
+}
+
+impl From<storage::Digest> for ImageDigest {
+    fn from(digest: storage::Digest) -> Self {
+        Self::new(digest)
+    }
+}
+
+impl From<ImageDigest> for storage::Digest {
+    fn from(digest: ImageDigest) -> Self {
+        digest.digest
+    }
+}
+
+/// Error parsing a specific image digest.
+#[derive(Debug, Error)]
+pub enum ImageDigestParseError {
+    /// The given digest was of the wrong length.
+    #[error("wrong length")]
+    WrongLength,
+    /// The given digest had an invalid or unsupported prefix.
+    #[error("wrong prefix")]
+    WrongPrefix,
+    /// The hex encoding was not valid.
+    #[error("hex decoding error")]
+    HexDecodeError,
+}
+
+impl FromStr for ImageDigest {
+    type Err = ImageDigestParseError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        const SHA256_LEN: usize = 32;
+        const PREFIX_LEN: usize = 7;
+        const DIGEST_HEX_LEN: usize = SHA256_LEN * 2;
+
+        if raw.len() != PREFIX_LEN + DIGEST_HEX_LEN {
+            return Err(ImageDigestParseError::WrongLength);
+        }
+
+        let hex_encoded = raw
+            .strip_prefix("sha256:")
+            .ok_or(ImageDigestParseError::WrongPrefix)?;
+
+        let digest = <[u8; SHA256_LEN]>::from_hex(hex_encoded)
+            .map_err(|_| ImageDigestParseError::HexDecodeError)?;
+
+        Ok(Self {
+            digest: storage::Digest::new(digest),
+        })
+    }
+}
+
+impl Display for ImageDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sha256:{}", self.digest)
+    }
+}
+
+/// Location of a given image.
+///
+/// In an open container registry, images are stored in what `container-registry` calls
+/// "repository" and "image" pairs. For example, the container image specified as
+/// `bitnami/nginx:latest` would have a repository of `bitnami`, image of `nginx` and tag (which
+/// is not part of [`ImageLocation`]) of `latest`.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct ImageLocation {
+    /// The repository part of the image location.
+    repository: String,
+    /// The image part of the image location.
+    image: String,
+}
+
+impl ImageLocation {
+    /// Creates a new image location.
+    pub fn new(repository: String, image: String) -> Self {
+        Self { repository, image }
+    }
+
+    /// Returns the repository portion of the given image location.
+    #[inline(always)]
+    pub fn repository(&self) -> &str {
+        self.repository.as_ref()
+    }
+
+    /// Returns the image portion of the given image location.
+    #[inline(always)]
+    pub fn image(&self) -> &str {
+        self.image.as_ref()
+    }
+}
+
+impl Display for ImageLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.repository, self.image)
+    }
+}
+
+/// Error parsing an [`ImageLocation`].
+#[derive(Debug, Error)]
+#[error("expected image location of the form `repository/image`")]
+pub struct ImageLocationParseError;
+
+impl FromStr for ImageLocation {
+    type Err = ImageLocationParseError;
+
+    /// Parses a location of the form `repository/image`, as produced by its `Display` impl.
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        match raw.split_once('/') {
+            Some((repository, image))
+                if !repository.is_empty() && !image.is_empty() && !image.contains('/') =>
+            {
+                Ok(ImageLocation::new(repository.to_owned(), image.to_owned()))
+            }
+            _ => Err(ImageLocationParseError),
+        }
+    }
+}
+
+/// Reference to a specific version of an image.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Reference {
+    /// Image reference by given tag (e.g. `latest`).
+    Tag(String),
+    /// Image referenced by given specific hash.
+    Digest(Digest),
+}
+
+impl Reference {
+    /// Creates a new by-tag reference.
+    #[inline(always)]
+    pub fn new_tag<S: ToString>(s: S) -> Self {
+        Reference::Tag(s.to_string())
+    }
+
+    /// Creates a new by-hash reference.
+    #[inline(always)]
+    pub fn new_digest(d: Digest) -> Self {
+        Reference::Digest(d)
+    }
+
+    /// Returns reference as naked tag, if it is a tag.
+    pub fn as_tag(&self) -> Option<&str> {
+        match self {
+            Reference::Tag(tag) => Some(tag),
+            Reference::Digest(_) => None,
+        }
+    }
+
+    /// Returns the referenced digest, if it is a by-hash reference.
+    pub fn as_digest(&self) -> Option<Digest> {
+        match self {
+            Reference::Tag(_) => None,
+            Reference::Digest(digest) => Some(*digest),
+        }
+    }
+}
+
+impl From<Digest> for Reference {
+    fn from(digest: Digest) -> Self {
+        Reference::Digest(digest)
+    }
+}
+
+impl From<ImageDigest> for Reference {
+    fn from(digest: ImageDigest) -> Self {
+        Reference::Digest(digest.digest)
+    }
+}
+
+impl<'de> Deserialize<'de> for Reference {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // Owned, as borrowing fails when deserializing a flattened `ManifestReference`.
+        let raw = <String>::deserialize(deserializer)?;
+
+        match ImageDigest::from_str(&raw) {
+            Ok(digest) => Ok(Self::Digest(digest.digest)),
+            Err(_) => Ok(Self::Tag(raw)),
+        }
+    }
+}
+
+impl Serialize for Reference {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            Reference::Tag(tag) => tag.serialize(serializer),
+            Reference::Digest(digest) => ImageDigest::new(*digest).serialize(serializer),
+        }
+    }
+}
+
+impl Display for Reference {
+    /// Formats tags as-is and digests with their algorithm, e.g. `sha256:2cf2...`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reference::Tag(tag) => Display::fmt(tag, f),
+            Reference::Digest(digest) => Display::fmt(&ImageDigest::new(*digest), f),
+        }
+    }
+}
+
+/// Error parsing a [`Reference`] or [`ManifestReference`].
+#[derive(Debug, Error)]
+pub enum ReferenceParseError {
+    /// The location part is invalid.
+    #[error(transparent)]
+    InvalidLocation(#[from] ImageLocationParseError),
+    /// The reference part is neither a valid tag nor a digest.
+    #[error("invalid tag or digest")]
+    InvalidReference,
+}
+
+impl FromStr for Reference {
+    type Err = ReferenceParseError;
+
+    /// Parses a digest of the form `sha256:...` or a tag.
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        if let Ok(digest) = raw.parse::<ImageDigest>() {
+            return Ok(digest.into());
+        }
+
+        if is_valid_tag(raw) {
+            Ok(Reference::new_tag(raw))
+        } else {
+            Err(ReferenceParseError::InvalidReference)
+        }
+    }
+}
+
+/// Refers to a specific manifest.
+///
+/// Combines an [`ImageLocation`] with a [`Reference`], e.g. `bitnami/nginx:latest`, which has an
+/// [`ImageLocation`] portion of `bitnami/nginx` and a [`Reference::Tag`] `latest`.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct ManifestReference {
+    #[serde(flatten)]
+    location: ImageLocation,
+    reference: Reference,
+}
+
+impl ManifestReference {
+    /// Creates a new manifest reference.
+    pub fn new(location: ImageLocation, reference: Reference) -> Self {
+        Self {
+            location,
+            reference,
+        }
+    }
+
+    /// Returns the location portion of the image location.
+    pub fn location(&self) -> &ImageLocation {
+        &self.location
+    }
+
+    /// Returns the reference portion of the image location.
+    pub fn reference(&self) -> &Reference {
+        &self.reference
+    }
+}
+
+impl Display for ManifestReference {
+    /// Formats the reference as `repository/image:tag` or `repository/image@sha256:...`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reference {
+            Reference::Tag(ref tag) => write!(f, "{}:{}", self.location, tag),
+            Reference::Digest(_) => write!(f, "{}@{}", self.location, self.reference),
+        }
+    }
+}
+
+impl FromStr for ManifestReference {
+    type Err = ReferenceParseError;
+
+    /// Parses a reference of the form `repository/image:tag` or `repository/image@sha256:...`.
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let (location, reference) = match raw.split_once('@') {
+            Some((location, digest)) => (
+                location,
+                digest
+                    .parse::<ImageDigest>()
+                    .map_err(|_| ReferenceParseError::InvalidReference)?
+                    .into(),
+            ),
+            None => {
+                let (location, tag) = raw
+                    .rsplit_once(':')
+                    .ok_or(ReferenceParseError::InvalidReference)?;
+                if !is_valid_tag(tag) {
+                    return Err(ReferenceParseError::InvalidReference);
+                }
+                (location, Reference::new_tag(tag))
+            }
+        };
+
+        Ok(Self::new(location.parse()?, reference))
+    }
+}
+
+/// Digest algorithms supported for content.
+pub(crate) const DIGEST_ALGORITHMS: &[&str] = &["sha256"];
+
+/// Media type of an OCI image index.
+pub(crate) const OCI_IMAGE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+/// Media type of an OCI image manifest.
+pub(crate) const OCI_IMAGE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// Media type of Docker image manifests (schema 2).
+pub(crate) const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+/// Media type of Docker manifest lists.
+pub(crate) const DOCKER_MANIFEST_LIST: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+
+/// Checks whether `media_type` is one of the manifest media types the registry understands.
+pub(crate) fn is_manifest_media_type(media_type: &str) -> bool {
+    [
+        OCI_IMAGE_MANIFEST,
+        OCI_IMAGE_INDEX,
+        DOCKER_MANIFEST,
+        DOCKER_MANIFEST_LIST,
+    ]
+    .contains(&media_type)
+}
+
+/// Media type of the empty descriptor, used as config of artifacts.
+pub(crate) const OCI_EMPTY: &str = "application/vnd.oci.empty.v1+json";
+/// Contents of the empty descriptor.
+pub(crate) const EMPTY_CONFIG: &[u8] = b"{}";
+
+/// A descriptor of content referenced by a manifest, e.g. a layer or another manifest.
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct ContentDescriptor {
+pub struct ContentDescriptor {
     media_type: String,
     digest: String, // TODO: Use digest type
     size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
     urls: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     annotations: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     artifact_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    platform: Option<Platform>,
+}
+
+impl ContentDescriptor {
+    /// Creates a new descriptor without any optional fields set.
+    pub(crate) fn new(media_type: String, digest: Digest, size: u64) -> Self {
+        Self {
+            media_type,
+            digest: ImageDigest::new(digest).to_string(),
+            size,
+            urls: None,
+            annotations: None,
+            data: None,
+            artifact_type: None,
+            platform: None,
+        }
+    }
+
+    /// Creates a new descriptor for a manifest.
+    pub(crate) fn for_manifest(manifest: &Manifest, digest: Digest, size: u64) -> Self {
+        Self {
+            media_type: manifest.media_type().to_owned(),
+            digest: ImageDigest::new(digest).to_string(),
+            size,
+            urls: None,
+            annotations: manifest.annotations().cloned(),
+            data: None,
+            artifact_type: manifest.artifact_type().map(ToOwned::to_owned),
+            platform: None,
+        }
+    }
+
+    /// Returns the media type of the referenced content.
+    pub fn media_type(&self) -> &str {
+        self.media_type.as_ref()
+    }
+
+    /// Returns the digest of the referenced content, as given in the manifest.
+    pub fn digest(&self) -> &str {
+        self.digest.as_ref()
+    }
+
+    /// Returns the size of the referenced content in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the annotations of the descriptor.
+    pub fn annotations(&self) -> Option<&HashMap<String, String>> {
+        self.annotations.as_ref()
+    }
+
+    /// Returns the artifact type of the referenced manifest, if any.
+    pub fn artifact_type(&self) -> Option<&str> {
+        self.artifact_type.as_deref()
+    }
+
+    /// Returns the platform of the referenced manifest, given by indices.
+    pub fn platform(&self) -> Option<&Platform> {
+        self.platform.as_ref()
+    }
+
+    /// Parses the digest of the descriptor.
+    pub fn parsed_digest(&self) -> Result<Digest, ImageDigestParseError> {
+        self.digest
+            .parse::<ImageDigest>()
+            .map(|digest| digest.digest())
+    }
+}
+
+/// The platform an image referenced by an index is built for.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Platform {
+    architecture: String,
+    os: String,
+    #[serde(rename = "os.version", skip_serializing_if = "Option::is_none")]
+    os_version: Option<String>,
+    #[serde(rename = "os.features", skip_serializing_if = "Option::is_none")]
+    os_features: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    variant: Option<String>,
 }
 
+impl Platform {
+    /// Returns the CPU architecture, e.g. `amd64`.
+    pub fn architecture(&self) -> &str {
+        &self.architecture
+    }
+
+    /// Returns the operating system, e.g. `linux`.
+    pub fn os(&self) -> &str {
+        &self.os
+    }
+
+    /// Returns the operating system version, if given.
+    pub fn os_version(&self) -> Option<&str> {
+        self.os_version.as_deref()
+    }
+
+    /// Returns the CPU variant, e.g. `v8`, if given.
+    pub fn variant(&self) -> Option<&str> {
+        self.variant.as_deref()
+    }
+
+    pub(crate) fn matches(&self, os: &str, architecture: &str) -> bool {
+        self.os == os && self.architecture == architecture
+    }
+}
+
+/// An image manifest, describing a single image or artifact.
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct ImageManifest {
+pub struct ImageManifest {
     schema_version: u32,
 
     media_type: String,
@@ -34,9 +561,124 @@ pub(crate) struct ImageManifest {
 }
 
 impl ImageManifest {
-    pub(crate) fn media_type(&self) -> &str {
+    /// Returns the media type of the manifest.
+    pub fn media_type(&self) -> &str {
         self.media_type.as_ref()
     }
+
+    /// Returns the descriptor of the image configuration.
+    pub fn config(&self) -> &ContentDescriptor {
+        &self.config
+    }
+
+    /// Returns the descriptors of the image layers, in order.
+    pub fn layers(&self) -> &[ContentDescriptor] {
+        &self.layers
+    }
+}
+
+/// An image index, pointing to other manifests, e.g. one image per platform.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageIndex {
+    schema_version: u32,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    media_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    annotations: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artifact_type: Option<String>,
+
+    manifests: Vec<ContentDescriptor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subject: Option<ContentDescriptor>,
+}
+
+impl ImageIndex {
+    /// Creates a new index listing the given manifests.
+    pub(crate) fn new(manifests: Vec<ContentDescriptor>) -> Self {
+        Self {
+            schema_version: 2,
+            media_type: Some(OCI_IMAGE_INDEX.to_owned()),
+            annotations: None,
+            artifact_type: None,
+            manifests,
+            subject: None,
+        }
+    }
+
+    /// Returns the descriptors of the manifests in the index.
+    pub fn manifests(&self) -> &[ContentDescriptor] {
+        &self.manifests
+    }
+}
+
+/// A manifest, which is either an image manifest or an index pointing to other manifests.
+#[derive(Debug)]
+pub enum Manifest {
+    /// An image manifest.
+    Image(Box<ImageManifest>),
+    /// An image index.
+    Index(Box<ImageIndex>),
+}
+
+impl Manifest {
+    /// Parses a manifest, determining its kind from its contents.
+    pub(crate) fn from_slice(raw: &[u8]) -> Result<Self, serde_json::Error> {
+        #[derive(Deserialize)]
+        struct Probe {
+            manifests: Option<serde::de::IgnoredAny>,
+        }
+
+        let probe: Probe = serde_json::from_slice(raw)?;
+
+        if probe.manifests.is_some() {
+            serde_json::from_slice(raw).map(|index| Manifest::Index(Box::new(index)))
+        } else {
+            serde_json::from_slice(raw).map(|image| Manifest::Image(Box::new(image)))
+        }
+    }
+
+    /// Returns the media type of the manifest.
+    ///
+    /// Indices that do not specify a media type are assumed to be OCI image indices.
+    pub fn media_type(&self) -> &str {
+        match self {
+            Manifest::Image(image) => image.media_type(),
+            Manifest::Index(index) => index.media_type.as_deref().unwrap_or(OCI_IMAGE_INDEX),
+        }
+    }
+
+    /// Returns the artifact type of the manifest.
+    ///
+    /// For image manifests without an explicit artifact type, the config media type is used, as
+    /// required by the OCI distribution spec for the referrers API.
+    pub fn artifact_type(&self) -> Option<&str> {
+        match self {
+            Manifest::Image(image) => image
+                .artifact_type
+                .as_deref()
+                .or(Some(image.config.media_type())),
+            Manifest::Index(index) => index.artifact_type.as_deref(),
+        }
+    }
+
+    /// Returns the annotations of the manifest.
+    pub fn annotations(&self) -> Option<&HashMap<String, String>> {
+        match self {
+            Manifest::Image(image) => image.annotations.as_ref(),
+            Manifest::Index(index) => index.annotations.as_ref(),
+        }
+    }
+
+    /// Returns the manifest this one refers to, e.g. the image signed by a signature.
+    pub fn subject(&self) -> Option<&ContentDescriptor> {
+        match self {
+            Manifest::Image(image) => image.subject.as_ref(),
+            Manifest::Index(index) => index.subject.as_ref(),
+        }
+    }
 }
 
 // TODO: Return error as:
@@ -77,6 +719,11 @@ impl OciError {
             message: code.to_string(),
         } // TODO: Use actual message
     }
+
+    pub(crate) fn with_message<S: Into<String>>(mut self, message: S) -> Self {
+        self.message = message.into();
+        self
+    }
 }
 
 #[derive(Clone, Copy, Debug, Serialize)]
@@ -142,7 +789,70 @@ impl IntoResponse for OciErrors {
 
 #[cfg(test)]
 mod tests {
-    use super::ImageManifest;
+    use proptest::prelude::*;
+
+    use super::{
+        ContentDescriptor, ImageDigest, ImageLocation, ImageManifest, Manifest, ManifestReference,
+        Reference,
+    };
+    use crate::storage::Digest;
+
+    #[test]
+    fn identifiers_round_trip() {
+        let digest: ImageDigest =
+            "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+                .parse()
+                .unwrap();
+
+        for raw in [
+            "bitnami/nginx:latest",
+            "bitnami/nginx:v1.2.3-rc_1",
+            "bitnami/nginx@sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+        ] {
+            let parsed: ManifestReference = raw.parse().unwrap();
+            assert_eq!(parsed.to_string(), raw);
+            assert_eq!(
+                parsed
+                    .location()
+                    .to_string()
+                    .parse::<ImageLocation>()
+                    .unwrap(),
+                *parsed.location()
+            );
+            assert_eq!(
+                &parsed.reference().to_string().parse::<Reference>().unwrap(),
+                parsed.reference()
+            );
+        }
+
+        let parsed: ManifestReference = format!("foo/bar@{digest}").parse().unwrap();
+        assert_eq!(parsed.reference(), &Reference::from(digest));
+        assert_eq!(parsed.reference().as_digest(), Some(digest.digest()));
+        assert_eq!(ImageDigest::from(Digest::from(digest)), digest);
+
+        for invalid in [
+            "bitnami/nginx",
+            "nginx:latest",
+            "bitnami/nginx:",
+            "bitnami/nginx:not a tag",
+            "bitnami/nginx@latest",
+            "a/b/c:latest",
+        ] {
+            assert!(invalid.parse::<ManifestReference>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn identifiers_serialize_as_strings() {
+        let reference: ManifestReference = "foo/bar:latest".parse().unwrap();
+        assert_eq!(
+            serde_json::to_value(&reference).unwrap(),
+            serde_json::json!({"repository": "foo", "image": "bar", "reference": "latest"})
+        );
+        let roundtripped: ManifestReference =
+            serde_json::from_value(serde_json::to_value(&reference).unwrap()).unwrap();
+        assert_eq!(roundtripped, reference);
+    }
 
     #[test]
     fn simple_example_schema_parse() {
@@ -165,4 +875,92 @@ mod tests {
 
         let _manifest: ImageManifest = serde_json::from_str(raw).expect("could not parse manifest");
     }
+
+    #[test]
+    fn index_is_detected() {
+        let raw = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [
+               {
+                  "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                  "size": 7143,
+                  "digest": "sha256:e692418e4cbaf90ca69d05a66403747baa33ee08806650b51fab815ad7fc331f",
+                  "platform": {
+                     "architecture": "ppc64le",
+                     "os": "linux"
+                  }
+               }
+            ]
+        }"#;
+
+        let manifest = Manifest::from_slice(raw.as_bytes()).expect("could not parse index");
+        assert!(matches!(manifest, Manifest::Index(_)));
+        assert_eq!(
+            manifest.media_type(),
+            "application/vnd.oci.image.index.v1+json"
+        );
+    }
+
+    /// Exercises all accessors of a parsed manifest, none of which may panic.
+    fn exercise(manifest: &Manifest) {
+        let _ = manifest.media_type();
+        let _ = manifest.artifact_type();
+        let _ = manifest.annotations();
+        if let Some(subject) = manifest.subject() {
+            let _ = subject.parsed_digest();
+        }
+        if let Manifest::Image(image) = manifest {
+            let _ = image.config().parsed_digest();
+            for layer in image.layers() {
+                let _ = layer.parsed_digest();
+            }
+        }
+        let _ = ContentDescriptor::for_manifest(manifest, Digest::new([0; 32]), 0);
+    }
+
+    proptest! {
+        #[test]
+        fn from_slice_never_panics(raw in any::<Vec<u8>>()) {
+            if let Ok(manifest) = Manifest::from_slice(&raw) {
+                exercise(&manifest);
+            }
+        }
+
+        #[test]
+        fn from_slice_handles_adversarial_descriptors(
+            media_type in "\\PC*",
+            digest in "\\PC*",
+            size in any::<u64>(),
+            with_subject in any::<bool>(),
+            index in any::<bool>(),
+        ) {
+            let descriptor = serde_json::json!({
+                "mediaType": media_type,
+                "digest": digest,
+                "size": size,
+            });
+            let mut manifest = if index {
+                serde_json::json!({
+                    "schemaVersion": 2,
+                    "manifests": [descriptor.clone()],
+                })
+            } else {
+                serde_json::json!({
+                    "schemaVersion": 2,
+                    "mediaType": media_type,
+                    "config": descriptor.clone(),
+                    "layers": [descriptor.clone()],
+                })
+            };
+            if with_subject {
+                manifest["subject"] = descriptor;
+            }
+
+            let raw = serde_json::to_vec(&manifest).expect("failed to serialize manifest");
+            let manifest = Manifest::from_slice(&raw).expect("failed to parse manifest");
+            prop_assert_eq!(matches!(manifest, Manifest::Index(_)), index);
+            exercise(&manifest);
+        }
+    }
 }
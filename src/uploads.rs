@@ -0,0 +1,267 @@
+//! Upload session bookkeeping.
+//!
+//! Keeps track of the currently open upload sessions and the users that started them, allowing
+//! the registry to limit the number of concurrent uploads per user. Sessions are closed once their
+//! upload is finalized or after a period of inactivity.
+//!
+//! Sessions are bound to the user and location they were started by. Requests continuing an upload
+//! from anyone else, or for another location, are answered as if the upload did not exist, so
+//! knowing an upload's UUID is not enough to write into it. As sessions are only kept in memory,
+//! uploads started before a restart cannot be continued.
+//!
+//! Finalized sessions are remembered along with their digest until they time out, so that clients
+//! retrying a finalization whose response got lost are answered with success again instead of an
+//! error. They no longer count towards the session limit and cannot be written to.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use uuid::Uuid;
+
+use crate::{
+    api_error::ApiError,
+    clock::Clock,
+    storage::{Digest, ImageLocation},
+};
+
+/// An open upload session.
+#[derive(Debug)]
+struct UploadSession {
+    /// The user that started the upload, `None` if unknown or anonymous.
+    owner: Option<String>,
+    /// The location the upload was started for.
+    location: ImageLocation,
+    /// Time of the last activity on this session.
+    last_activity: Instant,
+    /// Digest of the blob the upload was finalized as, if it was.
+    finalized: Option<Digest>,
+}
+
+/// Shared state of all upload sessions.
+#[derive(Debug, Default)]
+struct State {
+    /// Currently open sessions.
+    sessions: HashMap<Uuid, UploadSession>,
+    /// Number of sessions being created per user, see [`Reservation`].
+    pending: HashMap<Option<String>, usize>,
+}
+
+impl State {
+    /// Returns the number of open or pending sessions of `owner`.
+    fn count(&self, owner: &Option<String>) -> usize {
+        let open = self
+            .sessions
+            .values()
+            .filter(|session| &session.owner == owner && session.finalized.is_none())
+            .count();
+
+        open + self.pending.get(owner).copied().unwrap_or_default()
+    }
+
+    /// Releases a pending session of `owner`.
+    fn release_pending(&mut self, owner: &Option<String>) {
+        if let Some(count) = self.pending.get_mut(owner) {
+            *count -= 1;
+            if *count == 0 {
+                self.pending.remove(owner);
+            }
+        }
+    }
+}
+
+/// Tracker of open upload sessions.
+#[derive(Debug)]
+pub(crate) struct UploadSessions {
+    /// Maximum number of concurrent sessions per user.
+    max_per_user: Option<usize>,
+    /// Time of inactivity after which a session is considered abandoned.
+    timeout: Duration,
+    /// Source of the time activity is recorded at.
+    clock: Arc<dyn Clock>,
+    /// Shared state.
+    state: Mutex<State>,
+}
+
+impl UploadSessions {
+    /// Creates a new session tracker.
+    pub(crate) fn new(
+        max_per_user: Option<usize>,
+        timeout: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            max_per_user,
+            timeout,
+            clock,
+            state: Default::default(),
+        }
+    }
+
+    /// Reserves a new upload session for `owner` at `location`.
+    ///
+    /// Fails if the user already reached the maximum number of concurrent sessions. The returned
+    /// reservation must be committed once the upload has been created in storage, otherwise it is
+    /// released when dropped.
+    pub(crate) fn reserve(
+        &self,
+        owner: Option<&str>,
+        location: &ImageLocation,
+    ) -> Result<Reservation<'_>, ApiError> {
+        let owner = owner.map(ToOwned::to_owned);
+        let mut state = self.state.lock().expect("lock poisoned");
+
+        let (now, timeout) = (self.clock.instant(), self.timeout);
+        state
+            .sessions
+            .retain(|_, session| now.duration_since(session.last_activity) < timeout);
+
+        if let Some(max_per_user) = self.max_per_user {
+            if state.count(&owner) >= max_per_user {
+                return Err(ApiError::TooManyUploadSessions);
+            }
+        }
+
+        *state.pending.entry(owner.clone()).or_default() += 1;
+
+        Ok(Reservation {
+            sessions: self,
+            owner: Some(owner),
+            location: location.clone(),
+        })
+    }
+
+    /// Closes sessions that have been inactive for longer than the timeout.
+    ///
+    /// Returns the uploads of closed sessions that were never finalized, whose data is no longer
+    /// needed.
+    pub(crate) fn expire(&self) -> Vec<Uuid> {
+        let mut state = self.state.lock().expect("lock poisoned");
+
+        let (now, timeout) = (self.clock.instant(), self.timeout);
+        let mut abandoned = Vec::new();
+        state.sessions.retain(|&upload, session| {
+            if now.duration_since(session.last_activity) < timeout {
+                return true;
+            }
+            if session.finalized.is_none() {
+                abandoned.push(upload);
+            }
+            false
+        });
+
+        abandoned
+    }
+
+    /// Records activity of `owner` on an upload session at `location`.
+    ///
+    /// Fails with [`ApiError::UploadUnknown`] if there is no such session, it belongs to
+    /// another user or location, has timed out or has been finalized already.
+    pub(crate) fn claim(
+        &self,
+        upload: Uuid,
+        owner: Option<&str>,
+        location: &ImageLocation,
+    ) -> Result<(), ApiError> {
+        let mut state = self.state.lock().expect("lock poisoned");
+
+        let now = self.clock.instant();
+        match state.sessions.get_mut(&upload) {
+            Some(session)
+                if session.owner.as_deref() == owner
+                    && &session.location == location
+                    && session.finalized.is_none()
+                    && now.duration_since(session.last_activity) < self.timeout =>
+            {
+                session.last_activity = now;
+                Ok(())
+            }
+            _ => Err(ApiError::UploadUnknown),
+        }
+    }
+
+    /// Returns the digest an upload of `owner` at `location` was finalized as, if it was.
+    pub(crate) fn finalized(
+        &self,
+        upload: Uuid,
+        owner: Option<&str>,
+        location: &ImageLocation,
+    ) -> Option<Digest> {
+        let state = self.state.lock().expect("lock poisoned");
+
+        let now = self.clock.instant();
+        state
+            .sessions
+            .get(&upload)
+            .filter(|session| session.owner.as_deref() == owner && &session.location == location)
+            .filter(|session| now.duration_since(session.last_activity) < self.timeout)
+            .and_then(|session| session.finalized)
+    }
+
+    /// Marks an upload session as finalized as the blob with the given digest.
+    pub(crate) fn finalize(&self, upload: Uuid, digest: Digest) {
+        if let Some(session) = self
+            .state
+            .lock()
+            .expect("lock poisoned")
+            .sessions
+            .get_mut(&upload)
+        {
+            session.last_activity = self.clock.instant();
+            session.finalized = Some(digest);
+        }
+    }
+
+    /// Closes an upload session.
+    pub(crate) fn close(&self, upload: Uuid) {
+        self.state
+            .lock()
+            .expect("lock poisoned")
+            .sessions
+            .remove(&upload);
+    }
+}
+
+/// A reserved, but not yet created upload session.
+#[derive(Debug)]
+pub(crate) struct Reservation<'a> {
+    /// The tracker the reservation was made on.
+    sessions: &'a UploadSessions,
+    /// The owner of the reserved session, `None` once committed.
+    owner: Option<Option<String>>,
+    /// The location of the reserved session.
+    location: ImageLocation,
+}
+
+impl Reservation<'_> {
+    /// Turns the reservation into an open session for `upload`.
+    pub(crate) fn commit(mut self, upload: Uuid) {
+        let owner = self.owner.take().expect("reservation committed twice");
+        let mut state = self.sessions.state.lock().expect("lock poisoned");
+
+        state.release_pending(&owner);
+        state.sessions.insert(
+            upload,
+            UploadSession {
+                owner,
+                location: self.location.clone(),
+                last_activity: self.sessions.clock.instant(),
+                finalized: None,
+            },
+        );
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if let Some(owner) = self.owner.take() {
+            self.sessions
+                .state
+                .lock()
+                .expect("lock poisoned")
+                .release_pending(&owner);
+        }
+    }
+}
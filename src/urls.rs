@@ -0,0 +1,258 @@
+//! Public URLs of the registry.
+//!
+//! Handlers generate `Location` headers relative to the registry's own routes, e.g.
+//! `/v2/foo/bar/uploads/...`. Before a response is sent, these are rewritten to the URL clients
+//! have to use, which differs if the registry is mounted under a base path (see
+//! [`ContainerRegistryBuilder::base_path`](crate::ContainerRegistryBuilder::base_path)) or runs
+//! behind a reverse proxy.
+//!
+//! If an external URL is configured, it is always used. Otherwise, if forwarding headers are
+//! trusted, the origin and prefix a reverse proxy reports through the standard `Forwarded` header
+//! or the `X-Forwarded-Proto`, `X-Forwarded-Host` and `X-Forwarded-Prefix` headers are used. In
+//! all other cases, locations stay relative to the host the client connected to.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header::LOCATION, HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::ContainerRegistry;
+
+/// Standard forwarding header (RFC 7239).
+const FORWARDED: HeaderName = HeaderName::from_static("forwarded");
+
+/// Scheme of the original request, as reported by a proxy.
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+
+/// Host of the original request, as reported by a proxy.
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// Path prefix stripped by a proxy.
+const X_FORWARDED_PREFIX: HeaderName = HeaderName::from_static("x-forwarded-prefix");
+
+/// Configuration of the URLs the registry is reachable under.
+#[derive(Debug, Default)]
+pub(crate) struct PublicUrls {
+    /// Path the registry's routes are mounted under, without a trailing slash.
+    pub(crate) base_path: Option<String>,
+    /// URL clients reach the registry's routes under, without a trailing slash.
+    pub(crate) external_url: Option<String>,
+    /// Whether to use origins reported by reverse proxies.
+    pub(crate) trust_forwarded: bool,
+}
+
+impl PublicUrls {
+    /// Returns the URL or path prefix to prepend to locations in a response to a request with the
+    /// given headers.
+    fn prefix(&self, headers: &HeaderMap) -> String {
+        if let Some(ref external_url) = self.external_url {
+            return external_url.clone();
+        }
+
+        let base_path = self.base_path.as_deref().unwrap_or_default();
+        if !self.trust_forwarded {
+            return base_path.to_owned();
+        }
+
+        let forwarded = Forwarded::from_headers(headers);
+        let origin = match (forwarded.proto, forwarded.host) {
+            (Some(proto), Some(host)) => format!("{proto}://{host}"),
+            (None, Some(host)) => format!("http://{host}"),
+            (Some(proto), None) => match header_str(headers, &axum::http::header::HOST) {
+                Some(host) => format!("{proto}://{host}"),
+                None => String::new(),
+            },
+            (None, None) => String::new(),
+        };
+        let forwarded_prefix = forwarded.prefix.unwrap_or_default();
+
+        format!(
+            "{origin}{}{base_path}",
+            forwarded_prefix.trim_end_matches('/')
+        )
+    }
+}
+
+/// Information about the original request reported by a reverse proxy.
+#[derive(Debug, Default, PartialEq)]
+struct Forwarded<'a> {
+    /// Scheme, e.g. `https`.
+    proto: Option<&'a str>,
+    /// Host, including the port if any.
+    host: Option<&'a str>,
+    /// Path prefix.
+    prefix: Option<&'a str>,
+}
+
+impl<'a> Forwarded<'a> {
+    /// Extracts forwarding information from request headers.
+    ///
+    /// The `Forwarded` header takes precedence over the `X-Forwarded-*` headers. Only the first
+    /// (outermost) proxy's information is used if multiple proxies are chained.
+    fn from_headers(headers: &'a HeaderMap) -> Self {
+        let mut forwarded = Self::default();
+
+        if let Some(element) = header_str(headers, &FORWARDED).and_then(|v| v.split(',').next()) {
+            for pair in element.split(';') {
+                let Some((key, value)) = pair.trim().split_once('=') else {
+                    continue;
+                };
+                let value = value.trim_matches('"');
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "proto" => forwarded.proto = Some(value),
+                    "host" => forwarded.host = Some(value),
+                    _ => {}
+                }
+            }
+        }
+
+        let first = |name: HeaderName| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(',').next())
+        };
+        forwarded.proto = forwarded.proto.or_else(|| first(X_FORWARDED_PROTO));
+        forwarded.host = forwarded.host.or_else(|| first(X_FORWARDED_HOST));
+        forwarded.prefix = first(X_FORWARDED_PREFIX);
+
+        forwarded.proto = forwarded.proto.map(str::trim).filter(|v| is_valid_proto(v));
+        forwarded.host = forwarded.host.map(str::trim).filter(|v| is_valid_host(v));
+        forwarded.prefix = forwarded
+            .prefix
+            .map(str::trim)
+            .filter(|v| v.starts_with('/') && !v.starts_with("//"));
+        forwarded
+    }
+}
+
+/// Returns a header's value, if present and valid.
+fn header_str<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Checks whether a reported scheme is plausible.
+fn is_valid_proto(proto: &str) -> bool {
+    !proto.is_empty() && proto.chars().all(|c| c.is_ascii_alphanumeric() || c == '+')
+}
+
+/// Checks whether a reported host is plausible, i.e. cannot be used to inject a path.
+fn is_valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-.:[]".contains(c))
+}
+
+/// Middleware rewriting relative `Location` headers to public URLs.
+pub(crate) async fn rewrite_locations(
+    State(registry): State<Arc<ContainerRegistry>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let prefix = registry.public_urls.prefix(request.headers());
+    let mut response = next.run(request).await;
+
+    if prefix.is_empty() {
+        return response;
+    }
+
+    let rewritten = header_str(response.headers(), &LOCATION)
+        .filter(|location| location.starts_with('/'))
+        .and_then(|location| HeaderValue::try_from(format!("{prefix}{location}")).ok());
+    if let Some(location) = rewritten {
+        response.headers_mut().insert(LOCATION, location);
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use super::{Forwarded, PublicUrls};
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.parse().unwrap(), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    #[test]
+    fn forwarded_headers_are_parsed() {
+        let standard = headers(&[
+            (
+                "forwarded",
+                r#"for=192.0.2.1;proto=https;host="registry.example.com", for=10.0.0.1"#,
+            ),
+            ("x-forwarded-host", "ignored.example.com"),
+            ("x-forwarded-prefix", "/mirror/"),
+        ]);
+        assert_eq!(
+            Forwarded::from_headers(&standard),
+            Forwarded {
+                proto: Some("https"),
+                host: Some("registry.example.com"),
+                prefix: Some("/mirror/"),
+            }
+        );
+
+        let legacy = headers(&[
+            ("x-forwarded-proto", "https, http"),
+            ("x-forwarded-host", "registry.example.com:8443"),
+        ]);
+        assert_eq!(
+            Forwarded::from_headers(&legacy),
+            Forwarded {
+                proto: Some("https"),
+                host: Some("registry.example.com:8443"),
+                prefix: None,
+            }
+        );
+
+        // Values that could inject a path or scheme are ignored.
+        let hostile = headers(&[
+            ("x-forwarded-proto", "javascript:"),
+            ("x-forwarded-host", "evil.example.com/phish"),
+            ("x-forwarded-prefix", "//evil.example.com"),
+        ]);
+        assert_eq!(
+            Forwarded::from_headers(&hostile),
+            Forwarded {
+                proto: None,
+                host: None,
+                prefix: None,
+            }
+        );
+    }
+
+    #[test]
+    fn prefixes_are_resolved() {
+        let proxied = headers(&[
+            ("host", "internal:3000"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-prefix", "/mirror"),
+        ]);
+
+        let mut urls = PublicUrls {
+            base_path: Some("/registry".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(urls.prefix(&proxied), "/registry");
+
+        urls.trust_forwarded = true;
+        assert_eq!(
+            urls.prefix(&proxied),
+            "https://internal:3000/mirror/registry"
+        );
+
+        urls.external_url = Some("https://registry.example.com".to_owned());
+        assert_eq!(urls.prefix(&proxied), "https://registry.example.com");
+    }
+}
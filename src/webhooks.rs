@@ -0,0 +1,483 @@
+//! Signed webhook deliveries.
+//!
+//! With [`Webhooks`] set through
+//! [`ContainerRegistryBuilder::webhooks`](crate::ContainerRegistryBuilder::webhooks), every
+//! [registry event](crate::events::RegistryEvent) is posted as JSON to the configured endpoints,
+//! e.g. to trigger deployments once an image has been pushed. Each delivery carries the following
+//! headers:
+//!
+//! * `X-Registry-Delivery`: A unique ID of the delivery, kept when redelivering.
+//! * `X-Registry-Event`: The event's type, e.g. `manifest_pushed`.
+//! * `X-Registry-Timestamp`: Time of the attempt, in seconds since the Unix epoch.
+//! * `X-Registry-Signature`: `sha256=` followed by the hex encoded HMAC-SHA256 of the delivery ID,
+//!   a `.`, the timestamp, another `.` and the body, keyed with the endpoint's secret.
+//!
+//! Receivers authenticate deliveries by checking the signature, see [`verify_signature`]. Replays
+//! are prevented by rejecting timestamps that are too old and delivery IDs already seen. As the ID
+//! is signed, it cannot be changed to pass a captured delivery off as a new one.
+//!
+//! Deliveries are attempted once. The most recent deliveries and their outcomes are kept in memory
+//! and can be listed using [`ContainerRegistry::webhook_deliveries`], failed ones redelivered using
+//! [`ContainerRegistry::redeliver_webhook`]. Through the administrative API, these are
+//! `GET /admin/webhooks/deliveries` and `POST /admin/webhooks/deliveries/<id>/redeliver`, both
+//! requiring administrative write access, see
+//! [`AuthProvider::admin_permissions`](crate::auth::AuthProvider::admin_permissions).
+//!
+//! As the registry has no HTTP client of its own, requests are sent by a [`WebhookSender`], e.g. a
+//! [`CommandWebhookSender`] running `curl`.
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::async_trait;
+use sec::Secret;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    clock::{Clock, SystemClock},
+    events::RegistryEvent,
+    hashing::{hmac_sha256, verify_hmac_sha256},
+    ContainerRegistry, RegistryError,
+};
+
+/// Header carrying the ID of a delivery.
+pub const DELIVERY_HEADER: &str = "X-Registry-Delivery";
+/// Header carrying the type of the delivered event.
+pub const EVENT_HEADER: &str = "X-Registry-Event";
+/// Header carrying the time of a delivery attempt.
+pub const TIMESTAMP_HEADER: &str = "X-Registry-Timestamp";
+/// Header carrying the signature of a delivery.
+pub const SIGNATURE_HEADER: &str = "X-Registry-Signature";
+
+/// Number of deliveries kept for inspection and redelivery.
+const MAX_DELIVERIES: usize = 1000;
+
+/// A sender of webhook requests, see the [module documentation](self).
+#[async_trait]
+pub trait WebhookSender: Send + Sync {
+    /// Posts `body` to `url` along with `headers`.
+    ///
+    /// Must only return `Ok` if the endpoint accepted the delivery.
+    async fn send(
+        &self,
+        url: &str,
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+/// Sends webhook requests by running an external command, e.g. `curl`.
+///
+/// The body is passed on the command's standard input. In all arguments, `{url}` is replaced with
+/// the URL to post to. Headers are appended as pairs of `--header` and `Name: value` arguments:
+///
+/// ```
+/// use container_registry::webhooks::CommandWebhookSender;
+///
+/// let sender = CommandWebhookSender::new("curl")
+///     .arg("--fail")
+///     .arg("--silent")
+///     .arg("--data-binary")
+///     .arg("@-")
+///     .arg("{url}");
+/// ```
+///
+/// Deliveries are considered successful if the command exits successfully.
+#[derive(Clone, Debug)]
+pub struct CommandWebhookSender {
+    /// Program to run.
+    program: String,
+    /// Argument templates.
+    args: Vec<String>,
+}
+
+impl CommandWebhookSender {
+    /// Creates a new sender running `program`.
+    pub fn new<P: Into<String>>(program: P) -> Self {
+        Self {
+            program: program.into(),
+            args: Vec::new(),
+        }
+    }
+
+    /// Adds an argument template.
+    pub fn arg<S: Into<String>>(mut self, arg: S) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+}
+
+#[async_trait]
+impl WebhookSender for CommandWebhookSender {
+    async fn send(
+        &self,
+        url: &str,
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut child = tokio::process::Command::new(&self.program)
+            .args(self.args.iter().map(|arg| arg.replace("{url}", url)))
+            .args(
+                headers
+                    .iter()
+                    .flat_map(|(name, value)| ["--header".to_owned(), format!("{name}: {value}")]),
+            )
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let mut stdin = child.stdin.take().expect("stdin should be piped");
+        let written = stdin.write_all(body).await;
+        drop(stdin);
+
+        // A command exiting early fails writing, report its status instead.
+        let status = child.wait().await?;
+        if !status.success() {
+            return Err(format!("{} exited with {}", self.program, status).into());
+        }
+        Ok(written?)
+    }
+}
+
+/// An endpoint receiving deliveries.
+struct Endpoint {
+    /// URL to post to.
+    url: String,
+    /// Secret to sign deliveries with.
+    secret: Secret<String>,
+}
+
+/// Webhook endpoints and their deliveries, see the [module documentation](self).
+pub struct Webhooks {
+    /// Sender of requests.
+    sender: Arc<dyn WebhookSender>,
+    /// Endpoints receiving deliveries.
+    endpoints: Vec<Endpoint>,
+    /// Source of delivery timestamps.
+    clock: Arc<dyn Clock>,
+    /// Most recent deliveries, oldest first.
+    deliveries: Mutex<VecDeque<WebhookDelivery>>,
+}
+
+impl fmt::Debug for Webhooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Webhooks")
+            .field(
+                "endpoints",
+                &self
+                    .endpoints
+                    .iter()
+                    .map(|endpoint| &endpoint.url)
+                    .collect::<Vec<_>>(),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl Webhooks {
+    /// Creates a new set of webhooks sending requests through `sender`, without any endpoints.
+    pub fn new(sender: Arc<dyn WebhookSender>) -> Self {
+        Self {
+            sender,
+            endpoints: Vec::new(),
+            clock: Arc::new(SystemClock),
+            deliveries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Adds an endpoint receiving all events, signed with `secret`.
+    pub fn endpoint<U: Into<String>>(mut self, url: U, secret: Secret<String>) -> Self {
+        self.endpoints.push(Endpoint {
+            url: url.into(),
+            secret,
+        });
+        self
+    }
+
+    /// Sets the clock deliveries are timestamped by, see the [`clock`](crate::clock) module.
+    pub(crate) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Starts delivering `event` to all endpoints in the background.
+    pub(crate) fn dispatch(self: &Arc<Self>, event: &RegistryEvent) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            warn!(
+                event = event.kind(),
+                "not delivering webhooks outside of a runtime"
+            );
+            return;
+        };
+        let payload = serde_json::to_value(event).expect("serialization should not fail");
+
+        for (endpoint, target) in self.endpoints.iter().enumerate() {
+            let delivery = WebhookDelivery {
+                id: Uuid::new_v4(),
+                url: target.url.clone(),
+                event: event.kind(),
+                state: DeliveryState::Pending,
+                error: None,
+                attempts: 0,
+                last_attempt: None,
+                payload: payload.clone(),
+                endpoint,
+            };
+            let id = delivery.id;
+
+            let mut deliveries = self.deliveries.lock().expect("lock poisoned");
+            if deliveries.len() == MAX_DELIVERIES {
+                deliveries.pop_front();
+            }
+            deliveries.push_back(delivery);
+            drop(deliveries);
+
+            let webhooks = self.clone();
+            runtime.spawn(async move { webhooks.attempt(id).await });
+        }
+    }
+
+    /// Attempts a delivery, returning its updated state, `None` if it is no longer kept.
+    async fn attempt(&self, id: Uuid) -> Option<WebhookDelivery> {
+        let delivery = self.find(id)?;
+        let endpoint = &self.endpoints[delivery.endpoint];
+
+        let timestamp = unix_seconds(self.clock.now());
+        let body = serde_json::to_vec(&delivery.payload).expect("serialization should not fail");
+        let headers = [
+            ("Content-Type", "application/json".to_owned()),
+            (DELIVERY_HEADER, id.to_string()),
+            (EVENT_HEADER, delivery.event.to_owned()),
+            (TIMESTAMP_HEADER, timestamp.to_string()),
+            (
+                SIGNATURE_HEADER,
+                sign(endpoint.secret.reveal(), &id.to_string(), timestamp, &body),
+            ),
+        ];
+        let outcome = self.sender.send(&endpoint.url, &headers, &body).await;
+        if let Err(ref err) = outcome {
+            warn!(%id, url = endpoint.url, %err, "webhook delivery failed");
+        }
+
+        let mut deliveries = self.deliveries.lock().expect("lock poisoned");
+        let delivery = deliveries.iter_mut().find(|delivery| delivery.id == id)?;
+        delivery.attempts += 1;
+        delivery.last_attempt = Some(timestamp);
+        (delivery.state, delivery.error) = match outcome {
+            Ok(()) => (DeliveryState::Delivered, None),
+            Err(err) => (DeliveryState::Failed, Some(err.to_string())),
+        };
+        Some(delivery.clone())
+    }
+
+    /// Returns a kept delivery.
+    fn find(&self, id: Uuid) -> Option<WebhookDelivery> {
+        self.deliveries
+            .lock()
+            .expect("lock poisoned")
+            .iter()
+            .find(|delivery| delivery.id == id)
+            .cloned()
+    }
+}
+
+/// State of a webhook delivery.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryState {
+    /// Not attempted yet.
+    Pending,
+    /// Accepted by the endpoint.
+    Delivered,
+    /// The last attempt failed.
+    Failed,
+}
+
+/// A delivery of an event to a webhook endpoint.
+#[derive(Clone, Debug, Serialize)]
+pub struct WebhookDelivery {
+    /// ID of the delivery, sent in the `X-Registry-Delivery` header.
+    pub id: Uuid,
+    /// URL of the endpoint.
+    pub url: String,
+    /// Type of the delivered event.
+    pub event: &'static str,
+    /// Outcome of the last attempt.
+    pub state: DeliveryState,
+    /// Description of the error, if the last attempt failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Number of attempts so far.
+    pub attempts: u32,
+    /// Time of the last attempt, in seconds since the Unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_attempt: Option<u64>,
+    /// The delivered event.
+    pub payload: Value,
+    /// Index of the endpoint.
+    #[serde(skip)]
+    endpoint: usize,
+}
+
+/// Prefix of signatures, naming the algorithm.
+const SIGNATURE_PREFIX: &str = "sha256=";
+
+/// Returns the signature of the body of delivery `id` sent at `timestamp`.
+fn sign(secret: &str, id: &str, timestamp: u64, body: &[u8]) -> String {
+    let mac = hmac_sha256(secret.as_bytes(), &signed_message(id, timestamp, body));
+    format!("{SIGNATURE_PREFIX}{}", hex::encode(mac))
+}
+
+/// Returns the message signed for the body of delivery `id` sent at `timestamp`.
+fn signed_message(id: &str, timestamp: u64, body: &[u8]) -> Vec<u8> {
+    [
+        id.as_bytes(),
+        b".",
+        timestamp.to_string().as_bytes(),
+        b".",
+        body,
+    ]
+    .concat()
+}
+
+/// Returns the seconds since the Unix epoch at `time`.
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Verifies the signature of a received delivery, for use by receivers.
+///
+/// `id`, `timestamp` and `signature` are the values of the `X-Registry-Delivery`,
+/// `X-Registry-Timestamp` and `X-Registry-Signature` headers, `body` the raw request body.
+/// Deliveries sent more than `tolerance` before or after `now` are rejected, so captured
+/// deliveries cannot be replayed later.
+pub fn verify_signature(
+    secret: &str,
+    id: &str,
+    timestamp: &str,
+    body: &[u8],
+    signature: &str,
+    now: SystemTime,
+    tolerance: Duration,
+) -> bool {
+    let Ok(sent) = timestamp.parse::<u64>() else {
+        return false;
+    };
+    if unix_seconds(now).abs_diff(sent) > tolerance.as_secs() {
+        return false;
+    }
+
+    let Some(Ok(signature)) = signature.strip_prefix(SIGNATURE_PREFIX).map(hex::decode) else {
+        return false;
+    };
+    verify_hmac_sha256(
+        secret.as_bytes(),
+        &signed_message(id, sent, body),
+        &signature,
+    )
+}
+
+impl ContainerRegistry {
+    /// Returns the most recent webhook deliveries, most recent first.
+    ///
+    /// Empty if no webhooks are configured, see the [`webhooks`](crate::webhooks) module.
+    pub fn webhook_deliveries(&self) -> Vec<WebhookDelivery> {
+        self.webhooks
+            .as_ref()
+            .map(|webhooks| {
+                let deliveries = webhooks.deliveries.lock().expect("lock poisoned");
+                deliveries.iter().rev().cloned().collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Delivers the event of a previous webhook delivery again, returning its updated state.
+    ///
+    /// The delivery keeps its ID, but is signed anew with the current time. Returns
+    /// [`RegistryError::NotFound`] if the delivery is no longer kept.
+    pub async fn redeliver_webhook(&self, id: Uuid) -> Result<WebhookDelivery, RegistryError> {
+        let webhooks = self
+            .webhooks
+            .as_ref()
+            .ok_or(RegistryError::NotSupported("webhooks"))?;
+        webhooks.attempt(id).await.ok_or(RegistryError::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{sign, verify_signature};
+
+    #[test]
+    fn signatures_are_verified() {
+        const ID: &str = "5f0c6f3e-93a4-4b8e-9a51-0e6c2d3f4a11";
+        let body = br#"{"type":"tag_deleted"}"#;
+        let signature = sign("secret", ID, 1_700_000_000, body);
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let tolerance = Duration::from_secs(300);
+
+        assert!(verify_signature(
+            "secret",
+            ID,
+            "1700000000",
+            body,
+            &signature,
+            at(1_700_000_100),
+            tolerance
+        ));
+        assert!(!verify_signature(
+            "other secret",
+            ID,
+            "1700000000",
+            body,
+            &signature,
+            at(1_700_000_100),
+            tolerance
+        ));
+        // The delivery ID and timestamp are part of the signature.
+        assert!(!verify_signature(
+            "secret",
+            "another delivery",
+            "1700000000",
+            body,
+            &signature,
+            at(1_700_000_100),
+            tolerance
+        ));
+        assert!(!verify_signature(
+            "secret",
+            ID,
+            "1700000001",
+            body,
+            &signature,
+            at(1_700_000_100),
+            tolerance
+        ));
+        // Old deliveries are rejected.
+        assert!(!verify_signature(
+            "secret",
+            ID,
+            "1700000000",
+            body,
+            &signature,
+            at(1_700_000_301),
+            tolerance
+        ));
+    }
+}
@@ -45,9 +45,78 @@ pub(crate) fn basic_auth_response(input: &[u8]) -> IResult<&[u8], BasicAuthRespo
     Ok((input, basic))
 }
 
+/// Parses the token of a `Bearer` authorization header (RFC 6750).
+pub(crate) fn bearer_auth_response(input: &[u8]) -> IResult<&[u8], Vec<u8>> {
+    // Skip leading whitespace.
+    let input = skip_whitespace(input);
+
+    // Match tag.
+    let (input, _) = tag_no_case("bearer")(input)?;
+    let input = skip_whitespace(input);
+
+    // Tokens are opaque to us, anything up to the next whitespace is taken.
+    let (input, token) = take_while1(|c: u8| !c.is_ascii_whitespace())(input)?;
+
+    Ok((input, token.to_vec()))
+}
+
+/// Renders `value` as a quoted string, for use as a challenge parameter.
+fn quoted(value: &str) -> String {
+    let mut rv = String::with_capacity(value.len() + 2);
+    rv.push('"');
+    for c in value.chars() {
+        if matches!(c, '"' | '\\') {
+            rv.push('\\');
+        }
+        rv.push(c);
+    }
+    rv.push('"');
+    rv
+}
+
+/// Renders the challenges sent in `WWW-Authenticate` headers, one per header.
+///
+/// A `Basic` challenge for `realm` is always included. If `bearer_realm` is given, a `Bearer`
+/// challenge pointing clients at it for obtaining tokens follows, with `realm` as the service.
+pub(crate) fn challenges(realm: &str, bearer_realm: Option<&str>) -> Vec<String> {
+    let mut challenges = vec![format!("Basic realm={}", quoted(realm))];
+    if let Some(bearer_realm) = bearer_realm {
+        challenges.push(format!(
+            "Bearer realm={},service={}",
+            quoted(bearer_realm),
+            quoted(realm)
+        ));
+    }
+    challenges
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::www_authenticate::{basic_auth_response, BasicAuthResponse};
+    use crate::www_authenticate::{
+        basic_auth_response, bearer_auth_response, challenges, BasicAuthResponse,
+    };
+
+    #[test]
+    fn can_parse_bearer_response() {
+        assert_eq!(
+            bearer_auth_response(b"Bearer crpt1.abc-_.def= "),
+            Ok((&b" "[..], b"crpt1.abc-_.def=".to_vec()))
+        );
+        assert!(bearer_auth_response(b"Basic YWxhZGRpbjpvcGVuc2VzYW1l").is_err());
+        assert!(bearer_auth_response(b"Bearer ").is_err());
+    }
+
+    #[test]
+    fn challenges_are_quoted() {
+        assert_eq!(challenges("registry", None), [r#"Basic realm="registry""#]);
+        assert_eq!(
+            challenges(r#"my "registry""#, Some("https://auth.example.com/token")),
+            [
+                r#"Basic realm="my \"registry\"""#,
+                r#"Bearer realm="https://auth.example.com/token",service="my \"registry\"""#
+            ]
+        );
+    }
 
     #[test]
     fn can_parse_known_response() {